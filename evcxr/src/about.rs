@@ -0,0 +1,187 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eval_context::Config;
+use crate::sandbox::SandboxPolicy;
+use json::JsonValue;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Information about the environment in which evcxr is running. This is what
+/// we'd like to see attached to bug reports, so it's all gathered in one place
+/// by the `:about` command.
+#[derive(Debug, Clone)]
+pub(crate) struct AboutInfo {
+    pub(crate) evcxr_version: &'static str,
+    pub(crate) rustc_version: String,
+    pub(crate) toolchain: String,
+    pub(crate) host_target: String,
+    pub(crate) target_dir: PathBuf,
+    pub(crate) target_dir_bytes: u64,
+    pub(crate) config_files: Vec<PathBuf>,
+    pub(crate) sandbox: String,
+    pub(crate) linker: String,
+    pub(crate) sccache: bool,
+    pub(crate) offline_mode: bool,
+}
+
+impl AboutInfo {
+    pub(crate) fn gather(
+        config: &Config,
+        host_target: &str,
+        target_dir: &Path,
+        sandbox_policy: Option<&SandboxPolicy>,
+        prelude: Option<&Path>,
+    ) -> AboutInfo {
+        let mut config_files = Vec::new();
+        if let Some(config_dir) = crate::config_dir() {
+            for name in &["init.evcxr", "prelude.rs"] {
+                let file = config_dir.join(name);
+                if file.exists() {
+                    config_files.push(file);
+                }
+            }
+        }
+        config_files.extend(prelude.map(Path::to_owned));
+        AboutInfo {
+            evcxr_version: env!("CARGO_PKG_VERSION"),
+            rustc_version: rustc_version(config),
+            toolchain: if config.toolchain.is_empty() {
+                "default".to_owned()
+            } else {
+                config.toolchain.clone()
+            },
+            host_target: host_target.to_owned(),
+            target_dir: target_dir.to_owned(),
+            target_dir_bytes: dir_size(target_dir),
+            config_files,
+            sandbox: sandbox_policy.map_or_else(|| "none".to_owned(), SandboxPolicy::describe),
+            linker: config.linker.clone(),
+            sccache: config.sccache(),
+            offline_mode: config.offline_mode,
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> JsonValue {
+        let config_files: Vec<String> = self
+            .config_files
+            .iter()
+            .map(|f| f.to_string_lossy().into_owned())
+            .collect();
+        json::object! {
+            "evcxr_version" => self.evcxr_version,
+            "rustc_version" => self.rustc_version.clone(),
+            "toolchain" => self.toolchain.clone(),
+            "host_target" => self.host_target.clone(),
+            "target_dir" => self.target_dir.to_string_lossy().into_owned(),
+            "target_dir_bytes" => self.target_dir_bytes,
+            "config_files" => config_files,
+            "sandbox" => self.sandbox.clone(),
+            "linker" => self.linker.clone(),
+            "sccache" => self.sccache,
+            "offline_mode" => self.offline_mode,
+        }
+    }
+
+    pub(crate) fn to_text(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let _ = writeln!(out, "evcxr:        {}", self.evcxr_version);
+        let _ = writeln!(out, "rustc:        {}", self.rustc_version);
+        let _ = writeln!(out, "toolchain:    {}", self.toolchain);
+        let _ = writeln!(out, "host target:  {}", self.host_target);
+        let _ = writeln!(
+            out,
+            "target dir:   {} ({})",
+            self.target_dir.display(),
+            format_bytes(self.target_dir_bytes)
+        );
+        if self.config_files.is_empty() {
+            let _ = writeln!(out, "config files: none");
+        } else {
+            for (index, file) in self.config_files.iter().enumerate() {
+                let label = if index == 0 { "config files:" } else { "" };
+                let _ = writeln!(out, "{:<13} {}", label, file.display());
+            }
+        }
+        let _ = writeln!(out, "sandbox:      {}", self.sandbox);
+        let _ = writeln!(out, "linker:       {}", self.linker);
+        let _ = writeln!(out, "sccache:      {}", self.sccache);
+        let _ = write!(out, "offline mode: {}", self.offline_mode);
+        out
+    }
+}
+
+fn rustc_version(config: &Config) -> String {
+    let mut command = config.tool_paths.rustc_command();
+    if !config.toolchain.is_empty() {
+        command.arg(format!("+{}", config.toolchain));
+    }
+    match command.arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_owned()
+        }
+        Ok(output) => format!(
+            "unknown ({})",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(error) => format!("unknown ({})", error),
+    }
+}
+
+/// Returns the total size of all files under `dir`. Unreadable entries are
+/// ignored, since this is only used for reporting.
+pub(crate) fn dir_size(dir: &Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_bytes;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:bench`, which benchmarks expressions using criterion in the subprocess.
+//! Criterion prints its report to stdout as it goes. Its data is kept between runs, so that
+//! benchmarking the same expression again reports how much it changed.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use std::fmt::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The criterion dependency that we add if the user hasn't added criterion themselves. We need
+/// `html_reports` for plots.
+pub(crate) const CRITERION_DEPENDENCY: &str = r#"{ version = "0.5", features = ["html_reports"] }"#;
+
+/// The name of the criterion benchmark group that all benchmarks go in. This is also the name of
+/// its directory within the output directory.
+const GROUP_NAME: &str = "bench";
+
+/// Splits the arguments to `:bench` into the expressions to benchmark. Expressions are separated
+/// by `;`. An expression that's just a path, such as the name of a function, is called with no
+/// arguments.
+pub(crate) fn parse_expressions(args: &str) -> Result<Vec<String>, Error> {
+    let parsed = ast::SourceFile::parse(&format!("fn f() {{ {} }}", args));
+    if let Some(error) = parsed.errors().first() {
+        bail!("Invalid :bench expression: {}", error);
+    }
+    let statements = match parsed
+        .syntax_node()
+        .descendants()
+        .find_map(ast::StmtList::cast)
+    {
+        Some(statements) => statements,
+        None => bail!(":bench requires an expression. e.g. :bench fib(20)"),
+    };
+    let mut expressions = Vec::new();
+    for statement in statements.statements() {
+        match statement {
+            ast::Stmt::ExprStmt(statement) => expressions.extend(statement.expr()),
+            _ => bail!(
+                ":bench only accepts expressions, not `{}`",
+                statement.syntax().text()
+            ),
+        }
+    }
+    expressions.extend(statements.tail_expr());
+    if expressions.is_empty() {
+        bail!(":bench requires an expression. e.g. :bench fib(20)");
+    }
+    Ok(expressions
+        .iter()
+        .map(|expression| {
+            let text = expression.syntax().text().to_string();
+            if matches!(expression, ast::Expr::PathExpr(_)) {
+                format!("{}()", text)
+            } else {
+                text
+            }
+        })
+        .collect())
+}
+
+/// Returns code that benchmarks `expressions`, writing criterion's data into `output_dir`.
+pub(crate) fn bench_code(expressions: &[String], output_dir: &Path, plot: bool) -> String {
+    let mut code = format!(
+        "{{\n\
+        let mut evcxr_criterion = ::criterion::Criterion::default()\n    \
+            .output_directory(::std::path::Path::new({:?}))\n    \
+            {};\n\
+        let mut evcxr_bench_group = evcxr_criterion.benchmark_group({:?});\n",
+        output_dir.to_string_lossy(),
+        if plot {
+            ".plotting_backend(::criterion::PlottingBackend::Plotters)"
+        } else {
+            ".without_plots()"
+        },
+        GROUP_NAME
+    );
+    for expression in expressions {
+        writeln!(
+            code,
+            "evcxr_bench_group.bench_function({:?}, |evcxr_bencher| evcxr_bencher.iter(|| {{ {} }}));",
+            expression.split_whitespace().collect::<Vec<_>>().join(" "),
+            expression
+        )
+        .unwrap();
+    }
+    code.push_str("evcxr_bench_group.finish();\n}");
+    code
+}
+
+/// Returns where criterion writes the violin plot comparing the benchmarks. It only writes one
+/// when more than one expression was benchmarked.
+pub(crate) fn violin_plot_path(output_dir: &Path) -> PathBuf {
+    output_dir
+        .join(GROUP_NAME)
+        .join("report")
+        .join("violin.svg")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bench_code;
+    use super::parse_expressions;
+    use std::path::Path;
+
+    #[test]
+    fn expressions() {
+        assert_eq!(parse_expressions("fib(20)").unwrap(), vec!["fib(20)"]);
+        assert_eq!(
+            parse_expressions("fib; fast_fib(20); { let x = 1; x + 1 };").unwrap(),
+            vec!["fib()", "fast_fib(20)", "{ let x = 1; x + 1 }"]
+        );
+        assert!(parse_expressions("").is_err());
+        assert!(parse_expressions("let x = 1").is_err());
+        assert!(parse_expressions("fib(20").is_err());
+    }
+
+    #[test]
+    fn code() {
+        let code = bench_code(
+            &["fib(20)".to_owned(), "{\n    fast_fib(20)\n}".to_owned()],
+            Path::new("/tmp/criterion"),
+            false,
+        );
+        assert!(code.contains(".output_directory(::std::path::Path::new(\"/tmp/criterion\"))"));
+        assert!(code.contains(".without_plots();"));
+        assert!(code.contains(
+            "bench_function(\"fib(20)\", |evcxr_bencher| evcxr_bencher.iter(|| { fib(20) }));"
+        ));
+        assert!(code.contains("bench_function(\"{ fast_fib(20) }\","));
+    }
+}
@@ -20,18 +20,23 @@ use json::{self};
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
+use crate::cargo_retry;
 use crate::eval_context::Config;
 
 /// Returns the library names for the direct dependencies of the crate rooted at
 /// the specified path.
 pub(crate) fn get_library_names(config: &Config) -> Result<Vec<String>> {
-    let output = config
-        .cargo_command("metadata")
-        .arg("--format-version")
-        .arg("1")
-        .output()
-        .with_context(|| "Error running cargo metadata")?;
+    let output = cargo_retry::output_with_retries(
+        config
+            .cargo_command("metadata")
+            .arg("--format-version")
+            .arg("1"),
+        config,
+        &|_| {},
+    )
+    .with_context(|| "Error running cargo metadata")?;
     if output.status.success() {
         library_names_from_metadata(std::str::from_utf8(&output.stdout)?)
     } else {
@@ -62,8 +67,17 @@ pub(crate) fn validate_dep(dep: &str, dep_config: &str, config: &Config) -> Resu
             dep, dep_config
         ),
     )?;
+    // Running cargo metadata on our dummy package rewrites Cargo.lock, which would discard any
+    // versions that were pinned for the real crate. So we put it back afterwards.
+    let lock_path = config.crate_dir.join("Cargo.lock");
+    let saved_lock = std::fs::read(&lock_path).ok();
     let mut cmd = config.cargo_command("metadata");
-    let output = cmd.arg("-q").arg("--format-version=1").output()?;
+    let output =
+        cargo_retry::output_with_retries(cmd.arg("-q").arg("--format-version=1"), config, &|_| {});
+    if let Some(saved_lock) = saved_lock {
+        std::fs::write(&lock_path, saved_lock)?;
+    }
+    let output = output?;
     if output.status.success() {
         Ok(())
     } else {
@@ -85,6 +99,120 @@ pub(crate) fn validate_dep(dep: &str, dep_config: &str, config: &Config) -> Resu
     }
 }
 
+/// Returns the output of `cargo tree` for the crate in `config.crate_dir`, followed by how many
+/// crates it depends on. If `package` is given, the tree is inverted, showing what depends on it.
+pub(crate) fn dependency_tree(config: &Config, package: Option<&str>) -> Result<String> {
+    let mut command = config.cargo_command("tree");
+    if let Some(package) = package {
+        command.arg("--invert").arg(package);
+    }
+    let output = cargo_retry::output_with_retries(&mut command, config, &|_| {})
+        .with_context(|| "Error running cargo tree")?;
+    if !output.status.success() {
+        bail!(
+            "cargo tree failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let tree = String::from_utf8(output.stdout)?;
+    if package.is_some() {
+        return Ok(tree);
+    }
+    let count = count_packages(&tree);
+    Ok(format!(
+        "{}\n{} {} in total",
+        tree.trim_end(),
+        count,
+        if count == 1 { "crate" } else { "crates" }
+    ))
+}
+
+/// Returns how many distinct packages, other than the root, appear in the output of `cargo tree`.
+fn count_packages(tree: &str) -> usize {
+    let mut packages = HashSet::new();
+    for line in tree.lines().skip(1) {
+        let line = line.trim_start_matches(|c: char| "│├└─ |`-".contains(c));
+        // Lines like `[build-dependencies]` introduce a kind of dependency.
+        if line.starts_with('[') {
+            continue;
+        }
+        let mut words = line.split(' ');
+        if let (Some(name), Some(version)) = (words.next(), words.next()) {
+            packages.insert((name, version));
+        }
+    }
+    packages.len()
+}
+
+/// The features of one version of a package that's in the dependency graph.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PackageFeatures {
+    pub(crate) version: String,
+    /// The features that are enabled, by any crate that depends on the package.
+    pub(crate) enabled: Vec<String>,
+    /// All the features the package declares, including optional dependencies.
+    pub(crate) available: Vec<String>,
+}
+
+/// Returns the features of each version of `package` that the crate in `config.crate_dir` depends
+/// on, directly or indirectly.
+pub(crate) fn package_features(config: &Config, package: &str) -> Result<Vec<PackageFeatures>> {
+    let output = cargo_retry::output_with_retries(
+        config
+            .cargo_command("metadata")
+            .arg("--format-version")
+            .arg("1"),
+        config,
+        &|_| {},
+    )
+    .with_context(|| "Error running cargo metadata")?;
+    if !output.status.success() {
+        bail!(
+            "cargo metadata failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let features = features_from_metadata(std::str::from_utf8(&output.stdout)?, package)?;
+    if features.is_empty() {
+        bail!("`{}` isn't among the dependencies", package);
+    }
+    Ok(features)
+}
+
+fn features_from_metadata(metadata: &str, package: &str) -> Result<Vec<PackageFeatures>> {
+    let metadata = json::parse(metadata)?;
+    let mut result = Vec::new();
+    for node in metadata["resolve"]["nodes"].members() {
+        let id = node["id"].as_str().unwrap_or_default();
+        let metadata_package = match metadata["packages"]
+            .members()
+            .find(|metadata_package| metadata_package["id"] == id)
+        {
+            Some(metadata_package) if metadata_package["name"] == package => metadata_package,
+            _ => continue,
+        };
+        let mut enabled: Vec<String> = node["features"]
+            .members()
+            .filter_map(|feature| feature.as_str().map(str::to_owned))
+            .collect();
+        enabled.sort();
+        let mut available: Vec<String> = metadata_package["features"]
+            .entries()
+            .map(|(feature, _)| feature.to_owned())
+            .collect();
+        available.sort();
+        result.push(PackageFeatures {
+            version: metadata_package["version"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            enabled,
+            available,
+        });
+    }
+    Ok(result)
+}
+
 fn library_names_from_metadata(metadata: &str) -> Result<Vec<String>> {
     let metadata = json::parse(metadata)?;
     let mut direct_dependencies = Vec::new();
@@ -134,8 +262,12 @@ fn library_names_from_metadata(metadata: &str) -> Result<Vec<String>> {
 mod tests {
     use crate::eval_context::Config;
 
+    use super::count_packages;
+    use super::dependency_tree;
     use super::get_library_names;
     use super::library_names_from_metadata;
+    use super::package_features;
+    use super::PackageFeatures;
     use anyhow::Result;
     use std::path::Path;
     use tempfile;
@@ -214,4 +346,64 @@ mod tests {
             .contains("no_such_feature"));
         Ok(())
     }
+
+    #[test]
+    fn packages_in_tree() {
+        assert_eq!(
+            count_packages(
+                "ctx v1.0.0 (/tmp/ctx)\n\
+                 ├── regex v1.10.2\n\
+                 │   ├── aho-corasick v1.1.2\n\
+                 │   │   └── memchr v2.6.4\n\
+                 │   └── memchr v2.6.4\n\
+                 └── serde v1.0.193\n\
+                 [build-dependencies]\n\
+                 └── cc v1.0.83\n"
+            ),
+            5
+        );
+        assert_eq!(
+            count_packages("ctx v1.0.0 (/tmp/ctx)\n`-- libc v0.2.150\n"),
+            1
+        );
+    }
+
+    #[test]
+    fn tree_and_features() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let crate1 = tempdir.path().join("crate1");
+        let crate2 = tempdir.path().join("crate2");
+        create_crate(&crate1, "crate1", "")?;
+        let manifest = crate1.join("Cargo.toml");
+        let mut contents = std::fs::read_to_string(&manifest)?;
+        contents.push_str("\n[features]\ndefault = [\"a\"]\na = []\nb = []\nc = []\n");
+        std::fs::write(&manifest, contents)?;
+        create_crate(
+            &crate2,
+            "crate2",
+            &format!(
+                r#"crate1 = {{ path = "{}", features = ["b"] }}"#,
+                path_to_string(&crate1)
+            ),
+        )?;
+        let config = Config::new(crate2.to_owned());
+        let tree = dependency_tree(&config, None)?;
+        assert!(tree.contains("crate1 v0.0.1"));
+        assert!(tree.ends_with("1 crate in total"));
+        assert_eq!(
+            package_features(&config, "crate1")?,
+            vec![PackageFeatures {
+                version: "0.0.1".to_owned(),
+                enabled: vec!["a".to_owned(), "b".to_owned(), "default".to_owned()],
+                available: vec![
+                    "a".to_owned(),
+                    "b".to_owned(),
+                    "c".to_owned(),
+                    "default".to_owned()
+                ],
+            }]
+        );
+        assert!(package_features(&config, "crate3").is_err());
+        Ok(())
+    }
 }
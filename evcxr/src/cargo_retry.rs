@@ -0,0 +1,190 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrying cargo when it fails for reasons that are likely to go away by themselves, such as a
+//! registry timing out or another cargo process holding a lock that we need. Also streaming
+//! cargo's stderr as it's written, for `:build_log`.
+
+use crate::eval_context::Config;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::process::Command;
+use std::process::Output;
+use std::process::Stdio;
+use std::time::Duration;
+
+/// How long we wait before the first retry. Each subsequent retry waits twice as long.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Messages in cargo's stderr that indicate a transient failure, together with a description of
+/// the kind of failure.
+const TRANSIENT_FAILURES: &[(&str, &str)] = &[
+    ("spurious network error", "network error"),
+    ("failed to download", "network error"),
+    ("failed to fetch", "network error"),
+    ("[28] Timeout was reached", "registry timeout"),
+    ("operation timed out", "registry timeout"),
+    ("Couldn't resolve host", "network error"),
+    ("[35] SSL connect error", "network error"),
+    ("Connection reset by peer", "network error"),
+    (
+        "failed to acquire package cache lock",
+        "another cargo process holds a lock",
+    ),
+    (
+        "could not acquire package cache lock",
+        "another cargo process holds a lock",
+    ),
+    ("failed to lock file", "another cargo process holds a lock"),
+    ("Text file busy", "file in use"),
+    ("(os error 32)", "file in use"),
+    ("Resource temporarily unavailable", "file in use"),
+];
+
+/// Messages that mean that a failure isn't transient, even though it also matches one of
+/// `TRANSIENT_FAILURES`. For example, a dependency that doesn't exist makes downloading fail.
+const PERMANENT_FAILURES: &[&str] = &["no matching package named", "failed to select a version"];
+
+/// Returns a description of why cargo failed, if its stderr suggests that retrying might succeed.
+pub(crate) fn transient_failure(stderr: &str) -> Option<&'static str> {
+    if PERMANENT_FAILURES
+        .iter()
+        .any(|message| stderr.contains(message))
+    {
+        return None;
+    }
+    TRANSIENT_FAILURES
+        .iter()
+        .find(|(message, _)| stderr.contains(message))
+        .map(|(_, description)| *description)
+}
+
+/// Returns how long to wait before retry number `retry`, which starts from 1.
+fn backoff(retry: u32) -> Duration {
+    INITIAL_BACKOFF * 2u32.saturating_pow(retry.saturating_sub(1))
+}
+
+/// Runs `command`, which should run cargo, retrying up to `config.cargo_retries` times if it fails
+/// for what looks like a transient reason. Each retry is reported via `config.status_sender`. If
+/// `config.build_log` is set, each line of cargo's stderr is passed to `build_output` as soon as
+/// cargo writes it.
+pub(crate) fn output_with_retries(
+    command: &mut Command,
+    config: &Config,
+    build_output: &dyn Fn(&str),
+) -> std::io::Result<Output> {
+    let mut retry = 0;
+    loop {
+        let output = if config.build_log {
+            streamed_output(command, build_output)?
+        } else {
+            command.output()?
+        };
+        if output.status.success() || retry >= config.cargo_retries {
+            return Ok(output);
+        }
+        let reason = match transient_failure(&String::from_utf8_lossy(&output.stderr)) {
+            Some(reason) => reason,
+            None => return Ok(output),
+        };
+        retry += 1;
+        let delay = backoff(retry);
+        if let Some(sender) = &config.status_sender {
+            let _ = sender.send(format!(
+                "Cargo failed ({}). Retrying in {}s ({} of {})",
+                reason,
+                delay.as_secs(),
+                retry,
+                config.cargo_retries
+            ));
+        }
+        std::thread::sleep(delay);
+    }
+}
+
+/// Like `Command::output`, but passes each line of stderr to `on_line` while the command runs.
+/// Stdout is read on another thread, so that the command can't block on either pipe being full.
+fn streamed_output(command: &mut Command, on_line: &dyn Fn(&str)) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    let stdout_thread = std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        stdout.read_to_end(&mut bytes).map(|_| bytes)
+    });
+    let mut stderr = Vec::new();
+    let mut reader = BufReader::new(child.stderr.take().unwrap());
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? != 0 {
+        on_line(String::from_utf8_lossy(&line).trim_end());
+        stderr.append(&mut line);
+    }
+    let status = child.wait()?;
+    let stdout = match stdout_thread.join() {
+        Ok(stdout) => stdout?,
+        Err(_) => Vec::new(),
+    };
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::backoff;
+    use super::transient_failure;
+    use std::time::Duration;
+
+    #[test]
+    fn classify() {
+        assert_eq!(
+            transient_failure(
+                "warning: spurious network error (2 tries remaining): [28] Timeout was reached"
+            ),
+            Some("network error")
+        );
+        assert_eq!(
+            transient_failure("error: failed to acquire package cache lock"),
+            Some("another cargo process holds a lock")
+        );
+        assert_eq!(
+            transient_failure("error: failed to remove file: Text file busy (os error 26)"),
+            Some("file in use")
+        );
+        assert_eq!(
+            transient_failure(
+                "error: no matching package named `no_such_crate` found\n\
+                 failed to fetch `https://github.com/rust-lang/crates.io-index`"
+            ),
+            None
+        );
+        assert_eq!(
+            transient_failure("error[E0425]: cannot find value `x`"),
+            None
+        );
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(3), Duration::from_secs(4));
+    }
+}
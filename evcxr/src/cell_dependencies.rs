@@ -0,0 +1,316 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracks which variables each cell that ran defines, updates and reads, so that
+//! `:rerun_dependents` can evaluate the cells that depend on a variable again once it has changed.
+//! Like `cell_diff`, this only looks at the syntax of the code, without doing name resolution. A
+//! cell reads a variable if the variable's name appears anywhere outside the patterns of its `let`s.
+//! It updates a variable if it assigns to the variable or part of it, or borrows it mutably. Calling
+//! a method on a variable that was declared `mut` might update it, so we assume that it does when
+//! working out what else changed.
+
+use crate::code_block::CodeBlock;
+use crate::code_block::CodeKind;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxNode;
+use std::collections::HashSet;
+
+/// The variables that a cell defines, updates and reads.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CellVariables {
+    pub(crate) defines: HashSet<String>,
+    /// Variables that are assigned to or borrowed mutably.
+    pub(crate) updates: HashSet<String>,
+    /// Variables declared `mut` that methods are called on.
+    pub(crate) may_update: HashSet<String>,
+    pub(crate) reads: HashSet<String>,
+}
+
+impl CellVariables {
+    /// Analyzes `code`, a cell as the user wrote it. `mutable` holds the variables whose most
+    /// recent definition was `mut`, and is updated with the cell's definitions. Returns the code
+    /// without any commands, together with its variables, or None if there's only commands.
+    pub(crate) fn analyze<'a>(
+        code: &'a str,
+        mutable: &mut HashSet<String>,
+    ) -> Option<(&'a str, CellVariables)> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let mut code_without_commands = None;
+        let mut variables = CellVariables::default();
+        for segment in &user_code.segments {
+            if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+                if code_without_commands.is_none() {
+                    code_without_commands = Some(code[meta.start_byte..].trim());
+                }
+                variables.analyze_statement(&code_info.nodes[meta.node_index], mutable);
+            }
+        }
+        code_without_commands
+            .filter(|code| !code.is_empty())
+            .map(|code| (code, variables))
+    }
+
+    /// Returns the variables that the cell defines or assigns to.
+    pub(crate) fn outputs(&self) -> impl Iterator<Item = &String> {
+        self.defines.iter().chain(&self.updates)
+    }
+
+    fn analyze_statement(&mut self, node: &SyntaxNode, mutable: &mut HashSet<String>) {
+        if ast::Item::can_cast(node.kind()) {
+            // Items can't refer to variables.
+            return;
+        }
+        let let_stmt = match ast::LetStmt::cast(node.clone()) {
+            Some(let_stmt) => let_stmt,
+            None => {
+                self.analyze_code(node, mutable);
+                return;
+            }
+        };
+        let pat = let_stmt.pat();
+        for child in let_stmt.syntax().children() {
+            if pat.as_ref().map_or(true, |pat| pat.syntax() != &child) {
+                self.analyze_code(&child, mutable);
+            }
+        }
+        // The initializer is analyzed first, since in e.g. `let x = x + 1;` it reads the old `x`.
+        for ident in pat
+            .iter()
+            .flat_map(|pat| pat.syntax().descendants())
+            .filter_map(ast::IdentPat::cast)
+        {
+            if let Some(name) = ast::HasName::name(&ident) {
+                let name = name.text().to_string();
+                if ident.mut_token().is_some() {
+                    mutable.insert(name.clone());
+                } else {
+                    mutable.remove(&name);
+                }
+                self.defines.insert(name);
+            }
+        }
+    }
+
+    fn analyze_code(&mut self, node: &SyntaxNode, mutable: &HashSet<String>) {
+        // Identifiers are taken from tokens rather than from paths, so that we see those in the
+        // arguments of macros, e.g. `println!`.
+        self.reads.extend(
+            node.descendants_with_tokens()
+                .filter_map(|element| element.into_token())
+                .filter(|token| token.kind() == SyntaxKind::IDENT)
+                .map(|token| token.text().to_owned()),
+        );
+        for descendant in node.descendants() {
+            if let Some(bin_expr) = ast::BinExpr::cast(descendant.clone()) {
+                if let Some(ast::BinaryOp::Assignment { .. }) = bin_expr.op_kind() {
+                    self.updates.extend(bin_expr.lhs().and_then(root_variable));
+                }
+            } else if let Some(ref_expr) = ast::RefExpr::cast(descendant.clone()) {
+                if ref_expr.mut_token().is_some() {
+                    self.updates.extend(ref_expr.expr().and_then(root_variable));
+                }
+            } else if let Some(call) = ast::MethodCallExpr::cast(descendant) {
+                if let Some(name) = call.receiver().and_then(root_variable) {
+                    if mutable.contains(&name) {
+                        self.may_update.insert(name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The cells that have run successfully, in order.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CellHistory {
+    cells: Vec<Cell>,
+    /// Variables whose most recent definition was `mut`.
+    mutable: HashSet<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Cell {
+    /// The code of the cell, without any commands.
+    code: String,
+    variables: CellVariables,
+}
+
+impl CellHistory {
+    /// Records a cell that ran successfully. Cells that only contain commands aren't recorded.
+    pub(crate) fn record(&mut self, code: &str) {
+        if let Some((code, variables)) = CellVariables::analyze(code, &mut self.mutable) {
+            self.cells.push(Cell {
+                code: code.to_owned(),
+                variables,
+            });
+        }
+    }
+
+    /// Returns the code of the cells that depend on `variable`, in the order that they ran. These
+    /// are the cells that read it, directly or via other variables, apart from cells that define or
+    /// update it, since running those again would undo the change. If a cell that depends on it
+    /// updates some other variable, e.g. with `+=`, the cells that built that variable's current
+    /// value are included too, so that the update isn't applied twice.
+    pub(crate) fn dependents(&self, variable: &str) -> Vec<&str> {
+        let is_source = |cell: &Cell| {
+            cell.variables.defines.contains(variable) || cell.variables.updates.contains(variable)
+        };
+        let mut rerun = vec![false; self.cells.len()];
+        // Including a cell can mean that earlier cells need to be included, so we repeat until
+        // nothing more is included.
+        loop {
+            let mut changed = HashSet::from([variable]);
+            let mut included_more = false;
+            for (index, cell) in self.cells.iter().enumerate() {
+                if is_source(cell) {
+                    continue;
+                }
+                let variables = &cell.variables;
+                if !rerun[index]
+                    && variables
+                        .reads
+                        .iter()
+                        .any(|name| changed.contains(name.as_str()))
+                {
+                    rerun[index] = true;
+                    included_more = true;
+                }
+                if !rerun[index] {
+                    continue;
+                }
+                changed.extend(
+                    variables
+                        .outputs()
+                        .chain(&variables.may_update)
+                        .map(String::as_str),
+                );
+                for name in variables.updates.difference(&variables.defines) {
+                    for earlier in self.cells_building(name, index) {
+                        if !rerun[earlier] && !is_source(&self.cells[earlier]) {
+                            rerun[earlier] = true;
+                            included_more = true;
+                        }
+                    }
+                }
+            }
+            if !included_more {
+                break;
+            }
+        }
+        self.cells
+            .iter()
+            .zip(rerun)
+            .filter(|(_, rerun)| *rerun)
+            .map(|(cell, _)| cell.code.as_str())
+            .collect()
+    }
+
+    /// Returns the indexes of the cells before `end` that built the value of `name` at that point,
+    /// i.e. the last one that defined it and any after that that updated it.
+    fn cells_building(&self, name: &str, end: usize) -> Vec<usize> {
+        let start = match self.cells[..end]
+            .iter()
+            .rposition(|cell| cell.variables.defines.contains(name))
+        {
+            Some(start) => start,
+            None => return Vec::new(),
+        };
+        (start..end)
+            .filter(|index| *index == start || self.cells[*index].variables.updates.contains(name))
+            .collect()
+    }
+}
+
+/// Returns the variable that `expr` refers to or to part of, e.g. `a` for `a.b[0]`.
+fn root_variable(expr: ast::Expr) -> Option<String> {
+    match expr {
+        ast::Expr::PathExpr(path_expr) => path_expr
+            .path()?
+            .as_single_name_ref()
+            .map(|name| name.text().to_string()),
+        ast::Expr::FieldExpr(field_expr) => root_variable(field_expr.expr()?),
+        ast::Expr::IndexExpr(index_expr) => root_variable(index_expr.base()?),
+        ast::Expr::MethodCallExpr(call) => root_variable(call.receiver()?),
+        ast::Expr::ParenExpr(paren_expr) => root_variable(paren_expr.expr()?),
+        ast::Expr::PrefixExpr(prefix_expr) => root_variable(prefix_expr.expr()?),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CellHistory;
+
+    fn history(cells: &[&str]) -> CellHistory {
+        let mut history = CellHistory::default();
+        for cell in cells {
+            history.record(cell);
+        }
+        history
+    }
+
+    #[test]
+    fn test_dependents_in_order() {
+        let history = history(&[
+            "let x = 1;",
+            ":dep regex\nlet y = x * 2;",
+            "fn f() -> i32 { 1 }",
+            "let z = y + f();",
+            "println!(\"{}\", z);",
+            "let unrelated = 3;",
+            "let x = 10;",
+        ]);
+        assert_eq!(
+            history.dependents("x"),
+            vec!["let y = x * 2;", "let z = y + f();", "println!(\"{}\", z);"]
+        );
+        assert!(history.dependents("unrelated").is_empty());
+    }
+
+    #[test]
+    fn test_updates() {
+        let history = history(&[
+            "let x = 1;",
+            "let mut total = 0;",
+            "total += 5;",
+            "total += x;",
+            "let mut v = Vec::new();",
+            "v.push(x);",
+            "println!(\"{:?}\", v);",
+            "x = 2;",
+        ]);
+        // Updating `total` again would count 5 twice, so it's built again from its definition.
+        // The cell that assigns to `x` isn't run again, since that would undo the change.
+        assert_eq!(
+            history.dependents("x"),
+            vec![
+                "let mut total = 0;",
+                "total += 5;",
+                "total += x;",
+                "v.push(x);",
+                "println!(\"{:?}\", v);"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shadowing_reads_old_value() {
+        let history = history(&["let a = 1;", "let a = a + 1;", "let b = a;"]);
+        assert_eq!(history.dependents("b"), Vec::<&str>::new());
+        // The second cell defines `a`, so isn't run again.
+        assert_eq!(history.dependents("a"), vec!["let b = a;"]);
+    }
+}
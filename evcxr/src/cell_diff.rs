@@ -0,0 +1,303 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for re-evaluating a cell after it has been edited. We compare the statements of the
+//! cell before and after the edit and only evaluate what's needed: items whose definitions
+//! changed, plus statements that changed or that refer to something that changed. We don't do
+//! any real name resolution, so a statement is considered to refer to a name if it contains that
+//! name as an identifier anywhere.
+
+use crate::code_block::CodeBlock;
+use crate::code_block::CodeKind;
+use crate::dev_crate::type_references_any;
+use crate::item;
+use crate::EvalOutputs;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// The result of re-evaluating an edited cell. See `EvalContext::reeval_edited`.
+#[derive(Debug)]
+pub struct CellReeval {
+    /// Outputs from evaluating the parts of the cell that needed to be evaluated.
+    pub outputs: EvalOutputs,
+    /// Items defined by the cell that were added or whose definitions changed.
+    pub changed_items: Vec<String>,
+    /// Items that the cell used to define, but no longer does. They've been removed.
+    pub removed_items: Vec<String>,
+    /// Variables defined by the cell that were evaluated again.
+    pub rerun_variables: Vec<String>,
+    /// Items defined elsewhere that refer to an item that changed or was removed. All items are
+    /// recompiled on each evaluation, so these are already using the new definitions, but might
+    /// need to be updated.
+    pub invalidated_items: Vec<String>,
+    /// Variables defined elsewhere whose type refers to an item that changed or was removed. Their
+    /// values may no longer match their type, so they've been dropped.
+    pub invalidated_variables: Vec<String>,
+    /// Variables defined by other cells whose definitions refer to something that changed. They
+    /// still hold values computed from the old definitions. Re-evaluate those cells to update them.
+    pub stale_variables: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DefinitionKind {
+    /// A named item, together with any unnamed items (e.g. impls) that immediately follow it.
+    Item(String),
+    /// An item without a name, e.g. an impl that isn't preceded by a named item.
+    UnnamedItem,
+    /// A let statement and the names of the variables that it defines.
+    Let(Vec<String>),
+    Statement,
+}
+
+#[derive(Debug)]
+struct Definition {
+    kind: DefinitionKind,
+    text: String,
+}
+
+/// Splits `code` into its top-level definitions and statements. Any evcxr commands are ignored.
+fn definitions(code: &str) -> Vec<Definition> {
+    let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+    let mut definitions: Vec<Definition> = Vec::new();
+    for segment in &user_code.segments {
+        let node = if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+            &code_info.nodes[meta.node_index]
+        } else {
+            continue;
+        };
+        let text = segment.code.trim().to_owned();
+        let kind = if let Some(item) = ast::Item::cast(node.clone()) {
+            match item::item_name(&item) {
+                Some(name) => DefinitionKind::Item(name),
+                None => {
+                    // Keep unnamed items with the preceding named item, as happens when they're
+                    // evaluated.
+                    if let Some(Definition {
+                        kind: DefinitionKind::Item(_),
+                        text: item_text,
+                    }) = definitions.last_mut()
+                    {
+                        item_text.push('\n');
+                        item_text.push_str(&text);
+                        continue;
+                    }
+                    DefinitionKind::UnnamedItem
+                }
+            }
+        } else if let Some(let_stmt) = ast::LetStmt::cast(node.clone()) {
+            let names = let_stmt
+                .pat()
+                .map(|pat| {
+                    pat.syntax()
+                        .descendants()
+                        .filter_map(ast::IdentPat::cast)
+                        .filter_map(|ident| ast::HasName::name(&ident))
+                        .map(|name| name.text().to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+            DefinitionKind::Let(names)
+        } else {
+            DefinitionKind::Statement
+        };
+        definitions.push(Definition { kind, text });
+    }
+    definitions
+}
+
+/// What needs to happen to bring the state up to date with an edited cell.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct CellDiff {
+    /// The parts of the edited cell that need to be evaluated.
+    pub(crate) code: String,
+    pub(crate) changed_items: Vec<String>,
+    pub(crate) removed_items: Vec<String>,
+    /// The text of unnamed items that the cell no longer contains.
+    pub(crate) removed_unnamed_items: Vec<String>,
+    pub(crate) rerun_variables: Vec<String>,
+}
+
+impl CellDiff {
+    /// Returns the names of everything that changed or was removed.
+    pub(crate) fn changed_names(&self) -> HashSet<String> {
+        self.changed_items
+            .iter()
+            .chain(&self.removed_items)
+            .chain(&self.rerun_variables)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the names of items that changed or were removed.
+    pub(crate) fn changed_item_names(&self) -> HashSet<String> {
+        self.changed_items
+            .iter()
+            .chain(&self.removed_items)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Works out how to get from a cell containing `old_code` to one containing `new_code`.
+pub(crate) fn diff(old_code: &str, new_code: &str) -> CellDiff {
+    let old = definitions(old_code);
+    let new = definitions(new_code);
+    let mut old_items = HashMap::new();
+    let mut old_texts = HashSet::new();
+    for definition in &old {
+        match &definition.kind {
+            DefinitionKind::Item(name) => {
+                old_items.insert(name.as_str(), definition.text.as_str());
+            }
+            _ => {
+                old_texts.insert(definition.text.as_str());
+            }
+        }
+    }
+    let mut diff = CellDiff::default();
+    // Items can be referred to before they're defined, so we find all changed items first.
+    let mut changed = HashSet::new();
+    for definition in &new {
+        if let DefinitionKind::Item(name) = &definition.kind {
+            if old_items.get(name.as_str()) != Some(&definition.text.as_str()) {
+                changed.insert(name.clone());
+                diff.changed_items.push(name.clone());
+            }
+        }
+    }
+    let new_items: HashSet<&str> = new
+        .iter()
+        .filter_map(|definition| match &definition.kind {
+            DefinitionKind::Item(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    for definition in &old {
+        match &definition.kind {
+            DefinitionKind::Item(name) if !new_items.contains(name.as_str()) => {
+                changed.insert(name.clone());
+                diff.removed_items.push(name.clone());
+            }
+            DefinitionKind::UnnamedItem
+                if !new.iter().any(|new_def| new_def.text == definition.text) =>
+            {
+                diff.removed_unnamed_items.push(definition.text.clone());
+            }
+            _ => {}
+        }
+    }
+    let mut to_eval = Vec::new();
+    for definition in &new {
+        let include = match &definition.kind {
+            DefinitionKind::Item(name) => changed.contains(name),
+            DefinitionKind::UnnamedItem => !old_texts.contains(definition.text.as_str()),
+            DefinitionKind::Let(_) | DefinitionKind::Statement => {
+                !old_texts.contains(definition.text.as_str())
+                    || type_references_any(&definition.text, &changed)
+            }
+        };
+        if !include {
+            continue;
+        }
+        if let DefinitionKind::Let(names) = &definition.kind {
+            // Later statements that use these variables need to be evaluated again too.
+            changed.extend(names.iter().cloned());
+            diff.rerun_variables.extend(names.iter().cloned());
+        }
+        to_eval.push(definition.text.as_str());
+    }
+    diff.code = to_eval.join("\n");
+    diff
+}
+
+/// Returns the names of variables defined in `code` by statements that refer to any of `names`.
+pub(crate) fn variables_referring_to(code: &str, names: &HashSet<String>) -> Vec<String> {
+    definitions(code)
+        .into_iter()
+        .filter_map(|definition| match definition.kind {
+            DefinitionKind::Let(variables) if type_references_any(&definition.text, names) => {
+                Some(variables)
+            }
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff;
+    use super::variables_referring_to;
+
+    #[test]
+    fn test_first_evaluation_includes_everything() {
+        let diff = diff("", "struct Foo;\nlet a = 1;\na + 1");
+        assert_eq!(diff.code, "struct Foo;\nlet a = 1;\na + 1");
+        assert_eq!(diff.changed_items, vec!["Foo"]);
+        assert_eq!(diff.rerun_variables, vec!["a"]);
+    }
+
+    #[test]
+    fn test_only_changes_and_dependents_are_evaluated() {
+        let old =
+            "fn f() -> i32 { 1 }\nlet a = f();\nlet b = 2;\nlet c = a + b;\nprintln!(\"hi\");";
+        let new =
+            "fn f() -> i32 { 10 }\nlet a = f();\nlet b = 2;\nlet c = a + b;\nprintln!(\"hi\");";
+        let diff = diff(old, new);
+        assert_eq!(
+            diff.code,
+            "fn f() -> i32 { 10 }\nlet a = f();\nlet c = a + b;"
+        );
+        assert_eq!(diff.changed_items, vec!["f"]);
+        assert_eq!(diff.rerun_variables, vec!["a", "c"]);
+        assert!(diff.removed_items.is_empty());
+    }
+
+    #[test]
+    fn test_impls_stay_with_their_type() {
+        let old = "struct Foo;\nimpl Foo { fn x(&self) -> i32 { 1 } }\nfn g() {}";
+        let new = "struct Foo;\nimpl Foo { fn x(&self) -> i32 { 2 } }";
+        let diff = diff(old, new);
+        assert_eq!(
+            diff.code,
+            "struct Foo;\nimpl Foo { fn x(&self) -> i32 { 2 } }"
+        );
+        assert_eq!(diff.changed_items, vec!["Foo"]);
+        assert_eq!(diff.removed_items, vec!["g"]);
+        assert_eq!(
+            diff.changed_names(),
+            ["Foo", "g"].iter().map(|s| s.to_string()).collect()
+        );
+    }
+
+    #[test]
+    fn test_unchanged_cell_does_nothing() {
+        let code = "struct Foo;\nlet a = 1;\na";
+        assert_eq!(diff(code, code).code, "");
+    }
+
+    #[test]
+    fn test_variables_referring_to() {
+        let names = ["Foo".to_owned()].into_iter().collect();
+        assert_eq!(
+            variables_referring_to(
+                "let x = Foo::new();\nlet (y, z) = (Foo, 1);\nlet w = 2;",
+                &names
+            ),
+            vec!["x", "y", "z"]
+        );
+    }
+}
@@ -14,24 +14,78 @@
 
 use crate::errors::bail;
 use crate::errors::Error;
+use crate::evcxr_internal_runtime;
+use crate::jobs::Jobs;
+use crate::limits::ResourceLimits;
+use crate::limits::ALLOCATION_FAILURE_PREFIX;
+use crate::panic_report::PanicRewriter;
+use crate::panic_report::PanicSource;
+use crate::panic_report::SharedPanicSource;
 use crate::runtime;
+use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
 use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+/// How to (re)start the process that runs user code.
+#[derive(Clone)]
+enum Launcher {
+    Command(Arc<Mutex<process::Command>>),
+    /// Connect to a remote runtime that's listening on this address.
+    Tcp(String),
+}
+
+/// Something that the process wrote to stdout.
+pub(crate) enum Output {
+    Line(String),
+    /// Content of a binary mime type, e.g. `image/png`. It's sent as raw bytes, preceded by a line
+    /// with their length, so that it doesn't need to be encoded as text.
+    Binary {
+        mime_type: String,
+        data: Vec<u8>,
+    },
+}
 
 pub(crate) struct ChildProcess {
-    process: std::process::Child,
-    stdout: std::io::Lines<BufReader<std::process::ChildStdout>>,
+    /// None if we're connected to a runtime on another machine via TCP.
+    process: Option<std::process::Child>,
+    /// Output written to stdout, other than output from background jobs.
+    stdout: crossbeam_channel::Receiver<std::io::Result<Output>>,
     // Only none while in drop.
-    stdin: Option<std::process::ChildStdin>,
-    command: Arc<Mutex<process::Command>>,
+    stdin: Option<Box<dyn Write + Send>>,
+    launcher: Launcher,
+    /// Where we send output from background jobs.
+    stdout_sender: crossbeam_channel::Sender<String>,
     stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+    jobs: Arc<Mutex<Jobs>>,
+    /// The code that's running, so that panics written to stderr can refer to the user's cells.
+    panic_source: SharedPanicSource,
+    /// Whether user code is being run in response to a command from `start_execution`.
+    executing: Arc<AtomicBool>,
+    /// Limits applied to the process, so that we can report if one of them was the reason it
+    /// terminated.
+    limits: ResourceLimits,
+    /// Set if the process reported that an allocation failed.
+    allocation_failed: Arc<AtomicBool>,
+    /// How many bytes we've written to the process's stdin.
+    bytes_sent: usize,
+    /// The job object that the local process is in. It enforces `limits` and ensures that the
+    /// process is killed when we exit, however that happens. None if the process couldn't be put in
+    /// a job object, which can happen if we're in a job ourselves on Windows versions before 8.
+    #[cfg(windows)]
+    job: Option<JobObject>,
 }
 
 impl ChildProcess {
     pub(crate) fn new(
         mut command: std::process::Command,
+        stdout_sender: crossbeam_channel::Sender<String>,
         stderr_sender: crossbeam_channel::Sender<String>,
     ) -> Result<ChildProcess, Error> {
         // Avoid a fork bomb. We could call runtime_hook here but then all the work that we did up
@@ -46,71 +100,234 @@ impl ChildProcess {
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped());
+        // Putting the process in its own process group means that Ctrl-C in our console isn't also
+        // sent to it, and that we can send it Ctrl-Break to interrupt it.
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            command.creation_flags(winapi::um::winbase::CREATE_NEW_PROCESS_GROUP);
+        }
         ChildProcess::new_internal(
-            Arc::new(Mutex::new(command)),
+            Launcher::Command(Arc::new(Mutex::new(command))),
+            stdout_sender,
             Arc::new(Mutex::new(stderr_sender)),
+            Arc::default(),
+            Arc::default(),
+        )
+    }
+
+    /// Connects to a remote runtime listening on `address`. Output that the remote process writes
+    /// to stderr stays on the remote machine.
+    pub(crate) fn connect(
+        address: &str,
+        stdout_sender: crossbeam_channel::Sender<String>,
+        stderr_sender: crossbeam_channel::Sender<String>,
+    ) -> Result<ChildProcess, Error> {
+        ChildProcess::new_internal(
+            Launcher::Tcp(address.to_owned()),
+            stdout_sender,
+            Arc::new(Mutex::new(stderr_sender)),
+            Arc::default(),
+            Arc::default(),
         )
     }
 
     fn new_internal(
-        command: Arc<Mutex<std::process::Command>>,
+        launcher: Launcher,
+        stdout_sender: crossbeam_channel::Sender<String>,
         stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+        jobs: Arc<Mutex<Jobs>>,
+        panic_source: SharedPanicSource,
     ) -> Result<ChildProcess, Error> {
+        let command = match &launcher {
+            Launcher::Command(command) => command,
+            Launcher::Tcp(address) => {
+                let stream = match std::net::TcpStream::connect(address) {
+                    Ok(stream) => stream,
+                    Err(error) => bail!("Failed to connect to runtime at {}: {}", address, error),
+                };
+                let reader: Box<dyn Read + Send> = Box::new(stream.try_clone()?);
+                let executing = Arc::new(AtomicBool::new(false));
+                return Ok(ChildProcess {
+                    process: None,
+                    stdout: route_stdout(reader, &stdout_sender, &jobs, &executing),
+                    stdin: Some(Box::new(stream)),
+                    launcher,
+                    stdout_sender,
+                    stderr_sender,
+                    jobs,
+                    panic_source,
+                    executing,
+                    limits: ResourceLimits::default(),
+                    allocation_failed: Arc::new(AtomicBool::new(false)),
+                    bytes_sent: 0,
+                    #[cfg(windows)]
+                    job: None,
+                });
+            }
+        };
         let process = command.lock().unwrap().spawn();
         let mut process = match process {
             Ok(c) => c,
             Err(error) => bail!("Failed to run '{:?}': {:?}", command, error),
         };
+        // The process could start processes of its own before it's in the job, but it doesn't do
+        // anything until we send it a command.
+        #[cfg(windows)]
+        let job = JobObject::for_process(&process).ok();
 
-        let stdout = std::io::BufRead::lines(BufReader::new(process.stdout.take().unwrap()));
+        let executing = Arc::new(AtomicBool::new(false));
+        let stdout = route_stdout(
+            Box::new(process.stdout.take().unwrap()),
+            &stdout_sender,
+            &jobs,
+            &executing,
+        );
 
         // Handle stderr by patching it through to a channel in our output struct.
         let mut child_stderr =
             std::io::BufRead::lines(BufReader::new(process.stderr.take().unwrap()));
+        let allocation_failed = Arc::new(AtomicBool::new(false));
         std::thread::spawn({
             let stderr_sender = Arc::clone(&stderr_sender);
+            let allocation_failed = Arc::clone(&allocation_failed);
+            let mut panic_rewriter = PanicRewriter::new(Arc::clone(&panic_source));
             move || {
                 let stderr_sender = stderr_sender.lock().unwrap();
                 while let Some(Ok(line)) = child_stderr.next() {
-                    // Ignore errors, since it just means that the user of the library has dropped the receive end.
-                    let _ = stderr_sender.send(line);
+                    if line.starts_with(ALLOCATION_FAILURE_PREFIX) {
+                        allocation_failed.store(true, Ordering::Relaxed);
+                    }
+                    for line in panic_rewriter.process_line(line) {
+                        // Ignore errors, since it just means that the user of the library has dropped the receive end.
+                        let _ = stderr_sender.send(line);
+                    }
                 }
             }
         });
 
-        let stdin = process.stdin.take();
+        let stdin = process
+            .stdin
+            .take()
+            .map(|stdin| -> Box<dyn Write + Send> { Box::new(stdin) });
         Ok(ChildProcess {
-            process,
+            process: Some(process),
             stdout,
             stdin,
-            command,
+            launcher,
+            stdout_sender,
             stderr_sender,
+            jobs,
+            panic_source,
+            executing,
+            limits: ResourceLimits::default(),
+            allocation_failed,
+            bytes_sent: 0,
+            #[cfg(windows)]
+            job,
         })
     }
 
+    /// Records the limits that were applied to the process's command, so that we can report when
+    /// they're exceeded. On Windows, this is also what applies them.
+    pub(crate) fn with_limits(mut self, limits: ResourceLimits) -> Result<ChildProcess, Error> {
+        #[cfg(windows)]
+        {
+            if !limits.is_empty() && self.process.is_some() {
+                match &self.job {
+                    Some(job) => job.set_limits(&limits)?,
+                    None => bail!("Couldn't apply resource limits without a job object"),
+                }
+            }
+        }
+        self.limits = limits;
+        Ok(self)
+    }
+
     /// Terminates this process if it hasn't already, then restarts
     pub(crate) fn restart(&mut self) -> Result<ChildProcess, Error> {
         // If the process hasn't already terminated for some reason, kill it.
-        if let Ok(None) = self.process.try_wait() {
-            let _ = self.process.kill();
-            let _ = self.process.wait();
+        if let Some(process) = self.process.as_mut() {
+            if let Ok(None) = process.try_wait() {
+                let _ = process.kill();
+                let _ = process.wait();
+            }
         }
-        ChildProcess::new_internal(Arc::clone(&self.command), Arc::clone(&self.stderr_sender))
+        // Closing our end of a TCP connection causes the remote process to exit.
+        self.stdin.take();
+        // Background jobs die with the process.
+        self.jobs.lock().unwrap().clear();
+        ChildProcess::new_internal(
+            self.launcher.clone(),
+            self.stdout_sender.clone(),
+            Arc::clone(&self.stderr_sender),
+            Arc::clone(&self.jobs),
+            Arc::clone(&self.panic_source),
+        )?
+        .with_limits(self.limits.clone())
+    }
+
+    /// Returns the ID of the local process, or 0 if the process is on another machine.
+    pub(crate) fn process_id(&self) -> u32 {
+        self.process.as_ref().map_or(0, std::process::Child::id)
     }
 
     pub(crate) fn send(&mut self, command: &str) -> Result<(), Error> {
-        use std::io::Write;
         writeln!(self.stdin.as_mut().unwrap(), "{}", command)
             .map_err(|_| self.get_termination_error())?;
         self.stdin.as_mut().unwrap().flush()?;
+        self.bytes_sent += command.len() + 1;
         Ok(())
     }
 
-    pub(crate) fn recv_line(&mut self) -> Result<String, Error> {
+    /// Records the code that's about to run, so that panics in it can be reported in terms of the
+    /// user's cells.
+    pub(crate) fn set_panic_source(&self, source: PanicSource) {
+        *self.panic_source.lock().unwrap() = Some(source);
+    }
+
+    /// Sends a command that runs user code. Until the runtime reports that execution is complete,
+    /// output is returned by `recv_line`.
+    pub(crate) fn start_execution(&mut self, command: &str) -> Result<(), Error> {
+        self.executing.store(true, Ordering::SeqCst);
+        self.send(command)
+    }
+
+    /// Sends raw bytes, e.g. the contents of a file following an UPLOAD command.
+    pub(crate) fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.stdin
+            .as_mut()
+            .unwrap()
+            .write_all(bytes)
+            .map_err(|_| self.get_termination_error())?;
+        self.stdin.as_mut().unwrap().flush()?;
+        self.bytes_sent += bytes.len();
+        Ok(())
+    }
+
+    pub(crate) fn bytes_sent(&self) -> usize {
+        self.bytes_sent
+    }
+
+    pub(crate) fn recv(&mut self) -> Result<Output, Error> {
         Ok(self
             .stdout
-            .next()
-            .ok_or_else(|| self.get_termination_error())??)
+            .recv()
+            .map_err(|_| self.get_termination_error())??)
+    }
+
+    /// Like `recv`, but for where only lines are expected, such as replies to commands. Any binary
+    /// content is skipped.
+    pub(crate) fn recv_line(&mut self) -> Result<String, Error> {
+        loop {
+            if let Output::Line(line) = self.recv()? {
+                return Ok(line);
+            }
+        }
+    }
+
+    pub(crate) fn jobs(&self) -> MutexGuard<'_, Jobs> {
+        self.jobs.lock().unwrap()
     }
 
     fn get_termination_error(&mut self) -> Error {
@@ -119,12 +336,42 @@ impl ChildProcess {
         // just wait until we can aquire it, then drop it straight away.
         std::mem::drop(self.stderr_sender.lock().unwrap());
         let mut content = String::new();
-        while let Some(Ok(line)) = self.stdout.next() {
-            content.push_str(&line);
-            content.push('\n');
+        while let Ok(Ok(output)) = self.stdout.recv() {
+            if let Output::Line(line) = output {
+                content.push_str(&line);
+                content.push('\n');
+            }
         }
-        Error::SubprocessTerminated(match self.process.wait() {
+        let process = match self.process.as_mut() {
+            Some(process) => process,
+            None => {
+                return Error::SubprocessTerminated(format!(
+                    "{}Connection to remote runtime was closed",
+                    content
+                ))
+            }
+        };
+        Error::SubprocessTerminated(match process.wait() {
             Ok(exit_status) => {
+                #[cfg(unix)]
+                let cpu_time_exceeded = crate::limits::is_cpu_limit_signal(
+                    std::os::unix::process::ExitStatusExt::signal(&exit_status),
+                );
+                // The job object doesn't say why it terminated the process, so we check how much
+                // CPU time the process used.
+                #[cfg(windows)]
+                let cpu_time_exceeded = match (self.limits.max_cpu_secs(), user_time(process)) {
+                    (Some(secs), Some(used)) => used >= std::time::Duration::from_secs(secs),
+                    _ => false,
+                };
+                #[cfg(not(any(unix, windows)))]
+                let cpu_time_exceeded = false;
+                if let Some(violation) = self.limits.describe_violation(
+                    cpu_time_exceeded,
+                    self.allocation_failed.load(Ordering::Relaxed),
+                ) {
+                    return Error::SubprocessTerminated(format!("{}{}", content, violation));
+                }
                 #[cfg(target_os = "macos")]
                 {
                     use std::os::unix::process::ExitStatusExt;
@@ -148,6 +395,86 @@ impl ChildProcess {
     }
 }
 
+/// Starts a thread that reads output from `stdout`. Output from background jobs, and anything
+/// written while user code isn't `executing`, which can only have come from a background thread,
+/// is sent straight to `stdout_sender`. Other output is returned via the receiver.
+fn route_stdout(
+    stdout: Box<dyn Read + Send>,
+    stdout_sender: &crossbeam_channel::Sender<String>,
+    jobs: &Arc<Mutex<Jobs>>,
+    executing: &Arc<AtomicBool>,
+) -> crossbeam_channel::Receiver<std::io::Result<Output>> {
+    // Bounded, so that if we're slow to consume output, the process blocks when it writes more.
+    let (output_sender, outputs) = crossbeam_channel::bounded(1000);
+    let stdout_sender = stdout_sender.clone();
+    let jobs = Arc::clone(jobs);
+    let executing = Arc::clone(executing);
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        loop {
+            let output = match read_output(&mut reader) {
+                Ok(Some(output)) => output,
+                Ok(None) => break,
+                Err(error) => {
+                    let _ = output_sender.send(Err(error));
+                    break;
+                }
+            };
+            match &output {
+                Output::Line(line) => {
+                    if let Some(job_output) = jobs.lock().unwrap().process_line(line) {
+                        let _ = stdout_sender.send(job_output);
+                        continue;
+                    }
+                    if line == runtime::EVCXR_EXECUTION_COMPLETE {
+                        executing.store(false, Ordering::SeqCst);
+                    } else if !executing.load(Ordering::SeqCst) {
+                        let _ = stdout_sender.send(line.clone());
+                        continue;
+                    }
+                }
+                // Content from a background thread has nowhere to be displayed.
+                Output::Binary { .. } if !executing.load(Ordering::SeqCst) => continue,
+                Output::Binary { .. } => {}
+            }
+            if output_sender.send(Ok(output)).is_err() {
+                break;
+            }
+        }
+    });
+    outputs
+}
+
+/// Reads the next line, or binary content, from `reader`. Returns None once there's nothing more
+/// to read.
+fn read_output(reader: &mut impl BufRead) -> std::io::Result<Option<Output>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    let line = String::from_utf8(line)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+    if let Some(header) = line.strip_prefix(evcxr_internal_runtime::BEGIN_BINARY_CONTENT) {
+        if let Some((mime_type, len)) = header.rsplit_once(' ') {
+            if let Ok(len) = len.parse::<usize>() {
+                let mut data = vec![0; len];
+                reader.read_exact(&mut data)?;
+                return Ok(Some(Output::Binary {
+                    mime_type: mime_type.to_owned(),
+                    data,
+                }));
+            }
+        }
+    }
+    Ok(Some(Output::Line(line)))
+}
+
 impl Drop for ChildProcess {
     fn drop(&mut self) {
         // Drop child_stdin before we wait. Our subprocess uses stdin being
@@ -155,6 +482,135 @@ impl Drop for ChildProcess {
         self.stdin.take();
         // Wait for our subprocess to terminate. Otherwise we'll be left with
         // zombie processes.
-        let _ = self.process.wait();
+        if let Some(process) = self.process.as_mut() {
+            let _ = process.wait();
+        }
+    }
+}
+
+/// A Windows job object. Closing it kills the processes in it.
+#[cfg(windows)]
+struct JobObject(winapi::um::winnt::HANDLE);
+
+// A job object handle can be used from any thread.
+#[cfg(windows)]
+unsafe impl Send for JobObject {}
+
+#[cfg(windows)]
+impl JobObject {
+    /// Creates a job object containing `process`. Since we hold the only handle to the job object,
+    /// it's closed when we exit, even if we crash, so the process never outlives us.
+    fn for_process(process: &std::process::Child) -> Result<JobObject, Error> {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::jobapi2;
+        let handle = unsafe { jobapi2::CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let job = JobObject(handle);
+        job.set_limits(&ResourceLimits::default())?;
+        if unsafe { jobapi2::AssignProcessToJobObject(job.0, process.as_raw_handle() as _) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(job)
+    }
+
+    /// Sets the limits on each process in the job. Processes are always killed when the job is
+    /// closed, whatever the limits.
+    fn set_limits(&self, limits: &ResourceLimits) -> Result<(), Error> {
+        use winapi::um::jobapi2;
+        use winapi::um::winnt;
+        let mut info: winnt::JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { std::mem::zeroed() };
+        let mut flags = winnt::JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if let Some(bytes) = limits.max_memory {
+            flags |= winnt::JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+            info.ProcessMemoryLimit = bytes as usize;
+        }
+        if let Some(secs) = limits.max_cpu_secs() {
+            flags |= winnt::JOB_OBJECT_LIMIT_PROCESS_TIME;
+            // In units of 100ns.
+            unsafe {
+                *info
+                    .BasicLimitInformation
+                    .PerProcessUserTimeLimit
+                    .QuadPart_mut() = secs as i64 * 10_000_000;
+            }
+        }
+        info.BasicLimitInformation.LimitFlags = flags;
+        let succeeded = unsafe {
+            jobapi2::SetInformationJobObject(
+                self.0,
+                winnt::JobObjectExtendedLimitInformation,
+                &mut info as *mut _ as *mut winapi::ctypes::c_void,
+                std::mem::size_of_val(&info) as u32,
+            )
+        };
+        if succeeded == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+impl Drop for JobObject {
+    fn drop(&mut self) {
+        unsafe {
+            winapi::um::handleapi::CloseHandle(self.0);
+        }
+    }
+}
+
+/// Returns how much CPU time `process` has spent in user mode, which is what the job object limits.
+#[cfg(windows)]
+fn user_time(process: &std::process::Child) -> Option<std::time::Duration> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::minwindef::FILETIME;
+    let mut creation: FILETIME = unsafe { std::mem::zeroed() };
+    let mut exit = creation;
+    let mut kernel = creation;
+    let mut user = creation;
+    let succeeded = unsafe {
+        winapi::um::processthreadsapi::GetProcessTimes(
+            process.as_raw_handle() as _,
+            &mut creation,
+            &mut exit,
+            &mut kernel,
+            &mut user,
+        )
+    };
+    if succeeded == 0 {
+        return None;
+    }
+    // In units of 100ns.
+    let user = (u64::from(user.dwHighDateTime) << 32) | u64::from(user.dwLowDateTime);
+    Some(std::time::Duration::from_nanos(user * 100))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_output;
+    use super::Output;
+
+    #[test]
+    fn binary_content_between_lines() {
+        let mut stdout: &[u8] =
+            b"before\r\nEVCXR_BEGIN_BINARY_CONTENT image/png 4\n\x89\n\0\xffafter\n";
+        match read_output(&mut stdout).unwrap() {
+            Some(Output::Line(line)) => assert_eq!(line, "before"),
+            _ => panic!("Expected a line"),
+        }
+        match read_output(&mut stdout).unwrap() {
+            Some(Output::Binary { mime_type, data }) => {
+                assert_eq!(mime_type, "image/png");
+                assert_eq!(data, b"\x89\n\0\xff");
+            }
+            _ => panic!("Expected binary content"),
+        }
+        match read_output(&mut stdout).unwrap() {
+            Some(Output::Line(line)) => assert_eq!(line, "after"),
+            _ => panic!("Expected a line"),
+        }
+        assert!(read_output(&mut stdout).unwrap().is_none());
     }
 }
@@ -0,0 +1,101 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for checking code with clippy, which is turned on with `:clippy`. Checking then uses
+//! `cargo clippy` rather than `cargo check`, and each cell is checked before it's evaluated. Clippy
+//! reports lints in the same JSON format as rustc's errors, so they go through the same mapping
+//! back to the user's code.
+
+use crate::errors::bail;
+use crate::errors::CompilationError;
+use crate::errors::Error;
+
+/// Which lints, if any, clippy checks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ClippyMode {
+    Off,
+    /// Clippy's default lints.
+    On,
+    /// Clippy's default lints plus its pedantic ones.
+    Pedantic,
+}
+
+impl ClippyMode {
+    pub(crate) fn parse(value: &str) -> Result<ClippyMode, Error> {
+        Ok(match value {
+            "off" => ClippyMode::Off,
+            "on" => ClippyMode::On,
+            "pedantic" => ClippyMode::Pedantic,
+            _ => bail!(
+                "Clippy mode must be 'off', 'on' or 'pedantic', got '{}'",
+                value
+            ),
+        })
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ClippyMode::Off => "off",
+            ClippyMode::On => "on",
+            ClippyMode::Pedantic => "pedantic",
+        }
+    }
+
+    /// The cargo subcommand that checks code in this mode.
+    pub(crate) fn cargo_subcommand(self) -> &'static str {
+        match self {
+            ClippyMode::Off => "check",
+            ClippyMode::On | ClippyMode::Pedantic => "clippy",
+        }
+    }
+
+    /// Arguments for the compiler, i.e. that go after `--`.
+    pub(crate) fn lint_args(self) -> &'static [&'static str] {
+        match self {
+            ClippyMode::Pedantic => &["-W", "clippy::pedantic"],
+            ClippyMode::Off | ClippyMode::On => &[],
+        }
+    }
+}
+
+/// Returns the clippy lints in `errors` that are about the user's code. Lints in code that we
+/// generate aren't something the user can fix, and rustc's own warnings come from the build that
+/// evaluates the code, so aren't repeated here.
+pub(crate) fn user_lints(errors: Vec<CompilationError>) -> Vec<CompilationError> {
+    errors
+        .into_iter()
+        .filter(|error| {
+            error.level() == "warning"
+                && error.is_from_user_code()
+                && error
+                    .code()
+                    .map_or(false, |code| code.starts_with("clippy::"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClippyMode;
+
+    #[test]
+    fn parse() {
+        for mode in [ClippyMode::Off, ClippyMode::On, ClippyMode::Pedantic] {
+            assert_eq!(ClippyMode::parse(mode.name()).unwrap(), mode);
+        }
+        assert!(ClippyMode::parse("1").is_err());
+        assert_eq!(ClippyMode::Off.cargo_subcommand(), "check");
+        assert_eq!(ClippyMode::Pedantic.cargo_subcommand(), "clippy");
+    }
+}
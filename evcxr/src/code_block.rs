@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::errors::Span;
 use crate::statement_splitter;
 use anyhow::anyhow;
 use anyhow::Result;
@@ -28,6 +29,8 @@ pub(crate) struct Segment {
     /// Only present for original user code. Provides ordering and identity to the segments that
     /// came from the user.
     pub(crate) sequence: Option<usize>,
+    /// For user code from an earlier cell, the number of that cell and where in it the code was.
+    earlier_cell: Option<(usize, UserCodeMetadata)>,
 }
 
 impl Segment {
@@ -40,10 +43,22 @@ impl Segment {
             num_lines: num_lines(&code),
             code,
             sequence: None,
+            earlier_cell: None,
         }
     }
 }
 
+/// Where a line of the code that we compile came from in the user's cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct CellLine {
+    /// The number of the cell, or None if it's the cell being evaluated.
+    pub(crate) cell: Option<usize>,
+    /// The line in the cell, starting from 1.
+    pub(crate) line: usize,
+    /// The number of columns on the line that precede the compiled code.
+    pub(crate) column_offset: usize,
+}
+
 /// Information about the code the user supplied.
 pub(crate) struct UserCodeInfo<'a> {
     pub(crate) nodes: Vec<SyntaxNode>,
@@ -56,6 +71,8 @@ pub(crate) struct CommandCall {
     pub(crate) args: Option<String>,
     start_byte: usize,
     pub(crate) line_number: usize,
+    /// The column (starting from 1) at which `args` starts.
+    pub(crate) args_column: usize,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -79,7 +96,7 @@ pub(crate) enum CodeKind {
     /// much we can do besides give the user as much information as we can, apologise and ask to
     /// file a bug report.
     OtherGeneratedCode,
-    /// We had trouble determining what the error applied to.
+    /// An evcxr command, or code that one added, such as a crate attribute from `:crate_attr`.
     Command(CommandCall),
     Unknown,
 }
@@ -152,9 +169,17 @@ impl CodeBlock {
         *self = block;
     }
 
-    pub(crate) fn commit_old_user_code(&mut self) {
+    /// Marks the user code in this block as having come from the cell numbered `cell`, now that
+    /// it's no longer the code being evaluated.
+    pub(crate) fn commit_old_user_code(&mut self, cell: usize) {
         for segment in self.segments.iter_mut() {
-            if matches!(segment.kind, CodeKind::OriginalUserCode(_)) {
+            if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+                segment.earlier_cell = Some((cell, meta.clone()));
+            }
+            if matches!(
+                segment.kind,
+                CodeKind::OriginalUserCode(_) | CodeKind::Command(_)
+            ) {
                 segment.kind = CodeKind::OtherUserCode;
             }
         }
@@ -175,6 +200,42 @@ impl CodeBlock {
         self
     }
 
+    /// Adds `segment`, with the generated code `insertion` inserted at each of `offsets`, which
+    /// are byte offsets in its code, in increasing order. Original user code is split into several
+    /// segments around the insertions, so that errors in it are still reported where they are in
+    /// the cell.
+    pub(crate) fn with_insertions(
+        mut self,
+        segment: Segment,
+        offsets: &[usize],
+        insertion: &str,
+    ) -> Self {
+        let meta = match &segment.kind {
+            CodeKind::OriginalUserCode(meta) if !offsets.is_empty() => meta.clone(),
+            _ => return self.with_segment(segment),
+        };
+        let mut start = 0;
+        let mut meta = meta;
+        for &offset in offsets.iter().chain(std::iter::once(&segment.code.len())) {
+            let code = &segment.code[start..offset];
+            let mut piece = Segment::new(CodeKind::OriginalUserCode(meta.clone()), code.to_owned());
+            piece.sequence = segment.sequence;
+            self.segments.push(piece);
+            if offset == segment.code.len() {
+                break;
+            }
+            self = self.generated(insertion);
+            meta.start_byte += code.len();
+            meta.start_line += num_lines(code);
+            meta.column_offset = match code.rfind('\n') {
+                Some(newline) => count_columns(&code[newline + 1..]),
+                None => meta.column_offset + count_columns(code),
+            };
+            start = offset;
+        }
+        self
+    }
+
     pub(crate) fn with<T: Into<String>>(mut self, origin: CodeKind, code: T) -> Self {
         self.segments.push(Segment::new(origin, code.into()));
         self
@@ -205,12 +266,45 @@ impl CodeBlock {
         for (command_line_offset, line) in user_code.lines().enumerate() {
             // We only accept commands up until the first non-command.
             if let Some(captures) = command_re.captures(line) {
+                let start_byte = line.as_ptr() as usize - user_code.as_ptr() as usize;
+                let mut args = captures.get(3).map(|m| m.as_str().to_owned());
+                // In a cell that uses `:sql` for a query, everything after it is SQL.
+                let takes_rest_of_cell =
+                    &captures[1] == ":sql" && crate::sql::takes_rest_of_cell(args.as_deref());
+                let code = if takes_rest_of_cell {
+                    let rest = &user_code[start_byte + line.len()..];
+                    args = Some(format!("{}{}", args.unwrap_or_default(), rest));
+                    &user_code[start_byte..]
+                } else {
+                    line
+                };
                 code_block = code_block.with(
                     CodeKind::Command(CommandCall {
                         command: captures[1].to_owned(),
-                        args: captures.get(3).map(|m| m.as_str().to_owned()),
+                        args,
+                        start_byte,
+                        line_number: command_line_offset + 1,
+                        args_column: captures
+                            .get(3)
+                            .map_or(0, |m| count_columns(&line[..m.start()]) + 1),
+                    }),
+                    code,
+                );
+                if takes_rest_of_cell {
+                    break;
+                }
+            } else if let Some(shell) = parse_shell_escape(line) {
+                let args = match shell.capture_into {
+                    Some(variable) => format!("{} {} {}", SH_CAPTURE_FLAG, variable, shell.command),
+                    None => shell.command.to_owned(),
+                };
+                code_block = code_block.with(
+                    CodeKind::Command(CommandCall {
+                        command: ":sh".to_owned(),
+                        args: Some(args),
                         start_byte: line.as_ptr() as usize - user_code.as_ptr() as usize,
                         line_number: command_line_offset + 1,
+                        args_column: count_columns(&line[..shell.command_start]) + 1,
                     }),
                     line,
                 );
@@ -318,6 +412,58 @@ impl CodeBlock {
             .ok_or_else(|| anyhow!("Output offset {} doesn't refer to user code", output_offset))
     }
 
+    /// Returns the byte offset at which each segment starts in the output of `code_string`.
+    pub(crate) fn segment_start_offsets(&self) -> Vec<usize> {
+        let mut offset = 0;
+        self.segments
+            .iter()
+            .map(|segment| {
+                let start = offset;
+                offset += segment.code.len();
+                start
+            })
+            .collect()
+    }
+
+    /// Converts a byte offset in the output of `code_string` into a byte offset in the code that
+    /// the user originally wrote. Returns None if the offset isn't within original user code.
+    pub(crate) fn user_offset_for_generated_offset(
+        &self,
+        generated_offset: usize,
+    ) -> Option<usize> {
+        let starts = self.segment_start_offsets();
+        // Segments always end with a newline, so are never empty and start offsets are strictly
+        // increasing.
+        let index = match starts.binary_search(&generated_offset) {
+            Ok(index) => index,
+            Err(index) => index.checked_sub(1)?,
+        };
+        let segment = &self.segments[index];
+        if generated_offset >= starts[index] + segment.code.len() {
+            return None;
+        }
+        if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+            Some(meta.start_byte + generated_offset - starts[index])
+        } else {
+            None
+        }
+    }
+
+    /// Converts a range of bytes in the output of `code_string` into a range in the original user
+    /// code. The end of the range is exclusive, so may be just past the end of a segment.
+    pub(crate) fn user_range_for_generated_range(
+        &self,
+        start: usize,
+        end: usize,
+    ) -> Option<(usize, usize)> {
+        let user_start = self.user_offset_for_generated_offset(start)?;
+        if end <= start {
+            return Some((user_start, user_start));
+        }
+        let user_end = self.user_offset_for_generated_offset(end - 1)? + 1;
+        Some((user_start, user_end))
+    }
+
     pub(crate) fn load_variable(&mut self, code: String) {
         self.segments
             .push(Segment::new(CodeKind::OtherGeneratedCode, code));
@@ -352,17 +498,95 @@ impl CodeBlock {
     /// with the line offset into that segment. Out-of-range indices will return
     /// type Unknown.
     pub(crate) fn origin_for_line(&self, line_number: usize) -> (&CodeKind, usize) {
+        match self.segment_for_line(line_number) {
+            Some((segment, offset)) => (&segment.kind, offset),
+            None => (&CodeKind::Unknown, 0),
+        }
+    }
+
+    /// Returns where line `line_number` (starting from 1) of the output of `code_string` came
+    /// from in the user's cells, or None if it's not user code, or user code that we don't know
+    /// the origin of.
+    pub(crate) fn cell_line_for_generated_line(&self, line_number: usize) -> Option<CellLine> {
+        let (segment, offset) = self.segment_for_line(line_number)?;
+        let (cell, meta) = match (&segment.kind, &segment.earlier_cell) {
+            (CodeKind::OriginalUserCode(meta), _) => (None, meta),
+            (CodeKind::OtherUserCode, Some((cell, meta))) => (Some(*cell), meta),
+            _ => return None,
+        };
+        Some(CellLine {
+            cell,
+            line: meta.start_line + offset,
+            column_offset: if offset == 0 { meta.column_offset } else { 0 },
+        })
+    }
+
+    /// Returns where bytes `start..end` of the output of `code_string` came from in the user's
+    /// cells: the number of the cell, or None if it's the cell being evaluated, and the span in
+    /// it. Returns None if the range isn't within a single segment of user code whose origin we
+    /// know.
+    pub(crate) fn cell_span_for_generated_range(
+        &self,
+        start: usize,
+        end: usize,
+    ) -> Option<(Option<usize>, Span)> {
+        let starts = self.segment_start_offsets();
+        let index = match starts.binary_search(&start) {
+            Ok(index) => index,
+            Err(index) => index.checked_sub(1)?,
+        };
+        let segment = &self.segments[index];
+        let (start, end) = (start - starts[index], end - starts[index]);
+        if end > segment.code.len() || end < start {
+            return None;
+        }
+        let (cell, meta) = match (&segment.kind, &segment.earlier_cell) {
+            (CodeKind::OriginalUserCode(meta), _) => (None, meta),
+            (CodeKind::OtherUserCode, Some((cell, meta))) => (Some(*cell), meta),
+            _ => return None,
+        };
+        Some((
+            cell,
+            Span::from_user_code_segment(&segment.code, meta, start, end),
+        ))
+    }
+
+    fn segment_for_line(&self, line_number: usize) -> Option<(&Segment, usize)> {
         if line_number == 0 {
-            return (&CodeKind::Unknown, 0);
+            return None;
         }
         let mut current_line_number = 1;
         for segment in &self.segments {
             if current_line_number + segment.num_lines > line_number {
-                return (&segment.kind, line_number - current_line_number);
+                return Some((segment, line_number - current_line_number));
             }
             current_line_number += segment.num_lines;
         }
-        (&CodeKind::Unknown, 0)
+        None
+    }
+
+    /// Returns the line (starting from 1) in the output of `code_string` that came from line
+    /// `user_line` of the original user code, or None if that line isn't part of any user code
+    /// that we kept, e.g. because it's a command or a comment.
+    pub(crate) fn generated_line_for_user_line(&self, user_line: usize) -> Option<usize> {
+        let mut generated_line = 1;
+        for segment in &self.segments {
+            if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+                if user_line >= meta.start_line && user_line < meta.start_line + segment.num_lines {
+                    return Some(generated_line + user_line - meta.start_line);
+                }
+            }
+            generated_line += segment.num_lines;
+        }
+        None
+    }
+
+    /// The inverse of `generated_line_for_user_line`.
+    pub(crate) fn user_line_for_generated_line(&self, line_number: usize) -> Option<usize> {
+        match self.origin_for_line(line_number) {
+            (CodeKind::OriginalUserCode(meta), offset) => Some(meta.start_line + offset),
+            _ => None,
+        }
     }
 
     pub(crate) fn apply_fallback(&mut self, fallback: &CodeBlock) {
@@ -378,10 +602,58 @@ impl CodeBlock {
     }
 }
 
+/// Passed to `:sh`, followed by a variable name, when a shell escape's output is assigned to a
+/// variable.
+pub(crate) const SH_CAPTURE_FLAG: &str = "--capture";
+
+/// A line that runs a shell command, like IPython's `!` escape, e.g. `!ls -la`, or
+/// `let files = !ls;` to capture what it prints.
+#[derive(Debug, PartialEq, Eq)]
+struct ShellEscape<'a> {
+    capture_into: Option<&'a str>,
+    command: &'a str,
+    /// The byte offset of `command` in the line.
+    command_start: usize,
+}
+
+fn parse_shell_escape(line: &str) -> Option<ShellEscape> {
+    static SHELL_RE: OnceCell<Regex> = OnceCell::new();
+    let shell_re = SHELL_RE.get_or_init(|| {
+        Regex::new(r"^ *(?:let +([A-Za-z_][A-Za-z0-9_]*) *= *)?!([^ !=(\[{].*)$").unwrap()
+    });
+    let captures = shell_re.captures(line)?;
+    let command = captures.get(2).unwrap();
+    let mut text = command.as_str().trim_end();
+    // Negating a function call or method call result, e.g. `!v.is_empty()`, is Rust, not a
+    // shell command.
+    let first_word = text.split_whitespace().next().unwrap_or_default();
+    if first_word.contains(&['(', '['][..]) || first_word.contains("::") {
+        return None;
+    }
+    let capture_into = captures.get(1).map(|m| m.as_str());
+    if capture_into.is_some() {
+        text = text.strip_suffix(';').unwrap_or(text).trim_end();
+    }
+    if text.is_empty() {
+        return None;
+    }
+    Some(ShellEscape {
+        capture_into,
+        command: text,
+        command_start: command.start(),
+    })
+}
+
 #[cfg(test)]
 mod test {
+    use super::parse_shell_escape;
+    use super::CellLine;
     use super::CodeBlock;
     use super::CodeKind;
+    use super::Segment;
+    use super::ShellEscape;
+    use super::UserCodeMetadata;
+    use proptest::prelude::*;
 
     #[test]
     fn basic_usage() {
@@ -427,4 +699,277 @@ mod test {
             user_code
         );
     }
+
+    #[test]
+    fn shell_escapes() {
+        assert_eq!(
+            parse_shell_escape("!ls -la"),
+            Some(ShellEscape {
+                capture_into: None,
+                command: "ls -la",
+                command_start: 1,
+            })
+        );
+        assert_eq!(
+            parse_shell_escape("let files = !ls *.rs;"),
+            Some(ShellEscape {
+                capture_into: Some("files"),
+                command: "ls *.rs",
+                command_start: 13,
+            })
+        );
+        assert_eq!(
+            parse_shell_escape("!./build.sh --release"),
+            Some(ShellEscape {
+                capture_into: None,
+                command: "./build.sh --release",
+                command_start: 1,
+            })
+        );
+        assert_eq!(parse_shell_escape("!v.is_empty()"), None);
+        assert_eq!(parse_shell_escape("let empty = !is_empty(&v);"), None);
+        assert_eq!(parse_shell_escape("!(a && b)"), None);
+        assert_eq!(parse_shell_escape("! ls"), None);
+        assert_eq!(parse_shell_escape("!"), None);
+        assert_eq!(parse_shell_escape("let x = !;"), None);
+        assert_eq!(parse_shell_escape("x != y"), None);
+
+        let (code, _) = CodeBlock::from_original_user_code(
+            "!echo hi
+let out = !pwd;
+out",
+        );
+        let commands: Vec<_> = code
+            .segments
+            .iter()
+            .filter_map(|segment| match &segment.kind {
+                CodeKind::Command(command) => {
+                    Some((command.command.as_str(), command.args.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                (":sh", Some("echo hi".to_owned())),
+                (":sh", Some("--capture out pwd".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_mapping() {
+        let (user_code, _nodes) =
+            CodeBlock::from_original_user_code(":dep foo\nlet a = 1;\n\nlet b = vec![\n  a,\n];");
+        let code = CodeBlock::new()
+            .generated("fn f() {")
+            .add_all(CodeBlock {
+                segments: user_code
+                    .segments
+                    .into_iter()
+                    .filter(|segment| !matches!(segment.kind, CodeKind::Command(_)))
+                    .collect(),
+            })
+            .generated("}");
+        assert_eq!(
+            code.code_string(),
+            "fn f() {\nlet a = 1;\nlet b = vec![\n  a,\n];\n}\n"
+        );
+        // Commands and blank lines aren't compiled.
+        assert_eq!(code.generated_line_for_user_line(1), None);
+        assert_eq!(code.generated_line_for_user_line(2), Some(2));
+        assert_eq!(code.generated_line_for_user_line(3), None);
+        assert_eq!(code.generated_line_for_user_line(5), Some(4));
+        assert_eq!(code.user_line_for_generated_line(4), Some(5));
+        assert_eq!(code.user_line_for_generated_line(1), None);
+        assert_eq!(code.user_line_for_generated_line(6), None);
+    }
+
+    #[test]
+    fn sql_takes_rest_of_cell() {
+        let commands = |user_code| {
+            let (code, _nodes) = CodeBlock::from_original_user_code(user_code);
+            code.segments
+                .into_iter()
+                .map(|segment| match segment.kind {
+                    CodeKind::Command(command) => (command.command, command.args),
+                    _ => panic!("Unexpected code: {}", segment.code),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            commands(":dep x\n:sql let t =\nSELECT 1;\n:vars\nSELECT 2"),
+            vec![
+                (":dep".to_owned(), Some("x".to_owned())),
+                (
+                    ":sql".to_owned(),
+                    Some("let t =\nSELECT 1;\n:vars\nSELECT 2".to_owned())
+                ),
+            ]
+        );
+        assert_eq!(
+            commands(":sql connect a.duckdb\n:sql SELECT 1"),
+            vec![
+                (":sql".to_owned(), Some("connect a.duckdb".to_owned())),
+                (":sql".to_owned(), Some("SELECT 1".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn cell_lines() {
+        let (mut earlier, _nodes) = CodeBlock::from_original_user_code("fn f() {\n    g();\n}");
+        earlier.commit_old_user_code(3);
+        let (current, _nodes) = CodeBlock::from_original_user_code("let a = 1;\n  f();");
+        let code = CodeBlock::new()
+            .add_all(earlier)
+            .generated("fn run() {")
+            .add_all(current)
+            .generated("}");
+        assert_eq!(
+            code.code_string(),
+            "fn f() {\n    g();\n}\nfn run() {\nlet a = 1;\nf();\n}\n"
+        );
+        assert_eq!(
+            code.cell_line_for_generated_line(2),
+            Some(CellLine {
+                cell: Some(3),
+                line: 2,
+                column_offset: 0
+            })
+        );
+        assert_eq!(
+            code.cell_line_for_generated_line(6),
+            Some(CellLine {
+                cell: None,
+                line: 2,
+                column_offset: 2
+            })
+        );
+        assert_eq!(code.cell_line_for_generated_line(4), None);
+        assert_eq!(code.cell_line_for_generated_line(0), None);
+        assert_eq!(code.cell_line_for_generated_line(8), None);
+
+        let cell_span = |start: usize, end: usize| {
+            code.cell_span_for_generated_range(start, end)
+                .map(|(cell, span)| {
+                    (
+                        cell,
+                        (span.start_line, span.start_column),
+                        (span.end_line, span.end_column),
+                        span.byte_start,
+                    )
+                })
+        };
+        let generated = code.code_string();
+        let g = generated.find("g()").unwrap();
+        assert_eq!(cell_span(g, g + 1), Some((Some(3), (2, 5), (2, 6), 13)));
+        let f = generated.find("f();").unwrap();
+        assert_eq!(cell_span(f, f + 1), Some((None, (2, 3), (2, 4), 13)));
+        let run = generated.find("run").unwrap();
+        assert_eq!(cell_span(run, run + 3), None);
+    }
+
+    #[test]
+    fn insertions() {
+        let user_code = "let a = 1;\nfor i in 0..3 { f(i); }";
+        let (code, _nodes) = CodeBlock::from_original_user_code(user_code);
+        let mut segments = code.segments.into_iter();
+        let first = segments.next().unwrap();
+        let second = segments.next().unwrap();
+        let brace = second.code.find('{').unwrap() + 1;
+        let code =
+            CodeBlock::new()
+                .with_segment(first)
+                .with_insertions(second, &[brace], "check();");
+        let generated = code.code_string();
+        assert_eq!(
+            generated,
+            "let a = 1;\nfor i in 0..3 {\ncheck();\n f(i); }\n"
+        );
+        // User code after the insertion still maps back to where it was in the cell.
+        let f = generated.find("f(i)").unwrap();
+        assert_eq!(
+            code.user_offset_for_generated_offset(f),
+            user_code.find("f(i)")
+        );
+        assert_eq!(code.user_line_for_generated_line(3), None);
+        assert_eq!(
+            code.cell_line_for_generated_line(4),
+            Some(CellLine {
+                cell: None,
+                line: 2,
+                column_offset: 15
+            })
+        );
+    }
+
+    /// A piece of code and whether it was written by the user.
+    fn pieces() -> impl Strategy<Value = Vec<(bool, String)>> {
+        prop::collection::vec((any::<bool>(), "[a-z0-9 ;(){}é\n]{0,12}"), 0..12)
+    }
+
+    /// Builds a code block from `pieces`, returning it together with the original user code
+    /// and, for each user piece, its start offset in the original code.
+    fn build(pieces: &[(bool, String)]) -> (CodeBlock, String, Vec<usize>) {
+        let mut block = CodeBlock::new();
+        let mut user_code = String::new();
+        let mut user_starts = Vec::new();
+        for (index, (is_user, code)) in pieces.iter().enumerate() {
+            if *is_user {
+                // Separate user pieces, as the user's code would have whitespace between
+                // statements.
+                user_code.push(' ');
+                user_starts.push(user_code.len());
+                block = block.with_segment(Segment::new(
+                    CodeKind::OriginalUserCode(UserCodeMetadata {
+                        start_byte: user_code.len(),
+                        node_index: index,
+                        start_line: 1,
+                        column_offset: 0,
+                    }),
+                    code.clone(),
+                ));
+                user_code.push_str(code);
+            } else {
+                block = block.generated(code.clone());
+            }
+        }
+        (block, user_code, user_starts)
+    }
+
+    proptest! {
+        #[test]
+        fn generated_offsets_map_to_user_offsets(pieces in pieces()) {
+            let (block, user_code, user_starts) = build(&pieces);
+            let generated = block.code_string();
+            let starts = block.segment_start_offsets();
+            let mut user_pieces = user_starts.iter();
+            for ((is_user, code), (segment, start)) in
+                pieces.iter().zip(block.segments.iter().zip(&starts))
+            {
+                prop_assert_eq!(&generated[*start..*start + segment.code.len()], &segment.code);
+                if *is_user {
+                    let user_start = *user_pieces.next().unwrap();
+                    for i in 0..code.len() {
+                        prop_assert_eq!(
+                            block.user_offset_for_generated_offset(start + i),
+                            Some(user_start + i)
+                        );
+                    }
+                    // A span covering the whole piece maps back to exactly that piece.
+                    let (s, e) = block
+                        .user_range_for_generated_range(*start, start + code.len())
+                        .unwrap();
+                    prop_assert_eq!(&user_code[s..e], code.as_str());
+                } else {
+                    for i in 0..segment.code.len() {
+                        prop_assert_eq!(block.user_offset_for_generated_offset(start + i), None);
+                    }
+                }
+            }
+            prop_assert_eq!(block.user_offset_for_generated_offset(generated.len()), None);
+        }
+    }
 }
@@ -0,0 +1,285 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:asm`, `:mir` and `:llvm`, which show what rustc generates for a function. We have
+//! rustc emit the whole session crate, then pick out the named function. In assembly and LLVM IR,
+//! functions are identified by their mangled symbol names, so we look for the prefix that the
+//! legacy mangling scheme gives the function's path. That prefix is followed by a hash, which we
+//! can't compute ourselves.
+
+/// What to have rustc emit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Emit {
+    Asm,
+    Mir,
+    LlvmIr,
+}
+
+impl Emit {
+    /// The name of the command that shows this.
+    pub(crate) fn command_name(self) -> &'static str {
+        match self {
+            Emit::Asm => ":asm",
+            Emit::Mir => ":mir",
+            Emit::LlvmIr => ":llvm",
+        }
+    }
+
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            Emit::Asm => "assembly",
+            Emit::Mir => "MIR",
+            Emit::LlvmIr => "LLVM IR",
+        }
+    }
+
+    /// What this is called in rustc's `--emit` option.
+    pub(crate) fn rustc_name(self) -> &'static str {
+        match self {
+            Emit::Asm => "asm",
+            Emit::Mir => "mir",
+            Emit::LlvmIr => "llvm-ir",
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Emit::Asm => "s",
+            Emit::Mir => "mir",
+            Emit::LlvmIr => "ll",
+        }
+    }
+
+    /// The language to give Markdown code blocks, so that frontends highlight them. MIR isn't Rust,
+    /// but it's close enough that highlighting it as Rust helps.
+    pub(crate) fn markdown_language(self) -> &'static str {
+        match self {
+            Emit::Asm => "asm",
+            Emit::Mir => "rust",
+            Emit::LlvmIr => "llvm",
+        }
+    }
+}
+
+/// Returns the code for the function at `path` within `crate_name` from `emitted`, which is the
+/// output of rustc for `emit`. Returns None if the function isn't there.
+pub(crate) fn function_code(
+    emitted: &str,
+    emit: Emit,
+    crate_name: &str,
+    path: &str,
+) -> Option<String> {
+    let segments: Vec<&str> = path.split("::").map(str::trim).collect();
+    let lines = match emit {
+        Emit::Asm => asm_function(emitted, &symbol_prefix(crate_name, &segments))?,
+        Emit::Mir => mir_function(emitted, segments.last()?)?,
+        Emit::LlvmIr => llvm_ir_function(emitted, &symbol_prefix(crate_name, &segments))?,
+    };
+    Some(lines.join("\n"))
+}
+
+/// Returns the start of the legacy mangled symbol name for the non-generic function at `segments`
+/// within `crate_name`. e.g. `_ZN3ctx6double17h` for `double`. What follows is the function's hash
+/// and a terminating `E`.
+fn symbol_prefix(crate_name: &str, segments: &[&str]) -> String {
+    let mut prefix = "_ZN".to_owned();
+    for segment in std::iter::once(&crate_name).chain(segments) {
+        prefix.push_str(&segment.len().to_string());
+        prefix.push_str(segment);
+    }
+    prefix.push_str("17h");
+    prefix
+}
+
+/// Returns the lines of assembly from the label of the function whose symbol starts with
+/// `symbol_prefix` to the end of the function. Assembler directives, which are mostly for debug
+/// info and unwinding, are left out.
+fn asm_function<'a>(emitted: &'a str, symbol_prefix: &str) -> Option<Vec<&'a str>> {
+    let mut lines = emitted.lines().skip_while(|line| {
+        // On macOS, symbols have an extra leading underscore.
+        let symbol = if line.starts_with("__") {
+            &line[1..]
+        } else {
+            line
+        };
+        !(symbol.starts_with(symbol_prefix) && line.ends_with(':'))
+    });
+    let mut function = vec![lines.next()?];
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.starts_with(".Lfunc_end")
+            || trimmed.starts_with("Lfunc_end")
+            || trimmed == ".cfi_endproc"
+            || trimmed == ".seh_endproc"
+        {
+            break;
+        }
+        let is_label = trimmed.ends_with(':');
+        if (trimmed.starts_with('.') && !is_label) || trimmed.starts_with(".Ltmp") {
+            continue;
+        }
+        function.push(line);
+    }
+    Some(function)
+}
+
+/// Returns the lines of MIR for the function called `name`. Rustc only writes the function's name,
+/// not the module it's in, so if several functions have that name, we return the first.
+fn mir_function<'a>(emitted: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    let header = format!("fn {}(", name);
+    lines_until_closing_brace(emitted, |line| line.starts_with(&header))
+}
+
+/// Returns the lines of LLVM IR that define the function whose symbol starts with
+/// `symbol_prefix`.
+fn llvm_ir_function<'a>(emitted: &'a str, symbol_prefix: &str) -> Option<Vec<&'a str>> {
+    let symbol = format!("@{}", symbol_prefix);
+    lines_until_closing_brace(emitted, |line| {
+        line.starts_with("define ") && line.contains(&symbol)
+    })
+}
+
+/// Returns the lines from the first for which `is_start` returns true, to the next `}` at the start
+/// of a line.
+fn lines_until_closing_brace(emitted: &str, is_start: impl Fn(&str) -> bool) -> Option<Vec<&str>> {
+    let mut lines = emitted.lines().skip_while(|line| !is_start(line));
+    let mut function = vec![lines.next()?];
+    for line in lines {
+        function.push(line);
+        if line == "}" {
+            break;
+        }
+    }
+    Some(function)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::function_code;
+    use super::Emit;
+
+    #[test]
+    fn asm() {
+        let emitted = [
+            "\t.section\t.text._ZN3ctx6double17h0123456789abcdefE,\"ax\",@progbits",
+            "\t.p2align\t4, 0x90",
+            "\t.type\t_ZN3ctx6double17h0123456789abcdefE,@function",
+            "_ZN3ctx6double17h0123456789abcdefE:",
+            "\t.cfi_startproc",
+            "\tleal\t(%rdi,%rdi), %eax",
+            "\tretq",
+            ".Lfunc_end0:",
+            "\t.size\t_ZN3ctx6double17h0123456789abcdefE, .Lfunc_end0-_ZN3ctx6double17h0123456789abcdefE",
+            "\t.cfi_endproc",
+            "",
+            "_ZN3ctx4math6triple17h0123456789abcdefE:",
+            "\t.cfi_startproc",
+            "\ttestl\t%edi, %edi",
+            "\tjne\t.LBB1_2",
+            "\tleal\t(%rdi,%rdi,2), %eax",
+            ".LBB1_2:",
+            "\tretq",
+            ".Lfunc_end1:",
+        ]
+        .join("\n");
+        assert_eq!(
+            function_code(&emitted, Emit::Asm, "ctx", "double"),
+            Some(
+                "_ZN3ctx6double17h0123456789abcdefE:\n\tleal\t(%rdi,%rdi), %eax\n\tretq".to_owned()
+            )
+        );
+        assert_eq!(
+            function_code(&emitted, Emit::Asm, "ctx", "math::triple"),
+            Some(
+                [
+                    "_ZN3ctx4math6triple17h0123456789abcdefE:",
+                    "\ttestl\t%edi, %edi",
+                    "\tjne\t.LBB1_2",
+                    "\tleal\t(%rdi,%rdi,2), %eax",
+                    ".LBB1_2:",
+                    "\tretq",
+                ]
+                .join("\n")
+            )
+        );
+        assert_eq!(function_code(&emitted, Emit::Asm, "ctx", "triple"), None);
+        assert_eq!(function_code(&emitted, Emit::Asm, "ctx", "doubl"), None);
+    }
+
+    #[test]
+    fn mir() {
+        let emitted = [
+            "// WARNING: This output format is intended for human consumers only",
+            "fn double(_1: i32) -> i32 {",
+            "    debug x => _1;",
+            "    let mut _0: i32;",
+            "",
+            "    bb0: {",
+            "        _0 = Mul(_1, const 2_i32);",
+            "        return;",
+            "    }",
+            "}",
+            "",
+            "fn double::{closure#0}(_1: &{closure@src/lib.rs:3:5: 3:7}) -> () {",
+            "}",
+            "",
+            "fn triple(_1: i32) -> i32 {",
+            "}",
+        ]
+        .join("\n");
+        assert_eq!(
+            function_code(&emitted, Emit::Mir, "ctx", "double"),
+            Some(
+                emitted
+                    .lines()
+                    .skip(1)
+                    .take(9)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        );
+        assert_eq!(
+            function_code(&emitted, Emit::Mir, "ctx", "math::triple"),
+            Some("fn triple(_1: i32) -> i32 {\n}".to_owned())
+        );
+        assert_eq!(function_code(&emitted, Emit::Mir, "ctx", "quadruple"), None);
+    }
+
+    #[test]
+    fn llvm_ir() {
+        let emitted = [
+            "; ctx::double",
+            "; Function Attrs: nonlazybind uwtable",
+            "define internal i32 @_ZN3ctx6double17h0123456789abcdefE(i32 %x) unnamed_addr #0 {",
+            "start:",
+            "  %_0 = shl i32 %x, 1",
+            "  ret i32 %_0",
+            "}",
+            "",
+            "declare void @_ZN3ctx6double17h0123456789abcdefE.1()",
+        ]
+        .join("\n");
+        assert_eq!(
+            function_code(&emitted, Emit::LlvmIr, "ctx", "double"),
+            Some(
+                emitted
+                    .lines()
+                    .skip(2)
+                    .take(5)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        );
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Comms let user code exchange messages with the frontend, as Jupyter widgets do. User code
+//! (normally via evcxr_runtime) reports comms that it opens and messages it sends on them by
+//! printing lines with the prefixes below. These get passed to `EvalCallbacks::comm_sender`. We
+//! also keep track of the state of each comm, updated both by user code and by the frontend, so
+//! that user code can read it back, e.g. to get the current value of a slider.
+
+use json::JsonValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// These prefixes are duplicated in evcxr_runtime, which doesn't depend on this crate.
+/// Followed by `<comm_id> <target_name> <json data>`.
+pub(crate) const COMM_OPEN: &str = "EVCXR_COMM_OPEN ";
+/// Followed by `<comm_id> <json data>`.
+pub(crate) const COMM_MSG: &str = "EVCXR_COMM_MSG ";
+/// Followed by `<comm_id>`.
+pub(crate) const COMM_CLOSE: &str = "EVCXR_COMM_CLOSE ";
+/// Followed by `<comm_id> <key>`. We reply with the JSON for that key of the comm's state.
+pub(crate) const COMM_GET: &str = "EVCXR_COMM_GET ";
+
+/// A message from user code to the frontend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommMessage {
+    pub comm_id: String,
+    pub kind: CommMessageKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommMessageKind {
+    Open {
+        target_name: String,
+        data: JsonValue,
+    },
+    Msg {
+        data: JsonValue,
+    },
+    Close,
+}
+
+/// The comms that are open, shared between the context and the frontend. Cloning gives another
+/// handle to the same comms, so the frontend can update them while user code is running.
+#[derive(Clone, Default)]
+pub struct Comms {
+    comms: Arc<Mutex<HashMap<String, Comm>>>,
+}
+
+struct Comm {
+    target_name: String,
+    state: JsonValue,
+}
+
+impl Comms {
+    /// Returns the IDs of open comms, together with their target names. If `target_name` is
+    /// supplied, only comms with that target are included.
+    pub fn list(&self, target_name: Option<&str>) -> Vec<(String, String)> {
+        let mut comms: Vec<(String, String)> = self
+            .comms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, comm)| target_name.map_or(true, |name| comm.target_name == name))
+            .map(|(id, comm)| (id.clone(), comm.target_name.clone()))
+            .collect();
+        comms.sort();
+        comms
+    }
+
+    /// Returns the state of the specified comm, or None if there's no such comm.
+    pub fn state(&self, comm_id: &str) -> Option<JsonValue> {
+        self.comms
+            .lock()
+            .unwrap()
+            .get(comm_id)
+            .map(|comm| comm.state.clone())
+    }
+
+    /// Handles a message on a comm. Messages with `"method": "update"`, which both user code and
+    /// the Jupyter widget frontend send when values change, update the comm's state.
+    pub fn handle_message(&self, comm_id: &str, data: &JsonValue) {
+        if data["method"].as_str() != Some("update") {
+            return;
+        }
+        if let Some(comm) = self.comms.lock().unwrap().get_mut(comm_id) {
+            for (key, value) in data["state"].entries() {
+                comm.state[key] = value.clone();
+            }
+        }
+    }
+
+    pub fn close(&self, comm_id: &str) {
+        self.comms.lock().unwrap().remove(comm_id);
+    }
+
+    fn open(&self, comm_id: &str, target_name: &str, data: &JsonValue) {
+        self.comms.lock().unwrap().insert(
+            comm_id.to_owned(),
+            Comm {
+                target_name: target_name.to_owned(),
+                state: data["state"].clone(),
+            },
+        );
+    }
+
+    /// Processes a line of output from user code. Returns the message to pass on to the frontend,
+    /// or None if the line wasn't a comm message.
+    pub(crate) fn process_line(&self, line: &str) -> Option<CommMessage> {
+        let (comm_id, kind) = if let Some(rest) = line.strip_prefix(COMM_OPEN) {
+            let mut parts = rest.splitn(3, ' ');
+            let comm_id = parts.next()?;
+            let target_name = parts.next()?;
+            let data = json::parse(parts.next()?).ok()?;
+            self.open(comm_id, target_name, &data);
+            (
+                comm_id,
+                CommMessageKind::Open {
+                    target_name: target_name.to_owned(),
+                    data,
+                },
+            )
+        } else if let Some(rest) = line.strip_prefix(COMM_MSG) {
+            let (comm_id, data) = rest.split_once(' ')?;
+            let data = json::parse(data).ok()?;
+            self.handle_message(comm_id, &data);
+            (comm_id, CommMessageKind::Msg { data })
+        } else if let Some(comm_id) = line.strip_prefix(COMM_CLOSE) {
+            self.close(comm_id);
+            (comm_id, CommMessageKind::Close)
+        } else {
+            return None;
+        };
+        Some(CommMessage {
+            comm_id: comm_id.to_owned(),
+            kind,
+        })
+    }
+
+    /// Returns the reply to a `COMM_GET` request, given what followed the prefix.
+    pub(crate) fn get_reply(&self, request: &str) -> String {
+        let (comm_id, key) = request.split_once(' ').unwrap_or((request, ""));
+        match self.state(comm_id) {
+            Some(state) => state[key].dump(),
+            None => JsonValue::Null.dump(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommMessageKind;
+    use super::Comms;
+
+    #[test]
+    fn comm_lifecycle() {
+        let comms = Comms::default();
+        let open = comms
+            .process_line(
+                r#"EVCXR_COMM_OPEN c1 jupyter.widget {"state":{"value":5,"max":10},"buffer_paths":[]}"#,
+            )
+            .unwrap();
+        assert_eq!(open.comm_id, "c1");
+        assert!(matches!(
+            open.kind,
+            CommMessageKind::Open { ref target_name, .. } if target_name == "jupyter.widget"
+        ));
+        assert_eq!(
+            comms.list(Some("jupyter.widget")),
+            vec![("c1".to_owned(), "jupyter.widget".to_owned())]
+        );
+        assert!(comms.list(Some("other")).is_empty());
+        assert_eq!(comms.get_reply("c1 value"), "5");
+
+        // An update from the frontend.
+        comms.handle_message(
+            "c1",
+            &json::parse(r#"{"method":"update","state":{"value":7}}"#).unwrap(),
+        );
+        assert_eq!(comms.get_reply("c1 value"), "7");
+        assert_eq!(comms.get_reply("c1 max"), "10");
+
+        // An update from user code.
+        let msg = comms
+            .process_line(r#"EVCXR_COMM_MSG c1 {"method":"update","state":{"value":2}}"#)
+            .unwrap();
+        assert!(matches!(msg.kind, CommMessageKind::Msg { .. }));
+        assert_eq!(comms.get_reply("c1 value"), "2");
+
+        assert_eq!(
+            comms.process_line("EVCXR_COMM_CLOSE c1").unwrap().kind,
+            CommMessageKind::Close
+        );
+        assert_eq!(comms.get_reply("c1 value"), "null");
+        assert!(comms.process_line("Hello").is_none());
+    }
+}
@@ -13,13 +13,32 @@
 // limitations under the License.
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
 
+use crate::about;
+use crate::bench;
+use crate::clippy;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
 use crate::code_block::Segment;
+use crate::code_block::SH_CAPTURE_FLAG;
 use crate::code_block::{self};
+use crate::codegen::Emit;
+use crate::comm::Comms;
+use crate::command_info::ArgumentInfo;
+use crate::command_info::ArgumentKind;
+use crate::command_info::CommandInfo;
+use crate::content::Content;
 use crate::crash_guard::CrashGuard;
+use crate::debugger::Debugger;
+use crate::dep_cache;
+use crate::dev_crate::DevCrate;
+use crate::environment::parse_env_args;
+use crate::environment::EnvCommand;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
@@ -27,9 +46,26 @@ use crate::errors::Span;
 use crate::errors::SpannedMessage;
 use crate::eval_context::ContextState;
 use crate::eval_context::EvalCallbacks;
+use crate::eval_event::EvalEventSink;
+use crate::file_loader::LoadedCell;
+use crate::interrupt::InterruptHandle;
+use crate::layout::TypeLayout;
+use crate::metrics::Metrics;
+use crate::native_libs::NativeLib;
+use crate::profiler::Profiler;
+use crate::reactive::ReactiveMode;
 use crate::rust_analyzer::Completion;
 use crate::rust_analyzer::Completions;
+use crate::rust_analyzer::Diagnostic;
+use crate::rust_analyzer::Location;
+use crate::rust_analyzer::SemanticToken;
+use crate::sarif::SessionDiagnostics;
+use crate::sql;
+use crate::sql::SqlCommand;
+use crate::timing::Timing;
+use crate::why_slow::EvalProfile;
 use crate::EvalContext;
+use crate::EvalContextConfig;
 use crate::EvalContextOutputs;
 use crate::EvalOutputs;
 use anyhow::Result;
@@ -39,13 +75,40 @@ use once_cell::sync::OnceCell;
 /// ':') that alter context state or print information.
 pub struct CommandContext {
     print_timings: bool,
+    /// Whether `:time` was used in the cell currently being evaluated.
+    time_this_eval: bool,
     eval_context: EvalContext,
     last_errors: Vec<CompilationError>,
+    /// The errors and warnings of each evaluation, for `:sarif`.
+    diagnostics: SessionDiagnostics,
+    dev_crates: Vec<DevCrate>,
+    last_eval_profile: Option<EvalProfile>,
+    /// Dependencies that we failed to add this session, keyed by name and configuration, with the
+    /// reason that they failed.
+    failed_deps: HashMap<(String, String), String>,
+    /// Dependencies added with `:dep --scoped` in the cell currently being evaluated. They're
+    /// removed once it finishes.
+    scoped_deps: HashSet<String>,
+    /// The command whose callback is running, if any.
+    current_command: Option<CommandCall>,
+    /// Names of the profiles that have been used since the session started or was cleared.
+    profiles_in_use: Vec<String>,
+    /// The code of the most recent cells evaluated this session that contained code, rather than
+    /// just commands, for `:rustfmt N`. At most `MAX_KEPT_INPUTS` are kept.
+    inputs: VecDeque<String>,
+    /// How many cells with code were evaluated before the first one in `inputs`.
+    dropped_inputs: usize,
 }
 
 impl CommandContext {
     pub fn new() -> Result<(CommandContext, EvalContextOutputs), Error> {
-        let (eval_context, eval_context_outputs) = EvalContext::new()?;
+        Self::with_config(EvalContextConfig::default())
+    }
+
+    pub fn with_config(
+        config: EvalContextConfig,
+    ) -> Result<(CommandContext, EvalContextOutputs), Error> {
+        let (eval_context, eval_context_outputs) = EvalContext::with_config(config)?;
         let command_context = CommandContext::with_eval_context(eval_context);
         Ok((command_context, eval_context_outputs))
     }
@@ -53,8 +116,18 @@ impl CommandContext {
     pub fn with_eval_context(eval_context: EvalContext) -> CommandContext {
         CommandContext {
             print_timings: false,
+            time_this_eval: false,
             eval_context,
             last_errors: Vec::new(),
+            diagnostics: SessionDiagnostics::default(),
+            dev_crates: Vec::new(),
+            last_eval_profile: None,
+            failed_deps: HashMap::new(),
+            scoped_deps: HashSet::new(),
+            current_command: None,
+            profiles_in_use: Vec::new(),
+            inputs: VecDeque::new(),
+            dropped_inputs: 0,
         }
     }
 
@@ -68,6 +141,29 @@ impl CommandContext {
         self.execute_with_callbacks(to_run, &mut EvalCallbacks::default())
     }
 
+    /// Evaluates the cells of a `.rs` file or a Jupyter notebook in order, writing a line to stdout
+    /// before each one. Evaluation stops at the first cell that fails, with an error saying where it
+    /// was. See `file_loader` for how files are split into cells.
+    pub fn execute_file(&mut self, path: &Path) -> Result<EvalOutputs, Error> {
+        let cells = crate::file_loader::cells_from_file(path)?;
+        let mut outputs = EvalOutputs::new();
+        for (index, cell) in cells.iter().enumerate() {
+            self.eval_context.write_stdout_line(format!(
+                "[{}/{}] {}",
+                index + 1,
+                cells.len(),
+                cell.location()
+            ));
+            let mut cell_outputs = self
+                .execute(&cell.code)
+                .map_err(|error| cell.failure(index + 1, cells.len(), error))?;
+            // Keep the output of each cell on its own line.
+            end_with_newline(&mut cell_outputs);
+            outputs.merge(cell_outputs);
+        }
+        Ok(outputs)
+    }
+
     pub fn check(&mut self, code: &str) -> Result<Vec<CompilationError>, Error> {
         let (user_code, code_info) = CodeBlock::from_original_user_code(code);
         let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
@@ -80,6 +176,82 @@ impl CommandContext {
         self.eval_context.check(non_command_code, state, &code_info)
     }
 
+    /// Returns the problems that rust-analyzer finds in `code`, a cell that the user may not have
+    /// finished writing, together with everything defined earlier in the session. Unlike `check`,
+    /// this doesn't run cargo, so it's quick enough for frontends to call as the user types, in
+    /// order to underline problems before the cell is run. It finds fewer problems than `check`,
+    /// since rust-analyzer only reports some of what rustc does.
+    pub fn diagnostics(&mut self, code: &str) -> Result<Vec<Diagnostic>, Error> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
+        // Commands are checked as they'd be run, so problems with them are reported too.
+        let mut diagnostics: Vec<Diagnostic> = errors
+            .iter()
+            .filter_map(|error| {
+                Some(Diagnostic {
+                    code: "command".to_owned(),
+                    message: error.message(),
+                    level: error.level().to_owned(),
+                    span: error.primary_spanned_message()?.span?,
+                })
+            })
+            .collect();
+        diagnostics.extend(self.eval_context.diagnostics(
+            non_command_code,
+            state,
+            &code_info,
+            code,
+        )?);
+        Ok(diagnostics)
+    }
+
+    /// Returns ranges of `code`, a cell, together with what each is, as rust-analyzer sees them
+    /// for semantic highlighting. This lets frontends color code the way that IDEs do, e.g.
+    /// distinguishing mutable variables and macros, without parsing it themselves. Names defined
+    /// in earlier cells are resolved. Lines that are commands have no tokens.
+    pub fn semantic_tokens(&mut self, code: &str) -> Result<Vec<SemanticToken>, Error> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let (non_command_code, state, _errors) = self.prepare_for_analysis(user_code)?;
+        self.eval_context
+            .semantic_tokens(non_command_code, state, &code_info, code)
+    }
+
+    /// Returns where what's referred to at byte `offset` of `code` is defined. Items defined in
+    /// earlier cells resolve to those cells, so that notebooks can jump to the cell that defined a
+    /// function or type. Returns nothing if `offset` isn't on a name, or if the definition isn't
+    /// in this session, e.g. because it's in the standard library.
+    pub fn definition(&mut self, code: &str, offset: usize) -> Result<Vec<Location>, Error> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let (non_command_code, state, _errors) = self.prepare_for_analysis(user_code)?;
+        self.eval_context
+            .definitions(non_command_code, state, &code_info, offset)
+    }
+
+    /// Like `definition`, but returns the definition together with every reference to it. Those in
+    /// earlier cells come first, in the order the cells were evaluated, followed by those in
+    /// `code`.
+    pub fn references(&mut self, code: &str, offset: usize) -> Result<Vec<Location>, Error> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let (non_command_code, state, _errors) = self.prepare_for_analysis(user_code)?;
+        self.eval_context
+            .references(non_command_code, state, &code_info, offset)
+    }
+
+    /// Starts compiling `partial_code`, the cell that the user is still writing, in the background.
+    /// Frontends can call this as the user types, so that when the cell is run, dependencies added
+    /// by its `:dep` commands are already built and compilation of the cell mostly hits warm
+    /// caches. Returns whether compilation was started. It isn't if compilation started by an
+    /// earlier call is still running, so calling this often is cheap.
+    pub fn precompile(&mut self, partial_code: &str) -> Result<bool, Error> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(partial_code);
+        let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
+        if !errors.is_empty() {
+            return Ok(false);
+        }
+        self.eval_context
+            .precompile(non_command_code, state, &code_info)
+    }
+
     pub fn variables_and_types(&self) -> impl Iterator<Item = (&str, &str)> {
         self.eval_context.variables_and_types()
     }
@@ -88,10 +260,113 @@ impl CommandContext {
         self.eval_context.reset_config();
     }
 
+    /// Returns metrics for this session. See `Metrics`.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.eval_context.metrics()
+    }
+
+    /// Returns the comms that user code has opened. See `Comms`.
+    pub fn comms(&self) -> Comms {
+        self.eval_context.comms()
+    }
+
+    /// Registers `sink` to be told about the phases of each evaluation as they happen. See
+    /// `EvalEvent`.
+    pub fn subscribe(&mut self, sink: impl EvalEventSink + 'static) {
+        self.eval_context.subscribe(sink);
+    }
+
+    /// Returns a handle to the debugger for this session. See `Debugger`.
+    pub fn debugger(&self) -> Debugger {
+        self.eval_context.debugger()
+    }
+
+    /// Returns a handle that can be used to interrupt user code from another thread. See
+    /// `InterruptHandle`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.eval_context.interrupt_handle()
+    }
+
+    /// Sets the mime types that the frontend can display, most preferred first. See
+    /// `EvalContext::set_mime_preferences`.
+    pub fn set_mime_preferences(&mut self, mime_types: Vec<String>) {
+        self.eval_context.set_mime_preferences(mime_types);
+    }
+
     pub fn defined_item_names(&self) -> impl Iterator<Item = &str> {
         self.eval_context.defined_item_names()
     }
 
+    /// Returns the type of `expression` without evaluating it. See `:type`.
+    pub fn type_of(&mut self, expression: &str) -> Result<String, Error> {
+        let state = self.eval_context.state();
+        self.eval_context.type_of(expression, &state)
+    }
+
+    /// Returns the size and alignment of `type_name`. See `:size`.
+    pub fn type_layout(&mut self, type_name: &str) -> Result<TypeLayout, Error> {
+        let state = self.eval_context.state();
+        self.eval_context.type_layout(type_name, &state)
+    }
+
+    /// Returns `code`, which can start with commands like any cell, formatted with rustfmt.
+    pub fn format_code(&self, code: &str) -> Result<String, Error> {
+        self.eval_context.format_cell(code)
+    }
+
+    /// Returns the code of cell `number`, counting from 1 the cells with code evaluated this
+    /// session, or of the last such cell if `number` is `None`, formatted with rustfmt. Only the
+    /// last `MAX_KEPT_INPUTS` cells are kept. See `:rustfmt`.
+    pub fn format_input(&self, number: Option<usize>) -> Result<String, Error> {
+        let input = match number {
+            None => match self.inputs.back() {
+                Some(input) => input,
+                None => bail!("No code has been evaluated yet"),
+            },
+            Some(number) => {
+                let last = self.dropped_inputs + self.inputs.len();
+                if number == 0 || number > last {
+                    bail!(
+                        "There's no cell {}. Cells are numbered from 1 to {}",
+                        number,
+                        last
+                    );
+                }
+                if number <= self.dropped_inputs {
+                    bail!(
+                        "Cell {} is no longer kept. Only the last {} cells can be formatted",
+                        number,
+                        MAX_KEPT_INPUTS
+                    );
+                }
+                &self.inputs[number - self.dropped_inputs - 1]
+            }
+        };
+        self.format_code(input)
+    }
+
+    /// Returns which cell `code` asks to format, if it's a use of `:rustfmt`: `Some(None)` for the
+    /// last cell, or `Some(Some(N))` for cell N. See `format_input`.
+    pub fn cell_to_format(code: &str) -> Option<Option<usize>> {
+        code.trim()
+            .strip_prefix(":rustfmt")
+            .and_then(parse_rustfmt_args)
+    }
+
+    fn record_input(&mut self, code: &str) {
+        if self.inputs.len() == MAX_KEPT_INPUTS {
+            self.inputs.pop_front();
+            self.dropped_inputs += 1;
+        }
+        self.inputs.push_back(code.to_owned());
+    }
+
+    /// Returns whether code should be formatted before it's echoed in a transcript. See
+    /// `:autoformat`.
+    pub fn autoformat(&self) -> bool {
+        self.eval_context.autoformat()
+    }
+
     pub fn execute_with_callbacks(
         &mut self,
         to_run: &str,
@@ -126,7 +401,15 @@ Panic detected. Here's some useful information if you're filing a bug report.
         use std::time::Instant;
         let mut eval_outputs = EvalOutputs::new();
         let start = Instant::now();
+        self.time_this_eval = false;
+        self.scoped_deps.clear();
+        let reloaded = self.check_dev_crates();
+        self.eval_context.cell_started(to_run);
+        self.diagnostics.evaluation_started();
         let mut state = self.eval_context.state();
+        for name in reloaded {
+            state.revalidate_dep(&name)?;
+        }
         let mut non_command_code = CodeBlock::new();
         let (user_code, code_info) = CodeBlock::from_original_user_code(to_run);
         for segment in user_code.segments {
@@ -141,19 +424,49 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 non_command_code = non_command_code.with_segment(segment);
             }
         }
+        let has_code = !non_command_code.is_empty();
+        if has_code {
+            self.record_input(to_run);
+        }
+        // Lints are checked for before evaluating, since evaluation changes the state that the code
+        // would be checked against.
+        let lints = if has_code && state.clippy() != "off" {
+            clippy::user_lints(self.eval_context.check(
+                non_command_code.clone(),
+                state.clone(),
+                &code_info,
+            )?)
+        } else {
+            Vec::new()
+        };
         let result =
             self.eval_context
                 .eval_with_callbacks(non_command_code, state, &code_info, callbacks);
+        if result.is_ok() && has_code {
+            self.eval_context.record_successful_cell(to_run);
+        }
+        self.remove_scoped_deps();
         let duration = start.elapsed();
+        self.eval_context
+            .metrics()
+            .record_eval(duration, result.is_ok());
         match result {
             Ok(m) => {
+                if !m.phases.is_empty() {
+                    self.last_eval_profile = Some(self.eval_context.eval_profile(&m.phases));
+                }
+                let timing = Timing::from_phases(duration, &m.phases);
                 eval_outputs.merge(m);
-                if self.print_timings {
-                    eval_outputs.timing = Some(duration);
+                eval_outputs.warnings.extend(lints);
+                self.diagnostics.record(to_run, &eval_outputs.warnings);
+                // If `:timeit` was used, it will have already supplied more detailed timing.
+                if eval_outputs.timing.is_none() && (self.print_timings || self.time_this_eval) {
+                    eval_outputs.timing = Some(timing);
                 }
                 Ok(eval_outputs)
             }
             Err(Error::CompilationErrors(errors)) => {
+                self.diagnostics.record(to_run, &errors);
                 self.last_errors = errors.clone();
                 Err(Error::CompilationErrors(errors))
             }
@@ -161,10 +474,189 @@ Panic detected. Here's some useful information if you're filing a bug report.
         }
     }
 
+    /// Like `execute_with_callbacks`, but for a notebook cell with an ID that stays the same when
+    /// the cell is edited. If `:reactive` is on, the cells downstream of this one are then either
+    /// invalidated or run again. If running the cell would create a cycle, it fails without
+    /// running anything.
+    pub fn execute_cell_with_callbacks(
+        &mut self,
+        cell_id: &str,
+        to_run: &str,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        let mode = self.eval_context.reactive_mode();
+        let downstream = if mode == ReactiveMode::Off {
+            Vec::new()
+        } else {
+            self.eval_context.reactive_downstream(cell_id, to_run)?
+        };
+        let result = self.execute_with_callbacks(to_run, callbacks);
+        // Even if the cell failed, some of it may have been kept, e.g. items defined by a cell with
+        // a runtime error.
+        self.eval_context.record_cell_id(cell_id);
+        let mut outputs = result?;
+        self.eval_context.record_reactive_cell(cell_id, to_run);
+        if downstream.is_empty() {
+            return Ok(outputs);
+        }
+        match mode {
+            ReactiveMode::Off => {}
+            ReactiveMode::Invalidate => {
+                let stale: Vec<String> = downstream
+                    .iter()
+                    .flat_map(|cell| cell.defines.iter().cloned())
+                    .collect();
+                let forgotten = self.eval_context.forget_variables(&stale);
+                let mut message = format!(
+                    "{} downstream {} should be run again:",
+                    downstream.len(),
+                    if downstream.len() == 1 {
+                        "cell"
+                    } else {
+                        "cells"
+                    }
+                );
+                for cell in &downstream {
+                    message.push_str("\n  ");
+                    message.push_str(&cell_summary(&cell.code));
+                }
+                if !forgotten.is_empty() {
+                    message.push_str(&format!(
+                        "\nUnavailable until then: {}",
+                        forgotten.join(", ")
+                    ));
+                }
+                self.eval_context.write_stdout_line(message);
+            }
+            ReactiveMode::Recompute => {
+                let cells: Vec<String> = downstream.into_iter().map(|cell| cell.code).collect();
+                let mut state = self.eval_context.state();
+                // Keep this cell's output on its own line.
+                end_with_newline(&mut outputs);
+                outputs.merge(self.rerun_cells(&cells, &mut state)?);
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// Forgets the cells with IDs `cell_ids`, e.g. because they were deleted from the notebook, so
+    /// that `:reactive` no longer treats them as downstream of anything. Their variables and items
+    /// remain.
+    pub fn forget_cells(&mut self, cell_ids: &[String]) {
+        for cell_id in cell_ids {
+            self.eval_context.forget_reactive_cell(cell_id);
+        }
+    }
+
+    /// Evaluates the cells that depend on `variable` again, in the order that they first ran,
+    /// writing a line to stdout before each one. Stops at the first cell that fails.
+    fn rerun_dependents(
+        &mut self,
+        variable: &str,
+        state: &mut ContextState,
+    ) -> Result<EvalOutputs, Error> {
+        let cells = state.cell_dependents(variable);
+        if cells.is_empty() {
+            return text_output(format!("No cells depend on {}", variable));
+        }
+        self.rerun_cells(&cells, state)
+    }
+
+    /// Evaluates `cells`, which mustn't contain commands, in order, writing a line to stdout
+    /// before each one. Stops at the first cell that fails.
+    fn rerun_cells(
+        &mut self,
+        cells: &[String],
+        state: &mut ContextState,
+    ) -> Result<EvalOutputs, Error> {
+        let mut outputs = EvalOutputs::new();
+        for (index, code) in cells.iter().enumerate() {
+            self.eval_context.write_stdout_line(format!(
+                "[{}/{}] {}",
+                index + 1,
+                cells.len(),
+                cell_summary(code)
+            ));
+            let result = self.eval_context.eval_with_state(code, state.clone());
+            *state = self.eval_context.state();
+            let mut cell_outputs = result.map_err(|error| {
+                rerun_failure(
+                    code.lines().next().unwrap_or_default(),
+                    index + 1,
+                    cells.len(),
+                    error,
+                )
+            })?;
+            // Keep the output of each cell on its own line.
+            end_with_newline(&mut cell_outputs);
+            outputs.merge(cell_outputs);
+        }
+        Ok(outputs)
+    }
+
+    /// Removes dependencies added with `:dep --scoped` during the cell that just finished.
+    fn remove_scoped_deps(&mut self) {
+        let scoped_deps = std::mem::take(&mut self.scoped_deps);
+        if scoped_deps.is_empty() {
+            return;
+        }
+        let forgotten = self.eval_context.remove_scoped_deps(&scoped_deps);
+        if !forgotten.is_empty() {
+            let mut deps: Vec<&str> = scoped_deps.iter().map(String::as_str).collect();
+            deps.sort_unstable();
+            self.eval_context.write_stdout_line(format!(
+                "Dropped {}, since they refer to scoped dependencies: {}",
+                forgotten.join(", "),
+                deps.join(", ")
+            ));
+        }
+    }
+
+    /// Checks whether any crates registered with `:dev_crate` have changed. Variables whose types
+    /// were changed are forgotten, since their stored values may no longer match the new type.
+    /// Cargo will rebuild the crate itself when we next compile. Returns the names of crates whose
+    /// manifests changed, since those need their dependencies to be validated again.
+    fn check_dev_crates(&mut self) -> Vec<String> {
+        let mut manifests_changed = Vec::new();
+        for dev_crate in &mut self.dev_crates {
+            if let Some(change) = dev_crate.check_for_changes() {
+                let forgotten = self.eval_context.forget_variables_where(|type_name| {
+                    crate::dev_crate::type_references_any(type_name, &change.changed_types)
+                });
+                let mut message = format!("Reloading {}", dev_crate.name);
+                if !forgotten.is_empty() {
+                    message.push_str(&format!(
+                        ". Types changed, so these variables were dropped: {}",
+                        forgotten.join(", ")
+                    ));
+                }
+                self.eval_context.write_stdout_line(message);
+                if change.manifest_changed {
+                    manifests_changed.push(dev_crate.name.clone());
+                }
+            }
+        }
+        manifests_changed
+    }
+
     pub fn set_opt_level(&mut self, level: &str) -> Result<(), Error> {
         self.eval_context.set_opt_level(level)
     }
 
+    /// Registers a hint to be shown alongside errors with `code`. See `EvalContext::register_hint`.
+    pub fn register_hint(&mut self, code: &str, hint: &str) {
+        self.eval_context.register_hint(code, hint);
+    }
+
+    /// Sets the target to build for. See `ContextState::set_target`.
+    pub fn set_target(&mut self, target: Option<String>) {
+        self.eval_context.set_target(target);
+    }
+
+    pub fn set_runner(&mut self, runner: Option<String>) {
+        self.eval_context.set_runner(runner);
+    }
+
     pub fn last_source(&self) -> std::io::Result<String> {
         self.eval_context.last_source()
     }
@@ -183,6 +675,44 @@ Panic detected. Here's some useful information if you're filing a bug report.
             .completions(non_command_code, state, &code_info.nodes, position)
     }
 
+    fn current_command(&self) -> Result<CommandCall, Error> {
+        match &self.current_command {
+            Some(command) => Ok(command.clone()),
+            None => bail!("No command is running"),
+        }
+    }
+
+    /// Fails, listing what breaks, if the items defined so far don't compile with `state`, which
+    /// has been changed as described by `change`. This lets us reject a change, such as to the
+    /// edition, that would otherwise leave every later evaluation failing.
+    fn check_items_still_compile(
+        &mut self,
+        state: &ContextState,
+        change: &str,
+    ) -> Result<(), Error> {
+        let (broken, errors) = self.eval_context.items_broken_by(state)?;
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let mut message = if broken.is_empty() {
+            format!("Existing code doesn't compile with {}", change)
+        } else {
+            format!(
+                "Existing items don't compile with {}: {}",
+                change,
+                broken.join(", ")
+            )
+        };
+        for error in &errors {
+            message.push('\n');
+            message.push_str(&error.message());
+        }
+        bail!(
+            "{}\nNothing was changed. Redefine them, then try again.",
+            message
+        )
+    }
+
     fn prepare_for_analysis(
         &mut self,
         user_code: CodeBlock,
@@ -235,9 +765,17 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 if !quiet {
                     println!("Loading startup commands from {:?}", config_file);
                 }
-                let contents = std::fs::read_to_string(config_file)?;
-                for line in contents.lines() {
-                    outputs.merge(self.execute(line)?);
+                let contents = std::fs::read_to_string(&config_file)?;
+                for (index, line) in contents.lines().enumerate() {
+                    let line_outputs = self.execute(line).map_err(|error| {
+                        Error::Message(format!(
+                            "{}:{}: {}",
+                            config_file.display(),
+                            index + 1,
+                            error
+                        ))
+                    })?;
+                    outputs.merge(line_outputs);
                 }
             }
             // Note: Loaded *after* init.evcxr so that it can access `:dep`s (or
@@ -247,10 +785,60 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 if !quiet {
                     println!("Executing prelude from {:?}", prelude_file);
                 }
-                let prelude = std::fs::read_to_string(prelude_file)?;
-                outputs.merge(self.execute(&prelude)?);
+                outputs.merge(self.execute_prelude(&prelude_file)?);
+            }
+        }
+        if let Some(prelude_file) = self.eval_context.prelude().map(Path::to_owned) {
+            if !quiet {
+                println!("Executing prelude from {:?}", prelude_file);
             }
+            outputs.merge(self.execute_prelude(&prelude_file)?);
+        }
+        for name in self.eval_context.startup_profiles().to_vec() {
+            if !self.profiles_in_use.contains(&name) {
+                outputs.merge(self.use_profile(&name)?);
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// Evaluates a prelude file. Errors give their position in the file, rather than in the cell
+    /// that it was split into.
+    fn execute_prelude(&mut self, path: &Path) -> Result<EvalOutputs, Error> {
+        let cells = crate::file_loader::cells_from_prelude(path)?;
+        self.execute_cells(&cells)
+    }
+
+    fn execute_cells(&mut self, cells: &[LoadedCell]) -> Result<EvalOutputs, Error> {
+        let mut outputs = EvalOutputs::new();
+        for (index, cell) in cells.iter().enumerate() {
+            let cell_outputs = self
+                .execute(&cell.code)
+                .map_err(|error| cell.failure(index + 1, cells.len(), error))?;
+            outputs.merge(cell_outputs);
+        }
+        Ok(outputs)
+    }
+
+    /// Evaluates the profile called `name` from `profiles.evcxr`, on top of whatever's already been
+    /// evaluated.
+    fn use_profile(&mut self, name: &str) -> Result<EvalOutputs, Error> {
+        if self.profiles_in_use.iter().any(|used| used == name) {
+            return text_output(format!("Profile `{}` is already in use", name));
         }
+        let path = match crate::profiles::profiles_file() {
+            Some(path) => path,
+            None => bail!("Profiles can't be used, since there's no config directory"),
+        };
+        let profiles = crate::profiles::load(&path)?;
+        let profile = crate::profiles::find(&profiles, name)?;
+        let cells = crate::file_loader::cells_from_part(
+            &path.display().to_string(),
+            &profile.code,
+            profile.start_line,
+        );
+        let outputs = self.execute_cells(&cells)?;
+        self.profiles_in_use.push(name.to_owned());
         Ok(outputs)
     }
 
@@ -274,10 +862,12 @@ Panic detected. Here's some useful information if you're filing a bug report.
         analysis_mode: bool,
     ) -> Result<EvalOutputs, CompilationError> {
         if let Some(command) = Self::commands_by_name().get(command_call.command.as_str()) {
+            self.current_command = Some(command_call.clone());
             let result = match &command.analysis_callback {
                 Some(analysis_callback) if analysis_mode => (analysis_callback)(self, state, args),
                 _ => (command.callback)(self, state, args),
             };
+            self.current_command = None;
             result.map_err(|error| {
                 // Span from the start of the arguments to the end of the arguments, or if no
                 // arguments are found, span the command. We look for the first non-space character
@@ -334,6 +924,16 @@ Panic detected. Here's some useful information if you're filing a bug report.
         })
     }
 
+    /// Returns descriptions of the available commands, sorted by name.
+    pub fn command_infos() -> Vec<CommandInfo> {
+        let mut infos: Vec<CommandInfo> = Self::commands_by_name()
+            .values()
+            .map(AvailableCommand::info)
+            .collect();
+        infos.sort_by_key(|info| info.name);
+        infos
+    }
+
     fn create_commands() -> Vec<AvailableCommand> {
         vec![
             AvailableCommand::new(
@@ -345,6 +945,55 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("Internals debugging: {}", debug_mode))
                 },
             ),
+            AvailableCommand::new(
+                ":load",
+                "Evaluate the cells of a .rs file or .ipynb notebook, stopping if one fails",
+                |ctx, state, args| {
+                    let path = match args.as_deref().map(str::trim) {
+                        Some(path) if !path.is_empty() => Path::new(path),
+                        _ => bail!(":load requires a file"),
+                    };
+                    let result = ctx.execute_file(path);
+                    *state = ctx.eval_context.state();
+                    result
+                },
+            )
+            .arg("file", ArgumentKind::Path, "The .rs or .ipynb file to load")
+            .example(":load analysis.ipynb")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":rerun_dependents",
+                "Evaluate again, in order, the cells that depend on a variable",
+                |ctx, state, args| {
+                    let variable = match args.as_deref().map(str::trim) {
+                        Some(variable) if !variable.is_empty() => variable,
+                        _ => bail!(":rerun_dependents requires a variable"),
+                    };
+                    ctx.rerun_dependents(variable, state)
+                },
+            )
+            .arg(
+                "variable",
+                ArgumentKind::Code,
+                "The variable whose dependents to evaluate",
+            )
+            .example(":rerun_dependents data")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":reactive",
+                "When a notebook cell runs, invalidate or recompute the cells that use its variables (off/invalidate/recompute)",
+                |_ctx, state, args| {
+                    if let Some(mode) = args {
+                        state.set_reactive(mode)?;
+                    }
+                    text_output(format!("Reactive: {}", state.reactive()))
+                },
+            )
+            .optional_arg(
+                "mode",
+                ArgumentKind::Choice(&["off", "invalidate", "recompute"]),
+                "What happens to downstream cells",
+            ),
             AvailableCommand::new(
                 ":load_config",
                 "Reloads startup configuration files. Accepts optional flag `--quiet` to suppress logging.",
@@ -355,10 +1004,29 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     result
                 },
             )
+            .optional_arg("--quiet", ArgumentKind::Flag, "Don't log what's loaded")
             .disable_in_analysis(),
             AvailableCommand::new(":version", "Print Evcxr version", |_ctx, _state, _args| {
                 text_output(env!("CARGO_PKG_VERSION"))
             }),
+            AvailableCommand::new(
+                ":about",
+                "Print version and environment details (useful for bug reports)",
+                |ctx, state, _args| {
+                    let about = ctx.eval_context.about_info(state);
+                    let mut outputs = EvalOutputs::new();
+                    let mut text = about.to_text();
+                    text.push('\n');
+                    outputs
+                        .content_by_mime_type
+                        .insert("text/plain".to_owned(), Content::Text(text));
+                    outputs
+                        .content_by_mime_type
+                        .insert("application/json".to_owned(), Content::Json(about.to_json()));
+                    Ok(outputs)
+                },
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":vars",
                 "List bound variables and their types",
@@ -369,6 +1037,84 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     ))
                 },
             ),
+            AvailableCommand::new(
+                ":results",
+                "List kept values of expressions, _1, _2 etc",
+                |_ctx, state, _args| text_output(state.results_summary()),
+            ),
+            AvailableCommand::new(
+                ":type",
+                "Show the type of an expression without evaluating it. :type <expr>",
+                |ctx, state, args| {
+                    let expression = args.as_deref().unwrap_or_default();
+                    if expression.trim().is_empty() {
+                        bail!(":type requires an expression. e.g. :type vec![1, 2, 3]");
+                    }
+                    text_output(ctx.eval_context.type_of(expression, state)?)
+                },
+            )
+            .arg("expr", ArgumentKind::Code, "The expression")
+            .example(":type vec![1, 2, 3]")
+            .example(":type v.iter().map(|x| x * 2)")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":size",
+                "Show the size and alignment of a type. :size <type>",
+                |ctx, state, args| {
+                    let type_name = args.as_deref().unwrap_or_default();
+                    if type_name.trim().is_empty() {
+                        bail!(":size requires a type. e.g. :size Option<Box<i32>>");
+                    }
+                    let layout = ctx.eval_context.type_layout(type_name, state)?;
+                    *state = ctx.eval_context.state();
+                    text_output(layout.to_text())
+                },
+            )
+            .arg("type", ArgumentKind::Code, "The type")
+            .example(":size Option<Box<i32>>")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":doc",
+                "Show the documentation of an item. :doc <path>",
+                |ctx, state, args| process_doc_command(ctx, state, args),
+            )
+            .arg("path", ArgumentKind::Code, "The item's path, as it would be written in code")
+            .example(":doc std::collections::HashMap")
+            .example(":doc Vec::with_capacity")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":expand",
+                "Show what code expands to once macros are expanded. :expand <code>",
+                |ctx, state, _args| process_expand_command(ctx, state),
+            )
+            .arg("code", ArgumentKind::Code, "An expression, statement or item")
+            .example(":expand vec![1, 2, 3]")
+            .example(":expand #[derive(Debug)] struct Point { x: i32 }")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":asm",
+                "Show the assembly generated for a function. :asm <function>",
+                |ctx, state, args| process_generated_code_command(ctx, state, args, Emit::Asm),
+            )
+            .arg("function", ArgumentKind::Text, "The function's path, e.g. math::double")
+            .example(":asm double")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":mir",
+                "Show the MIR generated for a function. :mir <function>",
+                |ctx, state, args| process_generated_code_command(ctx, state, args, Emit::Mir),
+            )
+            .arg("function", ArgumentKind::Text, "The function's path, e.g. math::double")
+            .example(":mir double")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":llvm",
+                "Show the LLVM IR generated for a function. :llvm <function>",
+                |ctx, state, args| process_generated_code_command(ctx, state, args, Emit::LlvmIr),
+            )
+            .arg("function", ArgumentKind::Text, "The function's path, e.g. math::double")
+            .example(":llvm double")
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":preserve_vars_on_panic",
                 "Try to keep vars on panic (0/1)",
@@ -380,57 +1126,535 @@ Panic detected. Here's some useful information if you're filing a bug report.
                         state.preserve_vars_on_panic()
                     ))
                 },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["0", "1"]),
+                "Whether to keep variables",
             ),
             AvailableCommand::new(
-                ":clear",
-                "Clear all state, keeping compilation cache",
-                |ctx, state, _args| {
-                    ctx.eval_context.clear().map(|_| {
-                        *state = ctx.eval_context.state();
-                        EvalOutputs::new()
-                    })
+                ":auto_recover",
+                "Restore definitions and simple variables if the subprocess crashes (0/1)",
+                |_ctx, state, args| {
+                    state.set_recovery_mode(args.as_ref().map(String::as_str) == Some("1"));
+                    text_output(format!("Auto recover: {}", state.recovery_mode()))
                 },
             )
-            .with_analysis_callback(|ctx, state, _args| {
-                *state = ctx.eval_context.cleared_state();
-                Ok(EvalOutputs::default())
-            }),
-            AvailableCommand::new(
-                ":dep",
-                "Add dependency. e.g. :dep regex = \"1.0\"",
-                |_ctx, state, args| process_dep_command(state, args),
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["0", "1"]),
+                "Whether to recover after a crash",
             ),
             AvailableCommand::new(
-                ":last_compile_dir",
-                "Print the directory in which we last compiled",
-                |ctx, _state, _args| {
-                    text_output(format!("{:?}", ctx.eval_context.last_compile_dir()))
+                ":migrate_types",
+                "Migrate variables to redefined types via serde, when their layout changes (on/off)",
+                |_ctx, state, args| {
+                    match args.as_deref() {
+                        Some("on") => state.set_migrate_types(true),
+                        Some("off") => state.set_migrate_types(false),
+                        None => {}
+                        Some(other) => {
+                            bail!(":migrate_types expects 'on' or 'off', got '{}'", other)
+                        }
+                    }
+                    text_output(format!(
+                        "Migrate types: {}",
+                        if state.migrate_types() { "on" } else { "off" }
+                    ))
                 },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["on", "off"]),
+                "Whether to migrate variables",
             ),
             AvailableCommand::new(
-                ":opt",
-                "Set optimization level (0/1/2)",
+                ":arena",
+                "Provide `arena`, for values that references kept in variables can point to (on/off)",
                 |_ctx, state, args| {
-                    let new_level = if let Some(n) = args {
-                        n
-                    } else if state.opt_level() == "2" {
-                        "0"
-                    } else {
-                        "2"
-                    };
-                    state.set_opt_level(new_level)?;
-                    text_output(format!("Optimization: {}", state.opt_level()))
+                    match args.as_deref() {
+                        Some("on") => state.set_arena(true),
+                        Some("off") => state.set_arena(false),
+                        None => {}
+                        Some(other) => bail!(":arena expects 'on' or 'off', got '{}'", other),
+                    }
+                    text_output(format!(
+                        "Arena: {}",
+                        if state.arena() { "on" } else { "off" }
+                    ))
                 },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["on", "off"]),
+                "Whether to provide the arena",
             ),
             AvailableCommand::new(
-                ":fmt",
-                "Set output formatter (default: {:?})",
+                ":auto_use",
+                "Import names that can't be found, when rustc suggests a single `use` (on/off)",
                 |_ctx, state, args| {
-                    let new_format = if let Some(f) = args { f } else { "{:?}" };
-                    state.set_output_format(new_format.to_owned());
-                    text_output(format!("Output format: {}", state.output_format()))
+                    match args.as_deref() {
+                        Some("on") => state.set_auto_use(true),
+                        Some("off") => state.set_auto_use(false),
+                        None => {}
+                        Some(other) => bail!(":auto_use expects 'on' or 'off', got '{}'", other),
+                    }
+                    text_output(format!(
+                        "Auto use: {}",
+                        if state.auto_use() { "on" } else { "off" }
+                    ))
                 },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["on", "off"]),
+                "Whether to add suggested imports",
+            ),
+            AvailableCommand::new(
+                ":auto_dep",
+                "Add crates from crates.io that code uses but that aren't dependencies (on/off)",
+                |_ctx, state, args| {
+                    match args.as_deref() {
+                        Some("on") => state.set_auto_dep(true),
+                        Some("off") => state.set_auto_dep(false),
+                        None => {}
+                        Some(other) => bail!(":auto_dep expects 'on' or 'off', got '{}'", other),
+                    }
+                    text_output(format!(
+                        "Auto dep: {}",
+                        if state.auto_dep() { "on" } else { "off" }
+                    ))
+                },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["on", "off"]),
+                "Whether to add missing crates",
+            ),
+            AvailableCommand::new(
+                ":cache",
+                "Show the size of the compiled-dependency cache, clean it, or turn it on/off",
+                |_ctx, state, args| process_cache_command(state, args),
+            )
+            .optional_arg(
+                "action",
+                ArgumentKind::Choice(&["on", "off", "clean"]),
+                "Whether to use the cache, or `clean` to empty it",
+            ),
+            AvailableCommand::new(
+                ":mem",
+                "Report heap allocations made by each evaluation (on/off)",
+                |_ctx, state, args| {
+                    match args.as_deref() {
+                        Some("on") => state.set_track_memory(true),
+                        Some("off") => state.set_track_memory(false),
+                        None => {}
+                        Some(other) => bail!(":mem expects 'on' or 'off', got '{}'", other),
+                    }
+                    text_output(format!(
+                        "Memory tracking: {}",
+                        if state.track_memory() { "on" } else { "off" }
+                    ))
+                },
+            )
+            .optional_arg(
+                "tracking",
+                ArgumentKind::Choice(&["on", "off"]),
+                "Whether to report allocations",
+            ),
+            AvailableCommand::new(
+                ":clippy",
+                "Check code with clippy and report its lints (off/on/pedantic)",
+                |_ctx, state, args| {
+                    if let Some(mode) = args {
+                        state.set_clippy(mode)?;
+                    }
+                    text_output(format!("Clippy: {}", state.clippy()))
+                },
+            )
+            .optional_arg(
+                "mode",
+                ArgumentKind::Choice(&["off", "on", "pedantic"]),
+                "Which lints to report. pedantic includes clippy's pedantic lints",
+            ),
+            AvailableCommand::new(
+                ":autoformat",
+                "Format code with rustfmt before it's exported or saved in a transcript (on/off)",
+                |_ctx, state, args| {
+                    match args.as_deref() {
+                        Some("on") => state.set_autoformat(true),
+                        Some("off") => state.set_autoformat(false),
+                        None => {}
+                        Some(other) => bail!(":autoformat expects 'on' or 'off', got '{}'", other),
+                    }
+                    text_output(format!(
+                        "Autoformat: {}",
+                        if state.autoformat() { "on" } else { "off" }
+                    ))
+                },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["on", "off"]),
+                "Whether to format code",
+            ),
+            AvailableCommand::new(
+                ":clear",
+                "Clear all state, keeping compilation cache",
+                |ctx, state, _args| {
+                    ctx.eval_context.clear().map(|_| {
+                        ctx.profiles_in_use.clear();
+                        *state = ctx.eval_context.state();
+                        EvalOutputs::new()
+                    })
+                },
+            )
+            .with_analysis_callback(|ctx, state, _args| {
+                *state = ctx.eval_context.cleared_state();
+                Ok(EvalOutputs::default())
+            }),
+            AvailableCommand::new(
+                ":export_project",
+                "Write a Cargo project with this session's definitions, dependencies and code",
+                |_ctx, state, args| {
+                    let dir = match args.as_deref().map(str::trim) {
+                        Some(dir) if !dir.is_empty() => Path::new(dir),
+                        _ => bail!(":export_project requires a directory"),
+                    };
+                    state.export_project(dir)?;
+                    text_output(format!(
+                        "Exported to {}. Run it with `cargo run` in that directory.",
+                        dir.display()
+                    ))
+                },
+            )
+            .arg(
+                "dir",
+                ArgumentKind::Path,
+                "The directory to create the project in",
+            )
+            .example(":export_project ../my_project")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":sarif",
+                "Write the errors and warnings of this session to a SARIF file",
+                |ctx, _state, args| {
+                    let path = match args.as_deref().map(str::trim) {
+                        Some(path) if !path.is_empty() => Path::new(path),
+                        _ => bail!(":sarif requires a file path"),
+                    };
+                    ctx.diagnostics.write(path)?;
+                    text_output(format!(
+                        "Wrote {} diagnostic(s) to {}",
+                        ctx.diagnostics.count(),
+                        path.display()
+                    ))
+                },
+            )
+            .arg("path", ArgumentKind::Path, "The file to write")
+            .example(":sarif evcxr.sarif")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":dep",
+                "Add dependency. e.g. :dep regex = \"1.0\". Use --force to retry a failed dependency, --scoped for this cell only",
+                |ctx, state, args| process_dep_command(ctx, state, args),
+            )
+            .optional_arg("--force", ArgumentKind::Flag, "Retry a dependency that failed earlier")
+            .optional_arg(
+                "--scoped",
+                ArgumentKind::Flag,
+                "Only use the dependency in the current cell",
+            )
+            .arg(
+                "dependency",
+                ArgumentKind::Text,
+                "A crate name, optionally followed by = and a version or dependency table",
+            )
+            .example(":dep regex = \"1.0\"")
+            .example(":dep serde = { version = \"1\", features = [\"derive\"] }")
+            .example(":dep --scoped rand = \"0.8\""),
+            AvailableCommand::new(
+                ":dep!",
+                "Add the crate that best matches a search of crates.io, at its latest version",
+                |ctx, state, args| process_dep_search_command(ctx, state, args),
+            )
+            .arg("query", ArgumentKind::Text, "What to search for")
+            .example(":dep! regex")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":search",
+                "Search crates.io for crates, showing the latest version of each",
+                |_ctx, state, args| {
+                    text_output(state.search_crates(args.as_deref().unwrap_or(""))?.to_string())
+                },
+            )
+            .arg("query", ArgumentKind::Text, "What to search for")
+            .example(":search async http client")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":deps",
+                "Show the dependency tree, or which features of a dependency are enabled",
+                |ctx, state, args| process_deps_command(ctx, state, args),
+            )
+            .arg(
+                "view",
+                ArgumentKind::Choice(&["tree", "features"]),
+                "Whether to show the tree or a crate's features",
+            )
+            .optional_arg(
+                "crate",
+                ArgumentKind::Text,
+                "For tree, a crate to show what depends on. For features, the crate to show",
+            )
+            .example(":deps tree")
+            .example(":deps tree syn")
+            .example(":deps features tokio")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":dep_flags",
+                "Set compiler flags for a dependency. e.g. :dep_flags tokio --cfg tokio_unstable",
+                |_ctx, state, args| process_dep_flags_command(state, args),
+            )
+            .optional_arg("dependency", ArgumentKind::Text, "A dependency added with :dep")
+            .optional_arg(
+                "flags",
+                ArgumentKind::Text,
+                "Flags to pass to rustc. Empty to clear them",
+            )
+            .example(":dep_flags tokio --cfg tokio_unstable"),
+            AvailableCommand::new(
+                ":rustflags",
+                "Set compiler flags for every crate in the build. `clear` removes them",
+                |_ctx, state, args| process_rustflags_command(state, args),
+            )
+            .optional_arg(
+                "flags",
+                ArgumentKind::Text,
+                "Flags to pass to rustc, replacing any set before",
+            )
+            .example(":rustflags -L native=/usr/local/cuda/lib64")
+            .example(":rustflags clear"),
+            AvailableCommand::new(
+                ":build_env",
+                "Set or unset an environment variable for builds, including build scripts, or list \
+                 those set",
+                |_ctx, state, args| process_build_env_command(state, args),
+            )
+            .optional_arg(
+                "action",
+                ArgumentKind::Choice(&["set", "unset"]),
+                "Whether to set or unset the variable",
+            )
+            .optional_arg(
+                "variable",
+                ArgumentKind::Text,
+                "KEY=VALUE to set, or KEY to unset",
+            )
+            .example(":build_env set CUDA_PATH=/usr/local/cuda")
+            .example(":build_env unset CUDA_PATH"),
+            AvailableCommand::new(
+                ":build_log",
+                "Show cargo's output, including from build scripts, while building (on/off)",
+                |_ctx, state, args| {
+                    match args.as_deref() {
+                        Some("on") => state.set_build_log(true),
+                        Some("off") => state.set_build_log(false),
+                        None => {}
+                        Some(other) => bail!(":build_log expects 'on' or 'off', got '{}'", other),
+                    }
+                    text_output(format!(
+                        "Build log: {}",
+                        if state.build_log() { "on" } else { "off" }
+                    ))
+                },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["on", "off"]),
+                "Whether to show cargo's output",
             ),
+            AvailableCommand::new(
+                ":link",
+                "Link against a native library, so that functions declared in extern blocks can be \
+                 called. :link <libname> [path]",
+                |_ctx, state, args| process_link_command(state, args),
+            )
+            .optional_arg(
+                "libname",
+                ArgumentKind::Text,
+                "The library's name, without any lib prefix or extension",
+            )
+            .optional_arg(
+                "path",
+                ArgumentKind::Path,
+                "The directory containing the library, if it's not on the search path",
+            )
+            .example(":link m")
+            .example(":link mylib ./build"),
+            AvailableCommand::new(
+                ":dev_crate",
+                "Use a local crate and reload it whenever it changes. :dev_crate [path]",
+                |ctx, state, args| process_dev_crate_command(ctx, state, args),
+            )
+            .optional_arg(
+                "path",
+                ArgumentKind::Path,
+                "The directory containing the crate's Cargo.toml",
+            )
+            .example(":dev_crate ../my_crate")
+            .with_analysis_callback(|_ctx, state, args| {
+                if let Some(path) = args {
+                    let dev_crate = DevCrate::new(Path::new(path.trim()))?;
+                    state.add_dep(&dev_crate.name, &dev_crate.dep_config())?;
+                }
+                Ok(EvalOutputs::default())
+            }),
+            AvailableCommand::new(
+                ":lock",
+                "Pin dependency versions. :lock import <path> | :lock export <path> | \
+                 :lock persist [path|off]",
+                |ctx, state, args| process_lock_command(ctx, state, args),
+            )
+            .optional_arg(
+                "action",
+                ArgumentKind::Choice(&["import", "export", "persist"]),
+                "Copy a lockfile into the session or the session's lockfile out, or keep the \
+                 session's lockfile in a file across sessions",
+            )
+            .optional_arg("path", ArgumentKind::Path, "The lockfile")
+            .example(":lock import Cargo.lock")
+            .example(":lock export session.lock")
+            .example(":lock persist"),
+            AvailableCommand::new(
+                ":unlock",
+                "Stop pinning dependency versions and re-resolve on the next build",
+                |ctx, state, _args| {
+                    state.set_lockfile(None);
+                    let lock_path = ctx.eval_context.cargo_lock_path();
+                    if lock_path.exists() {
+                        std::fs::remove_file(lock_path)?;
+                    }
+                    text_output("Dependency versions are no longer pinned")
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":last_compile_dir",
+                "Print the directory in which we last compiled",
+                |ctx, _state, _args| {
+                    text_output(format!("{:?}", ctx.eval_context.last_compile_dir()))
+                },
+            ),
+            AvailableCommand::new(
+                ":opt",
+                "Set optimization level (0/1/2)",
+                |_ctx, state, args| {
+                    let new_level = if let Some(n) = args {
+                        n
+                    } else if state.opt_level() == "2" {
+                        "0"
+                    } else {
+                        "2"
+                    };
+                    state.set_opt_level(new_level)?;
+                    text_output(format!("Optimization: {}", state.opt_level()))
+                },
+            )
+            .optional_arg(
+                "level",
+                ArgumentKind::Choice(&["0", "1", "2"]),
+                "The optimization level. Toggles between 0 and 2 if omitted",
+            ),
+            AvailableCommand::new(
+                ":fmt",
+                "Set output formatter (default: {:?})",
+                |_ctx, state, args| {
+                    let new_format = if let Some(f) = args { f } else { "{:?}" };
+                    state.set_output_format(new_format.to_owned());
+                    text_output(format!("Output format: {}", state.output_format()))
+                },
+            )
+            .optional_arg("format", ArgumentKind::Text, "A format string")
+            .example(":fmt {:#?}"),
+            AvailableCommand::new(
+                ":rustfmt",
+                "Show the last cell with code, or cell N, formatted with rustfmt",
+                |ctx, _state, args| process_rustfmt_command(ctx, args),
+            )
+            .optional_arg(
+                "N",
+                ArgumentKind::Integer,
+                "The number of a cell with code, counting from 1",
+            )
+            .example(":rustfmt")
+            .example(":rustfmt 3"),
+            AvailableCommand::new(
+                ":numfmt",
+                "Set how numbers in results are displayed, e.g. sep=, sig=4 sci=1e6 (or off)",
+                |_ctx, state, args| {
+                    if let Some(settings) = args {
+                        state.set_number_format(settings)?;
+                    }
+                    text_output(format!("Number format: {}", state.number_format()))
+                },
+            )
+            .optional_arg("settings", ArgumentKind::Text, "Settings to change, or off")
+            .example(":numfmt sep=, sig=4")
+            .example(":numfmt off"),
+            AvailableCommand::new(
+                ":table_rows",
+                "Set how many rows of a DataFrame are shown per page (default: 20)",
+                |_ctx, state, args| {
+                    if let Some(rows) = args {
+                        match rows.trim().parse::<usize>() {
+                            Ok(rows) => state.set_table_rows(rows)?,
+                            Err(_) => bail!("Invalid number of rows: {}", rows),
+                        }
+                    }
+                    text_output(format!("Table rows per page: {}", state.table_rows()))
+                },
+            )
+            .optional_arg("rows", ArgumentKind::Integer, "Rows per page")
+            .example(":table_rows 50"),
+            AvailableCommand::new(
+                ":output_limit",
+                "Set the most stdout, in MB, that an evaluation may print before it's truncated (or off)",
+                |_ctx, state, args| {
+                    if let Some(limit) = args {
+                        state.set_output_limit(limit)?;
+                    }
+                    text_output(format!("Output limit: {}", state.output_limit()))
+                },
+            )
+            .optional_arg("megabytes", ArgumentKind::Text, "The limit in MB, or off")
+            .example(":output_limit 50"),
+            AvailableCommand::new(
+                ":max_output_lines",
+                "Set how many lines of a result are shown before the rest is hidden (or off)",
+                |_ctx, state, args| {
+                    if let Some(max_lines) = args {
+                        state.set_max_output_lines(max_lines)?;
+                    }
+                    text_output(format!("Max output lines: {}", state.max_output_lines()))
+                },
+            )
+            .optional_arg("lines", ArgumentKind::Text, "The number of lines, or off")
+            .example(":max_output_lines 100"),
+            AvailableCommand::new(
+                ":cargo_retries",
+                "Set how many times cargo is retried after a network, lock or file-in-use failure",
+                |_ctx, state, args| {
+                    if let Some(retries) = args {
+                        match retries.trim().parse::<u32>() {
+                            Ok(retries) => state.set_cargo_retries(retries),
+                            Err(_) => bail!("Invalid number of retries: {}", retries),
+                        }
+                    }
+                    text_output(format!("Cargo retries: {}", state.cargo_retries()))
+                },
+            )
+            .optional_arg("retries", ArgumentKind::Integer, "Retries, or 0 to disable")
+            .example(":cargo_retries 5"),
             AvailableCommand::new(
                 ":efmt",
                 "Set the formatter for errors returned by ?",
@@ -444,17 +1668,91 @@ Panic detected. Here's some useful information if you're filing a bug report.
                         state.error_format_trait()
                     ))
                 },
-            ),
+            )
+            .optional_arg(
+                "format",
+                ArgumentKind::Text,
+                "A format string, or the name of a formatting trait",
+            )
+            .example(":efmt Debug"),
             AvailableCommand::new(
                 ":toolchain",
                 "Set which toolchain to use (e.g. nightly)",
-                |_ctx, state, args| {
+                |ctx, state, args| {
                     if let Some(arg) = args {
-                        state.set_toolchain(arg);
+                        if arg != state.toolchain() {
+                            state.set_toolchain(arg);
+                            ctx.check_items_still_compile(state, &format!("toolchain {}", arg))?;
+                        }
                     }
                     text_output(format!("Toolchain: {}", state.toolchain()))
                 },
-            ),
+            )
+            .with_analysis_callback(|_ctx, state, args| {
+                if let Some(arg) = args {
+                    state.set_toolchain(arg);
+                }
+                Ok(EvalOutputs::default())
+            })
+            .optional_arg("toolchain", ArgumentKind::Text, "A rustup toolchain name")
+            .example(":toolchain nightly")
+            .example(":toolchain nightly-2024-01-01"),
+            AvailableCommand::new(
+                ":edition",
+                "Set which Rust edition code is compiled with",
+                |ctx, state, args| {
+                    if let Some(arg) = args {
+                        if arg != state.edition() {
+                            state.set_edition(arg)?;
+                            ctx.check_items_still_compile(state, &format!("edition {}", arg))?;
+                        }
+                    }
+                    text_output(format!("Edition: {}", state.edition()))
+                },
+            )
+            .with_analysis_callback(|_ctx, state, args| {
+                if let Some(arg) = args {
+                    state.set_edition(arg)?;
+                }
+                Ok(EvalOutputs::default())
+            })
+            .optional_arg(
+                "edition",
+                ArgumentKind::Choice(crate::eval_context::EDITIONS),
+                "A Rust edition",
+            )
+            .example(":edition 2024"),
+            AvailableCommand::new(
+                ":crate_attr",
+                "Add, remove or list crate attributes, e.g. allow(dead_code)",
+                |ctx, state, args| process_crate_attr_command(ctx, state, args),
+            )
+            .optional_arg("--remove", ArgumentKind::Flag, "Remove the attribute")
+            .optional_arg(
+                "attribute",
+                ArgumentKind::Code,
+                "An attribute, with or without the surrounding #![]",
+            )
+            .example(":crate_attr allow(dead_code)")
+            .example(":crate_attr --remove allow(dead_code)"),
+            AvailableCommand::new(
+                ":features",
+                "Enable, disable or list unstable features (requires nightly)",
+                |ctx, state, args| process_features_command(ctx, state, args),
+            )
+            .optional_arg("--remove", ArgumentKind::Flag, "Disable the features")
+            .optional_arg("features", ArgumentKind::Text, "Feature names, separated by commas")
+            .example(":features never_type")
+            .example(":features --remove never_type"),
+            AvailableCommand::new(
+                ":warn",
+                "Set the level of lints, e.g. allow unused_variables, or list the levels set",
+                |ctx, state, args| process_warn_command(ctx, state, args),
+            )
+            .optional_arg("level", ArgumentKind::Choice(LINT_LEVELS), "The level to set")
+            .optional_arg("lints", ArgumentKind::Text, "Lint names, separated by commas")
+            .example(":warn allow unused_variables")
+            .example(":warn deny warnings"),
             AvailableCommand::new(
                 ":offline",
                 "Set offline mode when invoking cargo",
@@ -462,6 +1760,11 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     state.set_offline_mode(args.as_ref().map(String::as_str) == Some("1"));
                     text_output(format!("Offline mode: {}", state.offline_mode()))
                 },
+            )
+            .optional_arg(
+                "enabled",
+                ArgumentKind::Choice(&["0", "1"]),
+                "Whether to avoid network access",
             ),
             AvailableCommand::new(
                 ":quit",
@@ -477,6 +1780,180 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("Timing: {}", ctx.print_timings))
                 },
             ),
+            AvailableCommand::new(
+                ":time",
+                "Print how long the rest of the cell takes to compile and run",
+                |ctx, _state, args| {
+                    if args.is_some() {
+                        bail!(":time doesn't take arguments. It times the code that follows it");
+                    }
+                    ctx.time_this_eval = true;
+                    Ok(EvalOutputs::new())
+                },
+            ),
+            AvailableCommand::new(
+                ":timeit",
+                "Run an expression repeatedly and print statistics. :timeit [-n iterations] <expr>",
+                |ctx, state, args| process_timeit_command(ctx, state, args),
+            )
+            .optional_arg(
+                "-n iterations",
+                ArgumentKind::Integer,
+                "How many times to run the expression (default: 100)",
+            )
+            .arg("expression", ArgumentKind::Code, "The expression to run")
+            .example(":timeit -n 1000 fib(20)")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":profile",
+                "Run an expression under a sampling profiler and show a flamegraph",
+                |ctx, state, args| process_profile_command(ctx, state, args),
+            )
+            .arg("expression", ArgumentKind::Code, "The expression to run")
+            .example(":profile fib(30)")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":profiles",
+                "List the session profiles, or use one. :profiles [use <name>]",
+                |ctx, state, args| process_profiles_command(ctx, state, args),
+            )
+            .optional_arg(
+                "action",
+                ArgumentKind::Choice(&["use"]),
+                "Evaluate a profile on top of what's already there",
+            )
+            .optional_arg("name", ArgumentKind::Text, "The profile to use")
+            .example(":profiles use data-science")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":bench",
+                "Benchmark expressions with criterion. :bench [--plot] <expr>[; <expr>...]",
+                |ctx, state, args| process_bench_command(ctx, state, args),
+            )
+            .optional_arg(
+                "--plot",
+                ArgumentKind::Flag,
+                "Show a violin plot comparing the expressions",
+            )
+            .arg(
+                "expressions",
+                ArgumentKind::Code,
+                "The expressions to benchmark, separated by `;`",
+            )
+            .example(":bench fib(20)")
+            .example(":bench --plot sort_slowly(&v); sort_quickly(&v)")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":test",
+                "Run #[test] functions defined in the session. :test [filter]",
+                |ctx, state, args| process_test_command(ctx, state, args),
+            )
+            .optional_arg(
+                "filter",
+                ArgumentKind::Text,
+                "Only run tests whose names contain this",
+            )
+            .example(":test parse")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":bg",
+                "Run an expression on a background thread. If it's a future, it's polled there",
+                |ctx, state, args| process_bg_command(ctx, state, args),
+            )
+            .arg("expression", ArgumentKind::Code, "The expression to run")
+            .example(":bg download_all(urls)")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":jobs",
+                "List running background jobs",
+                |ctx, _state, _args| text_output(ctx.eval_context.jobs().summary()),
+            ),
+            AvailableCommand::new(
+                ":kill",
+                "Cancel a background job",
+                |ctx, state, args| process_kill_command(ctx, state, args),
+            )
+            .arg("id", ArgumentKind::Integer, "The job's ID, as shown by :jobs")
+            .example(":kill 1")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":sh",
+                "Run a shell command in the subprocess's working directory. Also `!command`",
+                |ctx, state, args| process_sh_command(ctx, state, args),
+            )
+            .arg("command", ArgumentKind::Text, "The command to run")
+            .example(":sh ls -la")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":sql",
+                "Run SQL with DuckDB. The rest of the cell is the query, whose result is shown as a \
+                 table. :sql [let name[: Vec<Record>] =] or :sql connect <url>",
+                |ctx, state, args| process_sql_command(ctx, state, args),
+            )
+            .optional_arg(
+                "query",
+                ArgumentKind::Text,
+                "The SQL to run, which may continue on the following lines",
+            )
+            .example(":sql SELECT 42 AS answer")
+            .example(":sql let sales =")
+            .example(":sql connect sales.duckdb")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":env",
+                "Set or unset an environment variable of the subprocess, or list those set",
+                |ctx, _state, args| {
+                    if let Some(command) = parse_env_args(":env", args.as_deref().unwrap_or(""))? {
+                        ctx.eval_context.run_env_command(command)?;
+                    }
+                    text_output(ctx.eval_context.env_summary())
+                },
+            )
+            .optional_arg(
+                "action",
+                ArgumentKind::Choice(&["set", "unset"]),
+                "Whether to set or unset the variable",
+            )
+            .optional_arg(
+                "variable",
+                ArgumentKind::Text,
+                "KEY=VALUE to set, or KEY to unset",
+            )
+            .example(":env set RUST_LOG=debug")
+            .example(":env unset RUST_LOG")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":cd",
+                "Change the working directory of the subprocess",
+                |ctx, _state, args| {
+                    let dir = match args {
+                        Some(dir) => dir.trim(),
+                        None => bail!(":cd requires a directory"),
+                    };
+                    let command = EnvCommand::change_dir(Path::new(dir))?;
+                    text_output(ctx.eval_context.run_env_command(command)?)
+                },
+            )
+            .arg("dir", ArgumentKind::Path, "The new working directory")
+            .example(":cd data")
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":pwd",
+                "Print the working directory of the subprocess",
+                |ctx, _state, _args| {
+                    text_output(ctx.eval_context.run_env_command(EnvCommand::GetWorkingDir)?)
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":why_slow",
+                "Suggest why the last evaluation was slow and what might help",
+                |ctx, _state, _args| match &ctx.last_eval_profile {
+                    Some(profile) => text_output(profile.report()),
+                    None => bail!("Nothing has been evaluated yet"),
+                },
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":time_passes",
                 "Toggle printing of rustc pass times (requires nightly)",
@@ -492,7 +1969,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     state.set_sccache(args.as_ref().map(String::as_str) != Some("0"))?;
                     text_output(format!("sccache: {}", state.sccache()))
                 },
-            ),
+            )
+            .optional_arg("enabled", ArgumentKind::Choice(&["0", "1"]), "Whether to use sccache"),
             AvailableCommand::new(
                 ":linker",
                 "Set/print linker. Supported: system, lld, mold",
@@ -502,12 +1980,57 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     }
                     text_output(format!("linker: {}", state.linker()))
                 },
+            )
+            .optional_arg(
+                "linker",
+                ArgumentKind::Choice(&["system", "lld", "mold"]),
+                "The linker to use",
             ),
+            AvailableCommand::new(
+                ":target",
+                "Set/print the target to build for. e.g. wasm32-wasi. Use `host` to reset",
+                |_ctx, state, args| {
+                    if let Some(target) = args {
+                        let target = target.trim();
+                        state.set_target(if target == "host" {
+                            None
+                        } else {
+                            Some(target.to_owned())
+                        });
+                    }
+                    text_output(format!("target: {}", state.target().unwrap_or("host")))
+                },
+            )
+            .optional_arg("target", ArgumentKind::Text, "A target triple, or host")
+            .example(":target wasm32-wasi"),
+            AvailableCommand::new(
+                ":runner",
+                "Set/print the command used to run code built for another target. e.g. wasmtime",
+                |_ctx, state, args| {
+                    if let Some(runner) = args {
+                        let runner = runner.trim();
+                        state.set_runner(if runner == "default" {
+                            None
+                        } else {
+                            Some(runner.to_owned())
+                        });
+                    }
+                    text_output(format!("runner: {}", state.runner().unwrap_or("default")))
+                },
+            )
+            .optional_arg(
+                "command",
+                ArgumentKind::Text,
+                "The command to run executables with, or default",
+            )
+            .example(":runner wasmtime"),
             AvailableCommand::new(
                 ":explain",
-                "Print explanation of last error",
-                |ctx, _state, _args| {
-                    if ctx.last_errors.is_empty() {
+                "Print explanation of last error, or of an error code",
+                |ctx, _state, args| {
+                    if let Some(code) = args.as_deref().filter(|code| !code.trim().is_empty()) {
+                        text_output(ctx.eval_context.explain_code(code)?)
+                    } else if ctx.last_errors.is_empty() {
                         bail!("No last error to explain");
                     } else {
                         let mut all_explanations = String::new();
@@ -521,7 +2044,9 @@ Panic detected. Here's some useful information if you're filing a bug report.
                         text_output(all_explanations)
                     }
                 },
-            ),
+            )
+            .optional_arg("code", ArgumentKind::Text, "An error code, e.g. E0597")
+            .example(":explain E0502"),
             AvailableCommand::new(
                 ":last_error_json",
                 "Print the last compilation error as JSON (for debugging)",
@@ -535,74 +2060,1070 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     bail!(errors_out);
                 },
             ),
-            AvailableCommand::new(":help", "Print command help", |_ctx, _state, _args| {
-                use std::fmt::Write;
-                let mut text = String::new();
-                let mut html = String::new();
-                writeln!(html, "<table>")?;
-                let mut commands = CommandContext::create_commands();
-                commands.sort_by(|a, b| a.name.cmp(b.name));
-                for cmd in commands {
-                    writeln!(text, "{:<17} {}", cmd.name, cmd.short_description).unwrap();
-                    writeln!(
-                        html,
-                        "<tr><td>{}</td><td>{}</td></tr>",
-                        cmd.name, cmd.short_description
-                    )?;
-                }
-                writeln!(html, "</table>")?;
-                Ok(EvalOutputs::text_html(text, html))
-            }),
+            AvailableCommand::new(":help", "Print command help", |_ctx, _state, args| {
+                process_help_command(args)
+            })
+            .optional_arg("--json", ArgumentKind::Flag, "Describe commands as JSON")
+            .optional_arg("command", ArgumentKind::Text, "The command to describe")
+            .example(":help :dep")
+            .example(":help --json"),
         ]
     }
 
-    fn vars_as_text(&self) -> String {
-        let mut out = String::new();
-        for (var, ty) in self.eval_context.variables_and_types() {
-            out.push_str(var);
-            out.push_str(": ");
-            out.push_str(ty);
-            out.push('\n');
+    fn vars_as_text(&self) -> String {
+        let mut out = String::new();
+        for (var, ty) in self.eval_context.variables_and_types() {
+            out.push_str(var);
+            out.push_str(": ");
+            out.push_str(ty);
+            out.push('\n');
+        }
+        out
+    }
+
+    fn vars_as_html(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<table><tr><th>Variable</th><th>Type</th></tr>");
+        for (var, ty) in self.eval_context.variables_and_types() {
+            out.push_str("<tr><td>");
+            html_escape(var, &mut out);
+            out.push_str("</td><td>");
+            html_escape(ty, &mut out);
+            out.push_str("</td><tr>");
+        }
+        out.push_str("</table>");
+        out
+    }
+}
+
+fn process_dep_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    use regex::Regex;
+    let args = if let Some(v) = args {
+        v
+    } else {
+        bail!(":dep requires arguments")
+    };
+    let mut args = args.as_str();
+    let mut force = false;
+    let mut scoped = false;
+    loop {
+        if let Some(rest) = args.strip_prefix("--force") {
+            force = true;
+            args = rest.trim_start();
+        } else if let Some(rest) = args.strip_prefix("--scoped") {
+            scoped = true;
+            args = rest.trim_start();
+        } else {
+            break;
+        }
+    }
+    static DEP_RE: OnceCell<Regex> = OnceCell::new();
+    let dep_re = DEP_RE.get_or_init(|| Regex::new("^([^= ]+) *(= *(.+))?$").unwrap());
+    if let Some(captures) = dep_re.captures(args) {
+        let key = (
+            captures[1].to_owned(),
+            captures.get(3).map_or("\"*\"", |m| m.as_str()).to_owned(),
+        );
+        // Resolving a dependency can be slow, especially when it fails due to network problems,
+        // so we don't retry identical dependencies that have already failed unless asked to.
+        if !force {
+            if let Some(reason) = ctx.failed_deps.get(&key) {
+                bail!(
+                    "{}\n(This dependency already failed earlier in this session. Use `:dep --force {}` to retry.)",
+                    reason,
+                    args
+                );
+            }
+        }
+        // A scoped dependency that's already part of the session stays part of it.
+        let newly_added = !state.external_deps.contains_key(&key.0);
+        if let Err(error) = state.add_dep(&key.0, &key.1) {
+            ctx.failed_deps.insert(key, error.to_string());
+            return Err(error);
+        }
+        ctx.failed_deps.remove(&key);
+        if scoped && newly_added {
+            ctx.scoped_deps.insert(key.0);
+        }
+        Ok(EvalOutputs::new())
+    } else {
+        bail!("Invalid :dep command. Expected: name = ... or just name");
+    }
+}
+
+fn process_crate_attr_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or("").trim();
+    if let Some(attribute) = args.strip_prefix("--remove") {
+        state.remove_crate_attribute(attribute)?;
+    } else if !args.is_empty() {
+        state.add_crate_attribute(args, ctx.current_command()?)?;
+    }
+    let attributes = state.crate_attributes();
+    if attributes.is_empty() {
+        text_output("No crate attributes")
+    } else {
+        text_output(attributes.join("\n"))
+    }
+}
+
+fn process_features_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let mut args = args.as_deref().unwrap_or("").trim();
+    let remove = if let Some(rest) = args.strip_prefix("--remove") {
+        args = rest;
+        true
+    } else {
+        false
+    };
+    for feature in args
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|feature| !feature.is_empty())
+    {
+        if !feature.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            bail!("Invalid feature name `{}`", feature);
+        }
+        let attribute = format!("feature({})", feature);
+        if remove {
+            state.remove_crate_attribute(&attribute)?;
+        } else {
+            state.add_crate_attribute(&attribute, ctx.current_command()?)?;
+        }
+    }
+    let features = enabled_features(state);
+    if features.is_empty() {
+        text_output("Features: none")
+    } else {
+        text_output(format!("Features: {}", features.join(", ")))
+    }
+}
+
+/// Returns the names of the features enabled by `#![feature(...)]` attributes, however they were
+/// added.
+fn enabled_features(state: &ContextState) -> Vec<&str> {
+    let mut features = Vec::new();
+    for attribute in state.crate_attributes() {
+        if let Some(list) = attribute
+            .strip_prefix("#![feature(")
+            .and_then(|rest| rest.strip_suffix(")]"))
+        {
+            features.extend(list.split(',').map(str::trim).filter(|f| !f.is_empty()));
+        }
+    }
+    features.sort_unstable();
+    features
+}
+
+/// The levels that `:warn` can set lints to.
+const LINT_LEVELS: &[&str] = &["allow", "warn", "deny"];
+
+fn process_warn_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or("").trim();
+    if !args.is_empty() {
+        let (level, lints) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        if !LINT_LEVELS.contains(&level) {
+            bail!(
+                "Lint level must be 'allow', 'warn' or 'deny', got '{}'",
+                level
+            );
+        }
+        let lints: Vec<&str> = lints
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|lint| !lint.is_empty())
+            .collect();
+        if lints.is_empty() {
+            bail!(":warn {} requires the names of lints", level);
+        }
+        for lint in lints {
+            if !lint
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == ':')
+            {
+                bail!("Invalid lint name `{}`", lint);
+            }
+            // Any level set for the lint before is replaced, rather than depending on the order of
+            // the attributes.
+            for previous_level in LINT_LEVELS {
+                let attribute = format!("#![{}({})]", previous_level, lint);
+                if state.crate_attributes().contains(&attribute.as_str()) {
+                    state.remove_crate_attribute(&attribute)?;
+                }
+            }
+            state.add_crate_attribute(&format!("{}({})", level, lint), ctx.current_command()?)?;
+        }
+    }
+    let levels = lint_levels(state);
+    if levels.is_empty() {
+        text_output("Lint levels: default")
+    } else {
+        text_output(format!("Lint levels: {}", levels.join(", ")))
+    }
+}
+
+/// Returns the lint levels set by crate attributes, e.g. `allow(unused_variables)` for
+/// `#![allow(unused_variables)]`, however they were added.
+fn lint_levels(state: &ContextState) -> Vec<&str> {
+    state
+        .crate_attributes()
+        .into_iter()
+        .filter_map(|attribute| {
+            let level = attribute.strip_prefix("#![")?.strip_suffix(']')?;
+            LINT_LEVELS
+                .iter()
+                .any(|name| {
+                    level
+                        .strip_prefix(name)
+                        .map_or(false, |rest| rest.starts_with('('))
+                })
+                .then(|| level)
+        })
+        .collect()
+}
+
+fn process_dep_flags_command(
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or("").trim();
+    if args.is_empty() {
+        let flags: Vec<String> = state
+            .dep_flags()
+            .map(|(dep, flags)| format!("{}: {}", dep, flags))
+            .collect();
+        if flags.is_empty() {
+            return text_output("No dependency flags set");
+        }
+        return text_output(flags.join("\n"));
+    }
+    let (dep, flags) = args.split_once(' ').unwrap_or((args, ""));
+    if !state.external_deps.contains_key(dep) {
+        bail!(
+            "{} isn't a dependency. Add it with :dep before setting its flags",
+            dep
+        );
+    }
+    state.set_dep_flags(dep, flags)?;
+    Ok(EvalOutputs::new())
+}
+
+fn process_rustflags_command(
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    match args.as_deref().unwrap_or("").trim() {
+        "" => {}
+        "clear" => state.set_rustflags(""),
+        flags => state.set_rustflags(flags),
+    }
+    if state.rustflags().is_empty() {
+        return text_output("No rustflags set");
+    }
+    text_output(state.rustflags().join(" "))
+}
+
+fn process_build_env_command(
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    match parse_env_args(":build_env", args.as_deref().unwrap_or(""))? {
+        Some(EnvCommand::Set(key, value)) => state.set_build_var(&key, Some(&value)),
+        Some(EnvCommand::Unset(key)) => state.set_build_var(&key, None),
+        _ => {}
+    }
+    if state.build_env().is_empty() {
+        return text_output("No build environment variables set");
+    }
+    let vars: Vec<String> = state
+        .build_env()
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    text_output(vars.join("\n"))
+}
+
+/// Handles `:dep! <query>`, which adds the top match for `query` as if by `:dep`.
+fn process_dep_search_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let query = args.as_deref().unwrap_or("");
+    let search = state.search_crates(query)?;
+    let top = match search.matches.first() {
+        Some(top) => top,
+        None => bail!("No crates matched `{}`", query.trim()),
+    };
+    let dependency = top.dependency();
+    process_dep_command(ctx, state, &Some(dependency.clone()))?;
+    text_output(format!("Added {}", dependency))
+}
+
+/// Handles `:deps tree [crate]` and `:deps features <crate>`.
+fn process_deps_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args: Vec<&str> = args.as_deref().unwrap_or("").split_whitespace().collect();
+    // Cargo reads the session's Cargo.toml, which needs to include any dependencies that were just
+    // added.
+    ctx.eval_context.write_cargo_toml(state)?;
+    match args.as_slice() {
+        ["tree"] => text_output(state.dependency_tree(None)?),
+        ["tree", package] => text_output(state.dependency_tree(Some(*package))?),
+        ["features", package] => {
+            let mut out = String::new();
+            for features in state.package_features(package)? {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(&format!(
+                    "{} {}\nEnabled: {}\nAvailable: {}\n",
+                    package,
+                    features.version,
+                    list_or_none(&features.enabled),
+                    list_or_none(&features.available)
+                ));
+            }
+            text_output(out.trim_end())
+        }
+        _ => bail!("Usage: :deps tree [crate] or :deps features <crate>"),
+    }
+}
+
+/// Handles `:cache`, which reports on the cache of compiled dependencies shared between sessions,
+/// and `:cache clean|on|off`.
+fn process_cache_command(
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let root = match dep_cache::cache_root() {
+        Some(root) => root,
+        None => bail!("There's no cache directory on this system, so dependencies aren't cached"),
+    };
+    match args.as_deref().map(str::trim) {
+        Some("clean") => {
+            let freed = dep_cache::clean(&root)?;
+            return text_output(format!(
+                "Removed cached dependencies, freeing {}",
+                about::format_bytes(freed)
+            ));
+        }
+        Some("on") => state.set_dep_cache(true),
+        Some("off") => state.set_dep_cache(false),
+        None | Some("") => {}
+        Some(other) => bail!(":cache expects 'on', 'off' or 'clean', got '{}'", other),
+    }
+    let (entries, bytes) = dep_cache::usage(&root);
+    text_output(format!(
+        "Dependency cache: {}\nLocation: {}\nEntries: {} ({})",
+        if state.dep_cache() { "on" } else { "off" },
+        root.display(),
+        entries,
+        about::format_bytes(bytes)
+    ))
+}
+
+fn list_or_none(items: &[String]) -> String {
+    if items.is_empty() {
+        "(none)".to_owned()
+    } else {
+        items.join(", ")
+    }
+}
+
+fn process_link_command(
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or("").trim();
+    if args.is_empty() {
+        let libs: Vec<String> = state
+            .native_libs()
+            .iter()
+            .map(NativeLib::to_string)
+            .collect();
+        if libs.is_empty() {
+            return text_output("No native libraries are linked");
         }
-        out
+        return text_output(libs.join("\n"));
     }
+    let (name, dir) = match args.split_once(' ') {
+        Some((name, dir)) => (name, Some(Path::new(dir.trim()))),
+        None => (args, None),
+    };
+    state.add_native_lib(NativeLib::new(name, dir)?);
+    Ok(EvalOutputs::new())
+}
 
-    fn vars_as_html(&self) -> String {
-        let mut out = String::new();
-        out.push_str("<table><tr><th>Variable</th><th>Type</th></tr>");
-        for (var, ty) in self.eval_context.variables_and_types() {
-            out.push_str("<tr><td>");
-            html_escape(var, &mut out);
-            out.push_str("</td><td>");
-            html_escape(ty, &mut out);
-            out.push_str("</td><tr>");
+/// Handles `:help [--json] [command]`.
+fn process_help_command(args: &Option<String>) -> Result<EvalOutputs, Error> {
+    use std::fmt::Write;
+    let mut json = false;
+    let mut command_name = None;
+    for arg in args.as_deref().unwrap_or("").split_whitespace() {
+        match arg {
+            "--json" => json = true,
+            name if command_name.is_none() => command_name = Some(name),
+            _ => bail!("Usage: :help [--json] [command]"),
         }
-        out.push_str("</table>");
-        out
     }
+    let infos = match command_name {
+        Some(name) => {
+            let name = format!(":{}", name.trim_start_matches(':'));
+            match CommandContext::commands_by_name().get(name.as_str()) {
+                Some(command) => vec![command.info()],
+                None => bail!("Unknown command: {}", name),
+            }
+        }
+        None => CommandContext::command_infos(),
+    };
+    if json {
+        let json = if command_name.is_some() {
+            infos[0].to_json()
+        } else {
+            infos
+                .iter()
+                .map(CommandInfo::to_json)
+                .collect::<Vec<_>>()
+                .into()
+        };
+        let mut outputs = EvalOutputs::new();
+        outputs
+            .content_by_mime_type
+            .insert("text/plain".to_owned(), Content::Text(json.pretty(2)));
+        outputs
+            .content_by_mime_type
+            .insert("application/json".to_owned(), Content::Json(json));
+        return Ok(outputs);
+    }
+    if command_name.is_some() {
+        return text_output(infos[0].to_text());
+    }
+    let mut text = String::new();
+    let mut html = String::new();
+    writeln!(html, "<table>")?;
+    for info in infos {
+        writeln!(text, "{:<17} {}", info.name, info.description)?;
+        let mut description = String::new();
+        html_escape(info.description, &mut description);
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            info.name, description
+        )?;
+    }
+    writeln!(html, "</table>")?;
+    text.push_str("\nUse :help <command> for details of a command's arguments.\n");
+    Ok(EvalOutputs::text_html(text, html))
 }
 
-fn process_dep_command(
+/// The number of times `:timeit` runs an expression if not told otherwise.
+const DEFAULT_TIMEIT_ITERATIONS: usize = 100;
+
+/// Splits the arguments to `:timeit` into the number of iterations and the expression to run.
+fn parse_timeit_args(args: &str) -> Result<(usize, &str), Error> {
+    let args = args.trim();
+    let (iterations, expression) = match args.strip_prefix("-n ") {
+        Some(rest) => {
+            let rest = rest.trim_start();
+            let (count, expression) = rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+            match count.parse::<usize>() {
+                Ok(iterations) if iterations > 0 => (iterations, expression.trim()),
+                _ => bail!("Invalid iteration count for :timeit: {}", count),
+            }
+        }
+        None => (DEFAULT_TIMEIT_ITERATIONS, args),
+    };
+    if expression.is_empty() {
+        bail!(":timeit requires an expression. e.g. :timeit -n 1000 fib(20)");
+    }
+    Ok((iterations, expression))
+}
+
+fn process_timeit_command(
+    ctx: &mut CommandContext,
     state: &mut ContextState,
     args: &Option<String>,
 ) -> Result<EvalOutputs, Error> {
-    use regex::Regex;
-    let args = if let Some(v) = args {
-        v
+    let (iterations, expression) = parse_timeit_args(args.as_deref().unwrap_or_default())?;
+    let code = format!(
+        "crate::evcxr_internal_runtime::time_iterations({}, || {{ {} }});",
+        iterations, expression
+    );
+    let start = std::time::Instant::now();
+    let mut outputs = ctx.eval_context.eval_with_state(&code, state.clone())?;
+    *state = ctx.eval_context.state();
+    let mut timing = outputs.timing.take().unwrap_or_default();
+    timing.set_phases(start.elapsed(), &outputs.phases);
+    outputs.timing = Some(timing);
+    Ok(outputs)
+}
+
+fn process_doc_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let path = match args.as_deref().map(str::trim) {
+        Some(path) if !path.is_empty() => path,
+        _ => bail!(":doc requires a path. e.g. :doc std::collections::HashMap"),
+    };
+    let docs = match ctx.eval_context.documentation(path, state)? {
+        Some(docs) => docs,
+        None => bail!("Couldn't find an item called `{}`", path),
+    };
+    let mut outputs = EvalOutputs::new();
+    outputs
+        .content_by_mime_type
+        .insert("text/plain".to_owned(), Content::Text(docs.to_text()));
+    outputs.content_by_mime_type.insert(
+        "text/markdown".to_owned(),
+        Content::Text(docs.to_markdown()),
+    );
+    Ok(outputs)
+}
+
+fn process_expand_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+) -> Result<EvalOutputs, Error> {
+    let command = ctx.current_command()?;
+    if command
+        .args
+        .as_deref()
+        .map_or(true, |args| args.trim().is_empty())
+    {
+        bail!(":expand requires code. e.g. :expand vec![1, 2, 3]");
+    }
+    let expanded = ctx.eval_context.expand(command, state)?;
+    let mut outputs = EvalOutputs::new();
+    outputs.content_by_mime_type.insert(
+        "text/markdown".to_owned(),
+        Content::Text(format!("```rust\n{}\n```", expanded)),
+    );
+    // Frontends that highlight Rust, like the REPL, can use this instead of text/plain.
+    outputs
+        .content_by_mime_type
+        .insert("text/rust".to_owned(), Content::Text(expanded.clone()));
+    outputs
+        .content_by_mime_type
+        .insert("text/plain".to_owned(), Content::Text(expanded));
+    Ok(outputs)
+}
+
+fn process_generated_code_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+    emit: Emit,
+) -> Result<EvalOutputs, Error> {
+    let path = match args.as_deref().map(str::trim) {
+        Some(path) if !path.is_empty() => path,
+        _ => bail!(
+            "{} requires a function. e.g. {} double",
+            emit.command_name(),
+            emit.command_name()
+        ),
+    };
+    let generated = ctx.eval_context.generated_code(emit, path, state)?;
+    let mut outputs = EvalOutputs::new();
+    outputs.content_by_mime_type.insert(
+        "text/markdown".to_owned(),
+        Content::Text(format!(
+            "```{}\n{}\n```",
+            emit.markdown_language(),
+            generated
+        )),
+    );
+    if emit == Emit::Mir {
+        outputs
+            .content_by_mime_type
+            .insert("text/rust".to_owned(), Content::Text(generated.clone()));
+    }
+    outputs
+        .content_by_mime_type
+        .insert("text/plain".to_owned(), Content::Text(generated));
+    Ok(outputs)
+}
+
+fn process_bench_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or_default().trim();
+    let (plot, args) = match args.strip_prefix("--plot") {
+        Some(rest) => (true, rest),
+        None => (false, args),
+    };
+    let expressions = bench::parse_expressions(args)?;
+    if plot && expressions.len() < 2 {
+        bail!(":bench --plot needs at least two expressions to compare");
+    }
+    if ctx.eval_context.local_child_process_id().is_none() {
+        bail!(":bench is only supported when code runs in a local subprocess");
+    }
+    if !state.external_deps.contains_key("criterion") {
+        state.add_dep("criterion", bench::CRITERION_DEPENDENCY)?;
+    }
+    let output_dir = ctx.eval_context.last_compile_dir().join("criterion");
+    let violin_plot = bench::violin_plot_path(&output_dir);
+    // Make sure that we don't show a plot left over from an earlier run.
+    let _ = std::fs::remove_file(&violin_plot);
+    let code = bench::bench_code(&expressions, &output_dir, plot);
+    let mut outputs = ctx.eval_context.eval_with_state(&code, state.clone())?;
+    *state = ctx.eval_context.state();
+    if plot {
+        let svg = match std::fs::read_to_string(&violin_plot) {
+            Ok(svg) => svg,
+            Err(error) => bail!("Failed to read criterion's violin plot: {}", error),
+        };
+        outputs.content_by_mime_type.insert(
+            "text/plain".to_owned(),
+            Content::Text(format!(
+                "Violin plot written to {}\n",
+                violin_plot.display()
+            )),
+        );
+        outputs
+            .content_by_mime_type
+            .insert("image/svg+xml".to_owned(), Content::Text(svg));
+    }
+    Ok(outputs)
+}
+
+fn process_test_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    if state.target().is_some() {
+        bail!(":test isn't supported when building for another target");
+    }
+    let filter = args.as_deref().map(str::trim).unwrap_or_default();
+    let tests: Vec<_> = state
+        .tests()
+        .into_iter()
+        .filter(|test| test.name.contains(filter))
+        .collect();
+    if tests.is_empty() {
+        if filter.is_empty() {
+            return text_output("No tests found");
+        }
+        return text_output(format!("No tests match `{}`", filter));
+    }
+    let mut outputs = ctx.eval_context.run_tests(&tests, state)?;
+    *state = ctx.eval_context.state();
+    if let Some(report) = &outputs.tests {
+        outputs
+            .content_by_mime_type
+            .insert("text/plain".to_owned(), Content::Text(report.to_text()));
+        outputs
+            .content_by_mime_type
+            .insert("text/html".to_owned(), Content::Html(report.to_html()));
+    }
+    Ok(outputs)
+}
+
+/// Code that runs an expression as a background job. Within the expression, `print!` and
+/// `println!` are replaced so that output is tagged with the job's ID.
+const BACKGROUND_JOB_CODE: &str = r#"
+crate::evcxr_internal_runtime::spawn_job(&evcxr_jobs, JOB_ID,
+    move |evcxr_job: &crate::evcxr_internal_runtime::Job| {
+        macro_rules! print {
+            ($($arg:tt)*) => { evcxr_job.print(&format!($($arg)*)) };
+        }
+        macro_rules! println {
+            () => { evcxr_job.print("\n") };
+            ($($arg:tt)*) => { evcxr_job.print(&format!("{}\n", format_args!($($arg)*))) };
+        }
+        use crate::evcxr_internal_runtime::RunFutureJob as _;
+        use crate::evcxr_internal_runtime::RunValueJob as _;
+        crate::evcxr_internal_runtime::JobValue({ EXPRESSION
+        }).run_job(evcxr_job);
+    });
+"#;
+
+fn process_bg_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let expression = match args.as_deref().map(str::trim) {
+        Some(expression) if !expression.is_empty() => expression,
+        _ => bail!(":bg requires an expression. e.g. :bg download_all(urls)"),
+    };
+    if state.target().is_some() {
+        bail!(":bg isn't supported when building for another target");
+    }
+    let id = ctx
+        .eval_context
+        .jobs()
+        .start(expression.lines().next().unwrap_or_default());
+    state.enable_background_jobs();
+    let code = BACKGROUND_JOB_CODE
+        .replace("JOB_ID", &id.to_string())
+        .replace("EXPRESSION", expression);
+    let mut outputs = match ctx.eval_context.eval_with_state(&code, state.clone()) {
+        Ok(outputs) => outputs,
+        Err(error) => {
+            ctx.eval_context.jobs().abandon(id);
+            return Err(error);
+        }
+    };
+    *state = ctx.eval_context.state();
+    outputs.content_by_mime_type.insert(
+        "text/plain".to_owned(),
+        Content::Text(format!("Started job {}", id)),
+    );
+    Ok(outputs)
+}
+
+/// Runs `:sql`, adding evcxr_sql as a dependency if it isn't one already.
+fn process_sql_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let command = sql::parse(args.as_deref().unwrap_or_default())?;
+    let code = match &command {
+        SqlCommand::Connect(None) => {
+            return text_output(match state.sql_connection() {
+                Some(url) => format!("Connected to {}", url),
+                None => "Using an in-memory DuckDB database".to_owned(),
+            });
+        }
+        SqlCommand::Connect(Some(url)) => sql::connect_code(url),
+        SqlCommand::Query {
+            binding,
+            sql: query,
+        } => sql::query_code(state.sql_connection(), binding.as_ref(), query),
+    };
+    if !state.external_deps.contains_key("evcxr_sql") {
+        state.add_dep("evcxr_sql", sql::SQL_DEPENDENCY)?;
+    }
+    let outputs = ctx.eval_context.eval_with_state(&code, state.clone())?;
+    *state = ctx.eval_context.state();
+    if let SqlCommand::Connect(Some(url)) = command {
+        state.set_sql_connection(url);
+        return text_output(format!("Connected to {}", url));
+    }
+    Ok(outputs)
+}
+
+/// Runs a shell command from `:sh`, or from `!` or `let name = !`, which are translated to `:sh`.
+fn process_sh_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or_default().trim();
+    if args.is_empty() {
+        bail!(":sh requires a command. e.g. :sh ls -la");
+    }
+    let code = match args
+        .strip_prefix(SH_CAPTURE_FLAG)
+        .and_then(|rest| rest.trim_start().split_once(' '))
+    {
+        Some((variable, command)) => format!(
+            "let {} = crate::evcxr_internal_runtime::capture_shell_command({:?});",
+            variable, command
+        ),
+        None => format!(
+            "crate::evcxr_internal_runtime::run_shell_command({:?});",
+            args
+        ),
+    };
+    let outputs = ctx.eval_context.eval_with_state(&code, state.clone())?;
+    *state = ctx.eval_context.state();
+    Ok(outputs)
+}
+
+fn process_kill_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let id = match args.as_deref().map(|id| id.trim().parse::<u64>()) {
+        Some(Ok(id)) => id,
+        _ => bail!(":kill requires a job ID. e.g. :kill 1"),
+    };
+    if !ctx.eval_context.jobs().is_running(id) {
+        bail!("No running job with ID {}. Use :jobs to list jobs.", id);
+    }
+    let mut outputs = ctx.eval_context.eval_with_state(
+        &format!(
+            "crate::evcxr_internal_runtime::cancel_job(&evcxr_jobs, {});",
+            id
+        ),
+        state.clone(),
+    )?;
+    *state = ctx.eval_context.state();
+    ctx.eval_context.jobs().set_cancelling(id);
+    outputs.content_by_mime_type.insert(
+        "text/plain".to_owned(),
+        Content::Text(format!(
+            "Cancelling job {}. It stops at its next await, or when it next checks \
+             evcxr_job.is_cancelled().",
+            id
+        )),
+    );
+    Ok(outputs)
+}
+
+/// Lists the profiles defined in `profiles.evcxr`, marking those in use.
+fn list_profiles(ctx: &CommandContext) -> Result<EvalOutputs, Error> {
+    let profiles = match crate::profiles::profiles_file() {
+        Some(path) => crate::profiles::load(&path)?,
+        None => Vec::new(),
+    };
+    if profiles.is_empty() {
+        return text_output(format!(
+            "No profiles are defined in {}",
+            crate::profiles::describe_profiles_file()
+        ));
+    }
+    let lines: Vec<String> = profiles
+        .iter()
+        .map(|profile| {
+            if ctx.profiles_in_use.contains(&profile.name) {
+                format!("{} (in use)", profile.name)
+            } else {
+                profile.name.clone()
+            }
+        })
+        .collect();
+    text_output(lines.join("\n"))
+}
+
+/// The number of cells that `:rustfmt N` can format. Older cells are forgotten.
+const MAX_KEPT_INPUTS: usize = 100;
+
+/// Handles `:rustfmt [N]`, which shows a cell formatted with rustfmt.
+fn process_rustfmt_command(
+    ctx: &mut CommandContext,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    match parse_rustfmt_args(args.as_deref().unwrap_or("")) {
+        Some(number) => text_output(ctx.format_input(number)?),
+        None => bail!("Usage: :rustfmt [N], where N is the number of a cell"),
+    }
+}
+
+/// Returns which cell `args` of `:rustfmt` ask to format: `Some(None)` for the last one,
+/// `Some(Some(N))` for cell N, or `None` if they aren't valid.
+fn parse_rustfmt_args(args: &str) -> Option<Option<usize>> {
+    let args = args.trim();
+    if args.is_empty() {
+        return Some(None);
+    }
+    args.parse().ok().map(Some)
+}
+
+fn process_profiles_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or("").trim();
+    if args.is_empty() {
+        return list_profiles(ctx);
+    }
+    let (subcommand, name) = match args.split_once(char::is_whitespace) {
+        Some((subcommand, name)) => (subcommand, name.trim()),
+        None => (args, ""),
+    };
+    if subcommand != "use" {
+        bail!(
+            "Unknown :profiles subcommand `{}`. Expected use",
+            subcommand
+        );
+    }
+    if name.is_empty() {
+        bail!(":profiles use requires a profile name. e.g. :profiles use data-science");
+    }
+    let result = ctx.use_profile(name);
+    *state = ctx.eval_context.state();
+    result
+}
+
+fn process_profile_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    use std::io::Write;
+    let expression = match args.as_deref().map(str::trim) {
+        Some(expression) if !expression.is_empty() => expression,
+        _ => bail!(":profile requires an expression. e.g. :profile fib(30)"),
+    };
+    let pid = match ctx.eval_context.local_child_process_id() {
+        Some(pid) => pid,
+        None => bail!(":profile is only supported when code runs in a local subprocess"),
+    };
+    let profiler = Profiler::start(pid)?;
+    let result = ctx
+        .eval_context
+        .eval_with_state(&format!("drop({{ {} }});", expression), state.clone());
+    // Stop the profiler even if evaluation failed, but report the evaluation error in preference.
+    let svg = profiler.finish();
+    let mut outputs = result?;
+    *state = ctx.eval_context.state();
+    let svg = svg?;
+    // The REPL can't display the flamegraph, so we save it somewhere that the user can open it
+    // from. Notebooks display the SVG directly.
+    let mut file = tempfile::Builder::new()
+        .prefix("evcxr-profile-")
+        .suffix(".svg")
+        .tempfile()?;
+    file.write_all(svg.as_bytes())?;
+    let path = file.into_temp_path().keep().map_err(|error| error.error)?;
+    outputs.content_by_mime_type.insert(
+        "text/plain".to_owned(),
+        Content::Text(format!("Flamegraph written to {}\n", path.display())),
+    );
+    outputs
+        .content_by_mime_type
+        .insert("image/svg+xml".to_owned(), Content::Text(svg));
+    Ok(outputs)
+}
+
+fn process_dev_crate_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let path = match args {
+        Some(path) => path.trim(),
+        None => {
+            if ctx.dev_crates.is_empty() {
+                return text_output("No dev crates");
+            }
+            let lines: Vec<String> = ctx
+                .dev_crates
+                .iter()
+                .map(|dev_crate| format!("{}: {}", dev_crate.name, dev_crate.path.display()))
+                .collect();
+            return text_output(lines.join("\n"));
+        }
+    };
+    let dev_crate = DevCrate::new(Path::new(path))?;
+    state.add_dep(&dev_crate.name, &dev_crate.dep_config())?;
+    let message = format!(
+        "Watching {} at {}",
+        dev_crate.name,
+        dev_crate.path.display()
+    );
+    ctx.dev_crates
+        .retain(|existing| existing.name != dev_crate.name);
+    ctx.dev_crates.push(dev_crate);
+    text_output(message)
+}
+
+fn process_lock_command(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    args: &Option<String>,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or("").trim();
+    if args.is_empty() {
+        let mut message = match state.lockfile() {
+            Some(lockfile) => format!(
+                "Dependency versions pinned to those imported from {}",
+                lockfile.display()
+            ),
+            None => "Dependency versions are not pinned".to_owned(),
+        };
+        if let Some(lockfile) = state.persistent_lockfile() {
+            message.push_str(&format!(". Persisted in {}", lockfile.display()));
+        }
+        return text_output(message);
+    }
+    let (subcommand, path) = match args.split_once(char::is_whitespace) {
+        Some((subcommand, path)) => (subcommand, path.trim()),
+        None => (args, ""),
+    };
+    if subcommand == "persist" {
+        return persist_lockfile(ctx, state, path);
+    }
+    if subcommand != "import" && subcommand != "export" {
+        bail!(
+            "Unknown :lock subcommand `{}`. Expected import, export or persist",
+            subcommand
+        );
+    }
+    if path.is_empty() {
+        bail!(":lock {} requires a path", subcommand);
+    }
+    let path = std::env::current_dir()?.join(path);
+    let lock_path = ctx.eval_context.cargo_lock_path();
+    if subcommand == "export" {
+        if !lock_path.exists() {
+            bail!("No Cargo.lock has been written yet. Try evaluating some code first.");
+        }
+        std::fs::copy(lock_path, &path)?;
+        return text_output(format!("Wrote {}", path.display()));
+    }
+    if !path.exists() {
+        bail!("Lockfile {} doesn't exist", path.display());
+    }
+    // This is a one-off copy. Later builds may update our Cargo.lock, e.g. when dependencies are
+    // added, but never the imported file. That only changes with an explicit `:lock export`.
+    std::fs::copy(&path, lock_path)?;
+    let message = format!("Dependency versions pinned to {}", path.display());
+    state.set_lockfile(Some(path));
+    text_output(message)
+}
+
+/// The file in the config directory that `:lock persist` uses when it isn't given a path.
+const PERSISTENT_LOCKFILE_NAME: &str = "Cargo.lock";
+
+/// Handles `:lock persist [path|off]`. Without a path, the lockfile is kept in the config
+/// directory.
+fn persist_lockfile(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    path: &str,
+) -> Result<EvalOutputs, Error> {
+    if path == "off" {
+        state.set_persistent_lockfile(None);
+        return text_output("Dependency versions will no longer be persisted");
+    }
+    let path = if path.is_empty() {
+        match crate::config_dir() {
+            Some(config_dir) => config_dir.join(PERSISTENT_LOCKFILE_NAME),
+            None => bail!("Couldn't find the config directory. Give a path to persist to."),
+        }
     } else {
-        bail!(":dep requires arguments")
+        std::env::current_dir()?.join(path)
     };
-    static DEP_RE: OnceCell<Regex> = OnceCell::new();
-    let dep_re = DEP_RE.get_or_init(|| Regex::new("^([^= ]+) *(= *(.+))?$").unwrap());
-    if let Some(captures) = dep_re.captures(args) {
-        state.add_dep(
-            &captures[1],
-            captures.get(3).map_or("\"*\"", |m| m.as_str()),
-        )?;
-        Ok(EvalOutputs::new())
+    let lock_path = ctx.eval_context.cargo_lock_path();
+    let message = if path.exists() {
+        crate::eval_context::load_persistent_lockfile(&path, &lock_path)?;
+        format!(
+            "Dependency versions loaded from {}, which will be updated when they change",
+            path.display()
+        )
     } else {
-        bail!("Invalid :dep command. Expected: name = ... or just name");
-    }
+        format!("Dependency versions will be saved to {}", path.display())
+    };
+    state.set_persistent_lockfile(Some(path));
+    text_output(message)
 }
 
 type CallbackFn = dyn Fn(&mut CommandContext, &mut ContextState, &Option<String>) -> Result<EvalOutputs, Error>
@@ -613,6 +3134,8 @@ type CallbackFn = dyn Fn(&mut CommandContext, &mut ContextState, &Option<String>
 struct AvailableCommand {
     name: &'static str,
     short_description: &'static str,
+    arguments: Vec<ArgumentInfo>,
+    examples: Vec<&'static str>,
     callback: Box<CallbackFn>,
     /// If `Some`, this callback will be run when preparing for analysis instead of `callback`.
     analysis_callback: Option<Box<CallbackFn>>,
@@ -634,6 +3157,8 @@ impl AvailableCommand {
         AvailableCommand {
             name,
             short_description,
+            arguments: Vec::new(),
+            examples: Vec::new(),
             callback: Box::new(callback),
             analysis_callback: None,
         }
@@ -657,6 +3182,49 @@ impl AvailableCommand {
     fn disable_in_analysis(self) -> Self {
         self.with_analysis_callback(|_ctx, _state, _args| Ok(EvalOutputs::default()))
     }
+
+    fn arg(self, name: &'static str, kind: ArgumentKind, description: &'static str) -> Self {
+        self.with_argument(name, kind, description, false)
+    }
+
+    fn optional_arg(
+        self,
+        name: &'static str,
+        kind: ArgumentKind,
+        description: &'static str,
+    ) -> Self {
+        self.with_argument(name, kind, description, true)
+    }
+
+    fn with_argument(
+        mut self,
+        name: &'static str,
+        kind: ArgumentKind,
+        description: &'static str,
+        optional: bool,
+    ) -> Self {
+        self.arguments.push(ArgumentInfo {
+            name,
+            kind,
+            optional,
+            description,
+        });
+        self
+    }
+
+    fn example(mut self, example: &'static str) -> Self {
+        self.examples.push(example);
+        self
+    }
+
+    fn info(&self) -> CommandInfo {
+        CommandInfo {
+            name: self.name,
+            description: self.short_description,
+            arguments: self.arguments.clone(),
+            examples: self.examples.clone(),
+        }
+    }
 }
 
 fn html_escape(input: &str, out: &mut String) {
@@ -670,12 +3238,49 @@ fn html_escape(input: &str, out: &mut String) {
     }
 }
 
+/// Returns the first line of `code`, followed by "..." if there's more.
+fn cell_summary(code: &str) -> String {
+    let first_line = code.lines().next().unwrap_or_default();
+    if first_line.len() < code.len() {
+        format!("{} ...", first_line)
+    } else {
+        first_line.to_owned()
+    }
+}
+
+/// Adds a newline to the end of the text/plain output, if there is some and it doesn't already end
+/// with one.
+fn end_with_newline(outputs: &mut EvalOutputs) {
+    if let Some(Content::Text(text)) = outputs.content_by_mime_type.get_mut("text/plain") {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    }
+}
+
+/// Returns an error reporting that `error` occurred in the `index`th (1-based) of the `count` cells
+/// that were run again because of a change, which starts with `first_line`. The positions of compilation errors
+/// are in that cell, rather than in the one being evaluated, so only their messages are included.
+fn rerun_failure(first_line: &str, index: usize, count: usize, error: Error) -> Error {
+    let mut message = format!("Stopped at cell {} of {} ({}):", index, count, first_line);
+    match error {
+        Error::CompilationErrors(errors) => {
+            for error in errors {
+                message.push('\n');
+                message.push_str(&error.message());
+            }
+        }
+        error => message.push_str(&format!("\n{}", error)),
+    }
+    Error::Message(message)
+}
+
 fn text_output<T: Into<String>>(text: T) -> Result<EvalOutputs, Error> {
     let mut outputs = EvalOutputs::new();
     let mut content = text.into();
     content.push('\n');
     outputs
         .content_by_mime_type
-        .insert("text/plain".to_owned(), content);
+        .insert("text/plain".to_owned(), Content::Text(content));
     Ok(outputs)
 }
@@ -0,0 +1,205 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Machine-readable descriptions of the commands that start with ':'. These are used to generate
+//! `:help` and can be given to frontends, e.g. to build a command palette or a form for a
+//! command's arguments.
+
+use json::object;
+use json::JsonValue;
+use std::fmt::Write;
+
+/// What kind of value an argument takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgumentKind {
+    /// The argument is a flag, whose name is given literally, e.g. `--force`.
+    Flag,
+    /// One of a fixed set of values.
+    Choice(&'static [&'static str]),
+    Integer,
+    /// A number, which may have a fractional part.
+    Number,
+    Path,
+    /// Rust code, such as an expression.
+    Code,
+    /// Any other text.
+    Text,
+}
+
+impl ArgumentKind {
+    fn type_name(self) -> &'static str {
+        match self {
+            ArgumentKind::Flag => "flag",
+            ArgumentKind::Choice(_) => "choice",
+            ArgumentKind::Integer => "integer",
+            ArgumentKind::Number => "number",
+            ArgumentKind::Path => "path",
+            ArgumentKind::Code => "code",
+            ArgumentKind::Text => "text",
+        }
+    }
+}
+
+/// An argument to a command. Arguments are given in order, separated by spaces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArgumentInfo {
+    pub name: &'static str,
+    pub kind: ArgumentKind,
+    pub optional: bool,
+    pub description: &'static str,
+}
+
+impl ArgumentInfo {
+    /// How the argument is shown in a synopsis, e.g. `<path>` or `[on|off]`.
+    fn usage(&self) -> String {
+        let usage = match self.kind {
+            ArgumentKind::Choice(choices) => choices.join("|"),
+            _ => self.name.to_owned(),
+        };
+        if self.optional {
+            format!("[{}]", usage)
+        } else {
+            format!("<{}>", usage)
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "name" => self.name,
+            "type" => self.kind.type_name(),
+            "optional" => self.optional,
+            "description" => self.description,
+        };
+        if let ArgumentKind::Choice(choices) = self.kind {
+            json["choices"] = choices.to_vec().into();
+        }
+        json
+    }
+}
+
+/// A description of a command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandInfo {
+    /// The name of the command, including the leading ':'.
+    pub name: &'static str,
+    pub description: &'static str,
+    pub arguments: Vec<ArgumentInfo>,
+    /// Example invocations of the command.
+    pub examples: Vec<&'static str>,
+}
+
+impl CommandInfo {
+    /// Returns a one-line summary of how to invoke the command, e.g. `:mem [on|off]`.
+    pub fn synopsis(&self) -> String {
+        let mut synopsis = self.name.to_owned();
+        for argument in &self.arguments {
+            synopsis.push(' ');
+            synopsis.push_str(&argument.usage());
+        }
+        synopsis
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "name" => self.name,
+            "synopsis" => self.synopsis(),
+            "description" => self.description,
+            "arguments" => self.arguments.iter().map(ArgumentInfo::to_json).collect::<Vec<_>>(),
+            "examples" => self.examples.clone(),
+        }
+    }
+
+    /// Returns detailed help for the command, as shown by `:help <command>`.
+    pub(crate) fn to_text(&self) -> String {
+        let mut text = format!("{}\n{}\n", self.synopsis(), self.description);
+        if !self.arguments.is_empty() {
+            text.push_str("\nArguments:\n");
+            for argument in &self.arguments {
+                writeln!(
+                    text,
+                    "  {:<17} {}{}",
+                    argument.usage(),
+                    argument.description,
+                    if argument.optional { " (optional)" } else { "" }
+                )
+                .unwrap();
+            }
+        }
+        if !self.examples.is_empty() {
+            text.push_str("\nExamples:\n");
+            for example in &self.examples {
+                writeln!(text, "  {}", example).unwrap();
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArgumentInfo;
+    use super::ArgumentKind;
+    use super::CommandInfo;
+
+    fn info() -> CommandInfo {
+        CommandInfo {
+            name: ":lock",
+            description: "Pin dependency versions",
+            arguments: vec![
+                ArgumentInfo {
+                    name: "action",
+                    kind: ArgumentKind::Choice(&["import", "export"]),
+                    optional: true,
+                    description: "What to do with the lockfile",
+                },
+                ArgumentInfo {
+                    name: "path",
+                    kind: ArgumentKind::Path,
+                    optional: false,
+                    description: "The lockfile",
+                },
+            ],
+            examples: vec![":lock import Cargo.lock"],
+        }
+    }
+
+    #[test]
+    fn synopsis_and_text() {
+        let info = info();
+        assert_eq!(info.synopsis(), ":lock [import|export] <path>");
+        assert_eq!(
+            info.to_text(),
+            ":lock [import|export] <path>\n\
+             Pin dependency versions\n\
+             \n\
+             Arguments:\n\
+             \x20 [import|export]   What to do with the lockfile (optional)\n\
+             \x20 <path>            The lockfile\n\
+             \n\
+             Examples:\n\
+             \x20 :lock import Cargo.lock\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let json = info().to_json();
+        assert_eq!(json["synopsis"], ":lock [import|export] <path>");
+        assert_eq!(json["arguments"][0]["type"], "choice");
+        assert_eq!(json["arguments"][0]["choices"][1], "export");
+        assert_eq!(json["arguments"][1]["optional"], false);
+        assert!(json["arguments"][1]["choices"].is_null());
+        assert_eq!(json["examples"][0], ":lock import Cargo.lock");
+    }
+}
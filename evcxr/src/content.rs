@@ -0,0 +1,165 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use json::JsonValue;
+
+/// Content of a particular mime type in `EvalOutputs`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Content {
+    /// Plain text, or any other textual format besides HTML and JSON, e.g. `image/svg+xml`.
+    Text(String),
+    Html(String),
+    /// Binary data, e.g. `image/png`.
+    Binary(Vec<u8>),
+    /// JSON, e.g. `application/json` or `application/vnd.vegalite.v4+json`.
+    Json(JsonValue),
+}
+
+impl Content {
+    /// Converts `data`, content of type `mime_type` as sent by user code, into the appropriate
+    /// variant. Binary formats sent as text, e.g. via `mime_type(..).text(..)` or a display
+    /// handle, are base64 encoded. If `data` isn't valid for its type, it's kept as text.
+    pub(crate) fn decode(mime_type: &str, data: String) -> Content {
+        if is_binary(mime_type) {
+            let encoded: String = data.split_whitespace().collect();
+            if let Ok(bytes) = base64::decode(encoded) {
+                return Content::Binary(bytes);
+            }
+        } else if is_json(mime_type) {
+            if let Ok(value) = json::parse(&data) {
+                return Content::Json(value);
+            }
+        } else if mime_type == "text/html" {
+            return Content::Html(data);
+        }
+        Content::Text(data)
+    }
+
+    /// Returns the content if it's text or HTML.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Content::Text(text) | Content::Html(text) => Some(text),
+            Content::Binary(_) | Content::Json(_) => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Content::Binary(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_json(&self) -> Option<&JsonValue> {
+        match self {
+            Content::Json(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the content as it should appear in a Jupyter mime bundle. Binary content is base64
+    /// encoded, as Jupyter requires.
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Content::Text(text) | Content::Html(text) => JsonValue::from(text.as_str()),
+            Content::Binary(bytes) => JsonValue::from(base64::encode(bytes)),
+            Content::Json(value) => value.clone(),
+        }
+    }
+
+    /// Combines `other`, which has the same mime type, with this content. Text is concatenated,
+    /// while binary and JSON content can't be, so is replaced.
+    pub(crate) fn append(&mut self, other: Content) {
+        match (self, other) {
+            (Content::Text(text), Content::Text(more))
+            | (Content::Html(text), Content::Html(more)) => text.push_str(&more),
+            (this, other) => *this = other,
+        }
+    }
+}
+
+fn is_binary(mime_type: &str) -> bool {
+    let (kind, subtype) = mime_type.split_once('/').unwrap_or((mime_type, ""));
+    match kind {
+        "image" => !subtype.ends_with("+xml"),
+        "audio" | "video" => true,
+        "application" => matches!(subtype, "pdf" | "octet-stream" | "zip"),
+        _ => false,
+    }
+}
+
+fn is_json(mime_type: &str) -> bool {
+    mime_type == "application/json" || mime_type.ends_with("+json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Content;
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            Content::decode("text/plain", "42".to_owned()),
+            Content::Text("42".to_owned())
+        );
+        assert_eq!(
+            Content::decode("text/html", "<b>42</b>".to_owned()),
+            Content::Html("<b>42</b>".to_owned())
+        );
+        assert_eq!(
+            Content::decode("image/png", "AAEC\n/w==".to_owned()),
+            Content::Binary(vec![0, 1, 2, 255])
+        );
+        assert_eq!(
+            Content::decode("image/svg+xml", "<svg/>".to_owned()),
+            Content::Text("<svg/>".to_owned())
+        );
+        assert_eq!(
+            Content::decode(
+                "application/vnd.vegalite.v4+json",
+                "{\"a\": [1]}".to_owned()
+            ),
+            Content::Json(json::object! {"a" => json::array![1]})
+        );
+        // Malformed content is kept as it is.
+        assert_eq!(
+            Content::decode("image/png", "not base64!".to_owned()),
+            Content::Text("not base64!".to_owned())
+        );
+        assert_eq!(
+            Content::decode("application/json", "{".to_owned()),
+            Content::Text("{".to_owned())
+        );
+    }
+
+    #[test]
+    fn to_json() {
+        assert_eq!(Content::Binary(vec![0, 1, 2, 255]).to_json(), "AAEC/w==");
+        assert_eq!(Content::Text("hi".to_owned()).to_json(), "hi");
+        assert_eq!(
+            Content::Json(json::array![1, 2]).to_json(),
+            json::array![1, 2]
+        );
+    }
+
+    #[test]
+    fn append() {
+        let mut content = Content::Text("a".to_owned());
+        content.append(Content::Text("b".to_owned()));
+        assert_eq!(content, Content::Text("ab".to_owned()));
+        let mut content = Content::Binary(vec![1]);
+        content.append(Content::Binary(vec![2]));
+        assert_eq!(content, Content::Binary(vec![2]));
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `:search`, which finds crates on crates.io, and `:dep!`, which adds the best match. We use
+//! `cargo search`, which queries the crates.io API. Results are cached per query, so that searches
+//! that have been done before still work offline.
+
+use crate::cargo_retry;
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::eval_context::Config;
+use std::fmt;
+use std::path::PathBuf;
+
+/// How many crates a search shows.
+const SEARCH_LIMIT: usize = 10;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CrateMatch {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) description: String,
+}
+
+impl CrateMatch {
+    /// Returns what to pass to `:dep` to add this crate.
+    pub(crate) fn dependency(&self) -> String {
+        format!("{} = \"{}\"", self.name, self.version)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CrateSearch {
+    pub(crate) matches: Vec<CrateMatch>,
+    /// Whether the matches came from the cache, because crates.io couldn't be searched.
+    pub(crate) cached: bool,
+}
+
+impl fmt::Display for CrateSearch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .matches
+            .iter()
+            .map(|m| m.dependency().len())
+            .max()
+            .unwrap_or(0);
+        for m in &self.matches {
+            if m.description.is_empty() {
+                writeln!(f, "{}", m.dependency())?;
+            } else {
+                writeln!(
+                    f,
+                    "{:width$}  # {}",
+                    m.dependency(),
+                    m.description,
+                    width = width
+                )?;
+            }
+        }
+        if self.cached {
+            write!(
+                f,
+                "(Couldn't reach crates.io, so these results are from an earlier search)"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Searches crates.io for `query`. If that fails, e.g. because we're offline, we fall back to the
+/// results of the last successful search for the same query.
+pub(crate) fn search(config: &Config, query: &str) -> Result<CrateSearch, Error> {
+    let query = query.trim();
+    if query.is_empty() {
+        bail!(":search requires a query. e.g. :search json");
+    }
+    let cache_file = cache_dir().map(|dir| dir.join(cache_file_name(query)));
+    let failure = if config.offline_mode {
+        "offline mode is on".to_owned()
+    } else {
+        let mut command = config.cargo_command("search");
+        command
+            .arg("--limit")
+            .arg(SEARCH_LIMIT.to_string())
+            .arg(query);
+        match cargo_retry::output_with_retries(&mut command, config, &|_| {}) {
+            Ok(output) if output.status.success() => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                if let Some(cache_file) = &cache_file {
+                    // Caching is best-effort, so a search still works if the cache can't be written.
+                    if let Some(dir) = cache_file.parent() {
+                        let _ = std::fs::create_dir_all(dir);
+                    }
+                    let _ = std::fs::write(cache_file, stdout.as_bytes());
+                }
+                return Ok(CrateSearch {
+                    matches: parse_search_output(&stdout),
+                    cached: false,
+                });
+            }
+            Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            Err(error) => error.to_string(),
+        }
+    };
+    match cache_file.and_then(|cache_file| std::fs::read_to_string(cache_file).ok()) {
+        Some(cached) => Ok(CrateSearch {
+            matches: parse_search_output(&cached),
+            cached: true,
+        }),
+        None => bail!(
+            "Couldn't search crates.io for `{}`, and it hasn't been searched for before: {}",
+            query,
+            failure
+        ),
+    }
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("evcxr").join("crate_search"))
+}
+
+/// Returns the name of the file that caches results for `query`. Queries that only differ in case
+/// or punctuation share a file, which is fine, since crates.io treats them the same.
+fn cache_file_name(query: &str) -> String {
+    let name: String = query
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}.txt", name)
+}
+
+/// Parses the output of `cargo search`, which has a line per crate like
+/// `regex = "1.10.2"    # An implementation of regular expressions for Rust.`, followed by notes
+/// that we ignore.
+fn parse_search_output(output: &str) -> Vec<CrateMatch> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (dependency, description) = match line.split_once('#') {
+                Some((dependency, description)) => (dependency, description.trim()),
+                None => (line, ""),
+            };
+            let (name, version) = dependency.split_once('=')?;
+            let version = version.trim().strip_prefix('"')?.strip_suffix('"')?;
+            Some(CrateMatch {
+                name: name.trim().to_owned(),
+                version: version.to_owned(),
+                description: description.to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cache_file_name;
+    use super::parse_search_output;
+    use super::CrateMatch;
+    use super::CrateSearch;
+
+    #[test]
+    fn parsing() {
+        let matches = parse_search_output(
+            "serde_json = \"1.0.108\"    # A JSON serialization file format\n\
+             json = \"0.12.4\"           # JSON implementation in Rust\n\
+             no_description = \"0.1.0\"\n\
+             ... and 3921 crates more (use --limit N to see more)\n\
+             note: to learn more about a package, run `cargo info <name>`\n",
+        );
+        assert_eq!(
+            matches,
+            vec![
+                CrateMatch {
+                    name: "serde_json".to_owned(),
+                    version: "1.0.108".to_owned(),
+                    description: "A JSON serialization file format".to_owned(),
+                },
+                CrateMatch {
+                    name: "json".to_owned(),
+                    version: "0.12.4".to_owned(),
+                    description: "JSON implementation in Rust".to_owned(),
+                },
+                CrateMatch {
+                    name: "no_description".to_owned(),
+                    version: "0.1.0".to_owned(),
+                    description: String::new(),
+                },
+            ]
+        );
+        assert_eq!(matches[0].dependency(), "serde_json = \"1.0.108\"");
+        assert_eq!(
+            CrateSearch {
+                matches,
+                cached: true
+            }
+            .to_string(),
+            "serde_json = \"1.0.108\"    # A JSON serialization file format\n\
+             json = \"0.12.4\"           # JSON implementation in Rust\n\
+             no_description = \"0.1.0\"\n\
+             (Couldn't reach crates.io, so these results are from an earlier search)"
+        );
+    }
+
+    #[test]
+    fn cache_file_names() {
+        assert_eq!(cache_file_name("Async HTTP"), "async_http.txt");
+        assert_eq!(cache_file_name("../etc"), "___etc.txt");
+    }
+}
@@ -0,0 +1,437 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Display of polars `DataFrame`s, arrow `RecordBatch`es and evcxr_sql `Table`s as tables. When any
+//! of these crates is a dependency, the code that displays the final expression of a cell checks
+//! whether the value is one of these and if so, sends its first few rows to us with `emit_table`
+//! from evcxr_internal_runtime. We render them as HTML, split into pages, and as plain text.
+
+use crate::number_format::NumberFormat;
+use std::fmt::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// The crates whose values we display as tables.
+pub(crate) const TABLE_CRATES: &[&str] = &["polars", "arrow", "evcxr_sql"];
+
+/// The most pages of rows included in HTML output. Rows beyond this aren't sent by user code.
+const MAX_PAGES: usize = 10;
+/// Cells in plain-text output are truncated to this many characters.
+const MAX_TEXT_CELL_WIDTH: usize = 40;
+
+/// Returns the code that displays the final expression, `value_code`, which should evaluate to a
+/// reference. Tables are recognised for whichever of the supported crates `has_dependency` says is
+/// available. Anything else is displayed by `display_value`, which is code that displays
+/// `evcxr_value`.
+pub(crate) fn display_code(
+    value_code: &str,
+    rows_per_page: usize,
+    has_dependency: impl Fn(&str) -> bool,
+    display_value: &str,
+) -> String {
+    let max_rows = rows_per_page * MAX_PAGES;
+    let mut code = String::from(
+        "{
+    struct EvcxrValue<'a, T>(&'a T);
+    trait EvcxrDisplayTable {
+        fn evcxr_display_table(&self) -> bool;
+    }
+    impl<'a, T> EvcxrDisplayTable for &EvcxrValue<'a, T> {
+        fn evcxr_display_table(&self) -> bool {
+            false
+        }
+    }
+",
+    );
+    if has_dependency("polars") {
+        write!(
+            code,
+            "    impl<'a> EvcxrDisplayTable for EvcxrValue<'a, ::polars::frame::DataFrame> {{
+        fn evcxr_display_table(&self) -> bool {{
+            let rows = self.0.height().min({max_rows});
+            let columns = self.0.get_columns();
+            evcxr_internal_runtime::emit_table(
+                columns.iter().map(|column| column.name().to_string()).collect(),
+                columns.iter().map(|column| column.dtype().to_string()).collect(),
+                columns
+                    .iter()
+                    .map(|column| {{
+                        let column = column.slice(0, rows).rechunk();
+                        let cells: Vec<String> = column.iter().map(|cell| cell.to_string()).collect();
+                        cells
+                    }})
+                    .collect(),
+                self.0.height(),
+            );
+            true
+        }}
+    }}
+"
+        )
+        .unwrap();
+    }
+    if has_dependency("arrow") {
+        write!(
+            code,
+            "    impl<'a> EvcxrDisplayTable for EvcxrValue<'a, ::arrow::record_batch::RecordBatch> {{
+        fn evcxr_display_table(&self) -> bool {{
+            let rows = self.0.num_rows().min({max_rows});
+            let schema = self.0.schema();
+            evcxr_internal_runtime::emit_table(
+                schema.fields().iter().map(|field| field.name().to_string()).collect(),
+                schema.fields().iter().map(|field| format!(\"{{:?}}\", field.data_type())).collect(),
+                self.0
+                    .columns()
+                    .iter()
+                    .map(|column| {{
+                        (0..rows)
+                            .map(|row| {{
+                                ::arrow::util::display::array_value_to_string(column, row)
+                                    .unwrap_or_else(|error| error.to_string())
+                            }})
+                            .collect()
+                    }})
+                    .collect(),
+                self.0.num_rows(),
+            );
+            true
+        }}
+    }}
+"
+        )
+        .unwrap();
+    }
+    if has_dependency("evcxr_sql") {
+        write!(
+            code,
+            "    impl<'a> EvcxrDisplayTable for EvcxrValue<'a, ::evcxr_sql::Table> {{
+        fn evcxr_display_table(&self) -> bool {{
+            let rows = &self.0.rows()[..self.0.len().min({max_rows})];
+            evcxr_internal_runtime::emit_table(
+                self.0.columns().to_vec(),
+                self.0.column_types().to_vec(),
+                (0..self.0.columns().len())
+                    .map(|column| rows.iter().map(|row| row[column].to_string()).collect())
+                    .collect(),
+                self.0.len(),
+            );
+            true
+        }}
+    }}
+"
+        )
+        .unwrap();
+    }
+    write!(
+        code,
+        "    let evcxr_value = {value_code};
+    if !(&EvcxrValue(evcxr_value)).evcxr_display_table() {{
+        {display_value}
+    }}
+}}"
+    )
+    .unwrap();
+    code
+}
+
+fn unescape_field(field: &str) -> String {
+    let mut result = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn split_fields(line: &str) -> Vec<String> {
+    line.split('\t').map(unescape_field).collect()
+}
+
+/// A table received from user code. Only the first rows are included.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Table {
+    names: Vec<String>,
+    types: Vec<String>,
+    rows: Vec<Vec<String>>,
+    total_rows: usize,
+}
+
+impl Table {
+    /// Parses a table sent by `emit_table`, given the rest of the line that started with
+    /// `BEGIN_TABLE`, which is the total number of rows, and the lines before `END_TABLE`. These are
+    /// column names, column types, then rows, with fields separated by tabs.
+    pub(crate) fn parse(total_rows: &str, lines: &[String]) -> Option<Table> {
+        let mut lines = lines.iter();
+        let names = split_fields(lines.next()?);
+        let types = split_fields(lines.next()?);
+        let rows = lines.map(|line| split_fields(line)).collect();
+        Some(Table {
+            names,
+            types,
+            rows,
+            total_rows: total_rows.trim().parse().ok()?,
+        })
+    }
+
+    /// Applies `number_format` to each cell.
+    pub(crate) fn format_numbers(&mut self, number_format: &NumberFormat) {
+        for cell in self.rows.iter_mut().flatten() {
+            *cell = number_format.apply(cell);
+        }
+    }
+
+    fn shape(&self) -> String {
+        format!(
+            "{} row{} × {} column{}",
+            self.total_rows,
+            if self.total_rows == 1 { "" } else { "s" },
+            self.names.len(),
+            if self.names.len() == 1 { "" } else { "s" }
+        )
+    }
+
+    /// Renders the table as HTML, with `rows_per_page` rows per page. Buttons switch between
+    /// pages, which needs the frontend to run scripts in output. Without that, only the first page
+    /// can be seen.
+    pub(crate) fn to_html(&self, rows_per_page: usize) -> String {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = format!(
+            "evcxr-table-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let rows_per_page = rows_per_page.max(1);
+        let mut html = format!(
+            "<div id=\"{}\" data-page=\"0\">\n<table>\n<thead><tr><th></th>",
+            id
+        );
+        for (name, type_name) in self.names.iter().zip(&self.types) {
+            write!(
+                html,
+                "<th>{}<br><small>{}</small></th>",
+                escape_html(name),
+                escape_html(type_name)
+            )
+            .unwrap();
+        }
+        html.push_str("</tr></thead>\n");
+        for (page, rows) in self.rows.chunks(rows_per_page).enumerate() {
+            html.push_str(if page == 0 {
+                "<tbody>"
+            } else {
+                "<tbody style=\"display: none\">"
+            });
+            for (index, row) in rows.iter().enumerate() {
+                write!(html, "<tr><th>{}</th>", page * rows_per_page + index).unwrap();
+                for cell in row {
+                    write!(html, "<td>{}</td>", escape_html(cell)).unwrap();
+                }
+                html.push_str("</tr>");
+            }
+            html.push_str("</tbody>\n");
+        }
+        html.push_str("</table>\n<p>");
+        let pages = self.rows.chunks(rows_per_page).len();
+        if pages > 1 {
+            let turn_page = |delta: i32| {
+                format!(
+                    "var d=document.getElementById('{id}'),b=d.getElementsByTagName('tbody'),\
+                     p=Math.min(Math.max(+d.dataset.page+({delta}),0),b.length-1);\
+                     for(var i=0;i<b.length;i++)b[i].style.display=i==p?'':'none';\
+                     d.dataset.page=p;d.querySelector('.evcxr-page').textContent=p+1;"
+                )
+            };
+            write!(
+                html,
+                "<button onclick=\"{}\">&lsaquo;</button> Page <span class=\"evcxr-page\">1</span> \
+                 of {} <button onclick=\"{}\">&rsaquo;</button> ",
+                turn_page(-1),
+                pages,
+                turn_page(1)
+            )
+            .unwrap();
+        }
+        html.push_str(&self.shape());
+        if self.rows.len() < self.total_rows {
+            write!(html, ", first {} shown", self.rows.len()).unwrap();
+        }
+        html.push_str("</p>\n</div>");
+        html
+    }
+
+    /// Renders the first `max_rows` rows of the table as plain text, with columns aligned.
+    pub(crate) fn to_text(&self, max_rows: usize) -> String {
+        let truncate = |cell: &str| -> String {
+            let cell = cell.replace(['\t', '\n'], " ");
+            if cell.chars().count() > MAX_TEXT_CELL_WIDTH {
+                let mut truncated: String = cell.chars().take(MAX_TEXT_CELL_WIDTH - 1).collect();
+                truncated.push('…');
+                truncated
+            } else {
+                cell
+            }
+        };
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .take(max_rows)
+            .map(|row| row.iter().map(|cell| truncate(cell)).collect())
+            .collect();
+        let names: Vec<String> = self.names.iter().map(|name| truncate(name)).collect();
+        let types: Vec<String> = self.types.iter().map(|name| truncate(name)).collect();
+        let widths: Vec<usize> = (0..names.len())
+            .map(|column| {
+                std::iter::once(&names[column])
+                    .chain(types.get(column))
+                    .chain(rows.iter().filter_map(|row| row.get(column)))
+                    .map(|cell| cell.chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let format_line = |cells: &[String]| -> String {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| {
+                    let padding = width.saturating_sub(cell.chars().count());
+                    format!("{}{}", cell, " ".repeat(padding))
+                })
+                .collect();
+            format!("{}\n", padded.join(" | ").trim_end())
+        };
+        let mut text = format_line(&names);
+        text.push_str(&format_line(&types));
+        let separators: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+        text.push_str(&separators.join("-+-"));
+        text.push('\n');
+        for row in &rows {
+            text.push_str(&format_line(row));
+        }
+        if rows.len() < self.total_rows {
+            let more = self.total_rows - rows.len();
+            writeln!(
+                text,
+                "… {} more row{}",
+                more,
+                if more == 1 { "" } else { "s" }
+            )
+            .unwrap();
+        }
+        text.push_str(&self.shape());
+        text
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::display_code;
+    use super::Table;
+    use crate::evcxr_internal_runtime::escape_field;
+
+    fn table(total_rows: usize, rows: usize) -> Table {
+        let mut lines = vec!["id\tname".to_owned(), "i64\tstr".to_owned()];
+        for row in 0..rows {
+            lines.push(format!("{}\trow {}", row, row));
+        }
+        Table::parse(&total_rows.to_string(), &lines).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let table = Table::parse(
+            "5",
+            &[
+                "id\tname".to_owned(),
+                "i64\tstr".to_owned(),
+                format!("0\t{}", escape_field("a\tb\\n")),
+            ],
+        )
+        .unwrap();
+        assert_eq!(table.names, vec!["id", "name"]);
+        assert_eq!(table.types, vec!["i64", "str"]);
+        assert_eq!(table.rows, vec![vec!["0", "a\tb\\n"]]);
+        assert_eq!(table.total_rows, 5);
+        assert_eq!(Table::parse("x", &[]), None);
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            table(2, 2).to_text(10),
+            "id  | name\n\
+             i64 | str\n\
+             ----+------\n\
+             0   | row 0\n\
+             1   | row 1\n\
+             2 rows × 2 columns"
+        );
+        assert_eq!(
+            table(100, 20).to_text(1),
+            "id  | name\n\
+             i64 | str\n\
+             ----+------\n\
+             0   | row 0\n\
+             … 99 more rows\n\
+             100 rows × 2 columns"
+        );
+    }
+
+    #[test]
+    fn html() {
+        let html = table(100, 5).to_html(2);
+        assert!(html.contains("<th>id<br><small>i64</small></th>"));
+        assert_eq!(html.matches("<tbody").count(), 3);
+        assert_eq!(html.matches("<tbody style=\"display: none\">").count(), 2);
+        assert!(html.contains("<tr><th>4</th><td>4</td>"));
+        assert!(html.contains(" of 3 <button"));
+        assert!(html.contains("100 rows × 2 columns, first 5 shown"));
+
+        let html = table(1, 1).to_html(2);
+        assert!(!html.contains("<button"));
+        assert!(html.contains("<p>1 row × 2 columns</p>"));
+    }
+
+    #[test]
+    fn code_only_mentions_available_crates() {
+        let code = display_code(
+            "&(42)",
+            5,
+            |name| name == "arrow",
+            "::print_any::print_any(evcxr_value);",
+        );
+        assert!(code.contains("RecordBatch"));
+        assert!(code.contains("num_rows().min(50)"));
+        assert!(!code.contains("polars"));
+        assert!(!code.contains("evcxr_sql"));
+        assert!(code.contains("let evcxr_value = &(42);"));
+        assert!(code.contains("::print_any::print_any(evcxr_value);"));
+    }
+}
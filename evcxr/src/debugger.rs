@@ -0,0 +1,599 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::code_block::CodeBlock;
+use crate::errors::bail;
+use crate::errors::Error;
+use json::JsonValue;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Environment variable that can be set to the command used to start a debug adapter, e.g.
+/// `codelldb --port 0` or `/usr/bin/lldb-dap`.
+const ADAPTER_VAR: &str = "EVCXR_DEBUG_ADAPTER";
+
+/// Debug adapters that we look for on `PATH` if `EVCXR_DEBUG_ADAPTER` isn't set. `lldb-vscode` is
+/// what `lldb-dap` was called before LLVM 18.
+const DEFAULT_ADAPTERS: &[&str] = &["lldb-dap", "lldb-vscode"];
+
+/// Seed for the hash used to name the files that cells are written to. Frontends compute the same
+/// hash to work out which file a cell corresponds to. This is the seed that ipykernel uses.
+const CELL_HASH_SEED: u32 = 0xc70f6907;
+
+/// How long to wait for the debug adapter to respond to a request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lets a frontend debug user code using the Debug Adapter Protocol (DAP). Requests are passed on
+/// to a debug adapter such as `lldb-dap`, which is attached to the subprocess in which user code
+/// runs. Source locations are translated between the files that cells are written to, which is
+/// what the frontend knows about, and the code that we actually compile. Breakpoints apply to the
+/// cell that's being evaluated. Cloning gives another handle to the same debugger.
+#[derive(Clone)]
+pub struct Debugger {
+    state: Arc<Mutex<DebuggerState>>,
+    events: crossbeam_channel::Receiver<JsonValue>,
+}
+
+struct DebuggerState {
+    adapter: Option<DebugAdapter>,
+    events_sender: crossbeam_channel::Sender<JsonValue>,
+    cell_dir: PathBuf,
+    process_id: u32,
+    attached: bool,
+    /// Breakpoints set by the frontend, keyed by cell file. Each is a DAP `SourceBreakpoint`.
+    breakpoints: HashMap<PathBuf, Vec<JsonValue>>,
+    /// The file for the cell currently being evaluated.
+    current_cell: Option<PathBuf>,
+    /// The code most recently compiled for `current_cell`, and the file it was written to.
+    compiled: Option<(CodeBlock, PathBuf)>,
+}
+
+impl Debugger {
+    pub(crate) fn new(cell_dir: PathBuf) -> Debugger {
+        let (events_sender, events) = crossbeam_channel::unbounded();
+        Debugger {
+            state: Arc::new(Mutex::new(DebuggerState {
+                adapter: None,
+                events_sender,
+                cell_dir,
+                process_id: 0,
+                attached: false,
+                breakpoints: HashMap::new(),
+                current_cell: None,
+                compiled: None,
+            })),
+            events,
+        }
+    }
+
+    /// Returns whether a debug adapter is available.
+    pub fn is_available() -> bool {
+        adapter_command().is_some()
+    }
+
+    /// Returns a channel that receives DAP events, such as the debuggee stopping at a breakpoint.
+    pub fn events(&self) -> crossbeam_channel::Receiver<JsonValue> {
+        self.events.clone()
+    }
+
+    /// Returns the file to which the cell containing `code` is written, for display by the
+    /// frontend.
+    pub fn cell_path(&self, code: &str) -> PathBuf {
+        cell_path(&self.state.lock().unwrap().cell_dir, code)
+    }
+
+    /// Handles a DAP request, returning the response. As well as standard DAP requests, the
+    /// requests that Jupyter adds to the protocol (`debugInfo`, `dumpCell` and
+    /// `inspectVariables`) are supported.
+    pub fn handle_request(&self, request: &JsonValue) -> JsonValue {
+        let mut response = json::object! {
+            "type" => "response",
+            "request_seq" => request["seq"].clone(),
+            "command" => request["command"].clone(),
+        };
+        match self.state.lock().unwrap().handle_request(request) {
+            Ok(body) => {
+                response["success"] = true.into();
+                response["body"] = body;
+            }
+            Err(error) => {
+                response["success"] = false.into();
+                response["message"] = error.to_string().into();
+            }
+        }
+        response
+    }
+
+    pub(crate) fn set_process_id(&self, process_id: u32) {
+        let mut state = self.state.lock().unwrap();
+        if state.process_id != process_id {
+            // Our adapter, if any, is attached to a process that no longer exists.
+            state.attached = false;
+        }
+        state.process_id = process_id;
+    }
+
+    /// Records that `code` is about to be evaluated, so that breakpoints set in its cell apply.
+    pub(crate) fn cell_started(&self, code: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.current_cell = Some(cell_path(&state.cell_dir, code));
+        state.compiled = None;
+    }
+
+    pub(crate) fn is_attached(&self) -> bool {
+        self.state.lock().unwrap().attached
+    }
+
+    /// Records that `code` was compiled from `path` and is about to be run, setting breakpoints in
+    /// it for the lines of the current cell that have them.
+    pub(crate) fn code_compiled(&self, code: &CodeBlock, path: &Path) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.compiled = Some((code.clone(), path.to_owned()));
+        if state.attached {
+            state.sync_breakpoints()?;
+        }
+        Ok(())
+    }
+}
+
+impl DebuggerState {
+    /// Handles `request`, returning the body of the response.
+    fn handle_request(&mut self, request: &JsonValue) -> Result<JsonValue, Error> {
+        let arguments = &request["arguments"];
+        match request["command"].as_str().unwrap_or_default() {
+            "debugInfo" => Ok(self.debug_info()),
+            "dumpCell" => {
+                let path = cell_path(&self.cell_dir, arguments["code"].as_str().unwrap_or(""));
+                if let Err(error) = std::fs::create_dir_all(&self.cell_dir)
+                    .and_then(|_| std::fs::write(&path, arguments["code"].as_str().unwrap_or("")))
+                {
+                    bail!("Failed to write {:?}: {}", path, error);
+                }
+                Ok(json::object! {"sourcePath" => path.to_string_lossy().into_owned()})
+            }
+            // Variables are only available while stopped, via `scopes` and `variables`.
+            "inspectVariables" => Ok(json::object! {"variables" => json::array![]}),
+            "initialize" => {
+                if self.adapter.is_none() {
+                    let command = match adapter_command() {
+                        Some(command) => command,
+                        None => bail!(
+                            "No debug adapter found. Install lldb-dap or set {} to the command \
+                             that starts one",
+                            ADAPTER_VAR
+                        ),
+                    };
+                    self.adapter = Some(DebugAdapter::start(&command, self.events_sender.clone())?);
+                }
+                self.forward("initialize", arguments.clone())
+            }
+            "attach" => {
+                if self.process_id == 0 {
+                    bail!("Debugging is only supported when user code runs in a local subprocess");
+                }
+                let mut arguments = arguments.clone();
+                arguments["pid"] = self.process_id.into();
+                let body = self.forward("attach", arguments)?;
+                self.attached = true;
+                self.sync_breakpoints()?;
+                Ok(body)
+            }
+            "setBreakpoints" => {
+                let source = PathBuf::from(arguments["source"]["path"].as_str().unwrap_or(""));
+                let breakpoints: Vec<JsonValue> =
+                    arguments["breakpoints"].members().cloned().collect();
+                self.breakpoints.insert(source.clone(), breakpoints);
+                if self.attached && self.current_cell.as_ref() == Some(&source) {
+                    if let Some(body) = self.sync_breakpoints()? {
+                        return Ok(body);
+                    }
+                }
+                // The cell hasn't been compiled, so the breakpoints can't be resolved yet.
+                Ok(json::object! {
+                    "breakpoints" => self.breakpoints[&source]
+                        .iter()
+                        .map(|breakpoint| json::object! {
+                            "verified" => false,
+                            "line" => breakpoint["line"].clone(),
+                        })
+                        .collect::<Vec<_>>(),
+                })
+            }
+            "stackTrace" => {
+                let mut body = self.forward("stackTrace", arguments.clone())?;
+                if let (Some(cell), Some((code, path))) = (&self.current_cell, &self.compiled) {
+                    for frame in body["stackFrames"].members_mut() {
+                        if frame["source"]["path"].as_str() != Some(&*path.to_string_lossy()) {
+                            continue;
+                        }
+                        let user_line = frame["line"]
+                            .as_usize()
+                            .and_then(|line| code.user_line_for_generated_line(line));
+                        if let Some(user_line) = user_line {
+                            frame["line"] = user_line.into();
+                            frame["source"] = source_for_cell(cell);
+                        }
+                    }
+                }
+                Ok(body)
+            }
+            "disconnect" => {
+                let result = self.forward("disconnect", arguments.clone());
+                self.adapter = None;
+                self.attached = false;
+                result
+            }
+            command => self.forward(command, arguments.clone()),
+        }
+    }
+
+    /// Sends `command` to the debug adapter, returning the body of its response.
+    fn forward(&mut self, command: &str, arguments: JsonValue) -> Result<JsonValue, Error> {
+        let adapter = match self.adapter.as_mut() {
+            Some(adapter) => adapter,
+            None => bail!("The debugger hasn't been initialized"),
+        };
+        let mut response = adapter.request(command, arguments)?;
+        if response["success"].as_bool() != Some(true) {
+            bail!(
+                "{} failed: {}",
+                command,
+                response["message"].as_str().unwrap_or("unknown error")
+            );
+        }
+        Ok(response["body"].take())
+    }
+
+    /// Sets breakpoints in the compiled code for the current cell's breakpoints. Returns a
+    /// `setBreakpoints` response body describing the cell's breakpoints, or None if no code has
+    /// been compiled for the cell yet.
+    fn sync_breakpoints(&mut self) -> Result<Option<JsonValue>, Error> {
+        let (code, path) = match &self.compiled {
+            Some((code, path)) => (code.clone(), path.clone()),
+            None => return Ok(None),
+        };
+        let requested = self
+            .current_cell
+            .as_ref()
+            .and_then(|cell| self.breakpoints.get(cell))
+            .cloned()
+            .unwrap_or_default();
+        let generated_lines: Vec<Option<usize>> = requested
+            .iter()
+            .map(|breakpoint| {
+                breakpoint["line"]
+                    .as_usize()
+                    .and_then(|line| code.generated_line_for_user_line(line))
+            })
+            .collect();
+        let to_set: Vec<JsonValue> = requested
+            .iter()
+            .zip(&generated_lines)
+            .filter_map(|(breakpoint, line)| {
+                let mut breakpoint = breakpoint.clone();
+                breakpoint["line"] = (*line)?.into();
+                Some(breakpoint)
+            })
+            .collect();
+        let mut body = self.forward(
+            "setBreakpoints",
+            json::object! {
+                "source" => json::object! {"path" => path.to_string_lossy().into_owned()},
+                "breakpoints" => to_set,
+            },
+        )?;
+        // The response needs a breakpoint for each requested one, in the same order.
+        let mut resolved = body["breakpoints"].members_mut().map(JsonValue::take);
+        let breakpoints: Vec<JsonValue> = requested
+            .iter()
+            .zip(generated_lines)
+            .map(|(breakpoint, generated_line)| {
+                let mut result = match generated_line.and_then(|_| resolved.next()) {
+                    Some(result) => result,
+                    None => json::object! {
+                        "verified" => false,
+                        "message" => "This line isn't part of the code that was run",
+                    },
+                };
+                result["line"] = result["line"]
+                    .as_usize()
+                    .and_then(|line| code.user_line_for_generated_line(line))
+                    .map_or_else(|| breakpoint["line"].clone(), JsonValue::from);
+                if let Some(cell) = &self.current_cell {
+                    result["source"] = source_for_cell(cell);
+                }
+                result
+            })
+            .collect();
+        Ok(Some(json::object! {"breakpoints" => breakpoints}))
+    }
+
+    fn debug_info(&self) -> JsonValue {
+        let breakpoints: Vec<JsonValue> = self
+            .breakpoints
+            .iter()
+            .map(|(source, breakpoints)| {
+                json::object! {
+                    "source" => source.to_string_lossy().into_owned(),
+                    "breakpoints" => breakpoints.clone(),
+                }
+            })
+            .collect();
+        json::object! {
+            "isStarted" => self.attached,
+            "hashMethod" => "Murmur2",
+            "hashSeed" => CELL_HASH_SEED,
+            "tmpFilePrefix" => format!(
+                "{}{}",
+                self.cell_dir.to_string_lossy(),
+                std::path::MAIN_SEPARATOR
+            ),
+            "tmpFileSuffix" => ".rs",
+            "breakpoints" => breakpoints,
+            "stoppedThreads" => json::array![],
+            "richRendering" => false,
+            "exceptionPaths" => json::array![],
+        }
+    }
+}
+
+fn source_for_cell(cell: &Path) -> JsonValue {
+    json::object! {
+        "name" => cell.file_name().map(|name| name.to_string_lossy().into_owned()),
+        "path" => cell.to_string_lossy().into_owned(),
+    }
+}
+
+fn cell_path(cell_dir: &Path, code: &str) -> PathBuf {
+    cell_dir.join(format!("{}.rs", murmur2(code.as_bytes(), CELL_HASH_SEED)))
+}
+
+/// Returns the command to start a debug adapter, if we can find one.
+fn adapter_command() -> Option<Vec<String>> {
+    if let Ok(command) = std::env::var(ADAPTER_VAR) {
+        let command: Vec<String> = command.split_whitespace().map(str::to_owned).collect();
+        return if command.is_empty() {
+            None
+        } else {
+            Some(command)
+        };
+    }
+    DEFAULT_ADAPTERS
+        .iter()
+        .find(|adapter| which::which(adapter).is_ok())
+        .map(|adapter| vec![adapter.to_string()])
+}
+
+/// A debug adapter process, to which we send DAP requests on stdin and from which we receive
+/// responses and events on stdout.
+struct DebugAdapter {
+    process: Child,
+    stdin: ChildStdin,
+    next_seq: i64,
+    responses: crossbeam_channel::Receiver<JsonValue>,
+}
+
+impl DebugAdapter {
+    fn start(
+        command: &[String],
+        events: crossbeam_channel::Sender<JsonValue>,
+    ) -> Result<DebugAdapter, Error> {
+        let process = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut process = match process {
+            Ok(process) => process,
+            Err(error) => bail!(
+                "Failed to start debug adapter '{}': {}",
+                command.join(" "),
+                error
+            ),
+        };
+        let stdin = process.stdin.take().unwrap();
+        let mut stdout = BufReader::new(process.stdout.take().unwrap());
+        let (response_sender, responses) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            while let Ok(Some(message)) = read_message(&mut stdout) {
+                let sent = match message["type"].as_str() {
+                    Some("response") => response_sender.send(message).is_ok(),
+                    Some("event") => events.send(message).is_ok(),
+                    // We don't support reverse requests, such as `runInTerminal`.
+                    _ => true,
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
+        Ok(DebugAdapter {
+            process,
+            stdin,
+            next_seq: 1,
+            responses,
+        })
+    }
+
+    fn request(&mut self, command: &str, arguments: JsonValue) -> Result<JsonValue, Error> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        write_message(
+            &mut self.stdin,
+            &json::object! {
+                "seq" => seq,
+                "type" => "request",
+                "command" => command,
+                "arguments" => arguments,
+            },
+        )?;
+        loop {
+            match self.responses.recv_timeout(RESPONSE_TIMEOUT) {
+                Ok(response) if response["request_seq"].as_i64() == Some(seq) => {
+                    return Ok(response)
+                }
+                // A response to an earlier request that we gave up waiting for.
+                Ok(_) => {}
+                Err(_) => bail!("The debug adapter didn't respond to '{}'", command),
+            }
+        }
+    }
+}
+
+impl Drop for DebugAdapter {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Writes a DAP message, which is JSON preceded by a header giving its length.
+fn write_message(out: &mut impl Write, message: &JsonValue) -> std::io::Result<()> {
+    let content = message.dump();
+    write!(out, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    out.flush()
+}
+
+/// Reads a DAP message. Returns None at end of input.
+fn read_message(input: &mut impl BufRead) -> Result<Option<JsonValue>, Error> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+    let content_length = match content_length {
+        Some(length) => length,
+        None => bail!("DAP message is missing Content-Length"),
+    };
+    let mut content = vec![0; content_length];
+    input.read_exact(&mut content)?;
+    Ok(Some(json::parse(&String::from_utf8_lossy(&content))?))
+}
+
+/// The 32 bit version of MurmurHash2. This is the hash that Jupyter frontends use to name the
+/// files for cells.
+fn murmur2(data: &[u8], seed: u32) -> u32 {
+    const M: u32 = 0x5bd1e995;
+    let mut h = seed ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= u32::from(*byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::murmur2;
+    use super::read_message;
+    use super::write_message;
+    use super::Debugger;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_message_framing() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &json::object! {"seq" => 1, "type" => "event"}).unwrap();
+        write_message(
+            &mut buffer,
+            &json::object! {"seq" => 2, "type" => "response"},
+        )
+        .unwrap();
+        let mut input = std::io::Cursor::new(buffer);
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["seq"], 1);
+        assert_eq!(
+            read_message(&mut input).unwrap().unwrap()["type"],
+            "response"
+        );
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_murmur2() {
+        assert_eq!(murmur2(b"", 0), 0);
+        // Covers both whole 4 byte chunks and a tail.
+        assert_eq!(murmur2(b"let x = 42;", 0xc70f6907), 3461996013);
+    }
+
+    #[test]
+    fn test_breakpoints_before_attach() {
+        let debugger = Debugger::new(PathBuf::from("/tmp/cells"));
+        let code = "let a = 1;\nlet b = 2;";
+        let cell = debugger.cell_path(code);
+        let response = debugger.handle_request(&json::object! {
+            "seq" => 3,
+            "type" => "request",
+            "command" => "setBreakpoints",
+            "arguments" => json::object! {
+                "source" => json::object! {"path" => cell.to_string_lossy().into_owned()},
+                "breakpoints" => json::array![json::object! {"line" => 2}],
+            },
+        });
+        assert_eq!(response["success"], true);
+        assert_eq!(response["request_seq"], 3);
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], false);
+        assert_eq!(response["body"]["breakpoints"][0]["line"], 2);
+
+        let info = debugger.handle_request(&json::object! {"seq" => 4, "command" => "debugInfo"});
+        assert_eq!(info["body"]["isStarted"], false);
+        assert_eq!(
+            format!(
+                "{}{}{}",
+                info["body"]["tmpFilePrefix"],
+                murmur2(code.as_bytes(), 0xc70f6907),
+                info["body"]["tmpFileSuffix"]
+            ),
+            cell.to_string_lossy()
+        );
+        assert_eq!(info["body"]["breakpoints"][0]["breakpoints"][0]["line"], 2);
+
+        // Anything that needs the adapter fails until it has been started.
+        let response = debugger.handle_request(&json::object! {"seq" => 5, "command" => "next"});
+        assert_eq!(response["success"], false);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cache of compiled dependencies that's shared between sessions, so that a new session with the
+//! same dependencies doesn't need to compile them again. Each entry is a copy of cargo's build
+//! outputs, other than those for the session crate itself, together with Cargo.lock. Entries are
+//! keyed by a hash of everything that affects how dependencies are built: the compiler, Cargo.toml
+//! and the cargo config. Before building, we copy a matching entry into the session's target
+//! directory. Cargo then sees that the dependencies are up to date. After the first successful
+//! build with a key that has no entry, we create one.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Prefixes of the names of build outputs for the session crate, which change with every build,
+/// so aren't worth caching. `code_` is the prefix of the copies of the shared object that we load.
+const SESSION_OUTPUT_PREFIXES: &[&str] = &["ctx-", "libctx", "code_", "libcode_"];
+
+/// Returns the directory containing the cache, if there's a cache directory.
+pub(crate) fn cache_root() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("evcxr").join("deps"))
+}
+
+/// Returns the key for builds of the crate in `crate_dir` with the compiler described by
+/// `rustc_version`, which is the output of `rustc -vV`, and `toolchain`.
+pub(crate) fn key(rustc_version: &str, toolchain: &str, crate_dir: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    rustc_version.hash(&mut hasher);
+    toolchain.hash(&mut hasher);
+    for file in &["Cargo.toml", ".cargo/config.toml"] {
+        fs::read_to_string(crate_dir.join(file))
+            .unwrap_or_default()
+            .hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// Copies the entry for `key`, if there is one, into `crate_dir`. Cargo.lock is only copied if
+/// `restore_lock` is set, since it would otherwise replace the one that the user chose with
+/// `:lock`. Returns whether there was an entry.
+pub(crate) fn restore(
+    root: &Path,
+    key: &str,
+    crate_dir: &Path,
+    restore_lock: bool,
+) -> io::Result<bool> {
+    let entry = root.join(key);
+    if !entry.is_dir() {
+        return Ok(false);
+    }
+    copy_dir(&entry.join("target"), &crate_dir.join("target"))?;
+    let lock = entry.join("Cargo.lock");
+    if restore_lock && lock.exists() {
+        fs::copy(lock, crate_dir.join("Cargo.lock"))?;
+    }
+    Ok(true)
+}
+
+/// Creates an entry for `key` from the build outputs in `crate_dir`, unless there already is one.
+/// If nothing but the session crate has been built, no entry is created.
+pub(crate) fn save(root: &Path, key: &str, crate_dir: &Path) -> io::Result<()> {
+    let entry = root.join(key);
+    if entry.exists() {
+        return Ok(());
+    }
+    // We copy into a temporary directory, then rename it, so that other sessions never see a
+    // partial entry.
+    let partial = root.join(format!("{}.partial-{}", key, std::process::id()));
+    let copied = copy_dir(&crate_dir.join("target"), &partial.join("target"))?;
+    let lock = crate_dir.join("Cargo.lock");
+    if copied > 0 && lock.exists() {
+        fs::copy(lock, partial.join("Cargo.lock"))?;
+    }
+    if copied == 0 || fs::rename(&partial, &entry).is_err() {
+        // Either there was nothing worth caching, or another session created the entry first.
+        let _ = fs::remove_dir_all(&partial);
+    }
+    Ok(())
+}
+
+/// Removes all entries. Returns how many bytes were freed.
+pub(crate) fn clean(root: &Path) -> io::Result<u64> {
+    let size = crate::about::dir_size(root);
+    if root.exists() {
+        fs::remove_dir_all(root)?;
+    }
+    Ok(size)
+}
+
+/// Returns how many entries there are and their total size in bytes.
+pub(crate) fn usage(root: &Path) -> (usize, u64) {
+    let entries = fs::read_dir(root)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| !entry.file_name().to_string_lossy().contains(".partial-"))
+                .count()
+        })
+        .unwrap_or(0);
+    (entries, crate::about::dir_size(root))
+}
+
+/// Copies the files under `from` to `to`, other than build outputs for the session crate,
+/// replacing any that already exist. Returns how many files were copied.
+fn copy_dir(from: &Path, to: &Path) -> io::Result<usize> {
+    let mut copied = 0;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if is_session_output(&name.to_string_lossy()) {
+            continue;
+        }
+        let destination = to.join(&name);
+        if entry.file_type()?.is_dir() {
+            copied += copy_dir(&entry.path(), &destination)?;
+        } else {
+            fs::create_dir_all(to)?;
+            fs::copy(entry.path(), &destination)?;
+            // Cargo considers outputs that are older than those of their dependencies to be out of
+            // date, so copies need the same modification times as the originals.
+            let modified = filetime::FileTime::from_last_modification_time(&entry.metadata()?);
+            filetime::set_file_mtime(&destination, modified)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+fn is_session_output(file_name: &str) -> bool {
+    SESSION_OUTPUT_PREFIXES
+        .iter()
+        .any(|prefix| file_name.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::key;
+    use super::restore;
+    use super::save;
+    use super::usage;
+    use std::fs;
+    use std::path::Path;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path().join("cache");
+        let session1 = tempdir.path().join("session1");
+        write(
+            &session1.join("Cargo.toml"),
+            "[dependencies]\nregex = \"1\"\n",
+        );
+        write(&session1.join("Cargo.lock"), "regex 1.10.2");
+        let deps = "target/x86_64-unknown-linux-gnu/debug/deps";
+        write(&session1.join(deps).join("libregex-abc.rlib"), "regex");
+        write(&session1.join(deps).join("libctx.so"), "ctx");
+        write(&session1.join(deps).join("libcode_1.so"), "code");
+        let key1 = key("rustc 1.70.0", "", &session1);
+        save(&root, &key1, &session1).unwrap();
+        assert_eq!(usage(&root).0, 1);
+
+        let session2 = tempdir.path().join("session2");
+        write(
+            &session2.join("Cargo.toml"),
+            "[dependencies]\nregex = \"1\"\n",
+        );
+        write(&session2.join("Cargo.lock"), "");
+        assert_eq!(key("rustc 1.70.0", "", &session2), key1);
+        assert_ne!(key("rustc 1.71.0", "", &session2), key1);
+        assert!(restore(&root, &key1, &session2, true).unwrap());
+        let restored = session2.join(deps);
+        assert_eq!(
+            fs::read_to_string(restored.join("libregex-abc.rlib")).unwrap(),
+            "regex"
+        );
+        assert!(!restored.join("libctx.so").exists());
+        assert!(!restored.join("libcode_1.so").exists());
+        assert_eq!(
+            fs::read_to_string(session2.join("Cargo.lock")).unwrap(),
+            "regex 1.10.2"
+        );
+
+        // A build with no dependencies isn't worth an entry.
+        let session3 = tempdir.path().join("session3");
+        write(&session3.join("Cargo.toml"), "[dependencies]\n");
+        write(&session3.join(deps).join("libctx.so"), "ctx");
+        let key3 = key("rustc 1.70.0", "", &session3);
+        save(&root, &key3, &session3).unwrap();
+        assert!(!restore(&root, &key3, &session3, true).unwrap());
+        assert_eq!(usage(&root).0, 1);
+    }
+}
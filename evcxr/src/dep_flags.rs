@@ -0,0 +1,215 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use std::collections::BTreeMap;
+
+/// Compiler flags registered for a dependency with `:dep_flags`. Codegen options that cargo
+/// supports in profiles are applied to just that dependency via a profile override. Anything else,
+/// such as `--cfg tokio_unstable`, can only be passed to rustc for every crate in the build, so
+/// goes in `rustflags`, but only while the dependency is in use.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct DepFlags {
+    /// Keys and TOML values for `[profile.dev.package.<name>]`.
+    profile: Vec<(String, String)>,
+    rustflags: Vec<String>,
+    /// The flags as the user wrote them.
+    original: String,
+}
+
+impl DepFlags {
+    pub(crate) fn parse(flags: &str) -> Result<DepFlags, Error> {
+        let mut result = DepFlags {
+            original: flags.trim().to_owned(),
+            ..DepFlags::default()
+        };
+        let mut words = flags.split_whitespace();
+        while let Some(word) = words.next() {
+            let codegen_option = if word == "-C" {
+                match words.next() {
+                    Some(option) => option,
+                    None => bail!("Missing option after -C"),
+                }
+            } else if let Some(option) = word.strip_prefix("-C") {
+                option
+            } else {
+                result.rustflags.push(word.to_owned());
+                continue;
+            };
+            match profile_setting(codegen_option) {
+                Some(setting) => result.profile.push(setting),
+                None => {
+                    result.rustflags.push("-C".to_owned());
+                    result.rustflags.push(codegen_option.to_owned());
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.profile.is_empty() && self.rustflags.is_empty()
+    }
+}
+
+impl std::fmt::Display for DepFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.original)
+    }
+}
+
+/// Converts a `-C` option into the equivalent cargo profile setting, if there is one.
+fn profile_setting(codegen_option: &str) -> Option<(String, String)> {
+    let (key, value) = codegen_option.split_once('=')?;
+    let value = match key {
+        "opt-level" => match value {
+            "s" | "z" => format!("\"{}\"", value),
+            _ => value.parse::<u8>().ok()?.to_string(),
+        },
+        "codegen-units" => value.parse::<u32>().ok()?.to_string(),
+        "debug-assertions" | "overflow-checks" => match value {
+            "y" | "yes" | "on" | "true" => "true".to_owned(),
+            "n" | "no" | "off" | "false" => "false".to_owned(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((key.to_owned(), value))
+}
+
+/// Returns profile overrides, for inclusion in Cargo.toml, for the crates in `flags` for which
+/// `is_dep` returns true.
+pub(crate) fn profile_overrides(
+    flags: &BTreeMap<String, DepFlags>,
+    mut is_dep: impl FnMut(&str) -> bool,
+) -> String {
+    let mut toml = String::new();
+    for (name, dep_flags) in flags {
+        if dep_flags.profile.is_empty() || !is_dep(name) {
+            continue;
+        }
+        toml.push_str(&format!("\n[profile.dev.package.{}]\n", name));
+        for (key, value) in &dep_flags.profile {
+            toml.push_str(&format!("{} = {}\n", key, value));
+        }
+    }
+    toml
+}
+
+/// Returns the rustflags for the crates in `flags` for which `is_dep` returns true.
+pub(crate) fn rustflags(
+    flags: &BTreeMap<String, DepFlags>,
+    mut is_dep: impl FnMut(&str) -> bool,
+) -> Vec<String> {
+    flags
+        .iter()
+        .filter(|(name, _)| is_dep(name))
+        .flat_map(|(_, dep_flags)| dep_flags.rustflags.iter().cloned())
+        .collect()
+}
+
+/// Returns the contents of a cargo config file that passes `rustflags` and sets the environment
+/// variables in `env` for builds, or None if there's neither. We use a `cfg` target section, since
+/// cargo merges those with any target rustflags the user has configured, whereas `build.rustflags`
+/// would be ignored if there were any. Variables are forced, so that they take precedence over any
+/// that evcxr was started with.
+pub(crate) fn cargo_config(rustflags: &[String], env: &BTreeMap<String, String>) -> Option<String> {
+    let mut config = String::new();
+    if !rustflags.is_empty() {
+        let quoted: Vec<String> = rustflags.iter().map(|flag| format!("{:?}", flag)).collect();
+        config.push_str(&format!(
+            "[target.'cfg(all())']\nrustflags = [{}]\n",
+            quoted.join(", ")
+        ));
+    }
+    if !env.is_empty() {
+        if !config.is_empty() {
+            config.push('\n');
+        }
+        config.push_str("[env]\n");
+        for (key, value) in env {
+            config.push_str(&format!(
+                "{:?} = {{ value = {:?}, force = true }}\n",
+                key, value
+            ));
+        }
+    }
+    if config.is_empty() {
+        None
+    } else {
+        Some(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cargo_config;
+    use super::profile_overrides;
+    use super::rustflags;
+    use super::DepFlags;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_parse() {
+        let flags =
+            DepFlags::parse("--cfg tokio_unstable -C opt-level=3 -Ctarget-cpu=native").unwrap();
+        assert_eq!(
+            flags.profile,
+            vec![("opt-level".to_owned(), "3".to_owned())]
+        );
+        assert_eq!(
+            flags.rustflags,
+            vec!["--cfg", "tokio_unstable", "-C", "target-cpu=native"]
+        );
+        assert_eq!(
+            flags.to_string(),
+            "--cfg tokio_unstable -C opt-level=3 -Ctarget-cpu=native"
+        );
+        assert!(DepFlags::parse("").unwrap().is_empty());
+        assert!(DepFlags::parse("-C").is_err());
+    }
+
+    #[test]
+    fn test_cargo_files() {
+        let mut flags = BTreeMap::new();
+        flags.insert(
+            "tokio".to_owned(),
+            DepFlags::parse("--cfg tokio_unstable").unwrap(),
+        );
+        flags.insert(
+            "image".to_owned(),
+            DepFlags::parse("-C opt-level=s -C overflow-checks=off").unwrap(),
+        );
+        assert_eq!(
+            profile_overrides(&flags, |_| true),
+            "\n[profile.dev.package.image]\nopt-level = \"s\"\noverflow-checks = false\n"
+        );
+        assert_eq!(
+            cargo_config(&rustflags(&flags, |_| true), &BTreeMap::new()).unwrap(),
+            "[target.'cfg(all())']\nrustflags = [\"--cfg\", \"tokio_unstable\"]\n"
+        );
+        let mut env = BTreeMap::new();
+        env.insert("CUDA_PATH".to_owned(), "/opt/cuda \"12\"".to_owned());
+        assert_eq!(
+            cargo_config(&["-Lnative=/opt/cuda/lib64".to_owned()], &env).unwrap(),
+            "[target.'cfg(all())']\nrustflags = [\"-Lnative=/opt/cuda/lib64\"]\n\n\
+             [env]\n\"CUDA_PATH\" = { value = \"/opt/cuda \\\"12\\\"\", force = true }\n"
+        );
+        // Flags for crates that aren't dependencies are ignored.
+        assert_eq!(profile_overrides(&flags, |name| name == "tokio"), "");
+        assert!(rustflags(&flags, |name| name == "image").is_empty());
+        assert_eq!(cargo_config(&[], &BTreeMap::new()), None);
+    }
+}
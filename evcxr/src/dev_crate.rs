@@ -0,0 +1,263 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:dev_crate`, which watches a local crate that's being worked on alongside the
+//! session. Cargo already rebuilds path dependencies when they change, so what we add here is
+//! noticing that a change happened and working out which stored variables can no longer be
+//! trusted. A variable's `TypeId` doesn't change when the definition of its type changes, so
+//! without this, a variable could be reinterpreted using the new layout of its type.
+
+use crate::errors::bail;
+use crate::errors::err;
+use crate::errors::Error;
+use once_cell::sync::OnceCell;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::SyntaxKind;
+use regex::Regex;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone)]
+pub(crate) struct DevCrate {
+    /// The name of the package, as it should appear in a `:dep`.
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+    last_modified: Option<SystemTime>,
+    manifest_modified: Option<SystemTime>,
+    /// Maps from the name of each type defined by the crate to its definition with whitespace and
+    /// comments removed.
+    type_definitions: HashMap<String, String>,
+}
+
+/// What changed in a dev crate since we last looked.
+pub(crate) struct DevCrateChange {
+    pub(crate) manifest_changed: bool,
+    /// Names of types whose definitions changed or that were removed.
+    pub(crate) changed_types: HashSet<String>,
+}
+
+impl DevCrate {
+    pub(crate) fn new(path: &Path) -> Result<DevCrate, Error> {
+        let path = path
+            .canonicalize()
+            .map_err(|error| err!("Couldn't find crate at {}: {}", path.display(), error))?;
+        let manifest = path.join("Cargo.toml");
+        let name = match std::fs::read_to_string(&manifest) {
+            Ok(contents) => package_name(&contents),
+            Err(error) => bail!("Couldn't read {}: {}", manifest.display(), error),
+        };
+        let name = match name {
+            Some(name) => name,
+            None => bail!("{} doesn't have a package name", manifest.display()),
+        };
+        Ok(DevCrate {
+            name,
+            last_modified: latest_modification(&path),
+            manifest_modified: modification_time(&manifest),
+            type_definitions: type_definitions(&path),
+            path,
+        })
+    }
+
+    /// Returns the configuration to pass to `:dep` for this crate.
+    pub(crate) fn dep_config(&self) -> String {
+        format!(
+            "{{ path = \"{}\" }}",
+            self.path.to_string_lossy().replace('\\', "\\\\")
+        )
+    }
+
+    /// Checks whether any of the crate's files have been modified since the last call. If they
+    /// have, returns what changed and records the current state for next time.
+    pub(crate) fn check_for_changes(&mut self) -> Option<DevCrateChange> {
+        let last_modified = latest_modification(&self.path);
+        if last_modified == self.last_modified {
+            return None;
+        }
+        self.last_modified = last_modified;
+        let manifest_modified = modification_time(&self.path.join("Cargo.toml"));
+        let manifest_changed = manifest_modified != self.manifest_modified;
+        self.manifest_modified = manifest_modified;
+        let type_definitions = type_definitions(&self.path);
+        let changed_types = self
+            .type_definitions
+            .iter()
+            .filter(|(name, definition)| type_definitions.get(*name) != Some(*definition))
+            .map(|(name, _)| name.clone())
+            .collect();
+        self.type_definitions = type_definitions;
+        Some(DevCrateChange {
+            manifest_changed,
+            changed_types,
+        })
+    }
+}
+
+/// Returns whether `type_name` refers to any of `changed_types`. We don't know how the type name
+/// was qualified, so we just look for matching identifiers. This errs on the side of dropping a
+/// variable whose type merely has the same name as one that changed.
+pub(crate) fn type_references_any(type_name: &str, changed_types: &HashSet<String>) -> bool {
+    type_name
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|ident| changed_types.contains(ident))
+}
+
+fn package_name(manifest: &str) -> Option<String> {
+    static NAME_RE: OnceCell<Regex> = OnceCell::new();
+    let name_re = NAME_RE.get_or_init(|| Regex::new(r#"^name *= *"([^"]+)""#).unwrap());
+    let mut in_package = false;
+    for line in manifest.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+        } else if in_package {
+            if let Some(captures) = name_re.captures(line) {
+                return Some(captures[1].to_owned());
+            }
+        }
+    }
+    None
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Returns the most recent modification time of any file in the crate that might affect how it
+/// builds.
+fn latest_modification(crate_dir: &Path) -> Option<SystemTime> {
+    let mut latest = modification_time(&crate_dir.join("Cargo.toml"));
+    for file in source_files(crate_dir) {
+        latest = latest.max(modification_time(&file));
+    }
+    latest
+}
+
+/// Returns all Rust source files in the crate, skipping the target directory and hidden
+/// directories.
+fn source_files(crate_dir: &Path) -> Vec<PathBuf> {
+    fn visit(dir: &Path, out: &mut Vec<PathBuf>) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if path.is_dir() {
+                if file_name != "target" && !file_name.starts_with('.') {
+                    visit(&path, out);
+                }
+            } else if file_name.ends_with(".rs") {
+                out.push(path);
+            }
+        }
+    }
+    let mut files = Vec::new();
+    visit(crate_dir, &mut files);
+    files
+}
+
+fn type_definitions(crate_dir: &Path) -> HashMap<String, String> {
+    let mut definitions: HashMap<String, String> = HashMap::new();
+    for file in source_files(crate_dir) {
+        if let Ok(source) = std::fs::read_to_string(&file) {
+            add_type_definitions(&source, &mut definitions);
+        }
+    }
+    definitions
+}
+
+fn add_type_definitions(source: &str, definitions: &mut HashMap<String, String>) {
+    let parse = SourceFile::parse(source);
+    for node in parse.syntax_node().descendants() {
+        let name = if let Some(item) = ast::Struct::cast(node.clone()) {
+            ast::HasName::name(&item)
+        } else if let Some(item) = ast::Enum::cast(node.clone()) {
+            ast::HasName::name(&item)
+        } else if let Some(item) = ast::Union::cast(node.clone()) {
+            ast::HasName::name(&item)
+        } else if let Some(item) = ast::TypeAlias::cast(node.clone()) {
+            ast::HasName::name(&item)
+        } else {
+            None
+        };
+        if let Some(name) = name {
+            let normalized: String = node
+                .descendants_with_tokens()
+                .filter_map(|element| element.into_token())
+                .filter(|token| {
+                    token.kind() != SyntaxKind::WHITESPACE && token.kind() != SyntaxKind::COMMENT
+                })
+                .map(|token| token.text().to_owned())
+                .collect::<Vec<_>>()
+                .join(" ");
+            // Types with the same name in different modules are treated as one. If either changes,
+            // we consider both to have changed.
+            definitions
+                .entry(name.text().to_string())
+                .or_default()
+                .push_str(&normalized);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::add_type_definitions;
+    use super::package_name;
+    use super::type_references_any;
+    use std::collections::HashMap;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(
+            package_name("[dependencies]\nname = \"x\"\n[package]\nname = \"foo-bar\"\n"),
+            Some("foo-bar".to_owned())
+        );
+        assert_eq!(package_name("[workspace]\n"), None);
+    }
+
+    #[test]
+    fn test_type_definitions_ignore_formatting() {
+        let mut a = HashMap::new();
+        add_type_definitions("pub struct Foo { x: i32 }\nfn f() {}", &mut a);
+        let mut b = HashMap::new();
+        add_type_definitions(
+            "// Comment\npub struct Foo {\n    x: i32 // more\n}\nfn f() { g() }",
+            &mut b,
+        );
+        let mut c = HashMap::new();
+        add_type_definitions("pub struct Foo { x: i64 }", &mut c);
+        assert_eq!(a.get("Foo"), b.get("Foo"));
+        assert_ne!(a.get("Foo"), c.get("Foo"));
+        assert!(!a.contains_key("f"));
+    }
+
+    #[test]
+    fn test_type_references_any() {
+        let changed: HashSet<String> = ["Foo".to_owned()].into_iter().collect();
+        assert!(type_references_any("Vec<my_crate::Foo>", &changed));
+        assert!(type_references_any("Foo", &changed));
+        assert!(!type_references_any("my_crate::FooBar", &changed));
+        assert!(!type_references_any("i32", &changed));
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Outputs that user code displays while it's running and then replaces, e.g. progress bars from
+//! evcxr_runtime. Unlike regular output, which is collected and shown when the cell finishes, these
+//! are passed straight to `EvalCallbacks::display_sender`, so the frontend can show them live. User
+//! code can also ask for the cell's output to be cleared, which goes to
+//! `EvalCallbacks::output_clearer`.
+
+use crate::content::Content;
+use std::collections::HashMap;
+
+// These prefixes are duplicated in evcxr_runtime, which doesn't depend on this crate. Both are
+// followed by `<display_id> <json object mapping mime types to content>`.
+pub(crate) const DISPLAY: &str = "EVCXR_DISPLAY ";
+pub(crate) const UPDATE_DISPLAY: &str = "EVCXR_UPDATE_DISPLAY ";
+/// Followed by `true` if clearing should wait until there's new output to replace it.
+pub(crate) const CLEAR_OUTPUT: &str = "EVCXR_CLEAR_OUTPUT ";
+
+/// Output from user code that's identified so that later output can replace it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplayUpdate {
+    pub display_id: String,
+    /// Whether this replaces earlier output with the same ID, rather than being shown for the
+    /// first time.
+    pub is_update: bool,
+    pub content_by_mime_type: HashMap<String, Content>,
+}
+
+impl DisplayUpdate {
+    pub fn get(&self, mime_type: &str) -> Option<&str> {
+        self.content_by_mime_type
+            .get(mime_type)
+            .and_then(Content::as_text)
+    }
+}
+
+/// Returns the display or update that `line` represents, if it's one of ours.
+pub(crate) fn parse(line: &str) -> Option<DisplayUpdate> {
+    let (is_update, rest) = if let Some(rest) = line.strip_prefix(DISPLAY) {
+        (false, rest)
+    } else {
+        (true, line.strip_prefix(UPDATE_DISPLAY)?)
+    };
+    let (display_id, data) = rest.split_once(' ')?;
+    let data = json::parse(data).ok()?;
+    if !data.is_object() {
+        return None;
+    }
+    let mut content_by_mime_type = HashMap::new();
+    for (mime_type, content) in data.entries() {
+        content_by_mime_type.insert(
+            mime_type.to_owned(),
+            Content::decode(mime_type, content.as_str()?.to_owned()),
+        );
+    }
+    Some(DisplayUpdate {
+        display_id: display_id.to_owned(),
+        is_update,
+        content_by_mime_type,
+    })
+}
+
+/// Returns whether clearing should wait for new output, if `line` asks for output to be cleared.
+pub(crate) fn parse_clear_output(line: &str) -> Option<bool> {
+    line.strip_prefix(CLEAR_OUTPUT)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use super::parse_clear_output;
+    use crate::content::Content;
+
+    #[test]
+    fn parse_display_updates() {
+        let display = parse(r#"EVCXR_DISPLAY p1 {"text/plain":"[--] 0/2"}"#).unwrap();
+        assert_eq!(display.display_id, "p1");
+        assert!(!display.is_update);
+        assert_eq!(display.get("text/plain"), Some("[--] 0/2"));
+        assert_eq!(display.get("text/html"), None);
+
+        let update = parse(r#"EVCXR_UPDATE_DISPLAY p1 {"text/plain":"[#-] 1/2"}"#).unwrap();
+        assert!(update.is_update);
+        assert_eq!(update.get("text/plain"), Some("[#-] 1/2"));
+
+        let json = parse(r#"EVCXR_DISPLAY p2 {"application/json":"[1, 2]"}"#).unwrap();
+        assert_eq!(
+            json.content_by_mime_type["application/json"],
+            Content::Json(json::array![1, 2])
+        );
+
+        assert_eq!(parse("EVCXR_DISPLAY p1"), None);
+        assert_eq!(parse("EVCXR_DISPLAY p1 [1]"), None);
+        assert_eq!(parse(r#"EVCXR_DISPLAY p1 {"text/plain":1}"#), None);
+        assert_eq!(parse("EVCXR_DISPLAY_OTHER p1 {}"), None);
+        assert_eq!(parse("hello"), None);
+    }
+
+    #[test]
+    fn parse_clear_outputs() {
+        assert_eq!(parse_clear_output("EVCXR_CLEAR_OUTPUT true"), Some(true));
+        assert_eq!(parse_clear_output("EVCXR_CLEAR_OUTPUT false"), Some(false));
+        assert_eq!(parse_clear_output("EVCXR_CLEAR_OUTPUT"), None);
+        assert_eq!(parse_clear_output("EVCXR_CLEAR_OUTPUT soon"), None);
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:doc`, which shows the documentation of an item. Rust-analyzer finds the item and
+//! gives us its doc comments, which are Markdown. We tidy them up the way that rustdoc would, then
+//! leave rendering the Markdown to the frontend.
+
+/// The documentation of an item.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ItemDocs {
+    /// How the item is declared, e.g. a function's signature. None for items like modules, where
+    /// the declaration isn't informative.
+    pub(crate) signature: Option<String>,
+    /// The item's doc comments, as Markdown.
+    pub(crate) docs: Option<String>,
+}
+
+impl ItemDocs {
+    pub(crate) fn to_markdown(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(signature) = &self.signature {
+            parts.push(format!("```rust\n{}\n```", signature));
+        }
+        parts.push(match &self.docs {
+            Some(docs) => tidy_markdown(docs),
+            None => "*No documentation*".to_owned(),
+        });
+        parts.join("\n\n")
+    }
+
+    pub(crate) fn to_text(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(signature) = &self.signature {
+            parts.push(signature.clone());
+        }
+        parts.push(match &self.docs {
+            Some(docs) => tidy_markdown(docs),
+            None => "No documentation".to_owned(),
+        });
+        parts.join("\n\n")
+    }
+}
+
+/// Makes doc comments display as they would in rustdoc. That is, code blocks without a language,
+/// or with only rustdoc's attributes, are Rust, and lines of Rust code that start with `#` are
+/// hidden.
+fn tidy_markdown(docs: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_code_block = false;
+    let mut in_rust_code = false;
+    for line in docs.lines() {
+        let trimmed = line.trim_start();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            if in_code_block {
+                in_code_block = false;
+                lines.push(line.to_owned());
+                continue;
+            }
+            in_code_block = true;
+            in_rust_code = is_rust_code_block(info);
+            if in_rust_code {
+                let indent = &line[..line.len() - trimmed.len()];
+                lines.push(format!("{}```rust", indent));
+                continue;
+            }
+        } else if in_rust_code && in_code_block && is_hidden_line(trimmed) {
+            continue;
+        }
+        lines.push(line.to_owned());
+    }
+    lines.join("\n")
+}
+
+fn is_rust_code_block(info: &str) -> bool {
+    info.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|attribute| !attribute.is_empty())
+        .all(|attribute| {
+            matches!(
+                attribute,
+                "rust"
+                    | "ignore"
+                    | "should_panic"
+                    | "no_run"
+                    | "compile_fail"
+                    | "test_harness"
+                    | "edition2015"
+                    | "edition2018"
+                    | "edition2021"
+            )
+        })
+}
+
+/// Returns whether a line of Rust code in a doc comment is hidden. Rustdoc hides `#` on its own
+/// and lines starting with `# `, but not `#[attribute]` or `#!`.
+fn is_hidden_line(line: &str) -> bool {
+    line == "#" || line.starts_with("# ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ItemDocs;
+
+    #[test]
+    fn markdown() {
+        let docs = ItemDocs {
+            signature: Some("pub fn double(x: i32) -> i32".to_owned()),
+            docs: Some(
+                [
+                    "Doubles `x`.",
+                    "",
+                    "```",
+                    "# fn main() {",
+                    "#[allow(unused)]",
+                    "let y = double(2);",
+                    "#",
+                    "# }",
+                    "```",
+                    "",
+                    "```text",
+                    "# Not hidden",
+                    "```",
+                ]
+                .join("\n"),
+            ),
+        };
+        assert_eq!(
+            docs.to_markdown(),
+            [
+                "```rust",
+                "pub fn double(x: i32) -> i32",
+                "```",
+                "",
+                "Doubles `x`.",
+                "",
+                "```rust",
+                "#[allow(unused)]",
+                "let y = double(2);",
+                "```",
+                "",
+                "```text",
+                "# Not hidden",
+                "```",
+            ]
+            .join("\n")
+        );
+        assert_eq!(ItemDocs::default().to_text(), "No documentation".to_owned());
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Environment variables and the working directory of the process that runs user code, as set by
+//! `:env` and `:cd`. Changes are sent to the process as commands, which it replies to, and are
+//! recorded so that they can be sent again if the process is restarted. Our own environment is
+//! left alone.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Followed by `<key> <value>`.
+const SET_ENV: &str = "SET_ENV ";
+/// Followed by `<key>`.
+const UNSET_ENV: &str = "UNSET_ENV ";
+/// Followed by the new working directory, which may be relative to the current one.
+const CHDIR: &str = "CHDIR ";
+const GET_CWD: &str = "GET_CWD";
+/// Replies start with one of these. Successful replies to `CHDIR` and `GET_CWD` are followed by
+/// the absolute path of the working directory, while errors are followed by a message.
+const REPLY_OK: &str = "OK";
+const REPLY_ERROR: &str = "ERROR ";
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Environment {
+    /// Variables that have been set, or unset if None.
+    vars: BTreeMap<String, Option<String>>,
+    working_dir: Option<PathBuf>,
+}
+
+impl Environment {
+    pub(crate) fn new(vars: &[(String, String)], working_dir: Option<&Path>) -> Environment {
+        Environment {
+            vars: vars
+                .iter()
+                .map(|(key, value)| (key.clone(), Some(value.clone())))
+                .collect(),
+            working_dir: working_dir.map(Path::to_owned),
+        }
+    }
+
+    /// Records the result of `command`, which the process has carried out. `reply` is what it
+    /// replied with.
+    pub(crate) fn record(&mut self, command: &EnvCommand, reply: &str) {
+        match command {
+            EnvCommand::Set(key, value) => {
+                self.vars.insert(key.clone(), Some(value.clone()));
+            }
+            EnvCommand::Unset(key) => {
+                self.vars.insert(key.clone(), None);
+            }
+            // Relative paths would mean something else after a restart, so we keep the absolute
+            // path that the process replied with.
+            EnvCommand::ChangeDir(_) => self.working_dir = Some(PathBuf::from(reply)),
+            EnvCommand::GetWorkingDir => {}
+        }
+    }
+
+    /// Returns the commands that recreate this environment in a new process.
+    pub(crate) fn commands(&self) -> Vec<EnvCommand> {
+        let mut commands: Vec<EnvCommand> = self
+            .vars
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => EnvCommand::Set(key.clone(), value.clone()),
+                None => EnvCommand::Unset(key.clone()),
+            })
+            .collect();
+        if let Some(working_dir) = &self.working_dir {
+            commands.push(EnvCommand::ChangeDir(working_dir.clone()));
+        }
+        commands
+    }
+
+    /// Returns the variables that have been set or unset, in the format that `:env` shows them.
+    pub(crate) fn summary(&self) -> String {
+        if self.vars.is_empty() {
+            return "No environment variables have been set".to_owned();
+        }
+        let lines: Vec<String> = self
+            .vars
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => format!("{}={}", key, value),
+                None => format!("{} (unset)", key),
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+/// A change to, or query of, the environment of the process that runs user code.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum EnvCommand {
+    Set(String, String),
+    Unset(String),
+    ChangeDir(PathBuf),
+    GetWorkingDir,
+}
+
+impl EnvCommand {
+    /// Returns a command that sets `key` to `value`, provided the process can be sent them.
+    pub(crate) fn set(key: &str, value: &str) -> Result<EnvCommand, Error> {
+        check_key(key)?;
+        if value.contains(&['\n', '\r', '\0'][..]) {
+            bail!("Environment variable values can't contain newlines or NUL characters");
+        }
+        Ok(EnvCommand::Set(key.to_owned(), value.to_owned()))
+    }
+
+    pub(crate) fn unset(key: &str) -> Result<EnvCommand, Error> {
+        check_key(key)?;
+        Ok(EnvCommand::Unset(key.to_owned()))
+    }
+
+    pub(crate) fn change_dir(dir: &Path) -> Result<EnvCommand, Error> {
+        let text = match dir.to_str() {
+            Some(text) => text,
+            None => bail!("The directory must be valid UTF-8"),
+        };
+        if text.is_empty() || text.contains(&['\n', '\r'][..]) {
+            bail!("Invalid directory: {:?}", text);
+        }
+        Ok(EnvCommand::ChangeDir(dir.to_owned()))
+    }
+
+    /// Returns the line to send to the process.
+    pub(crate) fn to_line(&self) -> String {
+        match self {
+            EnvCommand::Set(key, value) => format!("{}{} {}", SET_ENV, key, value),
+            EnvCommand::Unset(key) => format!("{}{}", UNSET_ENV, key),
+            EnvCommand::ChangeDir(dir) => format!("{}{}", CHDIR, dir.display()),
+            EnvCommand::GetWorkingDir => GET_CWD.to_owned(),
+        }
+    }
+}
+
+fn check_key(key: &str) -> Result<(), Error> {
+    if key.is_empty()
+        || key
+            .chars()
+            .any(|c| c == '=' || c == '\0' || c.is_whitespace())
+    {
+        bail!("Invalid environment variable name: {:?}", key);
+    }
+    Ok(())
+}
+
+/// Parses the arguments of `:env`, or of `:build_env`, which takes the same ones, e.g.
+/// `set KEY=VALUE` or `unset KEY`. Returns None if there aren't any, meaning that the variables that
+/// have been set should be listed. `command` is the name of the command, for errors.
+pub(crate) fn parse_env_args(command: &str, args: &str) -> Result<Option<EnvCommand>, Error> {
+    let args = args.trim();
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let (subcommand, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let rest = rest.trim_start();
+    match subcommand {
+        "set" => match rest.split_once('=') {
+            Some((key, value)) => EnvCommand::set(key, value).map(Some),
+            None => bail!("{} set expects KEY=VALUE", command),
+        },
+        "unset" => EnvCommand::unset(rest).map(Some),
+        other => bail!("{} expects 'set' or 'unset', got '{}'", command, other),
+    }
+}
+
+/// Returns the reply to `line`, which the process that runs user code received, if it's one of
+/// ours.
+pub(crate) fn handle_command(line: &str) -> Option<String> {
+    let result = if let Some(rest) = line.strip_prefix(SET_ENV) {
+        let (key, value) = rest.split_once(' ').unwrap_or((rest, ""));
+        std::env::set_var(key, value);
+        Ok(String::new())
+    } else if let Some(key) = line.strip_prefix(UNSET_ENV) {
+        std::env::remove_var(key);
+        Ok(String::new())
+    } else if let Some(dir) = line.strip_prefix(CHDIR) {
+        std::env::set_current_dir(dir)
+            .and_then(|_| std::env::current_dir())
+            .map(|dir| dir.display().to_string())
+    } else if line == GET_CWD {
+        std::env::current_dir().map(|dir| dir.display().to_string())
+    } else {
+        return None;
+    };
+    Some(match result {
+        Ok(text) if text.is_empty() => REPLY_OK.to_owned(),
+        Ok(text) => format!("{} {}", REPLY_OK, text),
+        Err(error) => format!("{}{}", REPLY_ERROR, error),
+    })
+}
+
+/// Returns what the process replied with, e.g. a directory, or an error with its message.
+pub(crate) fn parse_reply(reply: &str) -> Result<String, Error> {
+    if let Some(message) = reply.strip_prefix(REPLY_ERROR) {
+        bail!("{}", message);
+    }
+    match reply.strip_prefix(REPLY_OK) {
+        Some(text) => Ok(text.trim_start().to_owned()),
+        None => bail!("Unexpected reply from subprocess: {}", reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::handle_command;
+    use super::parse_env_args;
+    use super::parse_reply;
+    use super::EnvCommand;
+    use super::Environment;
+    use std::path::PathBuf;
+
+    #[test]
+    fn env_args() {
+        assert_eq!(parse_env_args(":env", "").unwrap(), None);
+        assert_eq!(
+            parse_env_args(":env", "set RUST_LOG=debug,hyper=info").unwrap(),
+            Some(EnvCommand::Set(
+                "RUST_LOG".to_owned(),
+                "debug,hyper=info".to_owned()
+            ))
+        );
+        assert_eq!(
+            parse_env_args(":env", "set EMPTY=").unwrap(),
+            Some(EnvCommand::Set("EMPTY".to_owned(), String::new()))
+        );
+        assert_eq!(
+            parse_env_args(":env", "unset  HOME").unwrap(),
+            Some(EnvCommand::Unset("HOME".to_owned()))
+        );
+        assert!(parse_env_args(":env", "set FOO").is_err());
+        assert!(parse_env_args(":env", "set =1").is_err());
+        assert!(parse_env_args(":env", "unset").is_err());
+        assert!(parse_env_args(":env", "unset A B").is_err());
+        assert_eq!(
+            parse_env_args(":build_env", "get FOO")
+                .unwrap_err()
+                .to_string(),
+            ":build_env expects 'set' or 'unset', got 'get'"
+        );
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let key = "EVCXR_ENVIRONMENT_TEST";
+        let reply = handle_command(&EnvCommand::set(key, "a b").unwrap().to_line()).unwrap();
+        assert_eq!(parse_reply(&reply).unwrap(), "");
+        assert_eq!(std::env::var(key).as_deref(), Ok("a b"));
+        handle_command(&EnvCommand::unset(key).unwrap().to_line()).unwrap();
+        assert!(std::env::var(key).is_err());
+
+        let reply = handle_command(&EnvCommand::GetWorkingDir.to_line()).unwrap();
+        assert_eq!(
+            PathBuf::from(parse_reply(&reply).unwrap()),
+            std::env::current_dir().unwrap()
+        );
+        let reply = handle_command("CHDIR /evcxr/does/not/exist").unwrap();
+        assert!(parse_reply(&reply).is_err());
+        assert_eq!(handle_command("LOAD foo.so"), None);
+    }
+
+    #[test]
+    fn replaying() {
+        let mut environment = Environment::new(&[("A".to_owned(), "1".to_owned())], None);
+        let unset = EnvCommand::Unset("B".to_owned());
+        environment.record(&unset, "");
+        environment.record(
+            &EnvCommand::ChangeDir(PathBuf::from("data")),
+            "/home/user/data",
+        );
+        assert_eq!(
+            environment.commands(),
+            vec![
+                EnvCommand::Set("A".to_owned(), "1".to_owned()),
+                unset,
+                EnvCommand::ChangeDir(PathBuf::from("/home/user/data")),
+            ]
+        );
+        assert_eq!(environment.summary(), "A=1\nB (unset)");
+    }
+}
@@ -18,6 +18,9 @@ use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
+use crate::code_block::UserCodeMetadata;
+use crate::explain;
+use crate::toolchain::ToolchainError;
 use json::JsonValue;
 use json::{self};
 use once_cell::sync::OnceCell;
@@ -34,6 +37,7 @@ pub struct CompilationError {
     pub(crate) code_origins: Vec<CodeKind>,
     spanned_messages: Vec<SpannedMessage>,
     level: String,
+    hint: Option<String>,
 }
 
 fn spans_in_local_source(span: &JsonValue) -> Option<&JsonValue> {
@@ -63,26 +67,12 @@ fn get_code_origins_for_span<'a>(
                 code_origins.push(code_block.origin_for_line(line));
             }
         }
-        let mut bs = span["byte_start"].as_usize().unwrap_or(0) + 20;
-        let mut be = span["byte_end"].as_usize().unwrap_or(0) + 20;
-        for x in &code_block.segments {
-            if x.code.len() > bs {
-                break;
-            }
-            if matches!(x.kind, CodeKind::OriginalUserCode(_)) {
-                break;
-            }
-            bs -= x.code.len();
-        }
-        for x in &code_block.segments {
-            if x.code.len() > be {
-                break;
-            }
-            if matches!(x.kind, CodeKind::OriginalUserCode(_)) {
-                break;
-            }
-            be -= x.code.len();
-        }
+        let (bs, be) = code_block
+            .user_range_for_generated_range(
+                span["byte_start"].as_usize().unwrap_or(0),
+                span["byte_end"].as_usize().unwrap_or(0),
+            )
+            .unwrap_or((0, 0));
         (code_origins, (bs, be))
     } else {
         (vec![], (0, 0))
@@ -144,13 +134,19 @@ impl CompilationError {
             return None;
         };
 
-        Some(CompilationError {
+        let mut error = CompilationError {
             spanned_messages: build_spanned_messages(&json, code_block),
             message,
             level: json["level"].as_str().unwrap_or("").to_owned(),
             json,
             code_origins: code_origins.into_iter().cloned().collect(),
-        })
+            hint: None,
+        };
+        error.hint = error
+            .code()
+            .and_then(explain::builtin_hint)
+            .map(str::to_owned);
+        Some(error)
     }
 
     pub(crate) fn fill_lines(&mut self, code_info: &UserCodeInfo) {
@@ -177,6 +173,7 @@ impl CompilationError {
             json: JsonValue::Null,
             code_origins: vec![segment.kind.clone()],
             level: "error".to_owned(),
+            hint: None,
         }
     }
 
@@ -185,6 +182,16 @@ impl CompilationError {
         self.code_origins.iter().any(CodeKind::is_user_supplied)
     }
 
+    /// Returns whether this error is only about the code that's being evaluated, as opposed to e.g.
+    /// items defined by earlier code, which are compiled again each time.
+    pub(crate) fn is_only_from_original_user_code(&self) -> bool {
+        !self.code_origins.is_empty()
+            && self
+                .code_origins
+                .iter()
+                .all(|kind| matches!(kind, CodeKind::OriginalUserCode(_)))
+    }
+
     /// Returns whether this error originated in code that we generated.
     pub fn is_from_generated_code(&self) -> bool {
         self.code_origins.contains(&CodeKind::OtherGeneratedCode)
@@ -208,18 +215,18 @@ impl CompilationError {
         None
     }
 
-    pub fn evcxr_extra_hint(&self) -> Option<&'static str> {
-        if let Some(code) = self.code() {
-            Some(match code {
-                "E0597" => {
-                    "Values assigned to variables in Evcxr cannot contain references \
-                     (unless they're static)"
-                }
-                _ => return None,
-            })
-        } else {
-            None
-        }
+    /// Returns advice that's specific to evcxr for this kind of error, if there is any. See
+    /// `EvalContext::register_hint`.
+    pub fn evcxr_extra_hint(&self) -> Option<&str> {
+        self.hint.as_deref()
+    }
+
+    /// Replaces this error's hint with the one in `hints` for its code.
+    pub(crate) fn set_hint(&mut self, hints: &explain::Hints) {
+        self.hint = self
+            .code()
+            .and_then(|code| hints.get(code))
+            .map(str::to_owned);
     }
 
     pub fn spanned_messages(&self) -> &[SpannedMessage] {
@@ -240,6 +247,25 @@ impl CompilationError {
         &self.level
     }
 
+    /// Returns the lines of the generated code, rather than of user code, on which the error's
+    /// primary spans start.
+    pub(crate) fn primary_lines(&self) -> Vec<usize> {
+        let mut lines = Vec::new();
+        if let JsonValue::Array(spans) = &self.json["spans"] {
+            for span in spans {
+                if span["is_primary"].as_bool() != Some(true) {
+                    continue;
+                }
+                if let Some(line) =
+                    spans_in_local_source(span).and_then(|s| s["line_start"].as_usize())
+                {
+                    lines.push(line);
+                }
+            }
+        }
+        lines
+    }
+
     pub fn help(&self) -> Vec<String> {
         if let JsonValue::Array(children) = &self.json["children"] {
             children
@@ -265,6 +291,61 @@ impl CompilationError {
         }
     }
 
+    /// Returns the `use` statements that rustc suggests adding in order to resolve a name, e.g.
+    /// `use std::collections::HashMap;`. There's more than one if the name is ambiguous.
+    pub(crate) fn import_suggestions(&self) -> Vec<String> {
+        let mut suggestions: Vec<String> = Vec::new();
+        for child in self.json["children"].members() {
+            if child["level"] != "help"
+                || !child["message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .starts_with("consider importing")
+            {
+                continue;
+            }
+            for span in child["spans"].members() {
+                if let Some(replacement) = span["suggested_replacement"].as_str() {
+                    let replacement = replacement.trim();
+                    if replacement.starts_with("use ")
+                        && !suggestions.iter().any(|s| s == replacement)
+                    {
+                        suggestions.push(replacement.to_owned());
+                    }
+                }
+            }
+        }
+        suggestions
+    }
+
+    /// If this error is because code refers to a crate that isn't a dependency, e.g.
+    /// `use regex::Regex` without `:dep regex`, returns the crate's name. Crates that come with
+    /// Rust aren't included.
+    pub(crate) fn missing_crate(&self) -> Option<String> {
+        static MISSING_CRATE: OnceCell<Regex> = OnceCell::new();
+        let missing_crate = MISSING_CRATE.get_or_init(|| {
+            Regex::new(
+                "(?:use of undeclared crate or module|use of unresolved module or unlinked crate|\
+                 can't find crate for) `([A-Za-z_][A-Za-z0-9_]*)`",
+            )
+            .unwrap()
+        });
+        if !matches!(self.code(), Some("E0432" | "E0433" | "E0463")) {
+            return None;
+        }
+        let name = std::iter::once(self.message.as_str())
+            .chain(self.spanned_messages.iter().map(|m| m.label.as_str()))
+            .find_map(|text| missing_crate.captures(text))?[1]
+            .to_owned();
+        if matches!(
+            name.as_str(),
+            "std" | "core" | "alloc" | "proc_macro" | "test"
+        ) {
+            return None;
+        }
+        Some(name)
+    }
+
     pub fn rendered(&self) -> String {
         self.json["rendered"].as_str().unwrap_or("").to_owned()
     }
@@ -313,6 +394,334 @@ impl CompilationError {
     }
 }
 
+/// A compilation error, together with errors that are probably consequences of the same mistake,
+/// e.g. other uses of a misspelt name. See `group_errors`.
+#[derive(Debug, Clone)]
+pub struct ErrorGroup {
+    pub primary: CompilationError,
+    pub related: Vec<CompilationError>,
+}
+
+impl ErrorGroup {
+    /// Returns e.g. "3 related errors", or None if there aren't any.
+    pub fn related_summary(&self) -> Option<String> {
+        let kind = if self.primary.level() == "warning" {
+            "warning"
+        } else {
+            "error"
+        };
+        match self.related.len() {
+            0 => None,
+            1 => Some(format!("1 related {}", kind)),
+            count => Some(format!("{} related {}s", count, kind)),
+        }
+    }
+}
+
+/// Groups `errors`, so that a cascade of errors caused by a single mistake can be shown as one.
+/// Errors are grouped when their primary spans are the same, or when they have the same code,
+/// message and primary label, e.g. each use of an undefined variable. Exact duplicates are dropped.
+/// Groups are in the order of their first error, which is usually the root cause.
+pub fn group_errors(errors: Vec<CompilationError>) -> Vec<ErrorGroup> {
+    let mut groups: Vec<ErrorGroup> = Vec::new();
+    for error in errors {
+        let group = groups.iter_mut().find(|group| {
+            std::iter::once(&group.primary)
+                .chain(&group.related)
+                .any(|grouped| grouped.has_same_cause_as(&error))
+        });
+        match group {
+            Some(group) => {
+                let is_duplicate = std::iter::once(&group.primary)
+                    .chain(&group.related)
+                    .any(|grouped| grouped.is_duplicate_of(&error));
+                if !is_duplicate {
+                    group.related.push(error);
+                }
+            }
+            None => groups.push(ErrorGroup {
+                primary: error,
+                related: Vec::new(),
+            }),
+        }
+    }
+    groups
+}
+
+impl CompilationError {
+    fn has_same_cause_as(&self, other: &CompilationError) -> bool {
+        self.level == other.level
+            && ((self.primary_location().is_some()
+                && self.primary_location() == other.primary_location())
+                || (self.code() == other.code()
+                    && self.message == other.message
+                    && self.primary_label() == other.primary_label()))
+    }
+
+    fn is_duplicate_of(&self, other: &CompilationError) -> bool {
+        self.level == other.level
+            && self.code() == other.code()
+            && self.message == other.message
+            && self.primary_label() == other.primary_label()
+            && self.primary_location() == other.primary_location()
+    }
+
+    /// Returns the start and end lines and columns of the primary span in the user's code.
+    fn primary_location(&self) -> Option<(usize, usize, usize, usize)> {
+        self.primary_spanned_message()
+            .and_then(|message| message.span)
+            .map(|span| {
+                (
+                    span.start_line,
+                    span.start_column,
+                    span.end_line,
+                    span.end_column,
+                )
+            })
+    }
+
+    fn primary_label(&self) -> Option<&str> {
+        self.primary_spanned_message()
+            .map(|message| message.label.as_str())
+    }
+}
+
+/// ANSI styles used by `CompilationError::render`, matching rustc's.
+const ERROR_STYLE: &str = "1;31";
+const WARNING_STYLE: &str = "1;33";
+const SECONDARY_STYLE: &str = "1;34";
+const BOLD_STYLE: &str = "1";
+
+/// A span of a single line, to be underlined.
+struct Annotation<'a> {
+    /// 1-based line number.
+    line: usize,
+    /// 0-based columns on the line as it's displayed, end exclusive.
+    start: usize,
+    end: usize,
+    label: &'a str,
+    is_primary: bool,
+}
+
+impl CompilationError {
+    /// Returns the error rendered like rustc does in a terminal, with the lines of `source`, the
+    /// user's code, that it refers to, underlined and labelled. ANSI colors are used if `colors`.
+    pub fn render(&self, source: &str, colors: bool) -> String {
+        let paint = |text: &str, style: &str| {
+            if colors {
+                format!("\x1b[{}m{}\x1b[0m", style, text)
+            } else {
+                text.to_owned()
+            }
+        };
+        let level_style = if self.level == "warning" {
+            WARNING_STYLE
+        } else {
+            ERROR_STYLE
+        };
+        let header = match self.code() {
+            Some(code) => format!("{}[{}]", self.level, code),
+            None => self.level.clone(),
+        };
+        let mut out = vec![format!(
+            "{}{}",
+            paint(&header, level_style),
+            paint(&format!(": {}", self.message), BOLD_STYLE)
+        )];
+        let source_lines: Vec<&str> = source.lines().collect();
+        let annotations = self.annotations(&source_lines);
+        let width = annotations
+            .iter()
+            .map(|annotation| annotation.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let padding = " ".repeat(width);
+        let gutter = paint("|", SECONDARY_STYLE);
+        if let Some(span) = self
+            .primary_spanned_message()
+            .and_then(|message| message.span)
+        {
+            out.push(format!(
+                "{}{} {}:{}",
+                padding,
+                paint("-->", SECONDARY_STYLE),
+                span.start_line,
+                span.start_column
+            ));
+        }
+        if !annotations.is_empty() {
+            out.push(format!("{} {}", padding, gutter));
+            let mut line_numbers: Vec<usize> = annotations.iter().map(|a| a.line).collect();
+            line_numbers.sort_unstable();
+            line_numbers.dedup();
+            let mut previous = None;
+            for line_number in line_numbers {
+                if matches!(previous, Some(previous) if line_number > previous + 1) {
+                    out.push(paint("...", SECONDARY_STYLE));
+                }
+                previous = Some(line_number);
+                out.push(format!(
+                    "{} {} {}",
+                    paint(&format!("{:>1$}", line_number, width), SECONDARY_STYLE),
+                    gutter,
+                    source_lines[line_number - 1].replace('\t', "    ")
+                ));
+                let mut on_line: Vec<&Annotation> = annotations
+                    .iter()
+                    .filter(|annotation| annotation.line == line_number)
+                    .collect();
+                on_line.sort_by_key(|annotation| (annotation.start, !annotation.is_primary));
+                for annotation in on_line {
+                    let (mark, style) = if annotation.is_primary {
+                        ("^", level_style)
+                    } else {
+                        ("-", SECONDARY_STYLE)
+                    };
+                    let mut underline =
+                        mark.repeat(annotation.end.saturating_sub(annotation.start).max(1));
+                    if !annotation.label.is_empty() {
+                        underline.push(' ');
+                        underline.push_str(annotation.label);
+                    }
+                    out.push(format!(
+                        "{} {} {}{}",
+                        padding,
+                        gutter,
+                        " ".repeat(annotation.start),
+                        paint(&underline, style)
+                    ));
+                }
+            }
+        }
+        for message in &self.spanned_messages {
+            if message.span.is_none() && !message.label.is_empty() {
+                out.push(format!(
+                    "{} {} note: {}",
+                    padding,
+                    paint("=", SECONDARY_STYLE),
+                    message.label
+                ));
+            }
+        }
+        for help in self.help() {
+            out.push(format!("{}: {}", paint("help", BOLD_STYLE), help));
+        }
+        if let Some(hint) = self.evcxr_extra_hint() {
+            out.push(format!("{}: {}", paint("note", BOLD_STYLE), hint));
+        }
+        out.iter()
+            .map(|line| line.trim_end())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns the spans of the error that are within `source_lines`, split into single lines. A
+    /// span covering several lines is underlined to the end of its first line and from the start
+    /// of its last line, which is where its label goes.
+    fn annotations<'a>(&'a self, source_lines: &[&str]) -> Vec<Annotation<'a>> {
+        let mut annotations = Vec::new();
+        for message in &self.spanned_messages {
+            let span = match message.span {
+                Some(span) if span.start_line >= 1 && span.end_line <= source_lines.len() => span,
+                _ => continue,
+            };
+            let start_line = source_lines[span.start_line - 1];
+            let end_line = source_lines[span.end_line - 1];
+            let start = display_column(start_line, span.start_column);
+            let end = display_column(end_line, span.end_column);
+            if span.start_line == span.end_line {
+                annotations.push(Annotation {
+                    line: span.start_line,
+                    start,
+                    end,
+                    label: &message.label,
+                    is_primary: message.is_primary,
+                });
+            } else {
+                annotations.push(Annotation {
+                    line: span.start_line,
+                    start,
+                    end: display_column(start_line, start_line.chars().count() + 1),
+                    label: "",
+                    is_primary: message.is_primary,
+                });
+                annotations.push(Annotation {
+                    line: span.end_line,
+                    start: 0,
+                    end,
+                    label: &message.label,
+                    is_primary: message.is_primary,
+                });
+            }
+        }
+        annotations
+    }
+}
+
+/// Returns the 0-based column at which the character at 1-based `column` of `line` is displayed,
+/// given that tabs are displayed as 4 spaces.
+fn display_column(line: &str, column: usize) -> usize {
+    line.chars()
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+#[cfg(test)]
+impl CompilationError {
+    /// Returns an error with a primary span of one character, for tests.
+    pub(crate) fn for_testing(
+        level: &str,
+        code: Option<&str>,
+        message: &str,
+        label: &str,
+        line: usize,
+        column: usize,
+    ) -> CompilationError {
+        let mut error = CompilationError {
+            message: message.to_owned(),
+            json: JsonValue::Null,
+            code_origins: Vec::new(),
+            spanned_messages: Vec::new(),
+            level: level.to_owned(),
+            hint: None,
+        };
+        if let Some(code) = code {
+            error.json = json::object! { "code" => json::object! { "code" => code } };
+            error.hint = explain::builtin_hint(code).map(str::to_owned);
+        }
+        error.add_span_for_testing(label, line, column, true);
+        error
+    }
+
+    /// Adds a secondary span of one character, for tests.
+    pub(crate) fn add_secondary_span_for_testing(
+        &mut self,
+        label: &str,
+        line: usize,
+        column: usize,
+    ) {
+        self.add_span_for_testing(label, line, column, false);
+    }
+
+    fn add_span_for_testing(&mut self, label: &str, line: usize, column: usize, is_primary: bool) {
+        self.spanned_messages.push(SpannedMessage {
+            span: Some(Span {
+                start_line: line,
+                start_column: column,
+                end_line: line,
+                end_column: column + 1,
+                byte_start: 0,
+                byte_end: 0,
+                code_block_id: 0,
+            }),
+            lines: Vec::new(),
+            label: label.to_owned(),
+            is_primary,
+        });
+    }
+}
+
 fn sanitize_message(message: &str) -> String {
     // Any references to `evcxr_variable_store` are beyond the end of what the
     // user typed, so we replace such references with something more meaningful.
@@ -372,6 +781,61 @@ impl Span {
         }
     }
 
+    /// Returns a span covering the arguments of `command`.
+    pub(crate) fn from_command_args(command: &CommandCall) -> Span {
+        let args_columns = command.args.as_deref().map_or(0, count_columns);
+        Span::from_command(
+            command,
+            command.args_column,
+            command.args_column + args_columns,
+        )
+    }
+
+    /// Returns a span covering bytes `start..end` of `user_code`, which is what the user wrote.
+    pub(crate) fn from_user_code(user_code: &str, start: usize, end: usize) -> Span {
+        let meta = UserCodeMetadata {
+            start_byte: 0,
+            node_index: 0,
+            start_line: 1,
+            column_offset: 0,
+        };
+        Span::from_user_code_segment(user_code, &meta, start, end)
+    }
+
+    /// Returns a span covering bytes `start..end` of `code`, a segment of user code that `meta`
+    /// says where the user wrote.
+    pub(crate) fn from_user_code_segment(
+        code: &str,
+        meta: &UserCodeMetadata,
+        start: usize,
+        end: usize,
+    ) -> Span {
+        let position = |offset: usize| {
+            let before = &code[..offset];
+            match before.rfind('\n') {
+                Some(newline) => (
+                    meta.start_line + before.matches('\n').count(),
+                    count_columns(&before[newline + 1..]) + 1,
+                ),
+                None => (
+                    meta.start_line,
+                    meta.column_offset + count_columns(before) + 1,
+                ),
+            }
+        };
+        let (start_line, start_column) = position(start);
+        let (end_line, end_column) = position(end);
+        Span {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+            byte_start: meta.start_byte + start,
+            byte_end: meta.start_byte + end,
+            code_block_id: meta.node_index,
+        }
+    }
+
     pub(crate) fn from_segment(segment: &Segment, range: TextRange) -> Option<Span> {
         if let CodeKind::OriginalUserCode(meta) = &segment.kind {
             let (start_line, start_column) = line_and_column(
@@ -460,6 +924,8 @@ impl SpannedMessage {
                         byte_end: be,
                         code_block_id: start.node_index,
                     })
+                } else if let Some((CodeKind::Command(command), _)) = origins.first() {
+                    Some(Span::from_command_args(command))
                 } else {
                     // Spans within generated code won't mean anything to the user, suppress
                     // them.
@@ -511,6 +977,8 @@ pub enum Error {
     TypeRedefinedVariablesLost(Vec<String>),
     Message(String),
     SubprocessTerminated(String),
+    /// Cargo or rustc is missing or broken. The error includes guidance on fixing it.
+    ToolchainUnavailable(ToolchainError),
 }
 
 impl std::error::Error for Error {}
@@ -533,6 +1001,7 @@ impl fmt::Display for Error {
             Error::Message(message) | Error::SubprocessTerminated(message) => {
                 write!(f, "{}", message)?
             }
+            Error::ToolchainUnavailable(error) => write!(f, "{}", error)?,
         }
         Ok(())
     }
@@ -602,3 +1071,200 @@ macro_rules! _bail {
     ($($arg:tt)+) => {return Err($crate::errors::err!($($arg)+))}
 }
 pub(crate) use _bail as bail;
+
+#[cfg(test)]
+mod tests {
+    use super::group_errors;
+    use super::CompilationError;
+    use super::Span;
+
+    fn error(message: &str, label: &str, line: usize, column: usize) -> CompilationError {
+        CompilationError::for_testing("error", None, message, label, line, column)
+    }
+
+    #[test]
+    fn render() {
+        let mut mismatch = error("mismatched types", "expected `i32`, found `&str`", 3, 15);
+        if let Some(span) = &mut mismatch.spanned_messages[0].span {
+            span.end_column = 19;
+        }
+        let mut secondary = mismatch.spanned_messages[0].clone();
+        secondary.span = secondary.span.map(|span| Span {
+            start_column: 9,
+            end_column: 12,
+            ..span
+        });
+        secondary.label = "expected due to this".to_owned();
+        secondary.is_primary = false;
+        mismatch.spanned_messages.push(secondary);
+        let source = ":dep foo\nlet a = 1;\n\tlet b: i32 = \"hi\";";
+        assert_eq!(
+            mismatch.render(source, false),
+            "error: mismatched types\n \
+             --> 3:15\n  \
+              |\n\
+             3 |     let b: i32 = \"hi\";\n  \
+              |            --- expected due to this\n  \
+              |                  ^^^^ expected `i32`, found `&str`"
+        );
+        assert!(mismatch
+            .render(source, true)
+            .starts_with("\x1b[1;31merror\x1b[0m\x1b[1m: mismatched types\x1b[0m\n"));
+    }
+
+    #[test]
+    fn render_gaps_and_multiline_spans() {
+        let mut multiline = error("unclosed", "starts here", 1, 9);
+        if let Some(span) = &mut multiline.spanned_messages[0].span {
+            span.end_line = 10;
+            span.end_column = 2;
+        }
+        let source = format!("let x = {{\n{}}}", "    1\n".repeat(8));
+        assert_eq!(
+            multiline.render(&source, false),
+            "error: unclosed\n  \
+               --> 1:9\n   \
+                |\n \
+              1 | let x = {\n   \
+                |         ^\n\
+             ...\n\
+             10 | }\n   \
+                | ^ starts here"
+        );
+    }
+
+    #[test]
+    fn import_suggestions() {
+        let mut not_found = CompilationError::for_testing(
+            "error",
+            Some("E0433"),
+            "failed to resolve: use of undeclared type `HashMap`",
+            "use of undeclared type `HashMap`",
+            1,
+            9,
+        );
+        assert!(not_found.import_suggestions().is_empty());
+        not_found.json["children"] = json::array![
+            json::object! {
+                "level" => "help",
+                "message" => "consider importing one of these items",
+                "spans" => json::array![
+                    json::object! {
+                        "suggested_replacement" => "use std::collections::HashMap;\n"
+                    },
+                    json::object! {
+                        "suggested_replacement" => "use std::collections::HashMap;\n"
+                    },
+                    json::object! {
+                        "suggested_replacement" => "use hashbrown::HashMap;\n"
+                    },
+                ],
+            },
+            json::object! {
+                "level" => "help",
+                "message" => "a struct with a similar name exists",
+                "spans" => json::array![json::object! { "suggested_replacement" => "HashSet" }],
+            },
+        ];
+        assert_eq!(
+            not_found.import_suggestions(),
+            vec![
+                "use std::collections::HashMap;".to_owned(),
+                "use hashbrown::HashMap;".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_crates() {
+        let unresolved_import = CompilationError::for_testing(
+            "error",
+            Some("E0432"),
+            "unresolved import `regex`",
+            "use of undeclared crate or module `regex`",
+            1,
+            5,
+        );
+        assert_eq!(unresolved_import.missing_crate().as_deref(), Some("regex"));
+        let unresolved_path = CompilationError::for_testing(
+            "error",
+            Some("E0433"),
+            "failed to resolve: use of unresolved module or unlinked crate `serde_json`",
+            "use of unresolved module or unlinked crate `serde_json`",
+            1,
+            1,
+        );
+        assert_eq!(
+            unresolved_path.missing_crate().as_deref(),
+            Some("serde_json")
+        );
+        let std_crate = CompilationError::for_testing(
+            "error",
+            Some("E0463"),
+            "can't find crate for `test`",
+            "can't find crate",
+            1,
+            1,
+        );
+        assert_eq!(std_crate.missing_crate(), None);
+        let undeclared_type = CompilationError::for_testing(
+            "error",
+            Some("E0433"),
+            "failed to resolve: use of undeclared type `HashMap`",
+            "use of undeclared type `HashMap`",
+            1,
+            1,
+        );
+        assert_eq!(undeclared_type.missing_crate(), None);
+    }
+
+    #[test]
+    fn grouping() {
+        let not_found = "cannot find value `x` in this scope";
+        let groups = group_errors(vec![
+            error(not_found, "not found in this scope", 1, 9),
+            error("mismatched types", "expected `i32`, found `&str`", 2, 5),
+            error(not_found, "not found in this scope", 3, 1),
+            // Reported at the same place as the mismatch, so probably caused by it.
+            error("cannot add `&str` to `i32`", "no implementation", 2, 5),
+            error(not_found, "not found in this scope", 3, 1),
+            // A different mismatch.
+            error("mismatched types", "expected `u8`, found `bool`", 4, 5),
+        ]);
+        let summary: Vec<(String, usize)> = groups
+            .iter()
+            .map(|group| (group.primary.message(), group.related.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (not_found.to_owned(), 1),
+                ("mismatched types".to_owned(), 1),
+                ("mismatched types".to_owned(), 0)
+            ]
+        );
+        assert_eq!(
+            groups[0].related_summary().as_deref(),
+            Some("1 related error")
+        );
+        assert_eq!(groups[2].related_summary(), None);
+    }
+
+    #[test]
+    fn span_from_user_code() {
+        let code = "let a = 1;\nlet é = foo(a);\n";
+        let start = code.find("foo").unwrap();
+        let span = Span::from_user_code(code, start, start + 3);
+        assert_eq!(
+            (
+                span.start_line,
+                span.start_column,
+                span.end_line,
+                span.end_column
+            ),
+            (2, 9, 2, 12)
+        );
+        let span = Span::from_user_code(code, 11, 11);
+        assert_eq!((span.start_line, span.start_column), (2, 1));
+    }
+}
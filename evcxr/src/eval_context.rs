@@ -12,26 +12,92 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::about::AboutInfo;
+use crate::cell_dependencies::CellHistory;
+use crate::cell_diff;
+use crate::cell_diff::CellReeval;
 use crate::child_process::ChildProcess;
+use crate::child_process::Output;
+use crate::clippy::ClippyMode;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
+use crate::code_block::CommandCall;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
+use crate::codegen;
+use crate::codegen::Emit;
+use crate::comm;
+use crate::comm::CommMessage;
+use crate::comm::Comms;
+use crate::content::Content;
 use crate::crate_config::ExternalCrate;
+use crate::crate_search::CrateSearch;
+use crate::dataframes;
+use crate::dataframes::Table;
+use crate::debugger::Debugger;
+use crate::dep_flags::DepFlags;
+use crate::dev_crate::type_references_any;
+use crate::display_update;
+use crate::display_update::DisplayUpdate;
+use crate::docs::ItemDocs;
+use crate::environment;
+use crate::environment::EnvCommand;
+use crate::environment::Environment;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::errors::Span;
 use crate::errors::SpannedMessage;
+use crate::eval_event::EvalEvent;
+use crate::eval_event::EvalEventSink;
 use crate::evcxr_internal_runtime;
+use crate::explain;
+use crate::export::ExportedProject;
+use crate::interrupt;
+use crate::interrupt::InterruptHandle;
 use crate::item;
+use crate::jobs::Jobs;
+use crate::layout::TypeLayout;
+use crate::limits::ResourceLimits;
+use crate::memory::MemoryStats;
+use crate::metrics::Metrics;
+use crate::migration;
 use crate::module::Module;
 use crate::module::SoFile;
+use crate::module::CRATE_NAME;
+use crate::native_libs;
+use crate::native_libs::NativeLib;
+use crate::number_format::NumberFormat;
+use crate::output_limit;
+use crate::output_limit::OutputLimiter;
+use crate::panic_report::PanicSource;
+use crate::reactive::DownstreamCell;
+use crate::reactive::ReactiveCells;
+use crate::reactive::ReactiveMode;
+use crate::recovery;
+use crate::recovery::SnapshotVariable;
+use crate::remote::RemoteFiles;
+use crate::remote::RemoteTransport;
+use crate::result_history;
+use crate::result_history::ResultHistory;
 use crate::runtime;
 use crate::rust_analyzer::Completions;
+use crate::rust_analyzer::Diagnostic;
+use crate::rust_analyzer::Location;
 use crate::rust_analyzer::RustAnalyzer;
+use crate::rust_analyzer::SemanticToken;
 use crate::rust_analyzer::VariableInfo;
+use crate::rustfmt;
+use crate::sandbox::SandboxPolicy;
+use crate::stdin_relay;
+use crate::test_runner;
+use crate::test_runner::TestFn;
+use crate::test_runner::TestReport;
+use crate::timing::RunStatistics;
+use crate::timing::Timing;
+use crate::toolchain::ToolPaths;
 use crate::use_trees::Import;
+use crate::why_slow::EvalProfile;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
 use ra_ap_ide::TextRange;
@@ -40,14 +106,23 @@ use ra_ap_syntax::AstNode;
 use ra_ap_syntax::SyntaxKind;
 use ra_ap_syntax::SyntaxNode;
 use regex::Regex;
+use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::ops::Range;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::Arc;
+use std::sync::MutexGuard;
 use std::time::Duration;
 use std::time::Instant;
 
+/// Editions that code can be compiled with. The code we generate needs at least 2018.
+pub(crate) const EDITIONS: &[&str] = &["2018", "2021", "2024"];
+const DEFAULT_EDITION: &str = "2021";
+
 pub struct EvalContext {
     // Our tmpdir if EVCXR_TMPDIR wasn't set - Drop causes tmpdir to be cleaned up.
     _tmpdir: Option<tempfile::TempDir>,
@@ -55,8 +130,85 @@ pub struct EvalContext {
     committed_state: ContextState,
     child_process: ChildProcess,
     stdout_sender: crossbeam_channel::Sender<String>,
+    stderr_sender: crossbeam_channel::Sender<String>,
     analyzer: RustAnalyzer,
     initial_config: Config,
+    metrics: Arc<Metrics>,
+    sandbox_policy: Option<SandboxPolicy>,
+    /// Set if user code runs on another machine.
+    remote_files: Option<RemoteFiles>,
+    /// The code most recently evaluated for each cell, keyed by cell ID. Only cells evaluated via
+    /// `reeval_edited` are tracked.
+    cells: HashMap<String, String>,
+    /// The IDs of cells evaluated by `CommandContext::execute_cell_with_callbacks`, keyed by cell
+    /// number.
+    cell_ids: HashMap<usize, String>,
+    debugger: Debugger,
+    interrupt_handle: InterruptHandle,
+    comms: Comms,
+    /// The mime types that the frontend can display, most preferred first. Empty if it can display
+    /// anything.
+    mime_preferences: Vec<String>,
+    event_sinks: Vec<Box<dyn EvalEventSink>>,
+    /// Changes made to the subprocess's environment, which are made again if it restarts.
+    environment: Environment,
+    /// See `EvalContextConfig::prelude`.
+    prelude: Option<PathBuf>,
+    /// See `EvalContextConfig::profiles`.
+    startup_profiles: Vec<String>,
+}
+
+/// Where the process that runs user code comes from.
+enum RuntimeSource {
+    Subprocess(std::process::Command),
+    Remote(RemoteTransport),
+}
+
+/// Options that need to be decided when an `EvalContext` is created, as opposed to being changed
+/// later via `ContextState`.
+#[derive(Clone, Debug, Default)]
+pub struct EvalContextConfig {
+    /// If set, the subprocess in which user code runs is restricted according to this policy.
+    pub sandbox_policy: Option<SandboxPolicy>,
+    /// Limits on the memory and CPU time that the subprocess in which user code runs may use. If
+    /// one is exceeded, evaluation fails with `Error::SubprocessTerminated` describing which.
+    pub limits: ResourceLimits,
+    /// Path to cargo. If not set, cargo is looked up on `PATH`. Useful in hermetic environments
+    /// such as Nix or Bazel, where the toolchain isn't installed via rustup.
+    pub cargo_path: Option<PathBuf>,
+    /// Path to rustc. If not set, cargo chooses rustc in its usual way.
+    pub rustc_path: Option<PathBuf>,
+    /// If set, at most this many lines of each of stdout and stderr are buffered in
+    /// `EvalContextOutputs`. Once that many are waiting to be received, user code blocks when it
+    /// writes more, so output can't build up faster than the frontend can handle it. If unset,
+    /// buffering is unlimited, so receiving output is optional.
+    pub output_buffer_lines: Option<usize>,
+    /// The rustup toolchain to build with, e.g. `nightly`. Can be changed later with `:toolchain`.
+    pub toolchain: Option<String>,
+    /// The Rust edition that code is compiled with, e.g. `2018`. Can be changed later with
+    /// `:edition`. Defaults to 2021.
+    pub edition: Option<String>,
+    /// Directory in which to write the crate that user code is compiled in, and other files. If
+    /// not set, `EVCXR_TMPDIR` is used if set, otherwise a temporary directory is created and
+    /// deleted when the context is dropped. Contexts that run at the same time in one process must
+    /// not share a directory.
+    pub tmpdir: Option<PathBuf>,
+    /// Environment variables to set in the subprocess in which user code runs, in addition to
+    /// those that it inherits from us. More can be set later with `:env`.
+    pub env: Vec<(String, String)>,
+    /// The working directory of the subprocess in which user code runs. If not set, it's our
+    /// working directory. Can be changed later with `:cd`.
+    pub working_dir: Option<PathBuf>,
+    /// A file of commands and code, such as `:dep`s, `use` statements and helper functions, that
+    /// `:load_config` evaluates after `init.evcxr` and `prelude.rs` from the config directory.
+    pub prelude: Option<PathBuf>,
+    /// Names of profiles from `profiles.evcxr` in the config directory that `:load_config` uses
+    /// after the prelude, as if by `:profiles use`.
+    pub profiles: Vec<String>,
+    /// A file that dependency versions are kept in across sessions. If it exists, it's used as the
+    /// context's Cargo.lock from the start, and whenever a build changes Cargo.lock, e.g. because a
+    /// dependency was added, it's written back. Can be set later with `:lock persist`.
+    pub persistent_lockfile: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug)]
@@ -68,6 +220,13 @@ pub(crate) struct Config {
     // attempt to determine if the type of the variable is copy.
     preserve_vars_on_panic: bool,
     output_format: String,
+    number_format: NumberFormat,
+    /// Rows per page when displaying DataFrames.
+    table_rows: usize,
+    /// The most stdout, in bytes, that an evaluation may produce before the rest is dropped.
+    output_limit: Option<usize>,
+    /// The most lines of a result's text to show before the rest is hidden.
+    max_output_lines: Option<usize>,
     /// Whether to try to display the final expression. Currently this needs to
     /// be turned off when doing tab completion or cargo check, but otherwise it
     /// should always be on.
@@ -85,11 +244,73 @@ pub(crate) struct Config {
     pub(crate) sccache: Option<PathBuf>,
     /// Whether to attempt to avoid network access.
     pub(crate) offline_mode: bool,
+    /// How many times to retry cargo when it fails for what looks like a transient reason, such as
+    /// a registry timing out.
+    pub(crate) cargo_retries: u32,
+    /// Where to send status messages, such as cargo being retried. These go to the same place as
+    /// stderr from user code.
+    pub(crate) status_sender: Option<crossbeam_channel::Sender<String>>,
+    /// Whether to show cargo's output, including from build scripts, while building.
+    pub(crate) build_log: bool,
     pub(crate) toolchain: String,
+    /// The Rust edition that code is compiled with.
+    pub(crate) edition: String,
+    /// The file that Cargo.lock was imported from with `:lock import`, if any. While set, Cargo.lock
+    /// isn't replaced by the one from the dependency cache.
+    pub(crate) lockfile: Option<PathBuf>,
+    /// The file that Cargo.lock is written back to after each build that changes it, so that
+    /// dependency versions persist across sessions. See `EvalContextConfig::persistent_lockfile`.
+    pub(crate) persistent_lockfile: Option<PathBuf>,
+    /// Compiler flags for particular dependencies, keyed by the name passed to `:dep`. Flags are
+    /// only applied while that dependency is in use.
+    pub(crate) dep_flags: BTreeMap<String, DepFlags>,
+    /// Native libraries that code is linked against, added with `:link`.
+    pub(crate) native_libs: Vec<NativeLib>,
+    /// Compiler flags for every crate in the build, set with `:rustflags`.
+    rustflags: Vec<String>,
+    /// Environment variables for builds, including build scripts, set with `:build_env`.
+    build_env: BTreeMap<String, String>,
+    /// The database that `:sql` queries run against, set with `:sql connect`. If None, they run
+    /// against an in-memory database.
+    sql_connection: Option<String>,
+    /// Whether to snapshot variables after each evaluation so that they can be restored if the
+    /// subprocess crashes.
+    pub(crate) recovery_mode: bool,
+    /// Whether to count heap allocations made by user code and report them with each evaluation.
+    pub(crate) track_memory: bool,
+    /// Whether to check code with clippy, and which of its lints to use.
+    pub(crate) clippy: ClippyMode,
+    /// Whether to format code with rustfmt before it's written to exported projects and
+    /// transcripts.
+    pub(crate) autoformat: bool,
+    /// Whether to migrate variables to new definitions of their types via serde.
+    pub(crate) migrate_types: bool,
+    /// Whether to add the `use` that rustc suggests for a name that can't be found, then retry.
+    pub(crate) auto_use: bool,
+    /// Whether to add crates that code refers to but that aren't dependencies, then retry.
+    pub(crate) auto_dep: bool,
+    /// Whether to reuse dependencies compiled by earlier sessions, and save ours for later ones.
+    pub(crate) dep_cache: bool,
+    /// Whether user code can allocate into a session-scoped arena, via a variable named `arena`.
+    pub(crate) arena: bool,
+    /// What happens to downstream cells when a cell with an ID runs.
+    pub(crate) reactive: ReactiveMode,
+    /// The target to build for, if not the host. Code for other targets is built as an executable
+    /// and run with `runner`, rather than being loaded into our subprocess.
+    pub(crate) target: Option<String>,
+    /// Command used to run executables built for `target`, e.g. `wasmtime` or `qemu-aarch64`.
+    pub(crate) runner: Option<String>,
+    /// Whether we're compiling for `:test`, which sets `cfg(test)` and turns `#[test]` functions
+    /// into ordinary functions, so that they can be called.
+    pub(crate) test_mode: bool,
+    /// Hints shown alongside errors, keyed by error code.
+    pub(crate) hints: explain::Hints,
+    pub(crate) tool_paths: ToolPaths,
 }
 
-fn create_initial_config(crate_dir: PathBuf) -> Config {
+fn create_initial_config(crate_dir: PathBuf, tool_paths: ToolPaths) -> Config {
     let mut config = Config::new(crate_dir);
+    config.tool_paths = tool_paths;
     // default the linker to mold, then lld, first checking if either are installed
     // neither linkers support macos, so fallback to system (aka default)
     // https://github.com/rui314/mold/issues/132
@@ -108,6 +329,10 @@ impl Config {
             debug_mode: false,
             preserve_vars_on_panic: false,
             output_format: "{:?}".to_owned(),
+            number_format: NumberFormat::default(),
+            table_rows: 20,
+            output_limit: output_limit::DEFAULT_OUTPUT_LIMIT,
+            max_output_lines: None,
             display_final_expression: true,
             expand_use_statements: true,
             opt_level: "2".to_owned(),
@@ -116,7 +341,33 @@ impl Config {
             linker: "system".to_owned(),
             sccache: None,
             offline_mode: false,
+            cargo_retries: 2,
+            status_sender: None,
+            build_log: false,
             toolchain: String::new(),
+            edition: DEFAULT_EDITION.to_owned(),
+            lockfile: None,
+            persistent_lockfile: None,
+            dep_flags: BTreeMap::new(),
+            native_libs: Vec::new(),
+            rustflags: Vec::new(),
+            build_env: BTreeMap::new(),
+            sql_connection: None,
+            recovery_mode: false,
+            track_memory: false,
+            clippy: ClippyMode::Off,
+            autoformat: false,
+            migrate_types: false,
+            auto_use: false,
+            auto_dep: false,
+            dep_cache: true,
+            arena: false,
+            reactive: ReactiveMode::Off,
+            target: None,
+            runner: None,
+            test_mode: false,
+            hints: explain::Hints::default(),
+            tool_paths: ToolPaths::default(),
         }
     }
 
@@ -141,10 +392,13 @@ impl Config {
         let mut command = if self.linker == "mold" {
             Command::new("mold")
         } else {
-            Command::new("cargo")
+            Command::new(&self.tool_paths.cargo)
         };
         if self.linker == "mold" {
-            command.arg("-run").arg("cargo");
+            command.arg("-run").arg(&self.tool_paths.cargo);
+        }
+        if let Some(rustc) = &self.tool_paths.rustc {
+            command.env("RUSTC", rustc);
         }
         if !self.toolchain.is_empty() {
             command.arg(format!("+{}", self.toolchain));
@@ -156,6 +410,16 @@ impl Config {
         command.current_dir(&self.crate_dir);
         command
     }
+
+    /// Returns a cargo command that builds the session crate. With `:build_log` on, cargo is very
+    /// verbose, so that output from build scripts is shown.
+    pub(crate) fn cargo_build_command(&self, command_name: &str) -> Command {
+        let mut command = self.cargo_command(command_name);
+        if self.build_log {
+            command.arg("-vv");
+        }
+        command
+    }
 }
 
 #[derive(Debug)]
@@ -181,6 +445,12 @@ static ERROR_FORMATS: &[ErrorFormat] = &[
 
 const PANIC_NOTIFICATION: &str = "EVCXR_PANIC_NOTIFICATION";
 
+/// The variable in which we store the most recent error returned by `?` in user code.
+const LAST_ERROR_VARIABLE: &str = "_err";
+
+/// The function that contains the code passed to `:expand`.
+const EXPANSION_FN_NAME: &str = "evcxr_expansion";
+
 // Outputs from an EvalContext. This is a separate struct since users may want
 // destructure this and pass its components to separate threads.
 pub struct EvalContextOutputs {
@@ -191,30 +461,69 @@ pub struct EvalContextOutputs {
 //#[non_exhaustive]
 pub struct EvalCallbacks<'a> {
     pub input_reader: &'a dyn Fn(&str, bool) -> String,
+    /// Called with messages that user code sends on comms, e.g. to create or update a widget.
+    pub comm_sender: &'a dyn Fn(CommMessage),
+    /// Called with output that user code displays while it's running and may later replace, e.g.
+    /// a progress bar.
+    pub display_sender: &'a dyn Fn(DisplayUpdate),
+    /// Called when user code asks for the output of the cell to be cleared. The argument is
+    /// whether clearing should wait until there's new output to replace it.
+    pub output_clearer: &'a dyn Fn(bool),
 }
 
 fn default_input_reader(_: &str, _: bool) -> String {
     String::new()
 }
 
+fn default_comm_sender(_: CommMessage) {}
+
+fn default_display_sender(_: DisplayUpdate) {}
+
+fn default_output_clearer(_: bool) {}
+
 impl<'a> Default for EvalCallbacks<'a> {
     fn default() -> Self {
         EvalCallbacks {
             input_reader: &default_input_reader,
+            comm_sender: &default_comm_sender,
+            display_sender: &default_display_sender,
+            output_clearer: &default_output_clearer,
         }
     }
 }
 
 impl EvalContext {
     pub fn new() -> Result<(EvalContext, EvalContextOutputs), Error> {
-        fix_path();
+        Self::with_config(EvalContextConfig::default())
+    }
 
+    pub fn with_config(
+        config: EvalContextConfig,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
         let current_exe = std::env::current_exe()?;
-        Self::with_subprocess_command(std::process::Command::new(&current_exe))
+        Self::with_runtime_and_config(
+            RuntimeSource::Subprocess(std::process::Command::new(&current_exe)),
+            config,
+        )
+    }
+
+    /// Creates a context in which code is compiled locally, but run on another machine. The
+    /// remote machine must have the same OS and architecture as this one.
+    pub fn with_remote_runtime(
+        transport: RemoteTransport,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        Self::with_runtime_and_config(
+            RuntimeSource::Remote(transport),
+            EvalContextConfig::default(),
+        )
     }
 
     #[cfg(windows)]
-    fn apply_platform_specific_vars(module: &Module, command: &mut std::process::Command) {
+    fn apply_platform_specific_vars(
+        module: &Module,
+        tool_paths: &ToolPaths,
+        command: &mut std::process::Command,
+    ) {
         // Windows doesn't support rpath, so we need to set PATH so that it
         // knows where to find dlls.
         use std::ffi::OsString;
@@ -222,7 +531,7 @@ impl EvalContext {
         path_var_value.push(&module.deps_dir());
         path_var_value.push(";");
 
-        let mut sysroot_command = std::process::Command::new("rustc");
+        let mut sysroot_command = tool_paths.rustc_command();
         sysroot_command.arg("--print").arg("sysroot");
         path_var_value.push(format!(
             "{}\\bin;",
@@ -234,7 +543,12 @@ impl EvalContext {
     }
 
     #[cfg(not(windows))]
-    fn apply_platform_specific_vars(_module: &Module, _command: &mut std::process::Command) {}
+    fn apply_platform_specific_vars(
+        _module: &Module,
+        _tool_paths: &ToolPaths,
+        _command: &mut std::process::Command,
+    ) {
+    }
 
     #[doc(hidden)]
     pub fn new_for_testing() -> (EvalContext, EvalContextOutputs) {
@@ -250,16 +564,31 @@ impl EvalContext {
                 .unwrap();
         let mut state = context.state();
         state.set_offline_mode(true);
+        // Tests shouldn't write to or depend on the user's cache.
+        state.set_dep_cache(false);
         context.commit_state(state);
         (context, outputs)
     }
 
     pub fn with_subprocess_command(
-        mut subprocess_command: std::process::Command,
+        subprocess_command: std::process::Command,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        Self::with_runtime_and_config(
+            RuntimeSource::Subprocess(subprocess_command),
+            EvalContextConfig::default(),
+        )
+    }
+
+    fn with_runtime_and_config(
+        runtime_source: RuntimeSource,
+        config: EvalContextConfig,
     ) -> Result<(EvalContext, EvalContextOutputs), Error> {
         let mut opt_tmpdir = None;
         let tmpdir_path;
-        if let Ok(from_env) = std::env::var("EVCXR_TMPDIR") {
+        if let Some(tmpdir) = &config.tmpdir {
+            std::fs::create_dir_all(tmpdir)?;
+            tmpdir_path = tmpdir.clone();
+        } else if let Ok(from_env) = std::env::var("EVCXR_TMPDIR") {
             tmpdir_path = PathBuf::from(from_env);
         } else {
             let tmpdir = tempfile::tempdir()?;
@@ -267,29 +596,90 @@ impl EvalContext {
             opt_tmpdir = Some(tmpdir);
         }
 
+        let tool_paths =
+            ToolPaths::locate(config.cargo_path.as_deref(), config.rustc_path.as_deref())?;
         let analyzer = RustAnalyzer::new(&tmpdir_path)?;
-        let module = Module::new(tmpdir_path)?;
-
-        Self::apply_platform_specific_vars(&module, &mut subprocess_command);
-
-        let (stdout_sender, stdout_receiver) = crossbeam_channel::unbounded();
-        let (stderr_sender, stderr_receiver) = crossbeam_channel::unbounded();
-        let child_process = ChildProcess::new(subprocess_command, stderr_sender)?;
-        let initial_config = create_initial_config(module.crate_dir().to_owned());
-        let initial_state = ContextState::new(initial_config.clone());
+        let debugger = Debugger::new(tmpdir_path.join("cells"));
+        let module = Module::new(tmpdir_path, &tool_paths)?;
+
+        let (stdout_sender, stdout_receiver) = output_channel(config.output_buffer_lines);
+        let (stderr_sender, stderr_receiver) = output_channel(config.output_buffer_lines);
+        let remote_files = match &runtime_source {
+            RuntimeSource::Subprocess(_) => None,
+            RuntimeSource::Remote(_) => Some(RemoteFiles::default()),
+        };
+        let child_process = match runtime_source {
+            RuntimeSource::Subprocess(mut subprocess_command) => {
+                Self::apply_platform_specific_vars(&module, &tool_paths, &mut subprocess_command);
+                if let Some(policy) = &config.sandbox_policy {
+                    policy.apply(&mut subprocess_command, &tmpdir_path)?;
+                }
+                config.limits.apply(&mut subprocess_command)?;
+                ChildProcess::new(
+                    subprocess_command,
+                    stdout_sender.clone(),
+                    stderr_sender.clone(),
+                )?
+                .with_limits(config.limits.clone())?
+            }
+            RuntimeSource::Remote(transport) => {
+                if config.sandbox_policy.is_some() {
+                    bail!("Sandboxing isn't supported when running code remotely");
+                }
+                if !config.limits.is_empty() {
+                    bail!("Resource limits aren't supported when running code remotely");
+                }
+                transport.start(stdout_sender.clone(), stderr_sender.clone())?
+            }
+        };
+        let mut initial_config = create_initial_config(module.crate_dir().to_owned(), tool_paths);
+        initial_config.status_sender = Some(stderr_sender.clone());
+        let mut initial_state = ContextState::new(initial_config.clone());
+        if let Some(toolchain) = &config.toolchain {
+            initial_state.set_toolchain(toolchain);
+        }
+        if let Some(edition) = &config.edition {
+            initial_state.set_edition(edition)?;
+        }
+        if let Some(lockfile) = config.persistent_lockfile {
+            load_persistent_lockfile(&lockfile, &module.cargo_lock_path())?;
+            initial_state.set_persistent_lockfile(Some(lockfile));
+        }
+        let metrics = Arc::new(Metrics::default());
+        metrics.set_child_pid(child_process.process_id());
+        let interrupt_handle = InterruptHandle::default();
+        if remote_files.is_none() {
+            debugger.set_process_id(child_process.process_id());
+            interrupt_handle.set_process_id(child_process.process_id());
+        }
         let mut context = EvalContext {
             _tmpdir: opt_tmpdir,
             committed_state: initial_state,
             module,
             child_process,
             stdout_sender,
+            stderr_sender,
             analyzer,
             initial_config,
+            metrics,
+            sandbox_policy: config.sandbox_policy,
+            remote_files,
+            cells: HashMap::new(),
+            cell_ids: HashMap::new(),
+            debugger,
+            interrupt_handle,
+            comms: Comms::default(),
+            mime_preferences: Vec::new(),
+            event_sinks: Vec::new(),
+            environment: Environment::new(&config.env, config.working_dir.as_deref()),
+            prelude: config.prelude,
+            startup_profiles: config.profiles,
         };
         let outputs = EvalContextOutputs {
             stdout: stdout_receiver,
             stderr: stderr_receiver,
         };
+        context.apply_environment()?;
         if context.committed_state.linker() == "lld" && context.eval("42").is_err() {
             context.committed_state.set_linker("system".to_owned());
         } else {
@@ -312,15 +702,95 @@ impl EvalContext {
         self.eval_with_state(code, self.state())
     }
 
+    /// Evaluates `new_code` as the new contents of the cell identified by `cell_id`. Only the
+    /// parts of the cell that changed since it was last evaluated with this method, or that refer
+    /// to something that changed, are evaluated. Items that the cell no longer defines are removed.
+    /// The first time a cell is seen, all of its code is evaluated. Evcxr commands aren't supported
+    /// and are ignored.
+    pub fn reeval_edited(&mut self, cell_id: &str, new_code: &str) -> Result<CellReeval, Error> {
+        let old_code = self.cells.get(cell_id).map_or("", String::as_str);
+        let diff = cell_diff::diff(old_code, new_code);
+        let changed_items = diff.changed_item_names();
+        let rerun: HashSet<&str> = diff.rerun_variables.iter().map(String::as_str).collect();
+        let invalidated_variables: Vec<String> = self
+            .forget_variables_where(|type_name| {
+                crate::dev_crate::type_references_any(type_name, &changed_items)
+            })
+            .into_iter()
+            .filter(|name| !rerun.contains(name.as_str()))
+            .collect();
+
+        let mut state = self.state();
+        for name in &diff.removed_items {
+            state.items_by_name.remove(name);
+        }
+        state.unnamed_items.retain(|block| {
+            let code = block.code_string();
+            !diff
+                .removed_unnamed_items
+                .iter()
+                .any(|removed| code.trim() == removed)
+        });
+        let outputs = self.eval_with_state(&diff.code, state)?;
+
+        let mut invalidated_items: Vec<String> = self
+            .committed_state
+            .items_by_name
+            .iter()
+            .filter(|(name, block)| {
+                !changed_items.contains(*name)
+                    && crate::dev_crate::type_references_any(&block.code_string(), &changed_items)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        invalidated_items.sort();
+        let changed_names = diff.changed_names();
+        let mut stale_variables: Vec<String> = self
+            .cells
+            .iter()
+            .filter(|(other_id, _)| other_id.as_str() != cell_id)
+            .flat_map(|(_, code)| cell_diff::variables_referring_to(code, &changed_names))
+            .filter(|name| !rerun.contains(name.as_str()) && !invalidated_variables.contains(name))
+            .collect();
+        stale_variables.sort();
+        stale_variables.dedup();
+        self.cells.insert(cell_id.to_owned(), new_code.to_owned());
+        Ok(CellReeval {
+            outputs,
+            changed_items: diff.changed_items,
+            removed_items: diff.removed_items,
+            rerun_variables: diff.rerun_variables,
+            invalidated_items,
+            invalidated_variables,
+            stale_variables,
+        })
+    }
+
     pub fn eval_with_state(
         &mut self,
         code: &str,
         state: ContextState,
     ) -> Result<EvalOutputs, Error> {
+        self.debugger.cell_started(code);
         let (user_code, code_info) = CodeBlock::from_original_user_code(code);
         self.eval_with_callbacks(user_code, state, &code_info, &mut EvalCallbacks::default())
     }
 
+    /// Runs `tests`, which should come from `state.tests()`, in the subprocess. Their results are
+    /// in `tests` of the returned outputs.
+    pub(crate) fn run_tests(
+        &mut self,
+        tests: &[TestFn],
+        state: &ContextState,
+    ) -> Result<EvalOutputs, Error> {
+        let mut test_state = state.clone();
+        test_state.config.test_mode = true;
+        let result = self.eval_with_state(&test_runner::runner_code(tests), test_state);
+        // Test mode is only for compiling the tests, so shouldn't stick once they've run.
+        self.committed_state.config.test_mode = false;
+        result
+    }
+
     pub(crate) fn check(
         &mut self,
         user_code: CodeBlock,
@@ -335,6 +805,193 @@ impl EvalContext {
         Ok(state.apply_custom_errors(errors, &user_code, code_info))
     }
 
+    /// Returns the problems that rust-analyzer finds in `user_code`, as it would be evaluated with
+    /// `state`, without running cargo. Spans refer to `source`, which is the code the user wrote.
+    pub(crate) fn diagnostics(
+        &mut self,
+        user_code: CodeBlock,
+        state: ContextState,
+        code_info: &UserCodeInfo,
+        source: &str,
+    ) -> Result<Vec<Diagnostic>, Error> {
+        let code = self.analyze(user_code, state, code_info)?;
+        Ok(self.analyzer.diagnostics(|start, end| {
+            let (start, end) = code.user_range_for_generated_range(start, end)?;
+            Some(Span::from_user_code(source, start, end))
+        }))
+    }
+
+    /// Returns how rust-analyzer would highlight `user_code`, as it would be evaluated with `state`.
+    /// Spans refer to `source`, which is the code the user wrote.
+    pub(crate) fn semantic_tokens(
+        &mut self,
+        user_code: CodeBlock,
+        state: ContextState,
+        code_info: &UserCodeInfo,
+        source: &str,
+    ) -> Result<Vec<SemanticToken>, Error> {
+        let code = self.analyze(user_code, state, code_info)?;
+        Ok(self.analyzer.semantic_tokens(|start, end| {
+            let (start, end) = code.user_range_for_generated_range(start, end)?;
+            Some(Span::from_user_code(source, start, end))
+        }))
+    }
+
+    /// Returns where what's referred to at `offset` in `user_code` is defined, whether that's in
+    /// `user_code` itself or in an earlier cell.
+    pub(crate) fn definitions(
+        &mut self,
+        user_code: CodeBlock,
+        state: ContextState,
+        code_info: &UserCodeInfo,
+        offset: usize,
+    ) -> Result<Vec<Location>, Error> {
+        let code = self.analyze(user_code, state, code_info)?;
+        let ranges = match code.user_offset_to_output_offset(offset) {
+            Ok(offset) => self.analyzer.definitions(offset),
+            Err(_) => Vec::new(),
+        };
+        Ok(self.locations(&code, ranges))
+    }
+
+    /// Like `definitions`, but returns the definition together with all references to it, in
+    /// earlier cells and in `user_code`.
+    pub(crate) fn references(
+        &mut self,
+        user_code: CodeBlock,
+        state: ContextState,
+        code_info: &UserCodeInfo,
+        offset: usize,
+    ) -> Result<Vec<Location>, Error> {
+        let code = self.analyze(user_code, state, code_info)?;
+        let ranges = match code.user_offset_to_output_offset(offset) {
+            Ok(offset) => self.analyzer.references(offset),
+            Err(_) => Vec::new(),
+        };
+        let mut locations = self.locations(&code, ranges);
+        // Earlier cells first, in the order they were evaluated, then `user_code`.
+        locations.sort_by_key(|location| {
+            (
+                location.cell_number.unwrap_or(usize::MAX),
+                location.span.byte_start,
+            )
+        });
+        Ok(locations)
+    }
+
+    /// Returns where `ranges` of `code` came from in the user's cells, skipping those in code
+    /// that we generated.
+    fn locations(&self, code: &CodeBlock, ranges: Vec<Range<usize>>) -> Vec<Location> {
+        ranges
+            .into_iter()
+            .filter_map(|range| {
+                let (cell_number, span) =
+                    code.cell_span_for_generated_range(range.start, range.end)?;
+                Some(Location {
+                    cell_number,
+                    cell_id: cell_number.and_then(|number| self.cell_ids.get(&number).cloned()),
+                    span,
+                })
+            })
+            .collect()
+    }
+
+    /// Records that the cell that was just evaluated has the ID `cell_id`, so that locations in it
+    /// can refer to it.
+    pub(crate) fn record_cell_id(&mut self, cell_id: &str) {
+        self.cell_ids
+            .insert(self.committed_state.cell_number, cell_id.to_owned());
+    }
+
+    /// Gives rust-analyzer the code that `user_code` would be checked as, together with everything
+    /// defined earlier, and returns that code.
+    fn analyze(
+        &mut self,
+        user_code: CodeBlock,
+        mut state: ContextState,
+        code_info: &UserCodeInfo,
+    ) -> Result<CodeBlock, Error> {
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let user_code = state.apply(user_code, &code_info.nodes)?;
+        let code = state.analysis_code(user_code);
+        self.analyzer.set_source(code.code_string())?;
+        Ok(code)
+    }
+
+    /// Starts building `user_code`, which the user hasn't finished writing, in the background, so
+    /// that by the time it's run, its dependencies are built and incremental compilation has state
+    /// to reuse. Errors in the code don't matter, so aren't reported. Returns whether a build was
+    /// started. It isn't if an earlier one is still running, or if we're building for another
+    /// target, since that's done differently.
+    pub(crate) fn precompile(
+        &mut self,
+        user_code: CodeBlock,
+        mut state: ContextState,
+        code_info: &UserCodeInfo,
+    ) -> Result<bool, Error> {
+        if self.module.cross_target(state.target()).is_some() {
+            return Ok(false);
+        }
+        let user_code = state.apply(user_code, &code_info.nodes)?;
+        let code = state.code_to_compile(user_code, state.compilation_mode());
+        self.module.precompile(&code, &state.config)
+    }
+
+    /// Checks whether the items defined so far still compile with `state`, e.g. after its edition
+    /// or toolchain has been changed. Returns the names of the items that have errors, sorted,
+    /// together with all the errors. Some errors may not be attributable to a named item.
+    pub(crate) fn items_broken_by(
+        &mut self,
+        state: &ContextState,
+    ) -> Result<(Vec<String>, Vec<CompilationError>), Error> {
+        if state.items_by_name.is_empty()
+            && state.unnamed_items.is_empty()
+            && state.attributes.is_empty()
+        {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let mut state = state.clone();
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let code = state.analysis_code(CodeBlock::new());
+        self.module.write_cargo_toml(&state)?;
+        let errors: Vec<CompilationError> = self
+            .module
+            .check(&code, &state.config)?
+            .into_iter()
+            .filter(|error| error.level() == "error")
+            .collect();
+        // Work out which lines of the generated code each item occupies, so that we can tell which
+        // items errors are in.
+        let generated = code.code_string();
+        let item_lines: Vec<(&str, usize, usize)> = state
+            .items_by_name
+            .iter()
+            .filter_map(|(name, block)| {
+                let item_code = block.code_string();
+                let start = generated.find(&item_code)?;
+                let first_line = generated[..start].matches('\n').count() + 1;
+                let last_line = first_line + item_code.trim_end().matches('\n').count();
+                Some((name.as_str(), first_line, last_line))
+            })
+            .collect();
+        let mut broken = HashSet::new();
+        for error in &errors {
+            for line in error.primary_lines() {
+                if let Some((name, _, _)) = item_lines
+                    .iter()
+                    .find(|(_, first, last)| (*first..=*last).contains(&line))
+                {
+                    broken.insert(name.to_string());
+                }
+            }
+        }
+        let mut broken: Vec<String> = broken.into_iter().collect();
+        broken.sort();
+        Ok((broken, errors))
+    }
+
     /// Evaluates the supplied Rust code.
     pub(crate) fn eval_with_callbacks(
         &mut self,
@@ -353,11 +1010,28 @@ impl EvalContext {
         }
         let mut phases = PhaseDetailsBuilder::new();
         let code_out = state.apply(user_code.clone(), &code_info.nodes)?;
+        if state.config.migrate_types {
+            self.serialize_for_migration(&mut state, &mut phases, callbacks)?;
+        }
 
-        let mut outputs = match self.run_statements(code_out, &mut state, &mut phases, callbacks) {
-            error @ Err(Error::SubprocessTerminated(_)) => {
+        let result = self.run_statements(code_out, &mut state, &mut phases, callbacks);
+        let interrupted = self.interrupt_handle.execution_finished();
+        let mut outputs = match result {
+            Err(Error::SubprocessTerminated(message)) => {
+                let message = if interrupted {
+                    "Execution interrupted. The subprocess was restarted.".to_owned()
+                } else {
+                    message
+                };
+                if self.committed_state.config.recovery_mode {
+                    let recovery_message = self.restart_and_recover()?;
+                    return Err(Error::SubprocessTerminated(format!(
+                        "{}\n{}",
+                        message, recovery_message
+                    )));
+                }
                 self.restart_child_process()?;
-                return error;
+                return Err(Error::SubprocessTerminated(message));
             }
             Err(Error::CompilationErrors(errors)) => {
                 let mut errors = state.apply_custom_errors(errors, &user_code, code_info);
@@ -372,9 +1046,23 @@ impl EvalContext {
             Ok(x) => x,
         };
 
+        // Warnings about earlier code would be repeated each time, so we only keep those about
+        // the code that was just evaluated.
+        outputs.warnings = self
+            .module
+            .take_warnings()
+            .into_iter()
+            .filter(CompilationError::is_only_from_original_user_code)
+            .map(|mut warning| {
+                warning.fill_lines(code_info);
+                warning
+            })
+            .collect();
+
         // Once, we reach here, our code has successfully executed, so we
         // conclude that variable changes are now applied.
         self.commit_state(state);
+        self.committed_state.variables_to_snapshot.clear();
 
         phases.phase_complete("Execution");
         outputs.phases = phases.phases;
@@ -417,10 +1105,195 @@ impl EvalContext {
             c.code != "evcxr_variable_store"
                 && c.code != "evcxr_internal_runtime"
                 && c.code != "evcxr_analysis_wrapper"
+                && c.code != "evcxr_jobs"
         });
         Ok(completions)
     }
 
+    /// Returns the documentation of the item at `path`, which is resolved as it would be in code
+    /// evaluated with `state`, or None if there's no such item.
+    pub(crate) fn documentation(
+        &mut self,
+        path: &str,
+        state: &ContextState,
+    ) -> Result<Option<ItemDocs>, Error> {
+        let path = path.trim();
+        let parsed = ast::SourceFile::parse(&format!("fn f() {{ {}; }}", path));
+        let statements: Vec<ast::Stmt> = parsed
+            .syntax_node()
+            .descendants()
+            .find_map(ast::StmtList::cast)
+            .map_or_else(Vec::new, |list| list.statements().collect());
+        let is_path = parsed.errors().is_empty()
+            && matches!(
+                statements.as_slice(),
+                [ast::Stmt::ExprStmt(statement)]
+                    if matches!(statement.expr(), Some(ast::Expr::PathExpr(_)))
+            );
+        if !is_path {
+            bail!("`{}` isn't a path", path);
+        }
+        // Importing the path finds types, modules and macros, but not associated items, such as
+        // methods, which we find by using the path as a value.
+        let lookups = [
+            format!("use {} as evcxr_documented_item;", path),
+            format!("let _ = {};", path),
+        ];
+        let code = state
+            .analysis_code(CodeBlock::new().generated(lookups.join("\n")))
+            .code_string();
+        let mut offsets = Vec::new();
+        for lookup in &lookups {
+            match code.find(lookup.as_str()) {
+                Some(start) => offsets.push(start + lookup.find(path).unwrap() + path.len()),
+                None => bail!("Couldn't find `{}` in the analysis code", lookup),
+            }
+        }
+        self.write_cargo_toml(state)?;
+        self.analyzer.set_source(code)?;
+        Ok(offsets
+            .into_iter()
+            .find_map(|offset| self.analyzer.documentation(offset)))
+    }
+
+    /// Returns the type of `expression`, as inferred by rust-analyzer, without evaluating it. The
+    /// expression can refer to the variables and items defined so far.
+    pub(crate) fn type_of(
+        &mut self,
+        expression: &str,
+        state: &ContextState,
+    ) -> Result<String, Error> {
+        let expression = expression.trim();
+        let parsed = ast::SourceFile::parse(&format!("fn f() {{ {} }}", expression));
+        let is_expression = parsed.errors().is_empty()
+            && parsed
+                .syntax_node()
+                .descendants()
+                .find_map(ast::StmtList::cast)
+                .map_or(false, |list| {
+                    list.statements().next().is_none() && list.tail_expr().is_some()
+                });
+        if !is_expression {
+            bail!("`{}` isn't an expression", expression);
+        }
+        let probe = format!("let evcxr_type_probe = {};", expression);
+        let code = state
+            .analysis_code(CodeBlock::new().generated(&probe))
+            .code_string();
+        let start = match code.find(&probe) {
+            Some(start) => start + probe.find(expression).unwrap(),
+            None => bail!("Couldn't find `{}` in the analysis code", probe),
+        };
+        self.write_cargo_toml(state)?;
+        self.analyzer.set_source(code)?;
+        match self
+            .analyzer
+            .type_of_expression(start..start + expression.len())
+        {
+            Some(type_name) => Ok(type_name),
+            None => bail!("Couldn't work out the type of `{}`", expression),
+        }
+    }
+
+    /// Returns the size and alignment of `type_name` on the target that code runs on. Unlike
+    /// `type_of`, this compiles and runs a probe.
+    pub(crate) fn type_layout(
+        &mut self,
+        type_name: &str,
+        state: &ContextState,
+    ) -> Result<TypeLayout, Error> {
+        let type_name = type_name.trim();
+        if !crate::rust_analyzer::is_type_valid(type_name) {
+            bail!("`{}` isn't a type", type_name);
+        }
+        let outputs = self.eval_with_state(&TypeLayout::probe_code(type_name), state.clone())?;
+        match TypeLayout::from_outputs(&outputs) {
+            Some(layout) => Ok(layout),
+            None => bail!("Didn't get the layout of `{}` from the probe", type_name),
+        }
+    }
+
+    /// Returns what the arguments of `command` expand to once macros, including derives, have been
+    /// expanded in the context of the items defined so far.
+    pub(crate) fn expand(
+        &mut self,
+        command: CommandCall,
+        state: &ContextState,
+    ) -> Result<String, Error> {
+        let code = state.expansion_code(command);
+        self.write_cargo_toml(state)?;
+        let build_output = build_output_reporter(&self.event_sinks, &self.stderr_sender);
+        let expanded = self.module.expand(&code, &state.config, &build_output)?;
+        match extract_expansion(&expanded) {
+            Some(expansion) => Ok(expansion),
+            None => bail!("Couldn't find {} in the expanded code", EXPANSION_FN_NAME),
+        }
+    }
+
+    /// Returns `code`, a cell that may start with commands, formatted with rustfmt.
+    pub(crate) fn format_cell(&self, code: &str) -> Result<String, Error> {
+        rustfmt::format_cell(&self.committed_state.config, code)
+    }
+
+    /// Returns rustc's explanation of the error `code`, e.g. "E0597".
+    pub(crate) fn explain_code(&self, code: &str) -> Result<String, Error> {
+        explain::explain_code(&self.committed_state.config, code)
+    }
+
+    pub(crate) fn autoformat(&self) -> bool {
+        self.committed_state.autoformat()
+    }
+
+    pub(crate) fn reactive_mode(&self) -> ReactiveMode {
+        self.committed_state.config.reactive
+    }
+
+    /// Returns the cells downstream of the cell `cell_id`, were it to contain `code`, in the order
+    /// that they should run.
+    pub(crate) fn reactive_downstream(
+        &self,
+        cell_id: &str,
+        code: &str,
+    ) -> Result<Vec<DownstreamCell>, Error> {
+        self.committed_state
+            .reactive_cells
+            .downstream(cell_id, code)
+    }
+
+    /// Records that the cell `cell_id` executed successfully with `code`, for `:reactive`.
+    pub(crate) fn record_reactive_cell(&mut self, cell_id: &str, code: &str) {
+        self.committed_state.reactive_cells.record(cell_id, code);
+    }
+
+    /// Forgets the cell `cell_id`, so that it's no longer downstream of anything.
+    pub(crate) fn forget_reactive_cell(&mut self, cell_id: &str) {
+        self.committed_state.reactive_cells.forget(cell_id);
+    }
+
+    /// Returns what rustc generates for the function at `path`, in the form given by `emit`.
+    pub(crate) fn generated_code(
+        &mut self,
+        emit: Emit,
+        path: &str,
+        state: &ContextState,
+    ) -> Result<String, Error> {
+        let code = state.code_to_compile(CodeBlock::new(), CompilationMode::NoCatch);
+        self.write_cargo_toml(state)?;
+        let build_output = build_output_reporter(&self.event_sinks, &self.stderr_sender);
+        let emitted = self
+            .module
+            .emit(&code, &state.config, emit, &build_output)?;
+        match codegen::function_code(&emitted, emit, CRATE_NAME, path) {
+            Some(function) => Ok(function),
+            None => bail!(
+                "Couldn't find `{}` in the generated {}. Only functions defined in this session \
+                 can be shown, and generic functions only once they're used.",
+                path,
+                emit.description()
+            ),
+        }
+    }
+
     pub fn last_source(&self) -> Result<String, std::io::Error> {
         self.module.last_source()
     }
@@ -429,6 +1302,12 @@ impl EvalContext {
         self.committed_state.set_opt_level(level)
     }
 
+    /// Registers `hint` to be shown alongside errors with `code`, e.g. "E0502", replacing any
+    /// existing hint for that code, including built-in ones. Only affects this context.
+    pub fn register_hint(&mut self, code: &str, hint: &str) {
+        self.committed_state.config.hints.register(code, hint);
+    }
+
     pub fn set_time_passes(&mut self, value: bool) {
         self.committed_state.set_time_passes(value);
     }
@@ -441,10 +1320,22 @@ impl EvalContext {
         self.committed_state.set_error_format(value)
     }
 
+    pub fn set_target(&mut self, target: Option<String>) {
+        self.committed_state.set_target(target);
+    }
+
+    pub fn set_runner(&mut self, runner: Option<String>) {
+        self.committed_state.set_runner(runner);
+    }
+
+    /// Returns the names and types of variables, excluding results, which are listed by
+    /// `:results`.
     pub fn variables_and_types(&self) -> impl Iterator<Item = (&str, &str)> {
-        self.committed_state
+        let state = &self.committed_state;
+        state
             .variable_states
             .iter()
+            .filter(move |(v, _)| !state.results.contains(v))
             .map(|(v, t)| (v.as_str(), t.type_name.as_str()))
     }
 
@@ -460,13 +1351,16 @@ impl EvalContext {
     // compiled. Config is preserved.
     pub fn clear(&mut self) -> Result<(), Error> {
         self.committed_state = self.cleared_state();
+        self.cells.clear();
         self.restart_child_process()
     }
 
-    /// Returns the state that would result from clearing. Config is preserved. Nothing is done to
-    /// the subprocess.
+    /// Returns the state that would result from clearing. Config and the numbering of cells are
+    /// preserved. Nothing is done to the subprocess.
     pub(crate) fn cleared_state(&self) -> ContextState {
-        ContextState::new(self.committed_state.config.clone())
+        let mut state = ContextState::new(self.committed_state.config.clone());
+        state.cell_number = self.committed_state.cell_number;
+        state
     }
 
     pub fn reset_config(&mut self) {
@@ -476,58 +1370,395 @@ impl EvalContext {
     fn restart_child_process(&mut self) -> Result<(), Error> {
         self.committed_state.variable_states.clear();
         self.committed_state.stored_variable_states.clear();
+        let _ = std::fs::remove_dir_all(recovery::snapshot_dir(
+            &self.committed_state.config.crate_dir,
+        ));
         self.child_process = self.child_process.restart()?;
+        if let Some(remote_files) = &mut self.remote_files {
+            remote_files.reset();
+        }
+        self.apply_environment()?;
+        self.metrics
+            .record_child_restart(self.child_process.process_id());
+        if self.remote_files.is_none() {
+            self.debugger
+                .set_process_id(self.child_process.process_id());
+            self.interrupt_handle
+                .set_process_id(self.child_process.process_id());
+        }
         Ok(())
     }
 
-    pub(crate) fn last_compile_dir(&self) -> &Path {
-        self.module.crate_dir()
+    /// Restarts the subprocess, then recreates the variables that we have snapshots of. Returns a
+    /// message saying which variables were restored and which were lost.
+    fn restart_and_recover(&mut self) -> Result<String, Error> {
+        let snapshot_dir = recovery::snapshot_dir(&self.committed_state.config.crate_dir);
+        let variables: Vec<SnapshotVariable> = self
+            .committed_state
+            .stored_variable_states
+            .iter()
+            .map(|(name, variable_state)| SnapshotVariable {
+                name: name.clone(),
+                type_name: variable_state.type_name.clone(),
+                is_mut: variable_state.is_mut,
+                debug: recovery::read_snapshot(&snapshot_dir, name),
+            })
+            .collect();
+        self.restart_child_process()?;
+        let plan = recovery::restore_plan(variables);
+        let mut message = "Session recovered. Definitions were kept.".to_owned();
+        if !plan.restored.is_empty() {
+            if let Err(error) = self.eval(&plan.code) {
+                return Ok(format!(
+                    "Session restarted, but restoring variables failed: {}",
+                    error
+                ));
+            }
+            message.push_str(&format!(
+                " Restored variables: {}.",
+                plan.restored.join(", ")
+            ));
+        }
+        if !plan.lost.is_empty() {
+            message.push_str(&format!(
+                " Lost variables, since they couldn't be snapshotted: {}.",
+                plan.lost.join(", ")
+            ));
+        }
+        Ok(message)
     }
 
-    fn commit_state(&mut self, mut state: ContextState) {
-        for variable_state in state.variable_states.values_mut() {
-            // This span only makes sense when the variable is first defined.
-            variable_state.definition_span = None;
-        }
-        state.stored_variable_states = state.variable_states.clone();
-        state.commit_old_user_code();
-        self.committed_state = state;
+    /// Sends a line to the same place as output from user code. Used for status messages that
+    /// aren't the result of evaluation.
+    pub(crate) fn write_stdout_line(&self, line: String) {
+        let _ = self.stdout_sender.send(line);
     }
 
-    fn run_statements(
+    /// Forgets all variables whose type satisfies `predicate`, returning their names. The values
+    /// remain in the subprocess until overwritten, but will no longer be accessible.
+    pub(crate) fn forget_variables_where(
         &mut self,
-        mut user_code: CodeBlock,
-        state: &mut ContextState,
-        phases: &mut PhaseDetailsBuilder,
-        callbacks: &mut EvalCallbacks,
-    ) -> Result<EvalOutputs, Error> {
-        self.write_cargo_toml(state)?;
-        self.fix_variable_types(state, state.analysis_code(user_code.clone()))?;
-        // In some circumstances we may need a few tries before we get the code right. Note that
-        // we'll generally give up sooner than this if there's nothing left that we think we can
-        // fix. The limit is really to prevent retrying indefinitely in case our "fixing" of things
-        // somehow ends up flip-flopping back and forth. Not sure how that could happen, but best to
-        // avoid any infinite loops.
-        let mut remaining_retries = 5;
-        // TODO: Now that we have rust analyzer, we can probably with a bit of work obtain all the
-        // information we need without relying on compilation errors. See if we can get rid of this.
-        loop {
-            // Try to compile and run the code.
-            let result = self.try_run_statements(
-                user_code.clone(),
-                state,
-                state.compilation_mode(),
-                phases,
-                callbacks,
-            );
-            match result {
-                Ok(execution_artifacts) => {
-                    return Ok(execution_artifacts.output);
-                }
+        mut predicate: impl FnMut(&str) -> bool,
+    ) -> Vec<String> {
+        let mut forgotten: Vec<String> = self
+            .committed_state
+            .variable_states
+            .iter()
+            .filter(|(_, variable_state)| predicate(&variable_state.type_name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        forgotten.sort();
+        for name in &forgotten {
+            self.committed_state.variable_states.remove(name);
+            self.committed_state.stored_variable_states.remove(name);
+        }
+        forgotten
+    }
 
-                Err(Error::CompilationErrors(errors)) => {
-                    // If we failed to compile, attempt to deal with the first
-                    // round of compilation errors by adjusting variable types,
+    /// Forgets those of `names` that are variables, returning the ones that were, sorted.
+    pub(crate) fn forget_variables(&mut self, names: &[String]) -> Vec<String> {
+        let state = &mut self.committed_state;
+        let mut forgotten: Vec<String> = names
+            .iter()
+            .filter(|name| state.variable_states.remove(*name).is_some())
+            .cloned()
+            .collect();
+        forgotten.sort();
+        for name in &forgotten {
+            state.stored_variable_states.remove(name);
+        }
+        forgotten
+    }
+
+    /// Removes dependencies that were only meant to be available for a single cell. Variables,
+    /// items and imports that refer to them are forgotten too, since they'd no longer compile.
+    /// Returns the names of whatever was forgotten.
+    pub(crate) fn remove_scoped_deps(&mut self, deps: &HashSet<String>) -> Vec<String> {
+        let crate_names: HashSet<String> = deps.iter().map(|dep| dep.replace('-', "_")).collect();
+        let refers_to_deps = |code: &str| crate::dev_crate::type_references_any(code, &crate_names);
+        let mut forgotten = self.forget_variables_where(refers_to_deps);
+        let state = &mut self.committed_state;
+        for dep in deps {
+            state.external_deps.remove(dep);
+        }
+        for crate_name in &crate_names {
+            state.extern_crate_stmts.remove(crate_name);
+        }
+        let mut forgotten_items: Vec<String> = state
+            .items_by_name
+            .iter()
+            .filter(|(_, block)| refers_to_deps(&block.code_string()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        forgotten_items.sort();
+        for name in &forgotten_items {
+            state.items_by_name.remove(name);
+        }
+        state
+            .unnamed_items
+            .retain(|block| !refers_to_deps(&block.code_string()));
+        forgotten.extend(forgotten_items);
+        forgotten
+    }
+
+    /// Returns metrics for this context, which continue to be updated as evaluation proceeds.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Returns a handle to the debugger for this context. See `Debugger`.
+    pub fn debugger(&self) -> Debugger {
+        self.debugger.clone()
+    }
+
+    /// Returns the comms that user code has opened. See `Comms`.
+    pub fn comms(&self) -> Comms {
+        self.comms.clone()
+    }
+
+    /// Registers `sink` to be told about the phases of each evaluation as they happen. See
+    /// `EvalEvent`.
+    pub fn subscribe(&mut self, sink: impl EvalEventSink + 'static) {
+        self.event_sinks.push(Box::new(sink));
+    }
+
+    fn emit(&self, event: EvalEvent) {
+        for sink in &self.event_sinks {
+            sink.event(&event);
+        }
+    }
+
+    /// Sends a line that user code wrote to stdout.
+    fn send_stdout(&self, line: String) {
+        if !self.event_sinks.is_empty() {
+            self.emit(EvalEvent::Stdout(line.clone()));
+        }
+        // Note, errors sending are ignored, since it just means the user of the library has
+        // dropped the Receiver.
+        let _ = self.stdout_sender.send(line);
+    }
+
+    /// Returns a handle that can be used to interrupt user code from another thread. See
+    /// `InterruptHandle`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt_handle.clone()
+    }
+
+    /// Sets the mime types that the frontend can display, most preferred first. User code that
+    /// implements `evcxr_runtime::EvcxrDisplay` uses this to decide which representations to
+    /// produce. `*/*` stands for any type not otherwise listed. An empty list, the default, means
+    /// that any type can be displayed.
+    pub fn set_mime_preferences(&mut self, mime_types: Vec<String>) {
+        self.mime_preferences = mime_types;
+    }
+
+    /// Records that `code` is about to be evaluated, so that the debugger knows which cell
+    /// breakpoints should apply to, and so that locations in backtraces can refer to cells by
+    /// number.
+    pub(crate) fn cell_started(&mut self, code: &str) {
+        self.committed_state.cell_number += 1;
+        self.debugger.cell_started(code);
+    }
+
+    /// Returns the ID of the local subprocess in which user code runs, or None if user code runs
+    /// somewhere else, e.g. on a remote machine or under a runner for another target.
+    pub(crate) fn local_child_process_id(&self) -> Option<u32> {
+        if self.remote_files.is_some() || self.committed_state.config.target.is_some() {
+            return None;
+        }
+        Some(self.child_process.process_id())
+    }
+
+    /// Sends `command` to the subprocess and returns its reply, e.g. its new working directory.
+    /// Changes are recorded, so that they're made again if the subprocess restarts.
+    pub(crate) fn run_env_command(&mut self, command: EnvCommand) -> Result<String, Error> {
+        let reply = self.send_env_command(&command)?;
+        self.environment.record(&command, &reply);
+        Ok(reply)
+    }
+
+    /// Returns the prelude file given in the config, if any.
+    pub(crate) fn prelude(&self) -> Option<&Path> {
+        self.prelude.as_deref()
+    }
+
+    /// Returns the profiles to use at startup, as given in the config.
+    pub(crate) fn startup_profiles(&self) -> &[String] {
+        &self.startup_profiles
+    }
+
+    /// Checks that the symbols declared in `extern` blocks in `code` can be found once the built
+    /// code at `so_path` is loaded, which it is as a result.
+    fn check_native_symbols(
+        &mut self,
+        state: &ContextState,
+        code: &CodeBlock,
+        so_path: &str,
+    ) -> Result<(), Error> {
+        let symbols = native_libs::declared_symbols(&code.code_string());
+        if symbols.is_empty() {
+            return Ok(());
+        }
+        let libs = state.native_libs();
+        self.child_process
+            .start_execution(&native_libs::check_command(so_path, libs, &symbols))?;
+        let reply = self.child_process.recv_line()?;
+        // Followed by the subprocess saying that it's done.
+        self.child_process.recv_line()?;
+        native_libs::parse_reply(&reply, libs)
+    }
+
+    /// Returns the environment variables that have been set or unset with `:env` or the config.
+    pub(crate) fn env_summary(&self) -> String {
+        self.environment.summary()
+    }
+
+    fn send_env_command(&mut self, command: &EnvCommand) -> Result<String, Error> {
+        self.child_process.start_execution(&command.to_line())?;
+        let reply = self.child_process.recv_line()?;
+        // Followed by the subprocess saying that it's done, as it does after running user code.
+        self.child_process.recv_line()?;
+        environment::parse_reply(&reply)
+    }
+
+    /// Makes the recorded changes to the environment of a new subprocess.
+    fn apply_environment(&mut self) -> Result<(), Error> {
+        for command in self.environment.commands() {
+            if let Err(error) = self.send_env_command(&command) {
+                bail!("Failed to apply `{}`: {}", command.to_line(), error);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn jobs(&self) -> MutexGuard<'_, Jobs> {
+        self.child_process.jobs()
+    }
+
+    pub(crate) fn last_compile_dir(&self) -> &Path {
+        self.module.crate_dir()
+    }
+
+    pub(crate) fn cargo_lock_path(&self) -> PathBuf {
+        self.module.cargo_lock_path()
+    }
+
+    pub(crate) fn eval_profile(&self, phases: &[PhaseDetails]) -> EvalProfile {
+        let state = &self.committed_state;
+        EvalProfile {
+            phases: phases
+                .iter()
+                .map(|phase| (phase.name.clone(), phase.duration))
+                .collect(),
+            rebuilt_crates: self.module.last_rebuilt_crates().to_vec(),
+            linker: state.config.linker.clone(),
+            sccache: state.config.sccache(),
+            opt_level: state.config.opt_level.clone(),
+            item_count: state.items_by_name.len() + state.unnamed_items.len(),
+        }
+    }
+
+    pub(crate) fn about_info(&self, state: &ContextState) -> AboutInfo {
+        AboutInfo::gather(
+            &state.config,
+            self.module.host_target(),
+            &self.module.cargo_target_dir(),
+            self.sandbox_policy.as_ref(),
+            self.prelude(),
+        )
+    }
+
+    /// Records `code`, the cell that was just executed, so that its statements are included by
+    /// `:export_project` and it can be run again by `:rerun_dependents`. Not done for code that we
+    /// generate ourselves, e.g. for commands.
+    pub(crate) fn record_successful_cell(&mut self, code: &str) {
+        let state = &mut self.committed_state;
+        let statements = std::mem::take(&mut state.cell_statements);
+        state.exported_statements.extend(statements);
+        state.cell_history.record(code);
+    }
+
+    fn commit_state(&mut self, mut state: ContextState) {
+        for variable_state in state.variable_states.values_mut() {
+            // This span only makes sense when the variable is first defined.
+            variable_state.definition_span = None;
+        }
+        state.stored_variable_states = state.variable_states.clone();
+        state.migrating_in.clear();
+        let variable_states = &state.variable_states;
+        state
+            .results
+            .retain(|name| variable_states.contains_key(name));
+        state.commit_old_user_code();
+        self.committed_state = state;
+    }
+
+    /// Serializes the variables that would be lost because `state` changes the layout of their
+    /// types, by building and running the current code with some extra code added. The variables
+    /// are then deserialized by `state`'s code.
+    fn serialize_for_migration(
+        &mut self,
+        state: &mut ContextState,
+        phases: &mut PhaseDetailsBuilder,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<(), Error> {
+        let variables = state.variables_losing_type();
+        if variables.is_empty() {
+            return Ok(());
+        }
+        let missing_crates = self.committed_state.missing_migration_crates();
+        if !missing_crates.is_empty() {
+            self.write_stdout_line(format!(
+                "Couldn't migrate {}, since `:migrate_types` needs {}. Add them with `:dep`.",
+                variables.join(", "),
+                missing_crates.join(" and ")
+            ));
+            return Ok(());
+        }
+        let mut old_state = self.committed_state.clone();
+        old_state.migrating_out = variables.clone();
+        self.run_statements(CodeBlock::new(), &mut old_state, phases, callbacks)?;
+        // The code we just ran used up the build number, so it mustn't be used again.
+        state.build_num = old_state.build_num;
+        self.committed_state.build_num = old_state.build_num;
+        state.migrating_in = variables;
+        Ok(())
+    }
+
+    fn run_statements(
+        &mut self,
+        mut user_code: CodeBlock,
+        state: &mut ContextState,
+        phases: &mut PhaseDetailsBuilder,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        self.write_cargo_toml(state)?;
+        self.fix_variable_types(state, state.analysis_code(user_code.clone()))?;
+        // In some circumstances we may need a few tries before we get the code right. Note that
+        // we'll generally give up sooner than this if there's nothing left that we think we can
+        // fix. The limit is really to prevent retrying indefinitely in case our "fixing" of things
+        // somehow ends up flip-flopping back and forth. Not sure how that could happen, but best to
+        // avoid any infinite loops.
+        let mut remaining_retries = 5;
+        // TODO: Now that we have rust analyzer, we can probably with a bit of work obtain all the
+        // information we need without relying on compilation errors. See if we can get rid of this.
+        loop {
+            // Try to compile and run the code.
+            let result = self.try_run_statements(
+                user_code.clone(),
+                state,
+                state.compilation_mode(),
+                phases,
+                callbacks,
+            );
+            match result {
+                Ok(execution_artifacts) => {
+                    return Ok(execution_artifacts.output);
+                }
+
+                Err(Error::CompilationErrors(errors)) => {
+                    // If we failed to compile, attempt to deal with the first
+                    // round of compilation errors by adjusting variable types,
                     // whether they've been moved into the catch_unwind block
                     // etc.
                     if remaining_retries > 0 {
@@ -581,7 +1812,39 @@ impl EvalContext {
         callbacks: &mut EvalCallbacks,
     ) -> Result<ExecutionArtifacts, Error> {
         let code = state.code_to_compile(user_code, compilation_mode);
-        let so_file = self.module.compile(&code, &state.config)?;
+        if let Some(target) = self.module.cross_target(state.target()).map(str::to_owned) {
+            let compilation_start = self.compilation_started();
+            let build_output = build_output_reporter(&self.event_sinks, &self.stderr_sender);
+            let executable = self.module.compile_executable(
+                &code,
+                &state.config,
+                &target,
+                &state.current_user_fn_name(),
+                &build_output,
+            );
+            self.compilation_finished(compilation_start);
+            let executable = executable?;
+            let (fresh, rebuilt) = self.module.last_artifact_counts();
+            self.metrics.record_build(fresh, rebuilt);
+            if compilation_mode == CompilationMode::NoCatchExpectError {
+                return Ok(ExecutionArtifacts {
+                    output: EvalOutputs::new(),
+                });
+            }
+            phases.phase_complete("Final compile");
+            let execution_start = self.execution_started();
+            let output = self.run_executable(state, &target, &executable);
+            self.execution_finished(execution_start);
+            return Ok(ExecutionArtifacts { output: output? });
+        }
+        self.module.set_debug_info(self.debugger.is_attached());
+        let compilation_start = self.compilation_started();
+        let build_output = build_output_reporter(&self.event_sinks, &self.stderr_sender);
+        let so_file = self.module.compile(&code, &state.config, &build_output);
+        self.compilation_finished(compilation_start);
+        let so_file = so_file?;
+        let (fresh, rebuilt) = self.module.last_artifact_counts();
+        self.metrics.record_build(fresh, rebuilt);
 
         if compilation_mode == CompilationMode::NoCatchExpectError {
             // Uh-oh, caller was expecting an error, return OK and the caller can return the
@@ -591,9 +1854,106 @@ impl EvalContext {
             });
         }
         phases.phase_complete("Final compile");
+        self.debugger
+            .code_compiled(&code, &self.module.lib_rs_path())?;
 
-        let output = self.run_and_capture_output(state, &so_file, callbacks)?;
-        Ok(ExecutionArtifacts { output })
+        let execution_start = self.execution_started();
+        let output = self.run_and_capture_output(state, &code, &so_file, callbacks);
+        self.execution_finished(execution_start);
+        Ok(ExecutionArtifacts { output: output? })
+    }
+
+    fn compilation_started(&self) -> Instant {
+        self.emit(EvalEvent::CompilationStarted);
+        Instant::now()
+    }
+
+    fn compilation_finished(&self, start: Instant) {
+        self.emit(EvalEvent::CompilationFinished(start.elapsed()));
+    }
+
+    fn execution_started(&self) -> Instant {
+        self.emit(EvalEvent::ExecutionStarted);
+        Instant::now()
+    }
+
+    fn execution_finished(&self, start: Instant) {
+        self.emit(EvalEvent::ExecutionFinished(start.elapsed()));
+    }
+
+    /// Runs an executable built for a target other than the host. Unlike code run in our
+    /// subprocess, the executable runs to completion before we process its output, and it can't
+    /// read input. Each run is a fresh process, so variables can't be carried over.
+    fn run_executable(
+        &mut self,
+        state: &mut ContextState,
+        target: &str,
+        executable: &Path,
+    ) -> Result<EvalOutputs, Error> {
+        let mut command = Module::executable_command(&state.config, target, executable);
+        if let Some(policy) = &self.sandbox_policy {
+            policy.apply(&mut command, &self.module.tmpdir)?;
+        }
+        let run_output = match command.output() {
+            Ok(run_output) => run_output,
+            Err(error) => bail!(
+                "Failed to run {:?}: {}. Use :runner to configure how to run code for {}",
+                command,
+                error,
+                target
+            ),
+        };
+        state.build_num += 1;
+        let mut output = EvalOutputs::new();
+        let mut stdout_limiter = OutputLimiter::new(state.config.output_limit);
+        let stdout = String::from_utf8_lossy(&run_output.stdout);
+        let mut lines = stdout.lines();
+        while let Some(line) = lines.next() {
+            if let Some(mime_type) = line.strip_prefix("EVCXR_BEGIN_CONTENT ") {
+                let content: Vec<&str> = lines
+                    .by_ref()
+                    .take_while(|line| *line != "EVCXR_END_CONTENT")
+                    .collect();
+                let mut content = content.join("\n");
+                if mime_type == "text/plain" {
+                    content = state.config.number_format.apply(&content);
+                    content = truncate_result(state, content, &mut output);
+                }
+                output
+                    .content_by_mime_type
+                    .insert(mime_type.to_owned(), Content::decode(mime_type, content));
+            } else if let Some(total_rows) = line.strip_prefix(evcxr_internal_runtime::BEGIN_TABLE)
+            {
+                let table_lines: Vec<String> = lines
+                    .by_ref()
+                    .take_while(|line| *line != evcxr_internal_runtime::END_TABLE)
+                    .map(str::to_owned)
+                    .collect();
+                add_table_output(state, total_rows, &table_lines, &mut output);
+            } else if line != evcxr_internal_runtime::USER_ERROR_OCCURRED {
+                if let Some(line) = stdout_limiter.filter(line.to_owned()) {
+                    self.send_stdout(line);
+                }
+            }
+        }
+        for line in String::from_utf8_lossy(&run_output.stderr).lines() {
+            let _ = self.stderr_sender.send(line.to_owned());
+        }
+        if !state.variable_states.is_empty() {
+            let mut names: Vec<&str> = state.variable_states.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            let _ = self.stderr_sender.send(format!(
+                "Variables aren't kept between evaluations when running on {}. Dropped: {}",
+                target,
+                names.join(", ")
+            ));
+            state.variable_states.clear();
+        }
+        state.stored_variable_states.clear();
+        if !run_output.status.success() {
+            bail!("Execution failed: {}", run_output.status);
+        }
+        Ok(output)
     }
 
     pub(crate) fn write_cargo_toml(&self, state: &ContextState) -> Result<()> {
@@ -624,10 +1984,18 @@ impl EvalContext {
             // errors (although we may eventually drop the code that does that). At the time of
             // writing, the test `int_array` fails if we don't reject invalid types here.
             if variable_name == "evcxr_variable_store"
+                || variable_name == result_history::LATEST_RESULT
                 || !crate::rust_analyzer::is_type_valid(&type_name)
             {
                 continue;
             }
+            // Results that are references would fail to be stored. Results of type `()` aren't
+            // worth storing.
+            if state.results.contains(&variable_name)
+                && (type_name == "()" || type_name.contains('&') || type_name.contains('\''))
+            {
+                continue;
+            }
             let preserve_vars_on_panic = state.config.preserve_vars_on_panic;
             state
                 .variable_states
@@ -651,6 +2019,7 @@ impl EvalContext {
     fn run_and_capture_output(
         &mut self,
         state: &mut ContextState,
+        code: &CodeBlock,
         so_file: &SoFile,
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
@@ -659,21 +2028,45 @@ impl EvalContext {
         // things won't work if the path isn't UTF-8 - apparently that's a thing
         // on some platforms.
         let fn_name = state.current_user_fn_name();
-        self.child_process.send(&format!(
-            "LOAD_AND_RUN {} {}",
-            so_file.path.to_string_lossy(),
-            fn_name,
-        ))?;
+        let so_path = if let Some(remote_files) = &mut self.remote_files {
+            let std_lib_dir = std_lib_dir(&state.config, self.module.host_target())?;
+            remote_files.upload(&mut self.child_process, &so_file.path, &std_lib_dir)?
+        } else {
+            so_file.path.to_string_lossy().into_owned()
+        };
+        if !state.config.native_libs.is_empty() && state.config.target.is_none() {
+            self.check_native_symbols(state, code, &so_path)?;
+        }
+        self.child_process.set_panic_source(PanicSource::new(
+            code.clone(),
+            self.module.crate_dir(),
+            state.cell_number,
+        ));
+        self.interrupt_handle
+            .execution_started(state.is_interruptible());
+        self.child_process
+            .start_execution(&format!("LOAD_AND_RUN {} {}", so_path, fn_name))?;
+        let stdin_start = self.child_process.bytes_sent();
 
         state.build_num += 1;
 
         let mut got_panic = false;
         let mut lost_variables = Vec::new();
+        let mut stdout_limiter = OutputLimiter::new(state.config.output_limit);
+        let mut test_report = TestReport::default();
         static MIME_OUTPUT: OnceCell<Regex> = OnceCell::new();
         let mime_output =
             MIME_OUTPUT.get_or_init(|| Regex::new("EVCXR_BEGIN_CONTENT ([^ ]+)").unwrap());
         loop {
-            let line = self.child_process.recv_line()?;
+            let line = match self.child_process.recv()? {
+                Output::Line(line) => line,
+                Output::Binary { mime_type, data } => {
+                    output
+                        .content_by_mime_type
+                        .insert(mime_type, Content::Binary(data));
+                    continue;
+                }
+            };
             if line == runtime::EVCXR_EXECUTION_COMPLETE {
                 break;
             }
@@ -684,6 +2077,29 @@ impl EvalContext {
                 let prompt = line.split(':').nth(1).unwrap_or_default();
                 self.child_process
                     .send(&(callbacks.input_reader)(prompt, is_password))?;
+            } else if let Some((prompt, forwarded)) = stdin_relay::parse_waiting(&line) {
+                // User code is blocked reading stdin. If it hasn't yet received everything we've
+                // sent, then it's about to get more input, so we only ask the user if it has.
+                if forwarded == self.child_process.bytes_sent() - stdin_start {
+                    let input = (callbacks.input_reader)(prompt, false);
+                    self.child_process.send(&input)?;
+                } else if !prompt.is_empty() {
+                    if let Some(line) = stdout_limiter.filter(prompt.to_owned()) {
+                        self.send_stdout(line);
+                    }
+                }
+            } else if line == evcxr_internal_runtime::GET_MIME_PREFERENCES {
+                self.child_process.send(&self.mime_preferences.join(" "))?;
+            } else if let Some(request) = line.strip_prefix(comm::COMM_GET) {
+                self.child_process.send(&self.comms.get_reply(request))?;
+            } else if let Some(message) = self.comms.process_line(&line) {
+                (callbacks.comm_sender)(message);
+            } else if let Some(update) = display_update::parse(&line) {
+                (callbacks.display_sender)(update);
+            } else if let Some(wait) = display_update::parse_clear_output(&line) {
+                // Content that was displayed earlier in the cell, but not yet sent, is cleared too.
+                output.content_by_mime_type.clear();
+                (callbacks.output_clearer)(wait);
             } else if line == evcxr_internal_runtime::USER_ERROR_OCCURRED {
                 // A question mark operator in user code triggered an early
                 // return. Any variables moved into the block in which the code
@@ -694,10 +2110,52 @@ impl EvalContext {
                     .retain(|_variable_name, variable_state| {
                         variable_state.move_state != VariableMoveState::MovedIntoCatchUnwind
                     });
+                // The error itself was stored as `_err`.
+                state.variable_states.insert(
+                    LAST_ERROR_VARIABLE.to_owned(),
+                    VariableState {
+                        type_name: "String".to_owned(),
+                        is_mut: false,
+                        move_state: VariableMoveState::Available,
+                        is_copy_type: false,
+                        definition_span: None,
+                    },
+                );
+            } else if let Some(samples) = line.strip_prefix(evcxr_internal_runtime::TIMEIT_SAMPLES)
+            {
+                output.timing = Some(Timing {
+                    repeated_runs: RunStatistics::parse(samples),
+                    ..Timing::default()
+                });
+            } else if let Some(stats) = line.strip_prefix(evcxr_internal_runtime::MEMORY_STATS) {
+                output.memory = MemoryStats::parse(stats);
             } else if let Some(variable_name) =
                 line.strip_prefix(evcxr_internal_runtime::VARIABLE_CHANGED_TYPE)
             {
                 lost_variables.push(variable_name.to_owned());
+            } else if let Some(variable_name) = line.strip_prefix(evcxr_internal_runtime::MIGRATED)
+            {
+                self.write_stdout_line(format!("Migrated {}", variable_name));
+            } else if let Some(failure) =
+                line.strip_prefix(evcxr_internal_runtime::MIGRATION_FAILED)
+            {
+                let (variable_name, reason) = failure.split_once(' ').unwrap_or((failure, ""));
+                self.write_stdout_line(format!("Couldn't migrate {}: {}", variable_name, reason));
+            } else if let Some(total_rows) = line.strip_prefix(evcxr_internal_runtime::BEGIN_TABLE)
+            {
+                let mut table_lines = Vec::new();
+                loop {
+                    let line = self.child_process.recv_line()?;
+                    if line == evcxr_internal_runtime::END_TABLE {
+                        break;
+                    }
+                    if line == PANIC_NOTIFICATION {
+                        got_panic = true;
+                        break;
+                    }
+                    table_lines.push(line);
+                }
+                add_table_output(state, total_rows, &table_lines, &mut output);
             } else if let Some(captures) = mime_output.captures(&line) {
                 let mime_type = captures[1].to_owned();
                 let mut content = String::new();
@@ -715,15 +2173,29 @@ impl EvalContext {
                     }
                     content.push_str(&line);
                 }
+                if mime_type == "text/plain" {
+                    content = state.config.number_format.apply(&content);
+                    content = truncate_result(state, content, &mut output);
+                }
+                let content = Content::decode(&mime_type, content);
                 output.content_by_mime_type.insert(mime_type, content);
             } else {
-                // Note, errors sending are ignored, since it just means the
-                // user of the library has dropped the Receiver.
-                // TODO: Clone might not be necessary with NLL.
-                let _ = self.stdout_sender.send(line.clone());
+                // Output of tests being run by `:test` is kept with their results.
+                if let Some(line) = test_report
+                    .process_line(line)
+                    .and_then(|line| stdout_limiter.filter(line))
+                {
+                    self.send_stdout(line);
+                }
             }
         }
+        if !test_report.is_empty() {
+            output.tests = Some(test_report);
+        }
         if got_panic {
+            if self.interrupt_handle.interrupt_requested() {
+                let _ = self.stderr_sender.send("Execution interrupted.".to_owned());
+            }
             let mut lost = Vec::new();
             state
                 .variable_states
@@ -738,10 +2210,10 @@ impl EvalContext {
             if !lost.is_empty() {
                 output.content_by_mime_type.insert(
                     "text/plain".to_owned(),
-                    format!(
+                    Content::Text(format!(
                         "Panic occurred, the following variables have been lost: {}",
                         lost.join(", ")
-                    ),
+                    )),
                 );
             }
         } else if !lost_variables.is_empty() {
@@ -750,6 +2222,63 @@ impl EvalContext {
         Ok(output)
     }
 
+    /// Adds the crate on crates.io that's used in code as `crate_name`, for `:auto_dep`. Returns
+    /// whether it was added. If it wasn't, the reason is reported on stderr, so that the user can
+    /// see why, next to the original compilation error.
+    fn add_missing_dep(
+        &mut self,
+        crate_name: &str,
+        state: &mut ContextState,
+    ) -> Result<bool, Error> {
+        // Crates with `-` in their names are referred to in code with `_`.
+        let lib_name = |name: &str| name.replace('-', "_");
+        if state
+            .external_deps
+            .keys()
+            .any(|name| lib_name(name) == crate_name)
+        {
+            return Ok(false);
+        }
+        let search = match crate::crate_search::search(&state.config, crate_name) {
+            Ok(search) => search,
+            Err(error) => {
+                let _ = self.stderr_sender.send(format!(
+                    "Couldn't look up `{}` on crates.io for :auto_dep: {}",
+                    crate_name, error
+                ));
+                return Ok(false);
+            }
+        };
+        let found = match search
+            .matches
+            .iter()
+            .find(|found| lib_name(&found.name) == crate_name)
+        {
+            Some(found) => found,
+            None => {
+                let _ = self.stderr_sender.send(format!(
+                    "There's no crate called `{}` on crates.io for :auto_dep to add",
+                    crate_name
+                ));
+                return Ok(false);
+            }
+        };
+        if let Err(error) = state.add_dep(&found.name, &format!("\"{}\"", found.version)) {
+            let _ = self.stderr_sender.send(format!(
+                "Couldn't add {} for :auto_dep: {}",
+                found.dependency(),
+                error
+            ));
+            return Ok(false);
+        }
+        // Validating the dependency overwrote Cargo.toml.
+        self.write_cargo_toml(state)?;
+        let _ = self
+            .stderr_sender
+            .send(format!("Added {} (:auto_dep is on)", found.dependency()));
+        Ok(true)
+    }
+
     fn attempt_to_fix_error(
         &mut self,
         error: &CompilationError,
@@ -760,8 +2289,41 @@ impl EvalContext {
         static DISALLOWED_TYPES: OnceCell<Regex> = OnceCell::new();
         let disallowed_types =
             DISALLOWED_TYPES.get_or_init(|| Regex::new("(impl .*|[.*@])").unwrap());
+        // Falling back may make other fixes unnecessary, or even wrong. e.g. if the code being
+        // replaced borrowed a variable for too long, storing that variable would fail. Uses of
+        // moved values are an exception, since those are fixed the same way as for any other code.
+        let mut fell_back = false;
+        if error.code() != Some("E0382") {
+            for code_origin in &error.code_origins {
+                if let CodeKind::WithFallback(fallback) = code_origin {
+                    user_code.apply_fallback(fallback);
+                    fell_back = true;
+                }
+            }
+        }
+        if fell_back {
+            fixed_errors.insert("Fallback");
+            return Ok(());
+        }
+        if state.config.auto_dep {
+            if let Some(crate_name) = error.missing_crate() {
+                if self.add_missing_dep(&crate_name, state)? {
+                    fixed_errors.insert("Auto dep");
+                    return Ok(());
+                }
+            }
+        }
         for code_origin in &error.code_origins {
             match code_origin {
+                CodeKind::PackVariable { variable_name }
+                    if state.results.contains(variable_name)
+                        && !state.stored_variable_states.contains_key(variable_name)
+                        && !matches!(error.code(), Some("E0308" | "E0382" | "E0425")) =>
+                {
+                    // A result that can't be stored shouldn't stop the code from running.
+                    state.variable_states.remove(variable_name);
+                    fixed_errors.insert("Result not kept");
+                }
                 CodeKind::PackVariable { variable_name } => {
                     if error.code() == Some("E0308") {
                         // Handle mismatched types. We might eventually remove this code entirely
@@ -845,12 +2407,27 @@ impl EvalContext {
                         }
                     }
                 }
-                CodeKind::WithFallback(fallback) => {
-                    user_code.apply_fallback(fallback);
-                    fixed_errors.insert("Fallback");
+                CodeKind::WithFallback(_) => {
+                    // Handled above.
                 }
                 CodeKind::OriginalUserCode(_) | CodeKind::OtherUserCode => {
-                    if error.code() == Some("E0728") && !state.async_mode {
+                    if state.config.auto_use
+                        && matches!(
+                            error.code(),
+                            Some("E0405" | "E0412" | "E0422" | "E0425" | "E0433")
+                        )
+                    {
+                        // A name couldn't be found. If rustc knows of exactly one item with that
+                        // name, we import it. Otherwise we leave the user to choose.
+                        if let [use_stmt] = error.import_suggestions().as_slice() {
+                            if state.add_import(use_stmt) {
+                                let _ = self
+                                    .stderr_sender
+                                    .send(format!("Added `{}` (:auto_use is on)", use_stmt));
+                                fixed_errors.insert("Auto use");
+                            }
+                        }
+                    } else if error.code() == Some("E0728") && !state.async_mode {
                         state.async_mode = true;
                         if !state.external_deps.contains_key("tokio") {
                             state.add_dep("tokio", "\"0.2\"")?;
@@ -885,26 +2462,89 @@ impl EvalContext {
     }
 }
 
-fn fix_path() {
-    // If cargo isn't on our path, see if it exists in the same directory as
-    // our executable and if it does, add that directory to our PATH.
-    if which::which("cargo").is_err() {
-        if let Ok(current_exe) = std::env::current_exe() {
-            if let Some(bin_dir) = current_exe.parent() {
-                if bin_dir.join("cargo").exists() {
-                    if let Some(mut path) = std::env::var_os("PATH") {
-                        if cfg!(windows) {
-                            path.push(";");
-                        } else {
-                            path.push(":");
-                        }
-                        path.push(bin_dir);
-                        std::env::set_var("PATH", path);
-                    }
-                }
-            }
+/// Returns the directory containing the standard library's shared object for `target`.
+fn output_channel(
+    buffer_lines: Option<usize>,
+) -> (
+    crossbeam_channel::Sender<String>,
+    crossbeam_channel::Receiver<String>,
+) {
+    match buffer_lines {
+        Some(lines) => crossbeam_channel::bounded(lines),
+        None => crossbeam_channel::unbounded(),
+    }
+}
+
+/// Returns a function that reports a line of cargo's output from `:build_log`, both as an
+/// `EvalEvent` and on stderr, so that frontends that don't subscribe to events still show it.
+fn build_output_reporter<'a>(
+    event_sinks: &'a [Box<dyn EvalEventSink>],
+    stderr_sender: &'a crossbeam_channel::Sender<String>,
+) -> impl Fn(&str) + 'a {
+    move |line| {
+        let event = EvalEvent::BuildOutput(line.to_owned());
+        for sink in event_sinks {
+            sink.event(&event);
+        }
+        let _ = stderr_sender.send(line.to_owned());
+    }
+}
+
+/// Adds HTML and plain-text renderings of a table sent by `emit_table` to `output`.
+fn add_table_output(
+    state: &ContextState,
+    total_rows: &str,
+    lines: &[String],
+    output: &mut EvalOutputs,
+) {
+    if let Some(mut table) = Table::parse(total_rows, lines) {
+        table.format_numbers(&state.config.number_format);
+        output.content_by_mime_type.insert(
+            "text/html".to_owned(),
+            Content::Html(table.to_html(state.config.table_rows)),
+        );
+        output.content_by_mime_type.insert(
+            "text/plain".to_owned(),
+            Content::Text(table.to_text(state.config.table_rows)),
+        );
+    }
+}
+
+/// Returns the text of a result, cut short if it has more lines than `:max_output_lines` allows. The
+/// whole text is then also added to `output` as HTML, where it can be expanded, unless the result
+/// has its own HTML.
+fn truncate_result(state: &ContextState, text: String, output: &mut EvalOutputs) -> String {
+    match output_limit::truncate_lines(&text, state.config.max_output_lines) {
+        Some(truncated) => {
+            output
+                .content_by_mime_type
+                .entry("text/html".to_owned())
+                .or_insert(Content::Html(truncated.html));
+            truncated.text
         }
+        None => text,
+    }
+}
+
+fn std_lib_dir(config: &Config, target: &str) -> Result<PathBuf, Error> {
+    let mut command = config.tool_paths.rustc_command();
+    if !config.toolchain.is_empty() {
+        command.arg(format!("+{}", config.toolchain));
     }
+    let output = command.arg("--print").arg("sysroot").output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to get sysroot: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(
+        PathBuf::from(String::from_utf8_lossy(&output.stdout).trim())
+            .join("lib")
+            .join("rustlib")
+            .join(target)
+            .join("lib"),
+    )
 }
 
 /// Returns whether a type is fully specified. i.e. it doesn't contain any '_'.
@@ -945,8 +2585,15 @@ impl PhaseDetailsBuilder {
 
 #[derive(Default, Debug)]
 pub struct EvalOutputs {
-    pub content_by_mime_type: HashMap<String, String>,
-    pub timing: Option<Duration>,
+    pub content_by_mime_type: HashMap<String, Content>,
+    pub timing: Option<Timing>,
+    /// Heap usage of the evaluation, if memory tracking was turned on with `:mem on`.
+    pub memory: Option<MemoryStats>,
+    /// The results of running tests with `:test`.
+    pub tests: Option<TestReport>,
+    /// Compiler warnings about the evaluated code, together with clippy's lints if clippy was
+    /// turned on with `:clippy`.
+    pub warnings: Vec<CompilationError>,
     pub phases: Vec<PhaseDetails>,
 }
 
@@ -955,6 +2602,9 @@ impl EvalOutputs {
         EvalOutputs {
             content_by_mime_type: HashMap::new(),
             timing: None,
+            memory: None,
+            tests: None,
+            warnings: Vec::new(),
             phases: Vec::new(),
         }
     }
@@ -962,9 +2612,9 @@ impl EvalOutputs {
     pub fn text_html(text: String, html: String) -> EvalOutputs {
         let mut out = EvalOutputs::new();
         out.content_by_mime_type
-            .insert("text/plain".to_owned(), text);
+            .insert("text/plain".to_owned(), Content::Text(text));
         out.content_by_mime_type
-            .insert("text/html".to_owned(), html);
+            .insert("text/html".to_owned(), Content::Html(html));
         out
     }
 
@@ -972,17 +2622,32 @@ impl EvalOutputs {
         self.content_by_mime_type.is_empty()
     }
 
+    /// Returns the content of type `mime_type` if there is some and it's textual. See `Content`.
     pub fn get(&self, mime_type: &str) -> Option<&str> {
-        self.content_by_mime_type.get(mime_type).map(String::as_str)
+        self.content_by_mime_type
+            .get(mime_type)
+            .and_then(Content::as_text)
     }
 
     pub fn merge(&mut self, other: EvalOutputs) {
         for (mime_type, content) in other.content_by_mime_type {
-            self.content_by_mime_type
-                .entry(mime_type)
-                .or_default()
-                .push_str(&content);
+            match self.content_by_mime_type.entry(mime_type) {
+                Entry::Occupied(mut existing) => existing.get_mut().append(content),
+                Entry::Vacant(vacant) => {
+                    vacant.insert(content);
+                }
+            }
         }
+        if other.timing.is_some() {
+            self.timing = other.timing;
+        }
+        if other.memory.is_some() {
+            self.memory = other.memory;
+        }
+        if other.tests.is_some() {
+            self.tests = other.tests;
+        }
+        self.warnings.extend(other.warnings);
     }
 }
 
@@ -1032,6 +2697,13 @@ enum CompilationMode {
 #[derive(Clone, Debug)]
 pub struct ContextState {
     items_by_name: HashMap<String, CodeBlock>,
+    /// Structs, enums and unions that the code being evaluated redefines, mapped to whether their
+    /// layout is unchanged, in which case variables of those types are kept.
+    redefined_types: HashMap<String, bool>,
+    /// Variables to serialize, so that they can be migrated to new definitions of their types.
+    migrating_out: Vec<String>,
+    /// Variables that were serialized, to be deserialized into the new definitions of their types.
+    migrating_in: Vec<String>,
     unnamed_items: Vec<CodeBlock>,
     pub(crate) external_deps: HashMap<String, ExternalCrate>,
     // Keyed by crate name. Could use a set, except that the statement might be
@@ -1044,9 +2716,30 @@ pub struct ContextState {
     /// code was executed. Doesn't include newly defined variables until after
     /// execution completes.
     stored_variable_states: HashMap<String, VariableState>,
+    /// Variables to snapshot after the code that's about to run, in recovery mode: those that the
+    /// code mentions, so may change, and any that haven't been snapshotted since recovery mode was
+    /// turned on.
+    variables_to_snapshot: HashSet<String>,
     attributes: HashMap<String, CodeBlock>,
     async_mode: bool,
     allow_question_mark: bool,
+    /// Whether `:bg` has been used, in which case code gets access to the registry of jobs.
+    background_jobs: bool,
+    /// Whether `evcxr::register_formatter` has been used, in which case results are displayed with
+    /// any formatter registered for their type.
+    formatters: bool,
+    results: ResultHistory,
+    /// The statements of the code most recently applied, as they'd be written in a plain function.
+    cell_statements: Vec<String>,
+    /// The statements of cells that have executed successfully, for `:export_project`.
+    exported_statements: Vec<String>,
+    /// Cells that have executed successfully, for `:rerun_dependents`.
+    cell_history: CellHistory,
+    /// The latest code of cells that have executed successfully with an ID, for `:reactive`.
+    reactive_cells: ReactiveCells,
+    /// The number of the cell being evaluated, counting from 1 in the order in which cells were
+    /// evaluated, or 0 if cells aren't being counted.
+    cell_number: usize,
     build_num: i32,
     config: Config,
 }
@@ -1055,6 +2748,9 @@ impl ContextState {
     fn new(config: Config) -> ContextState {
         ContextState {
             items_by_name: HashMap::new(),
+            redefined_types: HashMap::new(),
+            migrating_out: Vec::new(),
+            migrating_in: Vec::new(),
             unnamed_items: vec![],
             external_deps: HashMap::from([(
                 "print_any".to_owned(),
@@ -1066,9 +2762,18 @@ impl ContextState {
             extern_crate_stmts: HashMap::new(),
             variable_states: HashMap::new(),
             stored_variable_states: HashMap::new(),
+            variables_to_snapshot: HashSet::new(),
             attributes: HashMap::new(),
             async_mode: false,
             allow_question_mark: false,
+            background_jobs: false,
+            formatters: false,
+            results: ResultHistory::default(),
+            cell_statements: Vec::new(),
+            exported_statements: Vec::new(),
+            cell_history: CellHistory::default(),
+            reactive_cells: ReactiveCells::default(),
+            cell_number: 0,
             build_num: 0,
             config,
         }
@@ -1082,10 +2787,40 @@ impl ContextState {
         self.config.time_passes = value;
     }
 
+    /// Returns the listing of kept results shown by `:results`.
+    pub(crate) fn results_summary(&self) -> String {
+        self.results.summary(|name| {
+            self.variable_states
+                .get(name)
+                .map(|variable_state| variable_state.type_name.as_str())
+        })
+    }
+
+    /// Makes `evcxr_jobs`, the registry of background jobs, available to code.
+    pub(crate) fn enable_background_jobs(&mut self) {
+        self.background_jobs = true;
+    }
+
     pub fn set_offline_mode(&mut self, value: bool) {
         self.config.offline_mode = value;
     }
 
+    pub fn set_cargo_retries(&mut self, retries: u32) {
+        self.config.cargo_retries = retries;
+    }
+
+    pub fn cargo_retries(&self) -> u32 {
+        self.config.cargo_retries
+    }
+
+    pub fn set_build_log(&mut self, value: bool) {
+        self.config.build_log = value;
+    }
+
+    pub fn build_log(&self) -> bool {
+        self.config.build_log
+    }
+
     pub fn set_sccache(&mut self, enabled: bool) -> Result<(), Error> {
         self.config.set_sccache(enabled)
     }
@@ -1094,84 +2829,421 @@ impl ContextState {
         self.config.sccache()
     }
 
-    pub fn set_error_format(&mut self, format_str: &str) -> Result<(), Error> {
-        for format in ERROR_FORMATS {
-            if format.format_str == format_str {
-                self.config.error_fmt = format;
-                return Ok(());
-            }
+    pub fn set_error_format(&mut self, format_str: &str) -> Result<(), Error> {
+        for format in ERROR_FORMATS {
+            if format.format_str == format_str {
+                self.config.error_fmt = format;
+                return Ok(());
+            }
+        }
+        bail!(
+            "Unsupported error format string. Available options: {}",
+            ERROR_FORMATS
+                .iter()
+                .map(|f| f.format_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    pub fn error_format(&self) -> &str {
+        self.config.error_fmt.format_str
+    }
+
+    pub fn error_format_trait(&self) -> &str {
+        self.config.error_fmt.format_trait
+    }
+
+    pub fn set_linker(&mut self, linker: String) {
+        self.config.linker = linker;
+    }
+
+    pub fn linker(&self) -> &str {
+        &self.config.linker
+    }
+
+    pub fn preserve_vars_on_panic(&self) -> bool {
+        self.config.preserve_vars_on_panic
+    }
+
+    pub fn offline_mode(&mut self) -> bool {
+        self.config.offline_mode
+    }
+
+    pub fn set_preserve_vars_on_panic(&mut self, value: bool) {
+        self.config.preserve_vars_on_panic = value;
+    }
+
+    pub fn recovery_mode(&self) -> bool {
+        self.config.recovery_mode
+    }
+
+    pub fn set_recovery_mode(&mut self, value: bool) {
+        if value && !self.config.recovery_mode {
+            self.variables_to_snapshot
+                .extend(self.variable_states.keys().cloned());
+        }
+        self.config.recovery_mode = value;
+    }
+
+    pub fn track_memory(&self) -> bool {
+        self.config.track_memory
+    }
+
+    pub fn set_track_memory(&mut self, value: bool) {
+        self.config.track_memory = value;
+    }
+
+    /// Returns whether code is checked with clippy: "off", "on" or "pedantic".
+    pub fn clippy(&self) -> &'static str {
+        self.config.clippy.name()
+    }
+
+    pub fn set_clippy(&mut self, mode: &str) -> Result<(), Error> {
+        self.config.clippy = ClippyMode::parse(mode)?;
+        Ok(())
+    }
+
+    pub fn autoformat(&self) -> bool {
+        self.config.autoformat
+    }
+
+    pub fn set_autoformat(&mut self, value: bool) {
+        self.config.autoformat = value;
+    }
+
+    pub fn migrate_types(&self) -> bool {
+        self.config.migrate_types
+    }
+
+    pub fn set_migrate_types(&mut self, value: bool) {
+        self.config.migrate_types = value;
+    }
+
+    pub fn auto_use(&self) -> bool {
+        self.config.auto_use
+    }
+
+    pub fn set_auto_use(&mut self, value: bool) {
+        self.config.auto_use = value;
+    }
+
+    pub fn auto_dep(&self) -> bool {
+        self.config.auto_dep
+    }
+
+    pub fn set_auto_dep(&mut self, value: bool) {
+        self.config.auto_dep = value;
+    }
+
+    pub fn dep_cache(&self) -> bool {
+        self.config.dep_cache
+    }
+
+    pub fn set_dep_cache(&mut self, value: bool) {
+        self.config.dep_cache = value;
+    }
+
+    /// Adds `use_stmt` to the items kept between evaluations, as if the user had written it.
+    /// Returns whether anything was added, which it isn't if the same import is already there.
+    fn add_import(&mut self, use_stmt: &str) -> bool {
+        use ra_ap_syntax::ast::HasModuleItem;
+        let mut added = false;
+        for item in ast::SourceFile::parse(use_stmt).tree().items() {
+            let use_tree = match item {
+                ast::Item::Use(use_item) => use_item.use_tree(),
+                _ => None,
+            };
+            if let Some(use_tree) = use_tree {
+                crate::use_trees::use_tree_names_do(&use_tree, &mut |import| {
+                    if let Import::Named { name, code } = import {
+                        let existing = self.items_by_name.get(&name).map(CodeBlock::code_string);
+                        if existing.as_deref().map(str::trim_end) != Some(code.as_str()) {
+                            self.items_by_name
+                                .insert(name, CodeBlock::new().other_user_code(code));
+                            added = true;
+                        }
+                    }
+                });
+            }
+        }
+        added
+    }
+
+    pub fn arena(&self) -> bool {
+        self.config.arena
+    }
+
+    pub fn set_arena(&mut self, value: bool) {
+        self.config.arena = value;
+    }
+
+    /// Returns those of the crates needed by `:migrate_types` that aren't dependencies.
+    pub(crate) fn missing_migration_crates(&self) -> Vec<&'static str> {
+        migration::REQUIRED_CRATES
+            .iter()
+            .filter(|name| !self.external_deps.contains_key(**name))
+            .copied()
+            .collect()
+    }
+
+    /// Returns what happens to downstream cells: "off", "invalidate" or "recompute".
+    pub fn reactive(&self) -> &'static str {
+        self.config.reactive.name()
+    }
+
+    pub fn set_reactive(&mut self, mode: &str) -> Result<(), Error> {
+        self.config.reactive = ReactiveMode::parse(mode)?;
+        Ok(())
+    }
+
+    pub fn debug_mode(&self) -> bool {
+        self.config.debug_mode
+    }
+
+    pub fn set_debug_mode(&mut self, debug_mode: bool) {
+        self.config.debug_mode = debug_mode;
+    }
+
+    pub fn opt_level(&self) -> &str {
+        &self.config.opt_level
+    }
+
+    pub fn set_opt_level(&mut self, level: &str) -> Result<(), Error> {
+        if level.is_empty() {
+            bail!("Optimization level cannot be an empty string");
+        }
+        self.config.opt_level = level.to_owned();
+        Ok(())
+    }
+    pub fn output_format(&self) -> &str {
+        &self.config.output_format
+    }
+
+    pub fn set_output_format(&mut self, output_format: String) {
+        self.config.output_format = output_format;
+    }
+
+    /// Updates how numbers in results are displayed. See `NumberFormat::with_settings` for the
+    /// format of `settings`.
+    pub fn set_number_format(&mut self, settings: &str) -> Result<(), Error> {
+        self.config.number_format = self.config.number_format.with_settings(settings)?;
+        Ok(())
+    }
+
+    pub fn number_format(&self) -> String {
+        self.config.number_format.to_string()
+    }
+
+    /// Sets how many rows of a DataFrame are shown per page. At most 10 pages are sent to the
+    /// frontend.
+    pub fn set_table_rows(&mut self, rows: usize) -> Result<(), Error> {
+        if rows == 0 {
+            bail!("The number of rows per page must be at least 1");
+        }
+        self.config.table_rows = rows;
+        Ok(())
+    }
+
+    pub fn table_rows(&self) -> usize {
+        self.config.table_rows
+    }
+
+    /// Sets the limit on stdout per evaluation. `limit` is a number of megabytes or "off".
+    pub fn set_output_limit(&mut self, limit: &str) -> Result<(), Error> {
+        match output_limit::parse_limit(limit) {
+            Some(limit) => self.config.output_limit = limit,
+            None => bail!(
+                "Invalid output limit `{}`. Expected megabytes or off",
+                limit
+            ),
+        }
+        Ok(())
+    }
+
+    pub fn output_limit(&self) -> String {
+        output_limit::format_limit(self.config.output_limit)
+    }
+
+    /// Sets how many lines of a result's text are shown before the rest is hidden. `max_lines` is
+    /// a number of lines or "off".
+    pub fn set_max_output_lines(&mut self, max_lines: &str) -> Result<(), Error> {
+        match output_limit::parse_max_lines(max_lines) {
+            Some(max_lines) => self.config.max_output_lines = max_lines,
+            None => bail!(
+                "Invalid number of lines `{}`. Expected a positive number or off",
+                max_lines
+            ),
+        }
+        Ok(())
+    }
+
+    pub fn max_output_lines(&self) -> String {
+        output_limit::format_max_lines(self.config.max_output_lines)
+    }
+
+    pub fn set_toolchain(&mut self, value: &str) {
+        self.config.toolchain = value.to_owned();
+    }
+
+    pub fn toolchain(&mut self) -> &str {
+        &self.config.toolchain
+    }
+
+    pub fn set_edition(&mut self, edition: &str) -> Result<(), Error> {
+        if !EDITIONS.contains(&edition) {
+            bail!(
+                "Unknown edition `{}`. Expected one of {}",
+                edition,
+                EDITIONS.join(", ")
+            );
+        }
+        self.config.edition = edition.to_owned();
+        Ok(())
+    }
+
+    pub fn edition(&self) -> &str {
+        &self.config.edition
+    }
+
+    /// Adds a crate attribute, e.g. `allow(dead_code)` or `#![feature(never_type)]`, which then
+    /// applies to all code. Errors in it are reported against the arguments of `command`, which
+    /// should be the command that added it. Returns the attribute as it appears in the code.
+    pub(crate) fn add_crate_attribute(
+        &mut self,
+        attribute: &str,
+        command: CommandCall,
+    ) -> Result<String, Error> {
+        let attribute = crate_attribute_code(attribute)?;
+        self.attributes.insert(
+            attribute.clone(),
+            CodeBlock::new().with(CodeKind::Command(command), attribute.clone()),
+        );
+        Ok(attribute)
+    }
+
+    /// Removes a crate attribute, whether it was added by a command or by code.
+    pub(crate) fn remove_crate_attribute(&mut self, attribute: &str) -> Result<(), Error> {
+        let attribute = crate_attribute_code(attribute)?;
+        if self.attributes.remove(&attribute).is_none() {
+            bail!("No crate attribute {}", attribute);
+        }
+        Ok(())
+    }
+
+    /// Returns the crate attributes, e.g. `#![feature(never_type)]`, sorted.
+    pub(crate) fn crate_attributes(&self) -> Vec<&str> {
+        let mut attributes: Vec<&str> = self.attributes.keys().map(String::as_str).collect();
+        attributes.sort_unstable();
+        attributes
+    }
+
+    /// Links code against `lib`, replacing any library with the same name.
+    pub(crate) fn add_native_lib(&mut self, lib: NativeLib) {
+        let libs = &mut self.config.native_libs;
+        match libs.iter_mut().find(|existing| existing.name == lib.name) {
+            Some(existing) => *existing = lib,
+            None => libs.push(lib),
         }
-        bail!(
-            "Unsupported error format string. Available options: {}",
-            ERROR_FORMATS
-                .iter()
-                .map(|f| f.format_str)
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
     }
 
-    pub fn error_format(&self) -> &str {
-        self.config.error_fmt.format_str
+    pub(crate) fn native_libs(&self) -> &[NativeLib] {
+        &self.config.native_libs
     }
 
-    pub fn error_format_trait(&self) -> &str {
-        self.config.error_fmt.format_trait
+    /// Sets the compiler flags for every crate in the build, replacing any set before.
+    pub(crate) fn set_rustflags(&mut self, flags: &str) {
+        self.config.rustflags = flags.split_whitespace().map(str::to_owned).collect();
     }
 
-    pub fn set_linker(&mut self, linker: String) {
-        self.config.linker = linker;
+    pub(crate) fn rustflags(&self) -> &[String] {
+        &self.config.rustflags
     }
 
-    pub fn linker(&self) -> &str {
-        &self.config.linker
+    /// Sets, or if `value` is None removes, an environment variable for builds.
+    pub(crate) fn set_build_var(&mut self, key: &str, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                self.config
+                    .build_env
+                    .insert(key.to_owned(), value.to_owned());
+            }
+            None => {
+                self.config.build_env.remove(key);
+            }
+        }
     }
 
-    pub fn preserve_vars_on_panic(&self) -> bool {
-        self.config.preserve_vars_on_panic
+    pub(crate) fn build_env(&self) -> &BTreeMap<String, String> {
+        &self.config.build_env
     }
 
-    pub fn offline_mode(&mut self) -> bool {
-        self.config.offline_mode
+    pub(crate) fn set_sql_connection(&mut self, url: &str) {
+        self.config.sql_connection = Some(url.to_owned());
     }
 
-    pub fn set_preserve_vars_on_panic(&mut self, value: bool) {
-        self.config.preserve_vars_on_panic = value;
+    pub(crate) fn sql_connection(&self) -> Option<&str> {
+        self.config.sql_connection.as_deref()
     }
 
-    pub fn debug_mode(&self) -> bool {
-        self.config.debug_mode
+    /// Records that Cargo.lock was imported from `path`, or with None, that dependency versions
+    /// are no longer pinned. The file is only read when it's imported, never written.
+    pub fn set_lockfile(&mut self, path: Option<PathBuf>) {
+        self.config.lockfile = path;
     }
 
-    pub fn set_debug_mode(&mut self, debug_mode: bool) {
-        self.config.debug_mode = debug_mode;
+    pub fn lockfile(&self) -> Option<&Path> {
+        self.config.lockfile.as_deref()
     }
 
-    pub fn opt_level(&self) -> &str {
-        &self.config.opt_level
+    /// Sets the file that Cargo.lock is written back to whenever a build changes it, or with None,
+    /// stops writing it. Doesn't read the file. See `load_persistent_lockfile`.
+    pub fn set_persistent_lockfile(&mut self, path: Option<PathBuf>) {
+        self.config.persistent_lockfile = path;
     }
 
-    pub fn set_opt_level(&mut self, level: &str) -> Result<(), Error> {
-        if level.is_empty() {
-            bail!("Optimization level cannot be an empty string");
+    pub fn persistent_lockfile(&self) -> Option<&Path> {
+        self.config.persistent_lockfile.as_deref()
+    }
+
+    /// Sets compiler flags to use when building the dependency `dep`, e.g. `--cfg tokio_unstable`.
+    /// Empty flags remove any previously set.
+    pub fn set_dep_flags(&mut self, dep: &str, flags: &str) -> Result<(), Error> {
+        let flags = DepFlags::parse(flags)?;
+        if flags.is_empty() {
+            self.config.dep_flags.remove(dep);
+        } else {
+            self.config.dep_flags.insert(dep.to_owned(), flags);
         }
-        self.config.opt_level = level.to_owned();
         Ok(())
     }
-    pub fn output_format(&self) -> &str {
-        &self.config.output_format
+
+    /// Returns the dependencies that have flags set and their flags, as the user wrote them.
+    pub fn dep_flags(&self) -> impl Iterator<Item = (&str, String)> {
+        self.config
+            .dep_flags
+            .iter()
+            .map(|(dep, flags)| (dep.as_str(), flags.to_string()))
     }
 
-    pub fn set_output_format(&mut self, output_format: String) {
-        self.config.output_format = output_format;
+    /// Sets the target to build for. `None`, or the host's own target, means that code is loaded
+    /// into our subprocess as usual. For any other target, each evaluation is built as an
+    /// executable and run with the configured runner. Variables don't persist between evaluations
+    /// in that case, since each runs in a separate process.
+    pub fn set_target(&mut self, target: Option<String>) {
+        self.config.target = target;
     }
 
-    pub fn set_toolchain(&mut self, value: &str) {
-        self.config.toolchain = value.to_owned();
+    pub fn target(&self) -> Option<&str> {
+        self.config.target.as_deref()
     }
 
-    pub fn toolchain(&mut self) -> &str {
-        &self.config.toolchain
+    pub fn set_runner(&mut self, runner: Option<String>) {
+        self.config.runner = runner;
+    }
+
+    pub fn runner(&self) -> Option<&str> {
+        self.config.runner.as_deref()
     }
 
     /// Adds a crate dependency with the specified name and configuration.
@@ -1188,6 +3260,15 @@ impl ContextState {
         Ok(())
     }
 
+    /// Validates a previously added dependency again, even though its configuration is unchanged.
+    /// Used when something the configuration refers to, such as a local crate, has changed.
+    pub(crate) fn revalidate_dep(&self, dep: &str) -> Result<(), Error> {
+        if let Some(external) = self.external_deps.get(dep) {
+            crate::cargo_metadata::validate_dep(&external.name, &external.config, &self.config)?;
+        }
+        Ok(())
+    }
+
     /// Clears fields that aren't useful for inclusion in bug reports and which might give away
     /// things like usernames.
     pub(crate) fn clear_non_debug_relevant_fields(&mut self) {
@@ -1195,6 +3276,12 @@ impl ContextState {
         if self.config.sccache.is_some() {
             self.config.sccache = Some(PathBuf::from("redacted"));
         }
+        if self.config.lockfile.is_some() {
+            self.config.lockfile = Some(PathBuf::from("redacted"));
+        }
+        if self.config.persistent_lockfile.is_some() {
+            self.config.persistent_lockfile = Some(PathBuf::from("redacted"));
+        }
     }
 
     fn apply_custom_errors(
@@ -1208,6 +3295,7 @@ impl ContextState {
             .filter_map(|error| self.customize_error(error, user_code))
             .map(|mut error| {
                 error.fill_lines(code_info);
+                error.set_hint(&self.config.hints);
                 error
             })
             .collect()
@@ -1284,6 +3372,36 @@ impl ContextState {
             || (self.items_by_name != new_state.items_by_name
                 && !new_state.items_by_name.is_empty())
             || (self.config.sccache != new_state.config.sccache)
+            || (self.config.lockfile != new_state.config.lockfile)
+            || (self.config.persistent_lockfile != new_state.config.persistent_lockfile)
+            || (self.config.dep_flags != new_state.config.dep_flags)
+            || (self.config.native_libs != new_state.config.native_libs)
+            || (self.config.rustflags != new_state.config.rustflags)
+            || (self.config.build_env != new_state.config.build_env)
+            || (self.config.target != new_state.config.target)
+            || (self.attributes != new_state.attributes)
+    }
+
+    /// Returns Cargo.toml profile overrides for dependencies that have flags set.
+    pub(crate) fn dep_profile_overrides(&self) -> String {
+        crate::dep_flags::profile_overrides(&self.config.dep_flags, |dep| {
+            self.external_deps.contains_key(dep)
+        })
+    }
+
+    /// Returns cargo config that passes rustflags set with `:rustflags`, for dependencies that
+    /// have flags set and for linking native libraries, and that sets the build environment, if
+    /// there's any of these.
+    pub(crate) fn cargo_config(&self) -> Option<String> {
+        let mut rustflags = self.config.rustflags.clone();
+        rustflags.extend(crate::dep_flags::rustflags(&self.config.dep_flags, |dep| {
+            self.external_deps.contains_key(dep)
+        }));
+        rustflags.extend(native_libs::rustflags(
+            &self.config.native_libs,
+            cfg!(unix) && self.config.target.is_none(),
+        ));
+        crate::dep_flags::cargo_config(&rustflags, &self.config.build_env)
     }
 
     pub(crate) fn format_cargo_deps(&self) -> String {
@@ -1294,6 +3412,13 @@ impl ContextState {
             .join("")
     }
 
+    /// Returns whether the code that we run can stop between statements when asked to, rather than
+    /// the subprocess needing to be killed. Unwinding part way through async code would poison the
+    /// lock on the tokio runtime.
+    fn is_interruptible(&self) -> bool {
+        self.compilation_mode() == CompilationMode::RunAndCatchPanics && !self.async_mode
+    }
+
     fn compilation_mode(&self) -> CompilationMode {
         if self.config.preserve_vars_on_panic {
             CompilationMode::RunAndCatchPanics
@@ -1303,15 +3428,34 @@ impl ContextState {
     }
 
     /// Returns code suitable for analysis purposes. Doesn't attempt to preserve runtime behavior.
+    /// Returns the attribute that exports a function under its own name. Edition 2024 requires it
+    /// to be marked unsafe.
+    fn no_mangle_attribute(&self) -> &'static str {
+        if self.config.edition == "2024" {
+            "#[unsafe(no_mangle)]"
+        } else {
+            "#[no_mangle]"
+        }
+    }
+
     fn analysis_code(&self, user_code: CodeBlock) -> CodeBlock {
         let mut code = CodeBlock::new()
             .generated("#![allow(unused_imports, unused_mut, dead_code)]")
             .add_all(self.attributes_code())
             .add_all(self.items_code())
             .add_all(self.error_trait_code(true))
+            .add_all(self.formatters_code())
             .generated("fn evcxr_variable_store<T: 'static>(_: T) {}")
+            .generated("fn evcxr_check_interrupt() {}");
+        if self.config.arena || self.formatters {
+            code = code.add_all(runtime_code(true));
+        }
+        code = code
             .generated("#[allow(unused_variables)]")
             .generated("async fn evcxr_analysis_wrapper(");
+        if self.config.arena && !self.stored_variable_states.contains_key("arena") {
+            code = code.generated("arena: &'static evcxr_internal_runtime::Arena,");
+        }
         for (var_name, state) in &self.stored_variable_states {
             code = code.generated(format!(
                 "{}{}: {},",
@@ -1338,6 +3482,19 @@ impl ContextState {
         code
     }
 
+    /// Returns code that contains the arguments of `command` in a function, so that we can find
+    /// what they expand to.
+    fn expansion_code(&self, command: CommandCall) -> CodeBlock {
+        let args = command.args.clone().unwrap_or_default();
+        CodeBlock::new()
+            .generated("#![allow(unused_imports, unused_mut, dead_code)]")
+            .add_all(self.attributes_code())
+            .add_all(self.items_code())
+            .generated(format!("fn {}() {{", EXPANSION_FN_NAME))
+            .with(CodeKind::Command(command), args)
+            .generated("}")
+    }
+
     fn code_to_compile(
         &self,
         user_code: CodeBlock,
@@ -1345,15 +3502,21 @@ impl ContextState {
     ) -> CodeBlock {
         let mut code = CodeBlock::new()
             .generated("#![allow(unused_imports, unused_mut, dead_code)]")
-            .add_all(self.attributes_code())
-            .add_all(self.items_code());
+            .add_all(self.attributes_code());
+        if !self.config.native_libs.is_empty() {
+            code = code.generated(native_libs::link_attributes(
+                &self.config.native_libs,
+                &self.config.edition,
+            ));
+        }
+        code = code.add_all(self.items_code());
         let has_user_code = !user_code.is_empty();
         if has_user_code {
             code = code.add_all(self.wrap_user_code(user_code, compilation_mode));
         } else {
             // TODO: Add a mechanism to load a crate without any function to call then remove this.
             code = code
-                .generated("#[no_mangle]")
+                .generated(self.no_mangle_attribute())
                 .generated(format!(
                     "pub extern \"C\" fn {}(",
                     self.current_user_fn_name()
@@ -1366,11 +3529,27 @@ impl ContextState {
     fn items_code(&self) -> CodeBlock {
         let mut code = CodeBlock::new().add_all(self.get_imports());
         for item in self.items_by_name.values().chain(self.unnamed_items.iter()) {
-            code = code.add_all(item.clone());
+            if self.config.test_mode {
+                code = code.other_user_code(test_runner::make_tests_callable(&item.code_string()));
+            } else {
+                code = code.add_all(item.clone());
+            }
         }
         code
     }
 
+    /// Returns the `#[test]` functions that have been defined, sorted by name.
+    pub(crate) fn tests(&self) -> Vec<TestFn> {
+        let mut tests: Vec<TestFn> = self
+            .items_by_name
+            .values()
+            .chain(self.unnamed_items.iter())
+            .flat_map(|item| test_runner::find_tests(&item.code_string()))
+            .collect();
+        tests.sort_by(|a, b| a.name.cmp(&b.name));
+        tests
+    }
+
     fn attributes_code(&self) -> CodeBlock {
         let mut code = CodeBlock::new();
         for attrib in self.attributes.values() {
@@ -1385,7 +3564,8 @@ impl ContextState {
             struct EvcxrUserCodeError {{}}
             impl<T: {}> From<T> for EvcxrUserCodeError {{
                 fn from(error: T) -> Self {{
-                    eprintln!("{}", error);
+                    let message = format!("{}", error);
+                    eprintln!("Error: {{}}", message);
                     {}
                     EvcxrUserCodeError {{}}
                 }}
@@ -1394,13 +3574,66 @@ impl ContextState {
             self.config.error_fmt.format_trait,
             self.config.error_fmt.format_str,
             if for_analysis {
-                ""
+                "let _ = message;"
             } else {
-                "println!(\"{}\", evcxr_internal_runtime::USER_ERROR_OCCURRED);"
+                "evcxr_internal_runtime::set_last_error(message);
+                 println!(\"{}\", evcxr_internal_runtime::USER_ERROR_OCCURRED);"
             }
         ))
     }
 
+    /// Returns the `evcxr` module, through which code registers formatters.
+    fn formatters_code(&self) -> CodeBlock {
+        if !self.formatters {
+            return CodeBlock::new();
+        }
+        CodeBlock::new().generated(
+            r#"
+            mod evcxr {
+                #[allow(dead_code)]
+                pub fn register_formatter<T: 'static>(formatter: impl Fn(&T) -> String + 'static) {
+                    crate::evcxr_internal_runtime::register_formatter(formatter);
+                }
+            }
+        "#,
+        )
+    }
+
+    /// Returns code that displays `value`, which should evaluate to a reference, with the formatter
+    /// registered for its type if there is one, otherwise with `print_any`.
+    fn display_value_code(&self, value: &str) -> CodeBlock {
+        self.display_code(|display| {
+            format!(
+                "{{
+    let evcxr_value = {value};
+    {display}
+}}",
+                value = value,
+                display = display
+            )
+        })
+    }
+
+    /// Returns the code produced by `wrap`, given code that displays a reference named
+    /// `evcxr_value`. Formatters only support values of `'static` types, so if there are any, this
+    /// falls back to code that just reports that they can't be used for the value.
+    fn display_code(&self, wrap: impl Fn(&str) -> String) -> CodeBlock {
+        if !self.formatters {
+            return CodeBlock::new().other_user_code(wrap("::print_any::print_any(evcxr_value);"));
+        }
+        CodeBlock::new().code_with_fallback(
+            wrap(
+                "if !evcxr_internal_runtime::display_with_formatter(evcxr_value) {
+        ::print_any::print_any(evcxr_value);
+    }",
+            ),
+            CodeBlock::new().other_user_code(wrap(
+                "evcxr_internal_runtime::formatters_unsupported();
+    ::print_any::print_any(evcxr_value);",
+            )),
+        )
+    }
+
     fn wrap_user_code(
         &self,
         mut user_code: CodeBlock,
@@ -1409,21 +3642,48 @@ impl ContextState {
         let needs_variable_store = !self.variable_states.is_empty()
             || !self.stored_variable_states.is_empty()
             || self.async_mode
-            || self.allow_question_mark;
+            || self.allow_question_mark
+            || self.background_jobs
+            || self.formatters
+            || self.config.arena;
         let mut code = CodeBlock::new();
         if self.allow_question_mark {
             code = code.add_all(self.error_trait_code(false));
         }
-        if needs_variable_store {
+        code = code.add_all(self.formatters_code());
+        if !self.migrating_in.is_empty() || !self.migrating_out.is_empty() {
+            code = code.generated(migration::HELPER_CODE);
+        }
+        let needs_full_runtime = needs_variable_store
+            || self.config.track_memory
+            || self.config.test_mode
+            || dataframes::TABLE_CRATES
+                .iter()
+                .any(|name| self.external_deps.contains_key(*name));
+        code = code.add_all(runtime_code(needs_full_runtime));
+        if compilation_mode == CompilationMode::RunAndCatchPanics && self.is_interruptible() {
             code = code
-                .generated("mod evcxr_internal_runtime {")
-                .generated(include_str!("evcxr_internal_runtime.rs"))
+                .generated("fn evcxr_check_interrupt() {")
+                .generated("  evcxr_internal_runtime::check_interrupt();")
                 .generated("}");
+            user_code = CodeBlock::new()
+                .generated("evcxr_internal_runtime::watch_for_interrupts();")
+                .add_all(user_code);
+        } else {
+            code = code.generated("fn evcxr_check_interrupt() {}");
         }
-        code = code.generated("#[no_mangle]").generated(format!(
-            "pub extern \"C\" fn {}(",
-            self.current_user_fn_name()
-        ));
+        if self.config.track_memory {
+            code = code
+                .generated("#[global_allocator]")
+                .generated("static EVCXR_ALLOCATOR: evcxr_internal_runtime::TrackingAllocator =")
+                .generated("  evcxr_internal_runtime::TrackingAllocator;");
+        }
+        code = code
+            .generated(self.no_mangle_attribute())
+            .generated(format!(
+                "pub extern \"C\" fn {}(",
+                self.current_user_fn_name()
+            ));
         if needs_variable_store {
             code = code
                 .generated("mut evcxr_variable_store: *mut evcxr_internal_runtime::VariableStore)")
@@ -1434,14 +3694,45 @@ impl ContextState {
                 )
                 .generated("}")
                 .generated("let evcxr_variable_store = unsafe {&mut *evcxr_variable_store};")
+                .add_all(
+                    self.migration_statements(&self.migrating_in, migration::deserialize_statement),
+                )
                 .add_all(self.check_variable_statements())
-                .add_all(self.load_variable_statements());
+                .add_all(
+                    self.migration_statements(&self.migrating_out, migration::serialize_statement),
+                );
+            if self.config.arena {
+                // Bound before variables are loaded, so that a variable named `arena` takes
+                // precedence.
+                code = code
+                    .generated("#[allow(unused_variables)]")
+                    .generated("let arena = evcxr_variable_store.arena();");
+            }
+            code = code.add_all(self.load_variable_statements());
+            if self.background_jobs {
+                // Bound outside of catch_unwind, since the variable store is shadowed inside it.
+                code = code.generated("#[allow(unused_variables)]").generated(
+                    "let evcxr_jobs = evcxr_variable_store.lazy_arc(\"evcxr_jobs\", \
+                     evcxr_internal_runtime::JobRegistry::default);",
+                );
+            }
+            if self.formatters {
+                code = code.generated(
+                    "evcxr_internal_runtime::set_formatters(evcxr_variable_store.lazy_arc(\
+                     \"evcxr_formatters\", evcxr_internal_runtime::FormatterRegistry::default));",
+                );
+            }
             user_code = user_code
                 .add_all(self.store_variable_statements(&VariableMoveState::MovedIntoCatchUnwind))
                 .add_all(self.store_variable_statements(&VariableMoveState::CopiedIntoCatchUnwind));
         } else {
             code = code.generated("evcxr_variable_store: *mut u8) -> *mut u8 {");
         }
+        if self.config.track_memory {
+            code = code.generated(
+                "let evcxr_memory_tracker = evcxr_internal_runtime::MemoryTracker::start();",
+            );
+        }
         if self.async_mode {
             user_code = CodeBlock::new()
                 .generated(stringify!(evcxr_variable_store
@@ -1500,6 +3791,20 @@ impl ContextState {
         }
         if needs_variable_store {
             code = code.add_all(self.store_variable_statements(&VariableMoveState::Available));
+            if self.allow_question_mark {
+                // Replaces any earlier error, which we'll have just stored if `_err` was defined.
+                code = code.generated(format!(
+                    "if let Some(evcxr_error) = evcxr_internal_runtime::take_last_error() {{ \
+                     evcxr_variable_store.put_variable::<String>(\"{}\", evcxr_error); }}",
+                    LAST_ERROR_VARIABLE
+                ));
+            }
+            if self.config.recovery_mode {
+                code = code.add_all(self.snapshot_variable_statements());
+            }
+        }
+        if self.config.track_memory {
+            code = code.generated("evcxr_memory_tracker.report();");
         }
         code = code.generated("evcxr_variable_store");
         code.generated("}")
@@ -1529,17 +3834,63 @@ impl ContextState {
         statements
     }
 
+    fn snapshot_variable_statements(&self) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        let snapshot_dir = recovery::snapshot_dir(&self.config.crate_dir);
+        for (var_name, var_state) in &self.variable_states {
+            if self.variables_to_snapshot.contains(var_name)
+                && recovery::can_snapshot(&var_state.type_name)
+            {
+                statements = statements.generated(format!(
+                    "evcxr_variable_store.snapshot_variable::<{}>(stringify!({}), {:?}, {});",
+                    var_state.type_name,
+                    var_name,
+                    snapshot_dir.to_string_lossy(),
+                    recovery::MAX_SNAPSHOT_BYTES
+                ));
+            }
+        }
+        statements
+    }
+
     fn check_variable_statements(&self) -> CodeBlock {
+        let unchanged = self.types_with_layout(true);
+        let changed = self.types_with_layout(false);
         let mut statements = CodeBlock::new().generated("{let mut vars_ok = true;");
         for (var_name, var_state) in &self.stored_variable_states {
+            // Values of a type that was redefined with the same layout can be moved into the new
+            // type as they are.
+            let check = if type_references_any(&var_state.type_name, &unchanged)
+                && !type_references_any(&var_state.type_name, &changed)
+            {
+                "check_variable_same_layout"
+            } else {
+                "check_variable"
+            };
             statements = statements.generated(format!(
-                "vars_ok &= evcxr_variable_store.check_variable::<{}>(stringify!({}));",
-                var_state.type_name, var_name
+                "vars_ok &= evcxr_variable_store.{}::<{}>(stringify!({}));",
+                check, var_state.type_name, var_name
             ));
         }
         statements.generated("if !vars_ok {return evcxr_variable_store;}}")
     }
 
+    /// Returns code that serializes or deserializes, depending on `statement`, those of `variables`
+    /// that are stored.
+    fn migration_statements(
+        &self,
+        variables: &[String],
+        statement: fn(&str, &str) -> String,
+    ) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        for name in variables {
+            if let Some(var_state) = self.stored_variable_states.get(name) {
+                statements = statements.generated(statement(name, &var_state.type_name));
+            }
+        }
+        statements
+    }
+
     // Returns code to load values from the variable store back into their variables.
     fn load_variable_statements(&self) -> CodeBlock {
         let mut statements = CodeBlock::new();
@@ -1571,10 +3922,13 @@ impl ContextState {
     /// code at a particular offset.
     fn commit_old_user_code(&mut self) {
         for block in self.items_by_name.values_mut() {
-            block.commit_old_user_code();
+            block.commit_old_user_code(self.cell_number);
         }
         for block in self.unnamed_items.iter_mut() {
-            block.commit_old_user_code();
+            block.commit_old_user_code(self.cell_number);
+        }
+        for block in self.attributes.values_mut() {
+            block.commit_old_user_code(self.cell_number);
         }
     }
 
@@ -1599,7 +3953,32 @@ impl ContextState {
             }
         }
 
+        if self.config.recovery_mode {
+            self.variables_to_snapshot.extend(mentioned_names(nodes));
+        }
         let mut code_out = CodeBlock::new();
+        self.cell_statements.clear();
+        self.redefined_types.clear();
+        let mut layout_dependencies = HashMap::new();
+        if let Some(latest_result) = self.results.latest() {
+            if self.stored_variable_states.contains_key(&latest_result)
+                && refers_to_latest_result(nodes)
+            {
+                let latest_result_code = format!(
+                    "let {} = &{};",
+                    result_history::LATEST_RESULT,
+                    latest_result
+                );
+                self.cell_statements.push(latest_result_code.clone());
+                code_out = code_out.generated(latest_result_code);
+            }
+        }
+        if user_code.segments.iter().any(|segment| {
+            matches!(segment.kind, CodeKind::OriginalUserCode(_))
+                && segment.code.contains("register_formatter")
+        }) {
+            self.formatters = true;
+        }
         let mut previous_item_name = None;
         let num_statements = user_code.segments.len();
         for (statement_index, segment) in user_code.segments.into_iter().enumerate() {
@@ -1609,10 +3988,16 @@ impl ContextState {
                 code_out = code_out.with_segment(segment);
                 continue;
             };
+            if !ast::Attr::can_cast(node.kind()) && !ast::Item::can_cast(node.kind()) {
+                // Where a request to interrupt can take effect.
+                code_out = code_out.generated("evcxr_check_interrupt();");
+            }
+
             if let Some(let_stmt) = ast::LetStmt::cast(node.clone()) {
                 if let Some(pat) = let_stmt.pat() {
                     self.record_new_locals(pat, let_stmt.ty(), &segment, node.text_range());
-                    code_out = code_out.with_segment(segment);
+                    self.record_statement(&segment.code);
+                    code_out = code_out.add_all(interruptible(segment, node));
                 }
             } else if ast::Attr::can_cast(node.kind()) {
                 self.attributes.insert(
@@ -1621,18 +4006,66 @@ impl ContextState {
                 );
             } else if ast::Expr::can_cast(node.kind()) {
                 if statement_index == num_statements - 1 {
-                    if self.config.display_final_expression {
-                        code_out = code_out.other_user_code(
-                            CodeBlock::new()
-                                .generated("::print_any::print_any(&(\n")
-                                .with_segment(segment)
-                                .generated("));")
+                    let has_table_crate = |name: &str| self.external_deps.contains_key(name);
+                    if self.config.display_final_expression
+                        && dataframes::TABLE_CRATES
+                            .iter()
+                            .any(|name| has_table_crate(name))
+                    {
+                        let value_code = CodeBlock::new()
+                            .generated("&(\n")
+                            .add_all(interruptible(segment, node))
+                            .generated(")")
+                            .code_string();
+                        let display = self.display_code(|display| {
+                            dataframes::display_code(
+                                &value_code,
+                                self.config.table_rows,
+                                has_table_crate,
+                                display,
+                            )
+                        });
+                        self.record_statement(&display.code_string());
+                        code_out = code_out.add_all(display);
+                    } else if self.config.display_final_expression {
+                        let display = self.display_value_code(
+                            &CodeBlock::new()
+                                .generated("&(\n")
+                                .add_all(interruptible(segment.clone(), node))
+                                .generated(")")
                                 .code_string(),
                         );
+                        if is_place_expression(node) {
+                            // Keeping the value would move it out of the place, which is probably a
+                            // variable that the user already has.
+                            self.record_statement(&display.code_string());
+                            code_out = code_out.add_all(display);
+                        } else {
+                            let (result_name, oldest_result) = self.results.add(&segment.code);
+                            if let Some(oldest_result) = oldest_result {
+                                self.variable_states.remove(&oldest_result);
+                            }
+                            let result_code = CodeBlock::new()
+                                .generated(format!("let {} = (\n", result_name))
+                                .add_all(interruptible(segment, node))
+                                .generated(");")
+                                .generated(
+                                    self.display_value_code(&format!("&{}", result_name))
+                                        .code_string(),
+                                )
+                                .code_string();
+                            // Outside of evcxr, the value can always be kept, since there's no
+                            // need to store it between cells.
+                            self.record_statement(&result_code);
+                            // If the value can't be kept, e.g. because it borrows from a variable,
+                            // we fall back to just displaying it.
+                            code_out = code_out.code_with_fallback(result_code, display);
+                        }
                     } else {
+                        self.record_statement(&format!("let _ = {};", segment.code.trim_end()));
                         code_out = code_out
                             .generated("let _ = ")
-                            .with_segment(segment)
+                            .add_all(interruptible(segment, node))
                             .generated(";");
                     }
                 } else {
@@ -1640,7 +4073,8 @@ impl ContextState {
                     // so don't try to print it. Yes, this is possible. For
                     // example `for x in y {}` is an expression. See the test
                     // non_semi_statements.
-                    code_out = code_out.with_segment(segment);
+                    self.record_statement(&segment.code);
+                    code_out = code_out.add_all(interruptible(segment, node));
                 }
             } else if let Some(item) = ast::Item::cast(node.clone()) {
                 match item {
@@ -1715,6 +4149,18 @@ impl ContextState {
                     item => {
                         let item_block = CodeBlock::new().with_segment(segment);
                         if let Some(item_name) = item::item_name(&item) {
+                            let old_signature =
+                                self.items_by_name.get(&item_name).and_then(|old_block| {
+                                    item::layout_signature_in(&old_block.code_string(), &item_name)
+                                });
+                            if let (Some(old_signature), Some(signature)) =
+                                (old_signature, item::layout_signature(&item))
+                            {
+                                self.redefined_types
+                                    .insert(item_name.clone(), old_signature == signature);
+                                layout_dependencies
+                                    .insert(item_name.clone(), item::layout_dependencies(&item));
+                            }
                             *self.items_by_name.entry(item_name.to_owned()).or_default() =
                                 item_block;
                             previous_item_name = Some(item_name);
@@ -1733,17 +4179,193 @@ impl ContextState {
                     }
                 }
             } else {
-                code_out = code_out.with_segment(segment);
+                self.record_statement(&segment.code);
+                code_out = code_out.add_all(interruptible(segment, node));
             }
         }
+        self.propagate_layout_changes(&layout_dependencies);
         Ok(code_out)
     }
 
+    /// A type whose definition is unchanged can still have a different layout if one of the types
+    /// that it contains did, so marks such types as changed too. Only primitives and the session's
+    /// own structs, enums and unions are known not to have changed. Anything else, e.g. a type
+    /// alias or a type from a dependency, might have.
+    fn propagate_layout_changes(
+        &mut self,
+        layout_dependencies: &HashMap<String, Option<Vec<String>>>,
+    ) {
+        loop {
+            let newly_changed: Vec<String> = self
+                .redefined_types
+                .iter()
+                .filter(|(name, unchanged)| {
+                    **unchanged
+                        && !self.layouts_unchanged(
+                            layout_dependencies[*name].as_deref(),
+                            &mut HashSet::new(),
+                        )
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            if newly_changed.is_empty() {
+                break;
+            }
+            for name in newly_changed {
+                self.redefined_types.insert(name, false);
+            }
+        }
+    }
+
+    /// Returns whether the types named by `dependencies`, the layout dependencies of a type, are
+    /// all known to have the same layout as before. `visited` is the session types already checked.
+    fn layouts_unchanged(
+        &self,
+        dependencies: Option<&[String]>,
+        visited: &mut HashSet<String>,
+    ) -> bool {
+        let dependencies = match dependencies {
+            Some(dependencies) => dependencies,
+            None => return false,
+        };
+        dependencies.iter().all(|name| {
+            if let Some(unchanged) = self.redefined_types.get(name) {
+                // Its own dependencies are checked by `propagate_layout_changes`.
+                return *unchanged;
+            }
+            match self.items_by_name.get(name) {
+                Some(block) => {
+                    !visited.insert(name.clone())
+                        || self.layouts_unchanged(
+                            item::layout_dependencies_in(&block.code_string(), name).as_deref(),
+                            visited,
+                        )
+                }
+                None => item::is_primitive_type(name),
+            }
+        })
+    }
+
+    /// Returns the variables that will be lost because the layout of their type changed, sorted.
+    fn variables_losing_type(&self) -> Vec<String> {
+        let changed = self.types_with_layout(false);
+        let mut variables: Vec<String> = self
+            .stored_variable_states
+            .iter()
+            .filter(|(_, variable_state)| type_references_any(&variable_state.type_name, &changed))
+            .map(|(name, _)| name.clone())
+            .collect();
+        variables.sort();
+        variables
+    }
+
+    /// Returns the names of the redefined types whose layout is unchanged, or those for which it
+    /// changed.
+    fn types_with_layout(&self, unchanged: bool) -> HashSet<String> {
+        self.redefined_types
+            .iter()
+            .filter(|(_, is_unchanged)| **is_unchanged == unchanged)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn record_statement(&mut self, code: &str) {
+        self.cell_statements.push(code.trim_end().to_owned());
+    }
+
+    /// Returns the code of the cells that depend on `variable`, in the order that they ran.
+    pub(crate) fn cell_dependents(&self, variable: &str) -> Vec<String> {
+        self.cell_history
+            .dependents(variable)
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Writes out a Cargo project containing the current items and dependencies, with a `main`
+    /// that runs the statements of the cells that have executed successfully.
+    pub(crate) fn export_project(&self, dir: &Path) -> Result<(), Error> {
+        let mut dependencies: Vec<String> = self
+            .external_deps
+            .values()
+            .map(|krate| format!("{} = {}", krate.name, krate.config))
+            .collect();
+        dependencies.sort();
+        let mut attributes: Vec<String> = self
+            .attributes
+            .values()
+            .map(|block| block.code_string().trim_end().to_owned())
+            .collect();
+        attributes.sort();
+        let mut extern_crate_stmts: Vec<&String> = self.extern_crate_stmts.values().collect();
+        extern_crate_stmts.sort();
+        let mut named_items: Vec<(&String, String)> = self
+            .items_by_name
+            .iter()
+            .map(|(name, block)| (name, block.code_string().trim_end().to_owned()))
+            .collect();
+        // Macros need to be defined before they're used, whereas other items can be in any order.
+        named_items.sort_by_key(|(name, code)| (!code.contains("macro_rules!"), *name));
+        let items = extern_crate_stmts
+            .into_iter()
+            .map(|stmt| stmt.trim_end().to_owned())
+            .chain(
+                self.unnamed_items
+                    .iter()
+                    .map(|block| block.code_string().trim_end().to_owned()),
+            )
+            .chain(named_items.into_iter().map(|(_, code)| code))
+            .collect();
+        let project = ExportedProject {
+            dependencies,
+            attributes,
+            items,
+            statements: self.exported_statements.clone(),
+            is_async: self.async_mode,
+            uses_question_mark: self.allow_question_mark,
+            edition: self.config.edition.clone(),
+        };
+        let mut main_rs = project.main_rs();
+        if self.config.autoformat {
+            main_rs = rustfmt::format_file(&self.config, &main_rs).map_err(|error| {
+                Error::from(format!(
+                    "{}\nUse `:autoformat off` to export the code without formatting it",
+                    error
+                ))
+            })?;
+        }
+        project.write(
+            dir,
+            &main_rs,
+            Some(&self.config.crate_dir.join("Cargo.lock")),
+        )
+    }
+
     fn dependency_lib_names(&self) -> Result<Vec<String>> {
         use crate::cargo_metadata;
         cargo_metadata::get_library_names(&self.config)
     }
 
+    /// Returns the dependency tree of the session crate, for `:deps tree`. The session's
+    /// Cargo.toml must have been written. See `cargo_metadata::dependency_tree`.
+    pub(crate) fn dependency_tree(&self, package: Option<&str>) -> Result<String> {
+        crate::cargo_metadata::dependency_tree(&self.config, package)
+    }
+
+    /// Returns the features of `package` in the session crate's dependency graph, for
+    /// `:deps features`. The session's Cargo.toml must have been written.
+    pub(crate) fn package_features(
+        &self,
+        package: &str,
+    ) -> Result<Vec<crate::cargo_metadata::PackageFeatures>> {
+        crate::cargo_metadata::package_features(&self.config, package)
+    }
+
+    /// Searches crates.io for `query`, for `:search` and `:dep!`.
+    pub(crate) fn search_crates(&self, query: &str) -> Result<CrateSearch, Error> {
+        crate::crate_search::search(&self.config, query)
+    }
+
     fn record_new_locals(
         &mut self,
         pat: ast::Pat,
@@ -1827,6 +4449,124 @@ fn replace_reserved_words_in_type(ty: &str) -> String {
         .to_string()
 }
 
+/// Where the part of evcxr_internal_runtime.rs that every evaluation needs ends.
+const RUNTIME_CORE_END: &str = "// Everything above is compiled into every evaluation.";
+
+/// Returns the `evcxr_internal_runtime` module. Unless `full` is set, it only has the core that
+/// every evaluation needs: interrupt handling and the helpers that commands like `:timeit` and
+/// `:sh` use. Most of the module is only needed when there are variables, so leaving it out keeps
+/// simple evaluations quick to compile.
+fn runtime_code(full: bool) -> CodeBlock {
+    let runtime = include_str!("evcxr_internal_runtime.rs");
+    let runtime = if full {
+        runtime
+    } else {
+        &runtime[..runtime.find(RUNTIME_CORE_END).unwrap_or(runtime.len())]
+    };
+    CodeBlock::new()
+        .generated("mod evcxr_internal_runtime {")
+        .generated(runtime)
+        .generated("}")
+}
+
+/// Returns whether `node` is an expression that refers to an existing place, such as a variable or
+/// one of its fields, rather than computing a new value.
+fn is_place_expression(node: &SyntaxNode) -> bool {
+    match ast::Expr::cast(node.clone()) {
+        Some(ast::Expr::PathExpr(_) | ast::Expr::FieldExpr(_) | ast::Expr::IndexExpr(_)) => true,
+        Some(ast::Expr::RefExpr(_)) => true,
+        Some(ast::Expr::PrefixExpr(prefix)) => prefix.op_kind() == Some(ast::UnaryOp::Deref),
+        Some(ast::Expr::ParenExpr(paren)) => paren
+            .expr()
+            .map_or(false, |expr| is_place_expression(expr.syntax())),
+        _ => false,
+    }
+}
+
+/// Returns `segment`, a statement of the cell being evaluated that was parsed as `node`, with a
+/// check for interrupts at the start of each of its loop bodies.
+fn interruptible(segment: Segment, node: &SyntaxNode) -> CodeBlock {
+    // The segment may start with whitespace that isn't part of the node.
+    let node_start = segment.code.len() - segment.code.trim_start().len();
+    if !segment.code[node_start..].starts_with(&node.text().to_string()) {
+        return CodeBlock::new().with_segment(segment);
+    }
+    let offsets: Vec<usize> = interrupt::loop_body_starts(node)
+        .into_iter()
+        .map(|offset| node_start + offset)
+        .collect();
+    CodeBlock::new().with_insertions(segment, &offsets, "evcxr_check_interrupt();")
+}
+
+/// Copies the persistent lockfile at `path`, if it exists, to `cargo_lock_path`, so that the
+/// dependency versions that it records are used.
+pub(crate) fn load_persistent_lockfile(path: &Path, cargo_lock_path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        if let Err(err) = std::fs::copy(path, cargo_lock_path) {
+            bail!("Error loading lockfile '{:?}': {}", path, err);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the identifiers that `nodes` use, which includes the names of any variables that they
+/// define or change.
+fn mentioned_names(nodes: &[SyntaxNode]) -> HashSet<String> {
+    nodes
+        .iter()
+        .flat_map(|node| node.descendants_with_tokens())
+        .filter_map(|element| element.into_token())
+        .filter(|token| token.kind() == SyntaxKind::IDENT)
+        .map(|token| token.text().to_owned())
+        .collect()
+}
+
+/// Returns whether any of `nodes` uses the name that refers to the most recent result.
+fn refers_to_latest_result(nodes: &[SyntaxNode]) -> bool {
+    nodes.iter().any(|node| {
+        node.descendants_with_tokens()
+            .filter_map(|element| element.into_token())
+            .any(|token| token.text() == result_history::LATEST_RESULT)
+    })
+}
+
+/// Returns `attribute` as an inner attribute, adding `#![` and `]` unless it already has them.
+/// Fails unless it's a single attribute.
+fn crate_attribute_code(attribute: &str) -> Result<String, Error> {
+    use ra_ap_syntax::ast::HasAttrs;
+    use ra_ap_syntax::ast::HasModuleItem;
+    let attribute = attribute.trim();
+    let code = if attribute.starts_with("#!") {
+        attribute.to_owned()
+    } else {
+        format!("#![{}]", attribute)
+    };
+    let parsed = ast::SourceFile::parse(&code);
+    let file = parsed.tree();
+    if !parsed.errors().is_empty() || file.attrs().count() != 1 || file.items().next().is_some() {
+        bail!("`{}` isn't a crate attribute", attribute);
+    }
+    Ok(code)
+}
+
+/// Returns the body of the function named `EXPANSION_FN_NAME` in `expanded`, which is code as
+/// pretty-printed by rustc, unindented.
+fn extract_expansion(expanded: &str) -> Option<String> {
+    let header = format!("fn {}() {{", EXPANSION_FN_NAME);
+    let mut lines = expanded
+        .lines()
+        .skip_while(|line| !line.starts_with(&header));
+    // Short bodies may be printed on the same line as the function's signature.
+    if let Some(body) = lines.next()?[header.len()..].trim().strip_suffix('}') {
+        return Some(body.trim().to_owned());
+    }
+    let body: Vec<&str> = lines
+        .take_while(|line| *line != "}")
+        .map(|line| line.strip_prefix("    ").unwrap_or(line))
+        .collect();
+    Some(body.join("\n"))
+}
+
 #[cfg(test)]
 mod tests {
     use ra_ap_syntax::ast::HasAttrs;
@@ -1850,6 +4590,58 @@ mod tests {
         ContextState::new(config)
     }
 
+    #[test]
+    fn core_runtime() {
+        let core = runtime_code(false).code_string();
+        assert!(core.contains("pub fn time_iterations"));
+        assert!(core.contains("pub fn check_interrupt"));
+        assert!(!core.contains("pub struct VariableStore"));
+        assert!(runtime_code(true)
+            .code_string()
+            .contains("pub struct VariableStore"));
+    }
+
+    #[test]
+    fn test_crate_attribute_code() {
+        assert_eq!(
+            crate_attribute_code("allow(dead_code)").unwrap(),
+            "#![allow(dead_code)]"
+        );
+        assert_eq!(
+            crate_attribute_code(" #![feature(never_type)] ").unwrap(),
+            "#![feature(never_type)]"
+        );
+        assert!(crate_attribute_code("#[allow(dead_code)]").is_err());
+        assert!(crate_attribute_code("allow(dead_code)] fn foo() {} #![allow(unused)").is_err());
+    }
+
+    #[test]
+    fn test_extract_expansion() {
+        let expanded = [
+            "#[prelude_import]",
+            "use std::prelude::rust_2021::*;",
+            "fn foo() {}",
+            "fn evcxr_expansion() {",
+            "    struct Point;",
+            "    impl Point {",
+            "        fn new() -> Point { Point }",
+            "    }",
+            "}",
+            "fn bar() {}",
+        ]
+        .join("\n");
+        assert_eq!(
+            extract_expansion(&expanded).unwrap(),
+            "struct Point;\nimpl Point {\n    fn new() -> Point { Point }\n}"
+        );
+        assert_eq!(extract_expansion("fn evcxr_expansion() {}\n").unwrap(), "");
+        assert_eq!(
+            extract_expansion("fn evcxr_expansion() { 21 * 2 }\n").unwrap(),
+            "21 * 2"
+        );
+        assert_eq!(extract_expansion("fn foo() {}\n"), None);
+    }
+
     #[test]
     fn test_attributes() {
         let mut state = create_state();
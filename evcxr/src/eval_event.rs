@@ -0,0 +1,61 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+/// Something that happened during evaluation, reported as it happens to sinks registered with
+/// `EvalContext::subscribe`, e.g. so that a frontend can show whether code is compiling or
+/// running.
+///
+/// A single evaluation may compile more than once, e.g. when we need to retry after working out
+/// the types of variables, so `CompilationStarted` and `CompilationFinished` may come in several
+/// pairs before `ExecutionStarted`. If compilation fails, there's no execution. While `:build_log`
+/// is on, each compilation's `BuildOutput` comes between its `CompilationStarted` and
+/// `CompilationFinished`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EvalEvent {
+    CompilationStarted,
+    /// A line that cargo, rustc or a build script wrote while building. Only sent while
+    /// `:build_log` is on, in which case the same lines are also sent to
+    /// `EvalContextOutputs::stderr`.
+    BuildOutput(String),
+    /// Compilation finished, successfully or not, having taken the given time.
+    CompilationFinished(Duration),
+    ExecutionStarted,
+    /// A line that user code wrote to stdout. The same lines are also sent to
+    /// `EvalContextOutputs::stdout`.
+    Stdout(String),
+    /// Execution finished, successfully or not, having taken the given time.
+    ExecutionFinished(Duration),
+}
+
+/// Receives `EvalEvent`s. Implemented for closures and for channel senders. Events are delivered
+/// on the thread that's evaluating, so sinks shouldn't block.
+pub trait EvalEventSink: Send {
+    fn event(&self, event: &EvalEvent);
+}
+
+impl<F: Fn(&EvalEvent) + Send> EvalEventSink for F {
+    fn event(&self, event: &EvalEvent) {
+        self(event);
+    }
+}
+
+impl EvalEventSink for crossbeam_channel::Sender<EvalEvent> {
+    fn event(&self, event: &EvalEvent) {
+        // An error means that the receiver has been dropped, so nobody is interested.
+        let _ = self.send(event.clone());
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Session;
+use crate::SessionConfig;
+use crate::SessionError;
+use crate::SessionOutputs;
+use crossbeam_channel::Receiver;
+use crossbeam_channel::Sender;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Identifies a piece of code submitted to an `EvalSession`.
+pub type RequestId = u64;
+
+/// Something that happened in an `EvalSession` since it was last polled.
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// The session has finished initializing and will now start evaluating submitted code.
+    Ready,
+    /// Initialization failed. No further events will be produced.
+    InitFailed(SessionError),
+    /// A line that user code wrote to stdout.
+    Stdout(String),
+    /// A line that user code (or the compiler) wrote to stderr.
+    Stderr(String),
+    /// Evaluation of a submitted request has finished.
+    Completed {
+        id: RequestId,
+        result: Result<SessionOutputs, SessionError>,
+    },
+}
+
+/// A non-blocking wrapper around `Session`, intended for embedding in applications that can't
+/// afford to block their main thread, such as game engines. Compilation and execution happen on a
+/// worker thread. Callers submit code with `submit`, then call `poll` periodically (e.g. once per
+/// frame) to collect whatever has become available.
+pub struct EvalSession {
+    // Dropping this tells the worker thread to exit once any in-progress evaluation completes.
+    request_sender: Sender<(RequestId, String)>,
+    event_receiver: Receiver<SessionEvent>,
+    next_id: RequestId,
+    pending: usize,
+    budget: Duration,
+}
+
+impl EvalSession {
+    /// Creates a new session. The underlying `Session` is created on the worker thread, so this
+    /// returns immediately. A `SessionEvent::Ready` will be produced once it's initialized.
+    pub fn new() -> EvalSession {
+        Self::with_config(SessionConfig::default())
+    }
+
+    /// Like `new`, but the `Session` is created with `config`.
+    pub fn with_config(config: SessionConfig) -> EvalSession {
+        Self::start(move || Session::with_config(config))
+    }
+
+    /// Creates a session that evaluates code in an existing `Session`.
+    pub fn with_session(session: Session) -> EvalSession {
+        Self::start(move || Ok(session))
+    }
+
+    fn start<F>(init: F) -> EvalSession
+    where
+        F: FnOnce() -> Result<Session, SessionError> + Send + 'static,
+    {
+        let (request_sender, request_receiver) = crossbeam_channel::unbounded();
+        let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            let mut session = match init() {
+                Ok(x) => x,
+                Err(error) => {
+                    let _ = event_sender.send(SessionEvent::InitFailed(error));
+                    return;
+                }
+            };
+            let (stdout, stderr) = session.output_receivers();
+            forward_lines(stdout, event_sender.clone(), SessionEvent::Stdout);
+            forward_lines(stderr, event_sender.clone(), SessionEvent::Stderr);
+            if event_sender.send(SessionEvent::Ready).is_err() {
+                return;
+            }
+            // The loop ends when the session is dropped, since that drops the request sender.
+            while let Ok((id, code)) = request_receiver.recv() {
+                let result = session.eval(&code);
+                if event_sender
+                    .send(SessionEvent::Completed { id, result })
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        EvalSession {
+            request_sender,
+            event_receiver,
+            next_id: 1,
+            pending: 0,
+            budget: Duration::from_millis(2),
+        }
+    }
+
+    /// Sets the maximum amount of time that `poll` may spend collecting events.
+    pub fn set_budget(&mut self, budget: Duration) {
+        self.budget = budget;
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    /// Queues `code` for evaluation. Code is evaluated in the order it was submitted. The returned
+    /// ID will be reported in the corresponding `SessionEvent::Completed`.
+    pub fn submit(&mut self, code: &str) -> RequestId {
+        let id = self.next_id;
+        self.next_id += 1;
+        // If the worker has gone away, we'll have already produced InitFailed, so there's nothing
+        // more useful to report here.
+        if self.request_sender.send((id, code.to_owned())).is_ok() {
+            self.pending += 1;
+        }
+        id
+    }
+
+    /// Returns whether any submitted code has yet to complete.
+    pub fn is_busy(&self) -> bool {
+        self.pending > 0
+    }
+
+    /// Returns all events that are ready, without waiting for any that aren't. If lots of events
+    /// are queued, returns early once the configured budget has been used, leaving the remainder
+    /// for the next call.
+    pub fn poll(&mut self) -> Vec<SessionEvent> {
+        let deadline = Instant::now() + self.budget;
+        let mut events = Vec::new();
+        while let Ok(event) = self.event_receiver.try_recv() {
+            if let SessionEvent::Completed { .. } = &event {
+                self.pending -= 1;
+            }
+            events.push(event);
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        events
+    }
+}
+
+impl Default for EvalSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn forward_lines(
+    lines: Receiver<String>,
+    event_sender: Sender<SessionEvent>,
+    to_event: fn(String) -> SessionEvent,
+) {
+    std::thread::spawn(move || {
+        while let Ok(line) = lines.recv() {
+            if event_sender.send(to_event(line)).is_err() {
+                break;
+            }
+        }
+    });
+}
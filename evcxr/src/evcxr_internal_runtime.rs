@@ -18,15 +18,162 @@
 
 pub const VARIABLE_CHANGED_TYPE: &str = "EVCXR_VARIABLE_CHANGED_TYPE:";
 pub const USER_ERROR_OCCURRED: &str = "EVCXR_ERROR_OCCURRED";
+pub const TIMEIT_SAMPLES: &str = "EVCXR_TIMEIT_SAMPLES:";
+pub const MEMORY_STATS: &str = "EVCXR_MEMORY_STATS:";
+// Duplicated in evcxr_runtime, which doesn't depend on evcxr.
+pub const GET_MIME_PREFERENCES: &str = "EVCXR_GET_MIME_PREFERENCES";
+// Also duplicated in evcxr_runtime. Followed by the mime type and the length of the content,
+// which is sent as raw bytes after the line.
+pub const BEGIN_BINARY_CONTENT: &str = "EVCXR_BEGIN_BINARY_CONTENT ";
+pub const BEGIN_TABLE: &str = "EVCXR_BEGIN_TABLE ";
+pub const END_TABLE: &str = "EVCXR_END_TABLE";
+pub const JOB_OUTPUT: &str = "EVCXR_JOB_OUTPUT ";
+pub const JOB_FINISHED: &str = "EVCXR_JOB_FINISHED ";
+pub const TEST_STARTED: &str = "EVCXR_TEST_STARTED ";
+pub const TEST_FINISHED: &str = "EVCXR_TEST_FINISHED ";
+pub const MIGRATED: &str = "EVCXR_MIGRATED:";
+pub const MIGRATION_FAILED: &str = "EVCXR_MIGRATION_FAILED:";
+
+static INTERRUPT_REQUESTED: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+/// Makes SIGINT, or Ctrl-Break on Windows, which evcxr sends the first time that the user
+/// interrupts, ask the code that's running to stop at its next call to `check_interrupt`. Each
+/// build of user code has its own copy of this module, so a request can't be left over from code
+/// that ran earlier.
+pub fn watch_for_interrupts() {
+    #[cfg(unix)]
+    {
+        extern "C" {
+            fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        }
+        extern "C" fn handler(_signal: i32) {
+            INTERRUPT_REQUESTED.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+        const SIGINT: i32 = 2;
+        unsafe {
+            signal(SIGINT, handler);
+        }
+    }
+    // Handlers are called most recently added first, so this one takes precedence over those added
+    // by earlier builds.
+    #[cfg(windows)]
+    {
+        extern "system" {
+            fn SetConsoleCtrlHandler(handler: extern "system" fn(u32) -> i32, add: i32) -> i32;
+        }
+        extern "system" fn handler(ctrl_type: u32) -> i32 {
+            const CTRL_BREAK_EVENT: u32 = 1;
+            if ctrl_type != CTRL_BREAK_EVENT {
+                return 0;
+            }
+            INTERRUPT_REQUESTED.store(true, std::sync::atomic::Ordering::SeqCst);
+            1
+        }
+        unsafe {
+            SetConsoleCtrlHandler(handler, 1);
+        }
+    }
+}
+
+/// Unwinds the current thread if an interrupt was requested. This doesn't call the panic hook, so
+/// nothing is printed. Called between statements of user code.
+pub fn check_interrupt() {
+    if INTERRUPT_REQUESTED.swap(false, std::sync::atomic::Ordering::SeqCst) {
+        std::panic::resume_unwind(Box::new("Execution interrupted"));
+    }
+}
+
+/// Runs `f` `iterations` times and reports how long each run took, in nanoseconds. Used by
+/// `:timeit`.
+pub fn time_iterations<T, F: FnMut() -> T>(iterations: usize, mut f: F) {
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        check_interrupt();
+        let start = std::time::Instant::now();
+        let value = black_box(f());
+        samples.push(start.elapsed().as_nanos().to_string());
+        drop(value);
+    }
+    println!("{}{}", TIMEIT_SAMPLES, samples.join(","));
+}
+
+/// Runs `command` with the platform's shell, with its output going wherever ours does. Used by
+/// `:sh` and `!`.
+pub fn run_shell_command(command: &str) {
+    match shell_command(command).status() {
+        Ok(status) => report_shell_status(status),
+        Err(error) => eprintln!("Failed to run shell: {}", error),
+    }
+}
+
+/// Runs `command` like `run_shell_command`, but returns what it writes to stdout, with trailing
+/// newlines removed, as `$(...)` does in a shell. Used by `let x = !command`.
+pub fn capture_shell_command(command: &str) -> String {
+    let output = shell_command(command)
+        .stderr(std::process::Stdio::inherit())
+        .output();
+    match output {
+        Ok(output) => {
+            report_shell_status(output.status);
+            String::from_utf8_lossy(&output.stdout)
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_owned()
+        }
+        Err(error) => {
+            eprintln!("Failed to run shell: {}", error);
+            String::new()
+        }
+    }
+}
+
+fn shell_command(command: &str) -> std::process::Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell_command = std::process::Command::new(shell);
+    shell_command
+        .arg(flag)
+        .arg(command)
+        // Our stdin is where evcxr sends us instructions.
+        .stdin(std::process::Stdio::null());
+    shell_command
+}
+
+fn report_shell_status(status: std::process::ExitStatus) {
+    if !status.success() {
+        eprintln!("Command failed with {}", status);
+    }
+}
+
+/// Prevents the optimizer from discarding the computation of `value`. We can't use
+/// `std::hint::black_box`, since this file is also compiled as part of evcxr, which supports
+/// older versions of Rust.
+fn black_box<T>(value: T) -> T {
+    unsafe {
+        let result = std::ptr::read_volatile(&value);
+        std::mem::forget(value);
+        result
+    }
+}
+
+// Everything above is compiled into every evaluation. Everything below is only included when
+// it's needed, e.g. when there are variables to store. See `runtime_code` in eval_context.rs.
 
 pub struct VariableStore {
     variables: std::collections::HashMap<String, Box<dyn std::any::Any + 'static>>,
+    /// Variables serialized as JSON by `:migrate_types`, waiting to be deserialized into new
+    /// definitions of their types.
+    migrations: std::collections::HashMap<String, String>,
 }
 
 impl VariableStore {
     pub fn new() -> VariableStore {
         VariableStore {
             variables: std::collections::HashMap::new(),
+            migrations: std::collections::HashMap::new(),
         }
     }
 
@@ -50,6 +197,24 @@ impl VariableStore {
         true
     }
 
+    /// Like `check_variable`, but for a variable whose type was redefined with an identical
+    /// layout, i.e. the same definition, referring only to primitives and types whose layout is
+    /// also unchanged. A value of the old type is moved into the new one as it is. The code of the
+    /// old type remains loaded, so the value doesn't refer to anything that's gone.
+    pub fn check_variable_same_layout<T: 'static>(&mut self, name: &str) -> bool {
+        let same_layout = self.variables.get(name).map_or(false, |v| {
+            !v.is::<T>() && std::alloc::Layout::for_value(&**v) == std::alloc::Layout::new::<T>()
+        });
+        if same_layout {
+            if let Some(v) = self.variables.remove(name) {
+                // The allocation has the layout of `T`, so can be owned by a `Box<T>`.
+                let value = unsafe { Box::from_raw(Box::into_raw(v) as *mut T) };
+                self.variables.insert(name.to_owned(), value);
+            }
+        }
+        self.check_variable::<T>(name)
+    }
+
     pub fn take_variable<T: 'static>(&mut self, name: &str) -> T {
         match self.variables.remove(name) {
             Some(v) => {
@@ -64,6 +229,63 @@ impl VariableStore {
         }
     }
 
+    pub fn get_variable<T: 'static>(&self, name: &str) -> Option<&T> {
+        self.variables.get(name).and_then(|v| v.downcast_ref::<T>())
+    }
+
+    pub fn put_migration(&mut self, name: &str, json: String) {
+        self.migrations.insert(name.to_owned(), json);
+    }
+
+    pub fn take_migration(&mut self, name: &str) -> Option<String> {
+        self.migrations.remove(name)
+    }
+
+    /// Writes the `Debug` output of a variable to a file named after it in `dir`, so that it can be
+    /// recreated if we crash. If the output would be longer than `max_len`, any earlier snapshot is
+    /// removed instead. Errors are ignored, since they only mean that the variable can't be
+    /// recovered.
+    pub fn snapshot_variable<T: std::fmt::Debug + 'static>(
+        &self,
+        name: &str,
+        dir: &str,
+        max_len: usize,
+    ) {
+        struct Capped {
+            text: String,
+            max_len: usize,
+        }
+        impl std::fmt::Write for Capped {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                if self.text.len() + s.len() > self.max_len {
+                    return Err(std::fmt::Error);
+                }
+                self.text.push_str(s);
+                Ok(())
+            }
+        }
+
+        let path = std::path::Path::new(dir).join(name);
+        let mut debug = Capped {
+            text: String::new(),
+            max_len,
+        };
+        let written = match self.variables.get(name).and_then(|v| v.downcast_ref::<T>()) {
+            Some(value) => std::fmt::Write::write_fmt(&mut debug, format_args!("{:?}", value)),
+            None => Err(std::fmt::Error),
+        };
+        if written.is_err() {
+            let _ = std::fs::remove_file(path);
+            return;
+        }
+        // Written to a temporary file first, so that a crash part way through can't leave a
+        // truncated snapshot.
+        let temporary_path = std::path::Path::new(dir).join(format!(".{}.tmp", name));
+        let _ = std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(&temporary_path, debug.text))
+            .and_then(|_| std::fs::rename(&temporary_path, path));
+    }
+
     pub fn lazy_arc<T: 'static, F: FnOnce() -> T>(
         &mut self,
         name: &str,
@@ -81,11 +303,546 @@ impl VariableStore {
         }
     }
 
+    /// Returns the session's arena, creating it the first time. The arena is leaked, so that
+    /// references to values in it are `'static`.
+    pub fn arena(&mut self) -> &'static Arena {
+        let arena = self
+            .variables
+            .entry("evcxr_arena".to_owned())
+            .or_insert_with(|| Box::new(&*Box::leak(Box::new(Arena::new()))));
+        match arena.downcast_ref::<&'static Arena>() {
+            Some(arena) => arena,
+            None => panic!("evcxr_arena changed type"),
+        }
+    }
+
     pub fn merge(&mut self, mut other: VariableStore) {
         self.variables.extend(other.variables.drain());
+        self.migrations.extend(other.migrations.drain());
     }
 }
 
 pub fn create_variable_store() -> *mut VariableStore {
     Box::into_raw(Box::new(VariableStore::new()))
 }
+
+/// A bump arena that lives for the rest of the session, used by `:arena`. Nothing allocated in it
+/// is ever freed or dropped, so references to values in it are `'static` and can be kept in
+/// variables.
+pub struct Arena {
+    /// The start and end of the free part of the current chunk.
+    next: std::cell::Cell<*mut u8>,
+    end: std::cell::Cell<*mut u8>,
+    bytes_allocated: std::cell::Cell<usize>,
+}
+
+impl Arena {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    const CHUNK_ALIGN: usize = 16;
+
+    fn new() -> Arena {
+        Arena {
+            next: std::cell::Cell::new(std::ptr::null_mut()),
+            end: std::cell::Cell::new(std::ptr::null_mut()),
+            bytes_allocated: std::cell::Cell::new(0),
+        }
+    }
+
+    /// Moves `value` into the arena. It will never be dropped. The reference is shared, since
+    /// references derived from a `&'static mut` kept in a variable would borrow the variable.
+    pub fn alloc<T: 'static>(&self, value: T) -> &'static T {
+        let ptr = self.alloc_layout(std::alloc::Layout::new::<T>()) as *mut T;
+        unsafe {
+            ptr.write(value);
+            &*ptr
+        }
+    }
+
+    /// Copies `value` into the arena.
+    pub fn alloc_str(&self, value: &str) -> &'static str {
+        let bytes = self.alloc_slice_copy(value.as_bytes());
+        unsafe { std::str::from_utf8_unchecked(bytes) }
+    }
+
+    /// Copies `values` into the arena.
+    pub fn alloc_slice_copy<T: Copy + 'static>(&self, values: &[T]) -> &'static [T] {
+        let layout = std::alloc::Layout::array::<T>(values.len()).unwrap();
+        let ptr = self.alloc_layout(layout) as *mut T;
+        unsafe {
+            std::ptr::copy_nonoverlapping(values.as_ptr(), ptr, values.len());
+            std::slice::from_raw_parts(ptr, values.len())
+        }
+    }
+
+    /// Returns how many bytes have been allocated in the arena.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated.get()
+    }
+
+    fn alloc_layout(&self, layout: std::alloc::Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            // Any non-null, aligned pointer will do.
+            return layout.align() as *mut u8;
+        }
+        self.bytes_allocated
+            .set(self.bytes_allocated.get() + layout.size());
+        // Large or unusually aligned values get an allocation of their own, so that they don't
+        // waste the rest of the current chunk.
+        if layout.size() > Arena::CHUNK_SIZE / 4 || layout.align() > Arena::CHUNK_ALIGN {
+            return Arena::alloc_chunk(layout);
+        }
+        let mut start = self.next.get();
+        let padding = start.align_offset(layout.align());
+        if start.is_null() || (self.end.get() as usize) - (start as usize) < padding + layout.size()
+        {
+            start = Arena::alloc_chunk(
+                std::alloc::Layout::from_size_align(Arena::CHUNK_SIZE, Arena::CHUNK_ALIGN).unwrap(),
+            );
+            self.end.set(unsafe { start.add(Arena::CHUNK_SIZE) });
+        } else {
+            start = unsafe { start.add(padding) };
+        }
+        self.next.set(unsafe { start.add(layout.size()) });
+        start
+    }
+
+    fn alloc_chunk(layout: std::alloc::Layout) -> *mut u8 {
+        let chunk = unsafe { std::alloc::alloc(layout) };
+        if chunk.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        chunk
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: std::cell::RefCell<Option<String>> = std::cell::RefCell::new(None);
+}
+
+/// Records the formatted error that a `?` in user code returned, so that it can be stored as `_err`
+/// once user code has finished.
+pub fn set_last_error(message: String) {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+pub fn take_last_error() -> Option<String> {
+    LAST_ERROR.with(|last_error| last_error.borrow_mut().take())
+}
+
+/// A `#[test]` function, run by `:test`.
+pub struct Test {
+    /// The function's path, e.g. `tests::it_works`.
+    pub name: &'static str,
+    /// If the test has `#[should_panic]`, text that the panic message must contain.
+    pub should_panic: Option<&'static str>,
+    pub ignore: bool,
+    /// Calls the function, returning why it failed if it returned an error.
+    pub run: fn() -> Option<String>,
+}
+
+/// The return types that `#[test]` functions may have.
+pub trait TestReturn {
+    fn test_error(self) -> Option<String>;
+}
+
+impl TestReturn for () {
+    fn test_error(self) -> Option<String> {
+        None
+    }
+}
+
+impl<E: std::fmt::Debug> TestReturn for Result<(), E> {
+    fn test_error(self) -> Option<String> {
+        self.err().map(|error| format!("Error: {:?}", error))
+    }
+}
+
+/// Runs `tests` one at a time, reporting when each starts and how it finished, so that output
+/// printed in between can be attributed to it. Used by `:test`.
+pub fn run_tests(tests: &[Test]) {
+    // Panics are reported as failures, so we don't want the panic hook to print them as well.
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    for test in tests {
+        println!("{}{}", TEST_STARTED, test.name);
+        if test.ignore {
+            println!("{}{} ignored", TEST_FINISHED, test.name);
+            continue;
+        }
+        let failure = match (std::panic::catch_unwind(test.run), test.should_panic) {
+            (Ok(error), None) => error,
+            (Ok(_), Some(_)) => Some("The test didn't panic as expected".to_owned()),
+            (Err(payload), None) => Some(panic_message(&*payload)),
+            (Err(payload), Some(expected)) => {
+                let message = panic_message(&*payload);
+                if message.contains(expected) {
+                    None
+                } else {
+                    Some(format!(
+                        "The panic message `{}` doesn't contain `{}`",
+                        message, expected
+                    ))
+                }
+            }
+        };
+        match failure {
+            Some(message) => println!(
+                "{}{} failed {}",
+                TEST_FINISHED,
+                test.name,
+                escape_field(&message)
+            ),
+            None => println!("{}{} ok", TEST_FINISHED, test.name),
+        }
+    }
+    std::panic::set_hook(previous_hook);
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_owned()
+    }
+}
+
+static ALLOCATIONS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static BYTES_ALLOCATED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static BYTES_FREED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// A global allocator that counts allocations, then defers to the system allocator. Installed in
+/// user code when `:mem on` is used. Since everything ends up with the system allocator, memory
+/// can still be freed by code compiled for other evaluations.
+pub struct TrackingAllocator;
+
+unsafe impl std::alloc::GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        record_allocation(layout.size());
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+        record_allocation(layout.size());
+        std::alloc::System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        record_free(layout.size());
+        std::alloc::System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = std::alloc::System.realloc(ptr, layout, new_size);
+        // On failure, the original allocation is unchanged.
+        if !new_ptr.is_null() {
+            record_reallocation(layout.size(), new_size);
+        }
+        new_ptr
+    }
+}
+
+fn record_allocation(size: usize) {
+    use std::sync::atomic::Ordering;
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_ALLOCATED.fetch_add(size as u64, Ordering::Relaxed);
+}
+
+/// Records an allocation changing from `old_size` to `new_size`. Only the difference counts, since
+/// the rest of the memory was already counted when it was first allocated.
+fn record_reallocation(old_size: usize, new_size: usize) {
+    use std::sync::atomic::Ordering;
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    if new_size > old_size {
+        BYTES_ALLOCATED.fetch_add((new_size - old_size) as u64, Ordering::Relaxed);
+    } else {
+        record_free(old_size - new_size);
+    }
+}
+
+fn record_free(size: usize) {
+    BYTES_FREED.fetch_add(size as u64, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Counts allocations made by `TrackingAllocator` between `start` and `report`.
+pub struct MemoryTracker {
+    allocations: u64,
+    bytes_allocated: u64,
+    bytes_freed: u64,
+    peak_rss: u64,
+}
+
+impl MemoryTracker {
+    pub fn start() -> MemoryTracker {
+        use std::sync::atomic::Ordering;
+        // Read the peak RSS first, so that the allocations needed to do so aren't counted.
+        let peak_rss = peak_rss();
+        MemoryTracker {
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            bytes_allocated: BYTES_ALLOCATED.load(Ordering::Relaxed),
+            bytes_freed: BYTES_FREED.load(Ordering::Relaxed),
+            peak_rss,
+        }
+    }
+
+    /// Reports allocations since `start`. The format is parsed by `MemoryStats::parse`.
+    pub fn report(&self) {
+        use std::sync::atomic::Ordering;
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - self.allocations;
+        let bytes_allocated = BYTES_ALLOCATED.load(Ordering::Relaxed) - self.bytes_allocated;
+        let bytes_freed = BYTES_FREED.load(Ordering::Relaxed) - self.bytes_freed;
+        println!(
+            "{}{} {} {} {}",
+            MEMORY_STATS,
+            allocations,
+            bytes_allocated,
+            bytes_allocated.saturating_sub(bytes_freed),
+            peak_rss().saturating_sub(self.peak_rss)
+        );
+    }
+}
+
+/// Returns the peak resident set size of this process in bytes, or 0 if we don't know how to
+/// determine it on this platform.
+fn peak_rss() -> u64 {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status.lines().find_map(|line| {
+                line.strip_prefix("VmHWM:")?
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+        })
+        .map_or(0, |kib| kib * 1024)
+}
+
+/// Sends a table, e.g. the first rows of a DataFrame, to evcxr to be displayed. `columns` holds the
+/// cells of each column, which may be fewer than `total_rows`. The format is parsed by
+/// `Table::parse`.
+pub fn emit_table(
+    names: Vec<String>,
+    types: Vec<String>,
+    columns: Vec<Vec<String>>,
+    total_rows: usize,
+) {
+    use std::io::Write;
+    fn line<'a>(fields: impl Iterator<Item = &'a str>) -> String {
+        let fields: Vec<String> = fields.map(escape_field).collect();
+        format!("{}\n", fields.join("\t"))
+    }
+    let mut output = format!("{}{}\n", BEGIN_TABLE, total_rows);
+    output.push_str(&line(names.iter().map(String::as_str)));
+    output.push_str(&line(types.iter().map(String::as_str)));
+    let rows = columns.iter().map(Vec::len).max().unwrap_or(0);
+    for row in 0..rows {
+        output.push_str(&line(
+            columns
+                .iter()
+                .map(|column| column.get(row).map_or("", String::as_str)),
+        ));
+    }
+    output.push_str(END_TABLE);
+    output.push('\n');
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let _ = out.write_all(output.as_bytes());
+    let _ = out.flush();
+}
+
+/// Escapes a field of a table, so that it doesn't contain tabs or newlines.
+pub fn escape_field(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Functions registered with `evcxr::register_formatter`, by the type of value that they format.
+/// Each is passed a pointer to a value of that type. An alias rather than a struct for the same
+/// reason as `JobRegistry`.
+pub type FormatterRegistry = std::cell::RefCell<
+    std::collections::HashMap<std::any::TypeId, Box<dyn Fn(*const ()) -> String>>,
+>;
+
+thread_local! {
+    static FORMATTERS: std::cell::RefCell<Option<std::sync::Arc<FormatterRegistry>>> =
+        std::cell::RefCell::new(None);
+}
+
+/// Makes `registry` the one that `register_formatter` adds to and that results are displayed with.
+/// Called before each evaluation, since each build of user code has its own copy of this module.
+pub fn set_formatters(registry: std::sync::Arc<FormatterRegistry>) {
+    FORMATTERS.with(|formatters| *formatters.borrow_mut() = Some(registry));
+}
+
+/// Makes `formatter` display results of type `T`, in place of their `Debug` output. Replaces any
+/// formatter that was registered for `T` before.
+pub fn register_formatter<T: 'static, F: Fn(&T) -> String + 'static>(formatter: F) {
+    FORMATTERS.with(|formatters| match &*formatters.borrow() {
+        Some(registry) => {
+            registry.borrow_mut().insert(
+                std::any::TypeId::of::<T>(),
+                // The registry only passes pointers to values of type `T`.
+                Box::new(move |value| formatter(unsafe { &*(value as *const T) })),
+            );
+        }
+        None => panic!("Formatters can only be registered by the thread that runs a cell's code"),
+    });
+}
+
+/// Displays `value` with the formatter registered for its type, if there is one. Returns whether
+/// there was. Formatters are found by `TypeId`, so only values of `'static` types are supported.
+pub fn display_with_formatter<T: 'static>(value: &T) -> bool {
+    let text = FORMATTERS.with(|formatters| {
+        let formatters = formatters.borrow();
+        let registry = formatters.as_ref()?.borrow();
+        let formatter = registry.get(&std::any::TypeId::of::<T>())?;
+        Some(formatter(value as *const T as *const ()))
+    });
+    match text {
+        Some(text) => {
+            println!(
+                "EVCXR_BEGIN_CONTENT text/plain\n{}\nEVCXR_END_CONTENT",
+                text
+            );
+            true
+        }
+        None => false,
+    }
+}
+
+/// Called instead of `display_with_formatter` for values whose types aren't `'static`. Reports
+/// that no formatter was used, if any have been registered.
+pub fn formatters_unsupported() {
+    let registered = FORMATTERS.with(|formatters| match &*formatters.borrow() {
+        Some(registry) => !registry.borrow().is_empty(),
+        None => false,
+    });
+    if registered {
+        eprintln!("Formatters can't be used for values that borrow, i.e. aren't 'static");
+    }
+}
+
+/// The cancellation flags of running background jobs, by job ID. Made only of standard library
+/// types, so that it can be shared between evaluations via the variable store.
+pub type JobRegistry =
+    std::sync::Mutex<std::collections::HashMap<u64, std::sync::Arc<std::sync::atomic::AtomicBool>>>;
+
+/// A background job started by `:bg`. Code run by the job can refer to it as `evcxr_job`.
+pub struct Job {
+    id: u64,
+    cancelled: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    partial_line: std::sync::Mutex<String>,
+}
+
+impl Job {
+    /// Returns whether the job has been cancelled with `:kill`. Jobs that aren't futures should
+    /// check this periodically and return once it's set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Writes output tagged with the job's ID. `print!` and `println!` within the job's code call
+    /// this.
+    pub fn print(&self, text: &str) {
+        let mut partial_line = self.partial_line.lock().unwrap();
+        partial_line.push_str(text);
+        while let Some(end) = partial_line.find('\n') {
+            println!("{}{} {}", JOB_OUTPUT, self.id, &partial_line[..end]);
+            partial_line.drain(..=end);
+        }
+    }
+
+    fn finish(&self, outcome: &str) {
+        let partial_line = std::mem::take(&mut *self.partial_line.lock().unwrap());
+        if !partial_line.is_empty() {
+            println!("{}{} {}", JOB_OUTPUT, self.id, partial_line);
+        }
+        println!("{}{} {}", JOB_FINISHED, self.id, outcome);
+    }
+}
+
+/// Runs `f` on a new thread as the background job `id`, reporting when it finishes.
+pub fn spawn_job<F: FnOnce(&Job) + Send + 'static>(
+    registry: &std::sync::Arc<JobRegistry>,
+    id: u64,
+    f: F,
+) {
+    let cancelled = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    registry
+        .lock()
+        .unwrap()
+        .insert(id, std::sync::Arc::clone(&cancelled));
+    let registry = std::sync::Arc::clone(registry);
+    std::thread::spawn(move || {
+        let job = Job {
+            id,
+            cancelled,
+            partial_line: std::sync::Mutex::new(String::new()),
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&job)));
+        registry.lock().unwrap().remove(&id);
+        job.finish(if result.is_err() {
+            "panicked"
+        } else if job.is_cancelled() {
+            "cancelled"
+        } else {
+            "done"
+        });
+    });
+}
+
+/// Asks the background job `id` to stop. Returns false if it has already finished.
+pub fn cancel_job(registry: &JobRegistry, id: u64) -> bool {
+    match registry.lock().unwrap().get(&id) {
+        Some(cancelled) => {
+            cancelled.store(true, std::sync::atomic::Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The value of a job's expression. If it's a future, `RunFutureJob` polls it to completion,
+/// otherwise `RunValueJob` just drops it, since the work was done computing it.
+pub struct JobValue<T>(pub T);
+
+pub trait RunFutureJob {
+    fn run_job(self, job: &Job);
+}
+
+impl<F: std::future::Future> RunFutureJob for JobValue<F> {
+    fn run_job(self, job: &Job) {
+        struct ThreadWaker(std::thread::Thread);
+        impl std::task::Wake for ThreadWaker {
+            fn wake(self: std::sync::Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker =
+            std::task::Waker::from(std::sync::Arc::new(ThreadWaker(std::thread::current())));
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut future = Box::pin(self.0);
+        // Dropping the future when we're cancelled stops it at whichever await it's blocked on.
+        while !job.is_cancelled() {
+            if future.as_mut().poll(&mut context).is_ready() {
+                return;
+            }
+            // Wake up periodically even if the future doesn't, so that we notice cancellation.
+            std::thread::park_timeout(std::time::Duration::from_millis(100));
+        }
+    }
+}
+
+pub trait RunValueJob {
+    fn run_job(self, job: &Job);
+}
+
+impl<T> RunValueJob for &JobValue<T> {
+    fn run_job(self, _job: &Job) {}
+}
@@ -0,0 +1,179 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Explanations of error codes. `:explain E0597` prints rustc's long-form explanation, which we get
+//! from `rustc --explain`. Separately, some errors mean something different, or have a different
+//! fix, when code is evaluated a cell at a time, so we keep hints about those, keyed by error code.
+//! These are shown together with the errors. Each context has its own set of hints, to which
+//! frontends can add with `EvalContext::register_hint`.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::eval_context::Config;
+use std::collections::HashMap;
+
+const BUILTIN_HINTS: &[(&str, &str)] = &[
+    (
+        "E0382",
+        "Values that are moved out of a variable in a cell are gone in later cells too, so the \
+         variable is no longer available. Use `.clone()` to move a copy instead, or define the \
+         variable again",
+    ),
+    (
+        "E0425",
+        "Variables defined by a cell that failed to compile or panicked aren't kept. `:vars` lists \
+         the variables that are currently defined",
+    ),
+    (
+        "E0432",
+        "If this is an external crate, it needs to be added with `:dep`, e.g. `:dep regex`",
+    ),
+    (
+        "E0433",
+        "If this is an external crate, it needs to be added with `:dep`, e.g. `:dep regex`",
+    ),
+    (
+        "E0499",
+        "Borrows end at the end of a cell at the latest, so moving code that uses the first borrow \
+         into an earlier cell, or into a block `{ ... }`, ends it sooner",
+    ),
+    (
+        "E0502",
+        "Borrows end at the end of a cell at the latest, so moving code that uses the first borrow \
+         into an earlier cell, or into a block `{ ... }`, ends it sooner",
+    ),
+    (
+        "E0597",
+        "Values assigned to variables in Evcxr cannot contain references (unless they're static). \
+         With `:arena on`, values moved into the arena with `arena.alloc(value)` live for the rest \
+         of the session, so references to them are static",
+    ),
+    (
+        "E0599",
+        "If the method comes from a trait, the trait needs to be in scope, so add a `use` for it, \
+         in this cell or an earlier one. Traits from external crates need the crate to be added \
+         with `:dep`",
+    ),
+];
+
+/// Returns the built-in hint for errors with `code`, if there is one.
+pub(crate) fn builtin_hint(code: &str) -> Option<&'static str> {
+    BUILTIN_HINTS
+        .iter()
+        .find(|(hint_code, _)| *hint_code == code)
+        .map(|(_, hint)| *hint)
+}
+
+/// The hints that a context shows alongside errors, keyed by error code.
+#[derive(Clone, Debug)]
+pub(crate) struct Hints {
+    by_code: HashMap<String, String>,
+}
+
+impl Default for Hints {
+    fn default() -> Hints {
+        Hints {
+            by_code: BUILTIN_HINTS
+                .iter()
+                .map(|(code, hint)| ((*code).to_owned(), (*hint).to_owned()))
+                .collect(),
+        }
+    }
+}
+
+impl Hints {
+    /// Registers `hint` to be shown alongside errors with `code`, e.g. "E0502", replacing any
+    /// existing hint for that code, including built-in ones.
+    pub(crate) fn register(&mut self, code: &str, hint: &str) {
+        self.by_code.insert(code.to_owned(), hint.to_owned());
+    }
+
+    /// Returns the hint for errors with `code`, if there is one.
+    pub(crate) fn get(&self, code: &str) -> Option<&str> {
+        self.by_code.get(code).map(String::as_str)
+    }
+}
+
+/// Returns `code` in the form rustc uses, e.g. "E0597" for "e0597" or "597". Fails if it isn't an
+/// error code.
+fn normalize_code(code: &str) -> Result<String, Error> {
+    let digits = code.trim();
+    let digits = digits
+        .strip_prefix('E')
+        .or_else(|| digits.strip_prefix('e'))
+        .unwrap_or(digits);
+    if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        bail!(
+            "'{}' isn't an error code. Error codes look like E0597",
+            code.trim()
+        );
+    }
+    Ok(format!("E{:0>4}", digits))
+}
+
+/// Returns rustc's explanation of the error `code`, followed by our hint for it, if any.
+pub(crate) fn explain_code(config: &Config, code: &str) -> Result<String, Error> {
+    let code = normalize_code(code)?;
+    let mut command = config.tool_paths.rustc_command();
+    if !config.toolchain.is_empty() {
+        command.arg(format!("+{}", config.toolchain));
+    }
+    let output = command.arg("--explain").arg(&code).output()?;
+    if !output.status.success() {
+        bail!(
+            "rustc couldn't explain {}: {}",
+            code,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let mut explanation = String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_owned();
+    if let Some(hint) = config.hints.get(&code) {
+        explanation.push_str("\n\nIn evcxr: ");
+        explanation.push_str(hint);
+    }
+    Ok(explanation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::builtin_hint;
+    use super::normalize_code;
+    use super::Hints;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_code("E0597").unwrap(), "E0597");
+        assert_eq!(normalize_code(" e0502 ").unwrap(), "E0502");
+        assert_eq!(normalize_code("382").unwrap(), "E0382");
+        assert!(normalize_code("E").is_err());
+        assert!(normalize_code("E05970").is_err());
+        assert!(normalize_code("unused_variables").is_err());
+    }
+
+    #[test]
+    fn registered_hints() {
+        let mut hints = Hints::default();
+        assert!(hints.get("E0597").unwrap().contains(":arena on"));
+        assert_eq!(hints.get("E0597"), builtin_hint("E0597"));
+        assert_eq!(hints.get("E9998"), None);
+        hints.register("E9998", "A hint for tests");
+        hints.register("E0597", "Replaced");
+        assert_eq!(hints.get("E9998"), Some("A hint for tests"));
+        assert_eq!(hints.get("E0597"), Some("Replaced"));
+        // Other contexts' hints are unaffected.
+        assert_eq!(Hints::default().get("E9998"), None);
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Writes out a session as a standalone Cargo project, for `:export_project`. Items and
+//! dependencies are taken as they currently are, while `main` runs the statements of each cell that
+//! executed successfully, in order.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use std::path::Path;
+
+/// The error type that `main` returns if code used `?`.
+const MAIN_ERROR_TYPE: &str = "Box<dyn std::error::Error>";
+
+pub(crate) struct ExportedProject {
+    /// Lines for the `[dependencies]` section of Cargo.toml.
+    pub(crate) dependencies: Vec<String>,
+    /// Crate attributes, e.g. `#![feature(...)]`.
+    pub(crate) attributes: Vec<String>,
+    pub(crate) items: Vec<String>,
+    /// Statements for the body of `main`.
+    pub(crate) statements: Vec<String>,
+    /// Whether statements need to be run by an async runtime.
+    pub(crate) is_async: bool,
+    /// Whether statements use `?`, in which case `main` returns a `Result`.
+    pub(crate) uses_question_mark: bool,
+    pub(crate) edition: String,
+}
+
+impl ExportedProject {
+    /// Writes the project into `dir`, which must not already contain a Cargo project. `main_rs` is
+    /// what `main_rs` returned, possibly after formatting. If `lockfile` is given, it's copied into
+    /// the project, so that dependencies resolve to the same versions as they did in the session.
+    pub(crate) fn write(
+        &self,
+        dir: &Path,
+        main_rs: &str,
+        lockfile: Option<&Path>,
+    ) -> Result<(), Error> {
+        if dir.join("Cargo.toml").exists() {
+            bail!("{} already contains a Cargo project", dir.display());
+        }
+        std::fs::create_dir_all(dir.join("src"))?;
+        std::fs::write(dir.join("Cargo.toml"), self.cargo_toml(dir))?;
+        std::fs::write(dir.join("src").join("main.rs"), main_rs)?;
+        if let Some(lockfile) = lockfile {
+            if lockfile.exists() {
+                std::fs::copy(lockfile, dir.join("Cargo.lock"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cargo_toml(&self, dir: &Path) -> String {
+        let mut contents = format!(
+            "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"{}\"\n\n[dependencies]\n",
+            package_name(dir),
+            self.edition
+        );
+        for dependency in &self.dependencies {
+            contents.push_str(dependency);
+            contents.push('\n');
+        }
+        contents
+    }
+
+    pub(crate) fn main_rs(&self) -> String {
+        let mut sections = Vec::new();
+        if !self.attributes.is_empty() {
+            sections.push(self.attributes.join("\n"));
+        }
+        sections.extend(self.items.iter().cloned());
+
+        let mut body = self.statements.join("\n");
+        if self.uses_question_mark {
+            body.push_str(&format!("\nOk::<(), {}>(())", MAIN_ERROR_TYPE));
+        }
+        if self.is_async {
+            body = format!(
+                "tokio::runtime::Runtime::new()\n.unwrap()\n.block_on(async {{\n{}\n}})",
+                body
+            );
+        }
+        // User code isn't reindented, since that could change multi-line string literals. Running
+        // `cargo fmt` on the project, or exporting with `:autoformat on`, will tidy it up.
+        sections.push(format!(
+            "fn main(){} {{\n{}\n}}",
+            if self.uses_question_mark {
+                format!(" -> Result<(), {}>", MAIN_ERROR_TYPE)
+            } else {
+                String::new()
+            },
+            body
+        ));
+        let mut main_rs = sections.join("\n\n");
+        main_rs.push('\n');
+        main_rs
+    }
+}
+
+/// Returns a package name based on the name of the directory that the project is in.
+fn package_name(dir: &Path) -> String {
+    let dir_name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let name: String = dir_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "evcxr_session".to_owned()
+    } else if name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name
+    } else {
+        format!("evcxr_{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::package_name;
+    use super::ExportedProject;
+    use std::path::Path;
+
+    fn project() -> ExportedProject {
+        ExportedProject {
+            dependencies: vec!["regex = \"1.5\"".to_owned()],
+            attributes: vec![],
+            items: vec![
+                "use regex::Regex;".to_owned(),
+                "fn double(x: i32) -> i32 {\n    x * 2\n}".to_owned(),
+            ],
+            statements: vec![
+                "let x = double(21);".to_owned(),
+                "let _1 = (\nx + 1);\n::print_any::print_any(&_1);".to_owned(),
+            ],
+            is_async: false,
+            uses_question_mark: false,
+            edition: "2021".to_owned(),
+        }
+    }
+
+    #[test]
+    fn main_rs() {
+        assert_eq!(
+            project().main_rs(),
+            "use regex::Regex;\n\n\
+             fn double(x: i32) -> i32 {\n    x * 2\n}\n\n\
+             fn main() {\n\
+             let x = double(21);\n\
+             let _1 = (\nx + 1);\n::print_any::print_any(&_1);\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn main_rs_async_with_question_mark() {
+        let mut project = project();
+        project.attributes = vec!["#![allow(unused)]".to_owned()];
+        project.items.clear();
+        project.statements = vec!["let x: i32 = \"1\".parse()?;".to_owned()];
+        project.is_async = true;
+        project.uses_question_mark = true;
+        assert_eq!(
+            project.main_rs(),
+            "#![allow(unused)]\n\n\
+             fn main() -> Result<(), Box<dyn std::error::Error>> {\n\
+             tokio::runtime::Runtime::new()\n\
+             .unwrap()\n\
+             .block_on(async {\n\
+             let x: i32 = \"1\".parse()?;\n\
+             Ok::<(), Box<dyn std::error::Error>>(())\n\
+             })\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn cargo_toml() {
+        assert_eq!(
+            project().cargo_toml(Path::new("/tmp/My Session")),
+            "[package]\nname = \"my_session\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\nregex = \"1.5\"\n"
+        );
+    }
+
+    #[test]
+    fn package_names() {
+        assert_eq!(package_name(Path::new("exported")), "exported");
+        assert_eq!(package_name(Path::new("a/2022-08")), "evcxr_2022-08");
+        assert_eq!(package_name(Path::new("/")), "evcxr_session");
+    }
+}
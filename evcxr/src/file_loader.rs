@@ -0,0 +1,419 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splits a `.rs` file or a Jupyter notebook into cells, for `:load`.
+//!
+//! Notebooks are split into their code cells. Rust files are split at `// %%` marker lines if they
+//! have any. Otherwise, each run of items and statements not separated by a blank line becomes a
+//! cell. Either way, a command such as `:dep` that follows code starts a new cell, since commands
+//! need to come first in a cell.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::statement_splitter;
+use json::JsonValue;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Lines starting with this (ignoring indentation) separate cells in a `.rs` file. This is the
+/// marker used by VS Code's interactive window and by Jupytext.
+const CELL_MARKER: &str = "// %%";
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LoadedCell {
+    /// The name of the file that the cell came from, as given.
+    file: String,
+    /// For notebooks, the 1-based index of the cell in the notebook.
+    notebook_cell: Option<usize>,
+    /// The 1-based line within the file (or notebook cell) on which the code starts.
+    start_line: usize,
+    pub(crate) code: String,
+}
+
+impl LoadedCell {
+    /// Returns where the cell is, e.g. `script.rs:12` or `analysis.ipynb cell 3`.
+    pub(crate) fn location(&self) -> String {
+        match self.notebook_cell {
+            Some(cell) => format!("{} cell {}", self.file, cell),
+            None => format!("{}:{}", self.file, self.start_line),
+        }
+    }
+
+    /// Returns the position in the file of a 1-based line and column within the cell's code.
+    fn position(&self, line: usize, column: usize) -> String {
+        let line = self.start_line + line - 1;
+        match self.notebook_cell {
+            Some(cell) => format!("{} cell {}:{}:{}", self.file, cell, line, column),
+            None => format!("{}:{}:{}", self.file, line, column),
+        }
+    }
+
+    /// Returns an error reporting that this cell, the `index`th (1-based) of `count`, failed with
+    /// `error`. Positions of compilation errors are given relative to the file.
+    pub(crate) fn failure(&self, index: usize, count: usize, error: Error) -> Error {
+        let mut message = format!(
+            "Stopped at {} (cell {} of {}):",
+            self.location(),
+            index,
+            count
+        );
+        match error {
+            Error::CompilationErrors(errors) => {
+                for error in errors {
+                    let position = error
+                        .primary_spanned_message()
+                        .and_then(|spanned| spanned.span.as_ref())
+                        .map(|span| self.position(span.start_line, span.start_column))
+                        .unwrap_or_else(|| self.location());
+                    write!(message, "\n{}: {}", position, error.message()).unwrap();
+                }
+            }
+            error => write!(message, "\n{}", error).unwrap(),
+        }
+        Error::Message(message)
+    }
+}
+
+/// Reads `path`, which should be a `.rs` file or a Jupyter notebook, and splits it into cells.
+pub(crate) fn cells_from_file(path: &Path) -> Result<Vec<LoadedCell>, Error> {
+    let file = path.display().to_string();
+    let contents = read(path)?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("rs") => Ok(cells_from_rust(&file, &contents)),
+        Some("ipynb") => cells_from_notebook(&file, &contents),
+        _ => bail!(
+            "Don't know how to load {}. Expected a .rs or .ipynb file",
+            file
+        ),
+    }
+}
+
+/// Reads a prelude, which is evaluated at startup, and splits it into cells. Whatever its extension,
+/// it's treated as Rust code and commands. Unlike a file loaded with `:load`, it's only split where
+/// it has to be, so that it takes as few builds as possible.
+pub(crate) fn cells_from_prelude(path: &Path) -> Result<Vec<LoadedCell>, Error> {
+    let contents = read(path)?;
+    Ok(split_rust(&path.display().to_string(), &contents, false))
+}
+
+/// Splits part of a file, which starts on line `start_line`, in the same way as a prelude.
+pub(crate) fn cells_from_part(file: &str, code: &str, start_line: usize) -> Vec<LoadedCell> {
+    let mut cells = split_rust(file, code, false);
+    for cell in &mut cells {
+        cell.start_line += start_line - 1;
+    }
+    cells
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(error) => bail!("Failed to read {}: {}", path.display(), error),
+    }
+}
+
+fn cells_from_notebook(file: &str, contents: &str) -> Result<Vec<LoadedCell>, Error> {
+    let notebook = match json::parse(contents) {
+        Ok(notebook) => notebook,
+        Err(error) => bail!("{} isn't a valid notebook: {}", file, error),
+    };
+    let language = notebook["metadata"]["language_info"]["name"]
+        .as_str()
+        .or_else(|| notebook["metadata"]["kernelspec"]["language"].as_str());
+    if let Some(language) = language {
+        if !language.eq_ignore_ascii_case("rust") {
+            bail!("{} is a {} notebook, not a Rust one", file, language);
+        }
+    }
+    if !notebook["cells"].is_array() {
+        bail!("{} isn't a valid notebook: it has no cells", file);
+    }
+    let mut cells = Vec::new();
+    for (index, cell) in notebook["cells"].members().enumerate() {
+        if cell["cell_type"].as_str() != Some("code") {
+            continue;
+        }
+        // nbformat allows the source to be either a string or a list of lines.
+        let code = match &cell["source"] {
+            JsonValue::Array(lines) => lines.iter().filter_map(JsonValue::as_str).collect(),
+            source => source.as_str().unwrap_or_default().to_owned(),
+        };
+        if !is_blank(&code) {
+            cells.push(LoadedCell {
+                file: file.to_owned(),
+                notebook_cell: Some(index + 1),
+                start_line: 1,
+                code,
+            });
+        }
+    }
+    Ok(cells)
+}
+
+fn cells_from_rust(file: &str, contents: &str) -> Vec<LoadedCell> {
+    let has_markers = contents
+        .lines()
+        .any(|line| line.trim_start().starts_with(CELL_MARKER));
+    split_rust(file, contents, !has_markers)
+}
+
+/// Splits Rust code at `// %%` markers and before commands that follow code, and if
+/// `at_blank_lines` is set, between runs of code separated by a blank line.
+fn split_rust(file: &str, contents: &str, at_blank_lines: bool) -> Vec<LoadedCell> {
+    let mut cells = Vec::new();
+    let mut add_cell = |start_byte: usize, code: &str| {
+        if !is_blank(code) {
+            cells.push(LoadedCell {
+                file: file.to_owned(),
+                notebook_cell: None,
+                start_line: contents[..start_byte].matches('\n').count() + 1,
+                code: code.to_owned(),
+            });
+        }
+    };
+    for (start_byte, section) in split_at_markers(contents) {
+        for (offset, chunk) in split_at_commands(section) {
+            if at_blank_lines {
+                for (chunk_offset, code) in split_at_blank_lines(chunk) {
+                    add_cell(start_byte + offset + chunk_offset, code);
+                }
+            } else {
+                add_cell(start_byte + offset, chunk);
+            }
+        }
+    }
+    cells
+}
+
+/// Splits `code` at `// %%` marker lines, which are dropped. Returns the byte offset of each part
+/// along with the part.
+fn split_at_markers(code: &str) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in code.split_inclusive('\n') {
+        if line.trim_start().starts_with(CELL_MARKER) {
+            parts.push((start, &code[start..offset]));
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    parts.push((start, &code[start..]));
+    parts
+}
+
+/// Splits `code` before each command that follows some Rust code. Returns the byte offset of each
+/// part along with the part.
+fn split_at_commands(code: &str) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    let mut seen_code = false;
+    for line in code.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if is_command(trimmed) {
+            if seen_code {
+                parts.push((start, &code[start..offset]));
+                start = offset;
+                seen_code = false;
+            }
+        } else if !trimmed.trim_end().is_empty() && !trimmed.starts_with("//") {
+            seen_code = true;
+        }
+        offset += line.len();
+    }
+    parts.push((start, &code[start..]));
+    parts
+}
+
+/// Splits `code`, which may start with some commands, after each statement or item that's followed
+/// by a blank line. Returns the byte offset of each part along with the part.
+fn split_at_blank_lines(code: &str) -> Vec<(usize, &str)> {
+    // Any commands stay with the code that follows them.
+    let mut code_start = 0;
+    for line in code.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if !is_command(trimmed) && !trimmed.is_empty() && !trimmed.starts_with("//") {
+            break;
+        }
+        code_start += line.len();
+    }
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let statements = statement_splitter::split_into_statements(&code[code_start..]);
+    for statement in statements.iter().take(statements.len().saturating_sub(1)) {
+        let trailing = statement.trailing();
+        let trailing_start =
+            code_start + statement.start_byte + statement.code.len() - trailing.len();
+        // The first line of `trailing` is the end of the statement's last line, so only later lines
+        // can be blank. We split after the last blank line, so that any comments go with the
+        // following statement.
+        let mut split = None;
+        let mut offset = 0;
+        for line in trailing.split_inclusive('\n') {
+            if offset > 0 && line.ends_with('\n') && line.trim().is_empty() {
+                split = Some(trailing_start + offset + line.len());
+            }
+            offset += line.len();
+        }
+        if let Some(split) = split {
+            parts.push((start, &code[start..split]));
+            start = split;
+        }
+    }
+    parts.push((start, &code[start..]));
+    parts
+}
+
+fn is_command(line: &str) -> bool {
+    line.starts_with(':') && !line.starts_with("::")
+}
+
+/// Returns whether `code` has nothing but whitespace and line comments.
+fn is_blank(code: &str) -> bool {
+    code.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with("//")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cells_from_notebook;
+    use super::cells_from_rust;
+    use super::split_rust;
+    use super::LoadedCell;
+    use crate::errors::Error;
+
+    fn codes(cells: &[LoadedCell]) -> Vec<(String, &str)> {
+        cells
+            .iter()
+            .map(|cell| (cell.location(), cell.code.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn rust_file_with_markers() {
+        let cells = cells_from_rust(
+            "a.rs",
+            ":dep regex = \"1\"\n\
+             // %% Setup\n\
+             fn double(x: i32) -> i32 {\n\n    x * 2\n}\n\
+             let x = double(2);\n\
+             // %%\n\
+             // %%\n\
+             x\n",
+        );
+        assert_eq!(
+            codes(&cells),
+            vec![
+                ("a.rs:1".to_owned(), ":dep regex = \"1\"\n"),
+                (
+                    "a.rs:3".to_owned(),
+                    "fn double(x: i32) -> i32 {\n\n    x * 2\n}\nlet x = double(2);\n"
+                ),
+                ("a.rs:10".to_owned(), "x\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rust_file_without_markers() {
+        let cells = cells_from_rust(
+            "a.rs",
+            ":dep regex = \"1\"\n\n\
+             use regex::Regex;\n\
+             fn double(x: i32) -> i32 {\n\n    x * 2\n}\n\n\
+             // Use it.\n\
+             let x = double(2); // Four.\n\
+             :vars\n\
+             x\n\n\n",
+        );
+        assert_eq!(
+            codes(&cells),
+            vec![
+                (
+                    "a.rs:1".to_owned(),
+                    ":dep regex = \"1\"\n\nuse regex::Regex;\n\
+                     fn double(x: i32) -> i32 {\n\n    x * 2\n}\n\n"
+                ),
+                (
+                    "a.rs:9".to_owned(),
+                    "// Use it.\nlet x = double(2); // Four.\n"
+                ),
+                ("a.rs:11".to_owned(), ":vars\nx\n\n\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn prelude() {
+        let cells = split_rust(
+            "init.rs",
+            ":dep regex = \"1\"\nuse regex::Regex;\n\nfn a() {}\n\nfn b() {}\n\
+             :dep log = \"0.4\"\nfn c() {}\n",
+            false,
+        );
+        assert_eq!(
+            codes(&cells),
+            vec![
+                (
+                    "init.rs:1".to_owned(),
+                    ":dep regex = \"1\"\nuse regex::Regex;\n\nfn a() {}\n\nfn b() {}\n"
+                ),
+                ("init.rs:7".to_owned(), ":dep log = \"0.4\"\nfn c() {}\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn notebook() {
+        let cells = cells_from_notebook(
+            "a.ipynb",
+            r##"{
+                "metadata": {"language_info": {"name": "Rust"}},
+                "cells": [
+                    {"cell_type": "markdown", "source": ["# Title"]},
+                    {"cell_type": "code", "source": ["let x = 1;\n", "x + 1"]},
+                    {"cell_type": "code", "source": ""},
+                    {"cell_type": "code", "source": ":vars"}
+                ]
+            }"##,
+        )
+        .unwrap();
+        assert_eq!(
+            codes(&cells),
+            vec![
+                ("a.ipynb cell 2".to_owned(), "let x = 1;\nx + 1"),
+                ("a.ipynb cell 4".to_owned(), ":vars"),
+            ]
+        );
+        assert_eq!(
+            cells[0]
+                .failure(1, 2, Error::Message("Boom".to_owned()))
+                .to_string(),
+            "Stopped at a.ipynb cell 2 (cell 1 of 2):\nBoom"
+        );
+
+        let error = cells_from_notebook(
+            "a.ipynb",
+            r#"{"metadata": {"kernelspec": {"language": "python"}}, "cells": []}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "a.ipynb is a python notebook, not a Rust one"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interrupting user code. Rust code can't be safely stopped at an arbitrary point, so when panics
+//! are caught, i.e. with `:preserve_vars_on_panic 1`, the first interrupt sends SIGINT to the
+//! subprocess, or Ctrl-Break on Windows. Its handler asks the code to stop, which it does between
+//! statements, or at the start of an iteration of a loop, by unwinding, as if it had panicked, so
+//! variables that weren't moved into the code are kept. Loops in closures and nested functions
+//! don't stop, since those may run on another thread or be const. Code that doesn't get to a
+//! point where it can stop, e.g. a long call into a library, needs a second interrupt, which kills
+//! the subprocess. So does any interrupt when panics aren't caught, or when the request to stop
+//! can't be sent, e.g. on Windows when we don't have a console. Evaluation then fails and the
+//! subprocess is restarted, just as after a crash, so variables are lost unless `:auto_recover` is
+//! on.
+
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxNode;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Lets other threads interrupt code that's running in a context, e.g. in response to a Jupyter
+/// interrupt request. Cloning gives another handle for the same context.
+#[derive(Clone, Default)]
+pub struct InterruptHandle {
+    inner: Arc<Inner>,
+}
+
+// Only uses atomics, so that it can be accessed from a signal handler.
+#[derive(Default)]
+struct Inner {
+    /// The ID of the local subprocess, or 0 if user code runs elsewhere.
+    process_id: AtomicU32,
+    /// Whether user code is currently running in the subprocess.
+    running: AtomicBool,
+    /// Whether the code that's running can stop when asked to, rather than needing to be killed.
+    graceful: AtomicBool,
+    /// Whether the code that's running, or that most recently ran, was interrupted.
+    interrupted: AtomicBool,
+}
+
+impl Inner {
+    fn interrupt(&self) -> bool {
+        let process_id = self.process_id.load(Ordering::SeqCst);
+        if process_id == 0 || !self.running.load(Ordering::SeqCst) {
+            return false;
+        }
+        let already_interrupted = self.interrupted.swap(true, Ordering::SeqCst);
+        if self.graceful.load(Ordering::SeqCst) && !already_interrupted {
+            request_stop(process_id)
+        } else {
+            kill(process_id)
+        }
+    }
+}
+
+impl InterruptHandle {
+    /// Interrupts user code, if any is running. Returns whether there was code to interrupt. If
+    /// the code can stop when asked to, it's asked to, and only a second interrupt kills the
+    /// subprocess. Interrupting isn't supported when code runs on a remote machine or for another
+    /// target.
+    pub fn interrupt(&self) -> bool {
+        self.inner.interrupt()
+    }
+
+    /// Makes SIGINT, or Ctrl-C in the console on Windows, interrupt user code, rather than
+    /// terminating the current process. Only the handle passed here most recently is interrupted.
+    /// Does nothing on other platforms without signals.
+    pub fn interrupt_on_sigint(&self) {
+        #[cfg(any(all(unix, not(target_os = "freebsd")), windows))]
+        {
+            let previous = SIGINT_TARGET.swap(
+                Arc::into_raw(Arc::clone(&self.inner)) as *mut Inner,
+                Ordering::SeqCst,
+            );
+            if previous.is_null() {
+                #[cfg(unix)]
+                {
+                    use sig::ffi::Sig;
+                    signal!(Sig::INT, sigint_handler);
+                }
+                #[cfg(windows)]
+                unsafe {
+                    winapi::um::consoleapi::SetConsoleCtrlHandler(Some(console_ctrl_handler), 1);
+                }
+            }
+            // Any previous target is leaked, since the signal handler might still be using it.
+        }
+    }
+
+    pub(crate) fn set_process_id(&self, process_id: u32) {
+        self.inner.process_id.store(process_id, Ordering::SeqCst);
+    }
+
+    /// Records that user code is running. `graceful` is whether it can stop when asked to.
+    pub(crate) fn execution_started(&self, graceful: bool) {
+        self.inner.graceful.store(graceful, Ordering::SeqCst);
+        self.inner.interrupted.store(false, Ordering::SeqCst);
+        self.inner.running.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether the code that's running has been asked to stop.
+    pub(crate) fn interrupt_requested(&self) -> bool {
+        self.inner.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Records that user code is no longer running. Returns whether it was interrupted.
+    pub(crate) fn execution_finished(&self) -> bool {
+        self.inner.running.store(false, Ordering::SeqCst);
+        self.inner.interrupted.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(any(all(unix, not(target_os = "freebsd")), windows))]
+static SIGINT_TARGET: std::sync::atomic::AtomicPtr<Inner> =
+    std::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
+
+#[cfg(any(all(unix, not(target_os = "freebsd")), windows))]
+extern "C" fn sigint_handler(_signal: i32) {
+    let target = SIGINT_TARGET.load(Ordering::SeqCst);
+    if !target.is_null() {
+        // Safety: targets are never freed once set.
+        unsafe { &*target }.interrupt();
+    }
+}
+
+/// Windows calls console control handlers on a thread of their own. Returning true stops the
+/// default handler from terminating us.
+#[cfg(windows)]
+unsafe extern "system" fn console_ctrl_handler(
+    ctrl_type: winapi::shared::minwindef::DWORD,
+) -> winapi::shared::minwindef::BOOL {
+    if ctrl_type != winapi::um::wincon::CTRL_C_EVENT {
+        return 0;
+    }
+    sigint_handler(0);
+    1
+}
+
+#[cfg(unix)]
+fn request_stop(process_id: u32) -> bool {
+    unsafe { libc::kill(process_id as libc::pid_t, libc::SIGINT) == 0 }
+}
+
+/// The subprocess is in a process group of its own, so we can send Ctrl-Break to just it. This
+/// only works if we share a console with it.
+#[cfg(windows)]
+fn request_stop(process_id: u32) -> bool {
+    let sent = unsafe {
+        winapi::um::wincon::GenerateConsoleCtrlEvent(
+            winapi::um::wincon::CTRL_BREAK_EVENT,
+            process_id,
+        )
+    };
+    sent != 0 || kill(process_id)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn request_stop(process_id: u32) -> bool {
+    kill(process_id)
+}
+
+#[cfg(unix)]
+fn kill(process_id: u32) -> bool {
+    unsafe { libc::kill(process_id as libc::pid_t, libc::SIGKILL) == 0 }
+}
+
+#[cfg(windows)]
+fn kill(process_id: u32) -> bool {
+    use winapi::um::handleapi;
+    use winapi::um::processthreadsapi;
+    use winapi::um::winnt;
+    unsafe {
+        let process = processthreadsapi::OpenProcess(winnt::PROCESS_TERMINATE, 0, process_id);
+        if process.is_null() {
+            return false;
+        }
+        let terminated = processthreadsapi::TerminateProcess(process, 1) != 0;
+        handleapi::CloseHandle(process);
+        terminated
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill(_process_id: u32) -> bool {
+    false
+}
+
+/// Returns where checks for interrupts should be inserted in `statement`, a statement of a cell:
+/// just after the opening brace of each loop body, as byte offsets from the start of the
+/// statement. Loops in closures, nested functions and const contexts are skipped, since calling
+/// the check there would either unwind the wrong thread or not compile.
+pub(crate) fn loop_body_starts(statement: &SyntaxNode) -> Vec<usize> {
+    let statement_start = usize::from(statement.text_range().start());
+    statement
+        .descendants()
+        .filter(|node| {
+            matches!(
+                node.kind(),
+                SyntaxKind::LOOP_EXPR | SyntaxKind::WHILE_EXPR | SyntaxKind::FOR_EXPR
+            )
+        })
+        .filter(|node| {
+            !node
+                .ancestors()
+                .take_while(|ancestor| ancestor != statement)
+                .any(|ancestor| stops_interrupt_checks(&ancestor))
+        })
+        .filter_map(|node| {
+            // The body is the last block, since a loop's condition or iterator may be one too.
+            let body = node
+                .children()
+                .filter(|child| child.kind() == SyntaxKind::BLOCK_EXPR)
+                .last()?;
+            let l_curly = body
+                .descendants_with_tokens()
+                .filter_map(|element| element.into_token())
+                .find(|token| token.kind() == SyntaxKind::L_CURLY)?;
+            Some(usize::from(l_curly.text_range().end()) - statement_start)
+        })
+        .collect()
+}
+
+fn stops_interrupt_checks(node: &SyntaxNode) -> bool {
+    match node.kind() {
+        SyntaxKind::CLOSURE_EXPR
+        | SyntaxKind::FN
+        | SyntaxKind::CONST
+        | SyntaxKind::STATIC
+        | SyntaxKind::CONST_ARG
+        | SyntaxKind::ARRAY_EXPR => true,
+        // A const block.
+        SyntaxKind::BLOCK_EXPR => node
+            .first_token()
+            .map_or(false, |token| token.kind() == SyntaxKind::CONST_KW),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::loop_body_starts;
+    use super::InterruptHandle;
+    use crate::statement_splitter::split_into_statements;
+
+    #[test]
+    fn test_loop_body_starts() {
+        let mark_loop_bodies = |code: &str| {
+            let statement = split_into_statements(code).remove(0).node;
+            let mut marked = statement.text().to_string();
+            for offset in loop_body_starts(&statement).into_iter().rev() {
+                marked.insert(offset, '@');
+            }
+            marked
+        };
+        assert_eq!(
+            mark_loop_bodies("for i in 0..3 { 'a: loop { while i > 0 {} } }"),
+            "for i in 0..3 {@ 'a: loop {@ while i > 0 {@} } }"
+        );
+        assert_eq!(mark_loop_bodies("for x in {v} {}"), "for x in {v} {@}");
+        // Loops that may run on another thread or in a const context are left alone.
+        assert_eq!(
+            mark_loop_bodies("let f = || loop {};"),
+            "let f = || loop {};"
+        );
+        assert_eq!(
+            mark_loop_bodies("let a = [0; { let mut n = 0; while n < 3 { n += 1; } n }];"),
+            "let a = [0; { let mut n = 0; while n < 3 { n += 1; } n }];"
+        );
+    }
+
+    #[test]
+    fn only_interrupts_running_code() {
+        let mut child = std::process::Command::new(if cfg!(windows) { "cmd" } else { "cat" })
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let handle = InterruptHandle::default();
+        assert!(!handle.interrupt());
+        handle.set_process_id(child.id());
+        assert!(!handle.interrupt());
+        handle.execution_started(false);
+        assert!(handle.clone().interrupt());
+        assert!(handle.interrupt_requested());
+        assert!(handle.execution_finished());
+        assert!(!child.wait().unwrap().success());
+        handle.execution_started(false);
+        assert!(!handle.execution_finished());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn second_interrupt_kills() {
+        // Ignores SIGINT, like user code that doesn't get to the end of a statement.
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "trap '' INT; cat"])
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(200));
+        pause();
+        let handle = InterruptHandle::default();
+        handle.set_process_id(child.id());
+        handle.execution_started(true);
+        assert!(!handle.interrupt_requested());
+        assert!(handle.interrupt());
+        assert!(handle.interrupt_requested());
+        pause();
+        assert!(child.try_wait().unwrap().is_none());
+        assert!(handle.interrupt());
+        assert!(handle.execution_finished());
+        assert!(!child.wait().unwrap().success());
+    }
+}
@@ -13,6 +13,9 @@
 // limitations under the License.
 
 use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::TextRange;
 
 /// Returns the name of an item if it has one.
 pub(crate) fn item_name(item: &ast::Item) -> Option<String> {
@@ -36,3 +39,179 @@ fn item_ident(item: &ast::Item) -> Option<ast::Name> {
         _ => None,
     }
 }
+
+/// Returns what determines the layout of `item` if it's a struct, enum or union: its tokens, apart
+/// from comments, visibility, attributes other than `#[repr(...)]` and trailing commas. Definitions
+/// with the same signature have the same layout, provided that the types that they refer to do too.
+pub(crate) fn layout_signature(item: &ast::Item) -> Option<String> {
+    if !matches!(
+        item,
+        ast::Item::Struct(_) | ast::Item::Enum(_) | ast::Item::Union(_)
+    ) {
+        return None;
+    }
+    let ignored: Vec<TextRange> = item
+        .syntax()
+        .descendants()
+        .filter(|node| {
+            ast::Visibility::can_cast(node.kind())
+                || (ast::Attr::can_cast(node.kind()) && !is_repr_attribute(&node.to_string()))
+        })
+        .map(|node| node.text_range())
+        .collect();
+    let tokens: Vec<String> = item
+        .syntax()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| {
+            !token.kind().is_trivia()
+                && !ignored
+                    .iter()
+                    .any(|range| range.contains_range(token.text_range()))
+        })
+        .map(|token| token.text().to_owned())
+        .collect();
+    let mut signature = Vec::with_capacity(tokens.len());
+    for (index, token) in tokens.iter().enumerate() {
+        if token != "," || tokens.get(index + 1).map(String::as_str) != Some("}") {
+            signature.push(token.as_str());
+        }
+    }
+    Some(signature.join(" "))
+}
+
+/// Returns the layout signature of the struct, enum or union called `name` in `code`.
+pub(crate) fn layout_signature_in(code: &str, name: &str) -> Option<String> {
+    find_item(code, name).and_then(|item| layout_signature(&item))
+}
+
+/// Returns the names of the types that the fields of `item`, a struct, enum or union, refer to,
+/// including those nested in other types, e.g. `Foo` and `u8` for `(Foo, &[u8])`. Returns `None` if
+/// the layout of `item` could depend on anything else, e.g. if it's generic, or refers to a type by
+/// a path or to a constant.
+pub(crate) fn layout_dependencies(item: &ast::Item) -> Option<Vec<String>> {
+    layout_signature(item)?;
+    let mut dependencies = Vec::new();
+    for node in item.syntax().descendants() {
+        if ast::GenericParamList::can_cast(node.kind()) {
+            return None;
+        }
+        if let Some(expr) = ast::Expr::cast(node.clone()) {
+            // e.g. an array length or an enum discriminant.
+            if !matches!(expr, ast::Expr::Literal(_)) {
+                return None;
+            }
+        }
+        match ast::Type::cast(node) {
+            Some(ast::Type::PathType(path_type)) => {
+                let name = path_type.path()?.as_single_name_ref()?;
+                dependencies.push(name.text().to_string());
+            }
+            Some(
+                ast::Type::ArrayType(_)
+                | ast::Type::NeverType(_)
+                | ast::Type::ParenType(_)
+                | ast::Type::PtrType(_)
+                | ast::Type::RefType(_)
+                | ast::Type::SliceType(_)
+                | ast::Type::TupleType(_),
+            )
+            | None => {}
+            Some(_) => return None,
+        }
+    }
+    Some(dependencies)
+}
+
+/// Returns the layout dependencies of the struct, enum or union called `name` in `code`.
+pub(crate) fn layout_dependencies_in(code: &str, name: &str) -> Option<Vec<String>> {
+    find_item(code, name).and_then(|item| layout_dependencies(&item))
+}
+
+/// Returns whether `name` is that of a primitive type, whose layout can't change.
+pub(crate) fn is_primitive_type(name: &str) -> bool {
+    matches!(
+        name,
+        "bool"
+            | "char"
+            | "str"
+            | "f32"
+            | "f64"
+            | "i8"
+            | "i16"
+            | "i32"
+            | "i64"
+            | "i128"
+            | "isize"
+            | "u8"
+            | "u16"
+            | "u32"
+            | "u64"
+            | "u128"
+            | "usize"
+    )
+}
+
+fn find_item(code: &str, name: &str) -> Option<ast::Item> {
+    SourceFile::parse(code)
+        .syntax_node()
+        .children()
+        .filter_map(ast::Item::cast)
+        .find(|item| item_name(item).as_deref() == Some(name))
+}
+
+fn is_repr_attribute(attribute: &str) -> bool {
+    let attribute: String = attribute.split_whitespace().collect();
+    attribute.starts_with("#[repr(")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::layout_dependencies_in;
+    use super::layout_signature_in;
+
+    #[test]
+    fn layout_signatures() {
+        let signature = |code| layout_signature_in(code, "Foo");
+        let original = signature("struct Foo { x: i32, y: Vec<String> }");
+        assert!(original.is_some());
+        assert_eq!(
+            signature(
+                "/// Docs.\n#[derive(Debug, Clone)]\npub struct Foo {\n    // Comment\n    pub x: \
+                 i32,\n    y: Vec<String>,\n}\nimpl Foo {}"
+            ),
+            original
+        );
+        assert_ne!(
+            signature("struct Foo { y: Vec<String>, x: i32 }"),
+            signature("struct Foo { x: i32, y: Vec<String> }")
+        );
+        assert_ne!(
+            signature("#[repr(C)] enum Foo { A, B }"),
+            signature("enum Foo { A, B }")
+        );
+        assert_eq!(signature("fn Foo() {}"), None);
+    }
+
+    #[test]
+    fn layout_dependencies() {
+        let dependencies = |code| layout_dependencies_in(code, "Foo");
+        assert_eq!(
+            dependencies("struct Foo { a: (Bar, &'static [u8]), b: [i32; 4] }"),
+            Some(vec!["Bar".to_owned(), "u8".to_owned(), "i32".to_owned()])
+        );
+        assert_eq!(
+            dependencies("enum Foo { A(u8), B { x: Bar } }"),
+            Some(vec!["u8".to_owned(), "Bar".to_owned()])
+        );
+        assert_eq!(
+            dependencies("struct Foo(Vec<u8>);"),
+            Some(vec!["Vec".to_owned(), "u8".to_owned()])
+        );
+        assert_eq!(dependencies("struct Foo<T> { x: T }"), None);
+        assert_eq!(dependencies("struct Foo { x: std::string::String }"), None);
+        assert_eq!(dependencies("struct Foo { x: [u8; LEN] }"), None);
+        assert_eq!(dependencies("enum Foo { A = START, B }"), None);
+        assert_eq!(dependencies("struct Foo { f: fn() }"), None);
+    }
+}
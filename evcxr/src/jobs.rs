@@ -0,0 +1,160 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking of background jobs started with `:bg`. Each job runs on its own thread in the
+//! subprocess, which tags the job's output with its ID. We pass tagged output on as soon as it
+//! arrives, even between evaluations.
+
+use crate::evcxr_internal_runtime;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+use std::time::Instant;
+
+struct Job {
+    description: String,
+    started: Instant,
+    cancelling: bool,
+}
+
+pub(crate) struct Jobs {
+    next_id: u64,
+    running: BTreeMap<u64, Job>,
+}
+
+impl Default for Jobs {
+    fn default() -> Jobs {
+        Jobs {
+            next_id: 1,
+            running: BTreeMap::new(),
+        }
+    }
+}
+
+impl Jobs {
+    /// Records a job that's about to be started and returns its ID.
+    pub(crate) fn start(&mut self, description: &str) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.running.insert(
+            id,
+            Job {
+                description: description.to_owned(),
+                started: Instant::now(),
+                cancelling: false,
+            },
+        );
+        id
+    }
+
+    /// Forgets a job, e.g. because the code to start it failed to compile.
+    pub(crate) fn abandon(&mut self, id: u64) {
+        self.running.remove(&id);
+    }
+
+    pub(crate) fn is_running(&self, id: u64) -> bool {
+        self.running.contains_key(&id)
+    }
+
+    pub(crate) fn set_cancelling(&mut self, id: u64) {
+        if let Some(job) = self.running.get_mut(&id) {
+            job.cancelling = true;
+        }
+    }
+
+    /// Forgets all jobs, since the process that was running them has gone. IDs aren't reused.
+    pub(crate) fn clear(&mut self) {
+        self.running.clear();
+    }
+
+    /// If `line` came from a background job, returns what to show the user in its place.
+    pub(crate) fn process_line(&mut self, line: &str) -> Option<String> {
+        if let Some(rest) = line.strip_prefix(evcxr_internal_runtime::JOB_OUTPUT) {
+            let (id, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            Some(format!("[job {}] {}", id, text))
+        } else if let Some(rest) = line.strip_prefix(evcxr_internal_runtime::JOB_FINISHED) {
+            let (id, outcome) = rest.split_once(' ').unwrap_or((rest, ""));
+            let elapsed = id
+                .parse()
+                .ok()
+                .and_then(|id| self.running.remove(&id))
+                .map(|job| format!(" after {}", format_duration(job.started.elapsed())))
+                .unwrap_or_default();
+            Some(format!("[job {}] {}{}", id, outcome, elapsed))
+        } else {
+            None
+        }
+    }
+
+    /// Returns a table of running jobs, as shown by `:jobs`.
+    pub(crate) fn summary(&self) -> String {
+        if self.running.is_empty() {
+            return "No background jobs\n".to_owned();
+        }
+        let mut summary = String::new();
+        for (id, job) in &self.running {
+            writeln!(
+                summary,
+                "{:<4} {:<10} {:>8}  {}",
+                id,
+                if job.cancelling {
+                    "cancelling"
+                } else {
+                    "running"
+                },
+                format_duration(job.started.elapsed()),
+                job.description
+            )
+            .unwrap();
+        }
+        summary
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Jobs;
+
+    #[test]
+    fn lifecycle() {
+        let mut jobs = Jobs::default();
+        assert_eq!(jobs.summary(), "No background jobs\n");
+        let first = jobs.start("fetch(url)");
+        let second = jobs.start("loop {}");
+        assert_eq!((first, second), (1, 2));
+        jobs.set_cancelling(second);
+        let summary = jobs.summary();
+        assert!(summary.starts_with("1    running        0.0s  fetch(url)\n"));
+        assert!(summary.contains("2    cancelling     0.0s  loop {}\n"));
+
+        assert_eq!(
+            jobs.process_line("EVCXR_JOB_OUTPUT 1 Fetched 3 pages"),
+            Some("[job 1] Fetched 3 pages".to_owned())
+        );
+        assert_eq!(jobs.process_line("Fetched 3 pages"), None);
+        assert_eq!(
+            jobs.process_line("EVCXR_JOB_FINISHED 1 done"),
+            Some("[job 1] done after 0.0s".to_owned())
+        );
+        assert!(!jobs.is_running(first));
+        assert!(jobs.is_running(second));
+
+        jobs.clear();
+        assert_eq!(jobs.start("x"), 3);
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:size`, which reports the size and alignment of a type. We evaluate a small probe
+//! that reports them as content with a MIME type of our own, so that the result is for whatever
+//! target code runs on and isn't affected by settings like `:fmt`.
+
+use crate::EvalOutputs;
+
+/// The MIME type of the content that the probe sends.
+const LAYOUT_MIME_TYPE: &str = "text/x-evcxr-layout";
+
+/// The size and alignment of a type, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TypeLayout {
+    pub size: usize,
+    pub align: usize,
+}
+
+impl TypeLayout {
+    /// Returns code that, when evaluated, reports the layout of `type_name`.
+    pub(crate) fn probe_code(type_name: &str) -> String {
+        format!(
+            "println!(\"EVCXR_BEGIN_CONTENT {}\\n{{}} {{}}\\nEVCXR_END_CONTENT\", \
+             ::std::mem::size_of::<{ty}>(), ::std::mem::align_of::<{ty}>());",
+            LAYOUT_MIME_TYPE,
+            ty = type_name
+        )
+    }
+
+    /// Returns the layout reported by evaluating `probe_code`.
+    pub(crate) fn from_outputs(outputs: &EvalOutputs) -> Option<TypeLayout> {
+        let (size, align) = outputs.get(LAYOUT_MIME_TYPE)?.trim().split_once(' ')?;
+        Some(TypeLayout {
+            size: size.parse().ok()?,
+            align: align.parse().ok()?,
+        })
+    }
+
+    pub(crate) fn to_text(self) -> String {
+        format!(
+            "size: {} {}, align: {} {}",
+            self.size,
+            bytes(self.size),
+            self.align,
+            bytes(self.align)
+        )
+    }
+}
+
+fn bytes(count: usize) -> &'static str {
+    if count == 1 {
+        "byte"
+    } else {
+        "bytes"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TypeLayout;
+    use super::LAYOUT_MIME_TYPE;
+    use crate::Content;
+    use crate::EvalOutputs;
+
+    #[test]
+    fn outputs() {
+        let mut outputs = EvalOutputs::new();
+        assert_eq!(TypeLayout::from_outputs(&outputs), None);
+        outputs.content_by_mime_type.insert(
+            LAYOUT_MIME_TYPE.to_owned(),
+            Content::Text("24 8".to_owned()),
+        );
+        let layout = TypeLayout::from_outputs(&outputs).unwrap();
+        assert_eq!(layout, TypeLayout { size: 24, align: 8 });
+        assert_eq!(layout.to_text(), "size: 24 bytes, align: 8 bytes");
+        assert_eq!(
+            TypeLayout { size: 0, align: 1 }.to_text(),
+            "size: 0 bytes, align: 1 byte"
+        );
+    }
+
+    #[test]
+    fn probe() {
+        assert_eq!(
+            TypeLayout::probe_code("Vec<u8>"),
+            "println!(\"EVCXR_BEGIN_CONTENT text/x-evcxr-layout\\n{} {}\\nEVCXR_END_CONTENT\", \
+             ::std::mem::size_of::<Vec<u8>>(), ::std::mem::align_of::<Vec<u8>>());"
+        );
+    }
+}
@@ -18,31 +18,130 @@ extern crate sig;
 
 #[macro_use]
 mod errors;
+mod about;
+mod bench;
 mod cargo_metadata;
+mod cargo_retry;
+mod cell_dependencies;
+mod cell_diff;
 mod child_process;
+mod clippy;
 mod code_block;
+mod codegen;
+mod comm;
 mod command_context;
+mod command_info;
+mod content;
 mod crash_guard;
 mod crate_config;
+mod crate_search;
+mod dataframes;
+mod debugger;
+mod dep_cache;
+mod dep_flags;
+mod dev_crate;
+mod display_update;
+mod docs;
+mod environment;
 mod eval_context;
+mod eval_event;
+mod eval_session;
 #[allow(dead_code)]
 mod evcxr_internal_runtime;
+mod explain;
+mod export;
+mod file_loader;
+mod interrupt;
 mod item;
+mod jobs;
+mod layout;
+mod limits;
+mod memory;
+mod metrics;
+mod migration;
 mod module;
+mod native_libs;
+mod number_format;
+mod output_limit;
+mod panic_report;
+mod profiler;
+mod profiles;
+mod reactive;
+mod recovery;
+mod remote;
+mod result_history;
 mod runtime;
 mod rust_analyzer;
+mod rustfmt;
+mod sandbox;
+mod sarif;
+mod session;
+mod session_manager;
+mod sql;
 mod statement_splitter;
+mod stdin_relay;
+mod test_runner;
+pub mod test_support;
+mod timing;
+mod toolchain;
 mod use_trees;
+mod why_slow;
 
+pub use crate::cell_diff::CellReeval;
+pub use crate::comm::CommMessage;
+pub use crate::comm::CommMessageKind;
+pub use crate::comm::Comms;
 pub use crate::command_context::CommandContext;
+pub use crate::command_info::ArgumentInfo;
+pub use crate::command_info::ArgumentKind;
+pub use crate::command_info::CommandInfo;
+pub use crate::content::Content;
+pub use crate::debugger::Debugger;
+pub use crate::display_update::DisplayUpdate;
+pub use crate::errors::group_errors;
 pub use crate::errors::CompilationError;
 pub use crate::errors::Error;
+pub use crate::errors::ErrorGroup;
+pub use crate::errors::Span;
 pub use crate::eval_context::EvalCallbacks;
 pub use crate::eval_context::EvalContext;
+pub use crate::eval_context::EvalContextConfig;
 pub use crate::eval_context::EvalContextOutputs;
 pub use crate::eval_context::EvalOutputs;
+pub use crate::eval_event::EvalEvent;
+pub use crate::eval_event::EvalEventSink;
+pub use crate::eval_session::EvalSession;
+pub use crate::eval_session::RequestId;
+pub use crate::eval_session::SessionEvent;
+pub use crate::interrupt::InterruptHandle;
+pub use crate::layout::TypeLayout;
+pub use crate::limits::ResourceLimits;
+pub use crate::memory::MemoryStats;
+pub use crate::metrics::Metrics;
+pub use crate::remote::RemoteTransport;
 pub use crate::runtime::runtime_hook;
+pub use crate::sandbox::SandboxPolicy;
+pub use crate::session::Completion;
+pub use crate::session::Interrupter;
+pub use crate::session::Layout;
+pub use crate::session::Session;
+pub use crate::session::SessionConfig;
+pub use crate::session::SessionError;
+pub use crate::session::SessionOutputs;
+pub use crate::session::Variable;
+pub use crate::session_manager::SessionId;
+pub use crate::session_manager::SessionManager;
+pub use crate::test_runner::TestOutcome;
+pub use crate::test_runner::TestReport;
+pub use crate::test_runner::TestResult;
+pub use crate::timing::RunStatistics;
+pub use crate::timing::Timing;
+pub use crate::toolchain::ToolchainError;
+pub use crate::toolchain::ToolchainErrorKind;
 pub use rust_analyzer::Completions;
+pub use rust_analyzer::Diagnostic;
+pub use rust_analyzer::Location;
+pub use rust_analyzer::SemanticToken;
 
 /// Return the directory that evcxr tools should use for their configuration.
 ///
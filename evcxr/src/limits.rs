@@ -0,0 +1,170 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::about::format_bytes;
+use crate::errors::Error;
+use std::process::Command;
+use std::time::Duration;
+
+/// Prefix of the message that Rust's default allocation error handler writes to stderr before
+/// aborting.
+pub(crate) const ALLOCATION_FAILURE_PREFIX: &str = "memory allocation of ";
+
+/// Limits on the resources that the subprocess running user code may use. These are enforced with
+/// rlimits on unix and with a job object on Windows. They aren't available on other platforms.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Maximum size in bytes of the subprocess's address space, or on Windows, of the memory that
+    /// it has committed. This includes the code of user code and its dependencies, so shouldn't be
+    /// set too low.
+    pub max_memory: Option<u64>,
+    /// Maximum CPU time that the subprocess may use. This is a total for the lifetime of the
+    /// subprocess, not per evaluation. The subprocess is restarted when it's exceeded, which resets
+    /// the total.
+    pub max_cpu_time: Option<Duration>,
+}
+
+impl ResourceLimits {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.max_memory.is_none() && self.max_cpu_time.is_none()
+    }
+
+    /// The CPU time limit in whole seconds, which is the granularity of rlimits.
+    pub(crate) fn max_cpu_secs(&self) -> Option<u64> {
+        self.max_cpu_time.map(|duration| duration.as_secs().max(1))
+    }
+
+    /// Arranges for the process started by `command` to be subject to these limits.
+    #[cfg(unix)]
+    pub(crate) fn apply(&self, command: &mut Command) -> Result<(), Error> {
+        use std::os::unix::process::CommandExt;
+        let max_memory = self.max_memory;
+        // The soft limit causes SIGXCPU to be sent. We set the hard limit a second later in case
+        // that signal is ignored, at which point the process is killed.
+        let max_cpu_secs = self.max_cpu_secs();
+        unsafe {
+            command.pre_exec(move || {
+                // The type of resource IDs varies between platforms, so we let it be inferred.
+                let set_rlimit = |resource, soft, hard| {
+                    let limit = libc::rlimit {
+                        rlim_cur: soft,
+                        rlim_max: hard,
+                    };
+                    if libc::setrlimit(resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                };
+                if let Some(bytes) = max_memory {
+                    set_rlimit(
+                        libc::RLIMIT_AS,
+                        bytes as libc::rlim_t,
+                        bytes as libc::rlim_t,
+                    )?;
+                }
+                if let Some(secs) = max_cpu_secs {
+                    set_rlimit(
+                        libc::RLIMIT_CPU,
+                        secs as libc::rlim_t,
+                        (secs + 1) as libc::rlim_t,
+                    )?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// On Windows, limits can only be applied once the process has started, which `ChildProcess`
+    /// does by putting it in a job object.
+    #[cfg(windows)]
+    pub(crate) fn apply(&self, _command: &mut Command) -> Result<(), Error> {
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    pub(crate) fn apply(&self, _command: &mut Command) -> Result<(), Error> {
+        if !self.is_empty() {
+            crate::errors::bail!("Resource limits are only supported on unix and Windows");
+        }
+        Ok(())
+    }
+
+    /// Returns a description of the limit that was exceeded, if the way in which the subprocess
+    /// terminated indicates that one was.
+    pub(crate) fn describe_violation(
+        &self,
+        cpu_time_exceeded: bool,
+        allocation_failed: bool,
+    ) -> Option<String> {
+        if let Some(bytes) = self.max_memory {
+            if allocation_failed {
+                return Some(format!(
+                    "Subprocess exceeded its memory limit of {}",
+                    format_bytes(bytes)
+                ));
+            }
+        }
+        if let Some(secs) = self.max_cpu_secs() {
+            if cpu_time_exceeded {
+                return Some(format!(
+                    "Subprocess exceeded its CPU time limit of {}s",
+                    secs
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Returns whether `signal` is the one that the OS sends when the CPU time limit is reached.
+/// SIGKILL isn't counted, even though it's sent at the hard limit, since it's also what the OOM
+/// killer and users send.
+#[cfg(unix)]
+pub(crate) fn is_cpu_limit_signal(signal: Option<i32>) -> bool {
+    signal == Some(libc::SIGXCPU)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::is_cpu_limit_signal;
+    use super::ResourceLimits;
+    use std::time::Duration;
+
+    #[test]
+    fn test_describe_violation() {
+        let limits = ResourceLimits {
+            max_memory: Some(512 * 1024 * 1024),
+            max_cpu_time: Some(Duration::from_secs(10)),
+        };
+        let describe = |signal, allocation_failed| {
+            limits.describe_violation(is_cpu_limit_signal(signal), allocation_failed)
+        };
+        assert_eq!(
+            describe(Some(libc::SIGABRT), true),
+            Some("Subprocess exceeded its memory limit of 512.0 MiB".to_owned())
+        );
+        assert_eq!(
+            describe(Some(libc::SIGXCPU), false),
+            Some("Subprocess exceeded its CPU time limit of 10s".to_owned())
+        );
+        assert_eq!(describe(Some(libc::SIGSEGV), false), None);
+        assert_eq!(describe(Some(libc::SIGKILL), false), None);
+        assert_eq!(
+            ResourceLimits::default()
+                .describe_violation(is_cpu_limit_signal(Some(libc::SIGXCPU)), true),
+            None
+        );
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::about::format_bytes;
+
+/// Memory used by an evaluation. Produced when memory tracking is turned on with `:mem on`.
+/// Allocations are counted by a global allocator that we compile into user code, so allocations
+/// made by C libraries, or frees done by code compiled for an earlier evaluation (e.g. dropping a
+/// variable via a trait object), may not be counted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// The number of heap allocations, including reallocations.
+    pub allocations: u64,
+    /// The total number of bytes requested by those allocations. A reallocation only counts the
+    /// bytes that it grew by.
+    pub bytes_allocated: u64,
+    /// Bytes allocated during the evaluation that hadn't been freed by the end of it. This
+    /// includes memory owned by variables that are kept for later evaluations, so isn't
+    /// necessarily a leak. If the evaluation freed more than it allocated, this is zero.
+    pub bytes_leaked: u64,
+    /// How much the peak resident set size of the subprocess grew during the evaluation. Zero if
+    /// the evaluation didn't exceed the previous peak.
+    pub peak_rss_delta: u64,
+}
+
+impl MemoryStats {
+    /// Parses the stats reported by the subprocess, which are the fields above, in order,
+    /// separated by spaces.
+    pub(crate) fn parse(stats: &str) -> Option<MemoryStats> {
+        let mut values = stats.split(' ').map(|value| value.parse::<u64>().ok());
+        let mut next = || values.next().flatten();
+        Some(MemoryStats {
+            allocations: next()?,
+            bytes_allocated: next()?,
+            bytes_leaked: next()?,
+            peak_rss_delta: next()?,
+        })
+    }
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} allocation{} totalling {}, {} not freed, peak RSS +{}",
+            self.allocations,
+            if self.allocations == 1 { "" } else { "s" },
+            format_bytes(self.bytes_allocated),
+            format_bytes(self.bytes_leaked),
+            format_bytes(self.peak_rss_delta)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStats;
+
+    #[test]
+    fn test_parse_and_display() {
+        let stats = MemoryStats::parse("3 2048 100 5242880").unwrap();
+        assert_eq!(
+            stats,
+            MemoryStats {
+                allocations: 3,
+                bytes_allocated: 2048,
+                bytes_leaked: 100,
+                peak_rss_delta: 5 * 1024 * 1024,
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "3 allocations totalling 2.0 KiB, 100 B not freed, peak RSS +5.0 MiB"
+        );
+        assert_eq!(MemoryStats::parse("3 2048 100"), None);
+        assert_eq!(MemoryStats::parse("3 x 100 0"), None);
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Write;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the buckets of the evaluation duration histogram.
+const DURATION_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Counters describing the health of a session. These are intended for operators running lots of
+/// sessions, e.g. in JupyterHub, who want to monitor them. All updates are atomic, so a `Metrics`
+/// can be shared with a thread that serves them while evaluation continues.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    evals: AtomicU64,
+    eval_errors: AtomicU64,
+    // Cumulative counts, one per bucket, as required by the Prometheus histogram format.
+    eval_duration_buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    eval_duration_micros: AtomicU64,
+    fresh_artifacts: AtomicU64,
+    rebuilt_artifacts: AtomicU64,
+    child_restarts: AtomicU64,
+    child_pid: AtomicU32,
+}
+
+impl Metrics {
+    pub(crate) fn record_eval(&self, duration: Duration, succeeded: bool) {
+        self.evals.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            self.eval_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let seconds = duration.as_secs_f64();
+        for (bucket, upper_bound) in self.eval_duration_buckets.iter().zip(DURATION_BUCKETS) {
+            if seconds <= upper_bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.eval_duration_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Records how many of the artifacts from a build were reused from cargo's cache and how many
+    /// needed to be rebuilt.
+    pub(crate) fn record_build(&self, fresh: u64, rebuilt: u64) {
+        self.fresh_artifacts.fetch_add(fresh, Ordering::Relaxed);
+        self.rebuilt_artifacts.fetch_add(rebuilt, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_child_restart(&self, child_pid: u32) {
+        self.child_restarts.fetch_add(1, Ordering::Relaxed);
+        self.set_child_pid(child_pid);
+    }
+
+    pub(crate) fn set_child_pid(&self, child_pid: u32) {
+        self.child_pid.store(child_pid, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus_text(&self) -> String {
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "evcxr_evals_total",
+            "Number of evaluations.",
+            self.evals.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "evcxr_eval_errors_total",
+            "Number of evaluations that failed.",
+            self.eval_errors.load(Ordering::Relaxed),
+        );
+        self.write_duration_histogram(&mut out);
+        write_counter(
+            &mut out,
+            "evcxr_build_cache_hits_total",
+            "Number of build artifacts that cargo was able to reuse.",
+            self.fresh_artifacts.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "evcxr_build_cache_misses_total",
+            "Number of build artifacts that cargo needed to build.",
+            self.rebuilt_artifacts.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "evcxr_child_restarts_total",
+            "Number of times the process that runs user code was restarted.",
+            self.child_restarts.load(Ordering::Relaxed),
+        );
+        let memory = [
+            ("kernel", resident_memory_bytes(std::process::id())),
+            (
+                "child",
+                resident_memory_bytes(self.child_pid.load(Ordering::Relaxed)),
+            ),
+        ];
+        if memory.iter().any(|(_, bytes)| bytes.is_some()) {
+            let _ = writeln!(
+                out,
+                "# HELP evcxr_resident_memory_bytes Resident memory size.\n\
+                 # TYPE evcxr_resident_memory_bytes gauge"
+            );
+            for (process, bytes) in memory {
+                if let Some(bytes) = bytes {
+                    let _ = writeln!(
+                        out,
+                        "evcxr_resident_memory_bytes{{process=\"{}\"}} {}",
+                        process, bytes
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    fn write_duration_histogram(&self, out: &mut String) {
+        let name = "evcxr_eval_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time taken by evaluations, including compilation.\n# TYPE {} histogram",
+            name, name
+        );
+        for (bucket, upper_bound) in self.eval_duration_buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                upper_bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.evals.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.eval_duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {} {}\n# TYPE {} counter\n{} {}",
+        name, help, name, name, value
+    );
+}
+
+/// Returns the resident set size of the specified process. Only supported on Linux.
+#[cfg(target_os = "linux")]
+fn resident_memory_bytes(pid: u32) -> Option<u64> {
+    if pid == 0 {
+        return None;
+    }
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory_bytes(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Returns the number of artifacts that cargo reported as fresh and as rebuilt, given the output
+/// of a cargo command run with `--message-format=json`.
+pub(crate) fn count_artifacts(cargo_stdout: &[u8]) -> (u64, u64) {
+    let mut fresh = 0;
+    let mut rebuilt = 0;
+    for line in String::from_utf8_lossy(cargo_stdout).lines() {
+        if let Ok(message) = json::parse(line) {
+            if message["reason"] == "compiler-artifact" {
+                if message["fresh"].as_bool() == Some(true) {
+                    fresh += 1;
+                } else {
+                    rebuilt += 1;
+                }
+            }
+        }
+    }
+    (fresh, rebuilt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::count_artifacts;
+    use super::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn test_count_artifacts() {
+        let stdout = br#"{"reason":"compiler-artifact","fresh":true}
+{"reason":"compiler-artifact","fresh":false}
+{"reason":"compiler-message"}
+not json
+{"reason":"compiler-artifact","fresh":true}"#;
+        assert_eq!(count_artifacts(stdout), (2, 1));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let metrics = Metrics::default();
+        metrics.record_eval(Duration::from_millis(300), true);
+        metrics.record_eval(Duration::from_secs(20), false);
+        let text = metrics.to_prometheus_text();
+        assert!(text.contains("evcxr_evals_total 2\n"));
+        assert!(text.contains("evcxr_eval_errors_total 1\n"));
+        assert!(text.contains("evcxr_eval_duration_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(text.contains("evcxr_eval_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(text.contains("evcxr_eval_duration_seconds_bucket{le=\"30\"} 2\n"));
+        assert!(text.contains("evcxr_eval_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("evcxr_eval_duration_seconds_sum 20.3\n"));
+    }
+}
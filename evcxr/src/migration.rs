@@ -0,0 +1,128 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Migration of variables to new definitions of their types via serde, which is turned on with
+//! `:migrate_types`. When a cell changes the layout of a struct, enum or union, values of the old
+//! type can't be used as the new one. So before such a cell runs, we build the current code once
+//! more, with code that serializes each affected variable to JSON. The cell's own code then
+//! deserializes them into the new definition before variables are checked. Whether types implement
+//! `Serialize` and `Deserialize` is worked out with autoref specialization, so that a variable
+//! whose type doesn't, just fails to migrate, rather than the code failing to compile.
+
+/// Helpers used by the code that we generate. Method calls on `&Value(..)` and `&Type(..)` use
+/// serde if the type implements the relevant trait, otherwise they fall back to the impls for the
+/// references, which report an error.
+pub(crate) const HELPER_CODE: &str = r#"
+mod evcxr_migration {
+    pub struct Value<'a, T>(pub &'a T);
+
+    pub trait Serialize {
+        fn evcxr_to_json(&self) -> Result<String, String>;
+    }
+
+    impl<T: serde::Serialize> Serialize for Value<'_, T> {
+        fn evcxr_to_json(&self) -> Result<String, String> {
+            serde_json::to_string(self.0).map_err(|error| error.to_string())
+        }
+    }
+
+    pub trait NotSerialize {
+        fn evcxr_to_json(&self) -> Result<String, String>;
+    }
+
+    impl<T> NotSerialize for &Value<'_, T> {
+        fn evcxr_to_json(&self) -> Result<String, String> {
+            Err("its type doesn't implement Serialize".to_owned())
+        }
+    }
+
+    pub struct Type<T>(pub std::marker::PhantomData<T>);
+
+    pub trait Deserialize<T> {
+        fn evcxr_from_json(&self, json: &str) -> Result<T, String>;
+    }
+
+    impl<T: serde::de::DeserializeOwned> Deserialize<T> for Type<T> {
+        fn evcxr_from_json(&self, json: &str) -> Result<T, String> {
+            serde_json::from_str(json).map_err(|error| error.to_string())
+        }
+    }
+
+    pub trait NotDeserialize<T> {
+        fn evcxr_from_json(&self, json: &str) -> Result<T, String>;
+    }
+
+    impl<T> NotDeserialize<T> for &Type<T> {
+        fn evcxr_from_json(&self, _json: &str) -> Result<T, String> {
+            Err("the new definition of its type doesn't implement Deserialize".to_owned())
+        }
+    }
+}
+"#;
+
+/// The crates that the generated code needs, as they'd be passed to `:dep`.
+pub(crate) const REQUIRED_CRATES: &[&str] = &["serde", "serde_json"];
+
+/// Returns code that serializes the variable `name` of type `type_name`, keeping the JSON in the
+/// variable store.
+pub(crate) fn serialize_statement(name: &str, type_name: &str) -> String {
+    format!(
+        "{{
+            #[allow(unused_imports)]
+            use evcxr_migration::{{NotSerialize as _, Serialize as _}};
+            let evcxr_json = evcxr_variable_store
+                .get_variable::<{type_name}>(stringify!({name}))
+                .map(|value| (&evcxr_migration::Value(value)).evcxr_to_json());
+            match evcxr_json {{
+                Some(Ok(json)) => evcxr_variable_store.put_migration(stringify!({name}), json),
+                Some(Err(error)) => println!(
+                    \"{{}}{{}} {{}}\",
+                    evcxr_internal_runtime::MIGRATION_FAILED,
+                    stringify!({name}),
+                    error
+                ),
+                None => {{}}
+            }}
+        }}",
+        name = name,
+        type_name = type_name
+    )
+}
+
+/// Returns code that replaces the variable `name` with a value of type `type_name` deserialized
+/// from JSON in the variable store, if there is some.
+pub(crate) fn deserialize_statement(name: &str, type_name: &str) -> String {
+    format!(
+        "if let Some(evcxr_json) = evcxr_variable_store.take_migration(stringify!({name})) {{
+            #[allow(unused_imports)]
+            use evcxr_migration::{{Deserialize as _, NotDeserialize as _}};
+            match (&evcxr_migration::Type::<{type_name}>(std::marker::PhantomData))
+                .evcxr_from_json(&evcxr_json)
+            {{
+                Ok(value) => {{
+                    evcxr_variable_store.put_variable::<{type_name}>(stringify!({name}), value);
+                    println!(\"{{}}{{}}\", evcxr_internal_runtime::MIGRATED, stringify!({name}));
+                }}
+                Err(error) => println!(
+                    \"{{}}{{}} {{}}\",
+                    evcxr_internal_runtime::MIGRATION_FAILED,
+                    stringify!({name}),
+                    error
+                ),
+            }}
+        }}",
+        name = name,
+        type_name = type_name
+    )
+}
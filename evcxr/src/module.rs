@@ -12,18 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cargo_retry;
 use crate::code_block::CodeBlock;
+use crate::codegen::Emit;
+use crate::dep_cache;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::eval_context::Config;
 use crate::eval_context::ContextState;
+use crate::toolchain::ToolPaths;
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Child;
 use std::process::Command;
+use std::process::Stdio;
 
 fn shared_object_name_from_crate_name(crate_name: &str) -> String {
     if cfg!(target_os = "macos") {
@@ -85,16 +91,50 @@ pub(crate) struct Module {
     pub(crate) tmpdir: PathBuf,
     build_num: i32,
     target: String,
+    // Number of artifacts that were fresh and that were rebuilt in the last successful build.
+    last_artifact_counts: (u64, u64),
+    // Names of dependencies that were rebuilt in the last successful build.
+    last_rebuilt_crates: Vec<String>,
+    // Whether to include debug info, so that a debugger can set breakpoints in user code.
+    debug_info: bool,
+    // Warnings from the last successful build.
+    last_warnings: Vec<CompilationError>,
+    // Output of `rustc -Vv`, which identifies the compiler for the dependency cache.
+    rustc_version: String,
+    // Key of the dependency cache entry that was last restored or saved, if any.
+    dep_cache_key: Option<String>,
+    // The cargo process started by the last call to `precompile`.
+    precompilation: Option<Child>,
 }
 
-const CRATE_NAME: &str = "ctx";
+impl Drop for Module {
+    fn drop(&mut self) {
+        // Our crate directory is about to be deleted, so there's no point letting a build that
+        // writes to it carry on.
+        if let Some(child) = &mut self.precompilation {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+pub(crate) const CRATE_NAME: &str = "ctx";
+const EXECUTABLE_NAME: &str = "evcxr_main";
 
 impl Module {
-    pub(crate) fn new(tmpdir: PathBuf) -> Result<Module, Error> {
+    pub(crate) fn new(tmpdir: PathBuf, tool_paths: &ToolPaths) -> Result<Module, Error> {
+        let (target, rustc_version) = get_host_target(tool_paths)?;
         let module = Module {
             tmpdir,
             build_num: 0,
-            target: get_host_target()?,
+            target,
+            last_artifact_counts: (0, 0),
+            last_rebuilt_crates: Vec::new(),
+            debug_info: false,
+            last_warnings: Vec::new(),
+            rustc_version,
+            dep_cache_key: None,
+            precompilation: None,
         };
         Ok(module)
     }
@@ -107,6 +147,46 @@ impl Module {
         self.tmpdir.join("target").join(&self.target)
     }
 
+    /// Returns the directory into which cargo writes all build outputs, for all targets.
+    pub(crate) fn cargo_target_dir(&self) -> PathBuf {
+        self.tmpdir.join("target")
+    }
+
+    pub(crate) fn last_artifact_counts(&self) -> (u64, u64) {
+        self.last_artifact_counts
+    }
+
+    pub(crate) fn last_rebuilt_crates(&self) -> &[String] {
+        &self.last_rebuilt_crates
+    }
+
+    /// Takes the warnings from the last successful build.
+    pub(crate) fn take_warnings(&mut self) -> Vec<CompilationError> {
+        std::mem::take(&mut self.last_warnings)
+    }
+
+    fn record_build_output(&mut self, cargo_output: &std::process::Output, code_block: &CodeBlock) {
+        let cargo_stdout = &cargo_output.stdout;
+        self.last_artifact_counts = crate::metrics::count_artifacts(cargo_stdout);
+        self.last_rebuilt_crates = crate::why_slow::rebuilt_crates(cargo_stdout, CRATE_NAME);
+        let (mut warnings, _non_json_error) = errors_from_cargo_output(cargo_output, code_block);
+        warnings.retain(|warning| warning.level() == "warning");
+        self.last_warnings = warnings;
+    }
+
+    pub(crate) fn set_debug_info(&mut self, debug_info: bool) {
+        self.debug_info = debug_info;
+    }
+
+    pub(crate) fn host_target(&self) -> &str {
+        &self.target
+    }
+
+    /// Returns `target` if it's set to something other than the host.
+    pub(crate) fn cross_target<'a>(&self, target: Option<&'a str>) -> Option<&'a str> {
+        target.filter(|target| *target != self.target)
+    }
+
     fn so_path(&self) -> PathBuf {
         self.deps_dir()
             .join(shared_object_name_from_crate_name(CRATE_NAME))
@@ -120,8 +200,17 @@ impl Module {
         &self.tmpdir
     }
 
+    /// Returns the path of the file containing the code that we compile.
+    pub(crate) fn lib_rs_path(&self) -> PathBuf {
+        self.src_dir().join("lib.rs")
+    }
+
     pub fn last_source(&self) -> Result<String, std::io::Error> {
-        std::fs::read_to_string(self.src_dir().join("lib.rs"))
+        std::fs::read_to_string(self.lib_rs_path())
+    }
+
+    pub(crate) fn cargo_lock_path(&self) -> PathBuf {
+        self.crate_dir().join("Cargo.lock")
     }
 
     // Writes Cargo.toml. Should be called before compile.
@@ -130,7 +219,28 @@ impl Module {
             self.crate_dir(),
             "Cargo.toml",
             &self.get_cargo_toml_contents(state),
-        )
+        )?;
+        self.write_cargo_config(state)?;
+        Ok(())
+    }
+
+    /// Writes or removes `.cargo/config.toml`, which passes rustflags needed by `:rustflags`,
+    /// `:dep_flags` and `:link`, and sets variables from `:build_env`. Cargo picks it up because it
+    /// runs in our crate directory.
+    fn write_cargo_config(&self, state: &ContextState) -> Result<(), Error> {
+        let config_dir = self.crate_dir().join(".cargo");
+        match state.cargo_config() {
+            Some(contents) => write_file(&config_dir, "config.toml", &contents),
+            None => {
+                let path = config_dir.join("config.toml");
+                if path.exists() {
+                    if let Err(err) = fs::remove_file(&path) {
+                        bail!("Error removing '{:?}': {}", path, err);
+                    }
+                }
+                Ok(())
+            }
+        }
     }
 
     pub(crate) fn check(
@@ -139,50 +249,49 @@ impl Module {
         config: &Config,
     ) -> Result<Vec<CompilationError>, Error> {
         self.write_code(code_block)?;
-        let output = config
-            .cargo_command("check")
-            .arg("--message-format=json")
-            .output();
-
-        let cargo_output = match output {
+        let subcommand = config.clippy.cargo_subcommand();
+        let mut command = config.cargo_command(subcommand);
+        command.arg("--message-format=json");
+        if !config.clippy.lint_args().is_empty() {
+            command.arg("--").args(config.clippy.lint_args());
+        }
+        let cargo_output = match command.output() {
             Ok(out) => out,
-            Err(err) => bail!("Error running 'cargo check': {}", err),
+            Err(err) => bail!("Error running 'cargo {}': {}", subcommand, err),
         };
         let (errors, _non_json_error) = errors_from_cargo_output(&cargo_output, code_block);
+        if errors.is_empty() && !cargo_output.status.success() {
+            // Most likely a problem with the toolchain, e.g. one that isn't installed.
+            bail!(
+                "cargo {} failed:\n{}",
+                subcommand,
+                String::from_utf8_lossy(&cargo_output.stderr).trim()
+            );
+        }
         Ok(errors)
     }
 
+    /// Compiles `code_block` into a shared object that can be loaded into the subprocess. With
+    /// `:build_log` on, cargo's output is passed to `build_output` as it's written.
     pub(crate) fn compile(
         &mut self,
         code_block: &CodeBlock,
         config: &Config,
+        build_output: &dyn Fn(&str),
     ) -> Result<SoFile, Error> {
-        let mut command = config.cargo_command("rustc");
         if config.time_passes && config.toolchain != "nightly" {
             bail!("time_passes option requires nightly compiler");
         }
-
-        command
-            .arg("--target")
-            .arg(&self.target)
-            .arg("--message-format=json")
-            .arg("--")
-            .arg("-C")
-            .arg("prefer-dynamic")
-            .env("CARGO_TARGET_DIR", "target");
-        if config.linker == "lld" {
-            command
-                .arg("-C")
-                .arg(format!("link-arg=-fuse-ld={}", config.linker));
-        }
-        if let Some(sccache) = &config.sccache {
-            command.env("RUSTC_WRAPPER", sccache);
-        }
+        let mut command = self.rustc_command(config);
         if config.time_passes {
             command.arg("-Ztime-passes");
         }
         self.write_code(code_block)?;
-        let cargo_output = run_cargo(command, code_block)?;
+        let dep_cache_key = self.restore_dep_cache(config);
+        let cargo_output = run_cargo(command, code_block, config, build_output)?;
+        self.record_build_output(&cargo_output, code_block);
+        self.save_persistent_lockfile(config)?;
+        self.save_dep_cache(dep_cache_key);
         if config.time_passes {
             let output = String::from_utf8_lossy(&cargo_output.stderr);
             eprintln!("{}", output);
@@ -205,6 +314,240 @@ impl Module {
         })
     }
 
+    /// Starts building `code_block` in the background, the same way as `compile` would, without
+    /// waiting for it or looking at the result. This builds any dependencies that aren't built yet
+    /// and leaves incremental compilation state that the next `compile` can reuse. If `compile` is
+    /// called before the build finishes, cargo makes it wait for the build, which then mostly
+    /// doesn't need redoing. Returns false, without starting anything, if a build started by an
+    /// earlier call is still running.
+    pub(crate) fn precompile(
+        &mut self,
+        code_block: &CodeBlock,
+        config: &Config,
+    ) -> Result<bool, Error> {
+        if let Some(child) = &mut self.precompilation {
+            if let Ok(None) = child.try_wait() {
+                return Ok(false);
+            }
+        }
+        let mut command = self.rustc_command(config);
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+        self.write_code(code_block)?;
+        self.restore_dep_cache(config);
+        match command.spawn() {
+            Ok(child) => self.precompilation = Some(child),
+            Err(err) => bail!("Error running cargo: {}", err),
+        }
+        Ok(true)
+    }
+
+    /// Writes Cargo.lock to the persistent lockfile, if there is one and the build changed it.
+    fn save_persistent_lockfile(&self, config: &Config) -> Result<(), Error> {
+        let lockfile = match &config.persistent_lockfile {
+            Some(lockfile) => lockfile,
+            None => return Ok(()),
+        };
+        let contents = match fs::read(self.cargo_lock_path()) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(()),
+        };
+        if fs::read(lockfile).ok().as_ref() == Some(&contents) {
+            return Ok(());
+        }
+        if let Some(parent) = lockfile.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Err(err) = fs::write(lockfile, contents) {
+            bail!("Error writing lockfile '{:?}': {}", lockfile, err);
+        }
+        Ok(())
+    }
+
+    /// If the dependency cache is on and our dependencies have changed since we last used it,
+    /// copies any cached build of the new dependencies into our target directory. Returns the key
+    /// for our dependencies, if the cache is on. Failing to use the cache doesn't fail the build.
+    fn restore_dep_cache(&mut self, config: &Config) -> Option<String> {
+        if !config.dep_cache {
+            return None;
+        }
+        let root = dep_cache::cache_root()?;
+        // A Cargo.lock that was imported or loaded mustn't be replaced by the cache's.
+        let pins_versions = config.lockfile.is_some() || config.persistent_lockfile.is_some();
+        let key = dep_cache::key(&self.rustc_version, &config.toolchain, &self.tmpdir);
+        if self.dep_cache_key.as_ref() != Some(&key) {
+            self.dep_cache_key = None;
+            if let Ok(true) = dep_cache::restore(&root, &key, &self.tmpdir, !pins_versions) {
+                self.dep_cache_key = Some(key.clone());
+            }
+        }
+        Some(key)
+    }
+
+    /// After a successful build, saves our dependencies to the cache if they're not there yet.
+    fn save_dep_cache(&mut self, key: Option<String>) {
+        let key = match key {
+            Some(key) if self.dep_cache_key.as_ref() != Some(&key) => key,
+            _ => return,
+        };
+        if let Some(root) = dep_cache::cache_root() {
+            let _ = dep_cache::save(&root, &key, &self.tmpdir);
+            self.dep_cache_key = Some(key);
+        }
+    }
+
+    /// Compiles `code_block` the same way as `compile`, but also has rustc emit `emit` for the crate
+    /// and returns it. All functions are codegened, even ones that nothing calls, so that users can
+    /// inspect any function they've defined.
+    pub(crate) fn emit(
+        &mut self,
+        code_block: &CodeBlock,
+        config: &Config,
+        emit: Emit,
+        build_output: &dyn Fn(&str),
+    ) -> Result<String, Error> {
+        let emitted_path = self
+            .tmpdir
+            .join(format!("{}.{}", CRATE_NAME, emit.extension()));
+        let mut command = self.rustc_command(config);
+        command
+            .arg("-C")
+            .arg("link-dead-code")
+            .arg("-C")
+            .arg("codegen-units=1")
+            .arg(format!(
+                "--emit={}={}",
+                emit.rustc_name(),
+                emitted_path.to_string_lossy()
+            ));
+        self.write_code(code_block)?;
+        run_cargo(command, code_block, config, build_output)?;
+        match fs::read_to_string(&emitted_path) {
+            Ok(emitted) => Ok(emitted),
+            Err(err) => bail!("Error reading '{:?}': {}", emitted_path, err),
+        }
+    }
+
+    /// Returns the cargo command used to build the session crate for loading into the subprocess.
+    /// Further rustc arguments can be appended.
+    fn rustc_command(&self, config: &Config) -> Command {
+        let mut command = config.cargo_build_command("rustc");
+        command
+            .arg("--target")
+            .arg(&self.target)
+            .arg("--message-format=json")
+            .arg("--")
+            .arg("-C")
+            .arg("prefer-dynamic")
+            .env("CARGO_TARGET_DIR", "target");
+        if config.linker == "lld" {
+            command
+                .arg("-C")
+                .arg(format!("link-arg=-fuse-ld={}", config.linker));
+        }
+        if let Some(sccache) = &config.sccache {
+            command.env("RUSTC_WRAPPER", sccache);
+        }
+        if self.debug_info {
+            command.arg("-C").arg("debuginfo=2");
+        } else {
+            // Line tables are enough for backtraces of panics to have locations that we can map
+            // back to the user's cells.
+            command.arg("-C").arg("debuginfo=1");
+        }
+        if config.test_mode {
+            command.arg("--cfg").arg("test");
+        }
+        command
+    }
+
+    /// Returns `code_block` with all macros expanded, as pretty-printed by rustc. This needs an
+    /// unstable rustc option, so like cargo-expand, we set RUSTC_BOOTSTRAP to allow it on stable.
+    pub(crate) fn expand(
+        &mut self,
+        code_block: &CodeBlock,
+        config: &Config,
+        build_output: &dyn Fn(&str),
+    ) -> Result<String, Error> {
+        let expanded_path = self.tmpdir.join("expanded.rs");
+        let mut command = config.cargo_build_command("rustc");
+        command
+            .arg("--lib")
+            .arg("--profile=check")
+            .arg("--message-format=json")
+            .arg("--")
+            .arg("-Zunpretty=expanded")
+            .arg("-o")
+            .arg(&expanded_path)
+            .env("RUSTC_BOOTSTRAP", "1")
+            .env("CARGO_TARGET_DIR", "target");
+        self.write_code(code_block)?;
+        run_cargo(command, code_block, config, build_output)?;
+        match fs::read_to_string(&expanded_path) {
+            Ok(expanded) => Ok(expanded),
+            Err(err) => bail!("Error reading '{:?}': {}", expanded_path, err),
+        }
+    }
+
+    /// Compiles `code_block` for a target other than the host, together with a main function that
+    /// calls `fn_name`. Returns the path of the resulting executable. Used when the user has
+    /// selected a target, since we can't load code for another target into our subprocess.
+    pub(crate) fn compile_executable(
+        &mut self,
+        code_block: &CodeBlock,
+        config: &Config,
+        target: &str,
+        fn_name: &str,
+        build_output: &dyn Fn(&str),
+    ) -> Result<PathBuf, Error> {
+        let mut command = config.cargo_build_command("build");
+        command
+            .arg("--bin")
+            .arg(EXECUTABLE_NAME)
+            .arg("--target")
+            .arg(target)
+            .arg("--message-format=json")
+            .env("CARGO_TARGET_DIR", "target");
+        if let Some(sccache) = &config.sccache {
+            command.env("RUSTC_WRAPPER", sccache);
+        }
+        self.write_code(code_block)?;
+        write_file(
+            &self.src_dir(),
+            "main.rs",
+            &format!(
+                "fn main() {{\n    {}::{}(std::ptr::null_mut());\n}}\n",
+                CRATE_NAME, fn_name
+            ),
+        )?;
+        let cargo_output = run_cargo(command, code_block, config, build_output)?;
+        self.record_build_output(&cargo_output, code_block);
+        self.save_persistent_lockfile(config)?;
+        match executable_from_cargo_output(&cargo_output.stdout) {
+            Some(executable) => Ok(executable),
+            None => bail!("Cargo didn't report building an executable for {}", target),
+        }
+    }
+
+    /// Returns a command that will run `executable`, which was built for `target`. If the user
+    /// hasn't configured a runner, we try to pick one based on the target, falling back to running
+    /// the executable directly, which works for targets the host can run natively.
+    pub(crate) fn executable_command(config: &Config, target: &str, executable: &Path) -> Command {
+        let default_runner = if target.starts_with("wasm32-wasi") {
+            Some("wasmtime")
+        } else {
+            None
+        };
+        match config.runner.as_deref().or(default_runner) {
+            Some(runner) => {
+                let mut parts = runner.split_whitespace();
+                let mut command = Command::new(parts.next().unwrap_or(runner));
+                command.args(parts).arg(executable);
+                command
+            }
+            None => Command::new(executable),
+        }
+    }
+
     fn write_code(&self, code_block: &CodeBlock) -> Result<(), Error> {
         write_file(&self.src_dir(), "lib.rs", &code_block.code_string())?;
         self.maybe_bump_lib_mtime();
@@ -231,17 +574,36 @@ impl Module {
 
     fn get_cargo_toml_contents(&self, state: &ContextState) -> String {
         let crate_imports = state.format_cargo_deps();
+        // When building for another target, we build an executable rather than something we can
+        // load into our subprocess. Targets like wasm don't support unwinding.
+        let (crate_type, bin, panic) = match self.cross_target(state.target()) {
+            Some(target) => (
+                "rlib",
+                format!(
+                    "\n[[bin]]\nname = \"{}\"\npath = \"src/main.rs\"\n",
+                    EXECUTABLE_NAME
+                ),
+                if target.starts_with("wasm") {
+                    "abort"
+                } else {
+                    "unwind"
+                },
+            ),
+            None => ("cdylib", String::new(), "unwind"),
+        };
         format!(
             r#"
 [package]
 name = "{}"
 version = "1.0.0"
-edition = "2021"
+edition = "{}"
+# src/main.rs is only used when building for another target, in which case we declare it below.
+autobins = false
 
 [lib]
-crate-type = ["cdylib"]
+crate-type = ["{}"]
 path = "src/lib.rs"
-
+{}
 [profile.dev]
 opt-level = {}
 debug = false
@@ -249,16 +611,21 @@ rpath = true
 lto = false
 debug-assertions = true
 codegen-units = 16
-panic = 'unwind'
+panic = '{}'
 incremental = true
 overflow-checks = true
 
 [dependencies]
 {}
-"#,
+{}"#,
             CRATE_NAME,
+            state.edition(),
+            crate_type,
+            bin,
             state.opt_level(),
-            crate_imports
+            panic,
+            crate_imports,
+            state.dep_profile_overrides()
         )
     }
 }
@@ -266,8 +633,10 @@ overflow-checks = true
 fn run_cargo(
     mut command: std::process::Command,
     code_block: &CodeBlock,
+    config: &Config,
+    build_output: &dyn Fn(&str),
 ) -> Result<std::process::Output, Error> {
-    let cargo_output = match command.output() {
+    let cargo_output = match cargo_retry::output_with_retries(&mut command, config, build_output) {
         Ok(out) => out,
         Err(err) => bail!("Error running 'cargo rustc': {}", err),
     };
@@ -276,6 +645,11 @@ fn run_cargo(
     } else {
         let (errors, non_json_error) = errors_from_cargo_output(&cargo_output, code_block);
         if errors.is_empty() {
+            if let Some(error) =
+                build_script_failure(&String::from_utf8_lossy(&cargo_output.stderr))
+            {
+                bail!(Error::Message(error));
+            }
             if let Some(error) = non_json_error {
                 bail!(Error::Message(error));
             } else {
@@ -292,6 +666,26 @@ fn run_cargo(
     }
 }
 
+/// Returns an error for a dependency's build script having failed, if that's what cargo reported
+/// in `stderr`. Cargo includes what the script printed, which usually says what it needed.
+fn build_script_failure(stderr: &str) -> Option<String> {
+    let start = stderr.find("error: failed to run custom build command for")?;
+    Some(format!(
+        "{}\n\nIf the build script needs environment variables, such as CUDA_PATH, set them with \
+         `:build_env set KEY=VALUE`. Library search paths can be added with \
+         `:rustflags -L native=<dir>`.",
+        stderr[start..].trim_end()
+    ))
+}
+
+fn executable_from_cargo_output(cargo_stdout: &[u8]) -> Option<PathBuf> {
+    String::from_utf8_lossy(cargo_stdout)
+        .lines()
+        .filter_map(|line| json::parse(line).ok())
+        .filter(|message| message["reason"] == "compiler-artifact")
+        .find_map(|message| message["executable"].as_str().map(PathBuf::from))
+}
+
 fn errors_from_cargo_output(
     cargo_output: &std::process::Output,
     code_block: &CodeBlock,
@@ -328,8 +722,9 @@ pub(crate) struct SoFile {
     pub(crate) path: PathBuf,
 }
 
-fn get_host_target() -> Result<String, Error> {
-    let output = match Command::new("rustc").arg("-Vv").output() {
+/// Returns the host target and the full output of `rustc -Vv`.
+fn get_host_target(tool_paths: &ToolPaths) -> Result<(String, String), Error> {
+    let output = match tool_paths.rustc_command().arg("-Vv").output() {
         Ok(o) => o,
         Err(error) => bail!("Failed to run rustc: {}", error),
     };
@@ -337,7 +732,7 @@ fn get_host_target() -> Result<String, Error> {
     let stderr = std::str::from_utf8(&output.stderr)?;
     for line in stdout.lines() {
         if let Some(host) = line.strip_prefix("host: ") {
-            return Ok(host.to_owned());
+            return Ok((host.to_owned(), stdout.to_owned()));
         }
     }
     bail!(
@@ -346,3 +741,21 @@ fn get_host_target() -> Result<String, Error> {
         stderr
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::build_script_failure;
+
+    #[test]
+    fn build_script_failures() {
+        let error = build_script_failure(
+            "   Compiling cudarc v0.9.14\n\
+             error: failed to run custom build command for `cudarc v0.9.14`\n\n\
+             Caused by:\n  --- stderr\n  CUDA_PATH isn't set\n",
+        )
+        .unwrap();
+        assert!(error.starts_with("error: failed to run custom build command for `cudarc"));
+        assert!(error.contains("CUDA_PATH isn't set\n\nIf the build script needs"));
+        assert_eq!(build_script_failure("error: could not compile `ctx`"), None);
+    }
+}
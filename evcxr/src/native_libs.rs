@@ -0,0 +1,347 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Native libraries linked with `:link`, so that functions in `extern` blocks can be called.
+//!
+//! A library's own symbols are only looked up when they're first called, so a missing one would
+//! otherwise crash the subprocess part way through running code. Instead, once code that's linked
+//! against a library has been built, the subprocess loads it and looks up every function and
+//! static declared in `extern` blocks, and we report all that are missing before running anything.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Followed by the path of the built code, then tab-separated fields, each of which is either `L`
+/// followed by the file of a linked library, or `S` followed by a symbol to look up.
+const CHECK_SYMBOLS: &str = "CHECK_SYMBOLS ";
+const REPLY_OK: &str = "OK";
+/// Followed by the space-separated symbols that weren't found.
+const REPLY_MISSING: &str = "MISSING ";
+const REPLY_ERROR: &str = "ERROR ";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NativeLib {
+    pub(crate) name: String,
+    /// The directory that contains the library, if it's not on the linker's search path.
+    pub(crate) dir: Option<PathBuf>,
+}
+
+impl NativeLib {
+    /// Returns a library called `name`, e.g. `m` for `libm.so`, in `dir`, which is made absolute,
+    /// since it's used from the directory that code is built in.
+    pub(crate) fn new(name: &str, dir: Option<&Path>) -> Result<NativeLib, Error> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '+'))
+        {
+            bail!("Invalid library name: {:?}", name);
+        }
+        let dir = match dir {
+            Some(dir) => match dir.canonicalize() {
+                Ok(dir) if dir.is_dir() => Some(dir),
+                Ok(_) => bail!("{} isn't a directory", dir.display()),
+                Err(error) => bail!("Can't use {}: {}", dir.display(), error),
+            },
+            None => None,
+        };
+        Ok(NativeLib {
+            name: name.to_owned(),
+            dir,
+        })
+    }
+
+    /// Returns the library's file, as the dynamic loader would look for it.
+    fn file(&self) -> PathBuf {
+        let file_name = libloading::library_filename(&self.name);
+        match &self.dir {
+            Some(dir) => dir.join(file_name),
+            None => PathBuf::from(file_name),
+        }
+    }
+}
+
+impl std::fmt::Display for NativeLib {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.dir {
+            Some(dir) => write!(f, "{} (in {})", self.name, dir.display()),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Returns an empty `extern` block for each library, which is how the generated crate links
+/// against them.
+pub(crate) fn link_attributes(libs: &[NativeLib], edition: &str) -> String {
+    let extern_keyword = if edition == "2024" {
+        "unsafe extern"
+    } else {
+        "extern"
+    };
+    libs.iter()
+        .map(|lib| {
+            format!(
+                "#[link(name = {:?})] {} \"C\" {{}}\n",
+                lib.name, extern_keyword
+            )
+        })
+        .collect()
+}
+
+/// Returns rustflags that tell the linker where to find libraries, and if `rpath` is set, tell the
+/// dynamic loader where to find them when code is loaded.
+pub(crate) fn rustflags(libs: &[NativeLib], rpath: bool) -> Vec<String> {
+    let mut flags = Vec::new();
+    for dir in libs.iter().filter_map(|lib| lib.dir.as_ref()) {
+        flags.push("-L".to_owned());
+        flags.push(format!("native={}", dir.display()));
+        if rpath {
+            flags.push("-C".to_owned());
+            flags.push(format!("link-arg=-Wl,-rpath,{}", dir.display()));
+        }
+    }
+    flags
+}
+
+/// Returns the names of the functions and statics declared in `extern` blocks in `code`, as they
+/// appear in the library, i.e. taking `#[link_name]` into account.
+pub(crate) fn declared_symbols(code: &str) -> Vec<String> {
+    static EXTERN_BLOCK: OnceCell<Regex> = OnceCell::new();
+    let extern_block =
+        EXTERN_BLOCK.get_or_init(|| Regex::new(r#"\bextern\s*(?:"([^"]*)")?\s*\{"#).unwrap());
+    static DECLARATION: OnceCell<Regex> = OnceCell::new();
+    let declaration = DECLARATION.get_or_init(|| {
+        Regex::new(
+            r#"(?:#\[link_name\s*=\s*"([^"]+)"\]\s*)?(?:pub(?:\([^)]*\))?\s+)?(?:(?:safe|unsafe)\s+)?(?:fn|static(?:\s+mut)?)\s+([A-Za-z_][A-Za-z0-9_]*)"#,
+        )
+        .unwrap()
+    });
+    let mut symbols: Vec<String> = Vec::new();
+    for block in extern_block.captures_iter(code) {
+        if matches!(block.get(1), Some(abi) if abi.as_str().contains("intrinsic")) {
+            continue;
+        }
+        // Declarations don't contain braces, so the block ends at the first closing one.
+        let body_start = block.get(0).unwrap().end();
+        let body = match code[body_start..].find('}') {
+            Some(len) => &code[body_start..body_start + len],
+            None => continue,
+        };
+        for captures in declaration.captures_iter(body) {
+            let symbol = captures
+                .get(1)
+                .or_else(|| captures.get(2))
+                .unwrap()
+                .as_str();
+            if !symbols.iter().any(|existing| existing == symbol) {
+                symbols.push(symbol.to_owned());
+            }
+        }
+    }
+    symbols
+}
+
+/// Returns the line that asks the subprocess to look up `symbols` once it's loaded the code at
+/// `so_path`.
+pub(crate) fn check_command(so_path: &str, libs: &[NativeLib], symbols: &[String]) -> String {
+    let mut line = format!("{}{}", CHECK_SYMBOLS, so_path);
+    for lib in libs {
+        line.push_str(&format!("\tL{}", lib.file().display()));
+    }
+    for symbol in symbols {
+        line.push_str(&format!("\tS{}", symbol));
+    }
+    line
+}
+
+/// A request to look up symbols, as received by the subprocess.
+pub(crate) struct CheckSymbols<'a> {
+    pub(crate) so_path: &'a str,
+    lib_files: Vec<&'a str>,
+    symbols: Vec<&'a str>,
+}
+
+impl<'a> CheckSymbols<'a> {
+    pub(crate) fn parse(line: &'a str) -> Option<CheckSymbols<'a>> {
+        let mut fields = line.strip_prefix(CHECK_SYMBOLS)?.split('\t');
+        let mut check = CheckSymbols {
+            so_path: fields.next()?,
+            lib_files: Vec::new(),
+            symbols: Vec::new(),
+        };
+        for field in fields {
+            if let Some(file) = field.strip_prefix('L') {
+                check.lib_files.push(file);
+            } else if let Some(symbol) = field.strip_prefix('S') {
+                check.symbols.push(symbol);
+            }
+        }
+        Some(check)
+    }
+
+    /// Looks up the symbols, given the loaded code, and returns the reply. Symbols are found via
+    /// the libraries that the code depends on. Linked libraries are also opened directly, in case
+    /// the linker left one out because nothing that's used so far needed it.
+    pub(crate) fn run(&self, code: &libloading::Library) -> String {
+        let mut libraries = Vec::new();
+        for file in &self.lib_files {
+            // Some "libraries" can't be loaded, e.g. linker scripts, but they're found via the
+            // code if they're needed.
+            if let Ok(library) = unsafe { libloading::Library::new(file) } {
+                libraries.push(library);
+            }
+        }
+        let missing: Vec<&str> = self
+            .symbols
+            .iter()
+            .copied()
+            .filter(|symbol| {
+                !std::iter::once(code)
+                    .chain(&libraries)
+                    .any(|library| unsafe { library.get::<*const u8>(symbol.as_bytes()) }.is_ok())
+            })
+            .collect();
+        if missing.is_empty() {
+            REPLY_OK.to_owned()
+        } else {
+            format!("{}{}", REPLY_MISSING, missing.join(" "))
+        }
+    }
+}
+
+/// Returns the reply for when the code couldn't be loaded.
+pub(crate) fn error_reply(error: &dyn std::fmt::Display) -> String {
+    format!("{}{}", REPLY_ERROR, error)
+}
+
+/// Returns an error describing what went wrong, if the subprocess's reply says that anything did.
+pub(crate) fn parse_reply(reply: &str, libs: &[NativeLib]) -> Result<(), Error> {
+    let lib_list = libs
+        .iter()
+        .map(|lib| lib.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if reply == REPLY_OK {
+        Ok(())
+    } else if let Some(missing) = reply.strip_prefix(REPLY_MISSING) {
+        bail!(
+            "These symbols, declared in extern blocks, weren't found in the linked libraries ({}): {}",
+            lib_list,
+            missing.split(' ').collect::<Vec<_>>().join(", ")
+        );
+    } else if let Some(message) = reply.strip_prefix(REPLY_ERROR) {
+        bail!(
+            "Failed to load code linked against {}: {}\nIf a library isn't on the library search \
+             path, give its directory, e.g. `:link mylib ./build`",
+            lib_list,
+            message
+        );
+    } else {
+        bail!("Unexpected reply from subprocess: {}", reply);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_command;
+    use super::declared_symbols;
+    use super::link_attributes;
+    use super::parse_reply;
+    use super::rustflags;
+    use super::CheckSymbols;
+    use super::NativeLib;
+    use std::path::PathBuf;
+
+    #[test]
+    fn symbols_in_extern_blocks() {
+        let code = r#"
+            extern "C" {
+                fn cos(x: f64) -> f64;
+                #[link_name = "real_sin"]
+                pub fn sin(x: f64) -> f64;
+                static mut errno: i32;
+            }
+            pub extern "C" fn callback(x: i32) -> i32 { x }
+            unsafe extern {
+                safe fn cos(x: f64) -> f64;
+            }
+            extern "rust-intrinsic" {
+                fn transmute();
+            }
+        "#;
+        assert_eq!(declared_symbols(code), vec!["cos", "real_sin", "errno"]);
+        assert!(declared_symbols("extern crate regex;").is_empty());
+    }
+
+    #[test]
+    fn build_config() {
+        let libs = vec![
+            NativeLib {
+                name: "m".to_owned(),
+                dir: None,
+            },
+            NativeLib {
+                name: "mylib".to_owned(),
+                dir: Some(PathBuf::from("/opt/mylib")),
+            },
+        ];
+        assert_eq!(
+            link_attributes(&libs, "2021"),
+            "#[link(name = \"m\")] extern \"C\" {}\n#[link(name = \"mylib\")] extern \"C\" {}\n"
+        );
+        assert!(link_attributes(&libs, "2024").starts_with("#[link(name = \"m\")] unsafe extern"));
+        assert_eq!(
+            rustflags(&libs, true),
+            vec![
+                "-L",
+                "native=/opt/mylib",
+                "-C",
+                "link-arg=-Wl,-rpath,/opt/mylib"
+            ]
+        );
+        assert_eq!(rustflags(&libs, false), vec!["-L", "native=/opt/mylib"]);
+        assert_eq!(libs[1].to_string(), "mylib (in /opt/mylib)");
+        assert!(NativeLib::new("", None).is_err());
+        assert!(NativeLib::new("a\"b", None).is_err());
+        assert!(NativeLib::new("m", Some(&PathBuf::from("/evcxr/does/not/exist"))).is_err());
+    }
+
+    #[test]
+    fn checking() {
+        let libs = vec![NativeLib {
+            name: "m".to_owned(),
+            dir: None,
+        }];
+        let line = check_command("/tmp/code.so", &libs, &["cos".to_owned(), "sin".to_owned()]);
+        let check = CheckSymbols::parse(&line).unwrap();
+        assert_eq!(check.so_path, "/tmp/code.so");
+        assert_eq!(check.symbols, vec!["cos", "sin"]);
+        assert_eq!(check.lib_files.len(), 1);
+        assert!(CheckSymbols::parse("LOAD /tmp/code.so").is_none());
+
+        assert!(parse_reply("OK", &libs).is_ok());
+        assert_eq!(
+            parse_reply("MISSING foo bar", &libs)
+                .unwrap_err()
+                .to_string(),
+            "These symbols, declared in extern blocks, weren't found in the linked libraries (m): \
+             foo, bar"
+        );
+        assert!(parse_reply("ERROR libm.so: cannot open", &libs).is_err());
+    }
+}
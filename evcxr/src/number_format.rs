@@ -0,0 +1,304 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors::bail;
+use crate::errors::Error;
+
+/// Preferences, set with `:numfmt`, for how numbers in the text of results are displayed. Numbers
+/// are found by scanning the text, so this applies equally to a single number and to numbers
+/// inside collections and structs. Anything within string or char literals is left alone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct NumberFormat {
+    /// Inserted between each group of three digits in the integer part of numbers.
+    thousands_separator: Option<char>,
+    /// Floats at least this large, or smaller than its reciprocal, use scientific notation.
+    scientific_threshold: Option<f64>,
+    /// The number of significant digits to round floats to.
+    significant_digits: Option<usize>,
+}
+
+impl NumberFormat {
+    /// Returns a copy of this format, updated with settings such as `sep=, sig=4 sci=1e6`. Each
+    /// setting can be turned off by setting it to `none`, or all of them with `off`.
+    pub(crate) fn with_settings(&self, settings: &str) -> Result<NumberFormat, Error> {
+        let mut format = self.clone();
+        for setting in settings.split_whitespace() {
+            if setting == "off" {
+                format = NumberFormat::default();
+                continue;
+            }
+            let (key, value) = match setting.split_once('=') {
+                Some(key_value) => key_value,
+                None => bail!(
+                    "Expected a setting of the form key=value, got '{}'",
+                    setting
+                ),
+            };
+            let value = Some(value).filter(|value| *value != "none");
+            match key {
+                "sep" => {
+                    format.thousands_separator = match value {
+                        None => None,
+                        Some("space") => Some(' '),
+                        Some(value) if value.chars().count() == 1 => value.chars().next(),
+                        Some(value) => bail!("Separator should be a single character: '{}'", value),
+                    }
+                }
+                "sci" => {
+                    format.scientific_threshold = match value.map(str::parse::<f64>) {
+                        None => None,
+                        Some(Ok(threshold)) if threshold > 1.0 => Some(threshold),
+                        _ => bail!("sci should be a number greater than 1, e.g. 1e6"),
+                    }
+                }
+                "sig" => {
+                    format.significant_digits = match value.map(str::parse::<usize>) {
+                        None => None,
+                        Some(Ok(digits)) if digits > 0 => Some(digits),
+                        _ => bail!("sig should be a positive number of digits"),
+                    }
+                }
+                _ => bail!("Unknown setting '{}'. Expected sep, sci or sig", key),
+            }
+        }
+        Ok(format)
+    }
+
+    /// Returns `text` with the numbers in it formatted according to our preferences.
+    pub(crate) fn apply(&self, text: &str) -> String {
+        if *self == NumberFormat::default() {
+            return text.to_owned();
+        }
+        let bytes = text.as_bytes();
+        let mut output = String::new();
+        let mut copied_up_to = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => i = end_of_string_literal(bytes, i),
+                b'\'' => i = end_of_char_literal(bytes, i),
+                b'0'..=b'9' if i == 0 || !is_word_byte(bytes[i - 1]) => {
+                    let end = end_of_number(bytes, i);
+                    if end < bytes.len() && is_word_byte(bytes[end]) {
+                        // Something like a hex literal or a version number, which we leave alone.
+                        i = end;
+                        while i < bytes.len() && is_word_byte(bytes[i]) {
+                            i += 1;
+                        }
+                        continue;
+                    }
+                    output.push_str(&text[copied_up_to..i]);
+                    output.push_str(&self.format_number(&text[i..end]));
+                    copied_up_to = end;
+                    i = end;
+                }
+                _ => i += 1,
+            }
+        }
+        output.push_str(&text[copied_up_to..]);
+        output
+    }
+
+    fn format_number(&self, number: &str) -> String {
+        if !number.contains(['.', 'e', 'E']) {
+            return self.separate_thousands(number);
+        }
+        let value: f64 = match number.parse() {
+            Ok(value) => value,
+            Err(_) => return number.to_owned(),
+        };
+        if let Some(threshold) = self.scientific_threshold {
+            if value != 0.0 && (value >= threshold || value < 1.0 / threshold) {
+                return match self.significant_digits {
+                    Some(digits) => format!("{:.*e}", digits - 1, value),
+                    None => format!("{:e}", value),
+                };
+            }
+        }
+        let fixed = match self.significant_digits {
+            Some(digits) if value != 0.0 => {
+                let magnitude = value.log10().floor() as i32;
+                let decimals = digits as i32 - 1 - magnitude;
+                if decimals >= 0 {
+                    format!("{:.*}", decimals as usize, value)
+                } else {
+                    let scale = 10f64.powi(-decimals);
+                    format!("{:.0}", (value / scale).round() * scale)
+                }
+            }
+            _ => number.to_owned(),
+        };
+        self.separate_thousands(&fixed)
+    }
+
+    /// Inserts our separator, if any, into the integer part of `number`.
+    fn separate_thousands(&self, number: &str) -> String {
+        let separator = match self.thousands_separator {
+            Some(separator) => separator,
+            None => return number.to_owned(),
+        };
+        let integer_len = number
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(number.len());
+        let mut output = String::new();
+        for (index, c) in number[..integer_len].chars().enumerate() {
+            if index > 0 && (integer_len - index) % 3 == 0 {
+                output.push(separator);
+            }
+            output.push(c);
+        }
+        output.push_str(&number[integer_len..]);
+        output
+    }
+}
+
+impl std::fmt::Display for NumberFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if *self == NumberFormat::default() {
+            return write!(f, "off");
+        }
+        let mut settings = Vec::new();
+        match self.thousands_separator {
+            Some(' ') => settings.push("sep=space".to_owned()),
+            Some(separator) => settings.push(format!("sep={}", separator)),
+            None => {}
+        }
+        if let Some(threshold) = self.scientific_threshold {
+            settings.push(format!("sci={:e}", threshold));
+        }
+        if let Some(digits) = self.significant_digits {
+            settings.push(format!("sig={}", digits));
+        }
+        write!(f, "{}", settings.join(" "))
+    }
+}
+
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'.'
+}
+
+/// Returns the end of the number starting at `start`, which must be a digit.
+fn end_of_number(bytes: &[u8], start: usize) -> usize {
+    let digits_from = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+    let mut end = digits_from(start);
+    if end + 1 < bytes.len() && bytes[end] == b'.' && bytes[end + 1].is_ascii_digit() {
+        end = digits_from(end + 1);
+    }
+    if end < bytes.len() && (bytes[end] == b'e' || bytes[end] == b'E') {
+        let mut exponent = end + 1;
+        if exponent < bytes.len() && (bytes[exponent] == b'-' || bytes[exponent] == b'+') {
+            exponent += 1;
+        }
+        if exponent < bytes.len() && bytes[exponent].is_ascii_digit() {
+            end = digits_from(exponent);
+        }
+    }
+    end
+}
+
+/// Returns the index just past the string literal that starts at `start`.
+fn end_of_string_literal(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the index just past the char literal that starts at `start`. If it doesn't look like a
+/// char literal, just skips the quote.
+fn end_of_char_literal(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    if i < bytes.len() && bytes[i] == b'\\' {
+        i += 1;
+    }
+    // Longest escape is \u{10FFFF}, longest UTF-8 character is 4 bytes.
+    let limit = (i + 9).min(bytes.len());
+    match bytes[i.min(limit)..limit].iter().position(|b| *b == b'\'') {
+        Some(offset) if offset > 0 => i + offset + 1,
+        _ => start + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NumberFormat;
+
+    fn format(settings: &str, text: &str) -> String {
+        NumberFormat::default()
+            .with_settings(settings)
+            .unwrap()
+            .apply(text)
+    }
+
+    #[test]
+    fn thousands_separator() {
+        assert_eq!(format("sep=,", "1234567"), "1,234,567");
+        assert_eq!(format("sep=_", "-1234.5678"), "-1_234.5678");
+        assert_eq!(format("sep=space", "[100, 1000]"), "[100, 1 000]");
+        assert_eq!(format("sep=none", "1234567"), "1234567");
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(format("sig=3", "3.14159"), "3.14");
+        assert_eq!(format("sig=3", "12345.6"), "12300");
+        assert_eq!(format("sig=2", "0.000123456"), "0.00012");
+        // Integers aren't affected by sig or sci.
+        assert_eq!(format("sig=2 sci=1e3", "123456"), "123456");
+        assert_eq!(format("sci=1e6", "12345678.9"), "1.23456789e7");
+        assert_eq!(
+            format("sci=1e6 sig=3", "[0.0000001, 2.5]"),
+            "[1.00e-7, 2.50]"
+        );
+        assert_eq!(format("sci=1e6", "0.0"), "0.0");
+    }
+
+    #[test]
+    fn leaves_non_numbers_alone() {
+        let format = NumberFormat::default()
+            .with_settings("sep=, sig=2")
+            .unwrap();
+        assert_eq!(
+            format.apply("Point { x1: 12345, name: \"12345 \\\"6789\\\"\", c: '9' }"),
+            "Point { x1: 12,345, name: \"12345 \\\"6789\\\"\", c: '9' }"
+        );
+        assert_eq!(format.apply("0x1234 v1.2.3 1.2.3"), "0x1234 v1.2.3 1.2.3");
+        assert_eq!(format.apply("NaN inf"), "NaN inf");
+    }
+
+    #[test]
+    fn settings() {
+        let format = NumberFormat::default()
+            .with_settings("sep=, sci=1e6")
+            .unwrap();
+        assert_eq!(format.to_string(), "sep=, sci=1e6");
+        let format = format.with_settings("sig=4 sep=none").unwrap();
+        assert_eq!(format.to_string(), "sci=1e6 sig=4");
+        assert_eq!(format.with_settings("off").unwrap().to_string(), "off");
+        assert!(format.with_settings("sig=0").is_err());
+        assert!(format.with_settings("sci=0.5").is_err());
+        assert!(format.with_settings("sep=ab").is_err());
+        assert!(format.with_settings("digits=3").is_err());
+    }
+}
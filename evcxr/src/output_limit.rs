@@ -0,0 +1,206 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limiting how much stdout a single evaluation can produce, so that code that prints in a tight
+//! loop doesn't overwhelm the frontend, and how many lines of a result are shown.
+
+const BYTES_PER_MB: usize = 1024 * 1024;
+
+/// The default limit on stdout per evaluation.
+pub(crate) const DEFAULT_OUTPUT_LIMIT: Option<usize> = Some(10 * BYTES_PER_MB);
+
+/// Counts the stdout produced by an evaluation and drops lines once it exceeds a limit.
+pub(crate) struct OutputLimiter {
+    limit: Option<usize>,
+    bytes: usize,
+    truncated: bool,
+}
+
+impl OutputLimiter {
+    pub(crate) fn new(limit: Option<usize>) -> OutputLimiter {
+        OutputLimiter {
+            limit,
+            bytes: 0,
+            truncated: false,
+        }
+    }
+
+    /// Returns the line to pass on in place of `line`. This is `line` itself until the limit is
+    /// reached, then a notice that output was truncated, then nothing.
+    pub(crate) fn filter(&mut self, line: String) -> Option<String> {
+        if self.truncated {
+            return None;
+        }
+        self.bytes += line.len() + 1;
+        match self.limit {
+            Some(limit) if self.bytes > limit => {
+                self.truncated = true;
+                Some(format!(
+                    "Output exceeded {}, truncated. Use :output_limit to change the limit.",
+                    format_limit(Some(limit))
+                ))
+            }
+            _ => Some(line),
+        }
+    }
+}
+
+/// Parses an argument to `:output_limit`, which is a number of megabytes or "off".
+pub(crate) fn parse_limit(value: &str) -> Option<Option<usize>> {
+    let value = value.trim();
+    if value == "off" {
+        return Some(None);
+    }
+    let megabytes: f64 = value.parse().ok()?;
+    if megabytes <= 0.0 || !megabytes.is_finite() {
+        return None;
+    }
+    Some(Some((megabytes * BYTES_PER_MB as f64) as usize))
+}
+
+pub(crate) fn format_limit(limit: Option<usize>) -> String {
+    match limit {
+        Some(bytes) => format!("{} MB", bytes as f64 / BYTES_PER_MB as f64),
+        None => "off".to_owned(),
+    }
+}
+
+/// A result's text with all but the first lines hidden.
+pub(crate) struct TruncatedText {
+    /// The first lines, followed by a note saying how many were hidden.
+    pub(crate) text: String,
+    /// The first lines, with the rest behind a "show more" toggle.
+    pub(crate) html: String,
+}
+
+/// Returns `text` truncated to `max_lines` lines, if it's longer than that.
+pub(crate) fn truncate_lines(text: &str, max_lines: Option<usize>) -> Option<TruncatedText> {
+    let max_lines = max_lines?;
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() <= max_lines {
+        return None;
+    }
+    let (shown, hidden) = lines.split_at(max_lines);
+    let shown = shown.join("\n");
+    let hidden_lines = if hidden.len() == 1 {
+        "1 more line".to_owned()
+    } else {
+        format!("{} more lines", hidden.len())
+    };
+    Some(TruncatedText {
+        text: format!(
+            "{}\n... {}. Use :max_output_lines to change the limit.",
+            shown, hidden_lines
+        ),
+        html: format!(
+            "<pre>{}</pre><details><summary>Show {}</summary><pre>{}</pre></details>",
+            escape_html(&shown),
+            hidden_lines,
+            escape_html(&hidden.join("\n"))
+        ),
+    })
+}
+
+/// Parses an argument to `:max_output_lines`, which is a positive number of lines or "off".
+pub(crate) fn parse_max_lines(value: &str) -> Option<Option<usize>> {
+    match value.trim() {
+        "off" => Some(None),
+        value => match value.parse() {
+            Ok(0) | Err(_) => None,
+            Ok(lines) => Some(Some(lines)),
+        },
+    }
+}
+
+pub(crate) fn format_max_lines(max_lines: Option<usize>) -> String {
+    match max_lines {
+        Some(lines) => lines.to_string(),
+        None => "off".to_owned(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_limit;
+    use super::format_max_lines;
+    use super::parse_limit;
+    use super::parse_max_lines;
+    use super::truncate_lines;
+    use super::OutputLimiter;
+
+    #[test]
+    fn truncates_once_limit_exceeded() {
+        let mut limiter = OutputLimiter::new(Some(10));
+        assert_eq!(limiter.filter("12345".to_owned()), Some("12345".to_owned()));
+        assert_eq!(limiter.filter("123".to_owned()), Some("123".to_owned()));
+        let notice = limiter.filter("1".to_owned()).unwrap();
+        assert!(notice.starts_with("Output exceeded 0.00000"));
+        assert_eq!(limiter.filter("1".to_owned()), None);
+
+        let mut limiter = OutputLimiter::new(None);
+        assert_eq!(
+            limiter.filter("x".repeat(100_000)).map(|line| line.len()),
+            Some(100_000)
+        );
+    }
+
+    #[test]
+    fn parse_and_format() {
+        assert_eq!(parse_limit("off"), Some(None));
+        assert_eq!(parse_limit("2"), Some(Some(2 * 1024 * 1024)));
+        assert_eq!(parse_limit("0.5"), Some(Some(512 * 1024)));
+        assert_eq!(parse_limit("0"), None);
+        assert_eq!(parse_limit("lots"), None);
+        assert_eq!(format_limit(Some(10 * 1024 * 1024)), "10 MB");
+        assert_eq!(format_limit(Some(512 * 1024)), "0.5 MB");
+        assert_eq!(format_limit(None), "off");
+    }
+
+    #[test]
+    fn truncates_long_results() {
+        assert!(truncate_lines("a\nb", Some(2)).is_none());
+        assert!(truncate_lines("a\nb\nc", None).is_none());
+        let truncated = truncate_lines("a\n<b>\nc\nd", Some(1)).unwrap();
+        assert_eq!(
+            truncated.text,
+            "a\n... 3 more lines. Use :max_output_lines to change the limit."
+        );
+        assert_eq!(
+            truncated.html,
+            "<pre>a</pre><details><summary>Show 3 more lines</summary>\
+             <pre>&lt;b&gt;\nc\nd</pre></details>"
+        );
+        assert!(truncate_lines("a\nb", Some(1))
+            .unwrap()
+            .text
+            .starts_with("a\n... 1 more line."));
+    }
+
+    #[test]
+    fn parse_and_format_max_lines() {
+        assert_eq!(parse_max_lines("off"), Some(None));
+        assert_eq!(parse_max_lines(" 100 "), Some(Some(100)));
+        assert_eq!(parse_max_lines("0"), None);
+        assert_eq!(parse_max_lines("many"), None);
+        assert_eq!(format_max_lines(Some(100)), "100");
+        assert_eq!(format_max_lines(None), "off");
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rewrites the panic messages and backtraces that the subprocess writes to stderr, so that they
+//! point at the user's cells rather than at lines of the crate that we generated. The subprocess
+//! runs with `RUST_BACKTRACE=1`, so panics that reach the default panic hook come with a short
+//! backtrace. Frames in code that we generated are dropped, as are the frames of the panic
+//! machinery and those outside of the outermost frame in user code. Frames in other crates are
+//! kept as they are. Locations in user code are written as `<cell N>:line:column`, where cells are
+//! numbered in the order in which they were evaluated.
+
+use crate::code_block::CodeBlock;
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// What we need to know about the code that's running in order to map locations back to cells.
+pub(crate) struct PanicSource {
+    code: CodeBlock,
+    crate_dir: PathBuf,
+    cell_number: usize,
+}
+
+impl PanicSource {
+    pub(crate) fn new(code: CodeBlock, crate_dir: &Path, cell_number: usize) -> PanicSource {
+        PanicSource {
+            code,
+            crate_dir: crate_dir.to_owned(),
+            cell_number,
+        }
+    }
+}
+
+pub(crate) type SharedPanicSource = Arc<Mutex<Option<PanicSource>>>;
+
+/// Rewrites lines of stderr, one at a time. Backtraces are held back until they're complete.
+pub(crate) struct PanicRewriter {
+    source: SharedPanicSource,
+    /// The frames of the backtrace that we're reading, if any.
+    frames: Option<Vec<Frame>>,
+}
+
+#[derive(Debug)]
+struct Frame {
+    name: String,
+    location: Option<String>,
+}
+
+/// Where a location in a backtrace came from.
+#[derive(Debug, PartialEq, Eq)]
+enum Origin {
+    /// Not the crate that we generated, e.g. the standard library or a dependency.
+    Elsewhere,
+    /// Code that we generated.
+    Generated,
+    /// User code, at the location given by the label.
+    User(String),
+}
+
+impl PanicRewriter {
+    pub(crate) fn new(source: SharedPanicSource) -> PanicRewriter {
+        PanicRewriter {
+            source,
+            frames: None,
+        }
+    }
+
+    /// Processes a line of stderr, returning the lines that should be output in its place.
+    pub(crate) fn process_line(&mut self, line: String) -> Vec<String> {
+        static FRAME: OnceCell<Regex> = OnceCell::new();
+        let frame = FRAME.get_or_init(|| Regex::new("^ +[0-9]+: (.*)$").unwrap());
+        static FRAME_LOCATION: OnceCell<Regex> = OnceCell::new();
+        let frame_location = FRAME_LOCATION.get_or_init(|| Regex::new("^ +at (.*)$").unwrap());
+        if let Some(frames) = self.frames.as_mut() {
+            if let Some(captures) = frame.captures(&line) {
+                frames.push(Frame {
+                    name: captures[1].to_owned(),
+                    location: None,
+                });
+                return Vec::new();
+            }
+            if let (Some(captures), Some(last)) =
+                (frame_location.captures(&line), frames.last_mut())
+            {
+                if last.location.is_none() {
+                    last.location = Some(captures[1].to_owned());
+                    return Vec::new();
+                }
+            }
+            let frames = self.frames.take().unwrap_or_default();
+            let mut output = self.format_frames(frames);
+            output.extend(self.process_line(line));
+            return output;
+        }
+        if line == "stack backtrace:" {
+            self.frames = Some(Vec::new());
+            return vec![line];
+        }
+        vec![self.rewrite_panic_line(line)]
+    }
+
+    /// Rewrites the location in the line that starts a panic message, if it's in user code. Rust
+    /// versions before 1.73 put the message on the same line, before the location.
+    fn rewrite_panic_line(&self, line: String) -> String {
+        const PANICKED_AT: &str = "' panicked at ";
+        let location_start = match line.find(PANICKED_AT) {
+            Some(index) if line.starts_with("thread '") => {
+                let rest = &line[index + PANICKED_AT.len()..];
+                if rest.starts_with('\'') {
+                    match rest.rfind(", ") {
+                        Some(comma) => index + PANICKED_AT.len() + comma + 2,
+                        None => return line,
+                    }
+                } else {
+                    index + PANICKED_AT.len()
+                }
+            }
+            _ => return line,
+        };
+        let (before, location) = line.split_at(location_start);
+        let (location, after) = match location.strip_suffix(':') {
+            Some(location) => (location, ":"),
+            None => (location, ""),
+        };
+        match self.origin(location) {
+            Origin::User(label) => format!("{}{}{}", before, label, after),
+            _ => line,
+        }
+    }
+
+    fn format_frames(&self, frames: Vec<Frame>) -> Vec<String> {
+        let origins: Vec<Origin> = frames
+            .iter()
+            .map(|frame| {
+                frame
+                    .location
+                    .as_ref()
+                    .map_or(Origin::Elsewhere, |location| self.origin(location))
+            })
+            .collect();
+        let kept: Vec<(Frame, Origin)> = match origins
+            .iter()
+            .rposition(|origin| matches!(origin, Origin::User(_)))
+        {
+            Some(outermost) => frames
+                .into_iter()
+                .zip(origins)
+                .take(outermost + 1)
+                .filter(|(frame, origin)| {
+                    *origin != Origin::Generated && !is_panic_machinery(&frame.name)
+                })
+                .collect(),
+            // There's nothing from the user's code, so it's best to leave the backtrace alone.
+            None => frames.into_iter().zip(origins).collect(),
+        };
+        let mut output = Vec::new();
+        for (index, (frame, origin)) in kept.into_iter().enumerate() {
+            output.push(format!("{:>4}: {}", index, self.frame_name(&frame.name)));
+            match (origin, frame.location) {
+                (Origin::User(label), _) => output.push(format!("             at {}", label)),
+                (_, Some(location)) => output.push(format!("             at {}", location)),
+                (_, None) => {}
+            }
+        }
+        output
+    }
+
+    /// Returns where `location`, e.g. "src/lib.rs:12:5", came from.
+    fn origin(&self, location: &str) -> Origin {
+        static LOCATION: OnceCell<Regex> = OnceCell::new();
+        let location_regex =
+            LOCATION.get_or_init(|| Regex::new("^(.*):([0-9]+):([0-9]+)$").unwrap());
+        let source = self.source.lock().unwrap();
+        let (source, captures) = match (source.as_ref(), location_regex.captures(location)) {
+            (Some(source), Some(captures)) => (source, captures),
+            _ => return Origin::Elsewhere,
+        };
+        let path = &captures[1];
+        if path != "src/lib.rs"
+            && path != "./src/lib.rs"
+            && Path::new(path) != source.crate_dir.join("src").join("lib.rs")
+        {
+            return Origin::Elsewhere;
+        }
+        let (line, column) = match (captures[2].parse(), captures[3].parse::<usize>()) {
+            (Ok(line), Ok(column)) => (line, column),
+            _ => return Origin::Elsewhere,
+        };
+        match source.code.cell_line_for_generated_line(line) {
+            Some(cell_line) => Origin::User(format!(
+                "{}:{}:{}",
+                cell_label(cell_line.cell.unwrap_or(source.cell_number)),
+                cell_line.line,
+                column + cell_line.column_offset
+            )),
+            None => Origin::Generated,
+        }
+    }
+
+    /// Returns the name of a frame with the name of our crate removed. The function that we wrap
+    /// around each cell's code is named after the cell instead.
+    fn frame_name(&self, name: &str) -> String {
+        let name = name.strip_prefix("ctx::").unwrap_or(name);
+        if let Some(rest) = name.strip_prefix("run_user_code_") {
+            let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+            if let Some(source) = self.source.lock().unwrap().as_ref() {
+                return format!("{}{}", cell_label(source.cell_number), rest);
+            }
+        }
+        name.to_owned()
+    }
+}
+
+fn is_panic_machinery(name: &str) -> bool {
+    name == "rust_begin_unwind"
+        || name.starts_with("core::panicking::")
+        || name.starts_with("std::panicking::")
+}
+
+fn cell_label(cell_number: usize) -> String {
+    if cell_number == 0 {
+        "<cell>".to_owned()
+    } else {
+        format!("<cell {}>", cell_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PanicRewriter;
+    use super::PanicSource;
+    use crate::code_block::CodeBlock;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    fn rewriter() -> PanicRewriter {
+        let (mut earlier, _nodes) =
+            CodeBlock::from_original_user_code("fn f(v: &[i32]) -> i32 {\n    v[5]\n}");
+        earlier.commit_old_user_code(1);
+        let (current, _nodes) = CodeBlock::from_original_user_code("let v = vec![1];\nf(&v);");
+        // Line 5 is the first line of the current cell.
+        let code = CodeBlock::new()
+            .add_all(earlier)
+            .generated("pub extern \"C\" fn run_user_code_7() {")
+            .add_all(current)
+            .generated("}");
+        PanicRewriter::new(Arc::new(Mutex::new(Some(PanicSource::new(
+            code,
+            Path::new("/tmp/evcxr"),
+            2,
+        )))))
+    }
+
+    fn process(rewriter: &mut PanicRewriter, stderr: &str) -> String {
+        let mut output = Vec::new();
+        for line in stderr.lines() {
+            output.extend(rewriter.process_line(line.to_owned()));
+        }
+        output.join("\n")
+    }
+
+    #[test]
+    fn backtrace() {
+        let stderr = "\
+thread '<unnamed>' panicked at src/lib.rs:2:5:
+index out of bounds: the len is 1 but the index is 5
+stack backtrace:
+   0: rust_begin_unwind
+             at /rustc/abc/library/std/src/panicking.rs:652:5
+   1: core::panicking::panic_fmt
+             at /rustc/abc/library/core/src/panicking.rs:72:14
+   2: core::panicking::panic_bounds_check
+             at /rustc/abc/library/core/src/panicking.rs:274:5
+   3: ctx::f
+             at ./src/lib.rs:2:5
+   4: ctx::run_user_code_7::{{closure}}
+             at /tmp/evcxr/src/lib.rs:6:1
+   5: std::panic::catch_unwind
+             at /rustc/abc/library/std/src/panic.rs:149:14
+   6: run_user_code_7
+             at ./src/lib.rs:4:1
+   7: evcxr::runtime::Runtime::load_and_run
+note: Some details are omitted, run with `RUST_BACKTRACE=full` for a verbose backtrace.";
+        assert_eq!(
+            process(&mut rewriter(), stderr),
+            "\
+thread '<unnamed>' panicked at <cell 1>:2:5:
+index out of bounds: the len is 1 but the index is 5
+stack backtrace:
+   0: f
+             at <cell 1>:2:5
+   1: <cell 2>::{{closure}}
+             at <cell 2>:2:1
+note: Some details are omitted, run with `RUST_BACKTRACE=full` for a verbose backtrace."
+        );
+    }
+
+    #[test]
+    fn old_panic_message_format() {
+        assert_eq!(
+            process(
+                &mut rewriter(),
+                "thread 'main' panicked at 'boom, really', src/lib.rs:6:3"
+            ),
+            "thread 'main' panicked at 'boom, really', <cell 2>:2:3"
+        );
+    }
+
+    #[test]
+    fn other_crates_unchanged() {
+        let stderr = "\
+thread 'worker' panicked at /home/me/.cargo/registry/src/foo-1.0/src/lib.rs:10:9:
+boom
+stack backtrace:
+   0: rust_begin_unwind
+             at /rustc/abc/library/std/src/panicking.rs:652:5
+   1: foo::bar
+             at /home/me/.cargo/registry/src/foo-1.0/src/lib.rs:10:9
+note: Some details are omitted, run with `RUST_BACKTRACE=full` for a verbose backtrace.";
+        assert_eq!(process(&mut rewriter(), stderr), stderr);
+        assert_eq!(
+            process(
+                &mut PanicRewriter::new(Arc::default()),
+                "thread 'main' panicked at src/lib.rs:6:3:"
+            ),
+            "thread 'main' panicked at src/lib.rs:6:3:"
+        );
+    }
+}
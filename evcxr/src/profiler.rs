@@ -0,0 +1,185 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:profile`, which samples the subprocess while it runs some user code, then
+//! renders the samples as a flamegraph. We use perf on Linux and dtrace on macOS. The subprocess
+//! is blocked waiting for input while we compile, so it only gets sampled while it's running code.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+
+/// How often to sample per second. Deliberately not a round number, so that we don't sample in
+/// lockstep with periodic activity in the code being profiled.
+const SAMPLE_FREQUENCY: u32 = 997;
+
+/// The profiler doesn't tell us when it has attached to the process, so we give it this long before
+/// starting to run code.
+const ATTACH_DELAY: Duration = Duration::from_millis(200);
+
+/// A profiler that's sampling the subprocess.
+pub(crate) struct Profiler {
+    process: Child,
+    /// Holds the file into which samples are written.
+    tempdir: tempfile::TempDir,
+}
+
+impl Profiler {
+    /// Starts sampling the process with ID `pid`.
+    pub(crate) fn start(pid: u32) -> Result<Profiler, Error> {
+        let tempdir = tempfile::tempdir()?;
+        let mut command = profiler_command(pid, &samples_path(tempdir.path()))?;
+        let program = command.get_program().to_string_lossy().into_owned();
+        let mut process = match command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(process) => process,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                bail!(":profile requires {} to be installed", program)
+            }
+            Err(error) => bail!("Failed to start {}: {}", program, error),
+        };
+        std::thread::sleep(ATTACH_DELAY);
+        // If the profiler has already exited, it most likely wasn't allowed to attach.
+        if process.try_wait()?.is_some() {
+            let output = process.wait_with_output()?;
+            bail!(
+                "{} failed to attach to the subprocess:\n{}",
+                program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(Profiler { process, tempdir })
+    }
+
+    /// Stops sampling and returns a flamegraph of the samples as SVG.
+    pub(crate) fn finish(mut self) -> Result<String, Error> {
+        stop(&self.process)?;
+        let output = self.process.wait_with_output()?;
+        let samples_path = samples_path(self.tempdir.path());
+        if !samples_path.exists() {
+            bail!(
+                "Profiler didn't write any samples:\n{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let mut folded = Vec::new();
+        fold_stacks(&samples_path, &mut folded)?;
+        let folded = String::from_utf8_lossy(&folded);
+        if folded.trim().is_empty() {
+            bail!(
+                "No samples were collected. The code may have finished too quickly to profile. Try \
+                 running it in a loop"
+            );
+        }
+        let mut options = inferno::flamegraph::Options::default();
+        options.title = "evcxr :profile".to_owned();
+        let mut svg = Vec::new();
+        inferno::flamegraph::from_lines(&mut options, folded.lines(), &mut svg)?;
+        Ok(String::from_utf8_lossy(&svg).into_owned())
+    }
+}
+
+fn samples_path(tempdir: &Path) -> PathBuf {
+    tempdir.join("samples")
+}
+
+#[cfg(target_os = "linux")]
+fn profiler_command(pid: u32, samples_path: &Path) -> Result<Command, Error> {
+    let mut command = Command::new("perf");
+    command
+        .arg("record")
+        .arg("-F")
+        .arg(SAMPLE_FREQUENCY.to_string())
+        .arg("-g")
+        .arg("-p")
+        .arg(pid.to_string())
+        .arg("-o")
+        .arg(samples_path);
+    Ok(command)
+}
+
+#[cfg(target_os = "macos")]
+fn profiler_command(pid: u32, samples_path: &Path) -> Result<Command, Error> {
+    let mut command = Command::new("dtrace");
+    command
+        .arg("-x")
+        .arg("ustackframes=100")
+        .arg("-n")
+        .arg(format!(
+            "profile-{} /pid == {}/ {{ @[ustack()] = count(); }}",
+            SAMPLE_FREQUENCY, pid
+        ))
+        .arg("-o")
+        .arg(samples_path);
+    Ok(command)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn profiler_command(_pid: u32, _samples_path: &Path) -> Result<Command, Error> {
+    bail!(":profile is only supported on Linux and macOS");
+}
+
+/// Asks the profiler to stop. Both perf and dtrace write out what they've collected when
+/// interrupted.
+#[cfg(unix)]
+fn stop(process: &Child) -> Result<(), Error> {
+    if unsafe { libc::kill(process.id() as libc::pid_t, libc::SIGINT) } != 0 {
+        bail!(
+            "Failed to stop profiler: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn stop(_process: &Child) -> Result<(), Error> {
+    bail!(":profile is only supported on Linux and macOS");
+}
+
+/// Converts the samples into "folded" form, one line per distinct stack with a count.
+#[cfg(target_os = "linux")]
+fn fold_stacks(samples_path: &Path, folded: &mut Vec<u8>) -> Result<(), Error> {
+    use inferno::collapse::Collapse;
+    let output = Command::new("perf")
+        .arg("script")
+        .arg("-i")
+        .arg(samples_path)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "perf script failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    inferno::collapse::perf::Folder::default().collapse(&output.stdout[..], folded)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fold_stacks(samples_path: &Path, folded: &mut Vec<u8>) -> Result<(), Error> {
+    use inferno::collapse::Collapse;
+    let samples = std::io::BufReader::new(std::fs::File::open(samples_path)?);
+    inferno::collapse::dtrace::Folder::default().collapse(samples, folded)?;
+    Ok(())
+}
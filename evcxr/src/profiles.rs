@@ -0,0 +1,189 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Named session profiles, each bundling dependencies, crate attributes and prelude code, which are
+//! used with `evcxr --profile <name>` or `:profiles use <name>`. They're defined in
+//! `profiles.evcxr` in the config directory. Each profile starts with its name in square brackets
+//! on a line of its own, which is followed by commands and code, as in a prelude:
+//!
+//! ```text
+//! [data-science]
+//! :dep polars = "0.25"
+//! :crate_attr allow(unused)
+//! use polars::prelude::*;
+//! ```
+
+use crate::errors::bail;
+use crate::errors::Error;
+use std::path::Path;
+use std::path::PathBuf;
+
+const PROFILES_FILE_NAME: &str = "profiles.evcxr";
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Profile {
+    pub(crate) name: String,
+    /// The 1-based line of the file on which the profile's code starts.
+    pub(crate) start_line: usize,
+    pub(crate) code: String,
+}
+
+/// Returns where profiles are defined, if there's a config directory.
+pub(crate) fn profiles_file() -> Option<PathBuf> {
+    crate::config_dir().map(|dir| dir.join(PROFILES_FILE_NAME))
+}
+
+/// Reads the profiles defined in `path`. There are none if it doesn't exist.
+pub(crate) fn load(path: &Path) -> Result<Vec<Profile>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => parse(&path.display().to_string(), &contents),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => bail!("Failed to read {}: {}", path.display(), error),
+    }
+}
+
+/// Returns the profile called `name`, or an error listing those that there are.
+pub(crate) fn find<'a>(profiles: &'a [Profile], name: &str) -> Result<&'a Profile, Error> {
+    match profiles.iter().find(|profile| profile.name == name) {
+        Some(profile) => Ok(profile),
+        None if profiles.is_empty() => bail!(
+            "No profile named `{}`. No profiles are defined in {}",
+            name,
+            describe_profiles_file()
+        ),
+        None => bail!(
+            "No profile named `{}`. Available profiles: {}",
+            name,
+            profiles
+                .iter()
+                .map(|profile| profile.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+pub(crate) fn describe_profiles_file() -> String {
+    match profiles_file() {
+        Some(path) => path.display().to_string(),
+        None => PROFILES_FILE_NAME.to_owned(),
+    }
+}
+
+/// Parses the contents of a profiles file. `file` is its name, for errors. Lines before the first
+/// profile may only be blank or comments.
+fn parse(file: &str, contents: &str) -> Result<Vec<Profile>, Error> {
+    let mut profiles: Vec<Profile> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if let Some(name) = header_name(line) {
+            if profiles.iter().any(|profile| profile.name == name) {
+                bail!(
+                    "{}:{}: Profile `{}` is defined more than once",
+                    file,
+                    index + 1,
+                    name
+                );
+            }
+            profiles.push(Profile {
+                name: name.to_owned(),
+                start_line: index + 2,
+                code: String::new(),
+            });
+        } else if let Some(profile) = profiles.last_mut() {
+            profile.code.push_str(line);
+            profile.code.push('\n');
+        } else if !line.trim().is_empty() && !line.trim_start().starts_with("//") {
+            bail!(
+                "{}:{}: Expected a profile name in square brackets, e.g. [data-science]",
+                file,
+                index + 1
+            );
+        }
+    }
+    Ok(profiles)
+}
+
+/// Returns the name of the profile that `line` starts, if it does.
+fn header_name(line: &str) -> Option<&str> {
+    let name = line.trim_end().strip_prefix('[')?.strip_suffix(']')?;
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find;
+    use super::parse;
+    use super::Profile;
+
+    #[test]
+    fn parsing() {
+        let profiles = parse(
+            "profiles.evcxr",
+            "// Profiles for evcxr.\n\n\
+             [data-science]\n\
+             :dep polars = \"0.25\"\n\
+             let v = [1, 2];\n\
+             [v]\n\
+             [web_2]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            profiles,
+            vec![
+                Profile {
+                    name: "data-science".to_owned(),
+                    start_line: 4,
+                    code: ":dep polars = \"0.25\"\nlet v = [1, 2];\n".to_owned(),
+                },
+                Profile {
+                    name: "v".to_owned(),
+                    start_line: 7,
+                    code: String::new(),
+                },
+                Profile {
+                    name: "web_2".to_owned(),
+                    start_line: 8,
+                    code: String::new(),
+                },
+            ]
+        );
+        assert_eq!(find(&profiles, "v").unwrap().start_line, 7);
+        assert_eq!(
+            find(&profiles, "web").unwrap_err().to_string(),
+            "No profile named `web`. Available profiles: data-science, v, web_2"
+        );
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert_eq!(
+            parse("p.evcxr", ":dep regex = \"1\"\n")
+                .unwrap_err()
+                .to_string(),
+            "p.evcxr:1: Expected a profile name in square brackets, e.g. [data-science]"
+        );
+        assert_eq!(
+            parse("p.evcxr", "[a]\n\n[a]\n").unwrap_err().to_string(),
+            "p.evcxr:3: Profile `a` is defined more than once"
+        );
+    }
+}
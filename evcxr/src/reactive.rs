@@ -0,0 +1,315 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reactive evaluation, which is turned on with `:reactive`. Notebook cells are identified by an ID
+//! that the frontend supplies, and we keep the latest code of each one. When a cell runs, the cells
+//! downstream of it, i.e. that read variables it defines or assigns to, directly or via other
+//! variables, are either invalidated or run again. Unlike `:rerun_dependents`, which follows the
+//! order in which code happened to run, this works on a graph of cells, so cycles between cells
+//! are possible and are reported as errors.
+//!
+//! Cells that update a variable in place, e.g. with `+=`, only pass it on to cells that first ran
+//! after them, otherwise any two cells that update the same variable would form a cycle.
+
+use crate::cell_dependencies::CellVariables;
+use crate::errors::bail;
+use crate::errors::Error;
+use std::collections::HashSet;
+
+/// What happens to downstream cells when a cell runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReactiveMode {
+    Off,
+    /// Variables defined by downstream cells are forgotten, so that stale values can't be used.
+    Invalidate,
+    /// Downstream cells are run again.
+    Recompute,
+}
+
+impl ReactiveMode {
+    pub(crate) fn parse(value: &str) -> Result<ReactiveMode, Error> {
+        Ok(match value {
+            "off" => ReactiveMode::Off,
+            "invalidate" => ReactiveMode::Invalidate,
+            "recompute" => ReactiveMode::Recompute,
+            _ => bail!(
+                "Reactive mode must be 'off', 'invalidate' or 'recompute', got '{}'",
+                value
+            ),
+        })
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ReactiveMode::Off => "off",
+            ReactiveMode::Invalidate => "invalidate",
+            ReactiveMode::Recompute => "recompute",
+        }
+    }
+}
+
+/// The latest code of each cell that has run successfully with an ID, in the order that the cells
+/// first ran.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReactiveCells {
+    cells: Vec<ReactiveCell>,
+}
+
+#[derive(Clone, Debug)]
+struct ReactiveCell {
+    id: String,
+    /// The code of the cell, without any commands.
+    code: String,
+    variables: CellVariables,
+}
+
+/// A cell that's downstream of one that's being run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DownstreamCell {
+    /// The code of the cell, without any commands.
+    pub(crate) code: String,
+    /// The variables that the cell defines, sorted. Variables that the cell being run also defines
+    /// are excluded, since they'll be up to date.
+    pub(crate) defines: Vec<String>,
+}
+
+impl ReactiveCells {
+    /// Records that the cell `id` ran successfully with `code`. Cells that only contain commands
+    /// have nothing to pass on, so are forgotten.
+    pub(crate) fn record(&mut self, id: &str, code: &str) {
+        match ReactiveCell::new(id, code) {
+            Some(cell) => match self.position(id) {
+                Some(index) => self.cells[index] = cell,
+                None => self.cells.push(cell),
+            },
+            None => self.forget(id),
+        }
+    }
+
+    /// Forgets the cell `id`, e.g. because it was deleted from the notebook.
+    pub(crate) fn forget(&mut self, id: &str) {
+        self.cells.retain(|cell| cell.id != id);
+    }
+
+    /// Returns the cells downstream of the cell `id`, were it to contain `code`, in an order in
+    /// which they can be run. Fails if the cells involved form a cycle.
+    pub(crate) fn downstream(&self, id: &str, code: &str) -> Result<Vec<DownstreamCell>, Error> {
+        let cell = match ReactiveCell::new(id, code) {
+            Some(cell) => cell,
+            None => return Ok(Vec::new()),
+        };
+        let mut cells = self.cells.clone();
+        let start = match self.position(id) {
+            Some(index) => {
+                cells[index] = cell;
+                index
+            }
+            None => {
+                cells.push(cell);
+                cells.len() - 1
+            }
+        };
+        let feeds = |from: usize, to: usize| {
+            let (source, target) = (&cells[from].variables, &cells[to].variables);
+            from != to
+                && target.reads.iter().any(|name| {
+                    source.defines.contains(name) || (from < to && source.updates.contains(name))
+                })
+        };
+        let reached = reachable(cells.len(), start, &feeds);
+        let cycle: Vec<&ReactiveCell> = (0..cells.len())
+            .filter(|index| reached[*index] && reachable(cells.len(), *index, &feeds)[*index])
+            .map(|index| &cells[index])
+            .collect();
+        if !cycle.is_empty() {
+            let mut message =
+                "These cells depend on each other, so can't be run reactively:".to_owned();
+            for cell in cycle {
+                message.push_str("\n  ");
+                message.push_str(cell.code.lines().next().unwrap_or_default());
+            }
+            bail!(message);
+        }
+        // Kahn's algorithm, preferring the cell that first ran earliest whenever there's a choice.
+        // There's no cycle, so there's always a cell that nothing remaining feeds.
+        let mut ordered = Vec::new();
+        let mut remaining = reached;
+        while let Some(next) = (0..cells.len()).find(|to| {
+            remaining[*to] && !(0..cells.len()).any(|from| remaining[from] && feeds(from, *to))
+        }) {
+            remaining[next] = false;
+            ordered.push(next);
+        }
+        let fresh = &cells[start].variables.defines;
+        Ok(ordered
+            .into_iter()
+            .map(|index| {
+                let cell = &cells[index];
+                let mut defines: Vec<String> =
+                    cell.variables.defines.difference(fresh).cloned().collect();
+                defines.sort();
+                DownstreamCell {
+                    code: cell.code.clone(),
+                    defines,
+                }
+            })
+            .collect())
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.cells.iter().position(|cell| cell.id == id)
+    }
+}
+
+impl ReactiveCell {
+    fn new(id: &str, code: &str) -> Option<ReactiveCell> {
+        // Whether variables are mutable only matters for calls that may update them, which we
+        // don't pass on.
+        let (code, variables) = CellVariables::analyze(code, &mut HashSet::new())?;
+        Some(ReactiveCell {
+            id: id.to_owned(),
+            code: code.to_owned(),
+            variables,
+        })
+    }
+}
+
+/// Returns which of `count` cells can be reached from `start` by following `feeds`. `start` is
+/// only included if it's part of a cycle.
+fn reachable(count: usize, start: usize, feeds: &dyn Fn(usize, usize) -> bool) -> Vec<bool> {
+    let mut reached = vec![false; count];
+    let mut pending = vec![start];
+    while let Some(from) = pending.pop() {
+        for (to, reached_to) in reached.iter_mut().enumerate() {
+            if !*reached_to && feeds(from, to) {
+                *reached_to = true;
+                pending.push(to);
+            }
+        }
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReactiveCells;
+    use super::ReactiveMode;
+
+    fn cells(cells: &[(&str, &str)]) -> ReactiveCells {
+        let mut reactive_cells = ReactiveCells::default();
+        for (id, code) in cells {
+            reactive_cells.record(id, code);
+        }
+        reactive_cells
+    }
+
+    fn downstream_code(cells: &ReactiveCells, id: &str, code: &str) -> Vec<String> {
+        cells
+            .downstream(id, code)
+            .unwrap()
+            .into_iter()
+            .map(|cell| cell.code)
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        for mode in [
+            ReactiveMode::Off,
+            ReactiveMode::Invalidate,
+            ReactiveMode::Recompute,
+        ] {
+            assert_eq!(ReactiveMode::parse(mode.name()).unwrap(), mode);
+        }
+        assert!(ReactiveMode::parse("on").is_err());
+    }
+
+    #[test]
+    fn downstream_in_dependency_order() {
+        let cells = cells(&[
+            ("c", "let z = y * 2;"),
+            ("a", "let x = 1;"),
+            ("b", ":dep regex\nlet y = x + 1;"),
+            ("d", "let unrelated = 3;"),
+        ]);
+        // "c" ran first, but reads `y`, which "b" defines.
+        assert_eq!(
+            downstream_code(&cells, "a", "let x = 10;"),
+            vec!["let y = x + 1;", "let z = y * 2;"]
+        );
+        let downstream = cells.downstream("b", "let y = x + 2;").unwrap();
+        assert_eq!(downstream.len(), 1);
+        assert_eq!(downstream[0].defines, vec!["z"]);
+        // The edited code is what counts, not what the cell contained before.
+        assert!(downstream_code(&cells, "b", "let w = 2;").is_empty());
+        assert!(downstream_code(&cells, "new", "let unrelated = 1;").is_empty());
+    }
+
+    #[test]
+    fn updates_only_pass_forward() {
+        let cells = cells(&[
+            ("a", "let mut total = 0;"),
+            ("b", "total += 1;"),
+            ("c", "total += 2;"),
+            ("d", "println!(\"{}\", total);"),
+        ]);
+        assert_eq!(
+            downstream_code(&cells, "b", "total += 5;"),
+            vec!["total += 2;", "println!(\"{}\", total);"]
+        );
+        assert_eq!(
+            downstream_code(&cells, "a", "let mut total = 10;"),
+            vec!["total += 1;", "total += 2;", "println!(\"{}\", total);"]
+        );
+    }
+
+    #[test]
+    fn defines_exclude_fresh_variables() {
+        let cells = cells(&[("a", "let x = 1;"), ("b", "let x = x + 1;\nlet y = x;")]);
+        // `x` is defined by the cell being run, so won't be stale, even though "b" redefines it.
+        let downstream = cells.downstream("a", "let x = 10;").unwrap();
+        assert_eq!(downstream.len(), 1);
+        assert_eq!(downstream[0].defines, vec!["y"]);
+    }
+
+    #[test]
+    fn cycle() {
+        let cells = cells(&[
+            ("a", "let x = 1;"),
+            ("b", "let y = x + 1;"),
+            ("c", "println!(\"{}\", y);"),
+        ]);
+        // The cell that prints `y` is downstream of the cycle, but not part of it.
+        let error = cells.downstream("a", "let x = y + 1;").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "These cells depend on each other, so can't be run reactively:\n  let x = y + 1;\n  \
+             let y = x + 1;"
+        );
+    }
+
+    #[test]
+    fn forget() {
+        let mut cells = cells(&[("a", "let x = 1;"), ("b", "let y = x;")]);
+        cells.forget("b");
+        assert!(downstream_code(&cells, "a", "let x = 2;").is_empty());
+        cells.record("b", "let y = x;");
+        assert_eq!(
+            downstream_code(&cells, "a", "let x = 2;"),
+            vec!["let y = x;"]
+        );
+        // Cells that only contain commands can't feed anything.
+        assert!(downstream_code(&cells, "a", ":vars").is_empty());
+    }
+}
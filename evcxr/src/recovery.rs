@@ -0,0 +1,217 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for recovering a session after the subprocess crashes. Definitions of items live in
+//! our state and are recompiled on every evaluation, so they survive a restart without us doing
+//! anything. Variables only exist in the subprocess, so while recovery mode is on, after each
+//! evaluation, the subprocess writes the `Debug` output of each variable that the code may have
+//! changed, and that we know how to recreate, to a file. Values whose output would be larger than
+//! `MAX_SNAPSHOT_BYTES` aren't kept. When the subprocess crashes, we start a new one and define
+//! the variables again from those files. Nothing with side effects is rerun. With a remote runtime,
+//! the files would be written on the other machine, so no variables are recovered.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The most `Debug` output that we keep for a variable.
+pub(crate) const MAX_SNAPSHOT_BYTES: usize = 64 * 1024;
+
+const INTEGER_TYPES: &[&str] = &[
+    "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+];
+const FLOAT_TYPES: &[&str] = &["f32", "f64"];
+
+/// Returns `type_name` with whitespace and common path prefixes removed.
+fn normalize_type(type_name: &str) -> String {
+    let mut normalized: String = type_name.chars().filter(|c| !c.is_whitespace()).collect();
+    for prefix in &[
+        "std::string::",
+        "alloc::string::",
+        "std::vec::",
+        "alloc::vec::",
+    ] {
+        normalized = normalized.replace(prefix, "");
+    }
+    normalized
+}
+
+fn is_scalar(type_name: &str) -> bool {
+    INTEGER_TYPES.contains(&type_name)
+        || FLOAT_TYPES.contains(&type_name)
+        || type_name == "bool"
+        || type_name == "char"
+}
+
+/// Returns the directory, within the crate directory `crate_dir`, that snapshots are written to.
+pub(crate) fn snapshot_dir(crate_dir: &Path) -> PathBuf {
+    crate_dir.join("variable_snapshots")
+}
+
+/// Returns the `Debug` output of `variable_name` that was last written to `snapshot_dir`, if any.
+pub(crate) fn read_snapshot(snapshot_dir: &Path, variable_name: &str) -> Option<String> {
+    std::fs::read_to_string(snapshot_dir.join(variable_name)).ok()
+}
+
+/// Returns whether we can recreate a variable of type `type_name` from its `Debug` output.
+pub(crate) fn can_snapshot(type_name: &str) -> bool {
+    let type_name = normalize_type(type_name);
+    if is_scalar(&type_name) || type_name == "String" {
+        return true;
+    }
+    match type_name
+        .strip_prefix("Vec<")
+        .and_then(|rest| rest.strip_suffix('>'))
+    {
+        Some(element) => is_scalar(element) || element == "String",
+        None => false,
+    }
+}
+
+/// Returns a Rust expression that evaluates to a value of type `type_name` whose `Debug` output
+/// was `debug`, or None if we can't produce one.
+pub(crate) fn restore_expression(type_name: &str, debug: &str) -> Option<String> {
+    let type_name = normalize_type(type_name);
+    if FLOAT_TYPES.contains(&type_name.as_str()) {
+        return Some(match debug {
+            "NaN" => format!("{}::NAN", type_name),
+            "inf" => format!("{}::INFINITY", type_name),
+            "-inf" => format!("{}::NEG_INFINITY", type_name),
+            _ => debug.to_owned(),
+        });
+    }
+    if is_scalar(&type_name) {
+        return Some(debug.to_owned());
+    }
+    if type_name == "String" {
+        return Some(format!("String::from({})", debug));
+    }
+    let element = type_name.strip_prefix("Vec<")?.strip_suffix('>')?;
+    if element == "String" {
+        Some(format!(
+            "vec!{}.into_iter().map(String::from).collect()",
+            debug
+        ))
+    } else if FLOAT_TYPES.contains(&element) {
+        // Non-finite values aren't valid literals. We could handle them, but it doesn't seem worth
+        // the complexity.
+        if debug.contains("NaN") || debug.contains("inf") {
+            None
+        } else {
+            Some(format!("vec!{}", debug))
+        }
+    } else if is_scalar(element) {
+        Some(format!("vec!{}", debug))
+    } else {
+        None
+    }
+}
+
+/// A variable that we're going to try to recreate.
+pub(crate) struct SnapshotVariable {
+    pub(crate) name: String,
+    pub(crate) type_name: String,
+    pub(crate) is_mut: bool,
+    pub(crate) debug: Option<String>,
+}
+
+/// The code needed to recreate variables, and the names of those that we can't recreate.
+pub(crate) struct RestorePlan {
+    pub(crate) code: String,
+    pub(crate) restored: Vec<String>,
+    pub(crate) lost: Vec<String>,
+}
+
+pub(crate) fn restore_plan(mut variables: Vec<SnapshotVariable>) -> RestorePlan {
+    variables.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut plan = RestorePlan {
+        code: String::new(),
+        restored: Vec::new(),
+        lost: Vec::new(),
+    };
+    for variable in variables {
+        let expression = variable
+            .debug
+            .as_deref()
+            .and_then(|debug| restore_expression(&variable.type_name, debug));
+        match expression {
+            Some(expression) => {
+                plan.code.push_str(&format!(
+                    "let {}{}: {} = {};\n",
+                    if variable.is_mut { "mut " } else { "" },
+                    variable.name,
+                    variable.type_name,
+                    expression
+                ));
+                plan.restored.push(variable.name);
+            }
+            None => plan.lost.push(variable.name),
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::can_snapshot;
+    use super::restore_expression;
+    use super::restore_plan;
+    use super::SnapshotVariable;
+
+    #[test]
+    fn test_can_snapshot() {
+        assert!(can_snapshot("i32"));
+        assert!(can_snapshot("std::string::String"));
+        assert!(can_snapshot("Vec<u8>"));
+        assert!(can_snapshot("alloc::vec::Vec<alloc::string::String>"));
+        assert!(!can_snapshot("Vec<Vec<u8>>"));
+        assert!(!can_snapshot("HashMap<String, i32>"));
+        assert!(!can_snapshot("&str"));
+    }
+
+    #[test]
+    fn test_restore_expression() {
+        assert_eq!(restore_expression("f64", "NaN").unwrap(), "f64::NAN");
+        assert_eq!(restore_expression("f32", "-1.5").unwrap(), "-1.5");
+        assert_eq!(
+            restore_expression("String", r#""a\"b\n""#).unwrap(),
+            r#"String::from("a\"b\n")"#
+        );
+        assert_eq!(
+            restore_expression("Vec<String>", r#"["a", "b"]"#).unwrap(),
+            r#"vec!["a", "b"].into_iter().map(String::from).collect()"#
+        );
+        assert_eq!(restore_expression("Vec<f64>", "[1.0, NaN]"), None);
+    }
+
+    #[test]
+    fn test_restore_plan() {
+        let plan = restore_plan(vec![
+            SnapshotVariable {
+                name: "b".to_owned(),
+                type_name: "Vec<i32>".to_owned(),
+                is_mut: true,
+                debug: Some("[1, 2]".to_owned()),
+            },
+            SnapshotVariable {
+                name: "a".to_owned(),
+                type_name: "Foo".to_owned(),
+                is_mut: false,
+                debug: None,
+            },
+        ]);
+        assert_eq!(plan.code, "let mut b: Vec<i32> = vec![1, 2];\n");
+        assert_eq!(plan.restored, vec!["b".to_owned()]);
+        assert_eq!(plan.lost, vec!["a".to_owned()]);
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running user code on another machine. Compilation still happens locally. Each shared object
+//! that we build is uploaded to the remote runtime over the same connection that we use to tell it
+//! what to run. The remote machine needs to have the same OS and architecture as the local one,
+//! but doesn't need a Rust toolchain, since we upload the standard library too.
+
+use crate::child_process::ChildProcess;
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::runtime;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// How to reach a remote runtime.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoteTransport {
+    /// Starts the runtime via ssh. `runtime_command` is run on `destination` and should be a
+    /// binary that calls `evcxr::runtime_hook()`, e.g. `evcxr`.
+    Ssh {
+        destination: String,
+        runtime_command: String,
+    },
+    /// Connects to a runtime that's listening on `address`. The remote runtime is started by
+    /// running a binary that calls `evcxr::runtime_hook()` with `EVCXR_RUNTIME_LISTEN` set to the
+    /// address to listen on. Each connection gets a fresh process. Nothing is encrypted or
+    /// authenticated, so this should only be used on trusted networks or through a tunnel.
+    Tcp { address: String },
+}
+
+impl RemoteTransport {
+    pub(crate) fn start(
+        &self,
+        stdout_sender: crossbeam_channel::Sender<String>,
+        stderr_sender: crossbeam_channel::Sender<String>,
+    ) -> Result<ChildProcess, Error> {
+        match self {
+            RemoteTransport::Ssh {
+                destination,
+                runtime_command,
+            } => {
+                let mut command = std::process::Command::new("ssh");
+                // ssh doesn't pass through environment variables, so we set the ones that our
+                // runtime needs on the remote side.
+                command
+                    .arg("-T")
+                    .arg(destination)
+                    .arg("env")
+                    .arg(format!("{}=1", runtime::EVCXR_IS_RUNTIME_VAR))
+                    .arg("RUST_BACKTRACE=1")
+                    .arg(runtime_command);
+                ChildProcess::new(command, stdout_sender, stderr_sender)
+            }
+            RemoteTransport::Tcp { address } => {
+                ChildProcess::connect(address, stdout_sender, stderr_sender)
+            }
+        }
+    }
+}
+
+/// Tracks what we've sent to a remote runtime, so that each file only needs to be sent once per
+/// process.
+#[derive(Default)]
+pub(crate) struct RemoteFiles {
+    std_loaded: bool,
+}
+
+impl RemoteFiles {
+    /// Uploads `so_file`, first loading the standard library if we haven't already, and returns
+    /// the name by which the runtime knows the file.
+    pub(crate) fn upload(
+        &mut self,
+        child_process: &mut ChildProcess,
+        so_file: &Path,
+        std_lib_dir: &Path,
+    ) -> Result<String, Error> {
+        if !self.std_loaded {
+            // Our shared objects link dynamically against std. The remote loader will use a
+            // library that's already loaded if it has the right soname, so loading std before
+            // anything else means it doesn't need to be found on disk.
+            let std_lib = find_std_lib(std_lib_dir)?;
+            let name = upload_file(child_process, &std_lib)?;
+            child_process.send(&format!("LOAD {}", name))?;
+            self.std_loaded = true;
+        }
+        upload_file(child_process, so_file)
+    }
+
+    /// Called when the runtime process is restarted, since a new process has none of our files.
+    pub(crate) fn reset(&mut self) {
+        self.std_loaded = false;
+    }
+}
+
+fn upload_file(child_process: &mut ChildProcess, path: &Path) -> Result<String, Error> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => bail!("Can't upload {}, since it has no file name", path.display()),
+    };
+    let contents = std::fs::read(path)?;
+    child_process.send(&format!("UPLOAD {} {}", name, contents.len()))?;
+    child_process.send_bytes(&contents)?;
+    Ok(name)
+}
+
+fn find_std_lib(std_lib_dir: &Path) -> Result<PathBuf, Error> {
+    let prefix = format!("{}std-", std::env::consts::DLL_PREFIX);
+    let entries = match std::fs::read_dir(std_lib_dir) {
+        Ok(entries) => entries,
+        Err(error) => bail!("Couldn't read {}: {}", std_lib_dir.display(), error),
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if file_name.starts_with(&prefix) && file_name.ends_with(std::env::consts::DLL_SUFFIX) {
+            return Ok(entry.path());
+        }
+    }
+    bail!(
+        "Couldn't find the standard library shared object in {}",
+        std_lib_dir.display()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_std_lib;
+
+    #[test]
+    fn test_find_std_lib() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = format!(
+            "{}std-0123abcd{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_SUFFIX
+        );
+        std::fs::write(dir.path().join("libstd-0123abcd.rlib"), "").unwrap();
+        assert!(find_std_lib(dir.path()).is_err());
+        std::fs::write(dir.path().join(&name), "").unwrap();
+        assert_eq!(find_std_lib(dir.path()).unwrap(), dir.path().join(name));
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The values of displayed expressions. When code ends with an expression, its value is kept in a
+//! variable named after its number, e.g. `_3`, so that later code can use it without it having
+//! been assigned. The variables are otherwise just like those that the user defines.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// How many results we keep. When there are more, the oldest is dropped.
+pub(crate) const MAX_RESULTS: usize = 10;
+
+/// Expressions longer than this are abbreviated when listed by `:results`.
+const MAX_EXPRESSION_CHARS: usize = 50;
+
+/// The name that code can use to refer to the most recent result.
+pub(crate) const LATEST_RESULT: &str = "__";
+
+#[derive(Clone, Debug)]
+pub(crate) struct ResultHistory {
+    next_number: usize,
+    /// The code of each result's expression, by number.
+    expressions: BTreeMap<usize, String>,
+}
+
+impl Default for ResultHistory {
+    fn default() -> ResultHistory {
+        ResultHistory {
+            next_number: 1,
+            expressions: BTreeMap::new(),
+        }
+    }
+}
+
+impl ResultHistory {
+    /// Records a result that's about to be computed by `expression`. Returns the name of the
+    /// variable to store it in and, if there are now too many results, the name of the variable
+    /// holding the oldest, which should be forgotten.
+    pub(crate) fn add(&mut self, expression: &str) -> (String, Option<String>) {
+        let number = self.next_number;
+        self.next_number += 1;
+        self.expressions
+            .insert(number, expression.trim().to_owned());
+        let mut oldest = None;
+        if self.expressions.len() > MAX_RESULTS {
+            let oldest_number = *self.expressions.keys().next().unwrap();
+            self.expressions.remove(&oldest_number);
+            oldest = Some(variable_name(oldest_number));
+        }
+        (variable_name(number), oldest)
+    }
+
+    /// Returns whether `name` is the variable of a result.
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        matches!(
+            name.strip_prefix('_').and_then(|number| number.parse().ok()),
+            Some(number) if self.expressions.contains_key(&number)
+        )
+    }
+
+    /// Forgets results for which `is_kept` returns false when given the result's variable, e.g.
+    /// because the value couldn't be stored.
+    pub(crate) fn retain(&mut self, mut is_kept: impl FnMut(&str) -> bool) {
+        self.expressions
+            .retain(|number, _| is_kept(&variable_name(*number)));
+    }
+
+    /// Returns the variable holding the most recent result.
+    pub(crate) fn latest(&self) -> Option<String> {
+        self.expressions
+            .keys()
+            .next_back()
+            .map(|n| variable_name(*n))
+    }
+
+    /// Returns the listing shown by `:results`. `type_name` returns the type of a variable.
+    pub(crate) fn summary<'a>(&self, type_name: impl Fn(&str) -> Option<&'a str>) -> String {
+        let mut summary = String::new();
+        for (number, expression) in &self.expressions {
+            let name = variable_name(*number);
+            if let Some(type_name) = type_name(&name) {
+                writeln!(
+                    summary,
+                    "{}: {} = {}",
+                    name,
+                    type_name,
+                    abbreviate(expression)
+                )
+                .unwrap();
+            }
+        }
+        if summary.is_empty() {
+            summary.push_str("No results\n");
+        }
+        summary
+    }
+}
+
+fn variable_name(number: usize) -> String {
+    format!("_{}", number)
+}
+
+/// Puts `expression` on one line, shortening it if it's long.
+fn abbreviate(expression: &str) -> String {
+    let expression = expression.split_whitespace().collect::<Vec<_>>().join(" ");
+    if expression.chars().count() <= MAX_EXPRESSION_CHARS {
+        return expression;
+    }
+    let mut abbreviated: String = expression.chars().take(MAX_EXPRESSION_CHARS - 3).collect();
+    abbreviated.push_str("...");
+    abbreviated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResultHistory;
+    use super::MAX_RESULTS;
+
+    #[test]
+    fn history() {
+        let mut history = ResultHistory::default();
+        assert_eq!(history.latest(), None);
+        assert_eq!(history.add("1 + 2"), ("_1".to_owned(), None));
+        assert_eq!(history.add("vec![1,\n    2]\n"), ("_2".to_owned(), None));
+        assert!(history.contains("_1"));
+        assert!(!history.contains("_3"));
+        assert!(!history.contains("__"));
+        assert_eq!(history.latest(), Some("_2".to_owned()));
+        assert_eq!(
+            history.summary(|name| if name == "_1" {
+                Some("i32")
+            } else {
+                Some("Vec<i32>")
+            }),
+            "_1: i32 = 1 + 2\n_2: Vec<i32> = vec![1, 2]\n"
+        );
+
+        history.retain(|name| name != "_2");
+        assert_eq!(history.latest(), Some("_1".to_owned()));
+        assert_eq!(history.summary(|_| None), "No results\n");
+
+        for _ in 1..MAX_RESULTS {
+            assert_eq!(history.add("x").1, None);
+        }
+        assert_eq!(history.add(&"x".repeat(60)).1, Some("_1".to_owned()));
+        assert!(history
+            .summary(|_| Some("String"))
+            .ends_with(" = xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx...\n"));
+    }
+}
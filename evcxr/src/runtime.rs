@@ -12,17 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::environment;
 use crate::errors::bail;
 use crate::errors::Error;
+use crate::native_libs;
+use crate::native_libs::CheckSymbols;
+use crate::stdin_relay::StdinRelay;
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::io;
+use std::io::BufRead;
+use std::io::Read;
 use std::marker::PhantomData;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::{self};
 
 pub(crate) const EVCXR_IS_RUNTIME_VAR: &str = "EVCXR_IS_RUNTIME";
 pub(crate) const EVCXR_EXECUTION_COMPLETE: &str = "EVCXR_EXECUTION_COMPLETE";
+/// If set, we listen on this address for connections from evcxr running on another machine.
+const EVCXR_RUNTIME_LISTEN_VAR: &str = "EVCXR_RUNTIME_LISTEN";
 
 /// Binaries can call this just after staring. If we detect that we're actually
 /// running as a subprocess, control will not return.
@@ -30,10 +39,64 @@ pub fn runtime_hook() {
     if std::env::var(EVCXR_IS_RUNTIME_VAR).is_ok() {
         Runtime::new().run_loop();
     }
+    if let Ok(address) = std::env::var(EVCXR_RUNTIME_LISTEN_VAR) {
+        serve_remote_runtimes(&address);
+    }
+}
+
+/// Accepts connections from evcxr on other machines, running each in a fresh subprocess whose
+/// stdin and stdout are the connection.
+#[cfg(unix)]
+fn serve_remote_runtimes(address: &str) -> ! {
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::io::IntoRawFd;
+    use std::process::Stdio;
+
+    let result = (|| -> Result<(), Error> {
+        let listener = std::net::TcpListener::bind(address)?;
+        eprintln!(
+            "Listening for evcxr connections on {}",
+            listener.local_addr()?
+        );
+        let current_exe = std::env::current_exe()?;
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            let input = stream.try_clone()?;
+            let child = std::process::Command::new(&current_exe)
+                .env(EVCXR_IS_RUNTIME_VAR, "1")
+                .env("RUST_BACKTRACE", "1")
+                .env_remove(EVCXR_RUNTIME_LISTEN_VAR)
+                .stdin(unsafe { Stdio::from_raw_fd(input.into_raw_fd()) })
+                .stdout(unsafe { Stdio::from_raw_fd(stream.into_raw_fd()) })
+                .spawn();
+            match child {
+                Ok(mut child) => {
+                    eprintln!("Started runtime {} for {}", child.id(), peer);
+                    std::thread::spawn(move || child.wait());
+                }
+                Err(error) => eprintln!("Failed to start runtime for {}: {}", peer, error),
+            }
+        }
+        Ok(())
+    })();
+    if let Err(error) = result {
+        eprintln!("{}", error);
+    }
+    std::process::exit(1);
+}
+
+#[cfg(not(unix))]
+fn serve_remote_runtimes(_address: &str) -> ! {
+    eprintln!("{} is only supported on unix", EVCXR_RUNTIME_LISTEN_VAR);
+    std::process::exit(1);
 }
 
 struct Runtime {
     shared_objects: Vec<libloading::Library>,
+    /// Where we store files uploaded by a remote evcxr. Created when the first file is uploaded.
+    upload_dir: Option<tempfile::TempDir>,
+    stdin: StdinRelay,
     variable_store_ptr: *mut std::os::raw::c_void,
     // Our variable store is permitted to contain non-Send types (e.g. Rc), therefore we need to be
     // non-Send as well.
@@ -44,19 +107,24 @@ impl Runtime {
     fn new() -> Runtime {
         Runtime {
             shared_objects: Vec::new(),
+            upload_dir: None,
+            stdin: StdinRelay::install(),
             variable_store_ptr: std::ptr::null_mut(),
             _phantom_rc: PhantomData,
         }
     }
 
     fn run_loop(&mut self) -> ! {
-        use std::io::BufRead;
-
         self.install_crash_handlers();
+        ignore_interrupts();
 
-        let stdin = std::io::stdin();
-        #[allow(unknown_lints, clippy::significant_drop_in_scrutinee)]
-        for line in stdin.lock().lines() {
+        loop {
+            let mut line = String::new();
+            let line = match self.stdin.commands().read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+                Err(error) => Err(error),
+            };
             if let Err(error) = self.handle_line(&line) {
                 eprintln!(
                     "While processing instruction `{:?}`, got error: {:?}",
@@ -65,6 +133,10 @@ impl Runtime {
                 std::process::exit(99);
             }
         }
+        // Our shared objects are never unloaded, but they can be deleted while loaded.
+        if let Some(upload_dir) = self.upload_dir.take() {
+            let _ = upload_dir.close();
+        }
         std::process::exit(0);
     }
 
@@ -73,20 +145,81 @@ impl Runtime {
         static LOAD_AND_RUN: OnceCell<Regex> = OnceCell::new();
         let load_and_run =
             LOAD_AND_RUN.get_or_init(|| Regex::new("LOAD_AND_RUN ([^ ]+) ([^ ]+)").unwrap());
+        static LOAD: OnceCell<Regex> = OnceCell::new();
+        let load = LOAD.get_or_init(|| Regex::new("^LOAD ([^ ]+)$").unwrap());
+        static UPLOAD: OnceCell<Regex> = OnceCell::new();
+        let upload = UPLOAD.get_or_init(|| Regex::new("^UPLOAD ([^ /\\\\]+) ([0-9]+)$").unwrap());
         if let Some(captures) = load_and_run.captures(line) {
             self.load_and_run(&captures[1], &captures[2])
+        } else if let Some(captures) = load.captures(line) {
+            let shared_object = unsafe { libloading::Library::new(self.resolve(&captures[1])) }?;
+            self.shared_objects.push(shared_object);
+            Ok(())
+        } else if let Some(captures) = upload.captures(line) {
+            let len = match captures[2].parse() {
+                Ok(len) => len,
+                Err(error) => bail!("Invalid upload length: {}", error),
+            };
+            self.receive_upload(&captures[1], len)
+        } else if let Some(reply) = environment::handle_command(line) {
+            println!("{}", reply);
+            println!("{}", EVCXR_EXECUTION_COMPLETE);
+            Ok(())
+        } else if let Some(check) = CheckSymbols::parse(line) {
+            let reply = match unsafe { libloading::Library::new(self.resolve(check.so_path)) } {
+                Ok(shared_object) => {
+                    let reply = check.run(&shared_object);
+                    self.shared_objects.push(shared_object);
+                    reply
+                }
+                Err(error) => native_libs::error_reply(&error),
+            };
+            println!("{}", reply);
+            println!("{}", EVCXR_EXECUTION_COMPLETE);
+            Ok(())
         } else {
             bail!("Unrecognised line: {}", line);
         }
     }
 
+    /// Reads a file of `len` bytes that follows an UPLOAD command and stores it so that it can be
+    /// loaded by name.
+    fn receive_upload(&mut self, file_name: &str, len: u64) -> Result<(), Error> {
+        if file_name == "." || file_name == ".." {
+            bail!("Invalid upload file name: {}", file_name);
+        }
+        if self.upload_dir.is_none() {
+            self.upload_dir = Some(tempfile::tempdir()?);
+        }
+        let path = self.upload_dir.as_ref().unwrap().path().join(file_name);
+        let mut file = std::fs::File::create(&path)?;
+        let copied = io::copy(&mut self.stdin.commands().by_ref().take(len), &mut file)?;
+        if copied != len {
+            bail!("Upload of {} ended after {} bytes", file_name, copied);
+        }
+        Ok(())
+    }
+
+    /// Returns the path of an uploaded file, if one with the supplied name exists, otherwise the
+    /// supplied path unchanged.
+    fn resolve(&self, path: &str) -> PathBuf {
+        if let Some(upload_dir) = &self.upload_dir {
+            let uploaded = upload_dir.path().join(path);
+            if uploaded.exists() {
+                return uploaded;
+            }
+        }
+        PathBuf::from(path)
+    }
+
     fn load_and_run(&mut self, so_path: &str, fn_name: &str) -> Result<(), Error> {
         use std::os::raw::c_void;
-        let shared_object = unsafe { libloading::Library::new(so_path) }?;
+        let shared_object = unsafe { libloading::Library::new(self.resolve(so_path)) }?;
         unsafe {
             let user_fn = shared_object
                 .get::<extern "C" fn(*mut c_void) -> *mut c_void>(fn_name.as_bytes())?;
-            self.variable_store_ptr = user_fn(self.variable_store_ptr);
+            let variable_store_ptr = self.variable_store_ptr;
+            self.variable_store_ptr = self.stdin.run_user_code(|| user_fn(variable_store_ptr));
         }
         println!("{}", EVCXR_EXECUTION_COMPLETE);
         self.shared_objects.push(shared_object);
@@ -120,6 +253,37 @@ impl Runtime {
     pub fn install_crash_handlers(&self) {}
 }
 
+/// While user code runs, it may handle SIGINT, so that it can be interrupted without losing
+/// variables. In between, and when it doesn't, SIGINT mustn't terminate us. Processes spawned by
+/// user code get the default handling back, which they wouldn't if SIGINT was ignored.
+#[cfg(unix)]
+fn ignore_interrupts() {
+    extern "C" fn handler(_signal: libc::c_int) {}
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handler as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Likewise for the Ctrl-Break that's sent on Windows. Handlers that user code adds are called
+/// before this one.
+#[cfg(windows)]
+fn ignore_interrupts() {
+    unsafe extern "system" fn handler(
+        ctrl_type: winapi::shared::minwindef::DWORD,
+    ) -> winapi::shared::minwindef::BOOL {
+        (ctrl_type == winapi::um::wincon::CTRL_BREAK_EVENT) as winapi::shared::minwindef::BOOL
+    }
+    unsafe {
+        winapi::um::consoleapi::SetConsoleCtrlHandler(Some(handler), 1);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn ignore_interrupts() {}
+
 impl Drop for Runtime {
     fn drop(&mut self) {
         // We never actually unload libraries. This is to prevent segfault on shutdown due to TLS
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::docs::ItemDocs;
+use crate::errors::Span;
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
@@ -35,6 +37,7 @@ use ra_ap_vfs_notify as vfs_notify;
 use ra_ide::CallableSnippets;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::ops::Range;
 use std::path::Path;
 use std::sync::mpsc;
 use std::sync::Arc;
@@ -182,6 +185,191 @@ impl RustAnalyzer {
         result
     }
 
+    /// Returns the documentation of the item referred to by the path that ends at `offset`, or
+    /// None if the path doesn't resolve to an item.
+    pub(crate) fn documentation(&self, offset: usize) -> Option<ItemDocs> {
+        use ra_hir::HasAttrs;
+        use ra_hir::HirDisplay;
+        let sema = ra_ide::Semantics::new(self.analysis_host.raw_database());
+        let source_file = sema.parse(self.source_file_id);
+        let path = source_file
+            .syntax()
+            .token_at_offset((offset as u32).into())
+            .left_biased()?
+            .parent()?
+            .ancestors()
+            .find_map(ast::Path::cast)?;
+        let db = sema.db;
+        let def = match sema.resolve_path(&path)? {
+            ra_hir::PathResolution::Def(def) => def,
+            _ => return None,
+        };
+        let (docs, signature) = match def {
+            ra_hir::ModuleDef::Module(it) => (it.docs(db), None),
+            ra_hir::ModuleDef::Function(it) => (it.docs(db), Some(it.display(db).to_string())),
+            ra_hir::ModuleDef::Adt(it) => (it.docs(db), Some(it.display(db).to_string())),
+            ra_hir::ModuleDef::Variant(it) => (it.docs(db), Some(it.display(db).to_string())),
+            ra_hir::ModuleDef::Const(it) => (it.docs(db), Some(it.display(db).to_string())),
+            ra_hir::ModuleDef::Static(it) => (it.docs(db), Some(it.display(db).to_string())),
+            ra_hir::ModuleDef::Trait(it) => (it.docs(db), Some(it.display(db).to_string())),
+            ra_hir::ModuleDef::TypeAlias(it) => (it.docs(db), Some(it.display(db).to_string())),
+            ra_hir::ModuleDef::Macro(it) => (it.docs(db), None),
+            _ => (None, None),
+        };
+        Some(ItemDocs {
+            signature,
+            docs: docs.map(|docs| docs.as_str().to_owned()),
+        })
+    }
+
+    /// Returns the inferred type of the expression that spans `range`, or None if its type isn't
+    /// known.
+    pub(crate) fn type_of_expression(&self, range: std::ops::Range<usize>) -> Option<String> {
+        use ra_hir::HirDisplay;
+        let sema = ra_ide::Semantics::new(self.analysis_host.raw_database());
+        let source_file = sema.parse(self.source_file_id);
+        let range = ra_ide::TextRange::new((range.start as u32).into(), (range.end as u32).into());
+        let node = match source_file.syntax().covering_element(range) {
+            ra_ap_syntax::NodeOrToken::Node(node) => node,
+            ra_ap_syntax::NodeOrToken::Token(token) => token.parent()?,
+        };
+        let expression = node.ancestors().find_map(ast::Expr::cast)?;
+        let ty = sema.type_of_expr(&expression)?.original();
+        if ty.is_unknown() {
+            return None;
+        }
+        Some(ty.display(sema.db).to_string())
+    }
+
+    /// Returns the problems that rust-analyzer finds in the current source. `span_for_range` maps a
+    /// range of bytes in the source to a span in the user's code. Problems for which it returns
+    /// None, because they're in code that we generated or in earlier cells, are skipped.
+    pub(crate) fn diagnostics(
+        &self,
+        span_for_range: impl Fn(usize, usize) -> Option<Span>,
+    ) -> Vec<Diagnostic> {
+        let mut config = ra_ide::DiagnosticsConfig {
+            disable_experimental: true,
+            ..ra_ide::DiagnosticsConfig::default()
+        };
+        // We don't expand proc macros, so what they generate can't be found. Code that's disabled
+        // by `#[cfg]` is reported, but isn't a problem.
+        for code in ["unresolved-proc-macro", "inactive-code"] {
+            config.disabled.insert(code.to_owned());
+        }
+        self.analysis_host
+            .analysis()
+            .diagnostics(
+                &config,
+                ra_ide::AssistResolveStrategy::None,
+                self.source_file_id,
+            )
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|diagnostic| {
+                let span = span_for_range(
+                    diagnostic.range.start().into(),
+                    diagnostic.range.end().into(),
+                )?;
+                let level = match diagnostic.severity {
+                    ra_ide::Severity::Error => "error",
+                    _ => "warning",
+                };
+                Some(Diagnostic {
+                    code: diagnostic.code.as_str().to_owned(),
+                    message: diagnostic.message,
+                    level: level.to_owned(),
+                    span,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns how rust-analyzer highlights the current source. `span_for_range` maps a range of
+    /// bytes in the source to a span in the user's code, or to None for code that the user didn't
+    /// write, which is skipped.
+    pub(crate) fn semantic_tokens(
+        &self,
+        span_for_range: impl Fn(usize, usize) -> Option<Span>,
+    ) -> Vec<SemanticToken> {
+        self.analysis_host
+            .analysis()
+            .highlight(self.source_file_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|highlight| {
+                // Highlights are shown as their tag followed by their modifiers, separated by
+                // dots, e.g. `function.declaration.unsafe`.
+                let description = highlight.highlight.to_string();
+                let mut parts = description.split('.');
+                let kind = parts.next()?;
+                if kind == "none" {
+                    return None;
+                }
+                let span =
+                    span_for_range(highlight.range.start().into(), highlight.range.end().into())?;
+                Some(SemanticToken {
+                    kind: kind.to_owned(),
+                    modifiers: parts.map(str::to_owned).collect(),
+                    span,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the ranges of the names of the definitions of what's referred to at `offset` in the
+    /// current source. Definitions elsewhere, e.g. in the standard library, aren't included.
+    pub(crate) fn definitions(&self, offset: usize) -> Vec<Range<usize>> {
+        let targets = match self
+            .analysis_host
+            .analysis()
+            .goto_definition(self.file_position(offset))
+        {
+            Ok(Some(targets)) => targets.info,
+            _ => return Vec::new(),
+        };
+        targets
+            .into_iter()
+            .filter(|target| target.file_id == self.source_file_id)
+            .map(|target| usize_range(target.focus_range.unwrap_or(target.full_range)))
+            .collect()
+    }
+
+    /// Returns the ranges of the definition of what's referred to at `offset` in the current
+    /// source, and of all references to it there, in order.
+    pub(crate) fn references(&self, offset: usize) -> Vec<Range<usize>> {
+        let results = match self
+            .analysis_host
+            .analysis()
+            .find_all_refs(self.file_position(offset), None)
+        {
+            Ok(Some(results)) => results,
+            _ => return Vec::new(),
+        };
+        let mut ranges = Vec::new();
+        for result in results {
+            if let Some(declaration) = result.declaration {
+                let nav = declaration.nav;
+                if nav.file_id == self.source_file_id {
+                    ranges.push(usize_range(nav.focus_range.unwrap_or(nav.full_range)));
+                }
+            }
+            if let Some(references) = result.references.get(&self.source_file_id) {
+                ranges.extend(references.iter().map(|(range, _)| usize_range(*range)));
+            }
+        }
+        ranges.sort_by_key(|range| (range.start, range.end));
+        ranges.dedup();
+        ranges
+    }
+
+    fn file_position(&self, offset: usize) -> ra_ide::FilePosition {
+        ra_ide::FilePosition {
+            file_id: self.source_file_id,
+            offset: (offset as u32).into(),
+        }
+    }
+
     fn load_cargo_toml(&mut self, change: &mut ra_ide::Change) -> Result<()> {
         let manifest = ProjectManifest::from_manifest_file(self.cargo_toml_filename())?;
         let config = CargoConfig {
@@ -388,6 +576,45 @@ pub struct Completion {
     pub code: String,
 }
 
+/// A problem that rust-analyzer found in code, without compiling it. Like completions, these don't
+/// expose rust-analyzer's own type.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// What kind of problem this is, e.g. `unresolved-import` or `syntax-error`.
+    pub code: String,
+    pub message: String,
+    /// "error" or "warning".
+    pub level: String,
+    /// Where the problem is in the code that was checked.
+    pub span: Span,
+}
+
+/// A range of code together with what rust-analyzer says it is, for semantic highlighting.
+#[derive(Debug, Clone)]
+pub struct SemanticToken {
+    /// What the code is, e.g. `keyword`, `function`, `variable`, `string_literal` or `macro`.
+    pub kind: String,
+    /// More about what the code is, e.g. `declaration`, `mutable` or `unsafe`.
+    pub modifiers: Vec<String>,
+    pub span: Span,
+}
+
+/// Where something is in the session's cells.
+#[derive(Debug, Clone)]
+pub struct Location {
+    /// The number of the cell, counting evaluations from 1, as in backtraces. None if it's in the
+    /// code that was passed in, rather than in a cell that was evaluated earlier.
+    pub cell_number: Option<usize>,
+    /// The ID of the cell, if it was evaluated with one by
+    /// `CommandContext::execute_cell_with_callbacks`.
+    pub cell_id: Option<String>,
+    pub span: Span,
+}
+
+fn usize_range(range: ra_ide::TextRange) -> Range<usize> {
+    range.start().into()..range.end().into()
+}
+
 /// Returns whether this appears to be a valid type. Rust analyzer, when asked to emit code for some
 /// types, produces invalid code. In particular, fixed sized arrays come out without a size. e.g.
 /// instead of `[i32, 5]`, we get `[i32, _]`.
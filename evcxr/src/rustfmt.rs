@@ -0,0 +1,187 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Formatting of code with rustfmt, which the REPL's `:rustfmt` uses to tidy up earlier input, and
+//! which `:autoformat` applies to code before it's written to exported projects and transcripts.
+//! rustfmt isn't published as a library that we can depend on, so we run the one that comes with
+//! the toolchain, passing code via stdin. It runs in our working directory, so picks up any
+//! `rustfmt.toml` there.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::eval_context::Config;
+use ra_ap_syntax::SourceFile;
+use std::io::Write;
+use std::ops::Range;
+use std::process::Stdio;
+
+/// The start of the function that we put a cell's code into, since rustfmt only formats whole
+/// files. The code starts on the same line, after a newline for each line of commands, so that line
+/// numbers in rustfmt's errors match the user's.
+const WRAPPER_START: &str = "fn evcxr_rustfmt() {";
+
+/// Returns `code`, which is a cell as the user would enter it, formatted with rustfmt. Any commands
+/// at the start of the cell are left as they are.
+pub(crate) fn format_cell(config: &Config, code: &str) -> Result<String, Error> {
+    let (commands, body) = split_commands(code);
+    if body.trim().is_empty() {
+        return Ok(code.to_owned());
+    }
+    let formatted = run_rustfmt(
+        config,
+        &format!(
+            "{}{}{}\n}}\n",
+            WRAPPER_START,
+            "\n".repeat(commands.matches('\n').count()),
+            body
+        ),
+    )?;
+    match unwrap_body(&formatted, &multiline_literals(&formatted)) {
+        Some(body) => Ok(format!("{}{}", commands, body)),
+        None => bail!("Unexpected output from rustfmt:\n{}", formatted),
+    }
+}
+
+/// Returns `code`, which must be a complete source file, formatted with rustfmt.
+pub(crate) fn format_file(config: &Config, code: &str) -> Result<String, Error> {
+    run_rustfmt(config, code)
+}
+
+fn run_rustfmt(config: &Config, code: &str) -> Result<String, Error> {
+    let mut command = config.tool_paths.rustfmt_command();
+    if !config.toolchain.is_empty() {
+        command.arg(format!("+{}", config.toolchain));
+    }
+    command
+        .arg("--edition")
+        .arg(&config.edition)
+        .arg("--emit")
+        .arg("stdout")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            bail!("Couldn't find rustfmt. It can be installed with `rustup component add rustfmt`")
+        }
+        Err(error) => bail!("Failed to run rustfmt: {}", error),
+    };
+    // rustfmt reads all of its input before writing anything, so writing it all before reading the
+    // output can't deadlock.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(code.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "rustfmt couldn't format the code:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Splits `code` into the commands, blank lines and comments at its start, which is where commands
+/// are accepted, and the code that follows.
+fn split_commands(code: &str) -> (&str, &str) {
+    let mut start = 0;
+    for line in code.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if !(trimmed.is_empty() || trimmed.starts_with(':') || trimmed.starts_with("//")) {
+            break;
+        }
+        start += line.len();
+    }
+    code.split_at(start)
+}
+
+/// Returns the byte ranges of literals in `code` that span multiple lines, i.e. strings, the lines
+/// of which mustn't be reindented.
+fn multiline_literals(code: &str) -> Vec<Range<usize>> {
+    SourceFile::parse(code)
+        .syntax_node()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| token.kind().is_literal() && token.text().contains('\n'))
+        .map(|token| {
+            let range = token.text_range();
+            usize::from(range.start())..usize::from(range.end())
+        })
+        .collect()
+}
+
+/// Returns the body of the function that rustfmt output, with one level of indentation removed.
+/// Lines that start within `literals` are left alone.
+fn unwrap_body(formatted: &str, literals: &[Range<usize>]) -> Option<String> {
+    let body = formatted.strip_prefix(WRAPPER_START)?.strip_prefix('\n')?;
+    let mut offset = formatted.len() - body.len();
+    let body = body.trim_end().strip_suffix('}')?;
+    let in_literal = |offset: usize| {
+        literals
+            .iter()
+            .any(|literal| literal.start < offset && offset < literal.end)
+    };
+    let mut lines = Vec::new();
+    for line in body.split_inclusive('\n') {
+        lines.push((in_literal(offset), line));
+        offset += line.len();
+    }
+    // rustfmt indents the body by a level, however big its configuration makes that.
+    let indent_len = lines
+        .iter()
+        .find(|(in_literal, line)| !in_literal && !line.trim().is_empty())
+        .map_or(0, |(_, line)| line.len() - line.trim_start().len());
+    let mut unindented = String::new();
+    for (in_literal, line) in lines {
+        if in_literal || line.len() < indent_len || !line.is_char_boundary(indent_len) {
+            unindented.push_str(line);
+        } else {
+            unindented.push_str(line[..indent_len].trim_start());
+            unindented.push_str(&line[indent_len..]);
+        }
+    }
+    Some(unindented.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_commands;
+    use super::unwrap_body;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            split_commands(":dep regex\n// Comment\n\nlet a=1;\n:not_a_command"),
+            (":dep regex\n// Comment\n\n", "let a=1;\n:not_a_command")
+        );
+        assert_eq!(split_commands("let a = 1;"), ("", "let a = 1;"));
+        assert_eq!(split_commands(":vars"), (":vars", ""));
+    }
+
+    #[test]
+    fn unwrap() {
+        let formatted = "fn evcxr_rustfmt() {\n    let s = \"a\n    b\";\n\n    if s.is_empty() {\n        1\n    } else {\n        2\n    }\n}\n";
+        let literal = formatted.find("\"a").unwrap()..formatted.find("b\"").unwrap() + 2;
+        assert_eq!(
+            unwrap_body(formatted, std::slice::from_ref(&literal)).unwrap(),
+            "let s = \"a\n    b\";\n\nif s.is_empty() {\n    1\n} else {\n    2\n}"
+        );
+        assert_eq!(
+            unwrap_body("fn evcxr_rustfmt() {\n  x\n}\n", &[]).unwrap(),
+            "x"
+        );
+        assert_eq!(unwrap_body("fn main() {}\n", &[]), None);
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Restrictions on what user code can do, for when evcxr is used to evaluate code from people who
+//! aren't trusted. Only the subprocess that runs user code is restricted. Compilation happens in
+//! the parent process and isn't affected. On Linux, filesystem access is restricted with landlock
+//! and network access with a seccomp filter. If the kernel doesn't support these, starting the
+//! subprocess fails rather than running without restrictions.
+
+use crate::errors::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Restrictions applied to the subprocess that runs user code.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    /// Whether user code may create network sockets. Unix domain sockets are always permitted.
+    pub allow_network: bool,
+    /// Paths that user code may write to, in addition to evcxr's own temporary directory. All
+    /// other paths are read-only.
+    pub writable_paths: Vec<PathBuf>,
+}
+
+impl SandboxPolicy {
+    /// Returns a short description of the policy for display by `:about`.
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "landlock+seccomp (network: {}, writable paths: {})",
+            if self.allow_network {
+                "allowed"
+            } else {
+                "denied"
+            },
+            self.writable_paths.len()
+        )
+    }
+
+    /// Arranges for the process started by `command` to be restricted according to this policy.
+    /// `tmpdir` is always writable, since that's where our subprocess finds the code it loads.
+    pub(crate) fn apply(&self, command: &mut Command, tmpdir: &Path) -> Result<(), Error> {
+        let mut writable_paths = vec![tmpdir.to_owned(), PathBuf::from("/dev/null")];
+        writable_paths.extend(self.writable_paths.iter().cloned());
+        imp::apply(command, &writable_paths, self.allow_network)
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod imp {
+    use crate::errors::bail;
+    use crate::errors::Error;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    // Constants from linux/landlock.h. The syscall numbers are the same on all architectures.
+    const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+    const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+    const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+    const ACCESS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_READ_DIR: u64 = 1 << 3;
+    /// All access rights in version 1 of the landlock ABI. We only handle these, so that we work
+    /// on any kernel that supports landlock.
+    const ACCESS_ALL: u64 = (1 << 13) - 1;
+    /// The access rights that apply to files, as opposed to directories.
+    const ACCESS_ALL_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE;
+    const ACCESS_READ_ONLY: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+
+    #[repr(C)]
+    struct LandlockRulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct LandlockPathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    // Constants from linux/seccomp.h, linux/filter.h and linux/audit.h.
+    const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_JGE_K: u16 = 0x35;
+    const BPF_RET_K: u16 = 0x06;
+    // Offsets within struct seccomp_data.
+    const OFFSET_NR: u32 = 0;
+    const OFFSET_ARCH: u32 = 4;
+    const OFFSET_ARG0: u32 = 16;
+    const SYS_IO_URING_SETUP: u32 = 425;
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub(super) struct SockFilter {
+        pub(super) code: u16,
+        pub(super) jt: u8,
+        pub(super) jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: libc::c_ushort,
+        filter: *const SockFilter,
+    }
+
+    const fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    /// Denies creation of sockets other than unix domain sockets. io_uring is denied outright,
+    /// since it can be used to create sockets without going through the socket syscall. So are
+    /// x32 syscalls, which have different numbers.
+    pub(super) const NETWORK_FILTER: [SockFilter; 11] = [
+        stmt(BPF_LD_W_ABS, OFFSET_ARCH),
+        jump(BPF_JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, OFFSET_NR),
+        jump(BPF_JGE_K, X32_SYSCALL_BIT, 5, 0),
+        jump(BPF_JEQ_K, SYS_IO_URING_SETUP, 4, 0),
+        jump(BPF_JEQ_K, libc::SYS_socket as u32, 0, 2),
+        stmt(BPF_LD_W_ABS, OFFSET_ARG0),
+        jump(BPF_JEQ_K, libc::AF_UNIX as u32, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EACCES as u32),
+    ];
+
+    pub(super) fn apply(
+        command: &mut Command,
+        writable_paths: &[PathBuf],
+        allow_network: bool,
+    ) -> Result<(), Error> {
+        // Everything that needs to allocate is done here, since only async-signal-safe functions
+        // may be called between fork and exec.
+        let mut rules = vec![(CString::new("/").unwrap(), ACCESS_READ_ONLY)];
+        for path in writable_paths {
+            let access = if path.is_dir() {
+                ACCESS_ALL
+            } else if path.exists() {
+                ACCESS_ALL_FILE
+            } else {
+                bail!("Sandbox writable path {} doesn't exist", path.display());
+            };
+            match CString::new(path.as_os_str().as_bytes()) {
+                Ok(path) => rules.push((path, access)),
+                Err(_) => bail!("Invalid sandbox path {}", path.display()),
+            }
+        }
+        unsafe {
+            command.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                restrict_filesystem(&rules)?;
+                if !allow_network {
+                    let program = SockFprog {
+                        len: NETWORK_FILTER.len() as libc::c_ushort,
+                        filter: NETWORK_FILTER.as_ptr(),
+                    };
+                    if libc::prctl(
+                        libc::PR_SET_SECCOMP,
+                        SECCOMP_MODE_FILTER,
+                        &program as *const SockFprog,
+                    ) != 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Must only call async-signal-safe functions, since it runs between fork and exec.
+    unsafe fn restrict_filesystem(rules: &[(CString, u64)]) -> std::io::Result<()> {
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: ACCESS_ALL,
+        };
+        let ruleset_fd = libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const LandlockRulesetAttr,
+            std::mem::size_of::<LandlockRulesetAttr>(),
+            0,
+        ) as libc::c_int;
+        if ruleset_fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let result = add_rules_and_restrict(ruleset_fd, rules);
+        libc::close(ruleset_fd);
+        result
+    }
+
+    unsafe fn add_rules_and_restrict(
+        ruleset_fd: libc::c_int,
+        rules: &[(CString, u64)],
+    ) -> std::io::Result<()> {
+        for (path, allowed_access) in rules {
+            let parent_fd = libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC);
+            if parent_fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let rule = LandlockPathBeneathAttr {
+                allowed_access: *allowed_access,
+                parent_fd,
+            };
+            let result = libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset_fd,
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const LandlockPathBeneathAttr,
+                0,
+            );
+            libc::close(parent_fd);
+            if result != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset_fd, 0) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod imp {
+    use crate::errors::bail;
+    use crate::errors::Error;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    pub(super) fn apply(
+        _command: &mut Command,
+        _writable_paths: &[PathBuf],
+        _allow_network: bool,
+    ) -> Result<(), Error> {
+        bail!("Sandboxing is only supported on Linux on x86_64 and aarch64");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SandboxPolicy;
+
+    #[test]
+    fn test_describe() {
+        let policy = SandboxPolicy::default();
+        assert_eq!(
+            policy.describe(),
+            "landlock+seccomp (network: denied, writable paths: 0)"
+        );
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_network_filter_jumps_are_in_bounds() {
+        use super::imp::NETWORK_FILTER;
+        const BPF_JMP: u16 = 0x05;
+        for (index, instruction) in NETWORK_FILTER.iter().enumerate() {
+            if instruction.code & 0x07 != BPF_JMP {
+                continue;
+            }
+            assert!(index + 1 + (instruction.jt as usize) < NETWORK_FILTER.len());
+            assert!(index + 1 + (instruction.jf as usize) < NETWORK_FILTER.len());
+        }
+    }
+}
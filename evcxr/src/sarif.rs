@@ -0,0 +1,280 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SARIF output for diagnostics, so that the errors and warnings of a session can be given to
+//! code-review and CI tools that understand SARIF. `:sarif` writes every diagnostic reported so far
+//! in the session. Each evaluation that had diagnostics is an artifact, named after its position in
+//! the session, e.g. `cell-3.rs`, and includes the code that was evaluated.
+
+use crate::errors::CompilationError;
+use crate::errors::Error;
+use json::object;
+use json::JsonValue;
+use std::path::Path;
+
+const SARIF_VERSION: &str = "2.1.0";
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+impl CompilationError {
+    /// Returns the error as a SARIF `result` object. Its locations are regions of the code that was
+    /// evaluated, with no `artifactLocation`, since the error doesn't know where that code came
+    /// from.
+    pub fn to_sarif(&self) -> JsonValue {
+        let mut text = self.message();
+        for help in self.help() {
+            text.push_str("\nhelp: ");
+            text.push_str(&help);
+        }
+        let mut result = object! {
+            "level" => sarif_level(self.level()),
+            "message" => object! { "text" => text },
+        };
+        if let Some(code) = self.code() {
+            result["ruleId"] = code.into();
+        }
+        let mut locations = Vec::new();
+        let mut related_locations = Vec::new();
+        for spanned_message in self.spanned_messages() {
+            let span = match &spanned_message.span {
+                Some(span) => span,
+                None => continue,
+            };
+            let mut location = object! {
+                "physicalLocation" => object! {
+                    "region" => object! {
+                        "startLine" => span.start_line,
+                        "startColumn" => span.start_column,
+                        "endLine" => span.end_line,
+                        "endColumn" => span.end_column,
+                    },
+                },
+            };
+            if !spanned_message.label.is_empty() {
+                location["message"] = object! { "text" => spanned_message.label.clone() };
+            }
+            if spanned_message.is_primary {
+                locations.push(location);
+            } else {
+                related_locations.push(location);
+            }
+        }
+        if !locations.is_empty() {
+            result["locations"] = locations.into();
+        }
+        if !related_locations.is_empty() {
+            result["relatedLocations"] = related_locations.into();
+        }
+        result
+    }
+}
+
+fn sarif_level(level: &str) -> &'static str {
+    match level {
+        "error" | "error: internal compiler error" => "error",
+        "warning" => "warning",
+        _ => "note",
+    }
+}
+
+/// Returns where the rule `code` is documented, for rustc's error codes and clippy's lints.
+fn help_uri(code: &str) -> Option<String> {
+    if let Some(lint) = code.strip_prefix("clippy::") {
+        Some(format!(
+            "https://rust-lang.github.io/rust-clippy/master/index.html#{}",
+            lint
+        ))
+    } else if code.len() > 1
+        && code.starts_with('E')
+        && code[1..].chars().all(|c| c.is_ascii_digit())
+    {
+        Some(format!(
+            "https://doc.rust-lang.org/error_codes/{}.html",
+            code
+        ))
+    } else {
+        None
+    }
+}
+
+/// The diagnostics reported by the evaluations of a session.
+#[derive(Debug, Default)]
+pub(crate) struct SessionDiagnostics {
+    evaluations: usize,
+    /// The evaluations that had diagnostics.
+    cells: Vec<DiagnosedCell>,
+}
+
+#[derive(Debug)]
+struct DiagnosedCell {
+    /// The 1-based position of the evaluation in the session.
+    number: usize,
+    code: String,
+    diagnostics: Vec<CompilationError>,
+}
+
+impl SessionDiagnostics {
+    /// Records that an evaluation started, so that evaluations are numbered even if they don't
+    /// have any diagnostics.
+    pub(crate) fn evaluation_started(&mut self) {
+        self.evaluations += 1;
+    }
+
+    /// Records that the current evaluation, of `code`, reported `diagnostics`.
+    pub(crate) fn record(&mut self, code: &str, diagnostics: &[CompilationError]) {
+        if !diagnostics.is_empty() {
+            self.cells.push(DiagnosedCell {
+                number: self.evaluations,
+                code: code.to_owned(),
+                diagnostics: diagnostics.to_vec(),
+            });
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.cells.iter().map(|cell| cell.diagnostics.len()).sum()
+    }
+
+    /// Returns a SARIF log containing all the diagnostics.
+    pub(crate) fn to_sarif(&self) -> JsonValue {
+        let mut rule_ids: Vec<&str> = Vec::new();
+        let mut artifacts = Vec::new();
+        let mut results = Vec::new();
+        for (index, cell) in self.cells.iter().enumerate() {
+            let uri = format!("cell-{}.rs", cell.number);
+            artifacts.push(object! {
+                "location" => object! { "uri" => uri.clone() },
+                "contents" => object! { "text" => cell.code.clone() },
+            });
+            for diagnostic in &cell.diagnostics {
+                let mut result = diagnostic.to_sarif();
+                for key in ["locations", "relatedLocations"] {
+                    // Indexing a missing key would add it, as null.
+                    if !result.has_key(key) {
+                        continue;
+                    }
+                    for location in result[key].members_mut() {
+                        location["physicalLocation"]["artifactLocation"] = object! {
+                            "uri" => uri.clone(),
+                            "index" => index,
+                        };
+                    }
+                }
+                if let Some(code) = diagnostic.code() {
+                    if !rule_ids.contains(&code) {
+                        rule_ids.push(code);
+                    }
+                }
+                results.push(result);
+            }
+        }
+        let rules: Vec<JsonValue> = rule_ids
+            .into_iter()
+            .map(|id| {
+                let mut rule = object! { "id" => id };
+                if let Some(uri) = help_uri(id) {
+                    rule["helpUri"] = uri.into();
+                }
+                rule
+            })
+            .collect();
+        object! {
+            "version" => SARIF_VERSION,
+            "$schema" => SARIF_SCHEMA,
+            "runs" => json::array![object! {
+                "tool" => object! {
+                    "driver" => object! {
+                        "name" => "evcxr",
+                        "version" => env!("CARGO_PKG_VERSION"),
+                        "informationUri" => "https://github.com/google/evcxr",
+                        "rules" => rules,
+                    },
+                },
+                "artifacts" => artifacts,
+                "results" => results,
+            }],
+        }
+    }
+
+    /// Writes a SARIF log containing all the diagnostics to `path`.
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Error> {
+        std::fs::write(path, self.to_sarif().pretty(2))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::help_uri;
+    use super::SessionDiagnostics;
+    use crate::errors::CompilationError;
+
+    #[test]
+    fn session_log() {
+        let mut diagnostics = SessionDiagnostics::default();
+        diagnostics.evaluation_started();
+        diagnostics.record("let a = 1;", &[]);
+        diagnostics.evaluation_started();
+        let mut error = CompilationError::for_testing(
+            "error",
+            Some("E0425"),
+            "cannot find value `b` in this scope",
+            "not found in this scope",
+            2,
+            9,
+        );
+        error.add_secondary_span_for_testing("defined here", 1, 5);
+        diagnostics.record("let c = 2;\nlet d = b;", &[error]);
+        assert_eq!(diagnostics.count(), 1);
+
+        let log = diagnostics.to_sarif();
+        assert_eq!(log["version"], "2.1.0");
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "E0425");
+        assert_eq!(
+            run["tool"]["driver"]["rules"][0]["helpUri"],
+            "https://doc.rust-lang.org/error_codes/E0425.html"
+        );
+        assert_eq!(run["artifacts"].len(), 1);
+        assert_eq!(run["artifacts"][0]["location"]["uri"], "cell-2.rs");
+        let result = &run["results"][0];
+        assert_eq!(result["ruleId"], "E0425");
+        assert_eq!(result["level"], "error");
+        let location = &result["locations"][0];
+        assert_eq!(location["message"]["text"], "not found in this scope");
+        let physical_location = &location["physicalLocation"];
+        assert_eq!(physical_location["artifactLocation"]["uri"], "cell-2.rs");
+        assert_eq!(physical_location["artifactLocation"]["index"], 0);
+        assert_eq!(physical_location["region"]["startLine"], 2);
+        assert_eq!(physical_location["region"]["startColumn"], 9);
+        assert_eq!(physical_location["region"]["endColumn"], 10);
+        assert_eq!(
+            result["relatedLocations"][0]["physicalLocation"]["region"]["startLine"],
+            1
+        );
+        assert_eq!(
+            result["relatedLocations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "cell-2.rs"
+        );
+    }
+
+    #[test]
+    fn help_uris() {
+        assert_eq!(
+            help_uri("clippy::len_zero").as_deref(),
+            Some("https://rust-lang.github.io/rust-clippy/master/index.html#len_zero")
+        );
+        assert_eq!(help_uri("unused_variables"), None);
+        assert_eq!(help_uri("E"), None);
+    }
+}
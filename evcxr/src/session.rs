@@ -0,0 +1,428 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `Session`, the API intended for tools that embed evcxr, such as IDEs, web playgrounds and test
+//! harnesses. Unlike `CommandContext` and `EvalContext`, which change as evcxr's frontends need,
+//! `Session` follows semver: its methods only change in a major release, and the types it
+//! returns are either `#[non_exhaustive]` or only accessed through methods, so that more can be
+//! added without breaking callers. It takes and returns its own types rather than those of the rest
+//! of the crate, such as `EvalContextConfig`, `EvalOutputs` and `Error`, so that those can keep
+//! changing.
+
+use crate::errors::Error;
+use crate::CommandContext;
+use crate::EvalContext;
+use crate::EvalContextConfig;
+use crate::EvalContextOutputs;
+use crate::EvalOutputs;
+use crate::InterruptHandle;
+use crate::SandboxPolicy;
+use crossbeam_channel::Receiver;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// An evcxr session: a set of variables, items and dependencies that code is evaluated against.
+///
+/// ```no_run
+/// # fn main() -> Result<(), evcxr::SessionError> {
+/// // You must call `evcxr::runtime_hook()` at the top of main, otherwise the library becomes a
+/// // fork-bomb.
+/// evcxr::runtime_hook();
+/// let mut session = evcxr::Session::new()?;
+/// session.eval("let x = 40;")?;
+/// let outputs = session.eval("x + 2")?;
+/// assert_eq!(outputs.get("text/plain"), Some("42"));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Session {
+    command_context: CommandContext,
+    stdout: Receiver<String>,
+    stderr: Receiver<String>,
+}
+
+/// How to create a `Session`. Settings that aren't given keep their defaults.
+///
+/// ```no_run
+/// # fn main() -> Result<(), evcxr::SessionError> {
+/// let config = evcxr::SessionConfig::new()
+///     .edition("2018")
+///     .max_memory(1 << 30);
+/// let session = evcxr::Session::with_config(config)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SessionConfig {
+    config: EvalContextConfig,
+}
+
+impl SessionConfig {
+    pub fn new() -> SessionConfig {
+        SessionConfig::default()
+    }
+
+    /// Sets the directory in which to write the crate that code is compiled in, and other files.
+    /// Sessions that run at the same time must not share a directory. If not set, `EVCXR_TMPDIR`
+    /// is used if set, otherwise a temporary directory is created and deleted with the session.
+    pub fn tmpdir(mut self, tmpdir: impl Into<PathBuf>) -> SessionConfig {
+        self.config.tmpdir = Some(tmpdir.into());
+        self
+    }
+
+    /// Sets the rustup toolchain to build with, e.g. `nightly`.
+    pub fn toolchain(mut self, toolchain: &str) -> SessionConfig {
+        self.config.toolchain = Some(toolchain.to_owned());
+        self
+    }
+
+    /// Sets the Rust edition that code is compiled with, e.g. `2018`. Defaults to 2021.
+    pub fn edition(mut self, edition: &str) -> SessionConfig {
+        self.config.edition = Some(edition.to_owned());
+        self
+    }
+
+    /// Sets the paths of cargo and rustc, for environments where they aren't found in the usual
+    /// way, e.g. Nix or Bazel.
+    pub fn tool_paths(
+        mut self,
+        cargo: impl Into<PathBuf>,
+        rustc: impl Into<PathBuf>,
+    ) -> SessionConfig {
+        self.config.cargo_path = Some(cargo.into());
+        self.config.rustc_path = Some(rustc.into());
+        self
+    }
+
+    /// Adds an environment variable for the process that runs code.
+    pub fn env(mut self, key: &str, value: &str) -> SessionConfig {
+        self.config.env.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sets the working directory of the process that runs code. Defaults to ours.
+    pub fn working_dir(mut self, working_dir: impl Into<PathBuf>) -> SessionConfig {
+        self.config.working_dir = Some(working_dir.into());
+        self
+    }
+
+    /// Limits the memory, in bytes, that the process that runs code may use. If code uses more,
+    /// evaluation fails with `SessionError::ProcessEnded`.
+    pub fn max_memory(mut self, bytes: u64) -> SessionConfig {
+        self.config.limits.max_memory = Some(bytes);
+        self
+    }
+
+    /// Limits the CPU time that the process that runs code may use in total, across evaluations.
+    /// If code uses more, evaluation fails with `SessionError::ProcessEnded`.
+    pub fn max_cpu_time(mut self, max_cpu_time: Duration) -> SessionConfig {
+        self.config.limits.max_cpu_time = Some(max_cpu_time);
+        self
+    }
+
+    /// Restricts the process that runs code to writing only to the session's directory and
+    /// `writable_paths`, and unless `allow_network` is set, denies it network access. Only
+    /// supported on Linux. Creating the session fails if the kernel doesn't support it.
+    pub fn sandbox(mut self, allow_network: bool, writable_paths: Vec<PathBuf>) -> SessionConfig {
+        self.config.sandbox_policy = Some(SandboxPolicy {
+            allow_network,
+            writable_paths,
+        });
+        self
+    }
+
+    /// Keeps dependency versions in `lockfile` across sessions. If it exists, the versions it
+    /// records are used, and it's updated whenever they change, e.g. when a dependency is added.
+    pub fn persistent_lockfile(mut self, lockfile: impl Into<PathBuf>) -> SessionConfig {
+        self.config.persistent_lockfile = Some(lockfile.into());
+        self
+    }
+
+    pub(crate) fn tmpdir_path(&self) -> Option<&PathBuf> {
+        self.config.tmpdir.as_ref()
+    }
+}
+
+/// A variable defined in a `Session`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Variable {
+    pub name: String,
+    /// The variable's type, as it would be written in code.
+    pub type_name: String,
+}
+
+/// The size and alignment of a type, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Layout {
+    pub size: usize,
+    pub align: usize,
+}
+
+/// Interrupts a `Session` from another thread.
+#[derive(Clone)]
+pub struct Interrupter {
+    handle: InterruptHandle,
+}
+
+impl Interrupter {
+    /// Interrupts the code that the session is running, if any. Returns whether there was
+    /// anything to interrupt.
+    pub fn interrupt(&self) -> bool {
+        self.handle.interrupt()
+    }
+}
+
+/// A possible completion of some code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Completion {
+    /// The code to replace the range with.
+    pub code: String,
+    /// The byte range of the code being completed that would be replaced.
+    pub range: std::ops::Range<usize>,
+}
+
+/// What evaluating code with `Session::eval` produced.
+#[derive(Debug)]
+pub struct SessionOutputs {
+    outputs: EvalOutputs,
+}
+
+impl SessionOutputs {
+    /// Returns the content of type `mime_type`, e.g. `text/plain` or `text/html`, if there is some
+    /// and it's textual.
+    pub fn get(&self, mime_type: &str) -> Option<&str> {
+        self.outputs.get(mime_type)
+    }
+
+    /// Returns the content of type `mime_type`, e.g. `image/png`, if there is some and it's binary.
+    pub fn bytes(&self, mime_type: &str) -> Option<&[u8]> {
+        self.outputs.content_by_mime_type.get(mime_type)?.as_bytes()
+    }
+
+    /// Returns the content of type `mime_type`, e.g. `application/json`, serialized as JSON, if
+    /// there is some and it's JSON.
+    pub fn json(&self, mime_type: &str) -> Option<String> {
+        let value = self
+            .outputs
+            .content_by_mime_type
+            .get(mime_type)?
+            .as_json()?;
+        Some(value.dump())
+    }
+
+    /// Returns the mime types of the content, sorted.
+    pub fn mime_types(&self) -> Vec<&str> {
+        let mut mime_types: Vec<&str> = self
+            .outputs
+            .content_by_mime_type
+            .keys()
+            .map(String::as_str)
+            .collect();
+        mime_types.sort_unstable();
+        mime_types
+    }
+
+    /// Returns the messages of the compiler's warnings about the code.
+    pub fn warnings(&self) -> Vec<String> {
+        self.outputs
+            .warnings
+            .iter()
+            .map(|warning| warning.message())
+            .collect()
+    }
+
+    /// Returns how long the evaluation took, if timing was turned on with `:timing`.
+    pub fn duration(&self) -> Option<Duration> {
+        self.outputs.timing.as_ref().map(|timing| timing.total)
+    }
+}
+
+/// Why a `Session` couldn't do what was asked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionError {
+    /// The code didn't compile. Has the message of each error.
+    Compilation(Vec<String>),
+    /// The process that runs the code ended, e.g. because the code panicked or crashed, so the
+    /// session's variables were lost.
+    ProcessEnded(String),
+    /// Anything else, e.g. an invalid command, or cargo or rustc not being installed.
+    Other(String),
+}
+
+impl SessionError {
+    fn new(error: Error) -> SessionError {
+        match error {
+            Error::CompilationErrors(errors) => {
+                SessionError::Compilation(errors.iter().map(|error| error.message()).collect())
+            }
+            Error::SubprocessTerminated(message) => SessionError::ProcessEnded(message),
+            other => SessionError::Other(other.to_string()),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Compilation(messages) => write!(f, "{}", messages.join("\n")),
+            SessionError::ProcessEnded(message) | SessionError::Other(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl Session {
+    /// Creates a session with the default configuration.
+    pub fn new() -> Result<Session, SessionError> {
+        Self::with_config(SessionConfig::default())
+    }
+
+    pub fn with_config(config: SessionConfig) -> Result<Session, SessionError> {
+        let (command_context, outputs) =
+            CommandContext::with_config(config.config).map_err(SessionError::new)?;
+        Ok(Self::from_parts(command_context, outputs))
+    }
+
+    #[doc(hidden)]
+    pub fn new_for_testing() -> Session {
+        let (eval_context, outputs) = EvalContext::new_for_testing();
+        Self::from_parts(CommandContext::with_eval_context(eval_context), outputs)
+    }
+
+    fn from_parts(command_context: CommandContext, outputs: EvalContextOutputs) -> Session {
+        Session {
+            command_context,
+            stdout: outputs.stdout,
+            stderr: outputs.stderr,
+        }
+    }
+
+    /// Evaluates `code`, which may contain statements, items, an expression whose value is
+    /// returned, and commands such as `:dep`, just like a notebook cell.
+    pub fn eval(&mut self, code: &str) -> Result<SessionOutputs, SessionError> {
+        let outputs = self
+            .command_context
+            .execute(code)
+            .map_err(SessionError::new)?;
+        Ok(SessionOutputs { outputs })
+    }
+
+    /// Returns possible completions at byte offset `position` in `code`, given the variables and
+    /// items defined so far.
+    pub fn complete(
+        &mut self,
+        code: &str,
+        position: usize,
+    ) -> Result<Vec<Completion>, SessionError> {
+        let completions = self
+            .command_context
+            .completions(code, position)
+            .map_err(SessionError::new)?;
+        let range = completions.start_offset..completions.end_offset;
+        Ok(completions
+            .completions
+            .into_iter()
+            .map(|completion| Completion {
+                code: completion.code,
+                range: range.clone(),
+            })
+            .collect())
+    }
+
+    /// Returns something that can interrupt evaluation from another thread.
+    pub fn interrupter(&self) -> Interrupter {
+        Interrupter {
+            handle: self.command_context.interrupt_handle(),
+        }
+    }
+
+    /// Returns the variables defined so far, sorted by name.
+    pub fn variables(&self) -> Vec<Variable> {
+        let mut variables: Vec<Variable> = self
+            .command_context
+            .variables_and_types()
+            .map(|(name, type_name)| Variable {
+                name: name.to_owned(),
+                type_name: type_name.to_owned(),
+            })
+            .collect();
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+        variables
+    }
+
+    /// Returns the names of the functions, types and other items defined so far, sorted.
+    pub fn items(&self) -> Vec<String> {
+        let mut items: Vec<String> = self
+            .command_context
+            .defined_item_names()
+            .map(str::to_owned)
+            .collect();
+        items.sort();
+        items
+    }
+
+    /// Returns the type of `expression`, inferred without evaluating it. The expression can refer to
+    /// the variables and items defined so far.
+    pub fn type_of(&mut self, expression: &str) -> Result<String, SessionError> {
+        self.command_context
+            .type_of(expression)
+            .map_err(SessionError::new)
+    }
+
+    /// Returns the size and alignment of `type_name`. This compiles and runs a small amount of code.
+    pub fn type_layout(&mut self, type_name: &str) -> Result<Layout, SessionError> {
+        let layout = self
+            .command_context
+            .type_layout(type_name)
+            .map_err(SessionError::new)?;
+        Ok(Layout {
+            size: layout.size,
+            align: layout.align,
+        })
+    }
+
+    /// Returns `code` formatted with rustfmt. Commands at the start of the code are left as they
+    /// are. The toolchain's rustfmt is used, so this fails if it isn't installed.
+    pub fn format_code(&self, code: &str) -> Result<String, SessionError> {
+        self.command_context
+            .format_code(code)
+            .map_err(SessionError::new)
+    }
+
+    /// Returns receivers of the lines that user code writes to stdout and stderr, for forwarding
+    /// them as they're written.
+    pub(crate) fn output_receivers(&self) -> (Receiver<String>, Receiver<String>) {
+        (self.stdout.clone(), self.stderr.clone())
+    }
+
+    /// Returns the lines that user code has written to stdout since this was last called. All the
+    /// output of an evaluation is available once `eval` returns.
+    pub fn take_stdout(&self) -> Vec<String> {
+        self.stdout.try_iter().collect()
+    }
+
+    /// Like `take_stdout`, but for stderr. This includes output from cargo while it builds
+    /// dependencies.
+    pub fn take_stderr(&self) -> Vec<String> {
+        self.stderr.try_iter().collect()
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Interrupter;
+use crate::Session;
+use crate::SessionConfig;
+use crate::SessionError;
+use crate::SessionOutputs;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+
+pub type SessionId = u64;
+
+struct ManagedSession {
+    session: Arc<Mutex<Session>>,
+    // Kept outside the session's lock, so that a session can be interrupted while it's evaluating.
+    interrupter: Interrupter,
+}
+
+/// Hosts any number of independent sessions in one process, e.g. so that a single service can
+/// back many notebook users. Each session has its own variables, dependencies, subprocess and
+/// directory, and sessions can evaluate code at the same time from different threads. Calls for
+/// the same session are serialized.
+pub struct SessionManager {
+    config: SessionConfig,
+    sessions: Mutex<HashMap<SessionId, ManagedSession>>,
+    next_id: AtomicU64,
+}
+
+impl SessionManager {
+    /// Creates a manager whose sessions are created with `config`. If that sets a `tmpdir`, each
+    /// session gets its own subdirectory of it.
+    pub fn new(config: SessionConfig) -> SessionManager {
+        SessionManager {
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Creates a new session and returns its ID.
+    pub fn create(&self) -> Result<SessionId, SessionError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut config = self.config.clone();
+        if let Some(tmpdir) = self.session_tmpdir(id) {
+            config = config.tmpdir(tmpdir);
+        }
+        let session = Session::with_config(config)?;
+        Ok(self.insert(id, session))
+    }
+
+    /// Adds an existing session, returning the ID by which it can now be accessed.
+    pub fn add(&self, session: Session) -> SessionId {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.insert(id, session)
+    }
+
+    /// Evaluates `code` in session `id`. Only that session is blocked while this runs.
+    pub fn eval(&self, id: SessionId, code: &str) -> Result<SessionOutputs, SessionError> {
+        self.with_session(id, |session| session.eval(code))?
+    }
+
+    /// Runs `f` with exclusive access to session `id`, e.g. to get completions or read its output.
+    pub fn with_session<R>(
+        &self,
+        id: SessionId,
+        f: impl FnOnce(&mut Session) -> R,
+    ) -> Result<R, SessionError> {
+        let session = match self.lock_sessions().get(&id) {
+            Some(managed) => Arc::clone(&managed.session),
+            None => return Err(no_session(id)),
+        };
+        let mut session = session.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(f(&mut session))
+    }
+
+    /// Interrupts whatever session `id` is running. Returns whether there was anything to
+    /// interrupt.
+    pub fn interrupt(&self, id: SessionId) -> Result<bool, SessionError> {
+        match self.lock_sessions().get(&id) {
+            Some(managed) => Ok(managed.interrupter.interrupt()),
+            None => Err(no_session(id)),
+        }
+    }
+
+    /// Removes session `id`, returning whether it existed. Its subprocess is stopped once any
+    /// evaluation that's in progress finishes. Its directory is deleted then too, unless it's under
+    /// `tmpdir` or `EVCXR_TMPDIR`.
+    pub fn close(&self, id: SessionId) -> bool {
+        self.lock_sessions().remove(&id).is_some()
+    }
+
+    /// Returns the IDs of the open sessions, in the order they were created.
+    pub fn session_ids(&self) -> Vec<SessionId> {
+        let mut ids: Vec<SessionId> = self.lock_sessions().keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn insert(&self, id: SessionId, session: Session) -> SessionId {
+        let managed = ManagedSession {
+            interrupter: session.interrupter(),
+            session: Arc::new(Mutex::new(session)),
+        };
+        self.lock_sessions().insert(id, managed);
+        id
+    }
+
+    fn session_tmpdir(&self, id: SessionId) -> Option<PathBuf> {
+        let parent = self
+            .config
+            .tmpdir_path()
+            .cloned()
+            .or_else(|| std::env::var_os("EVCXR_TMPDIR").map(PathBuf::from))?;
+        Some(parent.join(format!("session-{}", id)))
+    }
+
+    fn lock_sessions(&self) -> MutexGuard<HashMap<SessionId, ManagedSession>> {
+        // A panic while holding this lock can't leave the map inconsistent.
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn no_session(id: SessionId) -> SessionError {
+    SessionError::Other(format!("No session with ID {}", id))
+}
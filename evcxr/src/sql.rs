@@ -0,0 +1,183 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `:sql`, which runs SQL with the evcxr_sql crate. `:sql connect <url>` chooses the database.
+//! Otherwise, everything after `:sql`, including the rest of the cell, is SQL, which is run by code
+//! that we generate, so that its result is displayed or bound to a variable like any other value.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use once_cell::sync::OnceCell;
+use regex::Regex;
+
+/// The evcxr_sql dependency that we add if the user hasn't added it themselves.
+pub(crate) const SQL_DEPENDENCY: &str = r#""1.1""#;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SqlCommand<'a> {
+    /// `:sql connect`, with the URL to connect to, or None to show the current connection.
+    Connect(Option<&'a str>),
+    Query {
+        binding: Option<Binding<'a>>,
+        sql: &'a str,
+    },
+}
+
+/// The variable that a query's result is assigned to, from `:sql let name =` or
+/// `:sql let name: Vec<Record> =`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Binding<'a> {
+    name: &'a str,
+    /// If a type is given, rows are converted to it with `Table::records`.
+    type_name: Option<&'a str>,
+}
+
+/// Returns whether `:sql` with arguments `args`, which are what follows it on its line, takes the
+/// rest of the cell as SQL. Only `:sql connect` doesn't.
+pub(crate) fn takes_rest_of_cell(args: Option<&str>) -> bool {
+    connect_url(args.unwrap_or_default().trim()).is_none()
+}
+
+pub(crate) fn parse(args: &str) -> Result<SqlCommand<'_>, Error> {
+    static BINDING_RE: OnceCell<Regex> = OnceCell::new();
+    let binding_re = BINDING_RE.get_or_init(|| {
+        Regex::new(r"^let\s+([A-Za-z_][A-Za-z0-9_]*)\s*(?::\s*([^=]+?)\s*)?=").unwrap()
+    });
+    let args = args.trim();
+    if let Some(url) = connect_url(args) {
+        let url = if url.is_empty() { None } else { Some(url) };
+        return Ok(SqlCommand::Connect(url));
+    }
+    let (binding, sql) = match binding_re.captures(args) {
+        Some(captures) => (
+            Some(Binding {
+                name: captures.get(1).unwrap().as_str(),
+                type_name: captures.get(2).map(|m| m.as_str()),
+            }),
+            args[captures.get(0).unwrap().end()..].trim(),
+        ),
+        None => (None, args),
+    };
+    if sql.is_empty() {
+        bail!(
+            ":sql requires a query, either after it or on the following lines. e.g. :sql SELECT 42"
+        );
+    }
+    Ok(SqlCommand::Query { binding, sql })
+}
+
+/// If `args` is `connect`, optionally followed by a URL, returns the URL, which may be empty.
+fn connect_url(args: &str) -> Option<&str> {
+    let rest = args.strip_prefix("connect")?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+/// Returns code that checks that `url` can be connected to.
+pub(crate) fn connect_code(url: &str) -> String {
+    format!("evcxr_sql::connect({:?})?;", url)
+}
+
+/// Returns code that runs `sql` against `connection`, or the in-memory database if that's None.
+/// Without a binding, the code is an expression, so that the result is displayed.
+pub(crate) fn query_code(connection: Option<&str>, binding: Option<&Binding>, sql: &str) -> String {
+    let query = format!("evcxr_sql::query({:?}, {:?})?", connection, sql);
+    match binding {
+        None => query,
+        Some(Binding {
+            name,
+            type_name: None,
+        }) => format!("let {} = {};", name, query),
+        Some(Binding {
+            name,
+            type_name: Some(type_name),
+        }) => format!("let {}: {} = {}.records()?;", name, type_name, query),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use super::query_code;
+    use super::takes_rest_of_cell;
+    use super::Binding;
+    use super::SqlCommand;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            parse("connect  sales.duckdb ").unwrap(),
+            SqlCommand::Connect(Some("sales.duckdb"))
+        );
+        assert_eq!(parse("connect").unwrap(), SqlCommand::Connect(None));
+        assert_eq!(
+            parse("\nSELECT 1").unwrap(),
+            SqlCommand::Query {
+                binding: None,
+                sql: "SELECT 1"
+            }
+        );
+        assert_eq!(
+            parse("let products: Vec<Product> =\nSELECT * FROM products").unwrap(),
+            SqlCommand::Query {
+                binding: Some(Binding {
+                    name: "products",
+                    type_name: Some("Vec<Product>"),
+                }),
+                sql: "SELECT * FROM products"
+            }
+        );
+        assert_eq!(
+            parse("let t = SELECT 'a=b'").unwrap(),
+            SqlCommand::Query {
+                binding: Some(Binding {
+                    name: "t",
+                    type_name: None,
+                }),
+                sql: "SELECT 'a=b'"
+            }
+        );
+        // `connections` isn't `connect`.
+        assert!(matches!(
+            parse("connections").unwrap(),
+            SqlCommand::Query { .. }
+        ));
+        assert!(parse("let t =\n").is_err());
+        assert!(!takes_rest_of_cell(Some("connect x.duckdb")));
+        assert!(takes_rest_of_cell(None));
+        assert!(takes_rest_of_cell(Some("let t =")));
+    }
+
+    #[test]
+    fn code() {
+        assert_eq!(
+            query_code(None, None, "SELECT \"x\""),
+            r#"evcxr_sql::query(None, "SELECT \"x\"")?"#
+        );
+        assert_eq!(
+            query_code(
+                Some("a.duckdb"),
+                Some(&Binding {
+                    name: "rows",
+                    type_name: Some("Vec<Row>"),
+                }),
+                "SELECT 1"
+            ),
+            r#"let rows: Vec<Row> = evcxr_sql::query(Some("a.duckdb"), "SELECT 1")?.records()?;"#
+        );
+    }
+}
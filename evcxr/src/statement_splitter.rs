@@ -23,13 +23,24 @@ pub(crate) struct OriginalUserCode<'a> {
     pub(crate) start_byte: usize,
 }
 
+/// Code that we put before the user's code so that it parses as the body of a function.
+const PRELUDE: &str = "fn f(){";
+
+impl<'a> OriginalUserCode<'a> {
+    /// Returns the whitespace and comments that follow the statement, up to where the next one
+    /// starts.
+    pub(crate) fn trailing(&self) -> &'a str {
+        let node_end = usize::from(self.node.text_range().end()) - PRELUDE.len() - self.start_byte;
+        &self.code[node_end.min(self.code.len())..]
+    }
+}
+
 /// Attempt to split some code into separate statements. All of the input will be returned besides
 /// possibly some trailing whitespace. i.e. if we can't parse it as statements, everything from the
 /// point where we can't parse onwards will be returned as a single statement.
 pub(crate) fn split_into_statements(code: &str) -> Vec<OriginalUserCode> {
     let mut output = Vec::new();
-    let prelude = "fn f(){";
-    let parsed_file = SourceFile::parse(&(prelude.to_owned() + code + "}"));
+    let parsed_file = SourceFile::parse(&(PRELUDE.to_owned() + code + "}"));
     let mut start_byte = 0;
     if let Some(stmt_list) = parsed_file
         .syntax_node()
@@ -47,7 +58,7 @@ pub(crate) fn split_into_statements(code: &str) -> Vec<OriginalUserCode> {
             // whitespace after nodes rather than before, so our end is the
             // start of the next node, or failing that the end of the code.
             let end = next
-                .map(|next| usize::from(next.text_range().start()) - prelude.len())
+                .map(|next| usize::from(next.text_range().start()) - PRELUDE.len())
                 .unwrap_or(code.len());
             output.push(OriginalUserCode {
                 code: &code[start_byte..end],
@@ -163,4 +174,12 @@ mod test {
         assert!(ast::Expr::can_cast(out[1].node.kind()));
         assert_eq!(out[1].code, "Bar::result()");
     }
+
+    #[test]
+    fn trailing() {
+        let out = split_into_statements("\nlet a = 1; // one\n\nlet b = 2;");
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].trailing(), " // one\n\n");
+        assert_eq!(out[1].trailing(), "");
+    }
 }
@@ -0,0 +1,247 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lets user code read from stdin. The runtime receives its commands on stdin, so on Linux we move
+//! them to another file descriptor and give user code a pipe as its stdin instead. While user code
+//! is running, anything that evcxr sends is passed through to the pipe. If user code blocks reading
+//! from the pipe, we print `STDIN_WAITING` followed by how many bytes we've passed through so far.
+//! evcxr then asks the user for a line of input, unless it has sent more than that many bytes, in
+//! which case the read will be satisfied by input that's on its way, e.g. a reply to a request
+//! from `evcxr_input`.
+//!
+//! On other platforms, user code shares stdin with the runtime, so reads only return what evcxr
+//! sends in reply to an explicit request.
+
+use std::io::BufReader;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+/// Printed by the runtime when user code is blocked reading stdin. May be preceded on the same line
+/// by a prompt that user code printed without a newline.
+pub(crate) const STDIN_WAITING: &str = "EVCXR_STDIN_WAITING ";
+
+/// If `line` says that user code is waiting for input, returns the prompt that preceded it and how
+/// many bytes of input user code had been given.
+pub(crate) fn parse_waiting(line: &str) -> Option<(&str, usize)> {
+    let index = line.rfind(STDIN_WAITING)?;
+    let forwarded = line[index + STDIN_WAITING.len()..].parse().ok()?;
+    Some((&line[..index], forwarded))
+}
+
+type Commands = BufReader<Box<dyn Read + Send>>;
+
+struct RelayState {
+    executing: bool,
+    /// Bytes passed through to user code during the current execution.
+    forwarded: usize,
+    /// The value of `forwarded` when we last reported that user code was waiting for input.
+    reported: Option<usize>,
+}
+
+struct Shared {
+    commands: Mutex<Commands>,
+    state: Mutex<RelayState>,
+    started: Condvar,
+}
+
+/// Owns the runtime's command input and, on Linux, relays input to user code.
+pub(crate) struct StdinRelay {
+    shared: Arc<Shared>,
+}
+
+impl StdinRelay {
+    /// Sets up the relay. Must only be called once, before anything else reads stdin.
+    pub(crate) fn install() -> StdinRelay {
+        #[cfg(target_os = "linux")]
+        match linux::install() {
+            Ok(relay) => return relay,
+            Err(error) => eprintln!("Reading from stdin in user code won't work: {}", error),
+        }
+        StdinRelay::new(Box::new(std::io::stdin()))
+    }
+
+    fn new(commands: Box<dyn Read + Send>) -> StdinRelay {
+        StdinRelay {
+            shared: Arc::new(Shared {
+                commands: Mutex::new(BufReader::new(commands)),
+                state: Mutex::new(RelayState {
+                    executing: false,
+                    forwarded: 0,
+                    reported: None,
+                }),
+                started: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Returns the reader for commands from evcxr.
+    pub(crate) fn commands(&self) -> MutexGuard<'_, Commands> {
+        self.shared.commands.lock().unwrap()
+    }
+
+    /// Runs `user_code`, passing input from evcxr through to it while it runs.
+    pub(crate) fn run_user_code<T>(&self, user_code: impl FnOnce() -> T) -> T {
+        *self.shared.state.lock().unwrap() = RelayState {
+            executing: true,
+            forwarded: 0,
+            reported: None,
+        };
+        self.shared.started.notify_one();
+        let result = user_code();
+        self.shared.state.lock().unwrap().executing = false;
+        // Wait for any line that's currently being read, so that it can't be the next command.
+        drop(self.commands());
+        result
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::Shared;
+    use super::StdinRelay;
+    use super::STDIN_WAITING;
+    use std::fs::File;
+    use std::io::BufRead;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::io::RawFd;
+    use std::sync::Arc;
+
+    /// How long we wait for input from evcxr before checking whether user code is blocked.
+    const POLL_INTERVAL_MS: i32 = 20;
+
+    pub(super) fn install() -> std::io::Result<StdinRelay> {
+        let (commands, pipe) = unsafe {
+            let command_fd = libc::fcntl(0, libc::F_DUPFD_CLOEXEC, 3);
+            if command_fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let commands = File::from_raw_fd(command_fd);
+            let mut fds = [0; 2];
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let (reader, writer) = (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]));
+            // dup2 clears O_CLOEXEC, so user code can pass stdin on to processes that it starts.
+            if libc::dup2(reader.as_raw_fd(), 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            (commands, writer)
+        };
+        let command_fd = commands.as_raw_fd();
+        let relay = StdinRelay::new(Box::new(commands));
+        let shared = Arc::clone(&relay.shared);
+        std::thread::spawn(move || forward(&shared, command_fd, pipe));
+        Ok(relay)
+    }
+
+    fn forward(shared: &Shared, command_fd: RawFd, mut pipe: File) {
+        // Our main thread, which runs user code, has the same ID as our process.
+        let syscall_path = format!("/proc/self/task/{}/syscall", std::process::id());
+        loop {
+            {
+                let mut state = shared.state.lock().unwrap();
+                while !state.executing {
+                    state = shared.started.wait(state).unwrap();
+                }
+            }
+            if !has_input(shared, command_fd) {
+                let mut state = shared.state.lock().unwrap();
+                if state.executing
+                    && state.reported != Some(state.forwarded)
+                    && matches!(
+                        std::fs::read_to_string(&syscall_path),
+                        Ok(syscall) if is_reading_stdin(&syscall)
+                    )
+                {
+                    println!("{}{}", STDIN_WAITING, state.forwarded);
+                    state.reported = Some(state.forwarded);
+                }
+                continue;
+            }
+            let mut line = Vec::new();
+            {
+                let mut commands = shared.commands.lock().unwrap();
+                if !shared.state.lock().unwrap().executing {
+                    continue;
+                }
+                if commands.read_until(b'\n', &mut line).unwrap_or(0) == 0 {
+                    // evcxr has gone away. Dropping the pipe lets user code see the end of input.
+                    return;
+                }
+            }
+            if pipe.write_all(&line).is_err() {
+                return;
+            }
+            shared.state.lock().unwrap().forwarded += line.len();
+        }
+    }
+
+    /// Returns whether there's input from evcxr, waiting up to `POLL_INTERVAL_MS` for some.
+    fn has_input(shared: &Shared, command_fd: RawFd) -> bool {
+        if !shared.commands.lock().unwrap().buffer().is_empty() {
+            return true;
+        }
+        let mut poll_fd = libc::pollfd {
+            fd: command_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut poll_fd, 1, POLL_INTERVAL_MS) > 0 }
+    }
+
+    /// Returns whether `syscall`, the contents of /proc/<pid>/task/<tid>/syscall, shows the thread
+    /// blocked reading from file descriptor 0.
+    pub(super) fn is_reading_stdin(syscall: &str) -> bool {
+        let mut fields = syscall.split_whitespace();
+        fields.next().and_then(|number| number.parse().ok()) == Some(libc::SYS_read)
+            && fields.next() == Some("0x0")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_waiting;
+
+    #[test]
+    fn waiting() {
+        assert_eq!(parse_waiting("EVCXR_STDIN_WAITING 0"), Some(("", 0)));
+        assert_eq!(
+            parse_waiting("Name? EVCXR_STDIN_WAITING 12"),
+            Some(("Name? ", 12))
+        );
+        assert_eq!(parse_waiting("EVCXR_STDIN_WAITING x"), None);
+        assert_eq!(parse_waiting("Hello"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reading_stdin() {
+        use super::linux::is_reading_stdin;
+        let read = libc::SYS_read;
+        assert!(is_reading_stdin(&format!(
+            "{} 0x0 0x55d0c8a4e2a0 0x2000 0x0 0x0 0x0 0x7ffd6b1a7e48 0x7f3b4a31b1d2\n",
+            read
+        )));
+        assert!(!is_reading_stdin(&format!(
+            "{} 0x3 0x55d0c8a4e2a0 0x2000 0x0 0x0 0x0 0x7ffd6b1a7e48 0x7f3b4a31b1d2\n",
+            read
+        )));
+        assert!(!is_reading_stdin("running\n"));
+    }
+}
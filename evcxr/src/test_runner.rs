@@ -0,0 +1,530 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:test`, which runs the `#[test]` functions defined in the session. Rather than
+//! building a test harness, we compile the session's crate with `cfg(test)` set and the `#[test]`
+//! attributes removed, so that the tests become ordinary functions. We then call them from
+//! generated code in the subprocess, like any other evaluation.
+
+use crate::evcxr_internal_runtime;
+use once_cell::sync::OnceCell;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxNode;
+use ra_ap_syntax::TextRange;
+use ra_ap_syntax::TextSize;
+use regex::Regex;
+use std::fmt::Write;
+
+/// A `#[test]` function found in the session's code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TestFn {
+    /// The function's path within the crate, e.g. `tests::it_works`.
+    pub(crate) name: String,
+    /// If the test has `#[should_panic]`, a string literal containing text that the panic message
+    /// must contain. Empty if any panic will do.
+    pub(crate) should_panic: Option<String>,
+    pub(crate) ignore: bool,
+}
+
+/// Changes to make to an item's code to make its tests callable.
+enum Edit {
+    Delete(TextRange),
+    Insert(TextSize, &'static str),
+}
+
+/// Returns the tests in `code`, which should be a single item, such as a function or module.
+pub(crate) fn find_tests(code: &str) -> Vec<TestFn> {
+    analyze(code).0
+}
+
+/// Returns `code` with its `#[test]` functions turned into ordinary functions that can be called
+/// from the root of the crate. That is, the test attributes are removed and the functions, and
+/// any modules containing them, are made `pub(crate)` if they're private.
+pub(crate) fn make_tests_callable(code: &str) -> String {
+    let (_, mut edits) = analyze(code);
+    // Apply edits from the end, so that the offsets of the remaining edits stay valid.
+    edits.sort_by_key(|edit| match edit {
+        Edit::Delete(range) => range.start(),
+        Edit::Insert(offset, _) => *offset,
+    });
+    let mut code = code.to_owned();
+    for edit in edits.iter().rev() {
+        match edit {
+            Edit::Delete(range) => code.replace_range(std::ops::Range::<usize>::from(*range), ""),
+            Edit::Insert(offset, text) => code.insert_str(usize::from(*offset), text),
+        }
+    }
+    code
+}
+
+fn analyze(code: &str) -> (Vec<TestFn>, Vec<Edit>) {
+    let mut tests = Vec::new();
+    let mut edits = Vec::new();
+    let mut public_modules = Vec::new();
+    let parsed = ast::SourceFile::parse(code);
+    'functions: for function in parsed.syntax_node().descendants().filter_map(ast::Fn::cast) {
+        let mut is_test = false;
+        let mut should_panic = None;
+        let mut ignore = false;
+        let mut test_attributes = Vec::new();
+        for attr in ast::HasAttrs::attrs(&function) {
+            match attribute_name(&attr).as_str() {
+                "test" => is_test = true,
+                "should_panic" => should_panic = Some(expected_panic(&attr)),
+                "ignore" => ignore = true,
+                _ => continue,
+            }
+            // Remove the whitespace after the attribute too, so as not to leave a blank line.
+            let mut range = attr.syntax().text_range();
+            if let Some(next) = attr.syntax().next_sibling_or_token() {
+                if next.kind() == SyntaxKind::WHITESPACE {
+                    range = range.cover(next.text_range());
+                }
+            }
+            test_attributes.push(range);
+        }
+        let function_name = match ast::HasName::name(&function) {
+            Some(name) if is_test => name.text().to_string(),
+            _ => continue,
+        };
+        // Tests can only be reached from the root of the crate through modules. A function
+        // nested in a function body, for example, can't be called.
+        let mut modules = Vec::new();
+        for ancestor in function.syntax().ancestors().skip(1) {
+            if let Some(module) = ast::Module::cast(ancestor.clone()) {
+                modules.push(module);
+            } else if !matches!(
+                ancestor.kind(),
+                SyntaxKind::ITEM_LIST | SyntaxKind::SOURCE_FILE
+            ) {
+                continue 'functions;
+            }
+        }
+        let mut path = Vec::new();
+        for module in modules.iter().rev() {
+            match ast::HasName::name(module) {
+                Some(name) => path.push(name.text().to_string()),
+                None => continue 'functions,
+            }
+        }
+        path.push(function_name);
+        edits.extend(test_attributes.into_iter().map(Edit::Delete));
+        if ast::HasVisibility::visibility(&function).is_none() {
+            if let Some(start) = start_after_attributes(function.syntax()) {
+                edits.push(Edit::Insert(start, "pub(crate) "));
+            }
+        }
+        // The outermost module is declared at the root of the crate, so is visible there already.
+        for module in modules.iter().rev().skip(1) {
+            if ast::HasVisibility::visibility(module).is_none() {
+                if let Some(start) = start_after_attributes(module.syntax()) {
+                    if !public_modules.contains(&start) {
+                        public_modules.push(start);
+                        edits.push(Edit::Insert(start, "pub(crate) "));
+                    }
+                }
+            }
+        }
+        tests.push(TestFn {
+            name: path.join("::"),
+            should_panic,
+            ignore,
+        });
+    }
+    (tests, edits)
+}
+
+/// Returns the name of an attribute, e.g. `should_panic` for `#[should_panic(expected = "x")]`.
+fn attribute_name(attr: &ast::Attr) -> String {
+    let text = attr.syntax().text().to_string();
+    text.trim_start_matches("#[")
+        .split(|c: char| c == '(' || c == '=' || c == ']')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_owned()
+}
+
+/// Returns the string literal given as the expected panic message by a `#[should_panic]`
+/// attribute, or an empty string literal if there isn't one.
+fn expected_panic(attr: &ast::Attr) -> String {
+    static STRING_LITERAL: OnceCell<Regex> = OnceCell::new();
+    let string_literal = STRING_LITERAL.get_or_init(|| Regex::new(r#""(?:[^"\\]|\\.)*""#).unwrap());
+    string_literal
+        .find(&attr.syntax().text().to_string())
+        .map_or_else(|| "\"\"".to_owned(), |m| m.as_str().to_owned())
+}
+
+/// Returns where an item starts once its attributes and any leading comments are skipped, which
+/// is where its visibility would go.
+fn start_after_attributes(node: &SyntaxNode) -> Option<TextSize> {
+    node.children_with_tokens()
+        .find(|child| {
+            !matches!(
+                child.kind(),
+                SyntaxKind::ATTR | SyntaxKind::COMMENT | SyntaxKind::WHITESPACE
+            )
+        })
+        .map(|child| child.text_range().start())
+}
+
+/// Returns code that runs `tests` in the subprocess. This is evaluated with the session's state in
+/// test mode, so that the test functions exist and are callable.
+pub(crate) fn runner_code(tests: &[TestFn]) -> String {
+    let mut code = "crate::evcxr_internal_runtime::run_tests(&[\n".to_owned();
+    for test in tests {
+        writeln!(
+            code,
+            "    crate::evcxr_internal_runtime::Test {{\n        \
+                name: \"{name}\",\n        \
+                should_panic: {should_panic},\n        \
+                ignore: {ignore},\n        \
+                run: || crate::evcxr_internal_runtime::TestReturn::test_error(crate::{name}()),\n    \
+            }},",
+            name = test.name,
+            should_panic = test
+                .should_panic
+                .as_ref()
+                .map_or_else(|| "None".to_owned(), |expected| format!("Some({})", expected)),
+            ignore = test.ignore,
+        )
+        .unwrap();
+    }
+    code.push_str("]);");
+    code
+}
+
+/// The results of running tests with `:test`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TestReport {
+    /// The results of the tests that finished, in the order that they ran.
+    pub results: Vec<TestResult>,
+    /// The test that's running, if any.
+    running: Option<TestResult>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestResult {
+    /// The test function's path, e.g. `tests::it_works`.
+    pub name: String,
+    pub outcome: TestOutcome,
+    /// What the test wrote to stdout.
+    pub stdout: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    /// The test failed, for the given reason, e.g. a panic message.
+    Failed(String),
+    /// The test has `#[ignore]`, so wasn't run.
+    Ignored,
+}
+
+impl TestReport {
+    /// Returns whether no tests failed.
+    pub fn is_success(&self) -> bool {
+        self.count(|outcome| matches!(outcome, TestOutcome::Failed(_))) == 0
+    }
+
+    fn count(&self, matches: impl Fn(&TestOutcome) -> bool) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches(&result.outcome))
+            .count()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.results.is_empty() && self.running.is_none()
+    }
+
+    /// Processes a line of output from the subprocess. Lines that report tests starting and
+    /// finishing are consumed, as is output of a running test, which is recorded as part of its
+    /// result. Any other line is returned.
+    pub(crate) fn process_line(&mut self, line: String) -> Option<String> {
+        if let Some(name) = line.strip_prefix(evcxr_internal_runtime::TEST_STARTED) {
+            self.running = Some(TestResult {
+                name: name.to_owned(),
+                outcome: TestOutcome::Passed,
+                stdout: String::new(),
+            });
+            return None;
+        }
+        let running = match &mut self.running {
+            Some(running) => running,
+            None => return Some(line),
+        };
+        // If the test printed something without a newline at the end, then the line that says it
+        // finished will start with that output.
+        let (stdout, finished) = match line.find(evcxr_internal_runtime::TEST_FINISHED) {
+            Some(index) => (
+                &line[..index],
+                Some(&line[index + evcxr_internal_runtime::TEST_FINISHED.len()..]),
+            ),
+            None => (line.as_str(), None),
+        };
+        if finished.is_none() || !stdout.is_empty() {
+            running.stdout.push_str(stdout);
+            running.stdout.push('\n');
+        }
+        if let Some(finished) = finished {
+            // The line is the test's name, then its outcome, optionally followed by a message.
+            let outcome = finished
+                .split_once(' ')
+                .map_or("", |(_name, outcome)| outcome);
+            running.outcome = match outcome.split_once(' ') {
+                Some(("failed", message)) => TestOutcome::Failed(unescape(message)),
+                _ if outcome == "ignored" => TestOutcome::Ignored,
+                _ => TestOutcome::Passed,
+            };
+            self.results.extend(self.running.take());
+        }
+        None
+    }
+
+    pub(crate) fn to_text(&self) -> String {
+        let mut text = String::new();
+        for result in &self.results {
+            let outcome = match &result.outcome {
+                TestOutcome::Passed => "ok",
+                TestOutcome::Failed(_) => "FAILED",
+                TestOutcome::Ignored => "ignored",
+            };
+            writeln!(text, "test {} ... {}", result.name, outcome).unwrap();
+        }
+        for result in &self.results {
+            let failure = match &result.outcome {
+                TestOutcome::Failed(message) => Some(message),
+                _ => None,
+            };
+            if result.stdout.is_empty() && failure.is_none() {
+                continue;
+            }
+            write!(
+                text,
+                "\n---- {} stdout ----\n{}",
+                result.name, result.stdout
+            )
+            .unwrap();
+            if let Some(message) = failure {
+                writeln!(text, "{}", message).unwrap();
+            }
+        }
+        write!(
+            text,
+            "\ntest result: {}. {}",
+            if self.is_success() { "ok" } else { "FAILED" },
+            self.summary()
+        )
+        .unwrap();
+        text
+    }
+
+    pub(crate) fn to_html(&self) -> String {
+        let mut html = "<table>\n<tr><th>Test</th><th>Result</th><th>Output</th></tr>\n".to_owned();
+        for result in &self.results {
+            let (color, outcome, message) = match &result.outcome {
+                TestOutcome::Passed => ("green", "ok", ""),
+                TestOutcome::Failed(message) => ("red", "FAILED", message.as_str()),
+                TestOutcome::Ignored => ("gray", "ignored", ""),
+            };
+            let mut output = result.stdout.clone();
+            output.push_str(message);
+            writeln!(
+                html,
+                "<tr><td>{}</td><td style=\"color: {}\">{}</td><td><pre>{}</pre></td></tr>",
+                escape_html(&result.name),
+                color,
+                outcome,
+                escape_html(output.trim_end())
+            )
+            .unwrap();
+        }
+        write!(html, "</table>\n<p>{}</p>", self.summary()).unwrap();
+        html
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} passed; {} failed; {} ignored",
+            self.count(|outcome| *outcome == TestOutcome::Passed),
+            self.count(|outcome| matches!(outcome, TestOutcome::Failed(_))),
+            self.count(|outcome| *outcome == TestOutcome::Ignored)
+        )
+    }
+}
+
+/// Reverses `evcxr_internal_runtime::escape_field`.
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_tests;
+    use super::make_tests_callable;
+    use super::TestFn;
+    use super::TestOutcome;
+    use super::TestReport;
+    use super::TestResult;
+
+    #[test]
+    fn tests_in_modules() {
+        let code = r#"mod tests {
+    use super::*;
+    #[test]
+    fn works() {}
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn panics() {}
+    mod nested {
+        #[test]
+        #[ignore]
+        pub fn slow() {}
+    }
+    fn helper() {}
+}"#;
+        assert_eq!(
+            find_tests(code),
+            vec![
+                TestFn {
+                    name: "tests::works".to_owned(),
+                    should_panic: None,
+                    ignore: false,
+                },
+                TestFn {
+                    name: "tests::panics".to_owned(),
+                    should_panic: Some("\"boom\"".to_owned()),
+                    ignore: false,
+                },
+                TestFn {
+                    name: "tests::nested::slow".to_owned(),
+                    should_panic: None,
+                    ignore: true,
+                },
+            ]
+        );
+        assert_eq!(
+            make_tests_callable(code),
+            r#"mod tests {
+    use super::*;
+    pub(crate) fn works() {}
+    pub(crate) fn panics() {}
+    pub(crate) mod nested {
+        pub fn slow() {}
+    }
+    fn helper() {}
+}"#
+        );
+    }
+
+    #[test]
+    fn unreachable_and_non_tests() {
+        assert_eq!(find_tests("fn foo() { #[test] fn inner() {} }"), vec![]);
+        assert_eq!(find_tests("#[inline] fn foo() {}"), vec![]);
+        assert_eq!(
+            find_tests("#[test] #[should_panic] fn foo() {}"),
+            vec![TestFn {
+                name: "foo".to_owned(),
+                should_panic: Some("\"\"".to_owned()),
+                ignore: false,
+            }]
+        );
+        assert_eq!(
+            make_tests_callable("#[test] fn foo() {}"),
+            "pub(crate) fn foo() {}"
+        );
+    }
+
+    #[test]
+    fn report() {
+        let mut report = TestReport::default();
+        for line in [
+            "before",
+            "EVCXR_TEST_STARTED works",
+            "EVCXR_TEST_FINISHED works ok",
+            "EVCXR_TEST_STARTED fails",
+            "some output",
+            "partialEVCXR_TEST_FINISHED fails failed assertion failed\\nleft: 1",
+            "EVCXR_TEST_STARTED slow",
+            "EVCXR_TEST_FINISHED slow ignored",
+            "after",
+        ] {
+            let passed_through = report.process_line(line.to_owned());
+            assert_eq!(
+                passed_through.is_some(),
+                line == "before" || line == "after",
+                "{}",
+                line
+            );
+        }
+        assert_eq!(
+            report.results,
+            vec![
+                TestResult {
+                    name: "works".to_owned(),
+                    outcome: TestOutcome::Passed,
+                    stdout: String::new(),
+                },
+                TestResult {
+                    name: "fails".to_owned(),
+                    outcome: TestOutcome::Failed("assertion failed\nleft: 1".to_owned()),
+                    stdout: "some output\npartial\n".to_owned(),
+                },
+                TestResult {
+                    name: "slow".to_owned(),
+                    outcome: TestOutcome::Ignored,
+                    stdout: String::new(),
+                },
+            ]
+        );
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_text(),
+            "test works ... ok\n\
+             test fails ... FAILED\n\
+             test slow ... ignored\n\
+             \n\
+             ---- fails stdout ----\n\
+             some output\n\
+             partial\n\
+             assertion failed\n\
+             left: 1\n\
+             \n\
+             test result: FAILED. 1 passed; 1 failed; 1 ignored"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for projects built on evcxr, such as frontends, to test how they present errors without
+//! needing to run the compiler. Given some user code and compiler output for it, these produce the
+//! same `CompilationError`s that evaluating the code would, so the results can be snapshot tested.
+
+use crate::code_block::CodeBlock;
+use crate::code_block::CodeKind;
+use crate::code_block::UserCodeInfo;
+use crate::errors::CompilationError;
+use std::fmt::Write;
+
+/// Returns the code that compiler output passed to `compilation_errors` should refer to. User code
+/// is wrapped in a function, with each statement on its own line, much as it is when evaluated.
+/// Commands, such as `:dep`, and comment lines are dropped. The compiler should report the code as
+/// being in `src/lib.rs`.
+pub fn wrapped_code(source: &str) -> String {
+    wrap(source).0.code_string()
+}
+
+/// Converts compiler output for `wrapped_code(source)` into errors in terms of `source`. The output
+/// should contain one JSON message per line, as produced by `cargo --message-format=json` or
+/// `rustc --error-format=json`. Lines that aren't compiler messages are ignored.
+pub fn compilation_errors(source: &str, compiler_output: &str) -> Vec<CompilationError> {
+    let (code, code_info) = wrap(source);
+    compiler_output
+        .lines()
+        .filter_map(|line| json::parse(line).ok())
+        .filter_map(|json| CompilationError::opt_new(json, &code))
+        .map(|mut error| {
+            error.fill_lines(&code_info);
+            error
+        })
+        .collect()
+}
+
+/// Returns a plain-text rendering of `errors` that includes everything a frontend would use to
+/// display them. Unlike the compiler's own rendering, this only depends on evcxr's error mapping,
+/// so is suitable for comparing against expected output.
+pub fn format_errors(errors: &[CompilationError]) -> String {
+    let mut output = String::new();
+    for error in errors {
+        write!(output, "{}", error.level()).unwrap();
+        if let Some(code) = error.code() {
+            write!(output, "[{}]", code).unwrap();
+        }
+        writeln!(output, ": {}", error.message()).unwrap();
+        if error.is_from_generated_code() && !error.is_from_user_code() {
+            writeln!(output, "  (in generated code)").unwrap();
+        }
+        for spanned_message in error.spanned_messages() {
+            let location = match &spanned_message.span {
+                Some(span) => format!(
+                    "{}:{}-{}:{}",
+                    span.start_line, span.start_column, span.end_line, span.end_column
+                ),
+                None => "?".to_owned(),
+            };
+            let primary = if spanned_message.is_primary {
+                " (primary)"
+            } else {
+                ""
+            };
+            write!(output, "  --> {}{}", location, primary).unwrap();
+            if !spanned_message.label.is_empty() {
+                write!(output, ": {}", spanned_message.label).unwrap();
+            }
+            writeln!(output).unwrap();
+            for line in &spanned_message.lines {
+                writeln!(output, "    | {}", line).unwrap();
+            }
+        }
+        for help in error.help() {
+            writeln!(output, "  help: {}", help.replace('\n', "\n        ")).unwrap();
+        }
+        if let Some(hint) = error.evcxr_extra_hint() {
+            writeln!(output, "  hint: {}", hint).unwrap();
+        }
+    }
+    output
+}
+
+fn wrap(source: &str) -> (CodeBlock, UserCodeInfo) {
+    let (user_code, code_info) = CodeBlock::from_original_user_code(source);
+    let mut code = CodeBlock::new().generated("pub fn evcxr_user_code() {");
+    for segment in user_code.segments {
+        if !matches!(segment.kind, CodeKind::Command(_)) {
+            code = code.with_segment(segment);
+        }
+    }
+    (code.generated("}"), code_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compilation_errors;
+    use super::format_errors;
+    use super::wrapped_code;
+
+    #[test]
+    fn mismatched_types() {
+        let source = ":dep foo\nlet a: i32 = \"x\";";
+        assert_eq!(
+            wrapped_code(source),
+            "pub fn evcxr_user_code() {\nlet a: i32 = \"x\";\n}\n"
+        );
+        let compiler_output = r#"Compiling ctx v1.0.0
+{"reason":"compiler-message","message":{"message":"mismatched types","code":{"code":"E0308","explanation":null},"level":"error","spans":[{"file_name":"src/lib.rs","byte_start":40,"byte_end":43,"line_start":2,"line_end":2,"column_start":14,"column_end":17,"is_primary":true,"label":"expected `i32`, found `&str`"},{"file_name":"src/lib.rs","byte_start":34,"byte_end":37,"line_start":2,"line_end":2,"column_start":8,"column_end":11,"is_primary":false,"label":"expected due to this"}],"children":[],"rendered":""}}
+{"reason":"compiler-message","message":{"message":"aborting due to previous error","code":null,"level":"error","spans":[],"children":[],"rendered":""}}"#;
+        let errors = compilation_errors(source, compiler_output);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].is_from_user_code());
+        assert_eq!(
+            format_errors(&errors),
+            "error[E0308]: mismatched types\n  \
+             --> 2:14-2:17 (primary): expected `i32`, found `&str`\n    \
+             | let a: i32 = \"x\";\n  \
+             --> 2:8-2:11: expected due to this\n    \
+             | let a: i32 = \"x\";\n"
+        );
+    }
+
+    #[test]
+    fn error_in_generated_code() {
+        let errors = compilation_errors(
+            "42",
+            r#"{"message":"unclosed delimiter","code":null,"level":"error","spans":[{"file_name":"src/lib.rs","byte_start":0,"byte_end":3,"line_start":1,"line_end":1,"column_start":1,"column_end":4,"is_primary":true,"label":null}],"children":[]}"#,
+        );
+        assert_eq!(
+            format_errors(&errors),
+            "error: unclosed delimiter\n  (in generated code)\n  --> ? (primary)\n"
+        );
+    }
+}
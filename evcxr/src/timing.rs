@@ -0,0 +1,207 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eval_context::PhaseDetails;
+use crate::why_slow::EXECUTION_PHASE;
+use std::time::Duration;
+
+/// How long an evaluation took. Produced when timing is turned on with `:timing`, or for a single
+/// evaluation with `:time` or `:timeit`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timing {
+    /// Wall time for the whole evaluation, including anything we did besides compiling and running.
+    pub total: Duration,
+    /// Time spent compiling, including any retries needed to fix up the code.
+    pub compile: Duration,
+    /// Time spent running user code.
+    pub run: Duration,
+    /// Statistics for each run of the expression, if it was run repeatedly by `:timeit`.
+    pub repeated_runs: Option<RunStatistics>,
+}
+
+impl Timing {
+    /// Sets our durations based on the total time taken and the phases of evaluation.
+    pub(crate) fn set_phases(&mut self, total: Duration, phases: &[PhaseDetails]) {
+        self.total = total;
+        self.run = phases
+            .iter()
+            .filter(|phase| phase.name == EXECUTION_PHASE)
+            .map(|phase| phase.duration)
+            .sum();
+        self.compile = phases
+            .iter()
+            .filter(|phase| phase.name != EXECUTION_PHASE)
+            .map(|phase| phase.duration)
+            .sum();
+    }
+
+    pub(crate) fn from_phases(total: Duration, phases: &[PhaseDetails]) -> Timing {
+        let mut timing = Timing::default();
+        timing.set_phases(total, phases);
+        timing
+    }
+}
+
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Took {} (compile: {}, run: {})",
+            format_duration(self.total),
+            format_duration(self.compile),
+            format_duration(self.run)
+        )?;
+        if let Some(stats) = &self.repeated_runs {
+            write!(f, "\n{}", stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// Statistics about the time taken by each of several runs of the same code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunStatistics {
+    pub iterations: usize,
+    pub mean: Duration,
+    pub median: Duration,
+    /// Sample standard deviation. Zero if there was only one run.
+    pub stddev: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl RunStatistics {
+    /// Returns statistics for `samples`, or None if there aren't any.
+    pub(crate) fn from_samples(mut samples: Vec<Duration>) -> Option<RunStatistics> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let n = samples.len();
+        let secs: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / n as f64;
+        let variance = if n > 1 {
+            secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1) as f64
+        } else {
+            0.0
+        };
+        let median = if n % 2 == 0 {
+            (samples[n / 2 - 1] + samples[n / 2]) / 2
+        } else {
+            samples[n / 2]
+        };
+        Some(RunStatistics {
+            iterations: n,
+            mean: Duration::from_secs_f64(mean),
+            median,
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+            min: samples[0],
+            max: samples[n - 1],
+        })
+    }
+
+    /// Parses samples reported by the subprocess as comma separated nanoseconds.
+    pub(crate) fn parse(samples: &str) -> Option<RunStatistics> {
+        let samples = samples
+            .split(',')
+            .map(|s| s.parse().ok().map(Duration::from_nanos))
+            .collect::<Option<Vec<_>>>()?;
+        RunStatistics::from_samples(samples)
+    }
+}
+
+impl std::fmt::Display for RunStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} runs: mean {}, median {}, stddev {}, min {}, max {}",
+            self.iterations,
+            format_duration(self.mean),
+            format_duration(self.median),
+            format_duration(self.stddev),
+            format_duration(self.min),
+            format_duration(self.max)
+        )
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos < 1_000 {
+        format!("{}ns", nanos)
+    } else if nanos < 1_000_000 {
+        format!("{:.1}µs", nanos as f64 / 1e3)
+    } else if nanos < 1_000_000_000 {
+        format!("{:.1}ms", nanos as f64 / 1e6)
+    } else {
+        format!("{:.2}s", nanos as f64 / 1e9)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_duration;
+    use super::RunStatistics;
+    use super::Timing;
+    use crate::eval_context::PhaseDetails;
+    use std::time::Duration;
+
+    #[test]
+    fn test_statistics() {
+        let stats = RunStatistics::parse("4000,1000,3000,2000").unwrap();
+        assert_eq!(stats.iterations, 4);
+        assert_eq!(stats.mean, Duration::from_nanos(2500));
+        assert_eq!(stats.median, Duration::from_nanos(2500));
+        assert_eq!(stats.min, Duration::from_nanos(1000));
+        assert_eq!(stats.max, Duration::from_nanos(4000));
+        // Sample standard deviation of 1, 2, 3, 4 is sqrt(5/3).
+        assert_eq!(stats.stddev.as_nanos(), 1290);
+
+        let single = RunStatistics::parse("7").unwrap();
+        assert_eq!(single.median, Duration::from_nanos(7));
+        assert_eq!(single.stddev, Duration::ZERO);
+
+        assert_eq!(RunStatistics::parse(""), None);
+        assert_eq!(RunStatistics::parse("1,x"), None);
+    }
+
+    #[test]
+    fn test_timing_from_phases() {
+        let phase = |name: &str, ms| PhaseDetails {
+            name: name.to_owned(),
+            duration: Duration::from_millis(ms),
+        };
+        let timing = Timing::from_phases(
+            Duration::from_millis(1500),
+            &[
+                phase("Fix variable types", 400),
+                phase("Final compile", 900),
+                phase("Execution", 150),
+            ],
+        );
+        assert_eq!(timing.compile, Duration::from_millis(1300));
+        assert_eq!(timing.run, Duration::from_millis(150));
+        assert_eq!(
+            timing.to_string(),
+            "Took 1.50s (compile: 1.30s, run: 150.0ms)"
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_nanos(999)), "999ns");
+        assert_eq!(format_duration(Duration::from_nanos(1500)), "1.5µs");
+        assert_eq!(format_duration(Duration::from_micros(2500)), "2.5ms");
+    }
+}
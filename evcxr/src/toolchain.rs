@@ -0,0 +1,273 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Locating and checking the cargo and rustc that we build with. Without a working toolchain,
+//! nothing works, so we check up-front and produce an error that explains how to fix things,
+//! rather than letting the first evaluation fail with whatever cargo or the OS said.
+
+use crate::errors::Error;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The tools that we run in order to build user code.
+#[derive(Clone, Debug)]
+pub(crate) struct ToolPaths {
+    pub(crate) cargo: PathBuf,
+    /// If set, passed to cargo via `RUSTC`. Otherwise cargo picks rustc in its usual way.
+    pub(crate) rustc: Option<PathBuf>,
+}
+
+impl Default for ToolPaths {
+    fn default() -> Self {
+        ToolPaths {
+            cargo: PathBuf::from("cargo"),
+            rustc: None,
+        }
+    }
+}
+
+impl ToolPaths {
+    /// Finds cargo and rustc, using the supplied paths if given, and checks that they work.
+    pub(crate) fn locate(
+        cargo: Option<&Path>,
+        rustc: Option<&Path>,
+    ) -> Result<ToolPaths, ToolchainError> {
+        let bundled = if cargo.is_none() {
+            bundled_toolchain_dir()
+        } else {
+            None
+        };
+        let tool_paths = ToolPaths {
+            cargo: cargo
+                .map(Path::to_owned)
+                .or_else(|| bundled.as_ref().map(|dir| dir.join("cargo")))
+                .unwrap_or_else(|| PathBuf::from("cargo")),
+            rustc: rustc.map(Path::to_owned).or_else(|| {
+                bundled
+                    .map(|dir| dir.join("rustc"))
+                    .filter(|rustc| rustc.exists())
+            }),
+        };
+        check_tool("cargo", &tool_paths.cargo, cargo.is_some())?;
+        check_tool("rustc", tool_paths.rustc_path(), rustc.is_some())?;
+        Ok(tool_paths)
+    }
+
+    pub(crate) fn rustc_path(&self) -> &Path {
+        self.rustc.as_deref().unwrap_or_else(|| Path::new("rustc"))
+    }
+
+    pub(crate) fn rustc_command(&self) -> Command {
+        Command::new(self.rustc_path())
+    }
+
+    /// Returns a command that runs rustfmt from the same directory as cargo if it's there, e.g.
+    /// because evcxr is distributed with a toolchain, otherwise whichever rustfmt is on `PATH`.
+    pub(crate) fn rustfmt_command(&self) -> Command {
+        let rustfmt = self
+            .cargo
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| dir.join("rustfmt"))
+            .filter(|rustfmt| rustfmt.exists())
+            .unwrap_or_else(|| PathBuf::from("rustfmt"));
+        Command::new(rustfmt)
+    }
+}
+
+/// Returns the directory containing our executable if cargo isn't on `PATH`, but is in that
+/// directory, as happens when evcxr is distributed together with a toolchain. We use the tools
+/// from there by path, rather than adding the directory to `PATH`, since changing the environment
+/// would affect everything else in the process.
+fn bundled_toolchain_dir() -> Option<PathBuf> {
+    if which::which("cargo").is_ok() {
+        return None;
+    }
+    let bin_dir = std::env::current_exe().ok()?.parent()?.to_owned();
+    if bin_dir.join("cargo").exists() {
+        Some(bin_dir)
+    } else {
+        None
+    }
+}
+
+/// Returned when cargo or rustc is missing or doesn't work.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolchainError {
+    /// The tool that had a problem, "cargo" or "rustc".
+    pub tool: String,
+    /// The path that we tried to run.
+    pub path: PathBuf,
+    pub kind: ToolchainErrorKind,
+    /// Any output from the tool that might explain what went wrong.
+    pub detail: String,
+    /// Whether `path` was explicitly configured, as opposed to being looked up on `PATH`.
+    pub explicitly_configured: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolchainErrorKind {
+    /// The tool couldn't be found.
+    NotFound,
+    /// The tool is a rustup proxy, but rustup has no toolchain to run.
+    NoDefaultToolchain,
+    /// The tool ran, but failed.
+    Broken,
+}
+
+impl ToolchainError {
+    /// Returns instructions for fixing the problem on the current platform.
+    pub fn guidance(&self) -> String {
+        if self.explicitly_configured {
+            return format!(
+                "Check that the configured {} path points to a working {} executable.",
+                self.tool, self.tool
+            );
+        }
+        match self.kind {
+            ToolchainErrorKind::NotFound => {
+                if which::which("rustup").is_ok() {
+                    "rustup is installed, but its bin directory isn't on your PATH. Add \
+                     ~/.cargo/bin (or %USERPROFILE%\\.cargo\\bin on Windows) to PATH, then \
+                     restart evcxr."
+                        .to_owned()
+                } else {
+                    format!(
+                        "{} If you manage Rust some other way (e.g. with Nix or Bazel), put \
+                         cargo and rustc on your PATH or configure their paths explicitly.",
+                        rustup_install_instructions()
+                    )
+                }
+            }
+            ToolchainErrorKind::NoDefaultToolchain => {
+                "rustup doesn't have a default toolchain. Run `rustup default stable`.".to_owned()
+            }
+            ToolchainErrorKind::Broken => format!(
+                "Try running `{} --version` yourself. If you installed Rust with rustup, \
+                 `rustup update` may fix things.",
+                self.path.display()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ToolchainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ToolchainErrorKind::NotFound => {
+                write!(f, "Couldn't find {} ({})", self.tool, self.path.display())?
+            }
+            ToolchainErrorKind::NoDefaultToolchain => write!(
+                f,
+                "Couldn't run {}: no Rust toolchain is installed",
+                self.tool
+            )?,
+            ToolchainErrorKind::Broken => {
+                write!(f, "{} ({}) isn't working", self.tool, self.path.display())?
+            }
+        }
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        write!(f, "\n{}", self.guidance())
+    }
+}
+
+fn rustup_install_instructions() -> &'static str {
+    if cfg!(windows) {
+        "Install Rust by downloading and running rustup-init.exe from https://rustup.rs, then \
+         restart evcxr."
+    } else if cfg!(target_os = "macos") {
+        "Install Rust by running `curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh` \
+         (or `brew install rustup-init && rustup-init`), then restart your shell."
+    } else {
+        "Install Rust by running `curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh`, \
+         then restart your shell. Rust from your distribution's package manager also works, \
+         provided it includes the rust-src component."
+    }
+}
+
+fn check_tool(tool: &str, path: &Path, explicitly_configured: bool) -> Result<(), ToolchainError> {
+    let error = |kind, detail: String| ToolchainError {
+        tool: tool.to_owned(),
+        path: path.to_owned(),
+        kind,
+        detail,
+        explicitly_configured,
+    };
+    let output = match Command::new(path).arg("--version").output() {
+        Ok(output) => output,
+        Err(io_error) if io_error.kind() == std::io::ErrorKind::NotFound => {
+            return Err(error(ToolchainErrorKind::NotFound, String::new()));
+        }
+        Err(io_error) => return Err(error(ToolchainErrorKind::Broken, io_error.to_string())),
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    Err(error(classify_failure(&stderr), stderr))
+}
+
+fn classify_failure(stderr: &str) -> ToolchainErrorKind {
+    if stderr.contains("no default toolchain") || stderr.contains("no override and no default") {
+        ToolchainErrorKind::NoDefaultToolchain
+    } else {
+        ToolchainErrorKind::Broken
+    }
+}
+
+impl From<ToolchainError> for Error {
+    fn from(error: ToolchainError) -> Self {
+        Error::ToolchainUnavailable(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::classify_failure;
+    use super::ToolPaths;
+    use super::ToolchainErrorKind;
+    use std::path::Path;
+
+    #[test]
+    fn test_missing_tool() {
+        let error = ToolPaths::locate(Some(Path::new("/does/not/exist/cargo")), None).unwrap_err();
+        assert_eq!(error.tool, "cargo");
+        assert_eq!(error.kind, ToolchainErrorKind::NotFound);
+        assert!(error.explicitly_configured);
+        assert!(error
+            .to_string()
+            .contains("Check that the configured cargo path"));
+    }
+
+    #[test]
+    fn test_classify_failure() {
+        assert_eq!(
+            classify_failure(
+                "error: rustup could not choose a version of cargo to run, because one wasn't \
+                 specified explicitly, and no default is configured.\n\
+                 help: run 'rustup default stable' to download the latest stable release of Rust \
+                 and set it as your default toolchain.\nerror: no default toolchain configured"
+            ),
+            ToolchainErrorKind::NoDefaultToolchain
+        );
+        assert_eq!(
+            classify_failure("segmentation fault"),
+            ToolchainErrorKind::Broken
+        );
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `:why_slow`, which looks at what happened during the last evaluation and suggests
+//! what might make it faster. We don't have timings for what happens inside rustc, e.g. linking, so
+//! rather than guessing at how long each cause took, we rank them by how long the phases that they
+//! slow down took.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Name of the phase in which user code is run. All other phases are compilation.
+pub(crate) const EXECUTION_PHASE: &str = "Execution";
+const FINAL_COMPILE_PHASE: &str = "Final compile";
+/// Beyond this many items, recompiling them all on each evaluation starts to be noticeable.
+const MANY_ITEMS: usize = 30;
+
+/// What we know about an evaluation after it has completed.
+#[derive(Debug, Clone)]
+pub(crate) struct EvalProfile {
+    pub(crate) phases: Vec<(String, Duration)>,
+    /// Names of crates that cargo rebuilt, other than the crate containing user code.
+    pub(crate) rebuilt_crates: Vec<String>,
+    pub(crate) linker: String,
+    pub(crate) sccache: bool,
+    pub(crate) opt_level: String,
+    /// Number of items (functions, types etc) that get recompiled on every evaluation.
+    pub(crate) item_count: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Finding {
+    pub(crate) cause: String,
+    pub(crate) suggestion: String,
+}
+
+impl EvalProfile {
+    fn execution_time(&self) -> Duration {
+        self.phases
+            .iter()
+            .filter(|(name, _)| name == EXECUTION_PHASE)
+            .map(|(_, duration)| *duration)
+            .sum()
+    }
+
+    fn compile_time(&self) -> Duration {
+        self.phases
+            .iter()
+            .filter(|(name, _)| name != EXECUTION_PHASE)
+            .map(|(_, duration)| *duration)
+            .sum()
+    }
+
+    /// Returns the phases in which we compiled, found errors that we were able to fix (e.g. by
+    /// adjusting variable types), then compiled again.
+    fn retry_phases(&self) -> impl Iterator<Item = &(String, Duration)> {
+        self.phases
+            .iter()
+            .filter(|(name, _)| name != EXECUTION_PHASE && name != FINAL_COMPILE_PHASE)
+    }
+
+    /// Returns likely causes of slowness, most significant first. Each cause is ranked by how long
+    /// the phases that it slows down took, i.e. compilation, the extra compilations needed to fix
+    /// up the code, or execution. Causes that slow down the same phases stay in the order that
+    /// they're checked here, which is roughly how much they usually matter.
+    pub(crate) fn findings(&self) -> Vec<Finding> {
+        let compile = self.compile_time();
+        let execution = self.execution_time();
+        let mut findings = Vec::new();
+        if !self.rebuilt_crates.is_empty() {
+            findings.push((
+                compile,
+                Finding {
+                    cause: format!(
+                        "{} {} rebuilt: {}",
+                        self.rebuilt_crates.len(),
+                        if self.rebuilt_crates.len() == 1 {
+                            "dependency was"
+                        } else {
+                            "dependencies were"
+                        },
+                        self.rebuilt_crates.join(", ")
+                    ),
+                    suggestion: if self.sccache {
+                        "Dependencies are rebuilt when they're first added, or when the opt level, \
+                         toolchain or their features change. Avoid changing these mid-session."
+                            .to_owned()
+                    } else {
+                        "Run `:sccache 1` so that dependency builds are cached across sessions."
+                            .to_owned()
+                    },
+                },
+            ));
+        }
+        let retry_time: Duration = self.retry_phases().map(|(_, duration)| *duration).sum();
+        let retries: Vec<&str> = self.retry_phases().map(|(name, _)| name.as_str()).collect();
+        if !retries.is_empty() {
+            findings.push((
+                retry_time,
+                Finding {
+                    cause: format!(
+                        "{} extra {} needed to fix up the code ({})",
+                        retries.len(),
+                        if retries.len() == 1 {
+                            "compilation was"
+                        } else {
+                            "compilations were"
+                        },
+                        retries.join(", ")
+                    ),
+                    suggestion: "Give new variables explicit types, so that evcxr doesn't need to \
+                                 compile to find them out."
+                        .to_owned(),
+                },
+            ));
+        }
+        if self.item_count > MANY_ITEMS {
+            findings.push((
+                compile,
+                Finding {
+                    cause: format!(
+                        "{} items are recompiled on every evaluation",
+                        self.item_count
+                    ),
+                    suggestion: "Remove items you no longer need with `:clear`, or move stable \
+                                 code into a crate and load it with `:dev_crate`."
+                        .to_owned(),
+                },
+            ));
+        }
+        if self.opt_level != "0" && compile > execution * 2 && compile > Duration::from_secs(1) {
+            findings.push((
+                compile,
+                Finding {
+                    cause: format!("Optimizing generated code (opt-level {})", self.opt_level),
+                    suggestion: "Your code spends longer compiling than running. `:opt 0` will \
+                                 compile faster."
+                        .to_owned(),
+                },
+            ));
+        }
+        if self.linker == "system" && compile > Duration::from_secs(1) {
+            findings.push((
+                compile,
+                Finding {
+                    cause: "Linking with the system linker".to_owned(),
+                    suggestion: "Install mold or lld, then run `:linker mold` or `:linker lld`."
+                        .to_owned(),
+                },
+            ));
+        }
+        if execution > Duration::from_millis(100) {
+            findings.push((
+                execution,
+                Finding {
+                    cause: "Running your code".to_owned(),
+                    suggestion: if self.opt_level == "0" || self.opt_level == "1" {
+                        "`:opt 2` makes the generated code faster, at the cost of compile time."
+                            .to_owned()
+                    } else {
+                        "The code itself is slow. Consider a smaller input while experimenting."
+                            .to_owned()
+                    },
+                },
+            ));
+        }
+        // A stable sort, so that causes with the same phase time keep their order.
+        findings.sort_by(|(a, _), (b, _)| b.cmp(a));
+        findings.into_iter().map(|(_, finding)| finding).collect()
+    }
+
+    pub(crate) fn report(&self) -> String {
+        let compile = self.compile_time();
+        let execution = self.execution_time();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Last evaluation took {:.2}s (compilation {:.2}s, execution {:.2}s)",
+            (compile + execution).as_secs_f64(),
+            compile.as_secs_f64(),
+            execution.as_secs_f64()
+        );
+        let findings = self.findings();
+        if findings.is_empty() {
+            let _ = write!(out, "Nothing stands out as slow.");
+            return out;
+        }
+        let _ = writeln!(out, "Likely causes, most significant first:");
+        for (index, finding) in findings.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}. {}\n   {}",
+                index + 1,
+                finding.cause,
+                finding.suggestion
+            );
+        }
+        out.truncate(out.trim_end().len());
+        out
+    }
+}
+
+/// Returns the names of crates that cargo reports having rebuilt, other than `exclude`, given
+/// output from a cargo command run with `--message-format=json`.
+pub(crate) fn rebuilt_crates(cargo_stdout: &[u8], exclude: &str) -> Vec<String> {
+    String::from_utf8_lossy(cargo_stdout)
+        .lines()
+        .filter_map(|line| json::parse(line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-artifact" && message["fresh"].as_bool() == Some(false)
+        })
+        .filter_map(|message| message["target"]["name"].as_str().map(str::to_owned))
+        .filter(|name| name != exclude)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rebuilt_crates;
+    use super::EvalProfile;
+    use std::time::Duration;
+
+    fn profile(phases: &[(&str, u64)]) -> EvalProfile {
+        EvalProfile {
+            phases: phases
+                .iter()
+                .map(|(name, millis)| ((*name).to_owned(), Duration::from_millis(*millis)))
+                .collect(),
+            rebuilt_crates: Vec::new(),
+            linker: "mold".to_owned(),
+            sccache: false,
+            opt_level: "2".to_owned(),
+            item_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_findings_are_ranked() {
+        let mut p = profile(&[
+            ("Variable types", 1000),
+            ("Final compile", 4000),
+            ("Execution", 200),
+        ]);
+        p.rebuilt_crates = vec!["regex".to_owned()];
+        let causes: Vec<String> = p.findings().into_iter().map(|f| f.cause).collect();
+        assert_eq!(
+            causes,
+            vec![
+                "1 dependency was rebuilt: regex".to_owned(),
+                "Optimizing generated code (opt-level 2)".to_owned(),
+                "1 extra compilation was needed to fix up the code (Variable types)".to_owned(),
+                "Running your code".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_slow_execution_is_ranked_first() {
+        let mut p = profile(&[("Final compile", 1500), ("Execution", 5000)]);
+        p.linker = "system".to_owned();
+        p.item_count = 40;
+        let causes: Vec<String> = p.findings().into_iter().map(|f| f.cause).collect();
+        assert_eq!(
+            causes,
+            vec![
+                "Running your code".to_owned(),
+                "40 items are recompiled on every evaluation".to_owned(),
+                "Linking with the system linker".to_owned(),
+            ]
+        );
+        assert!(!p.report().contains('~'));
+    }
+
+    #[test]
+    fn test_fast_eval() {
+        let p = profile(&[("Final compile", 300), ("Execution", 1)]);
+        assert!(p.findings().is_empty());
+        assert!(p.report().ends_with("Nothing stands out as slow."));
+    }
+
+    #[test]
+    fn test_rebuilt_crates() {
+        let stdout = br#"{"reason":"compiler-artifact","fresh":false,"target":{"name":"regex"}}
+{"reason":"compiler-artifact","fresh":true,"target":{"name":"memchr"}}
+{"reason":"compiler-artifact","fresh":false,"target":{"name":"ctx"}}"#;
+        assert_eq!(rebuilt_crates(stdout, "ctx"), vec!["regex".to_owned()]);
+    }
+}
@@ -13,9 +13,12 @@
 // limitations under the License.
 
 use evcxr::CommandContext;
+use evcxr::Content;
 use evcxr::Error;
+use evcxr::EvalCallbacks;
 use evcxr::EvalContext;
 use evcxr::EvalContextOutputs;
+use evcxr::TestOutcome;
 use once_cell::sync::OnceCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
@@ -26,7 +29,7 @@ use std::sync::Mutex;
 use tempfile;
 
 #[track_caller]
-fn eval_and_unwrap(ctxt: &mut CommandContext, code: &str) -> HashMap<String, String> {
+fn eval_and_unwrap(ctxt: &mut CommandContext, code: &str) -> HashMap<String, Content> {
     match ctxt.execute(code) {
         Ok(output) => output.content_by_mime_type,
         Err(err) => {
@@ -235,6 +238,136 @@ fn printing() {
     assert_eq!(outputs.stderr.recv(), Ok("Another stderr line".to_owned()));
 }
 
+#[test]
+fn typed_content() {
+    let mut e = new_context();
+    let outputs = eval_and_unwrap(
+        &mut e,
+        r#"
+        println!("EVCXR_BEGIN_CONTENT image/png\nAAEC/w==\nEVCXR_END_CONTENT");
+        println!("EVCXR_BEGIN_CONTENT application/json\n{\"a\": 1}\nEVCXR_END_CONTENT");
+        println!("EVCXR_BEGIN_CONTENT text/html\n<b>hi</b>\nEVCXR_END_CONTENT");
+        "#,
+    );
+    assert_eq!(outputs["image/png"], Content::Binary(vec![0, 1, 2, 255]));
+    assert_eq!(
+        outputs["application/json"],
+        Content::Json(json::object! {"a" => 1})
+    );
+    assert_eq!(outputs["text/html"], Content::Html("<b>hi</b>".to_owned()));
+}
+
+#[test]
+fn output_limit() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    // About 100 bytes.
+    e.execute(":output_limit 0.0001").unwrap();
+    e.execute(r#"for i in 0..100 { println!("Line {:04}", i); }"#)
+        .unwrap();
+    let lines: Vec<String> = outputs.stdout.try_iter().collect();
+    assert_eq!(lines.len(), 11);
+    assert_eq!(lines[9], "Line 0009");
+    assert!(lines[10].starts_with("Output exceeded"));
+
+    // The limit applies to each evaluation separately.
+    eval!(e, println!("Another line"));
+    assert_eq!(outputs.stdout.recv(), Ok("Another line".to_owned()));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn read_stdin() {
+    let mut e = new_context();
+    let outputs = e
+        .execute_with_callbacks(
+            r#"
+            use std::io::Write;
+            print!("Hello ");
+            std::io::stdout().flush().unwrap();
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).unwrap();
+            line"#,
+            &mut EvalCallbacks {
+                input_reader: &|prompt, _| format!("{}world", prompt),
+                ..EvalCallbacks::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(outputs.get("text/plain"), Some("\"Hello world\\n\""));
+}
+
+#[test]
+fn display_updates_and_clear_output() {
+    let mut e = new_context();
+    let updates = std::cell::RefCell::new(Vec::new());
+    let clears = std::cell::RefCell::new(Vec::new());
+    let outputs = e
+        .execute_with_callbacks(
+            r##"
+            println!("EVCXR_BEGIN_CONTENT text/html\n<b>cleared</b>\nEVCXR_END_CONTENT");
+            println!("EVCXR_CLEAR_OUTPUT true");
+            println!("{}", r#"EVCXR_DISPLAY d1 {"text/plain":"0%"}"#);
+            println!("{}", r#"EVCXR_UPDATE_DISPLAY d1 {"text/plain":"100%"}"#);
+            42"##,
+            &mut EvalCallbacks {
+                display_sender: &|update| {
+                    updates.borrow_mut().push((
+                        update.display_id.clone(),
+                        update.is_update,
+                        update.get("text/plain").unwrap().to_owned(),
+                    ))
+                },
+                output_clearer: &|wait| clears.borrow_mut().push(wait),
+                ..EvalCallbacks::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(outputs.get("text/plain"), Some("42"));
+    // Content displayed before the output was cleared is dropped.
+    assert_eq!(outputs.get("text/html"), None);
+    assert_eq!(
+        updates.into_inner(),
+        vec![
+            ("d1".to_owned(), false, "0%".to_owned()),
+            ("d1".to_owned(), true, "100%".to_owned()),
+        ]
+    );
+    assert_eq!(clears.into_inner(), vec![true]);
+}
+
+#[test]
+fn background_jobs() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    let started = e
+        .execute(
+            r#":bg { std::thread::sleep(std::time::Duration::from_millis(100)); println!("working"); }"#,
+        )
+        .unwrap();
+    assert_eq!(started.get("text/plain"), Some("Started job 1"));
+    // Output arrives even though we're not evaluating anything.
+    assert_eq!(outputs.stdout.recv(), Ok("[job 1] working".to_owned()));
+    assert!(outputs
+        .stdout
+        .recv()
+        .unwrap()
+        .starts_with("[job 1] done after"));
+
+    e.execute(
+        ":bg while !evcxr_job.is_cancelled() { \
+         std::thread::sleep(std::time::Duration::from_millis(10)); }",
+    )
+    .unwrap();
+    let jobs = e.execute(":jobs").unwrap();
+    assert!(jobs.get("text/plain").unwrap().starts_with("2    running"));
+    e.execute(":kill 2").unwrap();
+    assert!(outputs
+        .stdout
+        .recv()
+        .unwrap()
+        .starts_with("[job 2] cancelled after"));
+    assert!(e.execute(":kill 2").is_err());
+}
+
 #[test]
 fn rc_refcell_etc() {
     let mut e = new_context();
@@ -346,9 +479,9 @@ fn tls_implementing_drop() {
     eval!(e, init_foo(););
 }
 
-fn text_plain(content: &str) -> HashMap<String, String> {
+fn text_plain(content: &str) -> HashMap<String, Content> {
     let mut map = HashMap::new();
-    map.insert("text/plain".to_owned(), content.to_owned());
+    map.insert("text/plain".to_owned(), Content::Text(content.to_owned()));
     map
 }
 
@@ -424,6 +557,269 @@ fn crate_deps() {
     assert_eq!(outputs.content_by_mime_type, text_plain("42"));
 }
 
+#[test]
+fn auto_use() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    assert!(e.execute("HashMap::<i32, i32>::new().len()").is_err());
+    eval_and_unwrap(&mut e, ":auto_use on");
+    assert_eq!(eval!(e, HashMap::<i32, i32>::new().len()), text_plain("0"));
+    assert_eq!(
+        outputs.stderr.try_recv().as_deref(),
+        Ok("Added `use std::collections::HashMap;` (:auto_use is on)")
+    );
+    // The import is kept, like one that the user wrote.
+    eval_and_unwrap(&mut e, ":auto_use off");
+    assert_eq!(eval!(e, HashMap::<u8, u8>::new().len()), text_plain("0"));
+}
+
+#[test]
+fn deps_command() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r42() -> i32 {42}").unwrap();
+    e.execute(&crate1.dep_command("")).unwrap();
+    let tree = e.execute(":deps tree").unwrap();
+    let tree = tree.get("text/plain").unwrap();
+    assert!(tree.contains("crate1 v0.0.1"));
+    assert!(tree.trim_end().ends_with("in total"));
+    let features = e.execute(":deps features crate1").unwrap();
+    assert_eq!(
+        features.get("text/plain").unwrap(),
+        "crate1 0.0.1\nEnabled: (none)\nAvailable: (none)\n"
+    );
+    assert!(e
+        .execute(":deps features crate2")
+        .unwrap_err()
+        .to_string()
+        .contains("isn't among the dependencies"));
+}
+
+#[test]
+fn failed_dep_is_not_retried_without_force() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r42() -> i32 {42}").unwrap();
+    let manifest_path = crate1.tempdir.path().join("Cargo.toml");
+    let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+    std::fs::remove_file(&manifest_path).unwrap();
+    let dep_command = crate1.dep_command("");
+    let first_error = e.execute(&dep_command).unwrap_err().to_string();
+    assert!(!first_error.contains("already failed"));
+
+    // Even though the crate is now fixed, we report the earlier failure.
+    std::fs::write(&manifest_path, manifest).unwrap();
+    let second_error = e.execute(&dep_command).unwrap_err().to_string();
+    assert!(second_error.contains(first_error.trim()));
+    assert!(second_error.contains(":dep --force crate1 ="));
+
+    let to_run = dep_command.replacen(":dep", ":dep --force", 1) + "\ncrate1::r42()";
+    let outputs = e.execute(&to_run).unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+}
+
+#[test]
+fn scoped_dep() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r40() -> i32 {40}").unwrap();
+    let to_run = crate1.dep_command("").replacen(":dep", ":dep --scoped", 1)
+        + "\nuse crate1::r40;\nfn r42() -> i32 { r40() + 2 }\nlet a = 1;\nr42()";
+    let outputs_of_cell = e.execute(&to_run).unwrap();
+    assert_eq!(outputs_of_cell.content_by_mime_type, text_plain("42"));
+    assert_eq!(
+        outputs.stdout.recv().unwrap(),
+        "Dropped r40, r42, since they refer to scoped dependencies: crate1"
+    );
+    // The dependency and everything that used it are gone, but other variables remain.
+    assert!(e
+        .execute(":dep_flags crate1 --cfg crate1_flag")
+        .unwrap_err()
+        .to_string()
+        .contains("isn't a dependency"));
+    assert!(e.execute("crate1::r40()").is_err());
+    assert_eq!(eval!(e, a + 1), text_plain("2"));
+}
+
+#[test]
+fn dep_flags() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new(
+        "crate1",
+        "pub fn flags() -> (bool, bool) { (cfg!(crate1_flag), cfg!(debug_assertions)) }",
+    )
+    .unwrap();
+    assert!(e.execute(":dep_flags crate1 --cfg crate1_flag").is_err());
+    e.execute(&crate1.dep_command("")).unwrap();
+    let outputs = e
+        .execute(":dep_flags crate1 --cfg crate1_flag -C debug-assertions=off\ncrate1::flags()")
+        .unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("(true, false)"));
+    // Debug assertions were only turned off for the dependency.
+    assert_eq!(eval!(e, cfg!(debug_assertions)), text_plain("true"));
+    assert_eq!(
+        e.execute(":dep_flags").unwrap().content_by_mime_type,
+        text_plain("crate1: --cfg crate1_flag -C debug-assertions=off\n")
+    );
+
+    let outputs = e.execute(":dep_flags crate1\ncrate1::flags()").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("(false, true)"));
+}
+
+#[test]
+fn time_and_timeit() {
+    let (mut e, _) = new_command_context_and_outputs();
+    // Nothing has been defined yet, so this checks that `:timeit` doesn't depend on variables.
+    let outputs = e.execute(":timeit -n 3 1 + 1").unwrap();
+    assert_eq!(outputs.timing.unwrap().repeated_runs.unwrap().iterations, 3);
+
+    let outputs = e.execute(":time\n40 + 2").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    let timing = outputs.timing.unwrap();
+    assert!(timing.compile > std::time::Duration::ZERO);
+    assert!(timing.repeated_runs.is_none());
+
+    // Timing is only for the cell containing `:time`.
+    assert!(e.execute("1").unwrap().timing.is_none());
+
+    e.execute("let v: Vec<u64> = (0..100).collect();").unwrap();
+    let outputs = e.execute(":timeit -n 25 v.iter().sum::<u64>()").unwrap();
+    let stats = outputs.timing.unwrap().repeated_runs.unwrap();
+    assert_eq!(stats.iterations, 25);
+    assert!(stats.min <= stats.median && stats.median <= stats.max);
+    // The variable was only borrowed, so is still usable.
+    assert_eq!(
+        e.execute("v.len()").unwrap().content_by_mime_type,
+        text_plain("100")
+    );
+    assert!(e.execute(":timeit -n 0 1").is_err());
+}
+
+#[test]
+fn memory_tracking() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute("1").unwrap().memory.is_none());
+    e.execute(":mem on").unwrap();
+    let outputs = e.execute("let v: Vec<u64> = (0..1000).collect();").unwrap();
+    let memory = outputs.memory.unwrap();
+    assert!(memory.allocations >= 1);
+    assert!(memory.bytes_allocated >= 8000);
+    // The vector is kept in a variable, so hasn't been freed.
+    assert!(memory.bytes_leaked >= 8000);
+
+    let memory = e.execute("drop(v);").unwrap().memory.unwrap();
+    assert_eq!(memory.bytes_leaked, 0);
+
+    // Growing a vector reallocates it repeatedly, but only the final capacity of 8192 bytes should
+    // count, not the sum of every size it passed through.
+    let outputs = e
+        .execute("let mut w = Vec::new(); for i in 0..1024u64 { w.push(i); }")
+        .unwrap();
+    let memory = outputs.memory.unwrap();
+    assert!(memory.bytes_allocated >= 8192);
+    assert!(memory.bytes_allocated < 12000);
+
+    e.execute(":mem off").unwrap();
+    assert!(e.execute("1").unwrap().memory.is_none());
+    assert!(e.execute(":mem maybe").is_err());
+}
+
+#[test]
+fn number_format() {
+    let (mut e, _) = new_command_context_and_outputs();
+    e.execute(":numfmt sep=, sig=3").unwrap();
+    assert_eq!(
+        eval_and_unwrap(&mut e, "vec![1234567.0f64, 3.14159]"),
+        text_plain("[1,230,000, 3.14]")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, "(\"12345\", 12345)"),
+        text_plain("(\"12345\", 12,345)")
+    );
+    e.execute(":numfmt off").unwrap();
+    assert_eq!(eval_and_unwrap(&mut e, "12345"), text_plain("12345"));
+    assert!(e.execute(":numfmt sig=none digits=2").is_err());
+}
+
+#[test]
+fn max_output_lines() {
+    let (mut e, _) = new_command_context_and_outputs();
+    e.execute(":max_output_lines 2").unwrap();
+    let outputs = eval_and_unwrap(&mut e, r#"println!("a\nb\nc"); "x\ny\nz".to_owned()"#);
+    assert_eq!(
+        outputs.get("text/plain").and_then(Content::as_text),
+        Some("\"x\\ny\\nz\"")
+    );
+    e.execute(":fmt {:#?}").unwrap();
+    let outputs = eval_and_unwrap(&mut e, "vec![1, 2, 3]");
+    assert_eq!(
+        outputs.get("text/plain").and_then(Content::as_text),
+        Some("[\n    1,\n... 3 more lines. Use :max_output_lines to change the limit.")
+    );
+    assert!(outputs
+        .get("text/html")
+        .and_then(Content::as_text)
+        .unwrap()
+        .contains("<summary>Show 3 more lines</summary>"));
+    e.execute(":max_output_lines off").unwrap();
+    assert_eq!(
+        eval_and_unwrap(&mut e, "vec![1, 2]"),
+        text_plain("[\n    1,\n    2,\n]")
+    );
+    assert!(e.execute(":max_output_lines 0").is_err());
+}
+
+#[test]
+fn custom_formatters() {
+    let mut e = new_context();
+    eval!(e,
+        struct Point { x: i32, y: i32 }
+        evcxr::register_formatter::<Point>(|p| format!("({}, {})", p.x, p.y));
+    );
+    assert_eq!(eval!(e, Point { x: 1, y: 2 }), text_plain("(1, 2)"));
+    eval!(e, let p = Point { x: 3, y: 4 };);
+    // Place expressions use the formatter too.
+    assert_eq!(eval!(e, p), text_plain("(3, 4)"));
+    // Types without a formatter are displayed as before.
+    assert_eq!(eval!(e, 42), text_plain("42"));
+    eval!(e, evcxr::register_formatter::<i32>(|i| format!("int {}", i)););
+    assert_eq!(eval!(e, 42), text_plain("int 42"));
+    // Values that borrow can't be looked up, so are displayed as before.
+    eval!(e, let s = String::from("borrowed"););
+    assert_eq!(eval!(e, s.as_str()), text_plain("\"borrowed\""));
+}
+
+#[test]
+fn reeval_edited_cell() {
+    let (mut e, _) = EvalContext::new_for_testing();
+    let first = e
+        .reeval_edited(
+            "c1",
+            "struct P { x: i32 }\nfn f() -> i32 { 1 }\nlet a = f();\nlet b = 5;",
+        )
+        .unwrap();
+    assert_eq!(first.changed_items, vec!["P", "f"]);
+    assert_eq!(first.rerun_variables, vec!["a", "b"]);
+    e.reeval_edited("c2", "let d = a * 2;").unwrap();
+    e.eval("let p = P { x: 1 };").unwrap();
+
+    let edited = e
+        .reeval_edited(
+            "c1",
+            "struct P { x: i64 }\nfn f() -> i32 { 10 }\nlet a = f();\nlet b = 5;",
+        )
+        .unwrap();
+    assert_eq!(edited.changed_items, vec!["P", "f"]);
+    assert_eq!(edited.rerun_variables, vec!["a"]);
+    assert_eq!(edited.invalidated_variables, vec!["p"]);
+    assert_eq!(edited.stale_variables, vec!["d"]);
+    assert_eq!(
+        e.eval("a + b + d").unwrap().content_by_mime_type,
+        text_plain("17")
+    );
+
+    // Removing a definition from the cell removes it from the crate.
+    let removed = e.reeval_edited("c1", "let a = 1;\nlet b = 5;").unwrap();
+    assert_eq!(removed.removed_items, vec!["P", "f"]);
+    assert!(e.eval("f()").is_err());
+}
+
 #[test]
 fn crate_name_with_hyphens() {
     let (mut e, _) = new_command_context_and_outputs();
@@ -505,6 +901,24 @@ fn continue_execution_after_bad_use_statement() {
     assert_eq!(eval!(e, "f".to_string() + "oo"), text_plain("\"foo\""));
 }
 
+#[test]
+fn error_byte_offsets_refer_to_user_code() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let code = ":opt 0\nlet a = 1;\n  let b: String = a;\nb";
+    match e.execute(code) {
+        Err(Error::CompilationErrors(errors)) => {
+            let span = errors[0]
+                .primary_spanned_message()
+                .and_then(|message| message.span.as_ref())
+                .unwrap();
+            assert_eq!(&code[span.byte_start..span.byte_end], "a");
+        }
+        x => {
+            panic!("Unexpected result: {:?}", x);
+        }
+    }
+}
+
 #[test]
 fn error_from_macro_expansion() {
     let mut e = new_context();
@@ -564,6 +978,89 @@ fn redefine_type_with_existing_var() {
     );
 }
 
+#[test]
+fn redefine_type_with_same_layout() {
+    let mut e = new_context();
+    eval!(e,
+        struct Point {x: i32, y: i32}
+        struct Line(Point, Point);
+        let p = Point {x: 1, y: 2};
+        let points = vec![Point {x: 3, y: 4}];
+        let line = Line(Point {x: 0, y: 0}, Point {x: 5, y: 6});
+    );
+    // Only the attributes and visibility of `Point` changed, so `p` and `points` are kept, but
+    // `Line` has different fields.
+    eval_and_unwrap(
+        &mut e,
+        "#[derive(Debug)]\npub struct Point {\n    pub x: i32,\n    pub y: i32,\n}\nstruct Line(i64, i64);",
+    );
+    assert_eq!(variable_names(&e), vec!["p", "points"]);
+    eval!(e,
+        assert_eq!(format!("{:?} {:?}", p, points), "Point { x: 1, y: 2 } [Point { x: 3, y: 4 }]");
+    );
+
+    // A type that refers to something other than primitives and the session's own types isn't
+    // known to have kept its layout, even though its definition is the same.
+    eval!(e,
+        type Coord = i32;
+        struct Wrapper { c: Coord }
+        let w = Wrapper { c: -1 };
+    );
+    eval_and_unwrap(&mut e, "type Coord = u32;\nstruct Wrapper { c: Coord }");
+    assert_eq!(variable_names(&e), vec!["p", "points"]);
+}
+
+#[test]
+fn migrate_types() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        ":dep serde = { version = \"1\", features = [\"derive\"] }\n:dep serde_json = \"1\"\n\
+         :migrate_types on",
+    );
+    eval_and_unwrap(
+        &mut e,
+        "#[derive(serde::Serialize, serde::Deserialize)]\nstruct Point { x: i32, y: i32 }\n\
+         struct Plain(i32);\nlet p = Point { x: 1, y: 2 };\nlet plain = Plain(3);",
+    );
+    // The new field has a default, so `p` can be deserialized into the new `Point`, but `Plain`
+    // can't be serialized.
+    eval_and_unwrap(
+        &mut e,
+        "#[derive(serde::Serialize, serde::Deserialize)]\n\
+         struct Point { x: i32, y: i32, #[serde(default)] z: i32 }\nstruct Plain(i64);",
+    );
+    assert_eq!(
+        outputs.stdout.try_iter().collect::<Vec<_>>(),
+        vec![
+            "Couldn't migrate plain: its type doesn't implement Serialize",
+            "Migrated p"
+        ]
+    );
+    assert_eq!(variable_names(&e), vec!["p"]);
+    eval_and_unwrap(&mut e, "assert_eq!((p.x, p.y, p.z), (1, 2, 0));");
+}
+
+#[test]
+fn arena() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":arena on");
+    // `words` borrows from `text`, which is only possible because `text` is in the arena.
+    eval_and_unwrap(
+        &mut e,
+        "let text = arena.alloc(String::from(\"the quick brown fox\"));\n\
+         let words: Vec<&str> = text.split(' ').collect();",
+    );
+    eval_and_unwrap(
+        &mut e,
+        "assert_eq!(words, vec![\"the\", \"quick\", \"brown\", \"fox\"]);\n\
+         assert!(arena.bytes_allocated() >= std::mem::size_of::<String>());",
+    );
+    // A variable named `arena` takes precedence.
+    eval_and_unwrap(&mut e, "let arena = 42;");
+    eval_and_unwrap(&mut e, "assert_eq!(arena, 42);");
+}
+
 #[test]
 fn abort_and_restart() {
     let mut e = new_context();
@@ -601,6 +1098,89 @@ fn abort_and_restart() {
     assert_eq!(e.defined_item_names().next(), None);
 }
 
+#[test]
+fn interrupt() {
+    let mut e = new_context();
+    eval!(
+        e,
+        pub fn foo() -> i32 {
+            42
+        }
+    );
+    let interrupt_handle = e.interrupt_handle();
+    // Nothing is running yet, so keep trying until the loop below has started.
+    let interrupter = std::thread::spawn(move || {
+        while !interrupt_handle.interrupt() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    });
+    let result = e.execute("loop { std::thread::sleep(std::time::Duration::from_millis(10)); }");
+    interrupter.join().unwrap();
+    match result {
+        Err(Error::SubprocessTerminated(message)) => {
+            assert!(message.starts_with("Execution interrupted"));
+        }
+        other => panic!(
+            "Unexpected result: {:?}",
+            other.map(|o| o.content_by_mime_type)
+        ),
+    }
+    eval!(e, assert_eq!(foo(), 42));
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_between_statements_keeps_variables() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":preserve_vars_on_panic 1\nlet a = vec![1, 2];");
+    let interrupt_handle = e.interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        while !interrupt_handle.interrupt() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    });
+    // The interrupt takes effect once the sleep has finished, before `b` is defined.
+    let outputs = e
+        .execute("std::thread::sleep(std::time::Duration::from_secs(2));\nlet b = 1;")
+        .unwrap();
+    interrupter.join().unwrap();
+    assert!(outputs.content_by_mime_type.is_empty());
+    assert_eq!(eval!(e, a.len()), text_plain("2"));
+    assert!(e.execute("b").is_err());
+}
+
+#[test]
+fn interrupt_in_loop_keeps_variables() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":preserve_vars_on_panic 1\nlet mut count = 0;");
+    let interrupt_handle = e.interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        while !interrupt_handle.interrupt() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    });
+    // The loop never gets to the end of the statement, but stops at the start of an iteration.
+    let outputs = e
+        .execute("while count >= 0 {\n    count += 1;\n    std::thread::yield_now();\n}")
+        .unwrap();
+    interrupter.join().unwrap();
+    assert!(outputs.content_by_mime_type.is_empty());
+    assert_eq!(eval!(e, count > 0), text_plain("true"));
+}
+
+#[test]
+fn errors_in_loop_bodies() {
+    let (mut ctx, _) = new_command_context_and_outputs();
+    let errors: Vec<String> = check(
+        &mut ctx,
+        "for i in 0..3 { let s: String = i; }\nloop {\n    let t: String = 1;\n}\nlet z = 1;",
+    )
+    .into_iter()
+    .filter(|line| line.starts_with("error"))
+    .collect();
+    assert_eq!(errors, vec!["error 1:33-1:34", "error 3:21-3:22"]);
+}
+
 #[test]
 fn variable_assignment_compile_fail_then_use_statement() {
     let mut e = new_context();
@@ -713,7 +1293,10 @@ fn question_mark_operator() {
         owned_mut.push_str("------");
         copy_mut += 10;
     );
-    assert_eq!(variable_names(&e), vec!["copy_mut", "owned", "owned_mut"]);
+    assert_eq!(
+        variable_names(&e),
+        vec!["_err", "copy_mut", "owned", "owned_mut"]
+    );
     eval!(e,
         assert_eq!(owned, "owned");
         assert_eq!(owned_mut, "owned_mut42");
@@ -721,6 +1304,52 @@ fn question_mark_operator() {
     );
 }
 
+#[test]
+fn last_error_is_stored() {
+    let mut e = new_context();
+    eval!(e, let v: i32 = "12".parse()?;);
+    assert_eq!(variable_names(&e), vec!["v"]);
+    eval!(e, let w: i32 = "x".parse()?;);
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("_err", "String"), ("v", "i32")]
+    );
+    assert_eq!(
+        eval!(e, _err.clone()),
+        text_plain("\"invalid digit found in string\"")
+    );
+    eval!(e, std::env::var("EVCXR_UNSET_VARIABLE")?;);
+    assert_eq!(
+        eval!(e, _err.clone()),
+        text_plain("\"environment variable not found\"")
+    );
+    // Cells that succeed leave the last error alone.
+    eval!(e, let v = v + 1;);
+    assert_eq!(eval!(e, _err.len()), text_plain("30"));
+}
+
+#[test]
+fn result_history() {
+    let mut e = new_context();
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+    assert_eq!(eval!(e, "a b c".split(' ').count()), text_plain("3"));
+    assert_eq!(eval!(e, _1 + _2 as i32), text_plain("45"));
+    eval!(e, let v = vec![1, 2];);
+    // Displaying a variable doesn't move it into a result.
+    assert_eq!(eval!(e, v), text_plain("[1, 2]"));
+    // Results that borrow can't be kept, but are still displayed.
+    eval!(e, v.iter().max());
+    assert_eq!(eval!(e, __ * 2), text_plain("90"));
+    assert_eq!(variable_names(&e), vec!["v"]);
+    assert_eq!(
+        e.execute(":results").unwrap().get("text/plain").unwrap(),
+        "_1: i32 = 40 + 2\n\
+         _2: usize = \"a b c\".split(' ').count()\n\
+         _3: i32 = _1 + _2 as i32\n\
+         _5: i32 = __ * 2\n\n"
+    );
+}
+
 #[test]
 fn format() {
     let mut e = new_context();
@@ -938,6 +1567,23 @@ fn assert_no_errors(ctx: &mut CommandContext, code: &str) {
     assert_eq!(strs(&check(ctx, code)), Vec::<&str>::new());
 }
 
+#[test]
+fn registered_hints_are_per_context() {
+    let (mut ctx, _) = new_command_context_and_outputs();
+    let (mut other_ctx, _) = new_command_context_and_outputs();
+    ctx.register_hint("E0308", "A hint for tests");
+    let code = "let s: String = 1;";
+    let hints = |ctx: &mut CommandContext| -> Vec<Option<String>> {
+        ctx.check(code)
+            .unwrap()
+            .iter()
+            .map(|error| error.evcxr_extra_hint().map(str::to_owned))
+            .collect()
+    };
+    assert_eq!(hints(&mut ctx), vec![Some("A hint for tests".to_owned())]);
+    assert_eq!(hints(&mut other_ctx), vec![None]);
+}
+
 #[test]
 fn check_for_errors() {
     let mut ctx = new_context();
@@ -1017,3 +1663,1114 @@ let s2 = "さび  äää"; let s2: String = 42; fn foo() -> i32 {
     // Dropped variables shouldn't report errors.
     assert_no_errors(&mut ctx, "let s1 = String::new(); std::mem::drop(s1);");
 }
+
+/// Returns the code, level and starting line of each of rust-analyzer's diagnostics for `code`.
+#[track_caller]
+fn diagnostics(ctx: &mut CommandContext, code: &str) -> Vec<String> {
+    let mut out: Vec<String> = ctx
+        .diagnostics(code)
+        .unwrap()
+        .iter()
+        .map(|diagnostic| {
+            format!(
+                "{} {} {}",
+                diagnostic.code, diagnostic.level, diagnostic.span.start_line
+            )
+        })
+        .collect();
+    out.sort();
+    out
+}
+
+#[test]
+fn diagnostics_without_compiling() {
+    let mut ctx = new_context();
+    eval_and_unwrap(&mut ctx, "let a = 10;");
+    assert_eq!(
+        diagnostics(&mut ctx, "let b = a + 1;\nb"),
+        Vec::<String>::new()
+    );
+    // Cells that haven't been finished yet are the point, so syntax errors are reported.
+    assert_eq!(
+        diagnostics(&mut ctx, "let b = a + 1;\nstd::mem::drop("),
+        vec!["syntax-error error 2"]
+    );
+    assert_eq!(
+        diagnostics(&mut ctx, "use std::not_a_module::Thing;"),
+        vec!["unresolved-import error 1"]
+    );
+    // Problems with commands are reported too.
+    assert_eq!(
+        diagnostics(&mut ctx, ":an_invalid_command\nlet b = 1;"),
+        vec!["command error 1"]
+    );
+}
+
+#[test]
+fn semantic_tokens() {
+    let mut ctx = new_context();
+    eval_and_unwrap(&mut ctx, "fn double(x: i32) -> i32 { x * 2 }");
+    let tokens = ctx
+        .semantic_tokens("let mut total = double(21);\nprintln!(\"{}\", total);")
+        .unwrap();
+    let token_at = |line: usize, column: usize| {
+        tokens
+            .iter()
+            .find(|token| token.span.start_line == line && token.span.start_column == column)
+            .unwrap_or_else(|| panic!("No token at {}:{} in {:?}", line, column, tokens))
+    };
+    assert_eq!(token_at(1, 1).kind, "keyword");
+    assert_eq!(token_at(1, 9).kind, "variable");
+    assert!(token_at(1, 9).modifiers.contains(&"mutable".to_owned()));
+    assert_eq!(token_at(1, 9).span.end_column, 14);
+    // `double` was defined in an earlier cell.
+    assert_eq!(token_at(1, 17).kind, "function");
+    assert_eq!(token_at(2, 1).kind, "macro");
+}
+
+#[test]
+fn definitions_and_references_across_cells() {
+    let mut ctx = new_context();
+    run_cell(
+        &mut ctx,
+        "define-double",
+        "fn double(x: i32) -> i32 {\n    x * 2\n}",
+    )
+    .unwrap();
+    eval_and_unwrap(
+        &mut ctx,
+        "fn quadruple(x: i32) -> i32 { double(double(x)) }",
+    );
+    let code = "let a = 1;\nlet b = double(a);";
+    let offset = code.find("double").unwrap() + 1;
+
+    let definitions = ctx.definition(code, offset).unwrap();
+    assert_eq!(definitions.len(), 1);
+    let definition = &definitions[0];
+    assert_eq!(definition.cell_id.as_deref(), Some("define-double"));
+    assert!(definition.cell_number.is_some());
+    assert_eq!(
+        (
+            definition.span.start_line,
+            definition.span.start_column,
+            definition.span.end_column
+        ),
+        (1, 4, 10)
+    );
+
+    let references = ctx.references(code, offset).unwrap();
+    assert_eq!(
+        references
+            .iter()
+            .map(|location| location.cell_id.as_deref())
+            .collect::<Vec<_>>(),
+        vec![Some("define-double"), None, None, None]
+    );
+    // The last reference is in the code that was passed in, on its second line.
+    let last = references.last().unwrap();
+    assert_eq!(last.cell_number, None);
+    assert_eq!((last.span.start_line, last.span.start_column), (2, 9));
+    // Nothing is found for names that aren't defined in the session.
+    assert!(ctx
+        .definition("let s = String::new();", 9)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn about_command() {
+    let mut e = new_context();
+    let outputs = eval_and_unwrap(&mut e, ":about");
+    let about = outputs["application/json"].as_json().unwrap();
+    assert_eq!(
+        about["evcxr_version"].as_str(),
+        Some(env!("CARGO_PKG_VERSION"))
+    );
+    assert!(about["rustc_version"]
+        .as_str()
+        .unwrap()
+        .starts_with("rustc"));
+    assert!(outputs["text/plain"]
+        .as_text()
+        .unwrap()
+        .contains("target dir:"));
+}
+
+#[test]
+fn help_command() {
+    let mut e = new_context();
+    let outputs = eval_and_unwrap(&mut e, ":help");
+    assert!(outputs["text/plain"].as_text().unwrap().contains(":dep "));
+
+    let outputs = eval_and_unwrap(&mut e, ":help dep");
+    let help = outputs["text/plain"].as_text().unwrap();
+    assert!(help.starts_with(":dep [--force] [--scoped] <dependency>\n"));
+    assert!(help.contains(":dep regex = \"1.0\""));
+
+    let outputs = eval_and_unwrap(&mut e, ":help --json");
+    let commands = outputs["application/json"].as_json().unwrap();
+    let mem = commands
+        .members()
+        .find(|command| command["name"] == ":mem")
+        .unwrap();
+    assert_eq!(mem["synopsis"], ":mem [on|off]");
+    assert_eq!(mem["arguments"][0]["choices"][1], "off");
+
+    assert!(e.execute(":help :no_such_command").is_err());
+}
+
+#[test]
+fn lock_export_and_import() {
+    let mut e = new_context();
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path().join("pinned versions");
+    std::fs::create_dir(&dir).unwrap();
+    let exported = dir.join("exported.lock");
+    eval!(e, 40 + 2);
+    eval_and_unwrap(&mut e, &format!(":lock export {}", exported.display()));
+    let contents = std::fs::read_to_string(&exported).unwrap();
+    // Extra whitespace around the subcommand is ignored, while the path may contain spaces.
+    eval_and_unwrap(
+        &mut e,
+        &format!(":lock   import  {} \n40 + 2", exported.display()),
+    );
+    // Importing is a one-off copy, so building doesn't write to the imported file.
+    std::fs::write(&exported, "# edited\n").unwrap();
+    eval!(e, 40 + 2);
+    assert_eq!(std::fs::read_to_string(&exported).unwrap(), "# edited\n");
+    let reexported = dir.join("reexported.lock");
+    eval_and_unwrap(&mut e, &format!(":lock export {}", reexported.display()));
+    assert_eq!(std::fs::read_to_string(&reexported).unwrap(), contents);
+    assert!(e.execute(":lock import /this/path/does/not/exist").is_err());
+    assert!(e.execute(":lock /some/path").is_err());
+    eval_and_unwrap(&mut e, ":unlock");
+}
+
+#[test]
+fn lock_persist() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let persisted = tempdir.path().join("persisted").join("Cargo.lock");
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, &format!(":lock persist {}", persisted.display()));
+    eval!(e, 40 + 2);
+    let exported = tempdir.path().join("exported.lock");
+    eval_and_unwrap(&mut e, &format!(":lock export {}", exported.display()));
+    let contents = std::fs::read_to_string(&exported).unwrap();
+    assert_eq!(std::fs::read_to_string(&persisted).unwrap(), contents);
+    eval_and_unwrap(&mut e, ":lock persist off");
+
+    // Another session starts with the persisted versions.
+    let (mut other, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut other,
+        &format!(":lock persist {}", persisted.display()),
+    );
+    let reexported = tempdir.path().join("reexported.lock");
+    eval_and_unwrap(
+        &mut other,
+        &format!(":lock export {}", reexported.display()),
+    );
+    assert_eq!(std::fs::read_to_string(&reexported).unwrap(), contents);
+}
+
+#[test]
+fn export_project() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, "fn double(x: i32) -> i32 { x * 2 }");
+    eval_and_unwrap(&mut e, "let x = double(20);");
+    // Neither cells that fail nor code run by commands are exported.
+    assert!(e.execute("let y: i32 = \"no\";").is_err());
+    eval_and_unwrap(&mut e, ":timeit -n 2 double(1)");
+    assert_eq!(eval_and_unwrap(&mut e, "x + 2"), text_plain("42"));
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path().join("exported");
+    eval_and_unwrap(&mut e, &format!(":export_project {}", dir.display()));
+    let main_rs = std::fs::read_to_string(dir.join("src").join("main.rs")).unwrap();
+    assert!(main_rs.contains("fn double(x: i32) -> i32 { x * 2 }\n"));
+    assert!(
+        main_rs.ends_with(
+            "fn main() {\n\
+             let x = double(20);\n\
+             let _1 = (\nx + 2\n);\n::print_any::print_any(&_1);\n\
+             }\n"
+        ),
+        "{}",
+        main_rs
+    );
+    let cargo_toml = std::fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+    assert!(cargo_toml.contains("name = \"exported\"\n"));
+    assert!(cargo_toml.contains("\nprint_any = "));
+    // We don't overwrite an existing project.
+    assert!(e
+        .execute(&format!(":export_project {}", dir.display()))
+        .is_err());
+}
+
+#[test]
+fn load_file() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    let tempdir = tempfile::tempdir().unwrap();
+    let script = tempdir.path().join("script.rs");
+    std::fs::write(
+        &script,
+        "fn double(x: i32) -> i32 {\n    x * 2\n}\n\nlet x = double(20);\n\nx + 2\n",
+    )
+    .unwrap();
+    assert_eq!(
+        eval_and_unwrap(&mut e, &format!(":load {}", script.display())),
+        text_plain("42\n")
+    );
+    for line in ["[1/3] ", "[2/3] ", "[3/3] "] {
+        assert!(outputs.stdout.recv().unwrap().starts_with(line));
+    }
+    assert_eq!(eval_and_unwrap(&mut e, "x"), text_plain("40"));
+
+    // Loading stops at the first cell that fails and reports where it was.
+    let notebook = tempdir.path().join("notebook.ipynb");
+    std::fs::write(
+        &notebook,
+        r#"{"cells": [
+            {"cell_type": "code", "source": ["let y = 1;"]},
+            {"cell_type": "code", "source": ["let z: i32 = \"no\";"]},
+            {"cell_type": "code", "source": ["let w = 3;"]}
+        ]}"#,
+    )
+    .unwrap();
+    let error = e.execute_file(&notebook).unwrap_err().to_string();
+    assert!(
+        error.starts_with(&format!(
+            "Stopped at {} cell 2 (cell 2 of 3):\n{} cell 2:1:14: ",
+            notebook.display(),
+            notebook.display()
+        )),
+        "{}",
+        error
+    );
+    assert_eq!(eval_and_unwrap(&mut e, "y"), text_plain("1"));
+    assert!(e.execute("w").is_err());
+}
+
+#[test]
+fn eval_session_poll() {
+    let mut session = evcxr::EvalSession::with_session(evcxr::Session::new_for_testing());
+    session.set_budget(std::time::Duration::from_millis(1));
+    let id = session.submit("40 + 2");
+    assert!(session.is_busy());
+    let start = std::time::Instant::now();
+    loop {
+        let poll_start = std::time::Instant::now();
+        let events = session.poll();
+        // Allow plenty of slack for slow CI machines, but poll should never wait for evaluation.
+        assert!(poll_start.elapsed() < std::time::Duration::from_millis(500));
+        let completed = events.into_iter().find_map(|event| match event {
+            evcxr::SessionEvent::Completed { id: done, result } if done == id => Some(result),
+            _ => None,
+        });
+        if let Some(result) = completed {
+            assert_eq!(result.unwrap().get("text/plain"), Some("42"));
+            break;
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(120));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(!session.is_busy());
+}
+
+#[test]
+fn crate_attr() {
+    let mut e = new_context();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":crate_attr"),
+        text_plain("No crate attributes")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":crate_attr deny(non_snake_case)"),
+        text_plain("#![deny(non_snake_case)]")
+    );
+    // The attribute applies to later cells too.
+    assert!(e.execute("fn BadName() {}").is_err());
+    eval_and_unwrap(&mut e, ":crate_attr --remove deny(non_snake_case)");
+    eval_and_unwrap(&mut e, "fn BadName() {}");
+
+    // Errors in an attribute are reported against the command that added it, and the attribute
+    // isn't added.
+    assert_eq!(
+        check(&mut e, ":crate_attr no_such_attribute"),
+        vec!["error 1:13-1:30"]
+    );
+    assert!(e.execute(":crate_attr no_such_attribute").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":crate_attr"),
+        text_plain("No crate attributes")
+    );
+    assert!(e.execute(":crate_attr #[allow(dead_code)]").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":features"),
+        text_plain("Features: none")
+    );
+}
+
+#[test]
+fn doc_command() {
+    let mut e = new_context();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        mod math {
+            /// Doubles `x`.
+            ///
+            /// ```
+            /// # use math::double;
+            /// assert_eq!(double(2), 4);
+            /// ```
+            pub fn double(x: i32) -> i32 { x * 2 }
+        }
+        use math::double;
+        "#,
+    );
+    let outputs = eval_and_unwrap(&mut e, ":doc double");
+    let text = outputs["text/plain"].as_text().unwrap();
+    assert!(
+        text.contains("fn double(x: i32) -> i32\n\nDoubles `x`."),
+        "{}",
+        text
+    );
+    let markdown = outputs["text/markdown"].as_text().unwrap();
+    assert!(
+        markdown.ends_with("```rust\nassert_eq!(double(2), 4);\n```"),
+        "{}",
+        markdown
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":doc math::double"),
+        eval_and_unwrap(&mut e, ":doc double")
+    );
+    assert!(e.execute(":doc no_such_item").is_err());
+    assert!(e.execute(":doc math::{double}").is_err());
+}
+
+#[test]
+fn expand() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, "macro_rules! double { ($x:expr) => { $x * 2 }; }");
+    let outputs = eval_and_unwrap(&mut e, ":expand double!(21)");
+    assert_eq!(outputs["text/plain"].as_text(), Some("21 * 2"));
+    assert_eq!(
+        outputs["text/markdown"].as_text(),
+        Some("```rust\n21 * 2\n```")
+    );
+    let outputs = eval_and_unwrap(&mut e, ":expand #[derive(Clone)] struct Point;");
+    let text = outputs["text/plain"].as_text().unwrap();
+    assert!(
+        text.contains("impl ::core::clone::Clone for Point"),
+        "{}",
+        text
+    );
+    // Nothing is defined by expanding.
+    assert!(e.execute("Point").is_err());
+    assert!(e.execute(":expand").is_err());
+    assert!(e.execute(":expand no_such_macro!()").is_err());
+}
+
+#[test]
+fn clippy_lints() {
+    let mut ctx = new_context();
+    assert!(ctx.execute(":clippy sometimes").is_err());
+    eval_and_unwrap(&mut ctx, ":clippy on");
+    let outputs = ctx.execute("let v = vec![1, 2, 3];\nv.len() == 0").unwrap();
+    assert_eq!(outputs.get("text/plain"), Some("false"));
+    let lints: Vec<_> = outputs
+        .warnings
+        .iter()
+        .map(|warning| {
+            let span = warning.primary_spanned_message().unwrap().span.unwrap();
+            (warning.code().unwrap().to_owned(), span.start_line)
+        })
+        .collect();
+    assert_eq!(lints, vec![("clippy::len_zero".to_owned(), 2)]);
+    // Live checking uses clippy too.
+    assert_eq!(check(&mut ctx, "let empty = v.len() == 0;").len(), 1);
+
+    eval_and_unwrap(&mut ctx, ":clippy off");
+    assert!(ctx.execute("v.len() == 0").unwrap().warnings.is_empty());
+    assert_no_errors(&mut ctx, "let empty = v.len() == 0;");
+}
+
+#[test]
+fn warnings_and_lint_levels() {
+    fn warning_codes(ctx: &mut CommandContext, code: &str) -> Vec<String> {
+        ctx.execute(code)
+            .unwrap()
+            .warnings
+            .iter()
+            .map(|warning| warning.code().unwrap_or_default().to_owned())
+            .collect()
+    }
+
+    let mut ctx = new_context();
+    assert_eq!(
+        warning_codes(&mut ctx, "fn f() -> i32 { let unused = 1; 2 }"),
+        vec!["unused_variables"]
+    );
+    // Warnings about code evaluated earlier aren't repeated.
+    assert!(warning_codes(&mut ctx, "let x = f();").is_empty());
+
+    assert_eq!(
+        ctx.execute(":warn allow unused_variables")
+            .unwrap()
+            .get("text/plain"),
+        Some("Lint levels: allow(unused_variables)")
+    );
+    assert!(warning_codes(&mut ctx, "let y = { let unused = 1; 2 };").is_empty());
+    eval_and_unwrap(&mut ctx, ":warn deny unused_variables");
+    assert!(ctx.execute("let y = { let unused = 1; 2 };").is_err());
+    assert_eq!(
+        ctx.execute(":warn").unwrap().get("text/plain"),
+        Some("Lint levels: deny(unused_variables)")
+    );
+    assert!(ctx.execute(":warn sometimes unused_variables").is_err());
+    assert!(ctx.execute(":warn allow").is_err());
+}
+
+#[test]
+fn type_and_size() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, "let v = vec![1u8, 2, 3];");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":type v.get(1).unwrap()"),
+        text_plain("&u8\n")
+    );
+    // The expression isn't evaluated, so doesn't panic.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":type { panic!(); 1u64 }"),
+        text_plain("u64\n")
+    );
+    assert!(e.execute(":type").is_err());
+    assert!(e.execute(":type let x = 1;").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":size Option<Box<i32>>"),
+        text_plain(&format!(
+            "size: {0} bytes, align: {0} bytes\n",
+            std::mem::size_of::<usize>()
+        ))
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":size ()"),
+        text_plain("size: 0 bytes, align: 1 byte\n")
+    );
+    assert!(e.execute(":size [u8; _]").is_err());
+    // The probe doesn't disturb the session.
+    assert_eq!(eval!(e, v.len()), text_plain("3"));
+}
+
+#[test]
+fn generated_code() {
+    let mut e = new_context();
+    eval_and_unwrap(
+        &mut e,
+        "fn double(x: i32) -> i32 { x * 2 } mod math { pub fn triple(x: i32) -> i32 { x * 3 } }",
+    );
+    let outputs = eval_and_unwrap(&mut e, ":mir double");
+    let text = outputs["text/plain"].as_text().unwrap();
+    assert!(text.starts_with("fn double(_1: i32) -> i32 {"), "{}", text);
+    let markdown = outputs["text/markdown"].as_text().unwrap();
+    assert!(markdown.starts_with("```rust\nfn double("), "{}", markdown);
+    let outputs = eval_and_unwrap(&mut e, ":llvm math::triple");
+    let text = outputs["text/plain"].as_text().unwrap();
+    assert!(text.starts_with("define "), "{}", text);
+    assert!(text.contains("@_ZN3ctx4math6triple17h"), "{}", text);
+    let outputs = eval_and_unwrap(&mut e, ":asm double");
+    let text = outputs["text/plain"].as_text().unwrap();
+    assert!(text.contains("_ZN3ctx6double17h"), "{}", text);
+    assert!(e.execute(":asm").is_err());
+    assert!(e.execute(":asm no_such_function").is_err());
+    // The session is unaffected.
+    assert_eq!(eval!(e, double(21)), text_plain("42"));
+}
+
+#[test]
+fn bench_invalid_arguments() {
+    let mut e = new_context();
+    // These are rejected before criterion is added, so don't need network access.
+    assert!(e.execute(":bench").is_err());
+    assert!(e.execute(":bench let x = 1;").is_err());
+    assert!(e.execute(":bench --plot 1 + 1").is_err());
+}
+
+#[test]
+fn test_command() {
+    let mut e = new_context();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":test"),
+        text_plain("No tests found")
+    );
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        fn double(x: i32) -> i32 { x * 2 }
+        #[test]
+        fn top_level() {}
+        mod tests {
+            use super::double;
+            #[test]
+            fn passes() {
+                assert_eq!(double(2), 4);
+            }
+            #[test]
+            fn fails() {
+                println!("doubling 3");
+                assert_eq!(double(3), 7, "bad double");
+            }
+            #[test]
+            #[should_panic(expected = "out of bounds")]
+            fn panics() {
+                let empty: Vec<i32> = Vec::new();
+                double(empty[0]);
+            }
+            #[test]
+            #[ignore]
+            fn slow() {}
+            mod nested {
+                #[test]
+                fn errs() -> Result<(), String> {
+                    Err("oops".to_owned())
+                }
+            }
+        }
+        "#,
+    );
+    let outputs = e.execute(":test").unwrap();
+    let report = outputs.tests.unwrap();
+    let results: Vec<_> = report
+        .results
+        .iter()
+        .map(|result| (result.name.as_str(), &result.outcome))
+        .collect();
+    assert!(matches!(
+        results.as_slice(),
+        [
+            ("tests::fails", TestOutcome::Failed(fails)),
+            ("tests::nested::errs", TestOutcome::Failed(errs)),
+            ("tests::panics", TestOutcome::Passed),
+            ("tests::passes", TestOutcome::Passed),
+            ("tests::slow", TestOutcome::Ignored),
+            ("top_level", TestOutcome::Passed),
+        ] if fails.contains("bad double") && errs == "Error: \"oops\""
+    ));
+    assert_eq!(report.results[0].stdout, "doubling 3\n");
+    assert!(!report.is_success());
+    let text = outputs.content_by_mime_type["text/plain"]
+        .as_text()
+        .unwrap();
+    assert!(
+        text.ends_with("test result: FAILED. 3 passed; 2 failed; 1 ignored"),
+        "{}",
+        text
+    );
+
+    // Like with `cargo build`, tests are only compiled when running them.
+    assert!(e.execute("tests::passes()").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":test nested"),
+        eval_and_unwrap(&mut e, ":test errs")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":test nothing"),
+        text_plain("No tests match `nothing`")
+    );
+}
+
+#[test]
+fn edition() {
+    let mut e = new_context();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":edition"),
+        text_plain("Edition: 2021")
+    );
+    // TryFrom is only in the prelude from 2021.
+    eval!(
+        e,
+        fn narrow(x: i64) -> i32 {
+            i32::try_from(x).unwrap()
+        }
+    );
+    let error = e.execute(":edition 2018").unwrap_err().to_string();
+    assert!(
+        error.contains("Existing items don't compile with edition 2018: narrow"),
+        "{}",
+        error
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":edition"),
+        text_plain("Edition: 2021")
+    );
+    eval!(
+        e,
+        fn narrow(x: i64) -> i32 {
+            use std::convert::TryFrom;
+            i32::try_from(x).unwrap()
+        }
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":edition 2018"),
+        text_plain("Edition: 2018")
+    );
+    assert_eq!(eval!(e, narrow(42)), text_plain("42"));
+    assert!(e.execute(":edition 2000").is_err());
+}
+
+#[test]
+fn eval_events() {
+    let (mut ctx, outputs) = new_command_context_and_outputs();
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    ctx.subscribe(sender);
+    eval_and_unwrap(&mut ctx, "println!(\"hi\"); 42");
+    let events: Vec<evcxr::EvalEvent> = receiver.try_iter().collect();
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|event| match event {
+            evcxr::EvalEvent::CompilationStarted => "compile",
+            evcxr::EvalEvent::CompilationFinished(_) => "compiled",
+            evcxr::EvalEvent::ExecutionStarted => "run",
+            evcxr::EvalEvent::Stdout(line) => line.as_str(),
+            evcxr::EvalEvent::ExecutionFinished(_) => "ran",
+            _ => "other",
+        })
+        .collect();
+    // Compilation may be attempted more than once, but always finishes before execution starts.
+    let run = kinds.iter().position(|kind| *kind == "run").unwrap();
+    assert_eq!(kinds[run - 1], "compiled");
+    assert_eq!(&kinds[run..], ["run", "hi", "ran"]);
+    assert_eq!(outputs.stdout.try_recv().as_deref(), Ok("hi"));
+
+    // A compilation error means there's no execution.
+    assert!(ctx.execute("let x: i32 = \"\";").is_err());
+    let events: Vec<evcxr::EvalEvent> = receiver.try_iter().collect();
+    assert!(matches!(
+        events.last(),
+        Some(evcxr::EvalEvent::CompilationFinished(_))
+    ));
+    assert!(!events.contains(&evcxr::EvalEvent::ExecutionStarted));
+}
+
+#[test]
+fn precompile() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, "fn double(x: i32) -> i32 { x * 2 }");
+    // Code that isn't finished yet doesn't compile, which doesn't matter.
+    assert!(e.precompile("let y = double(").unwrap());
+    // Running the cell waits for the build that was started, then builds what was actually run.
+    assert_eq!(eval_and_unwrap(&mut e, "double(21)"), text_plain("42"));
+    e.precompile("double(20) + ").unwrap();
+    assert_eq!(eval_and_unwrap(&mut e, "double(20) + 1"), text_plain("41"));
+}
+
+#[test]
+fn build_log() {
+    let (mut ctx, outputs) = new_command_context_and_outputs();
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    ctx.subscribe(sender);
+    eval_and_unwrap(&mut ctx, ":build_log on");
+    eval_and_unwrap(&mut ctx, "40 + 2");
+    let events: Vec<evcxr::EvalEvent> = receiver.try_iter().collect();
+    let build_output: Vec<&str> = events
+        .iter()
+        .filter_map(|event| match event {
+            evcxr::EvalEvent::BuildOutput(line) => Some(line.as_str()),
+            _ => None,
+        })
+        .collect();
+    // Cargo is run with -vv, so it shows how it runs rustc.
+    assert!(build_output.iter().any(|line| line.contains("Running `")));
+    let stderr: Vec<String> = outputs.stderr.try_iter().collect();
+    assert_eq!(stderr, build_output);
+
+    eval_and_unwrap(&mut ctx, ":build_log off");
+    receiver.try_iter().for_each(drop);
+    eval_and_unwrap(&mut ctx, "40 + 2");
+    assert!(!receiver
+        .try_iter()
+        .any(|event| matches!(event, evcxr::EvalEvent::BuildOutput(_))));
+}
+
+#[test]
+fn session_api() {
+    let mut session = evcxr::Session::new_for_testing();
+    session.eval("let values = vec![1, 2];").unwrap();
+    session.eval("fn double(x: i32) -> i32 { x * 2 }").unwrap();
+    let outputs = session
+        .eval("println!(\"hello\"); double(values[1])")
+        .unwrap();
+    assert_eq!(outputs.get("text/plain"), Some("4"));
+    assert_eq!(outputs.mime_types(), vec!["text/plain"]);
+    assert_eq!(session.take_stdout(), vec!["hello".to_owned()]);
+    assert!(session.take_stdout().is_empty());
+
+    let variables = session.variables();
+    assert_eq!(variables.len(), 1);
+    assert_eq!(variables[0].name, "values");
+    assert_eq!(variables[0].type_name, "Vec<i32>");
+    assert_eq!(session.items(), vec!["double".to_owned()]);
+
+    let completions = session.complete("doub", 4).unwrap();
+    assert!(completions
+        .iter()
+        .any(|completion| completion.code.starts_with("double") && completion.range == (0..4)));
+
+    assert_eq!(session.type_of("values[0] as i64").unwrap(), "i64");
+    let layout = session.type_layout("(u8, u32)").unwrap();
+    assert_eq!((layout.size, layout.align), (8, 4));
+
+    assert!(matches!(
+        session.eval("undefined_variable"),
+        Err(evcxr::SessionError::Compilation(messages)) if !messages.is_empty()
+    ));
+    // Nothing is running, so there's nothing to interrupt.
+    assert!(!session.interrupter().interrupt());
+}
+
+#[test]
+fn session_manager() {
+    let manager = std::sync::Arc::new(evcxr::SessionManager::new(Default::default()));
+    let ids: Vec<evcxr::SessionId> = (0..2)
+        .map(|_| manager.add(evcxr::Session::new_for_testing()))
+        .collect();
+    assert_eq!(manager.session_ids(), ids);
+    // Evaluate in both sessions at once. Each has its own variables.
+    let threads: Vec<_> = ids
+        .iter()
+        .enumerate()
+        .map(|(index, &id)| {
+            let manager = std::sync::Arc::clone(&manager);
+            std::thread::spawn(move || {
+                manager
+                    .eval(id, &format!("let x = {}; println!(\"{}\");", index, index))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    for (index, &id) in ids.iter().enumerate() {
+        let outputs = manager.eval(id, "x").unwrap();
+        assert_eq!(outputs.get("text/plain"), Some(index.to_string().as_str()));
+        assert_eq!(
+            manager
+                .with_session(id, |session| session.take_stdout())
+                .unwrap(),
+            vec![index.to_string()]
+        );
+    }
+    assert!(!manager.interrupt(ids[0]).unwrap());
+    assert!(manager.close(ids[0]));
+    assert!(!manager.close(ids[0]));
+    assert!(manager.eval(ids[0], "x").is_err());
+    assert_eq!(manager.session_ids(), vec![ids[1]]);
+}
+
+#[test]
+fn dev_crate_reload() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let dev = TmpCrate::new(
+        "dev1",
+        "pub struct Foo { pub x: i32 }\npub fn v() -> i32 { 1 }",
+    )
+    .unwrap();
+    let lib_rs = dev.tempdir.path().join("src").join("lib.rs");
+    eval_and_unwrap(
+        &mut e,
+        &format!(
+            ":dev_crate {}\nlet a = dev1::Foo {{ x: 5 }};\nlet b = 10;",
+            dev.tempdir.path().display()
+        ),
+    );
+    // Sleep so that modification times are distinguishable, even on filesystems with coarse
+    // timestamps.
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    std::fs::write(
+        &lib_rs,
+        "pub struct Foo { pub x: i32 }\npub fn v() -> i32 { 2 }",
+    )
+    .unwrap();
+    let outputs = e.execute("dev1::v() + a.x + b").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("17"));
+
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    std::fs::write(
+        &lib_rs,
+        "pub struct Foo { pub x: i64 }\npub fn v() -> i32 { 3 }",
+    )
+    .unwrap();
+    let outputs = e.execute("dev1::v() + b").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("13"));
+    assert!(e.execute("a.x").is_err());
+}
+
+#[test]
+fn target_command() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let outputs = e.execute(":target").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("target: host\n"));
+    let outputs = e.execute(":runner echo").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("runner: echo\n"));
+    // Selecting the host explicitly runs code in-process as usual, so variables persist.
+    eval_and_unwrap(&mut e, ":target host\nlet x = 40;");
+    let outputs = e.execute("x + 2").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+}
+
+#[test]
+fn why_slow() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute(":why_slow").is_err());
+    eval_and_unwrap(&mut e, "40 + 2");
+    let outputs = e.execute(":why_slow").unwrap();
+    assert!(outputs
+        .get("text/plain")
+        .unwrap()
+        .starts_with("Last evaluation took"));
+}
+
+#[cfg(unix)]
+#[test]
+fn remote_runtime_over_tcp() {
+    let testing_runtime_path = std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("testing_runtime");
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut server = std::process::Command::new(testing_runtime_path)
+        .env("EVCXR_RUNTIME_LISTEN", &address)
+        .spawn()
+        .unwrap();
+    while std::net::TcpStream::connect(&address).is_err() {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let (eval_context, _outputs) =
+        EvalContext::with_remote_runtime(evcxr::RemoteTransport::Tcp { address }).unwrap();
+    let mut e = CommandContext::with_eval_context(eval_context);
+    eval_and_unwrap(&mut e, "let x = 40;");
+    let outputs = e.execute("x + 2").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[cfg(unix)]
+#[test]
+fn auto_recover_skips_large_values() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":auto_recover 1\nlet small = 1;");
+    eval_and_unwrap(&mut e, "let big: Vec<u8> = vec![0; 100_000];");
+    match e.execute("std::process::abort();") {
+        Err(Error::SubprocessTerminated(message)) => {
+            assert!(message.contains("Restored variables: small."));
+            assert!(message.contains("Lost variables, since they couldn't be snapshotted: big."));
+        }
+        other => panic!(
+            "Unexpected result: {:?}",
+            other.map(|o| o.content_by_mime_type)
+        ),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn auto_recover_after_crash() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":auto_recover 1");
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        fn double(x: i32) -> i32 { x * 2 }
+        let mut a = 21;
+        let s = "hi".to_owned();
+        let rc = std::rc::Rc::new(1);
+        "#,
+    );
+    match e.execute("std::process::abort();") {
+        Err(Error::SubprocessTerminated(message)) => {
+            assert!(message.contains("Restored variables: a, s."));
+            assert!(message.contains("Lost variables, since they couldn't be snapshotted: rc."));
+        }
+        other => panic!(
+            "Unexpected result: {:?}",
+            other.map(|o| o.content_by_mime_type)
+        ),
+    }
+    let outputs = e
+        .execute("a += 1; format!(\"{} {}\", double(a), s)")
+        .unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("\"44 hi\""));
+}
+
+#[cfg(unix)]
+#[test]
+fn env_and_working_dir() {
+    let mut e = new_context();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":env set EVCXR_TEST_GREETING=hello world"),
+        text_plain("EVCXR_TEST_GREETING=hello world\n")
+    );
+    assert_eq!(eval_and_unwrap(&mut e, ":cd /"), text_plain("/\n"));
+    let check = r#"format!("{:?} {}", std::env::var("EVCXR_TEST_GREETING"), std::env::current_dir().unwrap().display())"#;
+    assert_eq!(
+        eval_and_unwrap(&mut e, check),
+        text_plain("\"Ok(\\\"hello world\\\") /\"")
+    );
+    // Our own environment is unchanged.
+    assert!(std::env::var("EVCXR_TEST_GREETING").is_err());
+    assert!(e.execute(":cd /evcxr/does/not/exist").is_err());
+
+    // The changes are made again when the subprocess restarts.
+    assert!(e.execute("std::process::abort();").is_err());
+    assert_eq!(eval_and_unwrap(&mut e, ":pwd"), text_plain("/\n"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, check),
+        text_plain("\"Ok(\\\"hello world\\\") /\"")
+    );
+    eval_and_unwrap(&mut e, ":env unset EVCXR_TEST_GREETING");
+    assert_eq!(
+        eval_and_unwrap(&mut e, "std::env::var(\"EVCXR_TEST_GREETING\").is_err()"),
+        text_plain("true")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn shell_commands() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    e.execute("!echo hello").unwrap();
+    assert_eq!(outputs.stdout.recv(), Ok("hello".to_owned()));
+    e.execute(":sh echo again").unwrap();
+    assert_eq!(outputs.stdout.recv(), Ok("again".to_owned()));
+    assert_eq!(
+        eval_and_unwrap(&mut e, "let out = !printf 'a\\nb\\n';\nout"),
+        text_plain("\"a\\nb\"")
+    );
+    // Negation is still Rust.
+    assert_eq!(
+        eval_and_unwrap(&mut e, "!out.is_empty()"),
+        text_plain("true")
+    );
+}
+
+#[test]
+fn format_code() {
+    let mut e = new_context();
+    // Commands are left alone, as are the contents of multi-line strings.
+    assert_eq!(
+        e.format_code(":dep regex = \"1\"\nfn f(x:i32)->i32{x*2}\nlet s=\"a\n  b\";f( 1 )")
+            .unwrap(),
+        ":dep regex = \"1\"\nfn f(x: i32) -> i32 {\n    x * 2\n}\nlet s = \"a\n  b\";\nf(1)"
+    );
+    assert!(e
+        .format_code("let x = ;")
+        .unwrap_err()
+        .to_string()
+        .contains("expected expression"));
+
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":autoformat on"),
+        text_plain("Autoformat: on\n")
+    );
+    assert!(e.autoformat());
+    eval_and_unwrap(&mut e, "fn double(x:i32)->i32{x*2}");
+    eval_and_unwrap(&mut e, "let x=double(20);");
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path().join("exported");
+    eval_and_unwrap(&mut e, &format!(":export_project {}", dir.display()));
+    let main_rs = std::fs::read_to_string(dir.join("src").join("main.rs")).unwrap();
+    assert!(
+        main_rs.contains("fn double(x: i32) -> i32 {\n    x * 2\n}\n"),
+        "{}",
+        main_rs
+    );
+    assert!(
+        main_rs.contains("\n    let x = double(20);\n"),
+        "{}",
+        main_rs
+    );
+}
+
+#[test]
+fn rustfmt_command() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute(":rustfmt").is_err());
+    eval_and_unwrap(&mut e, "fn double(x:i32)->i32{x*2}");
+    eval_and_unwrap(&mut e, ":fmt {:#?}");
+    eval_and_unwrap(&mut e, "let x=double( 20 );");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":rustfmt"),
+        text_plain("let x = double(20);\n")
+    );
+    // Cells with only commands aren't counted.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":rustfmt 1"),
+        text_plain("fn double(x: i32) -> i32 {\n    x * 2\n}\n")
+    );
+    assert!(e.execute(":rustfmt 3").is_err());
+    assert!(e.execute(":rustfmt x").is_err());
+}
+
+#[test]
+fn rerun_dependents() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, "let x = 2;");
+    eval_and_unwrap(&mut e, "let mut total = 0;");
+    eval_and_unwrap(&mut e, "total += 100;");
+    eval_and_unwrap(&mut e, ":timeit -n 1 1\nlet y = x * 10;\ntotal += y;");
+    eval_and_unwrap(&mut e, "let unrelated = 1;");
+    eval_and_unwrap(&mut e, "let x = 3;");
+    eval_and_unwrap(&mut e, ":rerun_dependents x");
+    // `total` is built again from its definition, rather than having 30 added to 120.
+    assert_eq!(eval_and_unwrap(&mut e, "total"), text_plain("130"));
+    assert_eq!(eval_and_unwrap(&mut e, "y"), text_plain("30"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":rerun_dependents unrelated"),
+        text_plain("No cells depend on unrelated\n")
+    );
+    assert!(e.execute(":rerun_dependents").is_err());
+}
+
+fn run_cell(ctx: &mut CommandContext, cell_id: &str, code: &str) -> Result<(), Error> {
+    ctx.execute_cell_with_callbacks(cell_id, code, &mut EvalCallbacks::default())
+        .map(|_| ())
+}
+
+#[test]
+fn reactive_cells() {
+    let mut e = new_context();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":reactive recompute"),
+        text_plain("Reactive: recompute\n")
+    );
+    run_cell(&mut e, "c", "let z = 1;").unwrap();
+    run_cell(&mut e, "a", "let x = 2;").unwrap();
+    run_cell(&mut e, "b", "let y = x * 10;").unwrap();
+    // Cell "c" first ran before the others, but now reads `y`, so runs after "b".
+    run_cell(&mut e, "c", "let z = y + 1;").unwrap();
+    run_cell(&mut e, "a", "let x = 3;").unwrap();
+    assert_eq!(eval_and_unwrap(&mut e, "z"), text_plain("31"));
+
+    eval_and_unwrap(&mut e, ":reactive invalidate");
+    run_cell(&mut e, "a", "let x = 4;").unwrap();
+    assert_eq!(variable_names(&e), vec!["x"]);
+
+    // `x` would be computed from `z`, which is computed from `x`.
+    assert!(run_cell(&mut e, "a", "let x = z;").is_err());
+    assert_eq!(eval_and_unwrap(&mut e, "x"), text_plain("4"));
+    // Once the cell that reads `x` is deleted, there's no cycle.
+    e.forget_cells(&["b".to_owned()]);
+    run_cell(&mut e, "c", "let z = 5;").unwrap();
+    run_cell(&mut e, "a", "let x = z;").unwrap();
+    assert_eq!(eval_and_unwrap(&mut e, "x"), text_plain("5"));
+}
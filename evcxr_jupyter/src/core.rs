@@ -15,18 +15,49 @@
 use crate::connection::Connection;
 use crate::control_file;
 use crate::jupyter_message::JupyterMessage;
+use crate::metrics_server;
+use crate::output_chunks::OutputChunker;
 use anyhow::bail;
 use anyhow::Result;
 use colored::*;
 use crossbeam_channel::Select;
+use evcxr::CommMessage;
+use evcxr::CommMessageKind;
 use evcxr::CommandContext;
+use evcxr::CommandInfo;
+use evcxr::Comms;
+use evcxr::CompilationError;
+use evcxr::Debugger;
+use evcxr::DisplayUpdate;
+use evcxr::EvalContextConfig;
+use evcxr::InterruptHandle;
 use json::JsonValue;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
-use std::time;
 use std::time::Duration;
+use std::time::Instant;
+
+/// The mime types that user code should produce, in the order that JupyterLab prefers to render
+/// them. Anything else, e.g. types handled by extensions, comes last.
+const MIME_PREFERENCES: &[&str] = &[
+    "application/vnd.jupyter.widget-view+json",
+    "text/html",
+    "text/markdown",
+    "text/latex",
+    "image/svg+xml",
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "application/json",
+    "text/plain",
+    "*/*",
+];
+
+/// How many lines of each of stdout and stderr may be waiting to be sent to the frontend before
+/// user code is made to wait.
+const OUTPUT_BUFFER_LINES: usize = 10_000;
 
 // Note, to avoid potential deadlocks, each thread should lock at most one mutex at a time.
 #[derive(Clone)]
@@ -34,8 +65,12 @@ pub(crate) struct Server {
     iopub: Arc<Mutex<Connection>>,
     stdin: Arc<Mutex<Connection>>,
     latest_execution_request: Arc<Mutex<Option<JupyterMessage>>>,
+    latest_debug_request: Arc<Mutex<Option<JupyterMessage>>>,
     shutdown_requested_receiver: Arc<Mutex<crossbeam_channel::Receiver<()>>>,
     shutdown_requested_sender: Arc<Mutex<crossbeam_channel::Sender<()>>>,
+    /// Asks the output pass-through thread to send any pending output, then notify the supplied
+    /// sender.
+    output_flush_sender: crossbeam_channel::Sender<crossbeam_channel::Sender<()>>,
 }
 
 impl Server {
@@ -67,13 +102,16 @@ impl Server {
 
         let (shutdown_requested_sender, shutdown_requested_receiver) =
             crossbeam_channel::unbounded();
+        let (output_flush_sender, output_flush_receiver) = crossbeam_channel::unbounded();
 
         let server = Server {
             iopub,
             latest_execution_request: Arc::new(Mutex::new(None)),
+            latest_debug_request: Arc::new(Mutex::new(None)),
             stdin: Arc::new(Mutex::new(stdin_socket)),
             shutdown_requested_receiver: Arc::new(Mutex::new(shutdown_requested_receiver)),
             shutdown_requested_sender: Arc::new(Mutex::new(shutdown_requested_sender)),
+            output_flush_sender,
         };
 
         let (execution_sender, execution_receiver) = crossbeam_channel::unbounded();
@@ -81,9 +119,27 @@ impl Server {
             crossbeam_channel::unbounded();
 
         thread::spawn(move || Self::handle_hb(&heartbeat));
-        server.start_thread(move |server: Server| server.handle_control(control_socket));
-        let (mut context, outputs) = CommandContext::new()?;
+        let (mut context, outputs) = CommandContext::with_config(EvalContextConfig {
+            output_buffer_lines: Some(OUTPUT_BUFFER_LINES),
+            ..EvalContextConfig::default()
+        })?;
+        metrics_server::start_if_requested(context.metrics())?;
+        let debugger = context.debugger();
+        server.clone().start_debug_event_thread(debugger.events());
+        let interrupt_handle = context.interrupt_handle();
+        // Some servers send SIGINT even though our kernelspec asks for interrupt messages.
+        interrupt_handle.interrupt_on_sigint();
+        server.start_thread(move |server: Server| {
+            server.handle_control(control_socket, debugger, interrupt_handle)
+        });
         context.execute(":load_config")?;
+        context.set_mime_preferences(
+            MIME_PREFERENCES
+                .iter()
+                .map(|mime_type| (*mime_type).to_owned())
+                .collect(),
+        );
+        let comms = context.comms();
         let context = Arc::new(Mutex::new(context));
         server.start_thread({
             let context = Arc::clone(&context);
@@ -93,6 +149,7 @@ impl Server {
                     &execution_sender,
                     &execution_response_receiver,
                     context,
+                    comms,
                 )
             }
         });
@@ -103,10 +160,10 @@ impl Server {
                 &execution_response_sender,
             )
         });
-        server.clone().start_output_pass_through_thread(vec![
-            ("stdout", outputs.stdout),
-            ("stderr", outputs.stderr),
-        ]);
+        server.clone().start_output_pass_through_thread(
+            vec![("stdout", outputs.stdout), ("stderr", outputs.stderr)],
+            output_flush_receiver,
+        );
         Ok(server)
     }
 
@@ -171,36 +228,80 @@ impl Server {
                 .send(&self.iopub.lock().unwrap())?;
             let mut callbacks = evcxr::EvalCallbacks {
                 input_reader: &|prompt, is_password| {
+                    // Make sure that anything printed before the input request is shown first.
+                    self.flush_output();
                     self.request_input(&message, prompt, is_password)
                         .unwrap_or_default()
                 },
+                comm_sender: &|comm_message| {
+                    if let Err(error) = self.send_comm_message(&message, comm_message) {
+                        eprintln!("{}", error);
+                    }
+                },
+                display_sender: &|update| {
+                    if let Err(error) = self.send_display_update(&message, update) {
+                        eprintln!("{}", error);
+                    }
+                },
+                output_clearer: &|wait| {
+                    // Anything printed before the request should be cleared too.
+                    self.flush_output();
+                    if let Err(error) = message
+                        .new_message("clear_output")
+                        .with_content(object! {"wait" => wait})
+                        .send(&self.iopub.lock().unwrap())
+                    {
+                        eprintln!("{}", error);
+                    }
+                },
             };
 
-            #[allow(unknown_lints, clippy::significant_drop_in_scrutinee)]
-            match context
-                .lock()
-                .unwrap()
-                .execute_with_callbacks(src, &mut callbacks)
-            {
+            let result = {
+                let mut context = context.lock().unwrap();
+                context.forget_cells(&message.deleted_cells());
+                match message.cell_id() {
+                    Some(cell_id) => {
+                        context.execute_cell_with_callbacks(cell_id, src, &mut callbacks)
+                    }
+                    None => context.execute_with_callbacks(src, &mut callbacks),
+                }
+            };
+            match result {
                 Ok(output) => {
-                    if !output.is_empty() {
-                        // Increase the odds that stdout will have been finished being sent. A
-                        // less hacky alternative would be to add a print statement, then block
-                        // waiting for it.
-                        thread::sleep(time::Duration::from_millis(1));
-                        let mut data = HashMap::new();
-                        // At the time of writing the json crate appears to have a generic From
-                        // implementation for a Vec<T> where T implements Into<JsonValue>. It also
-                        // has conversion from HashMap<String, JsonValue>, but it doesn't have
-                        // conversion from HashMap<String, T>. Perhaps send a PR? For now, we
-                        // convert the values manually.
-                        for (k, v) in output.content_by_mime_type {
-                            if k.contains("json") {
-                                data.insert(k, json::parse(&v).unwrap_or_else(|_| json::from(v)));
-                            } else {
-                                data.insert(k, json::from(v));
+                    if !output.warnings.is_empty() {
+                        // Warnings, including lints from `:clippy`, go to stderr so that they're styled like other
+                        // diagnostics, but appear after output from the cell.
+                        use std::fmt::Write;
+                        self.flush_output();
+                        let mut text = String::new();
+                        for warning in &output.warnings {
+                            writeln!(text, "{}: {}", "warning".yellow().bold(), warning.message())
+                                .unwrap();
+                            for line in annotated_lines(warning)
+                                .into_iter()
+                                .chain(help_lines(warning))
+                            {
+                                writeln!(text, "{}", line).unwrap();
                             }
                         }
+                        message
+                            .new_message("stream")
+                            .with_content(object! {
+                                "name" => "stderr",
+                                "text" => text,
+                            })
+                            .send(&self.iopub.lock().unwrap())?;
+                    }
+                    if !output.is_empty() {
+                        // Make sure that output from the cell appears before its result.
+                        self.flush_output();
+                        // Binary content such as images is base64 encoded here, since the
+                        // Jupyter protocol requires it.
+                        let data: HashMap<String, JsonValue> = output
+                            .content_by_mime_type
+                            .iter()
+                            .map(|(mime_type, content)| (mime_type.clone(), content.to_json()))
+                            .collect();
                         message
                             .new_message("execute_result")
                             .with_content(object! {
@@ -210,15 +311,19 @@ impl Server {
                             })
                             .send(&self.iopub.lock().unwrap())?;
                     }
-                    if let Some(duration) = output.timing {
-                        // TODO replace by duration.as_millis() when stable
-                        let ms = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+                    let summary: Vec<String> = output
+                        .timing
+                        .map(|timing| timing.to_string())
+                        .into_iter()
+                        .chain(output.memory.map(|memory| memory.to_string()))
+                        .collect();
+                    if !summary.is_empty() {
                         let mut data: HashMap<String, JsonValue> = HashMap::new();
                         data.insert(
                             "text/html".into(),
                             json::from(format!(
-                                "<span style=\"color: rgba(0,0,0,0.4);\">Took {}ms</span>",
-                                ms
+                                "<span style=\"color: rgba(0,0,0,0.4);\">{}</span>",
+                                summary.join("\n").replace('\n', "<br>")
                             )),
                         );
                         message
@@ -271,12 +376,64 @@ impl Server {
             .map(|value| value.to_owned())
     }
 
+    /// Publishes a message that user code sent on a comm. `request` is the execution request for
+    /// the code, so that any widgets get displayed in the right place.
+    fn send_comm_message(&self, request: &JupyterMessage, comm_message: CommMessage) -> Result<()> {
+        let message = match comm_message.kind {
+            CommMessageKind::Open { target_name, data } => {
+                request.new_message("comm_open").with_content(object! {
+                    "comm_id" => comm_message.comm_id,
+                    "target_name" => target_name,
+                    "data" => data,
+                })
+            }
+            CommMessageKind::Msg { data } => {
+                request.new_message("comm_msg").with_content(object! {
+                    "comm_id" => comm_message.comm_id,
+                    "data" => data,
+                })
+            }
+            CommMessageKind::Close => request.new_message("comm_close").with_content(object! {
+                "comm_id" => comm_message.comm_id,
+            }),
+        };
+        message.send(&self.iopub.lock().unwrap())
+    }
+
+    /// Publishes output that user code displayed while running, e.g. a progress bar. Updates
+    /// replace the earlier output with the same display ID.
+    fn send_display_update(&self, request: &JupyterMessage, update: DisplayUpdate) -> Result<()> {
+        let message_type = if update.is_update {
+            "update_display_data"
+        } else {
+            // Keep the display after anything printed before it.
+            self.flush_output();
+            "display_data"
+        };
+        let data: HashMap<String, JsonValue> = update
+            .content_by_mime_type
+            .iter()
+            .map(|(mime_type, content)| (mime_type.clone(), content.to_json()))
+            .collect();
+        request
+            .new_message(message_type)
+            .with_content(object! {
+                "data" => data,
+                "metadata" => object!(),
+                "transient" => object! {
+                    "display_id" => update.display_id,
+                },
+            })
+            .send(&self.iopub.lock().unwrap())
+    }
+
     fn handle_shell(
         self,
         connection: Connection,
         execution_channel: &crossbeam_channel::Sender<JupyterMessage>,
         execution_reply_receiver: &crossbeam_channel::Receiver<JupyterMessage>,
         context: Arc<Mutex<CommandContext>>,
+        comms: Comms,
     ) -> Result<()> {
         loop {
             let message = JupyterMessage::read(&connection)?;
@@ -286,6 +443,7 @@ impl Server {
                 execution_channel,
                 execution_reply_receiver,
                 &context,
+                &comms,
             )?;
         }
     }
@@ -297,6 +455,7 @@ impl Server {
         execution_channel: &crossbeam_channel::Sender<JupyterMessage>,
         execution_reply_receiver: &crossbeam_channel::Receiver<JupyterMessage>,
         context: &Arc<Mutex<CommandContext>>,
+        comms: &Comms,
     ) -> Result<()> {
         // Processing of every message should be enclosed between "busy" and "idle"
         // see https://jupyter-client.readthedocs.io/en/latest/messaging.html#messages-on-the-shell-router-dealer-channel
@@ -323,11 +482,42 @@ impl Server {
             execution_reply_receiver.recv()?.send(connection)?;
         } else if message.message_type() == "comm_open" {
             comm_open(message, context, Arc::clone(&self.iopub))?;
-        } else if message.message_type() == "comm_msg"
-            || message.message_type() == "comm_info_request"
-        {
-            // We don't handle this yet.
+        } else if message.message_type() == "comm_msg" {
+            // Widgets send updates when the user changes them. They may also ask for the current
+            // state, e.g. when the notebook is reloaded.
+            comms.handle_message(message.comm_id(), message.data());
+            if message.data()["method"].as_str() == Some("request_state") {
+                if let Some(state) = comms.state(message.comm_id()) {
+                    message
+                        .new_message("comm_msg")
+                        .with_content(object! {
+                            "comm_id" => message.comm_id(),
+                            "data" => object! {
+                                "method" => "update",
+                                "state" => state,
+                                "buffer_paths" => array![],
+                            },
+                        })
+                        .send(&self.iopub.lock().unwrap())?;
+                }
+            }
+        } else if message.message_type() == "comm_close" {
+            comms.close(message.comm_id());
+        } else if message.message_type() == "comm_info_request" {
+            let mut comms_json = object! {};
+            for (comm_id, target_name) in comms.list(message.get_content()["target_name"].as_str())
+            {
+                comms_json[comm_id] = object! {"target_name" => target_name};
+            }
+            message
+                .new_reply()
+                .with_content(object! {
+                    "status" => "ok",
+                    "comms" => comms_json,
+                })
+                .send(connection)?;
         } else if message.message_type() == "complete_request" {
+            let code = message.code().to_owned();
             let reply = message.new_reply().with_content(
                 match handle_completion_request(context, message) {
                     Ok(response_content) => response_content,
@@ -339,6 +529,10 @@ impl Server {
                 },
             );
             reply.send(connection)?;
+            // Completions are requested as the user types, so this is a good time to start
+            // compiling the cell in the background, so that it runs sooner. Any errors will be
+            // reported when it's run.
+            let _ = context.lock().unwrap().precompile(&code);
         } else {
             eprintln!(
                 "Got unrecognized message type on shell channel: {}",
@@ -349,16 +543,32 @@ impl Server {
         Ok(())
     }
 
-    fn handle_control(self, connection: Connection) -> Result<()> {
+    fn handle_control(
+        self,
+        connection: Connection,
+        debugger: Debugger,
+        interrupt_handle: InterruptHandle,
+    ) -> Result<()> {
         loop {
             let message = JupyterMessage::read(&connection)?;
             match message.message_type() {
                 "shutdown_request" => self.signal_shutdown(),
+                "debug_request" => {
+                    *self.latest_debug_request.lock().unwrap() = Some(message.clone());
+                    let response = debugger.handle_request(message.get_content());
+                    message
+                        .new_reply()
+                        .with_content(response)
+                        .send(&connection)?;
+                }
                 "interrupt_request" => {
-                    message.new_reply().send(&connection)?;
-                    eprintln!(
-                        "Rust doesn't support interrupting execution. Perhaps restart kernel?"
-                    );
+                    interrupt_handle.interrupt();
+                    message
+                        .new_reply()
+                        .with_content(object! {
+                            "status" => "ok",
+                        })
+                        .send(&connection)?;
                 }
                 _ => {
                     eprintln!(
@@ -370,33 +580,108 @@ impl Server {
         }
     }
 
+    /// Passes events from the debugger to the frontend. Events aren't in response to any
+    /// particular request, so we use the latest debug request as their parent.
+    fn start_debug_event_thread(self, events: crossbeam_channel::Receiver<JsonValue>) {
+        thread::spawn(move || {
+            for event in events {
+                let message = self
+                    .latest_debug_request
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|request| request.new_message("debug_event").with_content(event));
+                if let Some(message) = message {
+                    if let Err(error) = message.send(&self.iopub.lock().unwrap()) {
+                        eprintln!("{}", error);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Forwards output from user code to the frontend as stream messages. Lines are grouped into
+    /// chunks, see `OutputChunker`. Each message on `flush_requests` causes pending output to be
+    /// sent straight away, after which the sender that it contains is notified.
     fn start_output_pass_through_thread(
         self,
         channels: Vec<(&'static str, crossbeam_channel::Receiver<String>)>,
+        flush_requests: crossbeam_channel::Receiver<crossbeam_channel::Sender<()>>,
     ) {
         thread::spawn(move || {
+            let mut chunkers: Vec<OutputChunker> =
+                channels.iter().map(|_| OutputChunker::default()).collect();
             let mut select = Select::new();
             for (_, channel) in &channels {
                 select.recv(channel);
             }
+            let flush_index = select.recv(&flush_requests);
             loop {
-                let index = select.ready();
-                let (output_name, channel) = &channels[index];
-                // Read from the channel that has output until it has been idle
-                // for 1ms before we return to checking other channels. This
-                // reduces the extent to which outputs interleave. e.g. a
-                // multi-line print is performed to stderr, then another to
-                // stdout - we can't guarantee the order in which they get sent,
-                // but we'd like to try to make sure that we don't interleave
-                // their lines if possible.
-                while let Ok(line) = channel.recv_timeout(Duration::from_millis(1)) {
-                    self.pass_output_line(output_name, line);
+                let next_due = chunkers
+                    .iter()
+                    .filter_map(|chunker| chunker.time_until_due(Instant::now()))
+                    .min();
+                let ready = match next_due {
+                    Some(timeout) => select.ready_timeout(timeout).ok(),
+                    None => Some(select.ready()),
+                };
+                let mut flush_reply = None;
+                match ready {
+                    Some(index) if index == flush_index => {
+                        flush_reply = flush_requests.try_recv().ok();
+                    }
+                    Some(index) => {
+                        let (output_name, channel) = &channels[index];
+                        let chunker = &mut chunkers[index];
+                        // Read from the channel that has output until it has been idle for 1ms
+                        // before we return to checking other channels. This reduces the extent to
+                        // which outputs interleave. e.g. a multi-line print is performed to
+                        // stderr, then another to stdout - we can't guarantee the order in which
+                        // they get sent, but we'd like to try to make sure that we don't
+                        // interleave their lines if possible.
+                        while let Ok(line) = channel.recv_timeout(Duration::from_millis(1)) {
+                            chunker.push(&line);
+                            if chunker.is_full() {
+                                // While we wait, lines build up in the channel. Once it's full,
+                                // user code blocks when writing, so it can't outpace the frontend.
+                                if let Some(wait) = chunker.time_until_due(Instant::now()) {
+                                    thread::sleep(wait);
+                                }
+                                self.send_stream(output_name, chunker.take(Instant::now()));
+                            }
+                        }
+                    }
+                    None => {}
+                }
+                for ((output_name, channel), chunker) in channels.iter().zip(&mut chunkers) {
+                    if flush_reply.is_some() {
+                        for line in channel.try_iter() {
+                            chunker.push(&line);
+                        }
+                    }
+                    match chunker.time_until_due(Instant::now()) {
+                        Some(wait) if wait == Duration::ZERO || flush_reply.is_some() => {
+                            self.send_stream(output_name, chunker.take(Instant::now()));
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(reply) = flush_reply {
+                    let _ = reply.send(());
                 }
             }
         });
     }
 
-    fn pass_output_line(&self, output_name: &'static str, line: String) {
+    /// Waits until output that user code has written so far has been sent to the frontend.
+    fn flush_output(&self) {
+        let (reply_sender, reply) = crossbeam_channel::bounded(1);
+        if self.output_flush_sender.send(reply_sender).is_ok() {
+            let _ = reply.recv_timeout(Duration::from_secs(1));
+        }
+    }
+
+    fn send_stream(&self, output_name: &'static str, text: String) {
         let mut message = None;
         if let Some(exec_request) = &*self.latest_execution_request.lock().unwrap() {
             message = Some(exec_request.new_message("stream"));
@@ -405,7 +690,7 @@ impl Server {
             if let Err(error) = message
                 .with_content(object! {
                     "name" => output_name,
-                    "text" => format!("{}\n", line),
+                    "text" => text,
                 })
                 .send(&self.iopub.lock().unwrap())
             {
@@ -417,41 +702,16 @@ impl Server {
     fn emit_errors(&self, errors: &evcxr::Error, parent_message: &JupyterMessage) -> Result<()> {
         match errors {
             evcxr::Error::CompilationErrors(errors) => {
-                for error in errors {
+                for group in evcxr::group_errors(errors.clone()) {
+                    let error = &group.primary;
                     let message = format!("{}", error.message().bright_red());
                     if error.is_from_user_code() {
-                        let mut traceback = Vec::new();
-                        for spanned_message in error.spanned_messages() {
-                            for line in &spanned_message.lines {
-                                traceback.push(line.clone());
-                            }
-                            if let Some(span) = &spanned_message.span {
-                                let mut carrots = String::new();
-                                for _ in 1..span.start_column {
-                                    carrots.push(' ');
-                                }
-                                for _ in span.start_column..span.end_column {
-                                    carrots.push('^');
-                                }
-                                traceback.push(format!(
-                                    "{} {}",
-                                    carrots.bright_red(),
-                                    spanned_message.label.bright_blue()
-                                ));
-                            } else {
-                                traceback.push(spanned_message.label.clone());
-                            }
-                        }
-                        traceback.push(error.message());
-                        for help in error.help() {
-                            traceback.push(format!("{}: {}", "help".bold(), help));
-                        }
                         parent_message
                             .new_message("error")
                             .with_content(object! {
                                 "ename" => "Error",
                                 "evalue" => error.message(),
-                                "traceback" => traceback,
+                                "traceback" => user_code_traceback(error),
                             })
                             .send(&self.iopub.lock().unwrap())?;
                     } else {
@@ -466,6 +726,9 @@ impl Server {
                             })
                             .send(&self.iopub.lock().unwrap())?;
                     }
+                    if let Some(summary) = group.related_summary() {
+                        self.emit_related_errors(&summary, &group.related, parent_message)?;
+                    }
                 }
             }
             error => {
@@ -482,6 +745,103 @@ impl Server {
         }
         Ok(())
     }
+
+    /// Shows errors that are probably caused by the same mistake as one that was just shown,
+    /// collapsed under `summary`, so that they don't drown it out.
+    fn emit_related_errors(
+        &self,
+        summary: &str,
+        related: &[CompilationError],
+        parent_message: &JupyterMessage,
+    ) -> Result<()> {
+        let details: Vec<String> = related.iter().map(related_error_text).collect();
+        let mut data: HashMap<String, JsonValue> = HashMap::new();
+        data.insert("text/plain".into(), json::from(summary));
+        data.insert(
+            "text/html".into(),
+            json::from(format!(
+                "<details><summary>{}</summary><pre>{}</pre></details>",
+                escape_html(summary),
+                escape_html(&details.join("\n"))
+            )),
+        );
+        parent_message
+            .new_message("display_data")
+            .with_content(object! {
+                "data" => data,
+                "metadata" => object!(),
+            })
+            .send(&self.iopub.lock().unwrap())?;
+        Ok(())
+    }
+}
+
+/// Returns `error` as plain text, with the places in the user's code that it refers to.
+fn related_error_text(error: &CompilationError) -> String {
+    let mut text = error.message();
+    for spanned_message in error.spanned_messages() {
+        if let Some(span) = &spanned_message.span {
+            text.push_str(&format!(
+                "\n  --> {}:{} {}",
+                span.start_line, span.start_column, spanned_message.label
+            ));
+        }
+    }
+    text
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Returns lines that show where `error` is in the user's code, followed by its message and help.
+fn user_code_traceback(error: &CompilationError) -> Vec<String> {
+    let mut traceback = annotated_lines(error);
+    traceback.push(error.message());
+    traceback.extend(help_lines(error));
+    traceback
+}
+
+/// Returns the lines of the user's code that `error` refers to, with the parts it refers to marked.
+fn annotated_lines(error: &CompilationError) -> Vec<String> {
+    let mut lines = Vec::new();
+    for spanned_message in error.spanned_messages() {
+        for line in &spanned_message.lines {
+            lines.push(line.clone());
+        }
+        if let Some(span) = &spanned_message.span {
+            let mut carrots = String::new();
+            for _ in 1..span.start_column {
+                carrots.push(' ');
+            }
+            for _ in span.start_column..span.end_column {
+                carrots.push('^');
+            }
+            let carrots = if error.level() == "warning" {
+                carrots.yellow()
+            } else {
+                carrots.bright_red()
+            };
+            lines.push(format!(
+                "{} {}",
+                carrots,
+                spanned_message.label.bright_blue()
+            ));
+        } else {
+            lines.push(spanned_message.label.clone());
+        }
+    }
+    lines
+}
+
+fn help_lines(error: &CompilationError) -> Vec<String> {
+    error
+        .help()
+        .into_iter()
+        .map(|help| format!("{}: {}", "help".bold(), help))
+        .collect()
 }
 
 fn comm_open(
@@ -511,6 +871,21 @@ fn comm_open(
                 .unwrap();
         });
         Ok(())
+    } else if message.target_name() == "evcxr-commands" {
+        // Describes the available commands, e.g. so that the frontend can offer a command palette.
+        let commands: Vec<JsonValue> = CommandContext::command_infos()
+            .iter()
+            .map(CommandInfo::to_json)
+            .collect();
+        message
+            .new_message("comm_msg")
+            .without_parent_header()
+            .with_content(object! {
+                "comm_id" => message.comm_id(),
+                "data" => object! { "commands" => commands },
+            })
+            .send(&iopub.lock().unwrap())?;
+        message.comm_close_message().send(&iopub.lock().unwrap())
     } else {
         // Unrecognised comm target, just close the comm.
         message.comm_close_message().send(&iopub.lock().unwrap())
@@ -581,6 +956,7 @@ fn kernel_info() -> JsonValue {
             "codemirror_mode" => "rust",
         },
         "banner" => format!("EvCxR {} - Evaluation Context for Rust", env!("CARGO_PKG_VERSION")),
+        "debugger" => Debugger::is_available(),
         "help_links" => array![
             object!{"text" => "Rust std docs",
                     "url" => "https://doc.rust-lang.org/stable/std/"}
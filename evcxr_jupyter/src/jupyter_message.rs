@@ -154,6 +154,20 @@ impl JupyterMessage {
         self.content["comm_id"].as_str().unwrap_or("")
     }
 
+    /// The ID of the cell being executed, if the frontend sends one, as JupyterLab does.
+    pub(crate) fn cell_id(&self) -> Option<&str> {
+        self.metadata["cellId"].as_str()
+    }
+
+    /// The IDs of cells that were deleted since the last execute request. Sent by JupyterLab.
+    pub(crate) fn deleted_cells(&self) -> Vec<String> {
+        self.metadata["deletedCells"]
+            .members()
+            .filter_map(|id| id.as_str())
+            .map(str::to_owned)
+            .collect()
+    }
+
     // Creates a new child message of this message. ZMQ identities are not transferred.
     pub(crate) fn new_message(&self, msg_type: &str) -> JupyterMessage {
         let mut header = self.header.clone();
@@ -24,6 +24,8 @@ mod control_file;
 mod core;
 mod install;
 mod jupyter_message;
+mod metrics_server;
+mod output_chunks;
 
 fn run(control_file_name: &str) -> Result<()> {
     let config = control_file::Control::parse_file(control_file_name)?;
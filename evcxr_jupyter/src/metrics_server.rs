@@ -0,0 +1,83 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal HTTP server that serves session metrics in the Prometheus text format. This is only
+//! started if `EVCXR_METRICS_ADDR` is set. Every request gets the metrics, regardless of path, so
+//! there's no need for a real HTTP implementation.
+
+use anyhow::Context;
+use anyhow::Result;
+use evcxr::Metrics;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) const METRICS_ADDR_VAR: &str = "EVCXR_METRICS_ADDR";
+
+/// How long a client may take to send its request or receive the response. Connections are
+/// served one at a time, so a client that stalls would otherwise block all the others.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most of a request that we'll read, so that a client can't keep us reading forever.
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+/// Starts serving `metrics` if the environment requests it.
+pub(crate) fn start_if_requested(metrics: Arc<Metrics>) -> Result<()> {
+    let addr = match std::env::var(METRICS_ADDR_VAR) {
+        Ok(addr) if !addr.is_empty() => addr,
+        _ => return Ok(()),
+    };
+    let listener = TcpListener::bind(&addr)
+        .with_context(|| format!("Failed to bind metrics endpoint to {}", addr))?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(error) = serve(stream, &metrics) {
+                eprintln!("Error serving metrics: {}", error);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    // Read and discard the request headers. We respond the same way to everything.
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let body = metrics.to_prometheus_text();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Grouping lines of stdout or stderr into chunks, so that code that prints a lot doesn't send the
+//! frontend a stream message per line. Each stream sends at most one chunk per `MIN_INTERVAL`,
+//! although the first output after a quiet period is sent straight away.
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// Chunks are sent once they reach this size, subject to `MIN_INTERVAL`.
+pub(crate) const MAX_CHUNK_BYTES: usize = 64 * 1024;
+/// The minimum time between chunks from the same stream.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+pub(crate) struct OutputChunker {
+    pending: String,
+    last_sent: Option<Instant>,
+}
+
+impl OutputChunker {
+    pub(crate) fn push(&mut self, line: &str) {
+        self.pending.push_str(line);
+        self.pending.push('\n');
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.pending.len() >= MAX_CHUNK_BYTES
+    }
+
+    /// Returns how long until the pending output may be sent, or None if there's nothing pending.
+    pub(crate) fn time_until_due(&self, now: Instant) -> Option<Duration> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(self.last_sent.map_or(Duration::ZERO, |last_sent| {
+            MIN_INTERVAL.saturating_sub(now.saturating_duration_since(last_sent))
+        }))
+    }
+
+    /// Takes the pending output, recording that it was sent at `now`.
+    pub(crate) fn take(&mut self, now: Instant) -> String {
+        self.last_sent = Some(now);
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OutputChunker;
+    use super::MAX_CHUNK_BYTES;
+    use super::MIN_INTERVAL;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn chunks_are_rate_limited() {
+        let start = Instant::now();
+        let mut chunker = OutputChunker::default();
+        assert_eq!(chunker.time_until_due(start), None);
+        chunker.push("a");
+        chunker.push("b");
+        assert_eq!(chunker.time_until_due(start), Some(Duration::ZERO));
+        assert_eq!(chunker.take(start), "a\nb\n");
+        assert_eq!(chunker.time_until_due(start), None);
+
+        chunker.push("c");
+        let later = start + Duration::from_millis(20);
+        assert_eq!(
+            chunker.time_until_due(later),
+            Some(MIN_INTERVAL - (later - start))
+        );
+        assert_eq!(
+            chunker.time_until_due(start + MIN_INTERVAL * 2),
+            Some(Duration::ZERO)
+        );
+        assert!(!chunker.is_full());
+        chunker.push(&"x".repeat(MAX_CHUNK_BYTES));
+        assert!(chunker.is_full());
+    }
+}
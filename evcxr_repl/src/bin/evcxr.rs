@@ -12,26 +12,39 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use ariadne::{sources, ColorGenerator, Label, Report, ReportKind};
 use colored::*;
 use evcxr::CommandContext;
 use evcxr::CompilationError;
 use evcxr::Error;
+use evcxr::ErrorGroup;
+use evcxr::EvalCallbacks;
+use evcxr::EvalContextConfig;
+use evcxr_repl::latex_to_unicode;
+use evcxr_repl::parse_edit_mode;
+use evcxr_repl::AutoIndentHandler;
 use evcxr_repl::BgInitMutex;
+use evcxr_repl::CapturedOutput;
+use evcxr_repl::DedentHandler;
 use evcxr_repl::EvcxrRustylineHelper;
+use evcxr_repl::Highlighting;
+use evcxr_repl::History;
+use evcxr_repl::LiveDisplay;
+use evcxr_repl::LspServer;
+use evcxr_repl::Pager;
+use evcxr_repl::ReplConfig;
+use evcxr_repl::Transcript;
+use evcxr_repl::TranscriptEntry;
 use rustyline::error::ReadlineError;
-use rustyline::At;
-use rustyline::Cmd;
 use rustyline::EditMode;
 use rustyline::Editor;
+use rustyline::EventHandler;
 use rustyline::KeyCode;
 use rustyline::KeyEvent;
 use rustyline::Modifiers;
-use rustyline::Movement;
-use rustyline::Word;
-use std::fs;
 use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 use structopt::StructOpt;
 
 const PROMPT: &str = ">> ";
@@ -39,16 +52,21 @@ const PROMPT: &str = ">> ";
 struct Repl {
     command_context: Arc<BgInitMutex<CommandContext>>,
     ide_mode: bool,
-    command_history: Vec<(&'static str, &'static str)>,
+    transcript: Transcript,
+    pager: Pager,
+    captured_stdout: CapturedOutput,
+    captured_stderr: CapturedOutput,
 }
 
 fn send_output<T: io::Write + Send + 'static>(
     channel: crossbeam_channel::Receiver<String>,
     mut output: T,
     color: Option<Color>,
+    captured: CapturedOutput,
 ) {
     std::thread::spawn(move || {
         while let Ok(line) = channel.recv() {
+            captured.push_line(&line);
             let status = if let Some(color) = color {
                 writeln!(output, "{}", line.color(color))
             } else {
@@ -62,17 +80,49 @@ fn send_output<T: io::Write + Send + 'static>(
 }
 
 impl Repl {
-    fn new(ide_mode: bool, opt: String) -> Repl {
+    fn new(
+        ide_mode: bool,
+        opt: String,
+        target: Option<String>,
+        runner: Option<String>,
+        prelude: Option<PathBuf>,
+        profiles: Vec<String>,
+    ) -> Repl {
+        let captured_stdout = CapturedOutput::default();
+        let captured_stderr = CapturedOutput::default();
+        let (stdout_capture, stderr_capture) = (captured_stdout.clone(), captured_stderr.clone());
         let initialize = move || -> Result<CommandContext, Error> {
-            let (mut command_context, outputs) = CommandContext::new()?;
+            let (mut command_context, outputs) = CommandContext::with_config(EvalContextConfig {
+                prelude,
+                profiles,
+                ..EvalContextConfig::default()
+            })?;
 
-            send_output(outputs.stdout, io::stdout(), None);
-            send_output(outputs.stderr, io::stderr(), Some(Color::BrightRed));
+            send_output(outputs.stdout, io::stdout(), None, stdout_capture);
+            send_output(
+                outputs.stderr,
+                io::stderr(),
+                Some(Color::BrightRed),
+                stderr_capture,
+            );
+            // Ctrl-C while code is running interrupts it, rather than exiting. The first asks the
+            // code to stop, a second kills the subprocess.
+            command_context.interrupt_handle().interrupt_on_sigint();
+            // We only print plain text, so there's no point in user code producing anything else,
+            // except LaTeX, which we render as unicode.
+            command_context
+                .set_mime_preferences(vec!["text/plain".to_owned(), "text/latex".to_owned()]);
             command_context.execute(":load_config --quiet")?;
             if !opt.is_empty() {
                 // Ignore failure
                 command_context.set_opt_level(&opt).ok();
             }
+            if target.is_some() {
+                command_context.set_target(target);
+            }
+            if runner.is_some() {
+                command_context.set_runner(runner);
+            }
             Ok(command_context)
         };
         let command_context = Arc::new(BgInitMutex::new(move || {
@@ -85,22 +135,67 @@ impl Repl {
         Repl {
             command_context,
             ide_mode,
-            command_history: vec![],
+            transcript: Transcript::default(),
+            pager: Pager::default(),
+            captured_stdout,
+            captured_stderr,
         }
     }
-    fn execute(&mut self, to_run: &str) {
-        let execution_result = self.command_context.lock().execute(to_run);
-        let command_id =
-            Box::leak(format!("command_{}", self.command_history.len()).into_boxed_str());
-        let command_text = Box::leak(to_run.to_string().into_boxed_str());
-        self.command_history.push((command_id, command_text));
+    /// Evaluates `to_run`. Output that's Rust code is highlighted as specified by `highlighting`.
+    fn execute(&mut self, to_run: &str, highlighting: &Highlighting) {
+        let start = Instant::now();
+        let mut command_context = self.command_context.lock();
+        // Anything written since the last evaluation, e.g. while loading the config, isn't part of
+        // this one.
+        self.captured_stdout.take();
+        self.captured_stderr.take();
+        let live_display = LiveDisplay::new(io::stdout());
+        let execution_result = command_context.execute_with_callbacks(
+            to_run,
+            &mut EvalCallbacks {
+                input_reader: &read_input,
+                display_sender: &|update| live_display.show(&update),
+                output_clearer: &|_| live_display.clear(),
+                ..EvalCallbacks::default()
+            },
+        );
+        live_display.finish();
+        // The transcript records what was entered if it can't be formatted, e.g. because it
+        // doesn't parse.
+        let input = if command_context.autoformat() {
+            command_context
+                .format_code(to_run)
+                .unwrap_or_else(|_| to_run.to_owned())
+        } else {
+            to_run.to_owned()
+        };
+        drop(command_context);
+        let mut entry = TranscriptEntry {
+            input,
+            duration: start.elapsed(),
+            ..TranscriptEntry::default()
+        };
         let success = match execution_result {
-            Ok(output) => {
-                if let Some(text) = output.get("text/plain") {
-                    println!("{}", text);
+            Ok(mut output) => {
+                // Compiler warnings and lints from `:clippy`.
+                let warnings = std::mem::take(&mut output.warnings);
+                if !warnings.is_empty() {
+                    entry.errors = plain_errors(&warnings);
+                    self.display_errors(to_run, warnings);
                 }
-                if let Some(duration) = output.timing {
-                    println!("{}", format!("Took {}ms", duration.as_millis()).blue());
+                if let Some(latex) = output.get("text/latex") {
+                    let text = latex_to_unicode(latex);
+                    self.pager.print(&text);
+                    entry.output = text;
+                } else if let Some(text) = output.get("text/plain") {
+                    match output.get("text/rust") {
+                        Some(code) => self.pager.print(&highlighting.highlight(code, usize::MAX)),
+                        None => self.pager.print(text),
+                    }
+                    entry.output = text.to_owned();
+                }
+                if let Some(timing) = output.timing {
+                    println!("{}", timing.to_string().blue());
 
                     for phase in output.phases {
                         println!(
@@ -109,17 +204,25 @@ impl Repl {
                         );
                     }
                 }
+                if let Some(memory) = output.memory {
+                    println!("{}", memory.to_string().blue());
+                }
                 true
             }
             Err(evcxr::Error::CompilationErrors(errors)) => {
+                entry.errors = plain_errors(&errors);
                 self.display_errors(to_run, errors);
                 false
             }
             Err(err) => {
                 eprintln!("{}", format!("{}", err).bright_red());
+                entry.errors = err.to_string();
                 false
             }
         };
+        entry.output.insert_str(0, &self.captured_stdout.take());
+        entry.errors.insert_str(0, &self.captured_stderr.take());
+        self.transcript.record(entry);
 
         if self.ide_mode {
             let success_marker = if success { "\u{0091}" } else { "\u{0092}" };
@@ -127,54 +230,78 @@ impl Repl {
         }
     }
 
-    fn display_errors(&mut self, source: &str, errors: Vec<CompilationError>) {
-        let mut last_span_lines: &Vec<String> = &vec![];
-        for error in &errors {
-            if error.is_from_user_code() {
-                let mut builder =
-                    Report::build(ReportKind::Error, self.command_history.last().unwrap().0, 0)
-                        .with_message(&error.message());
-                let mut colors = ColorGenerator::new();
-                if let Some(code) = error.code() {
-                    builder = builder.with_code(code);
-                }
-                for spanned_message in error.spanned_messages() {
-                    if let Some(span) = &spanned_message.span {
-                        builder = builder.with_label(
-                            Label::new((
-                                self.command_history.last().unwrap().0,
-                                span.byte_start..span.byte_end,
-                            ))
-                            .with_message(&spanned_message.label)
-                            .with_color(colors.next())
-                            .with_order(10),
-                        );
-                    } else {
-                        // Our error originates from both user-code and generated
-                        // code.
-                        println!("{}", spanned_message.label.bright_blue());
-                    }
-                }
-                builder
-                    .finish()
-                    .print(sources(self.command_history.clone().into_iter()))
-                    .unwrap();
-                //println!("{}", error.message().bright_red());
-                for help in error.help() {
-                    println!("{} {}", "help:".bold(), help);
-                }
-                if let Some(extra_hint) = error.evcxr_extra_hint() {
-                    println!("{}", extra_hint);
-                }
-            } else {
+    fn display_errors(&self, source: &str, errors: Vec<CompilationError>) {
+        for group in evcxr::group_errors(errors) {
+            self.display_error(source, &group.primary);
+            if let Some(summary) = group.related_summary() {
                 println!(
-                    "A compilation error was found in code we generated.\n\
-                     Ideally this shouldn't happen. Type :last_error_json to see details.\n{}",
-                    error.rendered()
+                    "{}",
+                    format!("... and {}{}", summary, related_locations(&group)).bright_blue()
                 );
             }
         }
     }
+
+    fn display_error(&self, source: &str, error: &CompilationError) {
+        if error.is_from_user_code() {
+            println!(
+                "{}",
+                error.render(source, colored::control::SHOULD_COLORIZE.should_colorize())
+            );
+        } else {
+            println!(
+                "A compilation error was found in code we generated.\n\
+                 Ideally this shouldn't happen. Type :last_error_json to see details.\n{}",
+                error.rendered()
+            );
+        }
+    }
+}
+
+/// Returns where the related errors of `group` are, e.g. " at 3:1, 5:7".
+fn related_locations(group: &ErrorGroup) -> String {
+    let locations: Vec<String> = group
+        .related
+        .iter()
+        .filter_map(|error| error.primary_spanned_message()?.span)
+        .map(|span| format!("{}:{}", span.start_line, span.start_column))
+        .collect();
+    if locations.is_empty() {
+        String::new()
+    } else {
+        format!(" at {}", locations.join(", "))
+    }
+}
+
+/// Returns `errors` as plain text, for the transcript.
+fn plain_errors(errors: &[CompilationError]) -> String {
+    use std::fmt::Write;
+
+    let mut text = String::new();
+    for error in errors {
+        if !error.is_from_user_code() {
+            text.push_str(&error.rendered());
+            continue;
+        }
+        match error.code() {
+            Some(code) => write!(text, "{}[{}]: ", error.level(), code).unwrap(),
+            None => write!(text, "{}: ", error.level()).unwrap(),
+        }
+        writeln!(text, "{}", error.message()).unwrap();
+        for spanned_message in error.spanned_messages() {
+            if let Some(span) = &spanned_message.span {
+                write!(text, "  --> {}:{}", span.start_line, span.start_column).unwrap();
+                if !spanned_message.label.is_empty() {
+                    write!(text, " {}", spanned_message.label).unwrap();
+                }
+                text.push('\n');
+            }
+        }
+        for help in error.help() {
+            writeln!(text, "help: {}", help).unwrap();
+        }
+    }
+    text
 }
 
 /// Returns a 0-based grapheme index corresponding to the supplied 0-based character column.
@@ -211,6 +338,14 @@ fn readline_direct(prompt: &str) -> rustyline::Result<String> {
     }
 }
 
+/// Reads a line of input for user code, e.g. when it reads from stdin. Passwords are echoed, since
+/// we don't have a way to turn echo off.
+fn read_input(prompt: &str, _is_password: bool) -> String {
+    readline_direct(prompt)
+        .map(|line| line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+        .unwrap_or_default()
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "evcxr")]
 struct Options {
@@ -221,27 +356,98 @@ struct Options {
     /// Optimization level (0, 1 or 2)
     #[structopt(long, default_value = "")]
     opt: String,
+    /// Defaults to `edit_mode` from repl.conf, or emacs if that's not set.
     #[structopt(
         long,
         parse(try_from_str = parse_edit_mode),
         possible_values = &["vi", "emacs"],
-        default_value = "emacs"
      )]
-    edit_mode: rustyline::EditMode,
+    edit_mode: Option<rustyline::EditMode>,
+    /// What to print on startup. `about` prints the full `:about` report.
+    #[structopt(long, possible_values = &["short", "about", "none"], default_value = "short")]
+    banner: String,
+    /// Target to build for, if not the host. e.g. wasm32-wasi
+    #[structopt(long)]
+    target: Option<String>,
+    /// Command used to run code built for `--target`. e.g. "qemu-aarch64 -L /usr/aarch64-linux-gnu"
+    #[structopt(long)]
+    runner: Option<String>,
+    /// A file of commands and code to evaluate at startup, after init.evcxr and prelude.rs
+    #[structopt(long, parse(from_os_str))]
+    prelude: Option<PathBuf>,
+    /// A profile from profiles.evcxr to use at startup. May be given more than once
+    #[structopt(long = "profile", number_of_values = 1)]
+    profiles: Vec<String>,
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// Serve the Language Server Protocol on stdin and stdout, giving editors diagnostics,
+    /// completions and hover for code as it would be evaluated in a live session.
+    Lsp,
+}
+
+fn run_lsp_server(options: &Options) -> ! {
+    let result = CommandContext::with_config(EvalContextConfig {
+        prelude: options.prelude.clone(),
+        profiles: options.profiles.clone(),
+        ..EvalContextConfig::default()
+    })
+    .and_then(|(mut command_context, outputs)| {
+        command_context.execute(":load_config --quiet")?;
+        if !options.opt.is_empty() {
+            // Ignore failure
+            command_context.set_opt_level(&options.opt).ok();
+        }
+        if options.target.is_some() {
+            command_context.set_target(options.target.clone());
+        }
+        if options.runner.is_some() {
+            command_context.set_runner(options.runner.clone());
+        }
+        Ok((command_context, outputs))
+    });
+    let (command_context, outputs) = match result {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("Initialization failed: {}", error);
+            std::process::exit(1);
+        }
+    };
+    let stdin = io::stdin();
+    let mut server = LspServer::new(command_context, outputs, io::stdout());
+    match server.run(&mut stdin.lock()) {
+        Ok(true) => std::process::exit(0),
+        Ok(false) => std::process::exit(1),
+        Err(error) => {
+            eprintln!("Language server failed: {}", error);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
     evcxr::runtime_hook();
 
     let options = Options::from_args();
+    if let Some(Subcommand::Lsp) = options.command {
+        run_lsp_server(&options);
+    }
 
     #[cfg(windows)]
     colored::control::set_virtual_terminal(true).ok();
 
-    println!("Welcome to evcxr. For help, type :help");
+    if options.banner != "none" {
+        println!(
+            "Welcome to evcxr {}. For help, type :help. For environment details, type :about",
+            env!("CARGO_PKG_VERSION")
+        );
+    }
     // Print this now, because we silence `:load_config` (writing to stdout
     // interfers with rustyline somewhat).
-    if let Some(cfg) = evcxr::config_dir() {
+    if let (Some(cfg), true) = (evcxr::config_dir(), options.banner != "none") {
         let init = cfg.join("init.evcxr");
         if init.exists() {
             println!("Startup commands will be loaded from {}", init.display());
@@ -251,8 +457,24 @@ fn main() {
             println!("Prelude will be loaded from {}", prelude.display());
         }
     }
-    let mut repl = Repl::new(options.ide_mode, options.opt.clone());
-    let mut config_builder = match options.edit_mode {
+    if let (Some(prelude), true) = (&options.prelude, options.banner != "none") {
+        println!("Prelude will be loaded from {}", prelude.display());
+    }
+    let mut repl = Repl::new(
+        options.ide_mode,
+        options.opt.clone(),
+        options.target.clone(),
+        options.runner.clone(),
+        options.prelude.clone(),
+        options.profiles.clone(),
+    );
+    let repl_config = ReplConfig::load();
+    repl.pager = Pager::new(repl_config.pager);
+    if options.banner == "about" {
+        repl.execute(":about", &repl_config.highlighting);
+    }
+    let edit_mode = options.edit_mode.unwrap_or(repl_config.edit_mode);
+    let mut config_builder = match edit_mode {
         EditMode::Vi => {
             rustyline::Config::builder()
                 .edit_mode(EditMode::Vi)
@@ -260,30 +482,38 @@ fn main() {
         }
         _ => rustyline::Config::builder(), // default edit_mode is emacs
     };
+    // Our own history files hold up to 1000 entries each, which we want to be searchable.
+    config_builder = config_builder.max_history_size(2000);
     if std::env::var("EVCXR_COMPLETION_TYPE").as_deref() != Ok("circular") {
         config_builder = config_builder.completion_type(rustyline::CompletionType::List);
     }
     let config = config_builder.build();
     let mut editor = Editor::<EvcxrRustylineHelper>::with_config(config);
-    editor.bind_sequence(
-        KeyEvent(KeyCode::Left, Modifiers::CTRL),
-        Cmd::Move(Movement::BackwardWord(1, Word::Big)),
-    );
-    editor.bind_sequence(
-        KeyEvent(KeyCode::Right, Modifiers::CTRL),
-        Cmd::Move(Movement::ForwardWord(1, At::AfterEnd, Word::Big)),
-    );
-    editor.set_helper(Some(EvcxrRustylineHelper::new(Arc::clone(
-        &repl.command_context,
-    ))));
-    let mut opt_history_file = None;
-    let config_dir = evcxr::config_dir();
-    if let Some(config_dir) = &config_dir {
-        fs::create_dir_all(config_dir).ok();
-        let history_file = config_dir.join("history.txt");
-        editor.load_history(&history_file).ok();
-        opt_history_file = Some(history_file);
+    if repl_config.auto_indent {
+        editor.bind_sequence(
+            KeyEvent(KeyCode::Enter, Modifiers::NONE),
+            EventHandler::Conditional(Box::new(AutoIndentHandler::new(repl_config.clone()))),
+        );
+        for closer in ['}', ')', ']'] {
+            editor.bind_sequence(
+                KeyEvent::new(closer, Modifiers::NONE),
+                EventHandler::Conditional(Box::new(DedentHandler::new(
+                    repl_config.clone(),
+                    closer,
+                ))),
+            );
+        }
     }
+    repl_config.keybindings.apply(&mut editor);
+    editor.set_helper(Some(EvcxrRustylineHelper::new(
+        Arc::clone(&repl.command_context),
+        repl_config.clone(),
+    )));
+    let mut history = History::load(
+        repl_config.history_scope,
+        repl_config.history_file.as_deref(),
+    );
+    load_history(&mut editor, &history);
     loop {
         let prompt = format!("{}", PROMPT.yellow());
         let readline = if options.disable_readline {
@@ -293,8 +523,57 @@ fn main() {
         };
         match readline {
             Ok(line) => {
-                editor.add_history_entry(line.clone());
-                repl.execute(&line);
+                history.add(&line);
+                // Reload rather than just adding the line, so that any earlier copy is removed.
+                load_history(&mut editor, &history);
+                if let Some(args) = line.trim().strip_prefix(":history") {
+                    match history.handle_command(args) {
+                        Ok(output) => println!("{}", output),
+                        Err(error) => eprintln!("{}", error.bright_red()),
+                    }
+                    load_history(&mut editor, &history);
+                } else if let Some(args) = line.trim().strip_prefix(":save_transcript") {
+                    match repl.transcript.handle_command(args) {
+                        Ok(output) => println!("{}", output),
+                        Err(error) => eprintln!("{}", error.bright_red()),
+                    }
+                } else if let Some(args) = line.trim().strip_prefix(":pager") {
+                    match repl.pager.handle_command(args) {
+                        Ok(output) => println!("{}", output),
+                        Err(error) => eprintln!("{}", error.bright_red()),
+                    }
+                } else if let Some(number) = CommandContext::cell_to_format(&line) {
+                    let formatted = repl.command_context.lock().format_input(number);
+                    match formatted {
+                        Ok(formatted) => {
+                            let helper = editor.helper().expect("helper is always set");
+                            println!(
+                                "{}",
+                                helper.highlighting().highlight(&formatted, usize::MAX)
+                            );
+                            // Offer the formatted code first, so that it can be edited and rerun.
+                            history.add(&formatted);
+                            load_history(&mut editor, &history);
+                        }
+                        Err(error) => eprintln!("{}", format!("{}", error).bright_red()),
+                    }
+                } else if line.trim() == ":keybindings" {
+                    println!(
+                        "{}",
+                        repl_config
+                            .keybindings
+                            .summary(edit_mode, repl_config.auto_indent)
+                    );
+                } else if let Some(args) = line.trim().strip_prefix(":color") {
+                    let helper = editor.helper_mut().expect("helper is always set");
+                    match helper.handle_color_command(args) {
+                        Ok(output) => print!("{}", output),
+                        Err(error) => eprintln!("{}", error.bright_red()),
+                    }
+                } else {
+                    let helper = editor.helper().expect("helper is always set");
+                    repl.execute(&line, helper.highlighting());
+                }
             }
             Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
             Err(err) => {
@@ -303,18 +582,15 @@ fn main() {
             }
         }
     }
-    if let Some(history_file) = &opt_history_file {
-        editor.save_history(&history_file).ok();
-    }
+    history.save();
 }
 
-fn parse_edit_mode(src: &str) -> Result<EditMode, &str> {
-    let mode = match src {
-        "vi" => EditMode::Vi,
-        "emacs" => EditMode::Emacs,
-        _ => return Err("only 'vi' and 'emacs' are supported"),
-    };
-    Ok(mode)
+/// Replaces the editor's history with the entries for the current history scope.
+fn load_history(editor: &mut Editor<EvcxrRustylineHelper>, history: &History) {
+    editor.clear_history();
+    for entry in history.entries() {
+        editor.add_history_entry(entry);
+    }
 }
 
 #[cfg(feature = "mimalloc")]
@@ -0,0 +1,204 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Settings that only affect the REPL's line editor, as opposed to evaluation. These are read from
+//! `repl.conf` in the evcxr config directory. Each line has the form `key = value`. Blank lines and
+//! lines starting with `#` are ignored, and key bindings are given as `bind <key> = <action>`.
+
+use crate::highlight::Highlighting;
+use crate::history::HistoryScope;
+use crate::keybindings;
+use crate::keybindings::Keybindings;
+use rustyline::EditMode;
+use std::path::Path;
+use std::path::PathBuf;
+
+const CONFIG_FILE_NAME: &str = "repl.conf";
+
+#[derive(Clone, Debug)]
+pub struct ReplConfig {
+    /// Whether to offer closing brackets and quotes as a hint, which can be accepted with the right
+    /// arrow key.
+    pub auto_close: bool,
+    /// Whether to indent continuation lines based on how many brackets are open, and to dedent
+    /// when a closing bracket is typed on an otherwise blank line.
+    pub auto_indent: bool,
+    /// Number of spaces per indentation level.
+    pub indent_width: usize,
+    /// Which history the editor offers when the REPL starts.
+    pub history_scope: HistoryScope,
+    /// Where to save the global history, if not in the evcxr config directory.
+    pub history_file: Option<PathBuf>,
+    /// Which set of rustyline's default key bindings to use. Overridden by `--edit-mode`.
+    pub edit_mode: EditMode,
+    /// Key bindings added to those of the edit mode.
+    pub keybindings: Keybindings,
+    /// Whether input is syntax highlighted as it's typed, and with what colors.
+    pub highlighting: Highlighting,
+    /// Whether results that don't fit on the screen are shown through a pager.
+    pub pager: bool,
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        ReplConfig {
+            auto_close: false,
+            auto_indent: true,
+            indent_width: 4,
+            history_scope: HistoryScope::Merged,
+            history_file: None,
+            edit_mode: EditMode::Emacs,
+            keybindings: Keybindings::default(),
+            highlighting: Highlighting::default(),
+            pager: false,
+        }
+    }
+}
+
+impl ReplConfig {
+    /// Loads the config from the evcxr config directory, falling back to defaults for anything
+    /// that isn't specified. Problems with the file are reported on stderr, but aren't fatal.
+    pub fn load() -> ReplConfig {
+        match evcxr::config_dir() {
+            Some(dir) => Self::load_from(&dir.join(CONFIG_FILE_NAME)),
+            None => ReplConfig::default(),
+        }
+    }
+
+    fn load_from(path: &Path) -> ReplConfig {
+        let mut config = ReplConfig::default();
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            for error in config.apply(&contents) {
+                eprintln!("{}: {}", path.display(), error);
+            }
+        }
+        config
+    }
+
+    /// Applies settings from `contents`, returning a description of any lines that couldn't be
+    /// understood.
+    pub fn apply(&mut self, contents: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (line_index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.trim()),
+                None => Err("expected `key = value`".to_owned()),
+            };
+            if let Err(error) = result {
+                errors.push(format!("line {}: {}", line_index + 1, error));
+            }
+        }
+        errors
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "auto_close" => self.auto_close = parse_bool(value)?,
+            "auto_indent" => self.auto_indent = parse_bool(value)?,
+            "indent_width" => {
+                self.indent_width = value
+                    .parse()
+                    .map_err(|_| format!("invalid indent_width `{}`", value))?
+            }
+            "history_scope" => self.history_scope = value.parse()?,
+            "history_file" => self.history_file = Some(PathBuf::from(value)),
+            "edit_mode" => self.edit_mode = keybindings::parse_edit_mode(value)?,
+            "highlight" => self.highlighting.enabled = parse_bool(value)?,
+            "pager" => self.pager = parse_bool(value)?,
+            _ => {
+                if let Some(class_name) = key.strip_suffix("_color") {
+                    self.highlighting.set_color(class_name, value)?;
+                } else if let Some(key_name) = key.strip_prefix("bind ") {
+                    self.keybindings.bind(key_name, value)?;
+                } else {
+                    return Err(format!("unknown setting `{}`", key));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "1" | "on" => Ok(true),
+        "false" | "0" | "off" => Ok(false),
+        _ => Err(format!("expected true or false, got `{}`", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplConfig;
+    use crate::history::HistoryScope;
+    use rustyline::EditMode;
+    use std::path::Path;
+
+    #[test]
+    fn test_apply() {
+        let mut config = ReplConfig::default();
+        let errors = config.apply(
+            "# comment\n\
+             auto_close = true\n\
+             \n\
+             indent_width=2\n\
+             history_scope = project\n\
+             history_file = /tmp/evcxr history\n\
+             bogus = 1\n\
+             auto_indent maybe\n\
+             highlight = off\n\
+             keyword_color = bright_red\n\
+             keywords_color = red\n\
+             edit_mode = vi\n\
+             bind ctrl-k = kill_line\n\
+             bind ctrl-q = quit\n\
+             pager = on\n",
+        );
+        assert!(config.auto_close);
+        assert!(config.pager);
+        assert!(!config.highlighting.enabled);
+        assert_eq!(config.edit_mode, EditMode::Vi);
+        assert!(config
+            .keybindings
+            .summary(config.edit_mode, false)
+            .contains("  ctrl-k      kill_line\n"));
+        assert_eq!(config.indent_width, 2);
+        assert_eq!(config.history_scope, HistoryScope::Project);
+        assert_eq!(
+            config.history_file.as_deref(),
+            Some(Path::new("/tmp/evcxr history"))
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "line 7: unknown setting `bogus`".to_owned(),
+                "line 8: expected `key = value`".to_owned(),
+                "line 11: unknown token class `keywords`, expected one of: keyword, string, \
+                 number, comment, lifetime, macro, bracket"
+                    .to_owned(),
+                "line 14: unknown action `quit`, expected insert:<text> or one of: accept_line, \
+                 newline, complete, kill_line, kill_whole_line, backward_kill_word, \
+                 beginning_of_line, end_of_line, backward_word, forward_word, previous_history, \
+                 next_history, history_search_backward, history_search_forward, \
+                 reverse_search_history, forward_search_history, transpose_chars, undo, yank, \
+                 clear_screen, interrupt, noop"
+                    .to_owned(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Syntax highlighting of input as it's typed. Like `indent`, this uses rust-analyzer's parser to
+//! tokenize the input, so brackets in strings and comments are ignored when looking for the bracket
+//! that matches the one at the cursor.
+
+use colored::Color;
+use colored::Colorize;
+use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxToken;
+use std::borrow::Cow;
+use std::fmt::Write;
+
+const CLASS_COUNT: usize = 7;
+
+/// The kinds of token that can be given their own color.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum TokenClass {
+    Keyword,
+    String,
+    Number,
+    Comment,
+    Lifetime,
+    Macro,
+    /// The bracket matching the one next to the cursor.
+    Bracket,
+}
+
+impl TokenClass {
+    const ALL: [TokenClass; CLASS_COUNT] = [
+        TokenClass::Keyword,
+        TokenClass::String,
+        TokenClass::Number,
+        TokenClass::Comment,
+        TokenClass::Lifetime,
+        TokenClass::Macro,
+        TokenClass::Bracket,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            TokenClass::Keyword => "keyword",
+            TokenClass::String => "string",
+            TokenClass::Number => "number",
+            TokenClass::Comment => "comment",
+            TokenClass::Lifetime => "lifetime",
+            TokenClass::Macro => "macro",
+            TokenClass::Bracket => "bracket",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<TokenClass> {
+        TokenClass::ALL
+            .iter()
+            .copied()
+            .find(|class| class.name() == name)
+    }
+}
+
+const COLORS: &[(&str, Color)] = &[
+    ("black", Color::Black),
+    ("red", Color::Red),
+    ("green", Color::Green),
+    ("yellow", Color::Yellow),
+    ("blue", Color::Blue),
+    ("magenta", Color::Magenta),
+    ("cyan", Color::Cyan),
+    ("white", Color::White),
+    ("bright_black", Color::BrightBlack),
+    ("bright_red", Color::BrightRed),
+    ("bright_green", Color::BrightGreen),
+    ("bright_yellow", Color::BrightYellow),
+    ("bright_blue", Color::BrightBlue),
+    ("bright_magenta", Color::BrightMagenta),
+    ("bright_cyan", Color::BrightCyan),
+    ("bright_white", Color::BrightWhite),
+];
+
+/// Value that can be given instead of a color to leave a class of token uncolored.
+const NO_COLOR: &str = "none";
+
+/// Whether and how input is highlighted.
+#[derive(Clone, Debug)]
+pub struct Highlighting {
+    pub enabled: bool,
+    /// The color of each class of token, indexed like `TokenClass::ALL`.
+    colors: [Option<Color>; CLASS_COUNT],
+}
+
+impl Default for Highlighting {
+    fn default() -> Self {
+        Highlighting {
+            enabled: true,
+            colors: [
+                Some(Color::Magenta),
+                Some(Color::Green),
+                Some(Color::Cyan),
+                Some(Color::BrightBlack),
+                Some(Color::BrightBlue),
+                Some(Color::Blue),
+                Some(Color::BrightYellow),
+            ],
+        }
+    }
+}
+
+impl Highlighting {
+    /// Sets the color for the class of token named `class_name`, e.g. "keyword", to the color
+    /// named `color_name`, e.g. "bright_blue" or "none".
+    pub fn set_color(&mut self, class_name: &str, color_name: &str) -> Result<(), String> {
+        let class = TokenClass::from_name(class_name).ok_or_else(|| {
+            format!(
+                "unknown token class `{}`, expected one of: {}",
+                class_name,
+                TokenClass::ALL.map(TokenClass::name).join(", ")
+            )
+        })?;
+        let color = if color_name == NO_COLOR {
+            None
+        } else {
+            let color_name = color_name.replace(' ', "_").to_lowercase();
+            let color = COLORS
+                .iter()
+                .find(|(name, _)| *name == color_name)
+                .map(|(_, color)| *color)
+                .ok_or_else(|| format!("unknown color `{}`", color_name))?;
+            Some(color)
+        };
+        self.colors[class as usize] = color;
+        Ok(())
+    }
+
+    fn color(&self, class: TokenClass) -> Option<Color> {
+        self.colors[class as usize]
+    }
+
+    /// Handles the `:color` command. With no arguments, reports the current settings. Otherwise
+    /// turns highlighting on or off, or sets the color of a class of token.
+    pub fn handle_command(&mut self, args: &str) -> Result<String, String> {
+        let args: Vec<&str> = args.split_whitespace().collect();
+        match args.as_slice() {
+            [] => return Ok(self.summary()),
+            ["on"] => self.enabled = true,
+            ["off"] => self.enabled = false,
+            [class_name, color_name] => self.set_color(class_name, color_name)?,
+            _ => {
+                return Err(
+                    "Usage: :color [on|off] or :color <token class> <color name>".to_owned(),
+                )
+            }
+        }
+        Ok(self.summary())
+    }
+
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "Highlighting is {}\n",
+            if self.enabled { "on" } else { "off" }
+        );
+        for class in TokenClass::ALL {
+            let color_name = self
+                .color(class)
+                .and_then(|color| COLORS.iter().find(|(_, c)| *c == color))
+                .map_or(NO_COLOR, |(name, _)| *name);
+            writeln!(summary, "  {}: {}", class.name(), color_name).unwrap();
+        }
+        summary
+    }
+
+    /// Returns `source` with ANSI color codes added. If there's a bracket under or just before
+    /// `cursor`, then the bracket that matches it is highlighted.
+    pub fn highlight<'l>(&self, source: &'l str, cursor: usize) -> Cow<'l, str> {
+        if !self.enabled {
+            return Cow::Borrowed(source);
+        }
+        let tokens = tokens(source);
+        let matching = matching_bracket(&tokens, cursor);
+        let mut highlighted = String::with_capacity(source.len());
+        for (index, token) in tokens.iter().enumerate() {
+            let class = if Some(index) == matching {
+                Some(TokenClass::Bracket)
+            } else {
+                token_class(&tokens, index)
+            };
+            match class.and_then(|class| self.color(class)) {
+                Some(color) if class == Some(TokenClass::Bracket) => {
+                    write!(highlighted, "{}", token.text().color(color).bold()).unwrap()
+                }
+                Some(color) => write!(highlighted, "{}", token.text().color(color)).unwrap(),
+                None => highlighted.push_str(token.text()),
+            }
+        }
+        Cow::Owned(highlighted)
+    }
+}
+
+fn tokens(source: &str) -> Vec<SyntaxToken> {
+    SourceFile::parse(source)
+        .syntax_node()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .collect()
+}
+
+fn token_class(tokens: &[SyntaxToken], index: usize) -> Option<TokenClass> {
+    let kind = tokens[index].kind();
+    match kind {
+        SyntaxKind::STRING | SyntaxKind::BYTE_STRING | SyntaxKind::CHAR | SyntaxKind::BYTE => {
+            Some(TokenClass::String)
+        }
+        SyntaxKind::INT_NUMBER | SyntaxKind::FLOAT_NUMBER => Some(TokenClass::Number),
+        SyntaxKind::COMMENT => Some(TokenClass::Comment),
+        SyntaxKind::LIFETIME_IDENT => Some(TokenClass::Lifetime),
+        SyntaxKind::IDENT
+            if tokens
+                .get(index + 1)
+                .map_or(false, |next| next.kind() == SyntaxKind::BANG) =>
+        {
+            Some(TokenClass::Macro)
+        }
+        _ if kind.is_keyword() => Some(TokenClass::Keyword),
+        _ => None,
+    }
+}
+
+/// If `kind` is a bracket, returns whether it's an opening bracket, along with the kind of bracket
+/// that would pair with it.
+fn bracket(kind: SyntaxKind) -> Option<(bool, SyntaxKind)> {
+    match kind {
+        SyntaxKind::L_PAREN => Some((true, SyntaxKind::R_PAREN)),
+        SyntaxKind::L_BRACK => Some((true, SyntaxKind::R_BRACK)),
+        SyntaxKind::L_CURLY => Some((true, SyntaxKind::R_CURLY)),
+        SyntaxKind::R_PAREN => Some((false, SyntaxKind::L_PAREN)),
+        SyntaxKind::R_BRACK => Some((false, SyntaxKind::L_BRACK)),
+        SyntaxKind::R_CURLY => Some((false, SyntaxKind::L_CURLY)),
+        _ => None,
+    }
+}
+
+/// Returns the index of the token that matches the bracket under the cursor, or failing that, the
+/// bracket just before the cursor.
+fn matching_bracket(tokens: &[SyntaxToken], cursor: usize) -> Option<usize> {
+    let is_bracket_at = |index: &usize| bracket(tokens[*index].kind()).is_some();
+    let start = tokens
+        .iter()
+        .position(|token| usize::from(token.text_range().start()) == cursor)
+        .filter(is_bracket_at)
+        .or_else(|| {
+            tokens
+                .iter()
+                .position(|token| usize::from(token.text_range().end()) == cursor)
+                .filter(is_bracket_at)
+        })?;
+    let (is_opening, _) = bracket(tokens[start].kind())?;
+    // Brackets between the two must be balanced, so we keep a stack of the ones we expect to see.
+    let mut expected = Vec::new();
+    let candidates: Box<dyn Iterator<Item = usize>> = if is_opening {
+        Box::new(start..tokens.len())
+    } else {
+        Box::new((0..=start).rev())
+    };
+    for index in candidates {
+        let kind = tokens[index].kind();
+        match bracket(kind) {
+            Some((opening, counterpart)) if opening == is_opening => expected.push(counterpart),
+            Some(_) => {
+                if expected.pop() != Some(kind) {
+                    return None;
+                }
+                if expected.is_empty() {
+                    return Some(index);
+                }
+            }
+            None => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matching_bracket;
+    use super::token_class;
+    use super::tokens;
+    use super::Highlighting;
+    use super::TokenClass;
+
+    fn matching_offset(source: &str, cursor: usize) -> Option<usize> {
+        let tokens = tokens(source);
+        matching_bracket(&tokens, cursor).map(|index| tokens[index].text_range().start().into())
+    }
+
+    #[test]
+    fn test_matching_bracket() {
+        let source = "foo(a[1], \")\", { b })";
+        // Under the cursor.
+        assert_eq!(matching_offset(source, 3), Some(20));
+        assert_eq!(matching_offset(source, 20), Some(3));
+        assert_eq!(matching_offset(source, 5), Some(7));
+        // Just before the cursor.
+        assert_eq!(matching_offset(source, 4), Some(20));
+        assert_eq!(matching_offset(source, 21), Some(3));
+        assert_eq!(matching_offset(source, 16), Some(19));
+        // Brackets in strings don't count.
+        assert_eq!(matching_offset(source, 11), None);
+        // Nothing next to the cursor.
+        assert_eq!(matching_offset(source, 1), None);
+        // Unclosed or mismatched.
+        assert_eq!(matching_offset("foo(1", 3), None);
+        assert_eq!(matching_offset("foo(1]", 3), None);
+    }
+
+    #[test]
+    fn test_token_class() {
+        let tokens = tokens("let s: &'static str = format!(\"{}\", 1.5); // x");
+        let classes: Vec<(&str, TokenClass)> = tokens
+            .iter()
+            .enumerate()
+            .filter_map(|(index, token)| Some((token.text(), token_class(&tokens, index)?)))
+            .collect();
+        assert_eq!(
+            classes,
+            vec![
+                ("let", TokenClass::Keyword),
+                ("'static", TokenClass::Lifetime),
+                ("format", TokenClass::Macro),
+                ("\"{}\"", TokenClass::String),
+                ("1.5", TokenClass::Number),
+                ("// x", TokenClass::Comment),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handle_command() {
+        let mut highlighting = Highlighting::default();
+        assert!(highlighting.handle_command("").unwrap().starts_with(
+            "Highlighting is on\n  keyword: magenta\n  string: green\n  number: cyan\n"
+        ));
+        highlighting.handle_command("off").unwrap();
+        assert!(!highlighting.enabled);
+        assert_eq!(highlighting.highlight("let x = 1;", 0), "let x = 1;");
+
+        let summary = highlighting.handle_command("comment bright_red").unwrap();
+        assert!(summary.contains("  comment: bright_red\n"), "{}", summary);
+        let summary = highlighting.handle_command("macro none").unwrap();
+        assert!(summary.contains("  macro: none\n"), "{}", summary);
+        assert_eq!(
+            highlighting.handle_command("comment mauve"),
+            Err("unknown color `mauve`".to_owned())
+        );
+        assert!(highlighting
+            .handle_command("keywords red")
+            .unwrap_err()
+            .starts_with("unknown token class `keywords`"));
+        assert!(highlighting.handle_command("a b c").is_err());
+    }
+}
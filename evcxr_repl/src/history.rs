@@ -0,0 +1,405 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Line editor history. As well as the global history in the evcxr config directory, history is
+//! kept per project in `.evcxr/history` in the working directory, so that snippets resurface where
+//! they're relevant. Which of these the editor offers is controlled by the history scope.
+//!
+//! Each entry is a whole submission, so multi-line input comes back as a single block that can be
+//! edited as a whole. Entering something that's already in the history moves it to the end, rather
+//! than adding it again.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const GLOBAL_HISTORY_FILE_NAME: &str = "history.txt";
+const PROJECT_DIR_NAME: &str = ".evcxr";
+const PROJECT_HISTORY_FILE_NAME: &str = "history";
+/// The header that rustyline writes at the start of history files in which entries are escaped.
+const ESCAPED_FORMAT_HEADER: &str = "#V2";
+/// The maximum number of entries kept in each history file.
+const MAX_ENTRIES: usize = 1000;
+/// The number of entries `:history` lists if not told otherwise.
+const DEFAULT_ENTRIES_LISTED: usize = 20;
+
+/// Which history the line editor offers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryScope {
+    /// Only the global history.
+    Global,
+    /// Only the history for the current project.
+    Project,
+    /// Both, with entries from the current project taking precedence, i.e. being offered first.
+    Merged,
+}
+
+impl FromStr for HistoryScope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "global" => Ok(HistoryScope::Global),
+            "project" => Ok(HistoryScope::Project),
+            "merged" => Ok(HistoryScope::Merged),
+            _ => Err(format!(
+                "expected global, project or merged, got `{}`",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HistoryScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HistoryScope::Global => "global",
+            HistoryScope::Project => "project",
+            HistoryScope::Merged => "merged",
+        })
+    }
+}
+
+pub struct History {
+    scope: HistoryScope,
+    global_path: Option<PathBuf>,
+    project_dir: PathBuf,
+    global: Vec<String>,
+    project: Vec<String>,
+}
+
+impl History {
+    /// Loads the global history from `global_path`, or if that's None, from the evcxr config
+    /// directory. The project history is loaded from the working directory.
+    pub fn load(scope: HistoryScope, global_path: Option<&Path>) -> History {
+        let project_dir = std::env::current_dir()
+            .unwrap_or_default()
+            .join(PROJECT_DIR_NAME);
+        let global_path = match global_path {
+            Some(path) => Some(path.to_owned()),
+            None => evcxr::config_dir().map(|dir| dir.join(GLOBAL_HISTORY_FILE_NAME)),
+        };
+        History::load_from(scope, global_path, project_dir)
+    }
+
+    fn load_from(
+        scope: HistoryScope,
+        global_path: Option<PathBuf>,
+        project_dir: PathBuf,
+    ) -> History {
+        History {
+            scope,
+            global: global_path.as_deref().map(read_entries).unwrap_or_default(),
+            project: read_entries(&project_dir.join(PROJECT_HISTORY_FILE_NAME)),
+            global_path,
+            project_dir,
+        }
+    }
+
+    pub fn scope(&self) -> HistoryScope {
+        self.scope
+    }
+
+    /// Changes the scope. Selecting a scope that includes the project history creates the project
+    /// directory, so that history will be saved there from now on.
+    pub fn set_scope(&mut self, scope: HistoryScope) {
+        self.scope = scope;
+        if scope != HistoryScope::Global {
+            std::fs::create_dir_all(&self.project_dir).ok();
+        }
+    }
+
+    /// Returns the entries that the editor should offer, oldest first.
+    pub fn entries(&self) -> Vec<String> {
+        match self.scope {
+            HistoryScope::Global => self.global.clone(),
+            HistoryScope::Project => self.project.clone(),
+            HistoryScope::Merged => {
+                let project: HashSet<&String> = self.project.iter().collect();
+                self.global
+                    .iter()
+                    .filter(|entry| !project.contains(entry))
+                    .chain(self.project.iter())
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+
+    /// Records an entry in both the global and the project history, regardless of scope. Any
+    /// earlier copies of the entry are removed.
+    pub fn add(&mut self, entry: &str) {
+        for entries in [&mut self.global, &mut self.project] {
+            entries.retain(|existing| existing != entry);
+            entries.push(entry.to_owned());
+        }
+    }
+
+    /// Saves the global history and, if the project directory exists, the project history.
+    pub fn save(&self) {
+        if let Some(global_path) = &self.global_path {
+            if let Some(parent) = global_path.parent() {
+                std::fs::create_dir_all(parent).ok();
+            }
+            write_entries(global_path, &self.global).ok();
+        }
+        if self.project_dir.is_dir() {
+            write_entries(
+                &self.project_dir.join(PROJECT_HISTORY_FILE_NAME),
+                &self.project,
+            )
+            .ok();
+        }
+    }
+
+    /// Handles the arguments to the `:history` command, returning the text to show the user.
+    pub fn handle_command(&mut self, args: &str) -> Result<String, String> {
+        let mut words = args.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => Ok(self.listing(DEFAULT_ENTRIES_LISTED)),
+            (Some("scope"), None, _) => Ok(self.describe_scope()),
+            (Some("scope"), Some(scope), None) => {
+                self.set_scope(scope.parse()?);
+                Ok(self.describe_scope())
+            }
+            (Some("save"), Some(_), _) => {
+                // The rest of the arguments are the file name, which may contain spaces.
+                let path = Path::new(args.trim()["save".len()..].trim());
+                save_as_code(path, &self.entries())
+                    .map_err(|error| format!("Failed to write {}: {}", path.display(), error))?;
+                Ok(format!("History saved to {}", path.display()))
+            }
+            (Some(count), None, _) => match count.parse() {
+                Ok(count) => Ok(self.listing(count)),
+                Err(_) => Err(HISTORY_USAGE.to_owned()),
+            },
+            _ => Err(HISTORY_USAGE.to_owned()),
+        }
+    }
+
+    /// Returns the last `count` entries that the editor offers, numbered, oldest first.
+    fn listing(&self, count: usize) -> String {
+        let entries = self.entries();
+        let first = entries.len().saturating_sub(count);
+        let mut listing = Vec::new();
+        for (index, entry) in entries.iter().enumerate().skip(first) {
+            let number = format!("{:>5}  ", index + 1);
+            // Continuation lines are indented so that they line up with the first.
+            let entry = entry.replace('\n', &format!("\n{}", " ".repeat(number.len())));
+            listing.push(format!("{}{}", number, entry));
+        }
+        if listing.is_empty() {
+            "History is empty".to_owned()
+        } else {
+            listing.join("\n")
+        }
+    }
+
+    fn describe_scope(&self) -> String {
+        let mut output = format!("History scope: {}", self.scope);
+        if let Some(global_path) = &self.global_path {
+            output.push_str(&format!("\nGlobal history: {}", global_path.display()));
+        }
+        let project_path = self.project_dir.join(PROJECT_HISTORY_FILE_NAME);
+        if self.project_dir.is_dir() {
+            output.push_str(&format!("\nProject history: {}", project_path.display()));
+        } else {
+            output.push_str(&format!(
+                "\nProject history: not saved, since {} doesn't exist",
+                self.project_dir.display()
+            ));
+        }
+        output
+    }
+}
+
+const HISTORY_USAGE: &str =
+    "Usage: :history [count] | :history scope [global|project|merged] | :history save <file>";
+
+/// Reads a history file in the format written by rustyline, which older versions of evcxr used
+/// for the global history.
+fn read_entries(path: &Path) -> Vec<String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+    let mut lines = contents.lines().peekable();
+    let escaped = lines.peek() == Some(&ESCAPED_FORMAT_HEADER);
+    if escaped {
+        lines.next();
+    }
+    lines
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            if escaped {
+                unescape(line)
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect()
+}
+
+fn write_entries(path: &Path, entries: &[String]) -> std::io::Result<()> {
+    let mut contents = format!("{}\n", ESCAPED_FORMAT_HEADER);
+    for entry in &entries[entries.len().saturating_sub(MAX_ENTRIES)..] {
+        contents.push_str(&escape(entry));
+        contents.push('\n');
+    }
+    std::fs::write(path, contents)
+}
+
+/// Writes entries to `path` as code, i.e. unescaped, one after another.
+fn save_as_code(path: &Path, entries: &[String]) -> std::io::Result<()> {
+    let mut contents = String::new();
+    for entry in entries {
+        contents.push_str(entry);
+        contents.push('\n');
+    }
+    std::fs::write(path, contents)
+}
+
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => entry.push('\n'),
+                Some(other) => entry.push(other),
+                None => entry.push('\\'),
+            }
+        } else {
+            entry.push(c);
+        }
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+    use super::HistoryScope;
+    use std::path::PathBuf;
+
+    /// Returns an empty directory for a test to use.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "evcxr_history_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_scopes() {
+        let tmpdir = test_dir("scopes");
+        let global_path = tmpdir.join("history.txt");
+        // History written by rustyline before we managed it ourselves.
+        std::fs::write(
+            &global_path,
+            "#V2\nlet a = 1;\nfn f() {\\n    1\\n}\nlet b = 2;\n",
+        )
+        .unwrap();
+        let project_dir = tmpdir.join(".evcxr");
+        let mut history = History::load_from(
+            HistoryScope::Global,
+            Some(global_path.clone()),
+            project_dir.clone(),
+        );
+        assert_eq!(
+            history.entries(),
+            vec!["let a = 1;", "fn f() {\n    1\n}", "let b = 2;"]
+        );
+
+        // Without a project directory, project history isn't saved.
+        history.add("let a = 1;");
+        history.save();
+        assert!(!project_dir.exists());
+
+        history.set_scope(HistoryScope::Project);
+        history.add("let c = 3;");
+        history.save();
+        let mut history = History::load_from(HistoryScope::Merged, Some(global_path), project_dir);
+        // Project entries come last, so that they're offered first.
+        assert_eq!(
+            history.entries(),
+            vec![
+                "fn f() {\n    1\n}",
+                "let b = 2;",
+                "let a = 1;",
+                "let c = 3;"
+            ]
+        );
+        history.set_scope(HistoryScope::Project);
+        assert_eq!(history.entries(), vec!["let a = 1;", "let c = 3;"]);
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let tmpdir = test_dir("deduplication");
+        let mut history = History::load_from(HistoryScope::Global, None, tmpdir.join(".evcxr"));
+        history.add("let a = 1;");
+        history.add("let b = 2;");
+        history.add("let a = 1;");
+        history.add("let a = 1;");
+        assert_eq!(history.entries(), vec!["let b = 2;", "let a = 1;"]);
+    }
+
+    #[test]
+    fn test_command() {
+        let tmpdir = test_dir("command");
+        let mut history = History::load_from(HistoryScope::Global, None, tmpdir.join(".evcxr"));
+        assert_eq!(history.handle_command("").unwrap(), "History is empty");
+        assert!(history
+            .handle_command("scope")
+            .unwrap()
+            .starts_with("History scope: global\n"));
+        assert!(history
+            .handle_command("scope merged")
+            .unwrap()
+            .starts_with("History scope: merged\n"));
+        assert_eq!(history.scope(), HistoryScope::Merged);
+        assert!(history.handle_command("scope everywhere").is_err());
+        assert!(history.handle_command("clear").is_err());
+
+        history.add("let a = 1;");
+        history.add("fn f() {\n    1\n}");
+        history.add("f()");
+        assert_eq!(
+            history.handle_command("").unwrap(),
+            "    1  let a = 1;\n    2  fn f() {\n           1\n       }\n    3  f()"
+        );
+        assert_eq!(history.handle_command("1").unwrap(), "    3  f()");
+
+        let saved = tmpdir.join("saved.rs");
+        let output = history
+            .handle_command(&format!("save {}", saved.display()))
+            .unwrap();
+        assert_eq!(output, format!("History saved to {}", saved.display()));
+        assert_eq!(
+            std::fs::read_to_string(&saved).unwrap(),
+            "let a = 1;\nfn f() {\n    1\n}\nf()\n"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Works out which brackets, quotes and comments are still open in partially typed input. This uses
+//! rust-analyzer's parser, so we get the same tokenization as the compiler will, including for
+//! things like lifetimes vs char literals.
+
+use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxToken;
+
+/// Returns the characters needed to close everything that's open at the end of `source`, in the
+/// order they'd need to be typed.
+pub fn closers_needed(source: &str) -> String {
+    // If brackets are mismatched, there's nothing sensible we can suggest.
+    closers(source).unwrap_or_default()
+}
+
+/// Like `closers_needed`, but returns None if `source` can't be completed by adding closers, e.g.
+/// because brackets are mismatched.
+pub fn closers(source: &str) -> Option<String> {
+    let mut open = Vec::new();
+    let parse = SourceFile::parse(source);
+    let mut last_token = None;
+    for token in parse
+        .syntax_node()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+    {
+        if let Some(previous) = &last_token {
+            // Char literals end at a newline, so one that's unterminated but followed by something
+            // can't be fixed. If it's at the end, more typing may yet terminate it.
+            if unterminated_char_closer(previous).is_some() {
+                return None;
+            }
+        }
+        match token.kind() {
+            SyntaxKind::L_PAREN => open.push(')'),
+            SyntaxKind::L_BRACK => open.push(']'),
+            SyntaxKind::L_CURLY => open.push('}'),
+            SyntaxKind::R_PAREN | SyntaxKind::R_BRACK | SyntaxKind::R_CURLY => {
+                if open.pop() != token.text().chars().next() {
+                    return None;
+                }
+            }
+            _ => {}
+        }
+        last_token = Some(token);
+    }
+    let mut closers: String = open.into_iter().rev().collect();
+    if let Some(token) = last_token {
+        let closer = unterminated_string_closer(token.kind(), token.text())
+            .or_else(|| unterminated_char_closer(&token))
+            .or_else(|| unterminated_comment_closer(token.kind(), token.text()));
+        if let Some(closer) = closer {
+            // The literal or comment is innermost, so needs to be closed first.
+            closers.insert_str(0, &closer);
+        }
+    }
+    Some(closers)
+}
+
+/// Returns the number of indentation levels that a new line appended to `source` should have.
+pub fn indent_level(source: &str) -> usize {
+    closers_needed(source)
+        .chars()
+        .filter(|c| matches!(c, ')' | ']' | '}'))
+        .count()
+}
+
+fn unterminated_string_closer(kind: SyntaxKind, text: &str) -> Option<String> {
+    if kind != SyntaxKind::STRING && kind != SyntaxKind::BYTE_STRING {
+        return None;
+    }
+    // Strip any prefix, so we're left with e.g. `#"foo"#` for `br#"foo"#`.
+    let body = text.trim_start_matches(|c| c == 'b' || c == 'r');
+    let hashes = body.chars().take_while(|c| *c == '#').count();
+    let body = &body[hashes..];
+    let closer = format!("\"{}", "#".repeat(hashes));
+    let is_raw = text.trim_start_matches('b').starts_with('r');
+    let contents = body.strip_prefix('"')?;
+    let terminated = contents.ends_with(&closer)
+        && (is_raw || !ends_with_escape(&contents[..contents.len() - closer.len()]));
+    if terminated {
+        None
+    } else {
+        Some(closer)
+    }
+}
+
+fn unterminated_char_closer(token: &SyntaxToken) -> Option<String> {
+    if token.kind() != SyntaxKind::CHAR && token.kind() != SyntaxKind::BYTE {
+        return None;
+    }
+    let contents = token.text().trim_start_matches('b').strip_prefix('\'')?;
+    let terminated = contents.len() > 1
+        && contents.ends_with('\'')
+        && !ends_with_escape(&contents[..contents.len() - 1]);
+    if terminated {
+        None
+    } else {
+        Some("'".to_owned())
+    }
+}
+
+/// Block comments can be nested, so an unterminated one may need several closers.
+fn unterminated_comment_closer(kind: SyntaxKind, text: &str) -> Option<String> {
+    if kind != SyntaxKind::COMMENT || !text.starts_with("/*") {
+        return None;
+    }
+    let mut depth = 0usize;
+    let mut rest = text;
+    while !rest.is_empty() {
+        if rest.starts_with("/*") {
+            depth += 1;
+            rest = &rest[2..];
+        } else if rest.starts_with("*/") {
+            depth = depth.saturating_sub(1);
+            rest = &rest[2..];
+        } else {
+            let next = rest.chars().next().map_or(1, char::len_utf8);
+            rest = &rest[next..];
+        }
+    }
+    if depth == 0 {
+        None
+    } else {
+        Some("*/".repeat(depth))
+    }
+}
+
+/// Returns whether `s` ends in an odd number of backslashes, meaning a following quote would be
+/// escaped.
+fn ends_with_escape(s: &str) -> bool {
+    s.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::closers_needed;
+    use super::indent_level;
+
+    #[test]
+    fn test_closers_needed() {
+        assert_eq!(closers_needed("let x = 1;"), "");
+        assert_eq!(closers_needed("fn foo() {"), "}");
+        assert_eq!(closers_needed("foo(bar[1, "), "])");
+        assert_eq!(closers_needed("let s = \"{ ("), "\"");
+        assert_eq!(closers_needed("foo(\"abc\\\""), "\")");
+        assert_eq!(closers_needed("let s = r#\"a\"b"), "\"#");
+        assert_eq!(closers_needed("let c = '{'; {"), "}");
+        assert_eq!(closers_needed("// {\nfoo("), ")");
+        assert_eq!(closers_needed("foo(]"), "");
+        assert_eq!(closers_needed("/* a /* b */"), "*/");
+        assert_eq!(closers_needed("foo('"), "')");
+        assert_eq!(closers_needed("foo('\\'"), "')");
+        assert_eq!(closers_needed("foo('a ')\n"), "");
+    }
+
+    #[test]
+    fn test_indent_level() {
+        assert_eq!(indent_level(""), 0);
+        assert_eq!(indent_level("fn foo() {\n    if x {"), 2);
+        assert_eq!(indent_level("fn foo() {\n    if x {\n    }"), 1);
+        assert_eq!(indent_level("let s = \"{"), 0);
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key bindings that we add to rustyline's defaults for the selected edit mode, including any that
+//! the user configures with `bind <key> = <action>` lines in `repl.conf`.
+
+use rustyline::At;
+use rustyline::Cmd;
+use rustyline::EditMode;
+use rustyline::Editor;
+use rustyline::Helper;
+use rustyline::KeyCode;
+use rustyline::KeyEvent;
+use rustyline::Modifiers;
+use rustyline::Movement;
+use rustyline::Word;
+use std::fmt::Write;
+
+/// Prefix given to an action to make it insert text, e.g. `insert:.unwrap()`.
+const INSERT_PREFIX: &str = "insert:";
+
+/// Names of the actions that keys can be bound to, other than `insert:<text>`.
+const ACTION_NAMES: &[&str] = &[
+    "accept_line",
+    "newline",
+    "complete",
+    "kill_line",
+    "kill_whole_line",
+    "backward_kill_word",
+    "beginning_of_line",
+    "end_of_line",
+    "backward_word",
+    "forward_word",
+    "previous_history",
+    "next_history",
+    "history_search_backward",
+    "history_search_forward",
+    "reverse_search_history",
+    "forward_search_history",
+    "transpose_chars",
+    "undo",
+    "yank",
+    "clear_screen",
+    "interrupt",
+    "noop",
+];
+
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+    ("insert", KeyCode::Insert),
+    ("delete", KeyCode::Delete),
+    ("backspace", KeyCode::Backspace),
+    ("enter", KeyCode::Enter),
+    ("tab", KeyCode::Tab),
+    ("esc", KeyCode::Esc),
+    ("space", KeyCode::Char(' ')),
+];
+
+#[derive(Clone, Debug)]
+struct Keybinding {
+    /// The key as the user wrote it, normalized.
+    name: String,
+    key: KeyEvent,
+    action: String,
+    cmd: Cmd,
+}
+
+/// The key bindings we add to rustyline's.
+#[derive(Clone, Debug)]
+pub struct Keybindings {
+    bindings: Vec<Keybinding>,
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        let mut keybindings = Keybindings {
+            bindings: Vec::new(),
+        };
+        for (key, action) in [
+            ("ctrl-left", "backward_word"),
+            ("ctrl-right", "forward_word"),
+            // Emacs mode already has this, but vi mode doesn't.
+            ("ctrl-r", "reverse_search_history"),
+        ] {
+            keybindings.bind(key, action).unwrap();
+        }
+        keybindings
+    }
+}
+
+impl Keybindings {
+    /// Binds the key described by `key_name`, e.g. `ctrl-k`, to the action named `action`,
+    /// replacing any existing binding for that key.
+    pub fn bind(&mut self, key_name: &str, action: &str) -> Result<(), String> {
+        let (name, key) = parse_key(key_name)?;
+        let cmd = parse_action(action)?;
+        self.bindings.retain(|binding| binding.key != key);
+        self.bindings.push(Keybinding {
+            name,
+            key,
+            action: action.to_owned(),
+            cmd,
+        });
+        Ok(())
+    }
+
+    /// Adds our bindings to `editor`, replacing any existing bindings for the same keys.
+    pub fn apply<H: Helper>(&self, editor: &mut Editor<H>) {
+        for binding in &self.bindings {
+            editor.bind_sequence(binding.key, binding.cmd.clone());
+        }
+    }
+
+    /// Returns the description of the active bindings shown by `:keybindings`.
+    pub fn summary(&self, edit_mode: EditMode, auto_indent: bool) -> String {
+        let mut rows: Vec<(String, String)> = self
+            .bindings
+            .iter()
+            .map(|binding| (binding.name.clone(), binding.action.clone()))
+            .collect();
+        // Our bindings are applied after auto-indent's, so take precedence.
+        let enter_bound = self
+            .bindings
+            .iter()
+            .any(|binding| binding.key == KeyEvent(KeyCode::Enter, Modifiers::NONE));
+        if auto_indent && !enter_bound {
+            rows.push((
+                "enter".to_owned(),
+                "newline and indent if input is incomplete, otherwise accept_line".to_owned(),
+            ));
+        }
+        if auto_indent {
+            rows.push((
+                "} ) ]".to_owned(),
+                "dedent if typed on an otherwise blank line".to_owned(),
+            ));
+        }
+        let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+        let mode = match edit_mode {
+            EditMode::Vi => "vi",
+            _ => "emacs",
+        };
+        let mut summary = format!("Edit mode: {}\n", mode);
+        for (key, action) in rows {
+            writeln!(summary, "  {:<width$}  {}", key, action, width = width).unwrap();
+        }
+        write!(
+            summary,
+            "Other keys have rustyline's default {} bindings.",
+            mode
+        )
+        .unwrap();
+        summary
+    }
+}
+
+/// Parses an edit mode as given to `--edit-mode` or in `repl.conf`.
+pub fn parse_edit_mode(value: &str) -> Result<EditMode, String> {
+    match value {
+        "vi" => Ok(EditMode::Vi),
+        "emacs" => Ok(EditMode::Emacs),
+        _ => Err(format!("expected vi or emacs, got `{}`", value)),
+    }
+}
+
+/// Parses a key such as `ctrl-k`, `alt-shift-left` or `f5`, returning it normalized along with the
+/// event that rustyline will see when it's pressed.
+fn parse_key(key_name: &str) -> Result<(String, KeyEvent), String> {
+    let mut rest = key_name.trim();
+    let mut modifiers = Modifiers::NONE;
+    let mut name = String::new();
+    loop {
+        let lower = rest.to_lowercase();
+        let (modifier, prefix) = if lower.starts_with("ctrl-") {
+            (Modifiers::CTRL, "ctrl-")
+        } else if lower.starts_with("alt-") {
+            (Modifiers::ALT, "alt-")
+        } else if lower.starts_with("shift-") {
+            (Modifiers::SHIFT, "shift-")
+        } else {
+            break;
+        };
+        modifiers |= modifier;
+        name.push_str(prefix);
+        rest = &rest[prefix.len()..];
+    }
+    let mut chars = rest.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(c), None) => {
+            name.push(c.to_ascii_lowercase());
+            // rustyline reports letters pressed with ctrl or shift as upper case, and doesn't
+            // report shift separately for characters.
+            if modifiers.contains(Modifiers::CTRL) || modifiers.contains(Modifiers::SHIFT) {
+                modifiers.remove(Modifiers::SHIFT);
+                KeyCode::Char(c.to_ascii_uppercase())
+            } else {
+                KeyCode::Char(c)
+            }
+        }
+        _ => {
+            let lower = rest.to_lowercase();
+            name.push_str(&lower);
+            let function_key = lower
+                .strip_prefix('f')
+                .and_then(|number| number.parse::<u8>().ok())
+                .filter(|number| (1..=24).contains(number));
+            match function_key {
+                Some(number) => KeyCode::F(number),
+                None => KEY_NAMES
+                    .iter()
+                    .find(|(candidate, _)| *candidate == lower)
+                    .map(|(_, code)| *code)
+                    .ok_or_else(|| format!("unknown key `{}`", key_name))?,
+            }
+        }
+    };
+    Ok((name, KeyEvent(code, modifiers)))
+}
+
+fn parse_action(action: &str) -> Result<Cmd, String> {
+    if let Some(text) = action.strip_prefix(INSERT_PREFIX) {
+        return Ok(Cmd::Insert(1, text.to_owned()));
+    }
+    let cmd = match action {
+        "accept_line" => Cmd::AcceptLine,
+        "newline" => Cmd::Newline,
+        "complete" => Cmd::Complete,
+        "kill_line" => Cmd::Kill(Movement::EndOfLine),
+        "kill_whole_line" => Cmd::Kill(Movement::WholeLine),
+        "backward_kill_word" => Cmd::Kill(Movement::BackwardWord(1, Word::Big)),
+        "beginning_of_line" => Cmd::Move(Movement::BeginningOfLine),
+        "end_of_line" => Cmd::Move(Movement::EndOfLine),
+        "backward_word" => Cmd::Move(Movement::BackwardWord(1, Word::Big)),
+        "forward_word" => Cmd::Move(Movement::ForwardWord(1, At::AfterEnd, Word::Big)),
+        "previous_history" => Cmd::PreviousHistory,
+        "next_history" => Cmd::NextHistory,
+        "history_search_backward" => Cmd::HistorySearchBackward,
+        "history_search_forward" => Cmd::HistorySearchForward,
+        "reverse_search_history" => Cmd::ReverseSearchHistory,
+        "forward_search_history" => Cmd::ForwardSearchHistory,
+        "transpose_chars" => Cmd::TransposeChars,
+        "undo" => Cmd::Undo(1),
+        "yank" => Cmd::Yank(1, rustyline::Anchor::Before),
+        "clear_screen" => Cmd::ClearScreen,
+        "interrupt" => Cmd::Interrupt,
+        "noop" => Cmd::Noop,
+        _ => {
+            return Err(format!(
+                "unknown action `{}`, expected {}<text> or one of: {}",
+                action,
+                INSERT_PREFIX,
+                ACTION_NAMES.join(", ")
+            ))
+        }
+    };
+    Ok(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_edit_mode;
+    use super::parse_key;
+    use super::Keybindings;
+    use rustyline::EditMode;
+    use rustyline::KeyCode;
+    use rustyline::KeyEvent;
+    use rustyline::Modifiers;
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("Ctrl-k"),
+            Ok((
+                "ctrl-k".to_owned(),
+                KeyEvent(KeyCode::Char('K'), Modifiers::CTRL)
+            ))
+        );
+        assert_eq!(
+            parse_key("alt-shift-left"),
+            Ok((
+                "alt-shift-left".to_owned(),
+                KeyEvent(KeyCode::Left, Modifiers::ALT | Modifiers::SHIFT)
+            ))
+        );
+        assert_eq!(
+            parse_key("ctrl--"),
+            Ok((
+                "ctrl--".to_owned(),
+                KeyEvent(KeyCode::Char('-'), Modifiers::CTRL)
+            ))
+        );
+        assert_eq!(
+            parse_key("F5"),
+            Ok(("f5".to_owned(), KeyEvent(KeyCode::F(5), Modifiers::NONE)))
+        );
+        assert_eq!(
+            parse_key("ctrl-banana"),
+            Err("unknown key `ctrl-banana`".to_owned())
+        );
+        assert!(parse_key("f99").is_err());
+    }
+
+    #[test]
+    fn test_bindings() {
+        let mut keybindings = Keybindings::default();
+        keybindings.bind("ctrl-left", "beginning_of_line").unwrap();
+        keybindings.bind("alt-u", "insert:.unwrap()").unwrap();
+        assert!(keybindings
+            .bind("ctrl-k", "kill_everything")
+            .unwrap_err()
+            .starts_with("unknown action `kill_everything`"));
+        assert_eq!(
+            keybindings.summary(EditMode::Vi, false),
+            "Edit mode: vi\n\
+             \x20 ctrl-right  forward_word\n\
+             \x20 ctrl-r      reverse_search_history\n\
+             \x20 ctrl-left   beginning_of_line\n\
+             \x20 alt-u       insert:.unwrap()\n\
+             Other keys have rustyline's default vi bindings."
+        );
+        assert!(keybindings
+            .summary(EditMode::Emacs, true)
+            .contains("\n  enter       newline and indent"));
+    }
+
+    #[test]
+    fn test_parse_edit_mode() {
+        assert_eq!(parse_edit_mode("vi"), Ok(EditMode::Vi));
+        assert_eq!(parse_edit_mode("emacs"), Ok(EditMode::Emacs));
+        assert!(parse_edit_mode("ed").is_err());
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Showing LaTeX formulas in the terminal, by replacing commands with the unicode characters that
+//! they stand for. Only a common subset of math mode is understood. Anything else is shown much as
+//! it was written, so the result is always readable, if not always pretty.
+
+/// Renders `latex`, a formula in math mode, optionally surrounded by `$`, `$$`, `\(..\)` or
+/// `\[..\]`, as unicode text. E.g. `$\frac{\alpha}{2}$` becomes `α/2`.
+pub fn latex_to_unicode(latex: &str) -> String {
+    let formula = strip_delimiters(latex.trim());
+    let mut parser = Parser {
+        chars: formula.chars().collect(),
+        pos: 0,
+    };
+    parser.sequence(None).trim().to_owned()
+}
+
+fn strip_delimiters(latex: &str) -> &str {
+    for (start, end) in [("$$", "$$"), ("\\[", "\\]"), ("\\(", "\\)"), ("$", "$")] {
+        if latex.len() >= start.len() + end.len()
+            && latex.starts_with(start)
+            && latex.ends_with(end)
+        {
+            return &latex[start.len()..latex.len() - end.len()];
+        }
+    }
+    latex
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Renders everything up to `end`, which is consumed, or the end of the formula.
+    fn sequence(&mut self, end: Option<char>) -> String {
+        let mut out = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                _ if Some(c) == end => break,
+                '\\' => out.push_str(&self.command()),
+                '{' => out.push_str(&self.sequence(Some('}'))),
+                '^' => out.push_str(&script(&self.argument(), '^', superscript)),
+                '_' => out.push_str(&script(&self.argument(), '_', subscript)),
+                '&' | '~' => out.push(' '),
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// Renders the argument of a command, or of `^` or `_`, which is a group or a single token.
+    fn argument(&mut self) -> String {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                self.sequence(Some('}'))
+            }
+            Some('\\') => {
+                self.pos += 1;
+                self.command()
+            }
+            Some(c) => {
+                self.pos += 1;
+                c.to_string()
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Renders a command, given that the backslash that starts it has been consumed.
+    fn command(&mut self) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            // A command made of a single symbol, such as `\{` or `\,`.
+            return match self.peek() {
+                Some(c) => {
+                    self.pos += 1;
+                    match c {
+                        ',' | ';' | ':' | ' ' => " ".to_owned(),
+                        '!' => String::new(),
+                        '\\' => "\n".to_owned(),
+                        c => c.to_string(),
+                    }
+                }
+                None => "\\".to_owned(),
+            };
+        }
+        let name: String = self.chars[start..self.pos].iter().collect();
+        match name.as_str() {
+            "frac" | "dfrac" | "tfrac" => {
+                let numerator = self.argument();
+                let denominator = self.argument();
+                format!(
+                    "{}/{}",
+                    parenthesize(&numerator),
+                    parenthesize(&denominator)
+                )
+            }
+            "sqrt" => {
+                let index = if self.peek() == Some('[') {
+                    self.pos += 1;
+                    self.sequence(Some(']'))
+                } else {
+                    String::new()
+                };
+                let root = match index.as_str() {
+                    "3" => "∛",
+                    "4" => "∜",
+                    _ => "√",
+                };
+                format!("{}{}", root, parenthesize(&self.argument()))
+            }
+            "mathbb" => self.argument().chars().map(double_struck).collect(),
+            "text" | "mathrm" | "mathbf" | "mathit" | "mathsf" | "mathtt" | "mathcal"
+            | "operatorname" | "boldsymbol" => self.argument(),
+            // Sizing is meaningless in a terminal. A `.` stands for no delimiter.
+            "left" | "right" | "big" | "Big" | "bigg" | "Bigg" => {
+                if self.peek() == Some('.') {
+                    self.pos += 1;
+                }
+                String::new()
+            }
+            "begin" | "end" => {
+                self.argument();
+                String::new()
+            }
+            "quad" | "qquad" => " ".to_owned(),
+            name => match symbol(name) {
+                Some(symbol) => symbol.to_owned(),
+                // Things like `\sin` and `\log`, or commands that we don't know.
+                None => name.to_owned(),
+            },
+        }
+    }
+}
+
+/// Returns `text` wrapped in parentheses, if it needs them to be read as a single term.
+fn parenthesize(text: &str) -> String {
+    if text.chars().count() <= 1 || text.chars().all(|c| c.is_alphanumeric() || c == '.') {
+        text.to_owned()
+    } else {
+        format!("({})", text)
+    }
+}
+
+/// Renders `text` as a superscript or subscript, using `convert` to find the character for each of
+/// its characters. If any has no such character, falls back to e.g. `^(n+k)`.
+fn script(text: &str, marker: char, convert: fn(char) -> Option<char>) -> String {
+    let converted: Option<String> = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(convert)
+        .collect();
+    match converted {
+        Some(converted) if !converted.is_empty() => converted,
+        _ => format!("{}{}", marker, parenthesize(text)),
+    }
+}
+
+fn superscript(c: char) -> Option<char> {
+    const DIGITS: [char; 10] = ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'];
+    Some(match c {
+        '0'..='9' => DIGITS[c as usize - '0' as usize],
+        '+' => '⁺',
+        '-' => '⁻',
+        '=' => '⁼',
+        '(' => '⁽',
+        ')' => '⁾',
+        'a' => 'ᵃ',
+        'b' => 'ᵇ',
+        'c' => 'ᶜ',
+        'd' => 'ᵈ',
+        'e' => 'ᵉ',
+        'f' => 'ᶠ',
+        'g' => 'ᵍ',
+        'h' => 'ʰ',
+        'i' => 'ⁱ',
+        'j' => 'ʲ',
+        'k' => 'ᵏ',
+        'l' => 'ˡ',
+        'm' => 'ᵐ',
+        'n' => 'ⁿ',
+        'o' => 'ᵒ',
+        'p' => 'ᵖ',
+        'r' => 'ʳ',
+        's' => 'ˢ',
+        't' => 'ᵗ',
+        'u' => 'ᵘ',
+        'v' => 'ᵛ',
+        'w' => 'ʷ',
+        'x' => 'ˣ',
+        'y' => 'ʸ',
+        'z' => 'ᶻ',
+        'T' => 'ᵀ',
+        '′' => '′',
+        _ => return None,
+    })
+}
+
+fn subscript(c: char) -> Option<char> {
+    const DIGITS: [char; 10] = ['₀', '₁', '₂', '₃', '₄', '₅', '₆', '₇', '₈', '₉'];
+    Some(match c {
+        '0'..='9' => DIGITS[c as usize - '0' as usize],
+        '+' => '₊',
+        '-' => '₋',
+        '=' => '₌',
+        '(' => '₍',
+        ')' => '₎',
+        'a' => 'ₐ',
+        'e' => 'ₑ',
+        'h' => 'ₕ',
+        'i' => 'ᵢ',
+        'j' => 'ⱼ',
+        'k' => 'ₖ',
+        'l' => 'ₗ',
+        'm' => 'ₘ',
+        'n' => 'ₙ',
+        'o' => 'ₒ',
+        'p' => 'ₚ',
+        'r' => 'ᵣ',
+        's' => 'ₛ',
+        't' => 'ₜ',
+        'u' => 'ᵤ',
+        'v' => 'ᵥ',
+        'x' => 'ₓ',
+        _ => return None,
+    })
+}
+
+fn double_struck(c: char) -> char {
+    match c {
+        'C' => 'ℂ',
+        'H' => 'ℍ',
+        'N' => 'ℕ',
+        'P' => 'ℙ',
+        'Q' => 'ℚ',
+        'R' => 'ℝ',
+        'Z' => 'ℤ',
+        c => c,
+    }
+}
+
+/// Returns the character that a command without arguments stands for.
+fn symbol(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" => "ϵ",
+        "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "vartheta" => "ϑ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "pi" => "π",
+        "rho" => "ρ",
+        "sigma" => "σ",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" => "ϕ",
+        "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Upsilon" => "Υ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        "cdot" => "·",
+        "times" => "×",
+        "div" => "÷",
+        "pm" => "±",
+        "mp" => "∓",
+        "ast" => "∗",
+        "circ" => "∘",
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "neq" | "ne" => "≠",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "propto" => "∝",
+        "ll" => "≪",
+        "gg" => "≫",
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "sum" => "∑",
+        "prod" => "∏",
+        "int" => "∫",
+        "iint" => "∬",
+        "oint" => "∮",
+        "to" | "rightarrow" => "→",
+        "leftarrow" | "gets" => "←",
+        "leftrightarrow" => "↔",
+        "Rightarrow" | "implies" => "⇒",
+        "Leftarrow" => "⇐",
+        "Leftrightarrow" | "iff" => "⇔",
+        "mapsto" => "↦",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "subset" => "⊂",
+        "subseteq" => "⊆",
+        "supset" => "⊃",
+        "supseteq" => "⊇",
+        "cup" => "∪",
+        "cap" => "∩",
+        "emptyset" | "varnothing" => "∅",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" | "lnot" => "¬",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        "oplus" => "⊕",
+        "otimes" => "⊗",
+        "perp" => "⊥",
+        "angle" => "∠",
+        "degree" => "°",
+        "prime" => "′",
+        "hbar" => "ℏ",
+        "ell" => "ℓ",
+        "ldots" | "dots" => "…",
+        "cdots" => "⋯",
+        "vdots" => "⋮",
+        "ddots" => "⋱",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "mid" => "|",
+        "lvert" | "rvert" | "vert" => "|",
+        "lVert" | "rVert" | "Vert" => "‖",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::latex_to_unicode;
+
+    #[test]
+    fn symbols_and_delimiters() {
+        assert_eq!(latex_to_unicode(r"$\alpha + \beta$"), "α + β");
+        assert_eq!(latex_to_unicode(r"$$x \leq \infty$$"), "x ≤ ∞");
+        assert_eq!(latex_to_unicode(r"\[a \cdot b\]"), "a · b");
+        assert_eq!(latex_to_unicode(r"\forall x \in \mathbb{R}"), "∀ x ∈ ℝ");
+        assert_eq!(latex_to_unicode(r"\sin\theta"), "sinθ");
+        assert_eq!(latex_to_unicode(r"\unknown{x}"), "unknownx");
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(latex_to_unicode("x^2 + y_1"), "x² + y₁");
+        assert_eq!(latex_to_unicode("e^{-i}"), "e⁻ⁱ");
+        assert_eq!(latex_to_unicode(r"x^{\alpha}"), "x^α");
+        assert_eq!(latex_to_unicode("a_{n+k}"), "aₙ₊ₖ");
+        assert_eq!(latex_to_unicode("a_{y+1}"), "a_(y+1)");
+        assert_eq!(latex_to_unicode(r"\sum_{i=1}^{n} i"), "∑ᵢ₌₁ⁿ i");
+    }
+
+    #[test]
+    fn fractions_and_roots() {
+        assert_eq!(latex_to_unicode(r"\frac{1}{2}"), "1/2");
+        assert_eq!(latex_to_unicode(r"\frac{n(n+1)}{2}"), "(n(n+1))/2");
+        assert_eq!(latex_to_unicode(r"\frac12"), "1/2");
+        assert_eq!(latex_to_unicode(r"\sqrt{x^2 + 1}"), "√(x² + 1)");
+        assert_eq!(latex_to_unicode(r"\sqrt[3]{8}"), "∛8");
+        assert_eq!(latex_to_unicode(r"\left( \frac{a}{b} \right)"), "( a/b )");
+    }
+}
@@ -13,9 +13,34 @@
 // limitations under the License.
 
 mod bginit;
+mod config;
+mod highlight;
+mod history;
+mod indent;
+mod keybindings;
+mod latex;
+mod live_display;
+mod lsp;
+mod pager;
 mod repl;
 mod scan;
+mod transcript;
 
 pub use bginit::BgInitMutex;
 pub use bginit::BgInitMutexGuard;
+pub use config::ReplConfig;
+pub use highlight::Highlighting;
+pub use history::History;
+pub use history::HistoryScope;
+pub use keybindings::parse_edit_mode;
+pub use keybindings::Keybindings;
+pub use latex::latex_to_unicode;
+pub use live_display::LiveDisplay;
+pub use lsp::LspServer;
+pub use pager::Pager;
+pub use repl::AutoIndentHandler;
+pub use repl::DedentHandler;
 pub use repl::EvcxrRustylineHelper;
+pub use transcript::CapturedOutput;
+pub use transcript::Transcript;
+pub use transcript::TranscriptEntry;
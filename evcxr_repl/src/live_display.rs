@@ -0,0 +1,134 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Showing output that user code updates while it runs, such as progress bars. The latest display
+//! is redrawn in place on the current line of the terminal, rather than printing a line per update.
+
+use evcxr::DisplayUpdate;
+use std::cell::RefCell;
+use std::io::Write;
+
+/// Moves to the start of the line and clears it.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+pub struct LiveDisplay<W: Write> {
+    state: RefCell<State<W>>,
+}
+
+struct State<W> {
+    out: W,
+    /// The ID of the display on the current line, if any.
+    current: Option<String>,
+}
+
+impl<W: Write> LiveDisplay<W> {
+    pub fn new(out: W) -> LiveDisplay<W> {
+        LiveDisplay {
+            state: RefCell::new(State { out, current: None }),
+        }
+    }
+
+    /// Shows the first line of the update's plain text. Updates to a display other than the
+    /// latest are dropped, since it's no longer on the current line.
+    pub fn show(&self, update: &DisplayUpdate) {
+        let text = match update.get("text/plain") {
+            Some(text) => text.lines().next().unwrap_or_default(),
+            None => return,
+        };
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let is_current = state.current.as_deref() == Some(update.display_id.as_str());
+        if update.is_update && !is_current {
+            return;
+        }
+        if !is_current && state.current.is_some() {
+            let _ = writeln!(state.out);
+        }
+        let _ = write!(state.out, "{}{}", CLEAR_LINE, text);
+        let _ = state.out.flush();
+        state.current = Some(update.display_id.clone());
+    }
+
+    /// Removes the latest display, if any. Output that's already been printed can't be cleared.
+    pub fn clear(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.current.take().is_some() {
+            let _ = write!(state.out, "{}", CLEAR_LINE);
+            let _ = state.out.flush();
+        }
+    }
+
+    /// Leaves the latest display, if any, on its own line, so that output that follows starts on
+    /// the next one.
+    pub fn finish(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.current.take().is_some() {
+            let _ = writeln!(state.out);
+            let _ = state.out.flush();
+        }
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> W {
+        self.state.into_inner().out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LiveDisplay;
+    use evcxr::Content;
+    use evcxr::DisplayUpdate;
+    use std::collections::HashMap;
+
+    fn update(display_id: &str, is_update: bool, text: &str) -> DisplayUpdate {
+        let mut content_by_mime_type = HashMap::new();
+        content_by_mime_type.insert("text/plain".to_owned(), Content::Text(text.to_owned()));
+        DisplayUpdate {
+            display_id: display_id.to_owned(),
+            is_update,
+            content_by_mime_type,
+        }
+    }
+
+    #[test]
+    fn updates_are_drawn_in_place() {
+        let display = LiveDisplay::new(Vec::new());
+        display.show(&update("a", false, "[-] 0/1"));
+        display.show(&update("a", true, "[#] 1/1"));
+        display.show(&update("b", false, "b 0%\nignored"));
+        // `a` is no longer on the current line.
+        display.show(&update("a", true, "[#] 2/1"));
+        display.finish();
+        display.finish();
+        assert_eq!(
+            String::from_utf8(display.into_inner()).unwrap(),
+            "\r\x1b[K[-] 0/1\r\x1b[K[#] 1/1\n\r\x1b[Kb 0%\n"
+        );
+    }
+
+    #[test]
+    fn clearing() {
+        let display = LiveDisplay::new(Vec::new());
+        display.clear();
+        display.show(&update("a", false, "a"));
+        display.clear();
+        display.show(&update("b", false, "b"));
+        display.finish();
+        assert_eq!(
+            String::from_utf8(display.into_inner()).unwrap(),
+            "\r\x1b[Ka\r\x1b[K\r\x1b[Kb\n"
+        );
+    }
+}
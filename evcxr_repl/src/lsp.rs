@@ -0,0 +1,487 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `evcxr lsp`, a Language Server Protocol server that gives editors diagnostics, completions and
+//! hover for code as it would be evaluated in a live session, rather than in a static project.
+//! Open documents are treated like a cell that's about to be run, and the `evcxr.eval` command
+//! runs code in the session, so that later code can use what it defined.
+
+use evcxr::CommandContext;
+use evcxr::CompilationError;
+use evcxr::Error;
+use evcxr::EvalContextOutputs;
+use json::object;
+use json::JsonValue;
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+const EVAL_COMMAND: &str = "evcxr.eval";
+
+// JSON-RPC and LSP error codes.
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const REQUEST_FAILED: i32 = -32803;
+
+pub struct LspServer<W: Write> {
+    command_context: CommandContext,
+    outputs: EvalContextOutputs,
+    /// The text of each open document, keyed by URI.
+    documents: HashMap<String, String>,
+    writer: W,
+    shutdown_requested: bool,
+}
+
+impl<W: Write> LspServer<W> {
+    pub fn new(
+        command_context: CommandContext,
+        outputs: EvalContextOutputs,
+        writer: W,
+    ) -> LspServer<W> {
+        LspServer {
+            command_context,
+            outputs,
+            documents: HashMap::new(),
+            writer,
+            shutdown_requested: false,
+        }
+    }
+
+    /// Handles messages from `reader` until the client sends `exit` or closes the connection.
+    /// Returns whether the client shut down cleanly, i.e. sent `shutdown` before `exit`.
+    pub fn run<R: BufRead>(&mut self, reader: &mut R) -> io::Result<bool> {
+        while let Some(message) = read_message(reader)? {
+            if message["method"] == "exit" {
+                break;
+            }
+            self.handle_message(&message)?;
+            self.forward_output()?;
+        }
+        Ok(self.shutdown_requested)
+    }
+
+    fn handle_message(&mut self, message: &JsonValue) -> io::Result<()> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = &message["id"];
+        if id.is_null() {
+            return self.handle_notification(method, params);
+        }
+        let result = match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Ok(JsonValue::Null)
+            }
+            "textDocument/completion" => self.completion(params),
+            "textDocument/hover" => self.hover(params),
+            "workspace/executeCommand" => self.execute_command(params),
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method: {}", method))),
+        };
+        let response = match result {
+            Ok(result) => object! {"jsonrpc" => "2.0", "id" => id.clone(), "result" => result},
+            Err((code, message)) => object! {
+                "jsonrpc" => "2.0",
+                "id" => id.clone(),
+                "error" => object! {"code" => code, "message" => message},
+            },
+        };
+        write_message(&mut self.writer, &response)
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &JsonValue) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_owned(), text.to_owned());
+                self.publish_diagnostics(uri)?;
+            }
+            "textDocument/didChange" => {
+                // We ask for full document sync, so the last change has the whole text. Checking
+                // runs cargo, which is too slow to do on every keystroke, so we wait for a save.
+                let changes = &params["contentChanges"];
+                if let Some(text) = changes[changes.len().saturating_sub(1)]["text"].as_str() {
+                    self.documents.insert(uri.to_owned(), text.to_owned());
+                }
+            }
+            "textDocument/didSave" => {
+                if let Some(text) = params["text"].as_str() {
+                    self.documents.insert(uri.to_owned(), text.to_owned());
+                }
+                self.publish_diagnostics(uri)?;
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.send_notification(
+                    "textDocument/publishDiagnostics",
+                    object! {"uri" => uri, "diagnostics" => JsonValue::new_array()},
+                )?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn completion(&mut self, params: &JsonValue) -> Result<JsonValue, (i32, String)> {
+        let text = self.document(params)?;
+        let offset = offset_of(&text, &params["position"]);
+        let completions = self
+            .command_context
+            .completions(&text, offset)
+            .map_err(|error| (REQUEST_FAILED, error.to_string()))?;
+        let range = range_of(&text, completions.start_offset, completions.end_offset);
+        let mut items = JsonValue::new_array();
+        for completion in completions.completions {
+            items
+                .push(object! {
+                    "label" => completion.code.clone(),
+                    "textEdit" => object! {"range" => range.clone(), "newText" => completion.code},
+                })
+                .unwrap();
+        }
+        Ok(items)
+    }
+
+    fn hover(&mut self, params: &JsonValue) -> Result<JsonValue, (i32, String)> {
+        let text = self.document(params)?;
+        let offset = offset_of(&text, &params["position"]);
+        let (start, end) = identifier_at(&text, offset);
+        let identifier = &text[start..end];
+        if identifier.is_empty() {
+            return Ok(JsonValue::Null);
+        }
+        let contents = if let Some((_, type_name)) = self
+            .command_context
+            .variables_and_types()
+            .find(|(name, _)| *name == identifier)
+        {
+            format!(
+                "```rust\n{}: {}\n```\nSession variable",
+                identifier, type_name
+            )
+        } else if self
+            .command_context
+            .defined_item_names()
+            .any(|name| name == identifier)
+        {
+            format!(
+                "```rust\n{}\n```\nDefined earlier in the session",
+                identifier
+            )
+        } else {
+            return Ok(JsonValue::Null);
+        };
+        Ok(object! {
+            "contents" => object! {"kind" => "markdown", "value" => contents},
+            "range" => range_of(&text, start, end),
+        })
+    }
+
+    fn execute_command(&mut self, params: &JsonValue) -> Result<JsonValue, (i32, String)> {
+        if params["command"] != EVAL_COMMAND {
+            return Err((
+                INVALID_PARAMS,
+                format!("Unknown command: {}", params["command"]),
+            ));
+        }
+        let code = params["arguments"][0].as_str().ok_or_else(|| {
+            (
+                INVALID_PARAMS,
+                format!(
+                    "{} takes the code to evaluate as its argument",
+                    EVAL_COMMAND
+                ),
+            )
+        })?;
+        let result = self.command_context.execute(code);
+        // Whatever was defined may fix or cause problems in open documents.
+        let uris: Vec<String> = self.documents.keys().cloned().collect();
+        for uri in uris {
+            self.publish_diagnostics(&uri)
+                .map_err(|error| (REQUEST_FAILED, error.to_string()))?;
+        }
+        let outputs = match result {
+            Ok(outputs) => outputs,
+            Err(Error::CompilationErrors(errors)) => {
+                let messages: Vec<String> = errors.iter().map(CompilationError::message).collect();
+                return Err((REQUEST_FAILED, messages.join("\n")));
+            }
+            Err(error) => return Err((REQUEST_FAILED, error.to_string())),
+        };
+        let stdout: Vec<String> = self.outputs.stdout.try_iter().collect();
+        Ok(object! {
+            "stdout" => stdout.join("\n"),
+            "value" => outputs.get("text/plain").unwrap_or_default(),
+        })
+    }
+
+    fn document(&self, params: &JsonValue) -> Result<String, (i32, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        self.documents
+            .get(uri)
+            .cloned()
+            .ok_or_else(|| (INVALID_PARAMS, format!("Document isn't open: {}", uri)))
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let text = match self.documents.get(uri) {
+            Some(text) => text.clone(),
+            None => return Ok(()),
+        };
+        let diagnostics = match self.command_context.check(&text) {
+            Ok(errors) => errors
+                .iter()
+                .map(|error| diagnostic(&text, error))
+                .collect(),
+            Err(error) => vec![object! {
+                "range" => range_of(&text, 0, 0),
+                "severity" => 1,
+                "source" => "evcxr",
+                "message" => error.to_string(),
+            }],
+        };
+        self.send_notification(
+            "textDocument/publishDiagnostics",
+            object! {"uri" => uri, "diagnostics" => diagnostics},
+        )
+    }
+
+    /// Sends anything user code or cargo wrote to stderr to the client's log, since stdout is
+    /// used for the protocol. Stdout of user code is returned by `evcxr.eval` instead.
+    fn forward_output(&mut self) -> io::Result<()> {
+        let lines: Vec<String> = self.outputs.stderr.try_iter().collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.send_notification(
+            "window/logMessage",
+            object! {"type" => 4, "message" => lines.join("\n")},
+        )
+    }
+
+    fn send_notification(&mut self, method: &str, params: JsonValue) -> io::Result<()> {
+        write_message(
+            &mut self.writer,
+            &object! {"jsonrpc" => "2.0", "method" => method, "params" => params},
+        )
+    }
+}
+
+fn capabilities() -> JsonValue {
+    object! {
+        "capabilities" => object! {
+            "textDocumentSync" => object! {
+                "openClose" => true,
+                // Full document sync.
+                "change" => 1,
+                "save" => object! {"includeText" => true},
+            },
+            "completionProvider" => object! {"triggerCharacters" => json::array![".", ":"]},
+            "hoverProvider" => true,
+            "executeCommandProvider" => object! {"commands" => json::array![EVAL_COMMAND]},
+        },
+        "serverInfo" => object! {"name" => "evcxr", "version" => env!("CARGO_PKG_VERSION")},
+    }
+}
+
+fn diagnostic(text: &str, error: &CompilationError) -> JsonValue {
+    let range = match error
+        .primary_spanned_message()
+        .and_then(|message| message.span)
+    {
+        Some(span) => object! {
+            "start" => position_at_column(text, span.start_line, span.start_column),
+            "end" => position_at_column(text, span.end_line, span.end_column),
+        },
+        None => range_of(text, 0, 0),
+    };
+    let severity = match error.level() {
+        "error" => 1,
+        "warning" => 2,
+        _ => 3,
+    };
+    let mut diagnostic = object! {
+        "range" => range,
+        "severity" => severity,
+        "source" => "evcxr",
+        "message" => error.message(),
+    };
+    if let Some(code) = error.code() {
+        diagnostic["code"] = code.into();
+    }
+    diagnostic
+}
+
+/// Reads a message, which is a header giving its length, then JSON. Returns `None` at the end of
+/// input.
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<JsonValue>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let content_length = content_length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Message has no Content-Length")
+    })?;
+    let mut content = vec![0; content_length];
+    reader.read_exact(&mut content)?;
+    let content = String::from_utf8(content)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    json::parse(&content)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &JsonValue) -> io::Result<()> {
+    let content = message.dump();
+    write!(
+        writer,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    writer.flush()
+}
+
+/// Returns the byte offset in `text` of an LSP position, whose character is in UTF-16 code units.
+/// Positions past the end of a line or of the text are clamped.
+fn offset_of(text: &str, position: &JsonValue) -> usize {
+    let line = position["line"].as_usize().unwrap_or(0);
+    let character = position["character"].as_usize().unwrap_or(0);
+    let mut line_start = 0;
+    for _ in 0..line {
+        match text[line_start..].find('\n') {
+            Some(newline) => line_start += newline + 1,
+            None => return text.len(),
+        }
+    }
+    let line_text = text[line_start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (index, c) in line_text.char_indices() {
+        if units >= character {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line_text.len()
+}
+
+/// Returns the LSP position of byte offset `offset` in `text`.
+fn position_of(text: &str, offset: usize) -> JsonValue {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map(|newline| newline + 1).unwrap_or(0);
+    object! {
+        "line" => before.matches('\n').count(),
+        "character" => before[line_start..].encode_utf16().count(),
+    }
+}
+
+/// Returns the LSP position of a 1-based line and column (in characters), as used by `Span`.
+fn position_at_column(text: &str, line: usize, column: usize) -> JsonValue {
+    let line_text = text.lines().nth(line.saturating_sub(1)).unwrap_or_default();
+    let character: usize = line_text
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(char::len_utf16)
+        .sum();
+    object! {"line" => line.saturating_sub(1), "character" => character}
+}
+
+fn range_of(text: &str, start: usize, end: usize) -> JsonValue {
+    object! {"start" => position_of(text, start), "end" => position_of(text, end)}
+}
+
+/// Returns the byte range of the identifier that contains, or ends at, `offset`.
+fn identifier_at(text: &str, offset: usize) -> (usize, usize) {
+    let is_identifier_char = |c: char| c.is_alphanumeric() || c == '_';
+    let start = text[..offset]
+        .rfind(|c: char| !is_identifier_char(c))
+        .map(|index| index + text[index..].chars().next().unwrap().len_utf8())
+        .unwrap_or(0);
+    let end = text[offset..]
+        .find(|c: char| !is_identifier_char(c))
+        .map(|index| offset + index)
+        .unwrap_or(text.len());
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::identifier_at;
+    use super::offset_of;
+    use super::position_at_column;
+    use super::position_of;
+    use super::read_message;
+    use super::write_message;
+    use json::object;
+
+    #[test]
+    fn test_message_framing() {
+        let mut written = Vec::new();
+        write_message(&mut written, &object! {"id" => 1, "method" => "é"}).unwrap();
+        write_message(&mut written, &object! {"id" => 2}).unwrap();
+        assert!(written.starts_with(b"Content-Length: 22\r\n\r\n{\"id\":1,"));
+        let mut reader = &written[..];
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(object! {"id" => 1, "method" => "é"})
+        );
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(object! {"id" => 2})
+        );
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+        assert!(read_message(&mut &b"Content-Type: x\r\n\r\n{}"[..]).is_err());
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "let a = 1;\nlet é😀 = a;\n";
+        let position = |line: usize, character: usize| {
+            object! {"line" => line, "character" => character}
+        };
+        assert_eq!(offset_of(text, &position(0, 4)), 4);
+        // é is one UTF-16 code unit and two bytes. 😀 is two code units and four bytes.
+        assert_eq!(offset_of(text, &position(1, 5)), 17);
+        assert_eq!(offset_of(text, &position(1, 7)), 21);
+        assert_eq!(offset_of(text, &position(1, 100)), 26);
+        assert_eq!(offset_of(text, &position(5, 0)), text.len());
+        assert_eq!(position_of(text, 21), position(1, 7));
+        assert_eq!(position_of(text, 0), position(0, 0));
+        assert_eq!(position_at_column(text, 2, 7), position(1, 7));
+    }
+
+    #[test]
+    fn test_identifier_at() {
+        let text = "foo(bar_2) + é";
+        assert_eq!(identifier_at(text, 5), (4, 9));
+        assert_eq!(identifier_at(text, 9), (4, 9));
+        assert_eq!(identifier_at(text, 0), (0, 3));
+        assert_eq!(identifier_at(text, 11), (11, 11));
+        assert_eq!(identifier_at(text, text.len()), (13, 15));
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Showing results that don't fit on the screen through a pager, so that printing e.g. a huge `Vec`
+//! doesn't flood the terminal. Turned on with `:pager on` or `pager = on` in `repl.conf`.
+
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Used if `PAGER` isn't set. `-F` makes less exit straight away if everything fits on the screen,
+/// `-R` passes colors through and `-X` leaves the output on the screen afterwards.
+const DEFAULT_PAGER: &str = "less -FRX";
+
+/// Assumed if `LINES` doesn't say how tall the terminal is.
+const DEFAULT_TERMINAL_LINES: usize = 24;
+
+#[derive(Default)]
+pub struct Pager {
+    enabled: bool,
+}
+
+impl Pager {
+    pub fn new(enabled: bool) -> Pager {
+        Pager { enabled }
+    }
+
+    /// Handles the `:pager` command, which turns the pager on or off for the rest of the session.
+    pub fn handle_command(&mut self, args: &str) -> Result<String, String> {
+        match args.trim() {
+            "" => {}
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            other => return Err(format!("Usage: :pager [on|off], got `{}`", other)),
+        }
+        Ok(format!(
+            "Pager: {}",
+            if self.enabled { "on" } else { "off" }
+        ))
+    }
+
+    /// Prints `text`, followed by a newline. If the pager is on and `text` doesn't fit on the
+    /// screen, it's shown through the pager instead. If the pager can't be run, `text` is printed
+    /// as usual.
+    pub fn print(&self, text: &str) {
+        if self.enabled && text.lines().count() >= terminal_lines() && page(text).is_ok() {
+            return;
+        }
+        println!("{}", text);
+    }
+}
+
+fn terminal_lines() -> usize {
+    std::env::var("LINES")
+        .ok()
+        .and_then(|lines| lines.parse().ok())
+        .unwrap_or(DEFAULT_TERMINAL_LINES)
+}
+
+/// Returns the program to run as a pager, followed by its arguments.
+fn pager_command(pager_var: Option<String>) -> Vec<String> {
+    let pager = pager_var
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_owned());
+    pager.split_whitespace().map(str::to_owned).collect()
+}
+
+fn page(text: &str) -> std::io::Result<()> {
+    let command = pager_command(std::env::var("PAGER").ok());
+    let mut pager = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = pager.stdin.take() {
+        // Writing fails if the user quits the pager before it's read everything, which is fine.
+        let _ = writeln!(stdin, "{}", text);
+    }
+    pager.wait()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pager_command;
+    use super::Pager;
+
+    #[test]
+    fn test_handle_command() {
+        let mut pager = Pager::default();
+        assert_eq!(pager.handle_command(""), Ok("Pager: off".to_owned()));
+        assert_eq!(pager.handle_command(" on"), Ok("Pager: on".to_owned()));
+        assert!(pager.enabled);
+        assert_eq!(pager.handle_command("off"), Ok("Pager: off".to_owned()));
+        assert!(pager.handle_command("maybe").is_err());
+    }
+
+    #[test]
+    fn test_pager_command() {
+        assert_eq!(pager_command(None), vec!["less", "-FRX"]);
+        assert_eq!(pager_command(Some(" ".to_owned())), vec!["less", "-FRX"]);
+        assert_eq!(
+            pager_command(Some("more -d".to_owned())),
+            vec!["more", "-d"]
+        );
+    }
+}
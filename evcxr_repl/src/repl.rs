@@ -15,6 +15,10 @@
 use super::scan::validate_source_fragment;
 use super::scan::FragmentValidity;
 use crate::bginit::BgInitMutex;
+use crate::config::ReplConfig;
+use crate::highlight::Highlighting;
+use crate::indent;
+use crate::scan;
 use colored::*;
 use evcxr::CommandContext;
 use evcxr::Completions;
@@ -25,18 +29,37 @@ use rustyline::hint::Hinter;
 use rustyline::validate::ValidationContext;
 use rustyline::validate::ValidationResult;
 use rustyline::validate::Validator;
+use rustyline::Cmd;
+use rustyline::ConditionalEventHandler;
 use rustyline::Context;
+use rustyline::Event;
+use rustyline::EventContext;
 use rustyline::Helper;
+use rustyline::Movement;
+use rustyline::RepeatCount;
 use std::borrow::Cow;
 use std::sync::Arc;
 
 pub struct EvcxrRustylineHelper {
     command_context: Arc<BgInitMutex<CommandContext>>,
+    config: ReplConfig,
 }
 
 impl EvcxrRustylineHelper {
-    pub fn new(command_context: Arc<BgInitMutex<CommandContext>>) -> Self {
-        Self { command_context }
+    pub fn new(command_context: Arc<BgInitMutex<CommandContext>>, config: ReplConfig) -> Self {
+        Self {
+            command_context,
+            config,
+        }
+    }
+
+    /// Handles the `:color` command, which changes highlighting for the rest of the session.
+    pub fn handle_color_command(&mut self, args: &str) -> Result<String, String> {
+        self.config.highlighting.handle_command(args)
+    }
+
+    pub fn highlighting(&self) -> &Highlighting {
+        &self.config.highlighting
     }
 }
 
@@ -44,6 +67,20 @@ impl EvcxrRustylineHelper {
 
 impl Hinter for EvcxrRustylineHelper {
     type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        // Only offer closers when the cursor is at the end, since that's the only place that
+        // accepting the hint would put them.
+        if !self.config.auto_close || pos != line.len() || line.trim().is_empty() {
+            return None;
+        }
+        let closers = indent::closers_needed(line);
+        if closers.is_empty() {
+            None
+        } else {
+            Some(closers)
+        }
+    }
 }
 
 impl Completer for EvcxrRustylineHelper {
@@ -70,6 +107,16 @@ impl Completer for EvcxrRustylineHelper {
 }
 
 impl Highlighter for EvcxrRustylineHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.config.highlighting.highlight(line, pos)
+    }
+
+    // Any change, even just moving the cursor, can change what's highlighted, e.g. by starting a
+    // comment or moving next to a bracket.
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        self.config.highlighting.enabled
+    }
+
     fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
         &'s self,
         prompt: &'p str,
@@ -77,6 +124,10 @@ impl Highlighter for EvcxrRustylineHelper {
     ) -> Cow<'b, str> {
         prompt.yellow().to_string().into()
     }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        hint.dimmed().to_string().into()
+    }
 }
 
 impl Validator for EvcxrRustylineHelper {
@@ -85,8 +136,14 @@ impl Validator for EvcxrRustylineHelper {
         // If a user is hammering on the enter key, lets pass things along to
         // rustc. This is an escape hatch for the case where *we* know (well,
         // think) the source is incomplete, but the user doesn't. It also makes
-        // bugs in our code less disasterous.
-        if input.ends_with("\n\n") {
+        // bugs in our code less disasterous. Blank lines may have been auto-indented, so we
+        // ignore whitespace.
+        if input.matches('\n').count() >= 2
+            && input
+                .rsplit('\n')
+                .take(2)
+                .all(|line| line.trim().is_empty())
+        {
             return Ok(ValidationResult::Valid(None));
         }
         match validate_source_fragment(input) {
@@ -111,3 +168,82 @@ impl Validator for EvcxrRustylineHelper {
 }
 
 impl Helper for EvcxrRustylineHelper {}
+
+/// Bound to the enter key when auto-indent is enabled. If the input is incomplete, inserts a
+/// newline followed by indentation appropriate to the number of open brackets, or a single level
+/// if a statement is being continued. Otherwise defers to the default behavior, which will submit
+/// the input.
+pub struct AutoIndentHandler {
+    config: ReplConfig,
+}
+
+impl AutoIndentHandler {
+    pub fn new(config: ReplConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl ConditionalEventHandler for AutoIndentHandler {
+    fn handle(
+        &self,
+        _evt: &Event,
+        _n: RepeatCount,
+        _positive: bool,
+        ctx: &EventContext,
+    ) -> Option<Cmd> {
+        let line = ctx.line();
+        let current_line = line.rsplit('\n').next().unwrap_or("");
+        // Leave blank lines and mid-line edits to the default behavior, so that the validator's
+        // escape hatch for submitting input still works.
+        if ctx.pos() != line.len() || current_line.trim().is_empty() {
+            return None;
+        }
+        if validate_source_fragment(line) != FragmentValidity::Incomplete {
+            return None;
+        }
+        let mut level = indent::indent_level(line);
+        if level == 0 && scan::ends_mid_construct(line) {
+            // The statement continues on the next line, e.g. after a binary operator.
+            level = 1;
+        }
+        let indent = " ".repeat(level * self.config.indent_width);
+        Some(Cmd::Insert(1, format!("\n{}", indent)))
+    }
+}
+
+/// Bound to closing brackets when auto-indent is enabled. If the bracket is typed on a line that's
+/// otherwise blank, reindents that line to match the line that opened the bracket.
+pub struct DedentHandler {
+    config: ReplConfig,
+    closer: char,
+}
+
+impl DedentHandler {
+    pub fn new(config: ReplConfig, closer: char) -> Self {
+        Self { config, closer }
+    }
+}
+
+impl ConditionalEventHandler for DedentHandler {
+    fn handle(
+        &self,
+        _evt: &Event,
+        _n: RepeatCount,
+        _positive: bool,
+        ctx: &EventContext,
+    ) -> Option<Cmd> {
+        let before_cursor = &ctx.line()[..ctx.pos()];
+        let (previous_lines, current_line) = before_cursor.rsplit_once('\n')?;
+        if !current_line.trim().is_empty() {
+            return None;
+        }
+        // The indentation the bracket should get is that of the lines before the one that opened
+        // it, which is one less than the level inside the bracket.
+        let level = indent::indent_level(previous_lines).saturating_sub(1);
+        let indent = " ".repeat(level * self.config.indent_width);
+        Some(Cmd::Replace(
+            Movement::BeginningOfLine,
+            Some(format!("{}{}", indent, self.closer)),
+        ))
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A record of what was evaluated in the session and what came of it, which `:save_transcript`
+//! writes out as Markdown or HTML so that an exploration done in the REPL can be shared.
+
+use parking_lot::Mutex;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const TITLE: &str = "Evcxr session";
+
+const HTML_STYLE: &str = "\
+body { font-family: sans-serif; max-width: 60em; margin: 2em auto; }
+pre { padding: 0.5em; overflow-x: auto; }
+.input { background: #f0f0f0; }
+.output { border-left: 3px solid #c0c0c0; }
+.errors { border-left: 3px solid #d04040; color: #a02020; }
+.duration { color: #808080; font-size: small; margin-bottom: 2em; }";
+
+/// One evaluation, and what came of it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    pub input: String,
+    /// What was written to stdout, followed by the value of the final expression, if any.
+    pub output: String,
+    /// What was written to stderr, followed by any errors.
+    pub errors: String,
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+pub struct Transcript {
+    entries: Vec<TranscriptEntry>,
+}
+
+impl Transcript {
+    pub fn record(&mut self, entry: TranscriptEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Handles `:save_transcript <path>`. The format is chosen based on the file extension.
+    pub fn handle_command(&self, args: &str) -> Result<String, String> {
+        let path = Path::new(args.trim());
+        if path.as_os_str().is_empty() {
+            return Err("Usage: :save_transcript <file.md|file.html>".to_owned());
+        }
+        let contents = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("md" | "markdown") => self.to_markdown(),
+            Some("html" | "htm") => self.to_html(),
+            _ => {
+                return Err(format!(
+                    "Don't know what format to write {} in. Use a .md or .html extension",
+                    path.display()
+                ))
+            }
+        };
+        std::fs::write(path, contents)
+            .map_err(|error| format!("Failed to write {}: {}", path.display(), error))?;
+        Ok(format!(
+            "Transcript of {} evaluations saved to {}",
+            self.entries.len(),
+            path.display()
+        ))
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n", TITLE);
+        for entry in &self.entries {
+            markdown.push('\n');
+            markdown.push_str(&code_block("rust", &entry.input));
+            if !entry.output.is_empty() {
+                markdown.push('\n');
+                markdown.push_str(&code_block("text", &entry.output));
+            }
+            if !entry.errors.is_empty() {
+                markdown.push_str("\nErrors:\n\n");
+                markdown.push_str(&code_block("text", &entry.errors));
+            }
+            writeln!(markdown, "\n_Took {}_", format_duration(entry.duration)).unwrap();
+        }
+        markdown
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+             <style>\n{}\n</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+            TITLE, HTML_STYLE, TITLE
+        );
+        for entry in &self.entries {
+            writeln!(
+                html,
+                "<pre class=\"input\">{}</pre>",
+                escape_html(&entry.input)
+            )
+            .unwrap();
+            if !entry.output.is_empty() {
+                writeln!(
+                    html,
+                    "<pre class=\"output\">{}</pre>",
+                    escape_html(&entry.output)
+                )
+                .unwrap();
+            }
+            if !entry.errors.is_empty() {
+                writeln!(
+                    html,
+                    "<pre class=\"errors\">{}</pre>",
+                    escape_html(&entry.errors)
+                )
+                .unwrap();
+            }
+            writeln!(
+                html,
+                "<div class=\"duration\">Took {}</div>",
+                format_duration(entry.duration)
+            )
+            .unwrap();
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+/// Collects lines written by user code while an evaluation runs. Clones share the same buffer, so
+/// one can be given to the thread that prints the lines.
+#[derive(Clone, Default)]
+pub struct CapturedOutput(Arc<Mutex<String>>);
+
+impl CapturedOutput {
+    pub fn push_line(&self, line: &str) {
+        let mut captured = self.0.lock();
+        captured.push_str(line);
+        captured.push('\n');
+    }
+
+    /// Returns what's been captured since the last call.
+    pub fn take(&self) -> String {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
+/// Returns a fenced Markdown code block containing `code`. The fence is made longer than any run
+/// of backticks in `code`, so that it can't end the block early.
+fn code_block(language: &str, code: &str) -> String {
+    let mut longest_run = 0;
+    let mut run = 0;
+    for c in code.chars() {
+        if c == '`' {
+            run += 1;
+            longest_run = longest_run.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    let fence = "`".repeat((longest_run + 1).max(3));
+    format!(
+        "{}{}\n{}\n{}\n",
+        fence,
+        language,
+        code.trim_end_matches('\n'),
+        fence
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.trim_end_matches('\n').chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.2}s", duration.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::code_block;
+    use super::CapturedOutput;
+    use super::Transcript;
+    use super::TranscriptEntry;
+    use std::time::Duration;
+
+    fn transcript() -> Transcript {
+        let mut transcript = Transcript::default();
+        transcript.record(TranscriptEntry {
+            input: "let v = vec![1, 2];\nprintln!(\"{:?}\", v);".to_owned(),
+            output: "[1, 2]\n".to_owned(),
+            errors: String::new(),
+            duration: Duration::from_millis(250),
+        });
+        transcript.record(TranscriptEntry {
+            input: "v < 3".to_owned(),
+            output: String::new(),
+            errors: "error[E0308]: mismatched types".to_owned(),
+            duration: Duration::from_millis(1500),
+        });
+        transcript
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            transcript().to_markdown(),
+            "# Evcxr session\n\n\
+             ```rust\nlet v = vec![1, 2];\nprintln!(\"{:?}\", v);\n```\n\n\
+             ```text\n[1, 2]\n```\n\n\
+             _Took 250ms_\n\n\
+             ```rust\nv < 3\n```\n\n\
+             Errors:\n\n\
+             ```text\nerror[E0308]: mismatched types\n```\n\n\
+             _Took 1.50s_\n"
+        );
+        assert_eq!(code_block("text", "a ``` b"), "````text\na ``` b\n````\n");
+    }
+
+    #[test]
+    fn test_html() {
+        let html = transcript().to_html();
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.contains("<pre class=\"input\">v &lt; 3</pre>\n"));
+        assert!(html.contains(
+            "<pre class=\"input\">let v = vec![1, 2];\nprintln!(&quot;{:?}&quot;, v);</pre>\n\
+             <pre class=\"output\">[1, 2]</pre>\n\
+             <div class=\"duration\">Took 250ms</div>\n"
+        ));
+        assert!(html.ends_with("</body>\n</html>\n"));
+    }
+
+    #[test]
+    fn test_handle_command() {
+        let transcript = transcript();
+        assert!(transcript.handle_command("").is_err());
+        assert!(transcript
+            .handle_command("notes.txt")
+            .unwrap_err()
+            .starts_with("Don't know what format"));
+        let path =
+            std::env::temp_dir().join(format!("evcxr_transcript_test_{}.md", std::process::id()));
+        assert_eq!(
+            transcript.handle_command(&path.display().to_string()),
+            Ok(format!(
+                "Transcript of 2 evaluations saved to {}",
+                path.display()
+            ))
+        );
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("# Evcxr session\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_captured_output() {
+        let captured = CapturedOutput::default();
+        captured.clone().push_line("one");
+        captured.push_line("two");
+        assert_eq!(captured.take(), "one\ntwo\n");
+        assert_eq!(captured.take(), "");
+    }
+}
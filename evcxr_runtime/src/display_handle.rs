@@ -0,0 +1,128 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Output that's shown while code is still running and can be replaced later, e.g. to animate a
+//! chart or keep a table up to date. Unlike `display`, whose output appears when the cell finishes,
+//! these are sent to the frontend straight away.
+
+use crate::mime_bundle::frontend_preferences;
+use crate::widgets::emit;
+use crate::widgets::json_string;
+use crate::EvcxrDisplay;
+use crate::MimeBundle;
+use std::borrow::Cow;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+// These prefixes must match those in evcxr's display_update module.
+const DISPLAY: &str = "EVCXR_DISPLAY ";
+const UPDATE_DISPLAY: &str = "EVCXR_UPDATE_DISPLAY ";
+const CLEAR_OUTPUT: &str = "EVCXR_CLEAR_OUTPUT ";
+
+/// Returns an ID that no other display in this process has.
+pub(crate) fn new_display_id(kind: &str) -> String {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "evcxr-{}-{}-{}",
+        kind,
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Sends `entries`, pairs of mime type and content, as the display with the specified ID. If
+/// `is_update`, they replace what was previously shown for that ID.
+pub(crate) fn send_display(display_id: &str, is_update: bool, entries: &[(&str, &str)]) {
+    let data: Vec<String> = entries
+        .iter()
+        .map(|(mime_type, content)| format!("{}:{}", json_string(mime_type), json_string(content)))
+        .collect();
+    emit(&format!(
+        "{}{} {{{}}}",
+        if is_update { UPDATE_DISPLAY } else { DISPLAY },
+        display_id,
+        data.join(",")
+    ));
+}
+
+fn send_value<T: EvcxrDisplay + ?Sized>(display_id: &str, is_update: bool, value: &T) {
+    let mut bundle = MimeBundle::with_preferences(frontend_preferences());
+    value.evcxr_mime_bundle(&mut bundle);
+    // Displays are sent as JSON, so binary content has to be base64 encoded.
+    let entries: Vec<(&str, Cow<'_, str>)> = bundle
+        .entries_to_emit()
+        .into_iter()
+        .map(|(mime_type, content)| (mime_type.as_str(), content.to_text()))
+        .collect();
+    let entries: Vec<(&str, &str)> = entries
+        .iter()
+        .map(|(mime_type, content)| (*mime_type, content.as_ref()))
+        .collect();
+    send_display(display_id, is_update, &entries);
+}
+
+/// A displayed value that can be replaced, e.g. with each frame of an animation.
+/// ```
+/// struct Counter(u32);
+///
+/// impl evcxr_runtime::EvcxrDisplay for Counter {
+///     fn evcxr_mime_bundle(&self, bundle: &mut evcxr_runtime::MimeBundle) {
+///         bundle.text(format!("Count: {}", self.0));
+///     }
+/// }
+///
+/// let handle = evcxr_runtime::DisplayHandle::new(&Counter(0));
+/// for i in 1..=10 {
+///     handle.update(&Counter(i));
+/// }
+/// ```
+pub struct DisplayHandle {
+    display_id: String,
+}
+
+impl DisplayHandle {
+    /// Displays `value` straight away and returns a handle for replacing it.
+    pub fn new<T: EvcxrDisplay + ?Sized>(value: &T) -> DisplayHandle {
+        DisplayHandle::with_id(new_display_id("display"), value)
+    }
+
+    /// Displays `value` with the specified ID. Any display with the same ID, including one shown
+    /// by an earlier cell, is updated together with this one.
+    pub fn with_id<S: Into<String>, T: EvcxrDisplay + ?Sized>(
+        display_id: S,
+        value: &T,
+    ) -> DisplayHandle {
+        let handle = DisplayHandle {
+            display_id: display_id.into(),
+        };
+        send_value(&handle.display_id, false, value);
+        handle
+    }
+
+    pub fn display_id(&self) -> &str {
+        &self.display_id
+    }
+
+    /// Replaces what's shown with `value`.
+    pub fn update<T: EvcxrDisplay + ?Sized>(&self, value: &T) {
+        send_value(&self.display_id, true, value);
+    }
+}
+
+/// Clears the output of the current cell, including anything printed and values passed to
+/// `display`. If `wait` is true, the output is only cleared once there's new output to replace
+/// it, which avoids flickering when redrawing repeatedly.
+pub fn clear_output(wait: bool) {
+    emit(&format!("{}{}", CLEAR_OUTPUT, wait));
+}
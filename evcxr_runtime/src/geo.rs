@@ -0,0 +1,224 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Displaying geometries from the geojson and geo-types crates. They're sent as GeoJSON, which
+//! JupyterLab shows as an interactive map, along with their WKT (well-known text) for frontends
+//! that can only show text, such as the REPL.
+
+use crate::EvcxrDisplay;
+use crate::MimeBundle;
+use geojson::Feature;
+use geojson::FeatureCollection;
+use geojson::GeoJson;
+use geojson::Geometry;
+use geojson::Value;
+
+const GEOJSON_MIME_TYPE: &str = "application/geo+json";
+
+/// Adds the GeoJSON produced by `json`, unless the frontend can't display it, and `wkt`.
+fn add_geojson(bundle: &mut MimeBundle, json: impl FnOnce() -> String, wkt: String) {
+    if bundle.accepts(GEOJSON_MIME_TYPE) {
+        bundle.add(GEOJSON_MIME_TYPE, json());
+    }
+    bundle.text(wkt);
+}
+
+impl EvcxrDisplay for Geometry {
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+        add_geojson(bundle, || self.to_string(), wkt(&self.value));
+    }
+}
+
+impl EvcxrDisplay for Feature {
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+        add_geojson(bundle, || self.to_string(), feature_wkt(self));
+    }
+}
+
+impl EvcxrDisplay for FeatureCollection {
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+        add_geojson(bundle, || self.to_string(), feature_collection_wkt(self));
+    }
+}
+
+impl EvcxrDisplay for GeoJson {
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+        match self {
+            GeoJson::Geometry(geometry) => geometry.evcxr_mime_bundle(bundle),
+            GeoJson::Feature(feature) => feature.evcxr_mime_bundle(bundle),
+            GeoJson::FeatureCollection(collection) => collection.evcxr_mime_bundle(bundle),
+        }
+    }
+}
+
+/// Implements `EvcxrDisplay` for geo-types geometries by converting them to GeoJSON.
+macro_rules! display_geo_types {
+    ($($name:ident),*) => {
+        $(
+            impl<T: ::geo_types::CoordFloat> EvcxrDisplay for ::geo_types::$name<T> {
+                fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+                    Geometry::new(Value::from(self)).evcxr_mime_bundle(bundle);
+                }
+            }
+        )*
+    };
+}
+
+display_geo_types!(
+    Geometry,
+    Point,
+    Line,
+    LineString,
+    Polygon,
+    MultiPoint,
+    MultiLineString,
+    MultiPolygon,
+    GeometryCollection,
+    Rect,
+    Triangle
+);
+
+/// Displays GeoJSON, e.g. a geometry or feature collection from the geojson crate. Fails if `json`
+/// isn't valid GeoJSON. The error is boxed, since `geojson::Error` is large.
+/// ```no_run
+/// evcxr_runtime::display_geojson(r#"{"type": "Point", "coordinates": [-0.1276, 51.5072]}"#)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn display_geojson(json: &str) -> Result<(), Box<geojson::Error>> {
+    crate::display(&json.parse::<GeoJson>()?);
+    Ok(())
+}
+
+fn feature_wkt(feature: &Feature) -> String {
+    match &feature.geometry {
+        Some(geometry) => wkt(&geometry.value),
+        None => "GEOMETRYCOLLECTION EMPTY".to_owned(),
+    }
+}
+
+/// Features are shown as a collection of their geometries.
+fn feature_collection_wkt(collection: &FeatureCollection) -> String {
+    let geometries: Vec<&Geometry> = collection
+        .features
+        .iter()
+        .filter_map(|feature| feature.geometry.as_ref())
+        .collect();
+    format!(
+        "GEOMETRYCOLLECTION {}",
+        wkt_list(&geometries, |geometry| wkt(&geometry.value))
+    )
+}
+
+/// Returns the WKT for `value`, e.g. `POLYGON ((0 0, 1 0, 1 1, 0 0))`.
+fn wkt(value: &Value) -> String {
+    match value {
+        Value::Point(position) => format!("POINT ({})", wkt_position(position)),
+        Value::MultiPoint(points) => format!(
+            "MULTIPOINT {}",
+            wkt_list(points, |position| format!("({})", wkt_position(position)))
+        ),
+        Value::LineString(line) => format!("LINESTRING {}", wkt_positions(line)),
+        Value::MultiLineString(lines) => format!(
+            "MULTILINESTRING {}",
+            wkt_list(lines, |line| wkt_positions(line))
+        ),
+        Value::Polygon(rings) => format!("POLYGON {}", wkt_polygon(rings)),
+        Value::MultiPolygon(polygons) => format!(
+            "MULTIPOLYGON {}",
+            wkt_list(polygons, |rings| wkt_polygon(rings))
+        ),
+        Value::GeometryCollection(geometries) => format!(
+            "GEOMETRYCOLLECTION {}",
+            wkt_list(geometries, |geometry| wkt(&geometry.value))
+        ),
+    }
+}
+
+fn wkt_position(position: &[f64]) -> String {
+    let coordinates: Vec<String> = position.iter().map(f64::to_string).collect();
+    coordinates.join(" ")
+}
+
+fn wkt_positions(positions: &[Vec<f64>]) -> String {
+    wkt_list(positions, |position| wkt_position(position))
+}
+
+fn wkt_polygon(rings: &[Vec<Vec<f64>>]) -> String {
+    wkt_list(rings, |ring| wkt_positions(ring))
+}
+
+/// Returns `items` formatted by `format`, separated by commas and in parentheses, or `EMPTY` if
+/// there aren't any.
+fn wkt_list<T>(items: &[T], format: impl Fn(&T) -> String) -> String {
+    if items.is_empty() {
+        return "EMPTY".to_owned();
+    }
+    let items: Vec<String> = items.iter().map(format).collect();
+    format!("({})", items.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wkt;
+    use crate::EvcxrDisplay;
+    use crate::MimeBundle;
+    use geojson::Geometry;
+    use geojson::Value;
+
+    #[test]
+    fn geometry_wkt() {
+        assert_eq!(wkt(&Value::Point(vec![1.0, 2.5])), "POINT (1 2.5)");
+        assert_eq!(
+            wkt(&Value::MultiPoint(vec![vec![1.0, 2.0], vec![3.0, 4.0]])),
+            "MULTIPOINT ((1 2), (3 4))"
+        );
+        assert_eq!(wkt(&Value::LineString(vec![])), "LINESTRING EMPTY");
+        let ring = vec![
+            vec![0.0, 0.0],
+            vec![1.0, 0.0],
+            vec![1.0, 1.0],
+            vec![0.0, 0.0],
+        ];
+        assert_eq!(
+            wkt(&Value::Polygon(vec![ring.clone()])),
+            "POLYGON ((0 0, 1 0, 1 1, 0 0))"
+        );
+        assert_eq!(
+            wkt(&Value::MultiPolygon(vec![vec![ring]])),
+            "MULTIPOLYGON (((0 0, 1 0, 1 1, 0 0)))"
+        );
+        assert_eq!(
+            wkt(&Value::GeometryCollection(vec![Geometry::new(
+                Value::Point(vec![1.0, 2.0])
+            )])),
+            "GEOMETRYCOLLECTION (POINT (1 2))"
+        );
+    }
+
+    #[test]
+    fn geo_types_bundle() {
+        let mut bundle = MimeBundle::with_preferences(Vec::new());
+        ::geo_types::Point::new(1.0, 2.0).evcxr_mime_bundle(&mut bundle);
+        assert_eq!(bundle.content("text/plain"), Some("POINT (1 2)"));
+        assert!(bundle
+            .content("application/geo+json")
+            .unwrap()
+            .contains("\"coordinates\":[1.0,2.0]"));
+
+        // GeoJSON is skipped if the frontend can't display it.
+        let mut bundle = MimeBundle::with_preferences(vec!["text/plain".to_owned()]);
+        ::geo_types::Point::new(1.0, 2.0).evcxr_mime_bundle(&mut bundle);
+        assert_eq!(bundle.content("application/geo+json"), None);
+    }
+}
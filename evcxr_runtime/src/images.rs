@@ -0,0 +1,126 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for displaying images, e.g. plots that have been rendered to PNG or SVG.
+
+/// Displays an SVG image.
+/// ```
+/// evcxr_runtime::display_svg(
+///     r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">
+///         <circle cx="10" cy="10" r="8" fill="teal"/>
+///     </svg>"#,
+/// );
+/// ```
+pub fn display_svg(svg: &str) {
+    crate::mime_type("image/svg+xml").text(svg);
+}
+
+/// Displays an image that's already been encoded as a PNG.
+/// ```no_run
+/// let png = std::fs::read("plot.png").unwrap();
+/// evcxr_runtime::display_png(&png);
+/// ```
+#[cfg(feature = "bytes")]
+pub fn display_png(png: &[u8]) {
+    crate::mime_type("image/png").bytes(png);
+}
+
+/// Displays an image that's already been encoded as a JPEG.
+#[cfg(feature = "bytes")]
+pub fn display_jpeg(jpeg: &[u8]) {
+    crate::mime_type("image/jpeg").bytes(jpeg);
+}
+
+#[cfg(feature = "image")]
+mod image_crate_support {
+    use crate::EvcxrDisplay;
+    use crate::MimeBundle;
+    use image_crate::GenericImageView;
+    use std::ops::Deref;
+
+    /// Adds a PNG, produced by `encode`, to `bundle`, unless the frontend can't display it. Either
+    /// way, adds a plain-text description.
+    fn add_png(
+        bundle: &mut MimeBundle,
+        width: u32,
+        height: u32,
+        encode: impl FnOnce(&mut Vec<u8>) -> ::image_crate::ImageResult<()>,
+    ) {
+        let mut description = format!("Image {}x{}", width, height);
+        if bundle.accepts("image/png") {
+            let mut png = Vec::new();
+            match encode(&mut png) {
+                Ok(()) => {
+                    bundle.png(&png);
+                }
+                Err(error) => {
+                    description = format!("{} (failed to encode: {})", description, error)
+                }
+            }
+        }
+        bundle.text(description);
+    }
+
+    /// Images with 8 bits per channel, such as those from `ImageBuffer::from_fn`.
+    impl<P, C> EvcxrDisplay for ::image_crate::ImageBuffer<P, C>
+    where
+        P: ::image_crate::Pixel<Subpixel = u8> + 'static,
+        C: Deref<Target = [u8]>,
+    {
+        fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+            add_png(bundle, self.width(), self.height(), |png| {
+                ::image_crate::png::PngEncoder::new(png).encode(
+                    self,
+                    self.width(),
+                    self.height(),
+                    P::COLOR_TYPE,
+                )
+            });
+        }
+    }
+
+    /// Images of any format, such as those loaded with `image::open`.
+    impl EvcxrDisplay for ::image_crate::DynamicImage {
+        fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+            add_png(bundle, self.width(), self.height(), |png| {
+                self.write_to(png, ::image_crate::ImageOutputFormat::Png)
+            });
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::EvcxrDisplay;
+        use crate::MimeBundle;
+
+        #[test]
+        fn image_to_png() {
+            let image =
+                ::image_crate::ImageBuffer::from_fn(4, 3, |x, _y| ::image_crate::Luma([x as u8]));
+            let mut bundle = MimeBundle::with_preferences(Vec::new());
+            image.evcxr_mime_bundle(&mut bundle);
+            assert!(bundle
+                .content_bytes("image/png")
+                .unwrap()
+                .starts_with(b"\x89PNG"));
+            assert_eq!(bundle.content("text/plain"), Some("Image 4x3"));
+
+            // PNG encoding is skipped if the frontend can't display it.
+            let mut bundle = MimeBundle::with_preferences(vec!["text/plain".to_owned()]);
+            ::image_crate::DynamicImage::ImageLuma8(image).evcxr_mime_bundle(&mut bundle);
+            assert_eq!(bundle.content("image/png"), None);
+            assert_eq!(bundle.content("text/plain"), Some("Image 4x3"));
+        }
+    }
+}
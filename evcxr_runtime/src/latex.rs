@@ -0,0 +1,90 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Displaying mathematical formulas written in LaTeX. Jupyter typesets them, while the REPL shows
+//! an approximation made of unicode characters.
+
+use crate::EvcxrDisplay;
+use crate::MimeBundle;
+
+/// A type that can be written as a LaTeX formula, e.g. an expression from a symbolic math crate.
+/// Implementing this implements `EvcxrDisplay`, so values are shown as formulas when they're the
+/// result of a cell.
+/// ```
+/// struct Fraction(i64, i64);
+///
+/// impl evcxr_runtime::EvcxrLatex for Fraction {
+///     fn evcxr_latex(&self) -> String {
+///         format!(r"\frac{{{}}}{{{}}}", self.0, self.1)
+///     }
+/// }
+/// ```
+pub trait EvcxrLatex {
+    /// Returns the formula in LaTeX math mode, without `$` delimiters, e.g. `x^{2} + \alpha`.
+    fn evcxr_latex(&self) -> String;
+}
+
+impl<T: EvcxrLatex + ?Sized> EvcxrDisplay for T {
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+        let formula = self.evcxr_latex();
+        bundle.latex(format!("$${}$$", formula)).text(formula);
+    }
+}
+
+/// A formula given as LaTeX source in math mode.
+/// ```
+/// evcxr_runtime::Latex::new(r"e^{i\pi} + 1 = 0")
+/// # ;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Latex {
+    formula: String,
+}
+
+impl Latex {
+    pub fn new<S: Into<String>>(formula: S) -> Latex {
+        Latex {
+            formula: formula.into(),
+        }
+    }
+}
+
+impl EvcxrLatex for Latex {
+    fn evcxr_latex(&self) -> String {
+        self.formula.clone()
+    }
+}
+
+/// Displays a formula given as LaTeX source in math mode.
+/// ```
+/// evcxr_runtime::display_latex(r"\sum_{i=1}^{n} i = \frac{n(n+1)}{2}");
+/// ```
+pub fn display_latex(formula: &str) {
+    crate::display(&Latex::new(formula));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Latex;
+    use crate::EvcxrDisplay;
+    use crate::MimeBundle;
+
+    #[test]
+    fn formula_bundle() {
+        let mut bundle = MimeBundle::with_preferences(Vec::new());
+        Latex::new(r"\alpha^2").evcxr_mime_bundle(&mut bundle);
+        assert_eq!(bundle.content("text/latex"), Some(r"$$\alpha^2$$"));
+        assert_eq!(bundle.content("text/plain"), Some(r"\alpha^2"));
+    }
+}
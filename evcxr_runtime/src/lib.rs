@@ -14,7 +14,75 @@
 
 #[cfg(feature = "bytes")]
 extern crate base64;
+#[cfg(feature = "csv")]
+extern crate csv;
+#[cfg(feature = "geo")]
+extern crate geo_types;
+#[cfg(feature = "geo")]
+extern crate geojson;
+#[cfg(feature = "image")]
+extern crate image_crate;
+#[cfg(feature = "parquet")]
+extern crate parquet;
 
+#[cfg(any(feature = "csv", feature = "parquet"))]
+mod preview;
+#[cfg(feature = "csv")]
+pub use preview::preview_csv;
+#[cfg(feature = "parquet")]
+pub use preview::preview_parquet;
+#[cfg(any(feature = "csv", feature = "parquet"))]
+pub use preview::PreviewResult;
+
+mod display_handle;
+pub use display_handle::clear_output;
+pub use display_handle::DisplayHandle;
+
+#[cfg(feature = "geo")]
+mod geo;
+#[cfg(feature = "geo")]
+pub use geo::display_geojson;
+
+mod images;
+#[cfg(feature = "bytes")]
+pub use images::display_jpeg;
+#[cfg(feature = "bytes")]
+pub use images::display_png;
+pub use images::display_svg;
+
+mod latex;
+pub use latex::display_latex;
+pub use latex::EvcxrLatex;
+pub use latex::Latex;
+
+#[cfg(feature = "bytes")]
+mod media;
+#[cfg(feature = "bytes")]
+pub use media::display_audio;
+#[cfg(feature = "bytes")]
+pub use media::display_video_file;
+
+mod mime_bundle;
+pub use mime_bundle::display;
+pub use mime_bundle::EvcxrDisplay;
+pub use mime_bundle::MimeBundle;
+
+mod progress;
+pub use progress::progress;
+pub use progress::ProgressBar;
+pub use progress::ProgressIter;
+
+mod widgets;
+pub use widgets::FloatSlider;
+pub use widgets::IntSlider;
+pub use widgets::Progress;
+pub use widgets::Widget;
+
+use std::io::Write;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Prefer implementing `EvcxrDisplay`, which implements this for you.
 pub trait Display {
     /// Implementation should emit a representation of itself in one or mime
     /// types  using the functions below.
@@ -53,21 +121,119 @@ impl ContentMimeType {
     }
 
     /// Emits the supplied content, which should be of the mime type already
-    /// specified. The content is a binary format (e.g. image/png), the content
-    /// will be base64 encoded.
+    /// specified. The content is a binary format (e.g. image/png). It's sent to
+    /// evcxr as raw bytes.
     /// ```
     /// let buffer: Vec<u8> = vec![];
     /// evcxr_runtime::mime_type("image/png").bytes(&buffer);
     /// ```
     #[cfg(feature = "bytes")]
     pub fn bytes(self, buffer: &[u8]) {
-        self.text(&base64::encode(buffer))
+        let stdout = std::io::stdout();
+        let _ = write_binary_content(&mut stdout.lock(), &self.mime_type, buffer);
     }
 }
 
+// Must match evcxr's evcxr_internal_runtime module.
+#[cfg(feature = "bytes")]
+const BEGIN_BINARY_CONTENT: &str = "EVCXR_BEGIN_BINARY_CONTENT ";
+
+/// Writes binary content of type `mime_type` in the form that evcxr expects: a line with its
+/// length, followed by the raw bytes. That avoids encoding it as text, only for evcxr to decode it.
+#[cfg(feature = "bytes")]
+pub(crate) fn write_binary_content<W: Write>(
+    out: &mut W,
+    mime_type: &str,
+    data: &[u8],
+) -> std::io::Result<()> {
+    writeln!(out, "{}{} {}", BEGIN_BINARY_CONTENT, mime_type, data.len())?;
+    out.write_all(data)?;
+    out.flush()
+}
+
+/// Controls how `stream` batches up output.
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    /// How long to accumulate items before writing them out.
+    pub flush_interval: Duration,
+    /// The maximum number of items to accumulate before writing them out, regardless of how much
+    /// time has passed.
+    pub max_batch: usize,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions {
+            flush_interval: Duration::from_millis(250),
+            max_batch: 1000,
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Consumes `iter`, displaying items as they're produced. See `stream`.
+    pub fn stream<I>(&self, iter: I) -> usize
+    where
+        I: IntoIterator,
+        I::Item: std::fmt::Debug,
+    {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        self.stream_to(&mut out, iter).unwrap_or(0)
+    }
+
+    fn stream_to<W, I>(&self, out: &mut W, iter: I) -> std::io::Result<usize>
+    where
+        W: Write,
+        I: IntoIterator,
+        I::Item: std::fmt::Debug,
+    {
+        let mut count = 0;
+        let mut pending = String::new();
+        let mut pending_count = 0;
+        let mut last_flush = Instant::now();
+        for item in iter {
+            count += 1;
+            pending_count += 1;
+            pending.push_str(&format!("{:?}\n", item));
+            if pending_count >= self.max_batch || last_flush.elapsed() >= self.flush_interval {
+                out.write_all(pending.as_bytes())?;
+                out.flush()?;
+                pending.clear();
+                pending_count = 0;
+                last_flush = Instant::now();
+            }
+        }
+        out.write_all(pending.as_bytes())?;
+        writeln!(out, "[{} item{}]", count, if count == 1 { "" } else { "s" })?;
+        out.flush()?;
+        Ok(count)
+    }
+}
+
+/// Consumes `iter`, displaying each item (using its `Debug` implementation) as it's produced,
+/// rather than waiting until all items are available. Items are written in batches, at most every
+/// 250ms, followed by a count of how many items there were. Returns that count.
+/// ```
+/// let count = evcxr_runtime::stream((0..5).map(|i| i * i));
+/// assert_eq!(count, 5);
+/// ```
+/// Use `StreamOptions` to control batching.
+pub fn stream<I>(iter: I) -> usize
+where
+    I: IntoIterator,
+    I::Item: std::fmt::Debug,
+{
+    StreamOptions::default().stream(iter)
+}
+
 #[cfg(test)]
 mod tests {
     use super::mime_type;
+    #[cfg(feature = "bytes")]
+    use super::write_binary_content;
+    use super::StreamOptions;
+    use std::time::Duration;
 
     #[test]
     fn test_emit_data() {
@@ -78,4 +244,27 @@ mod tests {
     fn test_mime_type_accept_string() {
         mime_type("text/plain".to_owned()).text("Hello world");
     }
+
+    #[test]
+    #[cfg(feature = "bytes")]
+    fn test_binary_content() {
+        let mut out = Vec::new();
+        write_binary_content(&mut out, "image/png", b"\x89\n\0").unwrap();
+        assert_eq!(out, b"EVCXR_BEGIN_BINARY_CONTENT image/png 3\n\x89\n\0");
+    }
+
+    #[test]
+    fn test_stream_batches() {
+        let options = StreamOptions {
+            flush_interval: Duration::from_secs(3600),
+            max_batch: 2,
+        };
+        let mut out = Vec::new();
+        assert_eq!(options.stream_to(&mut out, vec![1, 2, 3]).unwrap(), 3);
+        assert_eq!(String::from_utf8(out).unwrap(), "1\n2\n3\n[3 items]\n");
+
+        let mut out = Vec::new();
+        assert_eq!(options.stream_to(&mut out, vec!["a"]).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), "\"a\"\n[1 item]\n");
+    }
 }
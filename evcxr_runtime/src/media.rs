@@ -0,0 +1,184 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for playing audio and video, e.g. signals produced while experimenting with DSP code.
+//! Media is sent both as is and base64 encoded inside an HTML player, since not all frontends play
+//! media types directly.
+
+use crate::EvcxrDisplay;
+use crate::MimeBundle;
+use std::path::Path;
+
+/// Audio or video, already encoded.
+struct Media {
+    mime_type: &'static str,
+    /// The HTML element that plays it, `audio` or `video`.
+    element: &'static str,
+    data: Vec<u8>,
+    description: String,
+}
+
+impl EvcxrDisplay for Media {
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+        if bundle.accepts("text/html") {
+            bundle.html(format!(
+                "<{element} controls src=\"data:{mime_type};base64,{encoded}\"></{element}>",
+                element = self.element,
+                mime_type = self.mime_type,
+                encoded = base64::encode(&self.data)
+            ));
+        }
+        if bundle.accepts(self.mime_type) {
+            bundle.bytes(self.mime_type, self.data.clone());
+        }
+        bundle.text(self.description.clone());
+    }
+}
+
+/// Plays `samples`, which are mono audio at `sample_rate` samples per second, ranging from -1.0 to
+/// 1.0. Samples outside that range are clipped.
+/// ```
+/// let rate = 44100;
+/// let samples: Vec<f32> = (0..rate)
+///     .map(|i| (i as f32 * 440.0 * 2.0 * std::f32::consts::PI / rate as f32).sin())
+///     .collect();
+/// evcxr_runtime::display_audio(&samples, rate);
+/// ```
+pub fn display_audio(samples: &[f32], sample_rate: u32) {
+    crate::display(&Media {
+        mime_type: "audio/wav",
+        element: "audio",
+        data: encode_wav(samples, sample_rate),
+        description: format!(
+            "Audio: {} samples at {} Hz ({:.2}s)",
+            samples.len(),
+            sample_rate,
+            samples.len() as f64 / f64::from(sample_rate.max(1))
+        ),
+    });
+}
+
+/// Plays a video file. The format is worked out from the file's extension, which should be one of
+/// `mp4`, `webm` or `ogv`.
+/// ```no_run
+/// evcxr_runtime::display_video_file("simulation.mp4")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn display_video_file<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mime_type = video_mime_type(path).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unsupported video format: {}", path.display()),
+        )
+    })?;
+    let data = std::fs::read(path)?;
+    crate::display(&Media {
+        mime_type,
+        element: "video",
+        description: format!("Video: {} ({} bytes)", path.display(), data.len()),
+        data,
+    });
+    Ok(())
+}
+
+fn video_mime_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" => Some("video/mp4"),
+        "webm" => Some("video/webm"),
+        "ogv" | "ogg" => Some("video/ogg"),
+        _ => None,
+    }
+}
+
+/// Encodes `samples` as a mono WAV file with 16 bits per sample.
+fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    const CHANNELS: u16 = 1;
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_len = samples.len() as u32 * u32::from(block_align);
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * u32::from(block_align)).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_wav;
+    use super::video_mime_type;
+    use super::Media;
+    use crate::EvcxrDisplay;
+    use crate::MimeBundle;
+    use std::path::Path;
+
+    #[test]
+    fn wav_encoding() {
+        let wav = encode_wav(&[0.0, 1.0, -2.0], 8000);
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[4..8], &42u32.to_le_bytes());
+        assert_eq!(&wav[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&wav[28..32], &16000u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &6u32.to_le_bytes());
+        assert_eq!(&wav[44..], &[0, 0, 0xff, 0x7f, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn video_types() {
+        assert_eq!(video_mime_type(Path::new("a/b.MP4")), Some("video/mp4"));
+        assert_eq!(video_mime_type(Path::new("b.webm")), Some("video/webm"));
+        assert_eq!(video_mime_type(Path::new("b.avi")), None);
+        assert_eq!(video_mime_type(Path::new("b")), None);
+    }
+
+    #[test]
+    fn media_bundle() {
+        let media = Media {
+            mime_type: "audio/wav",
+            element: "audio",
+            data: vec![1, 2, 3],
+            description: "Audio".to_owned(),
+        };
+        let mut bundle = MimeBundle::with_preferences(vec!["text/html".to_owned()]);
+        media.evcxr_mime_bundle(&mut bundle);
+        assert_eq!(
+            bundle.content("text/html"),
+            Some("<audio controls src=\"data:audio/wav;base64,AQID\"></audio>")
+        );
+        assert_eq!(bundle.content("audio/wav"), None);
+        assert_eq!(bundle.content("text/plain"), Some("Audio"));
+
+        let mut bundle = MimeBundle::with_preferences(Vec::new());
+        media.evcxr_mime_bundle(&mut bundle);
+        assert_eq!(bundle.content_bytes("audio/wav"), Some(&[1, 2, 3][..]));
+    }
+}
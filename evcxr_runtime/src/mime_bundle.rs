@@ -0,0 +1,317 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Displaying values as a bundle of representations, e.g. HTML and plain text, from which the
+//! frontend picks the one it likes best. The frontend tells us which mime types it can display, so
+//! that values can avoid producing representations that won't be used.
+
+use std::borrow::Cow;
+use std::io::Write;
+
+// Must match evcxr's evcxr_internal_runtime module.
+const GET_MIME_PREFERENCES: &str = "EVCXR_GET_MIME_PREFERENCES";
+// Set by evcxr in the process that runs user code.
+const EVCXR_IS_RUNTIME_VAR: &str = "EVCXR_IS_RUNTIME";
+const ANY_MIME_TYPE: &str = "*/*";
+
+/// A type that can be displayed as one or more mime types. Implementing this also implements
+/// `Display`, so values are shown automatically when they're the result of a cell.
+/// ```
+/// struct Celsius(f64);
+///
+/// impl evcxr_runtime::EvcxrDisplay for Celsius {
+///     fn evcxr_mime_bundle(&self, bundle: &mut evcxr_runtime::MimeBundle) {
+///         bundle
+///             .html(format!("<b>{:.1}&deg;C</b>", self.0))
+///             .text(format!("{:.1}°C", self.0));
+///     }
+/// }
+/// ```
+pub trait EvcxrDisplay {
+    /// Adds representations of `self` to `bundle`. Representations that are expensive to produce
+    /// can be skipped if `bundle.accepts` says that the frontend can't display them.
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle);
+}
+
+impl<T: EvcxrDisplay + ?Sized> crate::Display for T {
+    fn evcxr_display(&self) {
+        display(self);
+    }
+}
+
+/// Displays `value`, producing whichever of its representations the frontend can display.
+pub fn display<T: EvcxrDisplay + ?Sized>(value: &T) {
+    let mut bundle = MimeBundle::with_preferences(frontend_preferences());
+    value.evcxr_mime_bundle(&mut bundle);
+    bundle.emit();
+}
+
+/// Asks evcxr which mime types the frontend can display. Returns an empty list, meaning anything,
+/// if we're not running inside evcxr or it has no preference.
+pub(crate) fn frontend_preferences() -> Vec<String> {
+    if std::env::var_os(EVCXR_IS_RUNTIME_VAR).is_none() {
+        return Vec::new();
+    }
+    {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        let _ = writeln!(out, "{}", GET_MIME_PREFERENCES);
+        let _ = out.flush();
+    }
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line).is_err() {
+        return Vec::new();
+    }
+    line.split_whitespace().map(str::to_owned).collect()
+}
+
+/// A representation in a `MimeBundle`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Content {
+    Text(String),
+    /// Content of a binary format, e.g. image/png, which is sent as it is, not encoded as text.
+    #[cfg(feature = "bytes")]
+    Bytes(Vec<u8>),
+}
+
+impl Content {
+    /// Returns the content as text, base64 encoding binary content. Only for where the content has
+    /// to be sent as text, e.g. inside JSON.
+    pub(crate) fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            Content::Text(text) => Cow::Borrowed(text),
+            #[cfg(feature = "bytes")]
+            Content::Bytes(bytes) => Cow::Owned(base64::encode(bytes)),
+        }
+    }
+}
+
+/// Representations of a value, keyed by mime type.
+pub struct MimeBundle {
+    /// The mime types that the frontend can display, most preferred first. Empty if it can display
+    /// anything.
+    preferences: Vec<String>,
+    entries: Vec<(String, Content)>,
+}
+
+impl MimeBundle {
+    pub(crate) fn with_preferences(preferences: Vec<String>) -> MimeBundle {
+        MimeBundle {
+            preferences,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Returns where `mime_type` comes in the frontend's preferences, or None if the frontend can't
+    /// display it.
+    fn rank(&self, mime_type: &str) -> Option<usize> {
+        if self.preferences.is_empty() {
+            return Some(0);
+        }
+        self.preferences
+            .iter()
+            .position(|preference| preference == mime_type)
+            .or_else(|| {
+                self.preferences
+                    .iter()
+                    .position(|preference| preference == ANY_MIME_TYPE)
+            })
+    }
+
+    /// Returns whether the frontend can display `mime_type`.
+    pub fn accepts(&self, mime_type: &str) -> bool {
+        self.rank(mime_type).is_some()
+    }
+
+    /// Returns whichever of `candidates` the frontend would most like to display, or None if it
+    /// can't display any of them. Ties go to whichever comes first in `candidates`. Useful for
+    /// types that only want to produce a single representation.
+    pub fn preferred<'a>(&self, candidates: &[&'a str]) -> Option<&'a str> {
+        candidates
+            .iter()
+            .filter_map(|candidate| Some((self.rank(candidate)?, *candidate)))
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, candidate)| candidate)
+    }
+
+    /// Adds content of the specified mime type, replacing any previous content of that type. Binary
+    /// content (e.g. image/png) should be added with `bytes` instead.
+    pub fn add<M: Into<String>, S: Into<String>>(&mut self, mime_type: M, content: S) -> &mut Self {
+        self.add_content(mime_type.into(), Content::Text(content.into()))
+    }
+
+    /// Adds binary content (e.g. image/png) of the specified mime type, replacing any previous
+    /// content of that type.
+    #[cfg(feature = "bytes")]
+    pub fn bytes<M: Into<String>, B: Into<Vec<u8>>>(
+        &mut self,
+        mime_type: M,
+        bytes: B,
+    ) -> &mut Self {
+        self.add_content(mime_type.into(), Content::Bytes(bytes.into()))
+    }
+
+    fn add_content(&mut self, mime_type: String, content: Content) -> &mut Self {
+        self.entries.retain(|(existing, _)| *existing != mime_type);
+        self.entries.push((mime_type, content));
+        self
+    }
+
+    /// Adds a text/plain representation.
+    pub fn text<S: Into<String>>(&mut self, text: S) -> &mut Self {
+        self.add("text/plain", text)
+    }
+
+    /// Adds a text/html representation.
+    pub fn html<S: Into<String>>(&mut self, html: S) -> &mut Self {
+        self.add("text/html", html)
+    }
+
+    /// Adds a text/latex representation. Formulas should be delimited, e.g. with `$$`.
+    pub fn latex<S: Into<String>>(&mut self, latex: S) -> &mut Self {
+        self.add("text/latex", latex)
+    }
+
+    /// Adds an application/json representation. `json` should already be serialized.
+    pub fn json<S: Into<String>>(&mut self, json: S) -> &mut Self {
+        self.add("application/json", json)
+    }
+
+    /// Adds an image/png representation from the supplied PNG data.
+    #[cfg(feature = "bytes")]
+    pub fn png(&mut self, png: &[u8]) -> &mut Self {
+        self.bytes("image/png", png)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn content(&self, mime_type: &str) -> Option<&str> {
+        match self.entry(mime_type)? {
+            Content::Text(text) => Some(text),
+            #[cfg(feature = "bytes")]
+            Content::Bytes(_) => None,
+        }
+    }
+
+    #[cfg(all(test, feature = "bytes"))]
+    pub(crate) fn content_bytes(&self, mime_type: &str) -> Option<&[u8]> {
+        match self.entry(mime_type)? {
+            Content::Bytes(bytes) => Some(bytes),
+            Content::Text(_) => None,
+        }
+    }
+
+    #[cfg(test)]
+    fn entry(&self, mime_type: &str) -> Option<&Content> {
+        self.entries
+            .iter()
+            .find(|(existing, _)| existing == mime_type)
+            .map(|(_, content)| content)
+    }
+
+    /// Returns the entries to emit, most preferred first. Entries that the frontend can't display
+    /// are dropped, unless that would leave nothing.
+    pub(crate) fn entries_to_emit(&self) -> Vec<&(String, Content)> {
+        let mut entries: Vec<(usize, &(String, Content))> = self
+            .entries
+            .iter()
+            .filter_map(|entry| Some((self.rank(&entry.0)?, entry)))
+            .collect();
+        if entries.is_empty() {
+            return self.entries.iter().collect();
+        }
+        entries.sort_by_key(|(rank, _)| *rank);
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    fn emit(&self) {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        for (mime_type, content) in self.entries_to_emit() {
+            let _ = match content {
+                Content::Text(text) => writeln!(
+                    out,
+                    "EVCXR_BEGIN_CONTENT {}\n{}\nEVCXR_END_CONTENT",
+                    mime_type, text
+                ),
+                #[cfg(feature = "bytes")]
+                Content::Bytes(bytes) => crate::write_binary_content(&mut out, mime_type, bytes),
+            };
+        }
+        let _ = out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Content;
+    use super::MimeBundle;
+
+    fn sample_bundle(preferences: &[&str]) -> MimeBundle {
+        let mut bundle =
+            MimeBundle::with_preferences(preferences.iter().map(|p| (*p).to_owned()).collect());
+        bundle.text("plain").json("{}").html("<b>html</b>");
+        bundle
+    }
+
+    fn emitted_types(bundle: &MimeBundle) -> Vec<&str> {
+        bundle
+            .entries_to_emit()
+            .into_iter()
+            .map(|(mime_type, _)| mime_type.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn no_preferences() {
+        let bundle = sample_bundle(&[]);
+        assert!(bundle.accepts("image/png"));
+        assert_eq!(bundle.content("text/html"), Some("<b>html</b>"));
+        assert_eq!(
+            emitted_types(&bundle),
+            vec!["text/plain", "application/json", "text/html"]
+        );
+        assert_eq!(
+            bundle.preferred(&["image/png", "text/html"]),
+            Some("image/png")
+        );
+    }
+
+    #[test]
+    fn preference_order() {
+        let bundle = sample_bundle(&["text/html", "text/plain"]);
+        assert!(!bundle.accepts("application/json"));
+        assert_eq!(emitted_types(&bundle), vec!["text/html", "text/plain"]);
+        assert_eq!(
+            bundle.preferred(&["text/plain", "text/html"]),
+            Some("text/html")
+        );
+        assert_eq!(bundle.preferred(&["image/png"]), None);
+
+        let bundle = sample_bundle(&["text/html", "*/*", "text/plain"]);
+        assert_eq!(
+            emitted_types(&bundle),
+            vec!["text/html", "application/json", "text/plain"]
+        );
+    }
+
+    #[test]
+    fn nothing_accepted() {
+        let mut bundle = MimeBundle::with_preferences(vec!["text/plain".to_owned()]);
+        bundle.html("a").html("b");
+        assert_eq!(
+            bundle.entries_to_emit(),
+            vec![&("text/html".to_owned(), Content::Text("b".to_owned()))]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Previews of data files, showing their schema and first few rows.
+
+use std::error::Error;
+use std::path::Path;
+
+pub type PreviewResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// The schema and first rows of a file.
+struct Preview {
+    /// Name and type of each column.
+    columns: Vec<(String, String)>,
+    rows: Vec<Vec<String>>,
+    /// The total number of rows in the file, if known without reading all of it.
+    total_rows: Option<u64>,
+}
+
+impl Preview {
+    fn summary(&self) -> String {
+        match self.total_rows {
+            Some(total) => format!("{} of {} rows", self.rows.len(), total),
+            None => format!("first {} rows", self.rows.len()),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut widths: Vec<usize> = self
+            .columns
+            .iter()
+            .map(|(name, ty)| name.chars().count().max(ty.chars().count()))
+            .collect();
+        for row in &self.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.chars().count());
+            }
+        }
+        let mut out = String::new();
+        push_text_row(&mut out, &widths, self.columns.iter().map(|(name, _)| name));
+        push_text_row(&mut out, &widths, self.columns.iter().map(|(_, ty)| ty));
+        let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+        push_text_row(&mut out, &widths, rule.iter());
+        for row in &self.rows {
+            push_text_row(&mut out, &widths, row.iter());
+        }
+        out.push_str(&format!("({})", self.summary()));
+        out
+    }
+
+    fn to_html(&self) -> String {
+        let mut out = String::from("<table><thead><tr>");
+        for (name, ty) in &self.columns {
+            out.push_str(&format!(
+                "<th>{}<br><small>{}</small></th>",
+                escape_html(name),
+                escape_html(ty)
+            ));
+        }
+        out.push_str("</tr></thead><tbody>");
+        for row in &self.rows {
+            out.push_str("<tr>");
+            for value in row {
+                out.push_str(&format!("<td>{}</td>", escape_html(value)));
+            }
+            out.push_str("</tr>");
+        }
+        out.push_str(&format!(
+            "</tbody></table><p><small>{}</small></p>",
+            self.summary()
+        ));
+        out
+    }
+
+    fn display(&self) {
+        ::mime_type("text/plain").text(self.to_text());
+        ::mime_type("text/html").text(self.to_html());
+    }
+}
+
+fn push_text_row<'a, I: Iterator<Item = &'a String>>(
+    out: &mut String,
+    widths: &[usize],
+    values: I,
+) {
+    let cells: Vec<String> = values
+        .zip(widths)
+        .map(|(value, width)| format!("{:<width$}", value, width = width))
+        .collect();
+    out.push_str(cells.join(" | ").trim_end());
+    out.push('\n');
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Guesses the type of a CSV column from the values that we've seen.
+#[cfg(feature = "csv")]
+fn infer_csv_type<'a, I: Iterator<Item = &'a str>>(values: I) -> &'static str {
+    let mut ty = None;
+    for value in values.filter(|value| !value.is_empty()) {
+        let value_type = if value.parse::<i64>().is_ok() {
+            "integer"
+        } else if value.parse::<f64>().is_ok() {
+            "float"
+        } else if value == "true" || value == "false" {
+            "bool"
+        } else {
+            return "text";
+        };
+        ty = match (ty, value_type) {
+            (None, t) => Some(t),
+            (Some("integer"), "float") | (Some("float"), "integer") => Some("float"),
+            (Some(a), b) if a == b => Some(a),
+            _ => return "text",
+        };
+    }
+    ty.unwrap_or("text")
+}
+
+#[cfg(feature = "csv")]
+fn csv_preview<R: std::io::Read>(reader: R, n: usize) -> Result<Preview, ::csv::Error> {
+    let mut reader = ::csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(reader);
+    let headers: Vec<String> = reader.headers()?.iter().map(str::to_owned).collect();
+    let mut rows = Vec::new();
+    for record in reader.records().take(n) {
+        rows.push(record?.iter().map(str::to_owned).collect::<Vec<_>>());
+    }
+    let columns = headers
+        .into_iter()
+        .enumerate()
+        .map(|(index, name)| {
+            let values = rows
+                .iter()
+                .filter_map(|row: &Vec<String>| row.get(index).map(String::as_str));
+            (name, infer_csv_type(values).to_owned())
+        })
+        .collect();
+    Ok(Preview {
+        columns,
+        rows,
+        total_rows: None,
+    })
+}
+
+/// Displays the columns of a CSV file and its first `n` rows as a table. The first row of the
+/// file is taken to be headers. Column types are guessed from the rows that are displayed.
+/// ```no_run
+/// evcxr_runtime::preview_csv("data.csv", 10)?;
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[cfg(feature = "csv")]
+pub fn preview_csv<P: AsRef<Path>>(path: P, n: usize) -> PreviewResult {
+    let file = std::fs::File::open(path)?;
+    csv_preview(file, n)?.display();
+    Ok(())
+}
+
+/// Displays the schema of a Parquet file and its first `n` rows as a table.
+/// ```no_run
+/// evcxr_runtime::preview_parquet("data.parquet", 10)?;
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[cfg(feature = "parquet")]
+pub fn preview_parquet<P: AsRef<Path>>(path: P, n: usize) -> PreviewResult {
+    use parquet::file::reader::FileReader;
+    use parquet::file::reader::SerializedFileReader;
+
+    let reader = SerializedFileReader::new(std::fs::File::open(path)?)?;
+    let file_metadata = reader.metadata().file_metadata();
+    let columns = file_metadata
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|column| {
+            let ty = match column.logical_type() {
+                Some(logical_type) => format!("{:?}", logical_type),
+                None => column.physical_type().to_string(),
+            };
+            (column.path().string(), ty)
+        })
+        .collect();
+    let rows = reader
+        .get_row_iter(None)?
+        .take(n)
+        .map(|row| {
+            row.get_column_iter()
+                .map(|(_, field)| field.to_string())
+                .collect()
+        })
+        .collect();
+    Preview {
+        columns,
+        rows,
+        total_rows: Some(file_metadata.num_rows() as u64),
+    }
+    .display();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Preview;
+
+    fn preview() -> Preview {
+        Preview {
+            columns: vec![
+                ("name".to_owned(), "text".to_owned()),
+                ("n".to_owned(), "integer".to_owned()),
+            ],
+            rows: vec![
+                vec!["a<b".to_owned(), "1".to_owned()],
+                vec!["c".to_owned(), "20".to_owned()],
+            ],
+            total_rows: Some(5),
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            preview().to_text(),
+            "name | n\ntext | integer\n---- | -------\na<b  | 1\nc    | 20\n(2 of 5 rows)"
+        );
+    }
+
+    #[test]
+    fn test_html_is_escaped() {
+        assert!(preview().to_html().contains("<td>a&lt;b</td>"));
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_csv_preview() {
+        let csv = "name,score,ok\nx,1,true\ny,2.5,false\nz,,true\n";
+        let preview = super::csv_preview(csv.as_bytes(), 2).unwrap();
+        assert_eq!(
+            preview.columns,
+            vec![
+                ("name".to_owned(), "text".to_owned()),
+                ("score".to_owned(), "float".to_owned()),
+                ("ok".to_owned(), "bool".to_owned()),
+            ]
+        );
+        assert_eq!(preview.rows.len(), 2);
+        assert_eq!(preview.total_rows, None);
+    }
+}
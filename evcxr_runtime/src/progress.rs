@@ -0,0 +1,249 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Progress bars that are updated in place, so that long-running loops can report their progress
+//! without printing a line each time. Unlike the `Progress` widget, these don't need ipywidgets.
+//! Jupyter updates the bar's output, while the REPL redraws it on the same line of the terminal.
+
+use crate::display_handle::new_display_id;
+use crate::display_handle::send_display;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Updates more frequent than this are skipped, apart from the last.
+const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of characters in the plain-text bar.
+const BAR_WIDTH: u64 = 30;
+
+/// A progress bar that counts up to a total.
+/// ```
+/// let mut progress = evcxr_runtime::ProgressBar::new(100).with_message("Training");
+/// for _ in 0..100 {
+///     // Do some work.
+///     progress.inc(1);
+/// }
+/// progress.finish();
+/// ```
+pub struct ProgressBar {
+    display_id: String,
+    total: u64,
+    position: u64,
+    message: String,
+    /// When the bar was last sent, or None if it hasn't been displayed yet.
+    last_sent: Option<Instant>,
+    /// Whether there are changes that haven't been sent.
+    pending: bool,
+}
+
+impl ProgressBar {
+    /// Creates a progress bar that's complete once its position reaches `total`, and displays it.
+    pub fn new(total: u64) -> ProgressBar {
+        let mut bar = ProgressBar {
+            display_id: new_display_id("progress"),
+            total,
+            position: 0,
+            message: String::new(),
+            last_sent: None,
+            pending: false,
+        };
+        bar.send();
+        bar
+    }
+
+    /// Sets the text shown after the bar.
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> ProgressBar {
+        self.set_message(message);
+        self
+    }
+
+    pub fn set_message<S: Into<String>>(&mut self, message: S) {
+        self.message = message.into();
+        self.changed();
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position.min(self.total);
+        self.changed();
+    }
+
+    /// Advances the bar by `delta`.
+    pub fn inc(&mut self, delta: u64) {
+        let position = self.position.saturating_add(delta);
+        self.set_position(position);
+    }
+
+    /// Fills the bar, showing that the work is done. Unlike other changes, this is always shown
+    /// straight away.
+    pub fn finish(&mut self) {
+        self.position = self.total;
+        self.send();
+    }
+
+    fn changed(&mut self) {
+        self.pending = true;
+        if !matches!(self.last_sent, Some(last_sent) if last_sent.elapsed() < MIN_UPDATE_INTERVAL) {
+            self.send();
+        }
+    }
+
+    fn send(&mut self) {
+        send_display(
+            &self.display_id,
+            self.last_sent.is_some(),
+            &[("text/plain", &self.text()), ("text/html", &self.html())],
+        );
+        self.last_sent = Some(Instant::now());
+        self.pending = false;
+    }
+
+    /// Returns the position and percentage, followed by the message, if any.
+    fn summary(&self) -> String {
+        let percent = (self.position * 100).checked_div(self.total).unwrap_or(100);
+        let mut summary = format!("{}/{} ({}%)", self.position, self.total, percent);
+        if !self.message.is_empty() {
+            summary.push(' ');
+            summary.push_str(&self.message);
+        }
+        summary
+    }
+
+    fn text(&self) -> String {
+        let filled = (self.position * BAR_WIDTH)
+            .checked_div(self.total)
+            .unwrap_or(BAR_WIDTH);
+        format!(
+            "[{}{}] {}",
+            "#".repeat(filled as usize),
+            "-".repeat((BAR_WIDTH - filled) as usize),
+            self.summary()
+        )
+    }
+
+    fn html(&self) -> String {
+        format!(
+            "<progress value=\"{}\" max=\"{}\"></progress> {}",
+            self.position,
+            self.total,
+            self.summary()
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        )
+    }
+}
+
+impl Drop for ProgressBar {
+    /// Sends any changes that were skipped because they came too soon after the previous one.
+    fn drop(&mut self) {
+        if self.pending {
+            self.send();
+        }
+    }
+}
+
+/// An iterator that advances a progress bar as items are taken from it. See `progress`.
+pub struct ProgressIter<I> {
+    iter: I,
+    bar: ProgressBar,
+    started: bool,
+}
+
+impl<I: Iterator> Iterator for ProgressIter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        // An item is counted once the next one is requested, since that's when work on it is done.
+        if self.started {
+            self.bar.inc(1);
+        }
+        self.started = true;
+        let item = self.iter.next();
+        if item.is_none() {
+            self.bar.finish();
+        }
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+/// Wraps `iter` so that a progress bar shows how many of its items have been processed.
+/// ```
+/// let mut total = 0;
+/// for i in evcxr_runtime::progress(0..1000) {
+///     total += i;
+/// }
+/// ```
+pub fn progress<I>(iter: I) -> ProgressIter<I::IntoIter>
+where
+    I: IntoIterator,
+    I::IntoIter: ExactSizeIterator,
+{
+    let iter = iter.into_iter();
+    ProgressIter {
+        bar: ProgressBar::new(iter.len() as u64),
+        iter,
+        started: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProgressBar;
+    use std::time::Instant;
+
+    fn bar(total: u64, position: u64, message: &str) -> ProgressBar {
+        ProgressBar {
+            display_id: "p".to_owned(),
+            total,
+            position,
+            message: message.to_owned(),
+            last_sent: Some(Instant::now()),
+            pending: false,
+        }
+    }
+
+    #[test]
+    fn rendering() {
+        assert_eq!(
+            bar(10, 5, "").text(),
+            "[###############---------------] 5/10 (50%)"
+        );
+        assert_eq!(
+            bar(0, 0, "<done>").text(),
+            "[##############################] 0/0 (100%) <done>"
+        );
+        assert_eq!(
+            bar(3, 1, "a & b").html(),
+            "<progress value=\"1\" max=\"3\"></progress> 1/3 (33%) a &amp; b"
+        );
+    }
+
+    #[test]
+    fn updates_are_throttled() {
+        let mut progress = bar(10, 0, "");
+        progress.inc(20);
+        assert_eq!(progress.position(), 10);
+        assert!(progress.pending);
+        progress.finish();
+        assert!(!progress.pending);
+    }
+}
@@ -0,0 +1,401 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Widgets that are compatible with ipywidgets (version 8), such as sliders and progress bars.
+//! Each widget is backed by a Jupyter comm, which the Evcxr Jupyter kernel opens on our behalf.
+//! Values changed by the user in the notebook can be read back, both while a cell is running and
+//! from later cells.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// These prefixes must match those in evcxr's comm module.
+const COMM_OPEN: &str = "EVCXR_COMM_OPEN ";
+const COMM_MSG: &str = "EVCXR_COMM_MSG ";
+const COMM_CLOSE: &str = "EVCXR_COMM_CLOSE ";
+const COMM_GET: &str = "EVCXR_COMM_GET ";
+
+const WIDGET_TARGET: &str = "jupyter.widget";
+const BASE_MODULE: &str = "@jupyter-widgets/base";
+const CONTROLS_MODULE: &str = "@jupyter-widgets/controls";
+const MODULE_VERSION: &str = "2.0.0";
+const PROTOCOL_VERSION_MAJOR: u32 = 2;
+const PROTOCOL_VERSION_MINOR: u32 = 1;
+
+/// A value in a widget's state.
+enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Null,
+}
+
+impl Value {
+    fn to_json(&self) -> String {
+        match self {
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) if value.is_finite() => value.to_string(),
+            Value::Float(_) | Value::Null => "null".to_owned(),
+            Value::Str(value) => json_string(value),
+        }
+    }
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn state_json(state: &[(&str, Value)]) -> String {
+    let entries: Vec<String> = state
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), value.to_json()))
+        .collect();
+    format!("{{{}}}", entries.join(","))
+}
+
+fn new_comm_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0);
+    format!(
+        "{:08x}{:016x}{:08x}",
+        std::process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A widget model that's displayed in the notebook. Normally you'd use one of the specific
+/// widgets, such as `IntSlider`, which wrap this.
+pub struct Widget {
+    comm_id: String,
+    /// The JSON for values that we've set or read, in case we can't ask for the current value.
+    known_values: Mutex<HashMap<String, String>>,
+}
+
+impl Widget {
+    fn open(
+        module: &str,
+        model_name: &str,
+        view_name: Option<&str>,
+        state: Vec<(&str, Value)>,
+    ) -> Widget {
+        let mut full_state = vec![
+            ("_model_module", Value::Str(module.to_owned())),
+            (
+                "_model_module_version",
+                Value::Str(MODULE_VERSION.to_owned()),
+            ),
+            ("_model_name", Value::Str(model_name.to_owned())),
+            ("_view_module", Value::Str(module.to_owned())),
+            (
+                "_view_module_version",
+                Value::Str(MODULE_VERSION.to_owned()),
+            ),
+            (
+                "_view_name",
+                view_name.map_or(Value::Null, |name| Value::Str(name.to_owned())),
+            ),
+        ];
+        full_state.extend(state);
+        let widget = Widget {
+            comm_id: new_comm_id(),
+            known_values: Mutex::new(
+                full_state
+                    .iter()
+                    .map(|(key, value)| ((*key).to_owned(), value.to_json()))
+                    .collect(),
+            ),
+        };
+        emit(&format!(
+            "{}{} {} {{\"state\":{},\"buffer_paths\":[]}}",
+            COMM_OPEN,
+            widget.comm_id,
+            WIDGET_TARGET,
+            state_json(&full_state)
+        ));
+        widget
+    }
+
+    /// Opens a control widget, together with the layout and style widgets that it refers to.
+    fn open_control(
+        model_name: &str,
+        view_name: &str,
+        style_model_name: &str,
+        state: Vec<(&str, Value)>,
+    ) -> Widget {
+        let layout = Widget::open(BASE_MODULE, "LayoutModel", Some("LayoutView"), vec![]);
+        let style = Widget::open(
+            CONTROLS_MODULE,
+            style_model_name,
+            Some("StyleView"),
+            vec![("description_width", Value::Str(String::new()))],
+        );
+        let mut full_state = vec![
+            ("layout", Value::Str(layout.reference())),
+            ("style", Value::Str(style.reference())),
+            ("description", Value::Str(String::new())),
+        ];
+        full_state.extend(state);
+        Widget::open(CONTROLS_MODULE, model_name, Some(view_name), full_state)
+    }
+
+    /// Returns the ID of the comm that backs this widget.
+    pub fn comm_id(&self) -> &str {
+        &self.comm_id
+    }
+
+    fn reference(&self) -> String {
+        format!("IPY_MODEL_{}", self.comm_id)
+    }
+
+    fn set(&self, key: &str, value: Value) {
+        let json = value.to_json();
+        emit(&format!(
+            "{}{} {{\"method\":\"update\",\"state\":{{{}:{}}},\"buffer_paths\":[]}}",
+            COMM_MSG,
+            self.comm_id,
+            json_string(key),
+            json
+        ));
+        self.known_values
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), json);
+    }
+
+    /// Returns the JSON for the current value of `key`, including any changes made in the
+    /// notebook.
+    fn get(&self, key: &str) -> Option<String> {
+        emit(&format!("{}{} {}", COMM_GET, self.comm_id, key));
+        let mut line = String::new();
+        let mut known_values = self.known_values.lock().unwrap();
+        if std::io::stdin().read_line(&mut line).is_ok() {
+            let line = line.trim();
+            if !line.is_empty() && line != "null" {
+                known_values.insert(key.to_owned(), line.to_owned());
+            }
+        }
+        known_values.get(key).cloned()
+    }
+
+    fn get_f64(&self, key: &str) -> f64 {
+        self.get(key)
+            .and_then(|json| json.parse().ok())
+            .unwrap_or(0.0)
+    }
+
+    /// Sets the label shown next to the widget.
+    pub fn set_description(&self, description: &str) {
+        self.set("description", Value::Str(description.to_owned()));
+    }
+
+    /// Closes the widget, removing it from the notebook.
+    pub fn close(self) {
+        emit(&format!("{}{}", COMM_CLOSE, self.comm_id));
+    }
+}
+
+impl crate::EvcxrDisplay for Widget {
+    fn evcxr_mime_bundle(&self, bundle: &mut crate::MimeBundle) {
+        bundle
+            .add(
+                "application/vnd.jupyter.widget-view+json",
+                format!(
+                    "{{\"model_id\":{},\"version_major\":{},\"version_minor\":{}}}",
+                    json_string(&self.comm_id),
+                    PROTOCOL_VERSION_MAJOR,
+                    PROTOCOL_VERSION_MINOR
+                ),
+            )
+            .text("Widget (only displayed in notebooks)");
+    }
+}
+
+pub(crate) fn emit(line: &str) {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let _ = writeln!(out, "{}", line);
+    let _ = out.flush();
+}
+
+macro_rules! widget_wrapper {
+    ($name:ident) => {
+        impl $name {
+            /// Displays the widget as the output of the current cell.
+            pub fn display(&self) {
+                crate::display(&self.0);
+            }
+
+            /// Sets the label shown next to the widget.
+            pub fn with_description(self, description: &str) -> Self {
+                self.0.set_description(description);
+                self
+            }
+
+            /// Returns the underlying widget.
+            pub fn widget(&self) -> &Widget {
+                &self.0
+            }
+        }
+
+        impl crate::EvcxrDisplay for $name {
+            fn evcxr_mime_bundle(&self, bundle: &mut crate::MimeBundle) {
+                self.0.evcxr_mime_bundle(bundle);
+            }
+        }
+    };
+}
+
+/// A slider for choosing an integer.
+/// ```no_run
+/// let slider = evcxr_runtime::IntSlider::new(0, 100, 50).with_description("Threshold");
+/// slider.display();
+/// // Later, perhaps in another cell:
+/// let threshold = slider.value();
+/// ```
+pub struct IntSlider(Widget);
+
+impl IntSlider {
+    pub fn new(min: i64, max: i64, value: i64) -> IntSlider {
+        IntSlider(Widget::open_control(
+            "IntSliderModel",
+            "IntSliderView",
+            "SliderStyleModel",
+            vec![
+                ("min", Value::Int(min)),
+                ("max", Value::Int(max)),
+                ("step", Value::Int(1)),
+                ("value", Value::Int(value)),
+            ],
+        ))
+    }
+
+    /// Returns the slider's current value, which may have been changed in the notebook.
+    pub fn value(&self) -> i64 {
+        self.0.get_f64("value") as i64
+    }
+
+    pub fn set_value(&self, value: i64) {
+        self.0.set("value", Value::Int(value));
+    }
+}
+
+widget_wrapper!(IntSlider);
+
+/// A slider for choosing a float.
+pub struct FloatSlider(Widget);
+
+impl FloatSlider {
+    pub fn new(min: f64, max: f64, step: f64, value: f64) -> FloatSlider {
+        FloatSlider(Widget::open_control(
+            "FloatSliderModel",
+            "FloatSliderView",
+            "SliderStyleModel",
+            vec![
+                ("min", Value::Float(min)),
+                ("max", Value::Float(max)),
+                ("step", Value::Float(step)),
+                ("value", Value::Float(value)),
+            ],
+        ))
+    }
+
+    /// Returns the slider's current value, which may have been changed in the notebook.
+    pub fn value(&self) -> f64 {
+        self.0.get_f64("value")
+    }
+
+    pub fn set_value(&self, value: f64) {
+        self.0.set("value", Value::Float(value));
+    }
+}
+
+widget_wrapper!(FloatSlider);
+
+/// A progress bar that goes from 0 to `max`. Update it with `set_value` as work proceeds, e.g. from
+/// within a loop in the same cell.
+/// ```no_run
+/// let progress = evcxr_runtime::Progress::new(100.0);
+/// progress.display();
+/// for i in 0..100 {
+///     // Do some work.
+///     progress.set_value(i as f64 + 1.0);
+/// }
+/// ```
+pub struct Progress(Widget);
+
+impl Progress {
+    pub fn new(max: f64) -> Progress {
+        Progress(Widget::open_control(
+            "FloatProgressModel",
+            "ProgressView",
+            "ProgressStyleModel",
+            vec![
+                ("min", Value::Float(0.0)),
+                ("max", Value::Float(max)),
+                ("value", Value::Float(0.0)),
+                ("bar_style", Value::Str(String::new())),
+                ("orientation", Value::Str("horizontal".to_owned())),
+            ],
+        ))
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0.get_f64("value")
+    }
+
+    pub fn set_value(&self, value: f64) {
+        self.0.set("value", Value::Float(value));
+    }
+}
+
+widget_wrapper!(Progress);
+
+#[cfg(test)]
+mod tests {
+    use super::state_json;
+    use super::Value;
+
+    #[test]
+    fn test_state_json() {
+        assert_eq!(
+            state_json(&[
+                ("value", Value::Int(3)),
+                ("step", Value::Float(0.5)),
+                ("bad", Value::Float(f64::NAN)),
+                ("description", Value::Str("a \"b\"\n".to_owned())),
+                ("_view_name", Value::Null),
+            ]),
+            r#"{"value":3,"step":0.5,"bad":null,"description":"a \"b\"\n","_view_name":null}"#
+        );
+    }
+}
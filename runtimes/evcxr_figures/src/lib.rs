@@ -0,0 +1,260 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Displaying charts inline. In Jupyter, figures are shown in the notebook. In the REPL, which can
+//! only show text, they're written to a temporary file and a link to it is shown instead.
+
+use evcxr_runtime::EvcxrDisplay;
+use evcxr_runtime::MimeBundle;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Where plotly.js is loaded from when displaying plotly figures as HTML.
+const PLOTLY_JS_URL: &str = "https://cdn.plot.ly/plotly-2.18.0.min.js";
+
+/// The mime type rendered by JupyterLab's plotly extension.
+const PLOTLY_MIME_TYPE: &str = "application/vnd.plotly.v1+json";
+
+/// Used to give each figure's HTML element and temporary file a distinct name.
+static NEXT_FIGURE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A rendered chart, which is displayed when it's the result of a cell, or by calling `display`.
+#[derive(Debug, Clone)]
+pub struct Figure {
+    kind: FigureKind,
+}
+
+#[derive(Debug, Clone)]
+enum FigureKind {
+    Svg(String),
+    PlotlyJson(String),
+}
+
+impl Figure {
+    /// A figure that's already been rendered as an SVG image.
+    pub fn svg<S: Into<String>>(svg: S) -> Figure {
+        Figure {
+            kind: FigureKind::Svg(svg.into()),
+        }
+    }
+
+    /// A plotly figure, given as JSON with `data` and optionally `layout` and `config` fields, as
+    /// produced by e.g. `plotly::Plot::to_json`.
+    pub fn plotly_json<S: Into<String>>(json: S) -> Figure {
+        Figure {
+            kind: FigureKind::PlotlyJson(json.into()),
+        }
+    }
+
+    /// Displays the figure part way through a cell.
+    pub fn display(&self) {
+        evcxr_runtime::display(self);
+    }
+
+    /// Writes the figure to a new file in the temporary directory, as an SVG image or a web page
+    /// that draws it. Returns the file's path.
+    pub fn save_to_temp_file(&self) -> std::io::Result<PathBuf> {
+        let dir = std::env::temp_dir().join("evcxr_figures");
+        std::fs::create_dir_all(&dir)?;
+        let (extension, contents) = match &self.kind {
+            FigureKind::Svg(svg) => ("svg", svg.clone()),
+            FigureKind::PlotlyJson(json) => ("html", plotly_page(json)),
+        };
+        let path = dir.join(format!(
+            "figure-{}-{}.{}",
+            std::process::id(),
+            NEXT_FIGURE_ID.fetch_add(1, Ordering::Relaxed),
+            extension
+        ));
+        std::fs::write(&path, contents)?;
+        Ok(path)
+    }
+
+    /// Returns the text shown where the figure can't be displayed inline, which links to a file
+    /// containing it.
+    fn link_text(&self) -> String {
+        match self.save_to_temp_file() {
+            Ok(path) => format!("Figure saved to file://{}", path.display()),
+            Err(error) => format!("Figure (failed to save to a temporary file: {})", error),
+        }
+    }
+}
+
+impl EvcxrDisplay for Figure {
+    fn evcxr_mime_bundle(&self, bundle: &mut MimeBundle) {
+        let inline_mime_type = match &self.kind {
+            FigureKind::Svg(svg) => {
+                if bundle.accepts("image/svg+xml") {
+                    bundle.add("image/svg+xml", svg.as_str());
+                    Some("image/svg+xml")
+                } else {
+                    None
+                }
+            }
+            FigureKind::PlotlyJson(json) => {
+                if bundle.accepts(PLOTLY_MIME_TYPE) {
+                    bundle.add(PLOTLY_MIME_TYPE, json.as_str());
+                }
+                if bundle.accepts("text/html") {
+                    bundle.html(plotly_div(json));
+                }
+                bundle.preferred(&["text/html", PLOTLY_MIME_TYPE])
+            }
+        };
+        if inline_mime_type.is_some() {
+            bundle.text("Figure");
+        } else {
+            bundle.text(self.link_text());
+        }
+    }
+}
+
+/// Returns HTML that draws the plotly figure described by `json`, loading plotly.js if the page
+/// doesn't already have it. The classic notebook loads scripts with RequireJS, which plotly.js
+/// registers itself with rather than setting `window.Plotly`, so it's loaded that way there.
+fn plotly_div(json: &str) -> String {
+    let id = format!(
+        "evcxr-figure-{}-{}",
+        std::process::id(),
+        NEXT_FIGURE_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    format!(
+        r#"<div id="{id}"></div>
+<script type="text/javascript">
+(function() {{
+  var figure = {json};
+  var draw = function(Plotly) {{
+    Plotly.newPlot("{id}", figure.data, figure.layout || {{}}, figure.config || {{}});
+  }};
+  if (window.Plotly) {{
+    draw(window.Plotly);
+  }} else if (window.requirejs) {{
+    window.requirejs.config({{paths: {{plotly: "{module}"}}}});
+    window.requirejs(["plotly"], draw);
+  }} else {{
+    var script = document.createElement("script");
+    script.src = "{url}";
+    script.onload = function() {{ draw(window.Plotly); }};
+    document.head.appendChild(script);
+  }}
+}})();
+</script>"#,
+        id = id,
+        json = json,
+        // RequireJS module paths leave out the extension.
+        module = PLOTLY_JS_URL.trim_end_matches(".js"),
+        url = PLOTLY_JS_URL,
+    )
+}
+
+/// Returns a standalone web page that draws the plotly figure described by `json`.
+fn plotly_page(json: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><script src=\"{}\"></script></head>\n\
+         <body>\n{}\n</body>\n</html>\n",
+        PLOTLY_JS_URL,
+        plotly_div(json)
+    )
+}
+
+/// Renders a chart with plotters. `draw` is passed the drawing area to draw on, which is `width` by
+/// `height` pixels.
+/// ```no_run
+/// use plotters::prelude::*;
+///
+/// evcxr_figures::plot(640, 480, |root| {
+///     root.fill(&WHITE)?;
+///     let mut chart = ChartBuilder::on(root)
+///         .margin(10)
+///         .build_cartesian_2d(-1f32..1f32, 0f32..1f32)?;
+///     chart.draw_series(LineSeries::new(
+///         (-50..=50).map(|x| x as f32 / 50.0).map(|x| (x, x * x)),
+///         &RED,
+///     ))?;
+///     Ok(())
+/// })
+/// # .unwrap();
+/// ```
+#[cfg(feature = "plotters")]
+pub fn plot<F>(width: u32, height: u32, draw: F) -> Result<Figure, Box<dyn std::error::Error>>
+where
+    F: FnOnce(
+        &plotters::drawing::DrawingArea<plotters::prelude::SVGBackend, plotters::coord::Shift>,
+    ) -> Result<(), Box<dyn std::error::Error>>,
+{
+    use plotters::prelude::IntoDrawingArea;
+
+    let mut svg = String::new();
+    {
+        let root = plotters::prelude::SVGBackend::with_string(&mut svg, (width, height))
+            .into_drawing_area();
+        draw(&root)?;
+        root.present()?;
+    }
+    Ok(Figure::svg(svg))
+}
+
+#[cfg(feature = "plotly")]
+impl From<&plotly::Plot> for Figure {
+    fn from(plot: &plotly::Plot) -> Figure {
+        Figure::plotly_json(plot.to_json())
+    }
+}
+
+/// Returns a figure that displays `plot`.
+#[cfg(feature = "plotly")]
+pub fn plotly(plot: &plotly::Plot) -> Figure {
+    Figure::from(plot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::plotly_div;
+    use super::plotly_page;
+    use super::Figure;
+    use super::PLOTLY_JS_URL;
+
+    #[test]
+    fn plotly_html() {
+        let json = r#"{"data":[{"type":"bar","y":[1,2]}]}"#;
+        let html = plotly_div(json);
+        assert!(html.starts_with("<div id=\"evcxr-figure-"));
+        assert!(html.contains(&format!("var figure = {};", json)));
+        assert!(html.contains(&format!("script.src = \"{}\"", PLOTLY_JS_URL)));
+        assert!(html.contains("plotly: \"https://cdn.plot.ly/plotly-2.18.0.min\""));
+        // Each figure gets its own element.
+        assert_ne!(html, plotly_div(json));
+
+        let page = plotly_page(json);
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains(&format!("<script src=\"{}\"></script>", PLOTLY_JS_URL)));
+    }
+
+    #[test]
+    fn save_to_temp_file() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="2" height="2"></svg>"#;
+        let path = Figure::svg(svg).save_to_temp_file().unwrap();
+        assert_eq!(path.extension().unwrap(), "svg");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), svg);
+        std::fs::remove_file(path).unwrap();
+
+        let path = Figure::plotly_json("{}").save_to_temp_file().unwrap();
+        assert_eq!(path.extension().unwrap(), "html");
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("var figure = {};"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running Python code from Evcxr, and passing values between Rust and Python. The interpreter is
+//! embedded in the process that runs the session's code and is started the first time it's needed.
+//! All code runs in the namespace of `__main__`, so names defined by one cell can be used by later
+//! ones, just as with Rust variables.
+
+pub use pyo3;
+
+use evcxr_figures::Figure;
+use pyo3::exceptions::PyNameError;
+use pyo3::types::PyDict;
+use pyo3::types::PyModule;
+use pyo3::FromPyObject;
+use pyo3::PyResult;
+use pyo3::Python;
+use pyo3::ToPyObject;
+
+/// Environment variables that need to be set before the interpreter starts, unless the user has
+/// set them already.
+const PYTHON_ENVIRONMENT: &[(&str, &str)] = &[
+    // Python's output is shown as it's printed, rather than when its buffer fills up.
+    ("PYTHONUNBUFFERED", "1"),
+    // matplotlib draws off screen, for `figure`, rather than trying to open windows.
+    ("MPLBACKEND", "Agg"),
+];
+
+/// Runs Python code, which may be indented as a whole, e.g. when given as a multi-line string.
+///
+/// `py!("import numpy as np")` is the same as `run("import numpy as np")`. Rust variables can be
+/// made available to the code by listing them first, e.g. `py!(data, scale => "y = np.array(data)
+/// * scale")`, which sets Python variables `data` and `scale` to converted copies of them.
+#[macro_export]
+macro_rules! py {
+    ($($name:ident),+ => $code:expr) => {
+        $crate::run_with(
+            &[$((stringify!($name), &$name as &dyn $crate::pyo3::ToPyObject)),+],
+            $code,
+        )
+    };
+    ($code:expr) => {
+        $crate::run($code)
+    };
+}
+
+/// Runs Python code in the session's namespace.
+pub fn run(code: &str) -> PyResult<()> {
+    run_with(&[], code)
+}
+
+/// Sets each of `variables` in the session's namespace, then runs Python code there.
+pub fn run_with(variables: &[(&str, &dyn ToPyObject)], code: &str) -> PyResult<()> {
+    with_namespace(|py, namespace| {
+        for (name, value) in variables {
+            namespace.set_item(*name, value.to_object(py))?;
+        }
+        py.run(&dedent(code), Some(namespace), None)
+    })
+}
+
+/// Evaluates a Python expression and converts the result, e.g. `eval::<Vec<f64>>("y.tolist()")`.
+pub fn eval<T>(expression: &str) -> PyResult<T>
+where
+    T: for<'p> FromPyObject<'p>,
+{
+    with_namespace(|py, namespace| py.eval(expression.trim(), Some(namespace), None)?.extract())
+}
+
+/// Sets a Python variable to a converted copy of `value`.
+pub fn set<T: ToPyObject + ?Sized>(name: &str, value: &T) -> PyResult<()> {
+    with_namespace(|py, namespace| namespace.set_item(name, value.to_object(py)))
+}
+
+/// Returns a converted copy of a Python variable, e.g. `get::<HashMap<String, i64>>("counts")`.
+pub fn get<T>(name: &str) -> PyResult<T>
+where
+    T: for<'p> FromPyObject<'p>,
+{
+    with_namespace(|_py, namespace| match namespace.get_item(name) {
+        Some(value) => value.extract(),
+        None => Err(PyNameError::new_err(format!(
+            "name '{}' is not defined",
+            name
+        ))),
+    })
+}
+
+/// Renders matplotlib's current figure as SVG, then closes it, so that the next plot starts afresh.
+/// The result is displayed when it's the result of a cell.
+pub fn figure() -> PyResult<Figure> {
+    with_gil(|py| {
+        let pyplot = py.import("matplotlib.pyplot")?;
+        let buffer = py.import("io")?.getattr("StringIO")?.call0()?;
+        let options = PyDict::new(py);
+        options.set_item("format", "svg")?;
+        options.set_item("bbox_inches", "tight")?;
+        pyplot.call_method("savefig", (buffer,), Some(options))?;
+        pyplot.call_method0("close")?;
+        let svg: String = buffer.call_method0("getvalue")?.extract()?;
+        Ok(Figure::svg(svg))
+    })
+}
+
+fn with_gil<R>(f: impl FnOnce(Python) -> PyResult<R>) -> PyResult<R> {
+    for (name, value) in PYTHON_ENVIRONMENT {
+        if std::env::var_os(name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+    Python::with_gil(f)
+}
+
+fn with_namespace<R>(f: impl FnOnce(Python, &PyDict) -> PyResult<R>) -> PyResult<R> {
+    with_gil(|py| {
+        let namespace = PyModule::import(py, "__main__")?.dict();
+        f(py, namespace)
+    })
+}
+
+/// Removes the indentation that all non-blank lines of `code` have in common, since Python would
+/// otherwise reject it.
+fn dedent(code: &str) -> String {
+    let indentation = |line: &str| line.len() - line.trim_start_matches(&[' ', '\t'][..]).len();
+    let common = code
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(indentation)
+        .min()
+        .unwrap_or(0);
+    code.lines()
+        .map(|line| {
+            if indentation(line) >= common {
+                &line[common..]
+            } else {
+                line.trim_start()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dedent;
+
+    #[test]
+    fn dedenting() {
+        assert_eq!(dedent("x = 1"), "x = 1");
+        assert_eq!(
+            dedent("\n    for i in range(3):\n        print(i)\n  \n    done = True\n"),
+            "\nfor i in range(3):\n    print(i)\n\ndone = True"
+        );
+        assert_eq!(dedent("\tif x:\n\t\ty = 2"), "if x:\n\ty = 2");
+    }
+}
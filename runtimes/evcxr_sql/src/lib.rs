@@ -0,0 +1,652 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running SQL from Evcxr with DuckDB, which is what the `:sql` command does. Queries run against an
+//! in-memory database unless given a connection URL, which may name a DuckDB file, or a SQLite or
+//! PostgreSQL database for DuckDB to attach. Results are returned as a `Table`, which Evcxr displays
+//! as it does DataFrames, and whose rows can be converted to structs declared with `record!`.
+
+pub use duckdb;
+
+use duckdb::types::TimeUnit;
+use duckdb::Connection;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Connections that have been opened, keyed by URL, with the in-memory database under "". They're
+/// kept open so that tables created by one query are there for the next.
+static CONNECTIONS: Mutex<Vec<(String, Connection)>> = Mutex::new(Vec::new());
+
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<duckdb::Error> for Error {
+    fn from(error: duckdb::Error) -> Error {
+        Error(error.to_string())
+    }
+}
+
+/// A value from a query's result. DuckDB's types are mapped onto the nearest of these. Dates and
+/// timestamps become text, and types without a counterpart become text in DuckDB's debug format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("NULL"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Text(value) => f.write_str(value),
+            Value::Bytes(bytes) => {
+                f.write_str("\\x")?;
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<duckdb::types::Value> for Value {
+    fn from(value: duckdb::types::Value) -> Value {
+        use duckdb::types::Value as DuckValue;
+        let micros = |unit: TimeUnit, count: i64| match unit {
+            TimeUnit::Second => count * 1_000_000,
+            TimeUnit::Millisecond => count * 1_000,
+            TimeUnit::Microsecond => count,
+            TimeUnit::Nanosecond => count / 1_000,
+        };
+        match value {
+            DuckValue::Null => Value::Null,
+            DuckValue::Boolean(value) => Value::Bool(value),
+            DuckValue::TinyInt(value) => Value::Int(value.into()),
+            DuckValue::SmallInt(value) => Value::Int(value.into()),
+            DuckValue::Int(value) => Value::Int(value.into()),
+            DuckValue::BigInt(value) => Value::Int(value),
+            DuckValue::UTinyInt(value) => Value::Int(value.into()),
+            DuckValue::USmallInt(value) => Value::Int(value.into()),
+            DuckValue::UInt(value) => Value::Int(value.into()),
+            DuckValue::UBigInt(value) => i64::try_from(value)
+                .map(Value::Int)
+                .unwrap_or(Value::Float(value as f64)),
+            DuckValue::HugeInt(value) => i64::try_from(value)
+                .map(Value::Int)
+                .unwrap_or(Value::Float(value as f64)),
+            DuckValue::Float(value) => Value::Float(value.into()),
+            DuckValue::Double(value) => Value::Float(value),
+            DuckValue::Decimal(value) => {
+                let text = value.to_string();
+                text.parse().map(Value::Float).unwrap_or(Value::Text(text))
+            }
+            DuckValue::Text(value) => Value::Text(value),
+            DuckValue::Blob(value) => Value::Bytes(value),
+            DuckValue::Date32(days) => Value::Text(format_date(days.into())),
+            DuckValue::Timestamp(unit, count) => Value::Text(format_timestamp(micros(unit, count))),
+            other => Value::Text(format!("{:?}", other)),
+        }
+    }
+}
+
+/// Converts a `Value` to a Rust type, for fields of structs declared with `record!`.
+pub trait FromValue: Sized {
+    /// Returns the converted value, or None if it can't be represented as `Self`.
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Option<Value> {
+        Some(value.clone())
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Option<bool> {
+        match value {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! from_int_value {
+    ($($type:ty),*) => {
+        $(
+            impl FromValue for $type {
+                fn from_value(value: &Value) -> Option<$type> {
+                    match value {
+                        Value::Int(value) => (*value).try_into().ok(),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+from_int_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Option<f64> {
+        match value {
+            Value::Float(value) => Some(*value),
+            Value::Int(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &Value) -> Option<f32> {
+        f64::from_value(value).map(|value| value as f32)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<String> {
+        match value {
+            Value::Text(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(value: &Value) -> Option<Vec<u8>> {
+        match value {
+            Value::Bytes(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Option<T>> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+/// A row of a `Table`, whose values can be looked up by column name.
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    columns: &'a [String],
+    values: &'a [Value],
+}
+
+impl<'a> Record<'a> {
+    /// Returns the value in `column`, converted to `T`.
+    pub fn get<T: FromValue>(&self, column: &str) -> Result<T, Error> {
+        let value = match self.columns.iter().position(|name| name == column) {
+            Some(index) => &self.values[index],
+            None => {
+                return Err(Error(format!(
+                    "No column named `{}`. Columns: {}",
+                    column,
+                    self.columns.join(", ")
+                )))
+            }
+        };
+        T::from_value(value).ok_or_else(|| {
+            Error(format!(
+                "Column `{}` has value {:?}, which can't be converted to {}",
+                column,
+                value,
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+/// Converts a row of a `Table` to a Rust type. Use `record!` to declare a struct that implements
+/// this.
+pub trait FromRecord: Sized {
+    fn from_record(record: &Record) -> Result<Self, Error>;
+}
+
+/// Declares a struct whose fields are filled from the columns of the same names, so that a query's
+/// result can be converted to a `Vec` of it with `Table::records`, or with
+/// `:sql let name: Vec<Type> =`.
+///
+/// ```ignore
+/// evcxr_sql::record! {
+///     #[derive(Debug)]
+///     struct Product {
+///         name: String,
+///         price: f64,
+///         discontinued: Option<bool>,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! record {
+    (
+        $(#[$attribute:meta])*
+        $visibility:vis struct $name:ident {
+            $($(#[$field_attribute:meta])* $field_visibility:vis $field:ident: $type:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attribute])*
+        $visibility struct $name {
+            $($(#[$field_attribute])* $field_visibility $field: $type),*
+        }
+
+        impl $crate::FromRecord for $name {
+            fn from_record(
+                record: &$crate::Record,
+            ) -> ::std::result::Result<Self, $crate::Error> {
+                Ok($name {
+                    $($field: record.get(stringify!($field))?),*
+                })
+            }
+        }
+    };
+}
+
+/// The result of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    columns: Vec<String>,
+    column_types: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// The DuckDB type of each column, e.g. `Int64` or `Utf8`.
+    pub fn column_types(&self) -> &[String] {
+        &self.column_types
+    }
+
+    pub fn rows(&self) -> &[Vec<Value>] {
+        &self.rows
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Converts each row to a `T`, usually a struct declared with `record!`.
+    pub fn records<T: FromRecord>(&self) -> Result<Vec<T>, Error> {
+        self.rows
+            .iter()
+            .enumerate()
+            .map(|(index, values)| {
+                T::from_record(&Record {
+                    columns: &self.columns,
+                    values,
+                })
+                .map_err(|error| Error(format!("Row {}: {}", index, error)))
+            })
+            .collect()
+    }
+}
+
+/// Opens the database at `url`, or checks that it's already open. Later queries given the same URL
+/// use this connection.
+pub fn connect(url: &str) -> Result<(), Error> {
+    with_connection(Some(url), |_| Ok(()))
+}
+
+/// Runs `sql`, which may contain several statements separated by semicolons, against the database
+/// at `connection`, or the in-memory database if that's None. Returns the result of the last
+/// statement.
+pub fn query(connection: Option<&str>, sql: &str) -> Result<Table, Error> {
+    with_connection(connection, |connection| {
+        let statements = split_statements(sql);
+        let (last, earlier) = match statements.split_last() {
+            Some(statements) => statements,
+            None => return Err(Error("There's no SQL to run".to_owned())),
+        };
+        for statement in earlier {
+            connection.execute_batch(statement)?;
+        }
+        let mut statement = connection.prepare(last)?;
+        let mut rows = statement.query([])?;
+        let (columns, column_types) = match rows.as_ref() {
+            Some(statement) => (
+                statement.column_names(),
+                (0..statement.column_count())
+                    .map(|index| format!("{:?}", statement.column_type(index)))
+                    .collect(),
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+        let mut table = Table {
+            columns,
+            column_types,
+            rows: Vec::new(),
+        };
+        while let Some(row) = rows.next()? {
+            let values = (0..table.columns.len())
+                .map(|index| Ok(Value::from(row.get::<_, duckdb::types::Value>(index)?)))
+                .collect::<Result<_, Error>>()?;
+            table.rows.push(values);
+        }
+        Ok(table)
+    })
+}
+
+fn with_connection<R>(
+    url: Option<&str>,
+    f: impl FnOnce(&Connection) -> Result<R, Error>,
+) -> Result<R, Error> {
+    let url = url.unwrap_or_default().trim();
+    // A query that panicked part way through doesn't leave the connections in a bad state.
+    let mut connections = CONNECTIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let index = match connections.iter().position(|(open_url, _)| open_url == url) {
+        Some(index) => index,
+        None => {
+            connections.push((url.to_owned(), open(url)?));
+            connections.len() - 1
+        }
+    };
+    f(&connections[index].1)
+}
+
+fn open(url: &str) -> Result<Connection, Error> {
+    Ok(match source(url)? {
+        Source::Memory => Connection::open_in_memory()?,
+        Source::File(path) => Connection::open(path)?,
+        Source::Attach { extension, target } => {
+            let connection = Connection::open_in_memory()?;
+            connection.execute_batch(&attach_sql(extension, target))?;
+            connection
+        }
+    })
+}
+
+/// Where the data for a connection URL comes from.
+#[derive(Debug, PartialEq, Eq)]
+enum Source<'a> {
+    Memory,
+    File(&'a str),
+    /// A database of another kind, which an in-memory DuckDB database attaches with the extension
+    /// of the same name.
+    Attach {
+        extension: &'static str,
+        target: &'a str,
+    },
+}
+
+fn source(url: &str) -> Result<Source, Error> {
+    if url.is_empty() || url == ":memory:" || url == "duckdb::memory:" {
+        Ok(Source::Memory)
+    } else if let Some(path) = url.strip_prefix("duckdb:") {
+        Ok(Source::File(path.strip_prefix("//").unwrap_or(path)))
+    } else if let Some(path) = url.strip_prefix("sqlite:") {
+        Ok(Source::Attach {
+            extension: "sqlite",
+            target: path.strip_prefix("//").unwrap_or(path),
+        })
+    } else if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Source::Attach {
+            extension: "postgres",
+            target: url,
+        })
+    } else if url.ends_with(".duckdb") || url.ends_with(".db") {
+        Ok(Source::File(url))
+    } else {
+        Err(Error(format!(
+            "Unsupported connection URL: {}. Expected a DuckDB file such as data.duckdb, \
+             sqlite:<path> or postgres://...",
+            url
+        )))
+    }
+}
+
+fn attach_sql(extension: &str, target: &str) -> String {
+    format!(
+        "INSTALL {extension}; LOAD {extension}; ATTACH '{}' AS db (TYPE {extension}); USE db;",
+        target.replace('\'', "''")
+    )
+}
+
+/// Splits SQL into statements at semicolons that aren't in strings, quoted identifiers or comments.
+/// Statements that are only comments are dropped.
+fn split_statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut has_code = false;
+    let mut index = 0;
+    let skip_to = |from: usize, end: &str| -> usize {
+        sql[from..]
+            .find(end)
+            .map_or(sql.len(), |offset| from + offset + end.len() - 1)
+    };
+    while index < sql.len() {
+        let rest = &sql[index..];
+        if rest.starts_with("--") {
+            index = skip_to(index, "\n");
+        } else if rest.starts_with("/*") {
+            index = skip_to(index + 2, "*/");
+        } else if rest.starts_with("$$") {
+            index = skip_to(index + 2, "$$");
+            has_code = true;
+        } else if rest.starts_with('\'') || rest.starts_with('"') {
+            index = skip_to(index + 1, &rest[..1]);
+            has_code = true;
+        } else if rest.starts_with(';') {
+            if has_code {
+                statements.push(sql[start..index].trim());
+            }
+            start = index + 1;
+            has_code = false;
+        } else if !rest.starts_with(|c: char| c.is_whitespace()) {
+            has_code = true;
+        }
+        index += sql[index..].chars().next().map_or(1, char::len_utf8);
+    }
+    if has_code {
+        statements.push(sql[start..].trim());
+    }
+    statements
+}
+
+/// Formats a date given as a number of days since 1970-01-01, e.g. `2024-01-31`.
+fn format_date(days: i64) -> String {
+    // Howard Hinnant's civil_from_days.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats a timestamp given as a number of microseconds since 1970-01-01 00:00:00, e.g.
+/// `2024-01-31 12:30:00`, with microseconds if there are any.
+fn format_timestamp(micros: i64) -> String {
+    let micros_of_day = micros.rem_euclid(MICROS_PER_DAY);
+    let seconds = micros_of_day / 1_000_000;
+    let mut text = format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(micros.div_euclid(MICROS_PER_DAY)),
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    if micros_of_day % 1_000_000 != 0 {
+        text.push_str(&format!(".{:06}", micros_of_day % 1_000_000));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::attach_sql;
+    use super::format_date;
+    use super::format_timestamp;
+    use super::source;
+    use super::split_statements;
+    use super::Record;
+    use super::Source;
+    use super::Table;
+    use super::Value;
+
+    #[test]
+    fn statements() {
+        assert_eq!(
+            split_statements(
+                "CREATE TABLE t (s TEXT);\nINSERT INTO t VALUES ('a;b');\n\nSELECT * FROM t"
+            ),
+            vec![
+                "CREATE TABLE t (s TEXT)",
+                "INSERT INTO t VALUES ('a;b')",
+                "SELECT * FROM t"
+            ]
+        );
+        assert_eq!(
+            split_statements("SELECT \"x;\" -- Why; not?\nFROM t; /* done; */ -- End\n"),
+            vec!["SELECT \"x;\" -- Why; not?\nFROM t"]
+        );
+        assert_eq!(
+            split_statements("SELECT 'it''s; fine', $$é;$$;"),
+            vec!["SELECT 'it''s; fine', $$é;$$"]
+        );
+        assert!(split_statements("  -- Nothing\n").is_empty());
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(source(""), Ok(Source::Memory));
+        assert_eq!(source("sales.duckdb"), Ok(Source::File("sales.duckdb")));
+        assert_eq!(source("duckdb:///tmp/a"), Ok(Source::File("/tmp/a")));
+        assert_eq!(
+            source("sqlite://shop.sqlite"),
+            Ok(Source::Attach {
+                extension: "sqlite",
+                target: "shop.sqlite"
+            })
+        );
+        assert_eq!(
+            source("postgres://localhost/shop"),
+            Ok(Source::Attach {
+                extension: "postgres",
+                target: "postgres://localhost/shop"
+            })
+        );
+        assert!(source("mysql://localhost").is_err());
+        assert_eq!(
+            attach_sql("sqlite", "it's.db"),
+            "INSTALL sqlite; LOAD sqlite; ATTACH 'it''s.db' AS db (TYPE sqlite); USE db;"
+        );
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(-1), "1969-12-31");
+        assert_eq!(format_date(19_723), "2024-01-01");
+        assert_eq!(format_date(19_782), "2024-02-29");
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(
+            format_timestamp(19_723 * 86_400_000_000 + 45_296_000_001),
+            "2024-01-01 12:34:56.000001"
+        );
+        assert_eq!(format_timestamp(-1_000_000), "1969-12-31 23:59:59");
+    }
+
+    crate::record! {
+        #[derive(Debug, PartialEq)]
+        struct Product {
+            name: String,
+            price: f64,
+            stock: Option<u32>,
+        }
+    }
+
+    #[test]
+    fn records() {
+        let table = Table {
+            columns: vec!["name".to_owned(), "stock".to_owned(), "price".to_owned()],
+            column_types: vec!["Utf8".to_owned(), "Int32".to_owned(), "Float64".to_owned()],
+            rows: vec![
+                vec![Value::Text("Pen".to_owned()), Value::Int(3), Value::Int(2)],
+                vec![
+                    Value::Text("Ink".to_owned()),
+                    Value::Null,
+                    Value::Float(4.5),
+                ],
+            ],
+        };
+        assert_eq!(
+            table.records::<Product>().unwrap(),
+            vec![
+                Product {
+                    name: "Pen".to_owned(),
+                    price: 2.0,
+                    stock: Some(3),
+                },
+                Product {
+                    name: "Ink".to_owned(),
+                    price: 4.5,
+                    stock: None,
+                },
+            ]
+        );
+        let record = Record {
+            columns: &table.columns,
+            values: &table.rows[0],
+        };
+        assert_eq!(
+            record.get::<f64>("cost").unwrap_err().to_string(),
+            "No column named `cost`. Columns: name, stock, price"
+        );
+        assert_eq!(
+            record.get::<bool>("name").unwrap_err().to_string(),
+            "Column `name` has value Text(\"Pen\"), which can't be converted to bool"
+        );
+        assert_eq!(Value::Bytes(vec![1, 171]).to_string(), "\\x01ab");
+    }
+}